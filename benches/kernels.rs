@@ -0,0 +1,180 @@
+//! Benchmarks of how fast Lightbeam compiles and how fast the code that it generates runs, so
+//! that regressions from changes to translation or register allocation show up.
+//!
+//! These use the unstable `test` crate like the benchmarks in `src/tests.rs`, so run them
+//! with `cargo +nightly bench --features bench --bench kernels`. Compilation benchmarks set
+//! the number of bytes processed, so they're reported as throughput in MB/s of wasm.
+
+#![cfg(feature = "bench")]
+#![feature(test)]
+
+extern crate lightbeam;
+extern crate test;
+extern crate wabt;
+
+use lightbeam::{translate, translate_only, Instance};
+use test::{black_box, Bencher};
+
+/// Kernels that each take the amount of work to do and return something that depends on
+/// all of it.
+const KERNELS: &str = r#"
+(module
+  (memory 1 1)
+  (data (i32.const 0) "\01\02\01\03\04\02\01\03\01\04\02\00")
+  ;; Recursive Fibonacci, which is dominated by calls.
+  (func $fib (param $n i32) (result i32)
+    (if (result i32) (i32.lt_u (get_local $n) (i32.const 2))
+      (then (i32.const 1))
+      (else
+        (i32.add
+          (call $fib (i32.sub (get_local $n) (i32.const 1)))
+          (call $fib (i32.sub (get_local $n) (i32.const 2)))))))
+  ;; Copy `n` bytes from the start of memory to 32 KiB in, one at a time.
+  (func $memcpy (param $n i32) (result i32) (local $i i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (i32.store8 offset=32768 (get_local $i) (i32.load8_u (get_local $i)))
+        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+        (br $top)))
+    (i32.load offset=32768 (i32.const 0)))
+  ;; Run the bytecode program at the start of memory `n` times, dispatching on each opcode
+  ;; with a `br_table` like an interpreter. 0 ends the program, 1 adds 3, 2 doubles, 3 xors
+  ;; with the program counter and 4 subtracts 1.
+  (func $interpret (param $n i32) (result i32) (local $pc i32) (local $op i32) (local $acc i32)
+    (block $done
+      (loop $run
+        (br_if $done (i32.eqz (get_local $n)))
+        (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+        (set_local $pc (i32.const 0))
+        (loop $dispatch
+          (set_local $op (i32.load8_u (get_local $pc)))
+          (set_local $pc (i32.add (get_local $pc) (i32.const 1)))
+          (block $next
+            (block $sub
+              (block $xor
+                (block $double
+                  (block $add
+                    (block $halt
+                      (br_table $halt $add $double $xor $sub $halt (get_local $op)))
+                    (br $run))
+                  (set_local $acc (i32.add (get_local $acc) (i32.const 3)))
+                  (br $next))
+                (set_local $acc (i32.shl (get_local $acc) (i32.const 1)))
+                (br $next))
+              (set_local $acc (i32.xor (get_local $acc) (get_local $pc)))
+              (br $next))
+            (set_local $acc (i32.sub (get_local $acc) (i32.const 1))))
+          (br $dispatch))))
+    (get_local $acc)))
+"#;
+
+const FIB: u32 = 0;
+const MEMCPY: u32 = 1;
+const INTERPRET: u32 = 2;
+
+fn kernels() -> Instance {
+    translate(&wabt::wat2wasm(KERNELS).unwrap()).unwrap()
+}
+
+/// A module with `copies` copies of each kernel's function, which is big enough that the
+/// time to compile it isn't dominated by setting up the module.
+fn large_module(copies: usize) -> Vec<u8> {
+    let start = KERNELS.find("(func").unwrap();
+    let end = KERNELS.rfind(')').unwrap();
+    let (header, funcs) = (&KERNELS[..start], &KERNELS[start..end]);
+
+    let mut wat = header.to_string();
+    for i in 0..copies {
+        // Give each copy its own names, so that each copy of `$fib` calls itself
+        let mut copy = funcs.to_string();
+        for name in &["$fib", "$memcpy", "$interpret"] {
+            copy = copy.replace(name, &format!("{}{}", name, i));
+        }
+        wat.push_str(&copy);
+    }
+    wat.push(')');
+
+    wabt::wat2wasm(wat).unwrap()
+}
+
+fn bench_compile(b: &mut Bencher, wasm: &[u8]) {
+    b.bytes = wasm.len() as u64;
+    b.iter(|| black_box(translate_only(wasm).unwrap()));
+}
+
+#[bench]
+fn compile_kernels(b: &mut Bencher) {
+    bench_compile(b, &wabt::wat2wasm(KERNELS).unwrap());
+}
+
+#[bench]
+fn compile_large_module(b: &mut Bencher) {
+    bench_compile(b, &large_module(200));
+}
+
+#[bench]
+fn run_fib(b: &mut Bencher) {
+    let instance = kernels();
+
+    b.iter(|| instance.execute_func::<_, u32>(FIB, (black_box(20),)));
+}
+
+#[bench]
+fn run_memcpy(b: &mut Bencher) {
+    let instance = kernels();
+
+    b.bytes = 16384;
+    b.iter(|| instance.execute_func::<_, u32>(MEMCPY, (black_box(16384),)));
+}
+
+#[bench]
+fn run_interpret(b: &mut Bencher) {
+    let instance = kernels();
+
+    b.iter(|| instance.execute_func::<_, u32>(INTERPRET, (black_box(1000),)));
+}
+
+/// The results that the kernels compute, so that a benchmark can't be fast by being wrong.
+#[test]
+fn kernels_are_correct() {
+    fn fib(n: u32) -> u32 {
+        if n < 2 {
+            1
+        } else {
+            fib(n - 1) + fib(n - 2)
+        }
+    }
+
+    fn interpret(n: u32) -> u32 {
+        let program = [1u8, 2, 1, 3, 4, 2, 1, 3, 1, 4, 2, 0];
+        let mut acc = 0u32;
+        for _ in 0..n {
+            for (pc, &op) in program.iter().enumerate() {
+                let pc = pc as u32 + 1;
+                match op {
+                    1 => acc = acc.wrapping_add(3),
+                    2 => acc = acc.wrapping_shl(1),
+                    3 => acc ^= pc,
+                    4 => acc = acc.wrapping_sub(1),
+                    _ => break,
+                }
+            }
+        }
+        acc
+    }
+
+    let instance = kernels();
+    assert_eq!(instance.execute_func::<_, u32>(FIB, (20,)), Ok(fib(20)));
+    assert_eq!(
+        instance.execute_func::<_, u32>(MEMCPY, (16,)),
+        Ok(u32::from_le_bytes([1, 2, 1, 3]))
+    );
+    assert_eq!(
+        instance.execute_func::<_, u32>(INTERPRET, (100,)),
+        Ok(interpret(100))
+    );
+
+    let large = translate_only(&large_module(3)).unwrap().instantiate();
+    assert_eq!(large.execute_func::<_, u32>(FIB + 3, (10,)), Ok(fib(10)));
+}
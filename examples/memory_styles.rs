@@ -0,0 +1,158 @@
+//! Compile and run memory-heavy kernels with explicit bounds checks and with guard pages,
+//! and report the difference in compile time, code size and run time.
+//!
+//! Run with `cargo run --release --example memory_styles`.
+
+extern crate lightbeam;
+extern crate wabt;
+
+use lightbeam::{translate_only_with, Instance, MemoryStyle, TranslateOptions};
+use std::time::{Duration, Instant};
+
+const KERNELS: &str = r#"
+(module
+  (memory 16 16)
+  ;; Write `n` consecutive words.
+  (func $fill (param $n i32) (result i32) (local $i i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (i32.store (i32.shl (get_local $i) (i32.const 2)) (get_local $i))
+        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+        (br $top)))
+    (get_local $i))
+  ;; Sum `n` consecutive words.
+  (func $sum (param $n i32) (result i32) (local $i i32) (local $acc i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (set_local $acc
+          (i32.add (get_local $acc) (i32.load (i32.shl (get_local $i) (i32.const 2)))))
+        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+        (br $top)))
+    (get_local $acc))
+  ;; Copy `n` bytes from the start of memory to 512 KiB in, 8 at a time.
+  (func $copy (param $n i32) (result i32) (local $i i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (i64.store offset=524288 (get_local $i) (i64.load (get_local $i)))
+        (set_local $i (i32.add (get_local $i) (i32.const 8)))
+        (br $top)))
+    (get_local $i))
+  ;; Count the values of the first `n` bytes into a table of words at the end of memory.
+  (func $histogram (param $n i32) (result i32) (local $i i32) (local $bucket i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (set_local $bucket (i32.shl (i32.load8_u (get_local $i)) (i32.const 2)))
+        (i32.store offset=983040
+          (get_local $bucket)
+          (i32.add (i32.load offset=983040 (get_local $bucket)) (i32.const 1)))
+        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+        (br $top)))
+    (i32.load offset=983040 (i32.const 0))))
+"#;
+
+/// Function index, name and argument of each kernel.
+const RUNS: &[(u32, &str, u32)] = &[
+    (0, "fill", 1 << 16),
+    (1, "sum", 1 << 16),
+    (2, "copy", 1 << 18),
+    (3, "histogram", 1 << 18),
+];
+
+const COMPILE_ITERATIONS: u32 = 50;
+const RUN_ITERATIONS: u32 = 20;
+
+struct Report {
+    compile: Duration,
+    code_size: usize,
+    runs: Vec<(Duration, u32)>,
+}
+
+fn compile(wasm: &[u8], memory_style: MemoryStyle) -> Instance {
+    let options = TranslateOptions {
+        memory_style,
+        ..Default::default()
+    };
+    translate_only_with(wasm, options)
+        .expect("Failed to translate kernels")
+        .instantiate()
+}
+
+fn measure(wasm: &[u8], memory_style: MemoryStyle) -> Report {
+    let start = Instant::now();
+    for _ in 0..COMPILE_ITERATIONS {
+        compile(wasm, memory_style);
+    }
+    let compile_time = start.elapsed() / COMPILE_ITERATIONS;
+
+    let instance = compile(wasm, memory_style);
+    let runs = RUNS
+        .iter()
+        .map(|&(func, _, arg)| {
+            let mut best = Duration::from_secs(u64::max_value());
+            let mut result = 0;
+            for _ in 0..RUN_ITERATIONS {
+                let start = Instant::now();
+                result = instance
+                    .execute_func::<_, u32>(func, (arg,))
+                    .expect("Failed to run kernel");
+                best = best.min(start.elapsed());
+            }
+            (best, result)
+        })
+        .collect();
+
+    Report {
+        compile: compile_time,
+        code_size: instance.code_section().buffer().len(),
+        runs,
+    }
+}
+
+fn delta(checked: f64, guarded: f64) -> String {
+    format!("{:+.1}%", (guarded - checked) / checked * 100.)
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1e6 + f64::from(duration.subsec_nanos()) / 1e3
+}
+
+fn main() {
+    let wasm = wabt::wat2wasm(KERNELS).expect("Failed to assemble kernels");
+    let checked = measure(&wasm, MemoryStyle::BoundsChecked);
+    let guarded = measure(&wasm, MemoryStyle::GuardPages);
+
+    println!(
+        "{:<16}{:>16}{:>16}{:>10}",
+        "", "bounds checks", "guard pages", "delta"
+    );
+    println!(
+        "{:<16}{:>14.1}us{:>14.1}us{:>10}",
+        "compile",
+        micros(checked.compile),
+        micros(guarded.compile),
+        delta(micros(checked.compile), micros(guarded.compile))
+    );
+    println!(
+        "{:<16}{:>16}{:>16}{:>10}",
+        "code size",
+        format!("{}B", checked.code_size),
+        format!("{}B", guarded.code_size),
+        delta(checked.code_size as f64, guarded.code_size as f64)
+    );
+    for (&(_, name, _), (checked, guarded)) in
+        RUNS.iter().zip(checked.runs.iter().zip(&guarded.runs))
+    {
+        assert_eq!(checked.1, guarded.1, "{} gave different results", name);
+        println!(
+            "{:<16}{:>14.1}us{:>14.1}us{:>10}",
+            name,
+            micros(checked.0),
+            micros(guarded.0),
+            delta(micros(checked.0), micros(guarded.0))
+        );
+    }
+}
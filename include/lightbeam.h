@@ -0,0 +1,79 @@
+/*
+ * The C interface to Lightbeam, from `src/capi.rs`. Build the library with
+ * `cargo rustc --release --features capi --crate-type cdylib`.
+ *
+ * Functions that can fail return false or NULL, and `lightbeam_last_error`
+ * then describes what went wrong.
+ */
+
+#ifndef LIGHTBEAM_H
+#define LIGHTBEAM_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct lightbeam_instance lightbeam_instance;
+
+enum lightbeam_kind {
+    LIGHTBEAM_I32 = 0,
+    LIGHTBEAM_I64 = 1,
+    LIGHTBEAM_F32 = 2,
+    LIGHTBEAM_F64 = 3,
+};
+
+/* Integers are zero-extended to 64 bits and floats are stored as their bit
+ * patterns. */
+typedef struct lightbeam_value {
+    uint32_t kind;
+    uint64_t bits;
+} lightbeam_value;
+
+/* Why the code trapped, from `lightbeam_trap_code`. */
+enum lightbeam_trap {
+    LIGHTBEAM_TRAP_NONE = 0,
+    LIGHTBEAM_TRAP_STACK_OVERFLOW = 1,
+    LIGHTBEAM_TRAP_HEAP_OUT_OF_BOUNDS = 2,
+    LIGHTBEAM_TRAP_TABLE_OUT_OF_BOUNDS = 3,
+    LIGHTBEAM_TRAP_OUT_OF_BOUNDS = 4,
+    LIGHTBEAM_TRAP_INDIRECT_CALL_TO_NULL = 5,
+    LIGHTBEAM_TRAP_BAD_SIGNATURE = 6,
+    LIGHTBEAM_TRAP_INTEGER_OVERFLOW = 7,
+    LIGHTBEAM_TRAP_INTEGER_DIVISION_BY_ZERO = 8,
+    LIGHTBEAM_TRAP_BAD_CONVERSION_TO_INTEGER = 9,
+    LIGHTBEAM_TRAP_UNREACHABLE = 10,
+    LIGHTBEAM_TRAP_INTERRUPT = 11,
+    LIGHTBEAM_TRAP_USER = 12,
+    LIGHTBEAM_TRAP_ARITHMETIC_OVERFLOW = 13,
+};
+
+/* Translate a binary module and instantiate it. */
+lightbeam_instance *lightbeam_translate(const uint8_t *data, size_t len);
+void lightbeam_instance_delete(lightbeam_instance *instance);
+
+/* Find the index of an exported function. */
+bool lightbeam_get_func(const lightbeam_instance *instance, const char *name,
+                        uint32_t *func);
+
+/* Call a function. `result` can be NULL if it doesn't return anything. Traps
+ * raise SIGILL. */
+bool lightbeam_call(const lightbeam_instance *instance, uint32_t func,
+                    const lightbeam_value *args, size_t num_args,
+                    lightbeam_value *result);
+
+/* Why the code traps at `pc`, as a `lightbeam_trap`. */
+uint32_t lightbeam_trap_code(const lightbeam_instance *instance,
+                             const void *pc);
+
+/* The last error on this thread, valid until the next failing call. */
+const char *lightbeam_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,54 +1,52 @@
+use crate::breakpoints::{
+    self, Breakpoint, BreakpointHook, Breakpoints, DebugLocation, DebugOptions, FlagCondition,
+};
+use crate::code_buffer::CodeBuffer;
+use crate::coverage::{Coverage, CoverageGuards};
+use crate::devirtualize::StaticTable;
+use crate::emitter::{self, DynamicLabel, Emitter, Instruction, Operand, Piece, VecAssembler};
 use crate::error::Error;
-use crate::microwasm::{BrTarget, Ieee32, Ieee64, SignlessType, Type, Value, F32, F64, I32, I64};
-use crate::module::ModuleContext;
+use crate::index_space::{
+    DefinedFuncIndex, FuncIndex, ImportedFuncIndex, IndexVec, TrampolineIndex,
+};
+use crate::inline::InlineBodies;
+use crate::metrics::CompilationMetrics;
+use crate::microwasm::{
+    BrTarget, CostModel, Ieee32, Ieee64, Signedness, SignlessType, Type, Value, F32, F64, I32, I64,
+};
+use crate::module::{BuiltinFunction, ModuleContext, SigType, Signature};
+use crate::peephole::Peephole;
+use crate::profiling::OperatorClass;
+use crate::tiering::{HotnessCounters, OsrPoint, Tiering, ENTRY_LOOP_INDEX};
+use crate::trace_hooks::TraceHooks;
+use crate::unwind::{self, FunctionUnwind, UnwindRow};
 use cranelift_codegen::{binemit, ir};
 use dynasm::dynasm;
-use dynasmrt::x64::Assembler;
-use dynasmrt::{AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use dynasmrt::AssemblyOffset;
 use either::Either;
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    cmp,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
-    fmt::Display,
+    fmt::{self, Display},
     iter::{self, FromIterator},
     mem,
-    ops::RangeInclusive,
+    ops::{Range, RangeInclusive},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use self::registers::*;
 
-// TODO: Get rid of this! It's a total hack.
-mod magic {
-    use cranelift_codegen::ir;
-
-    /// Compute an `ir::ExternalName` for the `memory.grow` libcall for
-    /// 32-bit locally-defined memories.
-    pub fn get_memory32_grow_name() -> ir::ExternalName {
-        ir::ExternalName::user(1, 0)
-    }
-
-    /// Compute an `ir::ExternalName` for the `memory.grow` libcall for
-    /// 32-bit imported memories.
-    pub fn get_imported_memory32_grow_name() -> ir::ExternalName {
-        ir::ExternalName::user(1, 1)
-    }
-
-    /// Compute an `ir::ExternalName` for the `memory.size` libcall for
-    /// 32-bit locally-defined memories.
-    pub fn get_memory32_size_name() -> ir::ExternalName {
-        ir::ExternalName::user(1, 2)
-    }
-
-    /// Compute an `ir::ExternalName` for the `memory.size` libcall for
-    /// 32-bit imported memories.
-    pub fn get_imported_memory32_size_name() -> ir::ExternalName {
-        ir::ExternalName::user(1, 3)
-    }
-}
-
 /// Size of a pointer on the target in bytes.
 const WORD_SIZE: u32 = 8;
+/// The most that `rsp` moves at once without touching the stack, which is the smallest page
+/// size and so the smallest that a guard page can be.
+const STACK_PROBE_INTERVAL: u32 = 4096;
 
 type RegId = u8;
 
@@ -79,6 +77,20 @@ impl From<SignlessType> for Option<GPRType> {
     }
 }
 
+impl fmt::Display for GPR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const RQ_NAMES: [&str; NUM_GPRS as usize] = [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ];
+
+        match *self {
+            GPR::Rq(r) => f.write_str(RQ_NAMES[r as usize]),
+            GPR::Rx(r) => write!(f, "xmm{}", r),
+        }
+    }
+}
+
 impl GPR {
     fn type_(self) -> GPRType {
         match self {
@@ -235,8 +247,12 @@ const SIGN_MASK_F32: u32 = 0b10000000000000000000000000000000;
 const REST_MASK_F32: u32 = !SIGN_MASK_F32;
 
 impl GPRs {
-    fn take(&mut self) -> Option<RegId> {
-        let lz = self.bits.trailing_zeros();
+    /// Take a free register, only falling back to a register in `avoid` if no other
+    /// register is free.
+    fn take_avoiding(&mut self, avoid: u16) -> Option<RegId> {
+        let preferred = self.bits & !avoid;
+        let bits = if preferred != 0 { preferred } else { self.bits };
+        let lz = bits.trailing_zeros();
         if lz < 16 {
             let gpr = lz as RegId;
             self.mark_used(gpr);
@@ -264,25 +280,67 @@ impl GPRs {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone)]
 pub struct Registers {
     /// Registers at 64 bits and below (al/ah/ax/eax/rax, for example)
     scratch_64: (GPRs, [u8; NUM_GPRS as usize]),
     /// Registers at 128 bits (xmm0, for example)
     scratch_128: (GPRs, [u8; NUM_GPRS as usize]),
+    /// General-purpose registers that hold a value that's known to be 0 or 1, like a
+    /// materialized condition, as a bitmask.
+    bools: u16,
+    /// General-purpose registers whose upper 32 bits are known to be zero, like the result of
+    /// any 32-bit operation, as a bitmask.
+    zero_extended: u16,
+    /// The callee-saved registers that the function saves in its prologue, so that they can
+    /// be allocated too, or none if it doesn't save them.
+    callee_saved: &'static [GPR],
+}
+
+// What we know about the values in the registers doesn't change which ones are in use
+impl PartialEq for Registers {
+    fn eq(&self, other: &Self) -> bool {
+        self.scratch_64 == other.scratch_64 && self.scratch_128 == other.scratch_128
+    }
 }
 
+impl Eq for Registers {}
+
 impl Default for Registers {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// Lists the scratch registers that are in use, with how many values are in each if there's
+/// more than one.
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("{")?;
+        let mut first = true;
+        for &reg in self.allocatable().filter(|&&reg| !self.is_free(reg)) {
+            if !first {
+                f.write_str(", ")?;
+            }
+            first = false;
+
+            write!(f, "{}", reg)?;
+            if self.num_usages(reg) > 1 {
+                write!(f, " x{}", self.num_usages(reg))?;
+            }
+        }
+        f.write_str("}")
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         let mut result = Self {
             scratch_64: (GPRs::new(), [1; NUM_GPRS as _]),
             scratch_128: (GPRs::new(), [1; NUM_GPRS as _]),
+            bools: 0,
+            zero_extended: 0,
+            callee_saved: &[],
         };
 
         // Give ourselves a few scratch registers to work with, for now.
@@ -293,6 +351,21 @@ impl Registers {
         result
     }
 
+    /// Also allocate `callee_saved`, for a function that saves them.
+    pub fn with_callee_saved(mut self, callee_saved: &'static [GPR]) -> Self {
+        if self.callee_saved.is_empty() {
+            self.callee_saved = callee_saved;
+            for &reg in callee_saved {
+                self.release(reg);
+            }
+        }
+        self
+    }
+
+    fn allocatable(&self) -> impl Iterator<Item = &'static GPR> {
+        SCRATCH_REGS.iter().chain(self.callee_saved)
+    }
+
     fn scratch_counts_mut(&mut self, gpr: GPR) -> (u8, &mut (GPRs, [u8; NUM_GPRS as usize])) {
         match gpr {
             GPR::Rq(r) => (r, &mut self.scratch_64),
@@ -318,25 +391,60 @@ impl Registers {
         scratch_counts.1[gpr as usize]
     }
 
+    /// Take a free register of the given type. Return registers are only handed out once all
+    /// other scratch registers are in use, since they're clobbered by every call (and `RAX`/`RDX`
+    /// are also needed for division), so temporaries in them would need to be moved or spilled
+    /// more often. Callee-saved registers are kept for values that live across calls too.
     pub fn take(&mut self, ty: impl Into<GPRType>) -> Option<GPR> {
-        let (mk_gpr, scratch_counts) = match ty.into() {
-            GPRType::Rq => (GPR::Rq as fn(_) -> _, &mut self.scratch_64),
-            GPRType::Rx => (GPR::Rx as fn(_) -> _, &mut self.scratch_128),
+        let ty = ty.into();
+        let callee_saved = self.callee_saved;
+        let (mk_gpr, scratch_counts, return_regs) = match ty {
+            GPRType::Rq => (
+                GPR::Rq as fn(_) -> _,
+                &mut self.scratch_64,
+                INTEGER_RETURN_GPRS,
+            ),
+            GPRType::Rx => (
+                GPR::Rx as fn(_) -> _,
+                &mut self.scratch_128,
+                FLOAT_RETURN_GPRS,
+            ),
         };
 
-        let out = scratch_counts.0.take()?;
+        let avoid = return_regs
+            .iter()
+            .chain(callee_saved)
+            .filter(|r| r.type_() == ty)
+            .fold(0u16, |acc, r| match r {
+                GPR::Rq(r) | GPR::Rx(r) => acc | (1 << *r as u16),
+            });
+
+        let out = scratch_counts.0.take_avoiding(avoid)?;
         scratch_counts.1[out as usize] += 1;
-        Some(mk_gpr(out))
+
+        let out = mk_gpr(out);
+        self.forget(out);
+        Some(out)
+    }
+
+    /// Take a free callee-saved register, if the function saves them, to keep a value that's
+    /// live across a call in.
+    fn take_callee_saved(&mut self) -> Option<GPR> {
+        let reg = *self.callee_saved.iter().find(|&&reg| self.is_free(reg))?;
+        self.mark_used(reg);
+        self.forget(reg);
+        Some(reg)
     }
 
     pub fn release(&mut self, gpr: GPR) {
-        let (gpr, scratch_counts) = self.scratch_counts_mut(gpr);
-        let c = &mut scratch_counts.1[gpr as usize];
+        let (id, scratch_counts) = self.scratch_counts_mut(gpr);
+        let c = &mut scratch_counts.1[id as usize];
         *c = c
             .checked_sub(1)
-            .unwrap_or_else(|| panic!("Double-freed register: {}", gpr));
+            .unwrap_or_else(|| panic!("Double-freed register: {}", id));
         if *c == 0 {
-            scratch_counts.0.release(gpr);
+            scratch_counts.0.release(id);
+            self.forget(gpr);
         }
     }
 
@@ -344,6 +452,66 @@ impl Registers {
         let (gpr, scratch_counts) = self.scratch_counts(gpr);
         scratch_counts.0.is_free(gpr)
     }
+
+    /// Whether `gpr` holds a value that's known to be 0 or 1.
+    pub fn is_bool(&self, gpr: GPR) -> bool {
+        match gpr {
+            GPR::Rq(r) => self.bools & (1 << r) != 0,
+            GPR::Rx(_) => false,
+        }
+    }
+
+    fn set_bool(&mut self, gpr: GPR, is_bool: bool) {
+        set_bit(&mut self.bools, gpr, is_bool);
+    }
+
+    /// Whether the upper 32 bits of `gpr` are known to be zero.
+    pub fn is_zero_extended(&self, gpr: GPR) -> bool {
+        match gpr {
+            GPR::Rq(r) => self.zero_extended & (1 << r) != 0,
+            GPR::Rx(_) => false,
+        }
+    }
+
+    fn set_zero_extended(&mut self, gpr: GPR, is_zero_extended: bool) {
+        set_bit(&mut self.zero_extended, gpr, is_zero_extended);
+    }
+
+    /// Record that `regs` have been overwritten, by a call for example. None of them can
+    /// hold a value that's still in use.
+    pub fn clobber(&mut self, regs: &[GPR]) {
+        for &reg in regs {
+            debug_assert!(self.is_free(reg), "{} is in use but was clobbered", reg);
+            self.forget(reg);
+        }
+    }
+
+    /// Forget everything we know about the value in `gpr`, before it's overwritten.
+    fn forget(&mut self, gpr: GPR) {
+        self.set_bool(gpr, false);
+        self.set_zero_extended(gpr, false);
+    }
+
+    /// Free registers that still remember what we know about the values in them, in case
+    /// the code that uses them next can only be reached by falling through.
+    pub fn cleared(&self) -> Self {
+        let cleared = Registers::new().with_callee_saved(self.callee_saved);
+        Registers {
+            bools: self.bools,
+            zero_extended: self.zero_extended,
+            ..cleared
+        }
+    }
+}
+
+fn set_bit(mask: &mut u16, gpr: GPR, value: bool) {
+    if let GPR::Rq(r) = gpr {
+        if value {
+            *mask |= 1 << r;
+        } else {
+            *mask &= !(1 << r);
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -353,16 +521,258 @@ pub struct BlockCallingConvention {
 }
 
 impl BlockCallingConvention {
-    pub fn function_start(args: impl IntoIterator<Item = CCLoc>) -> Self {
+    /// The calling convention at the start of a function's body, and at its return, with
+    /// the stack as deep as `Context::frame_depth`.
+    pub fn function_start(args: impl IntoIterator<Item = CCLoc>, frame_depth: StackDepth) -> Self {
         BlockCallingConvention {
-            // We start and return the function with stack depth 1 since we must
-            // allow space for the saved return address.
-            stack_depth: StackDepth(1),
+            stack_depth: frame_depth,
             arguments: Vec::from_iter(args),
         }
     }
 }
 
+/// Whether generated functions maintain a frame pointer in `RBP`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FramePointer {
+    /// Address everything relative to `RSP` using the statically-known stack depth and
+    /// leave `RBP` alone. This is the default, since it makes prologues and epilogues free.
+    Omit,
+    /// Push `RBP` and point it at the saved value on entry to every function, so that
+    /// debuggers, profilers and unwinders can walk the stack by following the chain of
+    /// frame pointers.
+    Preserve,
+}
+
+impl Default for FramePointer {
+    fn default() -> Self {
+        FramePointer::Omit
+    }
+}
+
+/// How the generated code is laid out in memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CodeLayout {
+    /// Every function starts at a multiple of this many bytes, with `nop`s as padding in
+    /// between. Must be a power of two. 64 packs functions to cache lines.
+    pub function_alignment: u32,
+    /// Put the finished code in memory aligned to and, where the OS supports it, backed by
+    /// 2 MiB huge pages, which improves iTLB behaviour for very large modules.
+    pub huge_pages: bool,
+}
+
+impl Default for CodeLayout {
+    fn default() -> Self {
+        CodeLayout {
+            function_alignment: 1,
+            huge_pages: false,
+        }
+    }
+}
+
+/// Options for the code generated by a `CodeGenSession`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CodeGenOptions {
+    pub frame_pointer: FramePointer,
+    pub code_layout: CodeLayout,
+    /// Call a hook at the start of every basic block, for coverage-guided fuzzing.
+    pub coverage: Option<Coverage>,
+    /// Never embed the address of anything outside the generated code, so that translating
+    /// the same module with the same options gives byte-for-byte the same code in every
+    /// process. Runtime functions are called through the `VmCtx` instead, which costs a
+    /// load per call. Coverage, trace and breakpoint hooks can't be reached that way, so
+    /// this can't be combined with any of them.
+    pub deterministic: bool,
+    pub size_limits: CodeSizeLimits,
+    /// Record the code generated for each microwasm operator, for
+    /// `TranslatedCodeSection::side_by_side`. This formats every operator, so it's only
+    /// meant for debugging.
+    pub record_operator_ranges: bool,
+    /// Call hooks on function entry and exit and on every loop iteration, for tracing.
+    pub trace_hooks: Option<TraceHooks>,
+    /// Generate code that a debugger can stop in, starting the code for every wasm
+    /// instruction with a breakpoint site. See `TranslatedCodeSection::set_breakpoint`.
+    pub debug: Option<DebugOptions>,
+    /// Count how often each function runs, calling a hook when one gets hot, so that a
+    /// runtime can tier up to an optimizing compiler. See `tiering`.
+    pub tiering: Option<Tiering>,
+    /// Count how many loads, stores, calls and branches each instance runs. See
+    /// `Instance::operator_counts`.
+    pub profile_operators: bool,
+    /// Replace calls to functions without control flow and with at most this many microwasm
+    /// operators by their bodies. Functions translated lazily don't inline calls.
+    pub max_inlined_size: Option<usize>,
+    /// Call functions directly from `call_indirect` with a constant index into a table that
+    /// the module never writes to. See `devirtualize`.
+    pub devirtualize_calls: bool,
+    /// Trap with `ARITHMETIC_OVERFLOW` when an `i32` or `i64` `add`, `sub` or `mul` overflows
+    /// as a signed operation, rather than wrapping as wasm requires, to find unintended
+    /// wrapping while debugging. Code that wraps on purpose, like a hash function, traps too,
+    /// and operators whose result is never used are left out, so they never trap.
+    pub trap_on_overflow: bool,
+    /// Start every function, stub and `br_table` target with `endbr64`, so that with
+    /// Intel CET's indirect branch tracking enabled, indirect calls and jumps can only land
+    /// at the start of one of them rather than in the middle of some other code. Every
+    /// `ret` also returns to just after the `call` that it matches, so the code can run
+    /// with a hardware shadow stack too. Breakpoint sites return past data after the call,
+    /// so this can't be combined with `debug`.
+    pub cet: bool,
+    /// The cost of each operator, which the cost of every function in its `FunctionStats`
+    /// is the total of.
+    pub cost_model: CostModel,
+}
+
+/// `endbr64`, which dynasm doesn't know about. It's a `nop` on processors without CET.
+const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+/// The trap code for arithmetic that overflowed with `CodeGenOptions::trap_on_overflow`,
+/// which wasm code can't trap with otherwise.
+pub const ARITHMETIC_OVERFLOW: ir::TrapCode = ir::TrapCode::User(0);
+
+/// The number that a trap stub stores in the `VmCtx` to say why it trapped, which is never 0.
+pub(crate) fn trap_number(code: ir::TrapCode) -> u32 {
+    match code {
+        ir::TrapCode::StackOverflow => 1,
+        ir::TrapCode::HeapOutOfBounds => 2,
+        ir::TrapCode::TableOutOfBounds => 3,
+        ir::TrapCode::OutOfBounds => 4,
+        ir::TrapCode::IndirectCallToNull => 5,
+        ir::TrapCode::BadSignature => 6,
+        ir::TrapCode::IntegerOverflow => 7,
+        ir::TrapCode::IntegerDivisionByZero => 8,
+        ir::TrapCode::BadConversionToInteger => 9,
+        ir::TrapCode::UnreachableCodeReached => 10,
+        ir::TrapCode::Interrupt => 11,
+        ir::TrapCode::User(code) => 0x1_0000 | u32::from(code),
+    }
+}
+
+/// The trap code that `trap_number` gives `number`, if it gives it to any.
+pub(crate) fn trap_code(number: u32) -> Option<ir::TrapCode> {
+    Some(match number {
+        1 => ir::TrapCode::StackOverflow,
+        2 => ir::TrapCode::HeapOutOfBounds,
+        3 => ir::TrapCode::TableOutOfBounds,
+        4 => ir::TrapCode::OutOfBounds,
+        5 => ir::TrapCode::IndirectCallToNull,
+        6 => ir::TrapCode::BadSignature,
+        7 => ir::TrapCode::IntegerOverflow,
+        8 => ir::TrapCode::IntegerDivisionByZero,
+        9 => ir::TrapCode::BadConversionToInteger,
+        10 => ir::TrapCode::UnreachableCodeReached,
+        11 => ir::TrapCode::Interrupt,
+        _ if number >> 16 == 1 => ir::TrapCode::User(number as u16),
+        _ => return None,
+    })
+}
+
+/// Limits on the amount of machine code generated, to protect hosts against modules that
+/// are crafted to make it explode. Translation fails as soon as a limit is exceeded, rather
+/// than once the code has been generated.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CodeSizeLimits {
+    /// The most bytes that any one function's body may take. Out-of-line code, which is
+    /// shared between functions, isn't counted.
+    pub max_function_size: Option<usize>,
+    /// The most bytes that the whole code section may take. This can't be more than
+    /// `MAX_CODE_SIZE`, which applies even if it's `None`.
+    pub max_module_size: Option<usize>,
+}
+
+/// The most bytes that a code section can take, whatever the limits. Branches, calls and
+/// references to constants are all encoded with 32-bit displacements, which can't reach
+/// any further, so code that's any larger fails to translate rather than having its
+/// displacements silently truncated.
+pub const MAX_CODE_SIZE: usize = i32::max_value() as usize;
+
+impl CodeSizeLimits {
+    /// The most bytes that the whole code section may actually take.
+    fn module_size_limit(&self) -> usize {
+        self.max_module_size
+            .map_or(MAX_CODE_SIZE, |limit| limit.min(MAX_CODE_SIZE))
+    }
+}
+
+/// The code generated for a microwasm operator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorRange {
+    /// Where the wasm operator that this was translated from is in the module, if it was
+    /// translated from one.
+    pub wasm_offset: Option<usize>,
+    /// The operator as `microwasm::dis` prints it.
+    pub operator: String,
+    /// The range of the code section that was generated for it, which may be empty.
+    pub code: std::ops::Range<usize>,
+}
+
+/// A stub that traps, which every check for the same reason in a function jumps to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TrapSite {
+    /// Where the trapping instruction is in the code section.
+    pub offset: usize,
+    pub code: ir::TrapCode,
+}
+
+/// Statistics about the code generated for a function.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    /// The size of the function's body in bytes, not counting alignment padding after it or
+    /// out-of-line code.
+    pub code_size: usize,
+    /// The number of microwasm operators that were translated.
+    pub operators: usize,
+    /// The number of times that a value was moved from a register to the stack, either to
+    /// free the register or to save it across a call.
+    pub spills: usize,
+    /// The number of blocks that nothing branched to, which were skipped without generating
+    /// any code for them.
+    pub dead_blocks: usize,
+    /// The number of instructions that the peephole layer dropped because they had no effect,
+    /// like moves that undo the previous move or pushes that were immediately popped.
+    pub removed_instructions: usize,
+    /// The total cost of the function's microwasm operators under
+    /// `CodeGenOptions::cost_model`, counting every operator once wherever it is, including
+    /// in dead blocks and loops. This is a static estimate for embedders that want to weigh
+    /// functions against each other or against a budget before running them.
+    pub cost: u64,
+    /// The most bytes of stack that the function's own frame takes at any point: the return
+    /// address, saved registers, spilled values and arguments passed on the stack to the
+    /// functions that it calls. Those functions, and host functions and runtime functions
+    /// with them, take more on top of this.
+    pub max_stack_size: u32,
+}
+
+impl FramePointer {
+    fn saved_words(self) -> u32 {
+        match self {
+            FramePointer::Omit => 0,
+            FramePointer::Preserve => 1,
+        }
+    }
+
+    /// The registers that a function that makes calls saves in its prologue and can then
+    /// allocate. Without a frame pointer, `RBP` is just another callee-saved register.
+    fn callee_saved(self) -> &'static [GPR] {
+        match self {
+            FramePointer::Omit => CALLEE_SAVED_GPRS_AND_RBP,
+            FramePointer::Preserve => CALLEE_SAVED_GPRS,
+        }
+    }
+
+    /// Return from the function, assuming that the stack has already been restored to its
+    /// depth at the end of the prologue.
+    fn emit_ret(self, asm: &mut VecAssembler) {
+        if let FramePointer::Preserve = self {
+            dynasm!(asm
+                ; pop rbp
+            );
+        }
+
+        dynasm!(asm
+            ; ret
+        );
+    }
+}
+
 // TODO: Combine this with `ValueLocation`?
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CCLoc {
@@ -433,6 +843,11 @@ impl std::ops::Not for CondCode {
 }
 
 /// Describes location of a value.
+///
+/// Constants and comparison results don't take up a register or a stack slot until an
+/// operator needs them in one (see `into_reg` and `push_physical`), and `pick`, which is
+/// what locals are read with, only copies the location of the value, so most values are
+/// never moved before the operator that uses them.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ValueLocation {
     /// Value exists in a register.
@@ -442,7 +857,9 @@ pub enum ValueLocation {
     Stack(i32),
     /// Value is a literal
     Immediate(Value),
-    /// Value is a set condition code
+    /// Value is a set condition code. The flags are overwritten by the code for almost every
+    /// operator, so this is only ever the value on top of the stack, and it's materialized
+    /// as soon as anything is pushed on top of it.
     Cond(CondCode),
 }
 
@@ -470,6 +887,12 @@ impl ValueLocation {
         }
     }
 
+    /// The register that this is in, if it's a general-purpose register rather than an XMM
+    /// register.
+    fn int_reg(self) -> Option<GPR> {
+        self.reg().filter(|reg| reg.type_() == GPRType::Rq)
+    }
+
     fn immediate(self) -> Option<Value> {
         match self {
             ValueLocation::Immediate(i) => Some(i),
@@ -506,8 +929,49 @@ const SCRATCH_REGS: &[GPR] = &[
     RSI, RDX, RCX, R8, R9, RAX, R10, R11, XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7, XMM8,
     XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15,
 ];
+/// The registers that the System V ABI requires a function to preserve, other than `RBP`,
+/// which the frame pointer may be kept in. Functions that make calls save these in their
+/// prologue, so that values that are live across a call can stay in registers. They're
+/// saved in this order, which the unwind information depends on.
+const CALLEE_SAVED_GPRS: &[GPR] = &[RBX, R12, R13, R14, R15];
+/// The registers that functions that make calls save when they don't keep a frame pointer,
+/// which is `RBP` as well. It's saved last, so that the others are in the same place in the
+/// frame either way.
+const CALLEE_SAVED_GPRS_AND_RBP: &[GPR] = &[RBX, R12, R13, R14, R15, RBP];
 const VMCTX: RegId = rq::RDI;
 
+/// The most runs of indices with the same target that a `br_table` is lowered to compares
+/// for, rather than a jump table.
+const MAX_BR_TABLE_COMPARES: usize = 4;
+
+/// The registers that labels with more than one caller pin their parameters to, assigned in
+/// order of `Context::register_priority`, which is the internal "block calling convention".
+/// Parameters that don't fit go on the stack. Some scratch registers are left out so that a
+/// loop body has somewhere to compute without spilling the values that it carries round the
+/// loop.
+const BLOCK_GPRS: &[GPR] = &[RSI, RDX, RCX, R8, R9, RAX];
+const BLOCK_XMMS: &[GPR] = &[
+    XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7, XMM8, XMM9, XMM10, XMM11, XMM12, XMM13,
+];
+
+/// How calls from one of the module's functions to another are linked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallLinkage {
+    /// Each call is passed to the relocation sink, for embedders that lay out the functions
+    /// themselves. This is the default.
+    Relocations,
+    /// Calls to functions translated in the same session go straight to them, which is
+    /// resolved when the session's code is linked, and calls to any other function go
+    /// to the address given for it here, which must already be where it'll be run from.
+    Linked(HashMap<DefinedFuncIndex, usize>),
+}
+
+impl Default for CallLinkage {
+    fn default() -> Self {
+        CallLinkage::Relocations
+    }
+}
+
 #[must_use]
 #[derive(Debug, Clone)]
 pub struct FunctionEnd {
@@ -515,58 +979,393 @@ pub struct FunctionEnd {
 }
 
 pub struct CodeGenSession<'module, M> {
-    assembler: Assembler,
+    /// The function or stub being emitted. Each is assembled into a buffer of its own, which
+    /// starts where the one before it ends, so offsets in it are offsets in the finished
+    /// code.
+    assembler: VecAssembler,
+    /// The code emitted before the current function or stub, with the calls and references
+    /// between them still to be linked.
+    pieces: Vec<Piece>,
     pub module_context: &'module M,
     pub op_offset_map: Vec<(AssemblyOffset, Box<dyn Display + Send + Sync>)>,
     labels: Labels,
-    func_starts: Vec<(Option<AssemblyOffset>, DynamicLabel)>,
+    func_starts: IndexVec<DefinedFuncIndex, (Option<AssemblyOffset>, DynamicLabel)>,
+    trampolines: IndexVec<TrampolineIndex, AssemblyOffset>,
+    exit_stubs: HashMap<ImportedFuncIndex, TrampolineIndex>,
+    unwind: IndexVec<DefinedFuncIndex, FunctionUnwind>,
+    trampoline_unwind: IndexVec<TrampolineIndex, FunctionUnwind>,
+    stats: IndexVec<DefinedFuncIndex, FunctionStats>,
+    operator_ranges: IndexVec<DefinedFuncIndex, Vec<OperatorRange>>,
+    coverage_guards: CoverageGuards,
+    breakpoints: Breakpoints,
+    trap_sites: Vec<TrapSite>,
+    lazy_slots: HashMap<DefinedFuncIndex, AssemblyOffset>,
+    hotness_counters: HotnessCounters,
+    osr_points: Vec<OsrPoint>,
+    inline_bodies: Arc<InlineBodies>,
+    static_table: StaticTable,
+    bound_table_elements: HashSet<u32>,
+    options: CodeGenOptions,
+    metrics: Option<Arc<dyn CompilationMetrics>>,
+    call_linkage: CallLinkage,
+}
+
+/// The `Context` for `$func_idx` in `$session`, emitting into `$asm`. This is a macro rather
+/// than a method so that `$asm` can borrow from `$session` too.
+macro_rules! context {
+    ($session:ident, $func_idx:ident, $reloc_sink:expr, $asm:expr) => {
+        Context {
+            asm: Peephole::new($asm),
+            current_function: $func_idx,
+            reloc_sink: $reloc_sink,
+            func_starts: &$session.func_starts,
+            labels: &mut $session.labels,
+            block_state: Default::default(),
+            module_context: $session.module_context,
+            frame_pointer: $session.options.frame_pointer,
+            unwind: &mut $session.unwind[$func_idx],
+            stats: &mut $session.stats[$func_idx],
+            operator_ranges: if $session.options.record_operator_ranges {
+                Some(&mut $session.operator_ranges[$func_idx])
+            } else {
+                None
+            },
+            coverage: $session.options.coverage,
+            trace_hooks: $session.options.trace_hooks,
+            exit_label: None,
+            trap_labels: Vec::new(),
+            trap_sites: &mut $session.trap_sites,
+            loops: 0,
+            breakpoint_hook: $session.options.debug.map(|debug| debug.breakpoint_hook),
+            breakpoints: &mut $session.breakpoints,
+            deterministic: $session.options.deterministic,
+            trap_on_overflow: $session.options.trap_on_overflow,
+            cet: $session.options.cet,
+            callee_saved: &[],
+            local_uses: vec![],
+            out_of_registers: false,
+            spill_slots: 0..0,
+            size_limits: $session.options.size_limits,
+            coverage_guards: &mut $session.coverage_guards,
+            tiering: $session.options.tiering,
+            profile_operators: $session.options.profile_operators,
+            hotness_counter: $session.hotness_counters.counter($func_idx),
+            osr_points: &mut $session.osr_points,
+            static_table: &$session.static_table,
+            bound_table_elements: &mut $session.bound_table_elements,
+            call_linkage: &$session.call_linkage,
+        }
+    };
 }
 
 impl<'module, M> CodeGenSession<'module, M> {
     pub fn new(func_count: u32, module_context: &'module M) -> Self {
-        let mut assembler = Assembler::new().unwrap();
-        let func_starts = iter::repeat_with(|| (None, assembler.new_dynamic_label()))
+        let mut assembler = VecAssembler::new();
+        let func_starts = iter::repeat_with(|| (None, assembler.new_label()))
             .take(func_count as usize)
-            .collect::<Vec<_>>();
+            .collect();
 
         CodeGenSession {
             assembler,
+            pieces: vec![],
             op_offset_map: Default::default(),
             labels: Default::default(),
+            unwind: iter::repeat_with(Default::default)
+                .take(func_count as usize)
+                .collect(),
+            stats: iter::repeat_with(Default::default)
+                .take(func_count as usize)
+                .collect(),
+            operator_ranges: iter::repeat_with(Default::default)
+                .take(func_count as usize)
+                .collect(),
             func_starts,
+            trampolines: IndexVec::new(),
+            exit_stubs: HashMap::new(),
+            trampoline_unwind: IndexVec::new(),
+            coverage_guards: CoverageGuards::default(),
+            breakpoints: Breakpoints::default(),
+            trap_sites: Vec::new(),
+            lazy_slots: HashMap::new(),
+            hotness_counters: HotnessCounters::new(func_count),
+            osr_points: Vec::new(),
+            inline_bodies: Default::default(),
+            static_table: StaticTable::new(),
+            bound_table_elements: HashSet::new(),
             module_context,
+            options: CodeGenOptions::default(),
+            metrics: None,
+            call_linkage: CallLinkage::default(),
+        }
+    }
+
+    /// Set the options for functions translated from now on. All functions in a session
+    /// should use the same options.
+    pub fn set_options(&mut self, options: CodeGenOptions) {
+        assert!(
+            options.code_layout.function_alignment.is_power_of_two(),
+            "Function alignment must be a power of two"
+        );
+        assert!(
+            !(options.deterministic && options.coverage.is_some()),
+            "Coverage instrumentation embeds addresses, so it can't be deterministic"
+        );
+        assert!(
+            !(options.deterministic && options.trace_hooks.is_some()),
+            "Trace hooks are called by address, so they can't be deterministic"
+        );
+        assert!(
+            !(options.deterministic && options.debug.is_some()),
+            "Breakpoint hooks are called by address, so they can't be deterministic"
+        );
+        assert!(
+            !(options.deterministic && options.tiering.is_some()),
+            "Hotness counters are referred to by address, so they can't be deterministic"
+        );
+        assert!(
+            !(options.cet && options.debug.is_some()),
+            "Breakpoint sites don't return to their call, so they can't use a shadow stack"
+        );
+        assert!(
+            options
+                .tiering
+                .map_or(true, |tiering| tiering.threshold > 0),
+            "The tier-up threshold must be positive"
+        );
+        self.options = options;
+    }
+
+    /// Whether functions translated from now on have breakpoints, at which any local can be
+    /// read.
+    pub(crate) fn has_breakpoints(&self) -> bool {
+        self.options.debug.is_some()
+    }
+
+    /// Whether functions translated from now on count the operators that they run.
+    pub(crate) fn profiles_operators(&self) -> bool {
+        self.options.profile_operators
+    }
+
+    /// The cost model that functions translated from now on are weighed with.
+    pub(crate) fn cost_model(&self) -> CostModel {
+        self.options.cost_model
+    }
+
+    /// Inline calls to the functions in `inline_bodies` in functions translated from now on.
+    pub(crate) fn set_inline_bodies(&mut self, inline_bodies: InlineBodies) {
+        self.inline_bodies = Arc::new(inline_bodies);
+    }
+
+    pub(crate) fn inline_bodies(&self) -> Arc<InlineBodies> {
+        self.inline_bodies.clone()
+    }
+
+    /// Bind `call_indirect` with a constant index to the elements of `static_table` in
+    /// functions translated from now on. Every function in the module has to be translated
+    /// in this session, since the calls go straight to their labels.
+    pub(crate) fn set_static_table(&mut self, static_table: StaticTable) {
+        self.static_table = static_table;
+    }
+
+    /// Report on every function translated from now on to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn CompilationMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Link calls between the module's functions translated from now on as `linkage` says.
+    pub fn set_call_linkage(&mut self, linkage: CallLinkage) {
+        self.call_linkage = linkage;
+    }
+
+    pub(crate) fn function_translated(&self, func_idx: DefinedFuncIndex, time: Duration) {
+        log_debug!(
+            "Translated function {} in {:?}: {:?}",
+            func_idx,
+            time,
+            self.stats[func_idx]
+        );
+
+        if let Some(metrics) = &self.metrics {
+            metrics.function_translated(func_idx, time, &self.stats[func_idx]);
         }
     }
 
+    /// Finish the code emitted so far and assemble whatever comes next into a new buffer.
+    fn start_piece(&mut self) {
+        let base = self.assembler.offset().0;
+        let piece = mem::replace(&mut self.assembler, VecAssembler::starting_at(base)).finish();
+        self.pieces.push(piece);
+    }
+
     pub fn new_context<'this>(
         &'this mut self,
-        func_idx: u32,
+        func_idx: DefinedFuncIndex,
         reloc_sink: &'this mut dyn binemit::RelocSink,
     ) -> Context<'this, M> {
+        self.start_piece();
         {
-            let func_start = &mut self.func_starts[func_idx as usize];
+            let func_start = &mut self.func_starts[func_idx];
+
+            dynasm!(self.assembler
+                ; .align self.options.code_layout.function_alignment as usize
+            );
 
             // At this point we know the exact start address of this function. Save it
             // and define dynamic label at this location.
             func_start.0 = Some(self.assembler.offset());
             self.assembler.dynamic_label(func_start.1);
+            if self.options.cet {
+                emit_endbr64(&mut self.assembler);
+            }
         }
 
-        Context {
-            asm: &mut self.assembler,
-            current_function: func_idx,
-            reloc_sink,
-            func_starts: &self.func_starts,
-            labels: &mut self.labels,
-            block_state: Default::default(),
-            module_context: self.module_context,
+        context!(self, func_idx, reloc_sink, &mut self.assembler)
+    }
+
+    /// Like `new_context`, but emitting into `asm` rather than this session's assembler, so
+    /// that tests can see which instructions the backend emits for a function.
+    #[cfg(test)]
+    pub(crate) fn new_context_with<'this, E: Emitter>(
+        &'this mut self,
+        func_idx: DefinedFuncIndex,
+        reloc_sink: &'this mut dyn binemit::RelocSink,
+        asm: &'this mut E,
+    ) -> Context<'this, M, E> {
+        self.func_starts[func_idx].0 = Some(asm.offset());
+        context!(self, func_idx, reloc_sink, asm)
+    }
+
+    /// Emit a stub through which wasm code calls the host function imported as `import`. See
+    /// `emit_exit_stub`.
+    pub fn exit_stub(
+        &mut self,
+        import: ImportedFuncIndex,
+        params: impl IntoIterator<Item = SignlessType>,
+        func_offset: i32,
+        trapped_offset: i32,
+        trap_sp_offset: i32,
+    ) -> TrampolineIndex {
+        self.start_piece();
+        dynasm!(self.assembler
+            ; .align self.options.code_layout.function_alignment as usize
+        );
+        let start = self.assembler.offset();
+        let index = self.trampolines.push(start);
+        self.exit_stubs.insert(import, index);
+        if self.options.cet {
+            emit_endbr64(&mut self.assembler);
+        }
+
+        let unwind = emit_exit_stub(
+            &mut self.assembler,
+            start,
+            params,
+            func_offset,
+            trapped_offset,
+            trap_sp_offset,
+        );
+        self.trampoline_unwind.push(unwind);
+
+        index
+    }
+
+    /// Emit a stub in place of the given function, which calls the function at
+    /// `translate_offset` in the `VmCtx` to translate it the first time that it's called and
+    /// then jumps to the result. The stub finds the function's code through a slot after it,
+    /// which `TranslatedCodeSection::set_lazy_target` fills in once the function has been
+    /// translated, so that later calls just jump there. The translation function is called
+    /// with the `VmCtx` and the function's index, and returns the address of its code or
+    /// null if it can't be translated, in which case the stub traps.
+    pub fn lazy_stub(&mut self, func_idx: DefinedFuncIndex, translate_offset: i32) {
+        let float_args_size = (FLOAT_ARGS_IN_GPRS.len() * WORD_SIZE as usize) as i32;
+        // The `VmCtx`, the integer arguments and the float arguments
+        let saved_size =
+            (1 + INTEGER_ARGS_IN_GPRS.len()) as i32 * WORD_SIZE as i32 + float_args_size;
+        let slot = self.assembler.new_label();
+        let translate = self.assembler.new_label();
+        let trap = self.assembler.new_label();
+
+        self.start_piece();
+        dynasm!(self.assembler
+            ; .align self.options.code_layout.function_alignment as usize
+        );
+        let start = self.assembler.offset();
+        self.func_starts[func_idx].0 = Some(start);
+        self.assembler.dynamic_label(self.func_starts[func_idx].1);
+        if self.options.cet {
+            emit_endbr64(&mut self.assembler);
+        }
+
+        dynasm!(self.assembler
+            ; mov rax, [=>slot]
+            ; test rax, rax
+            ; jz =>translate
+            ; jmp rax
+            ; =>translate
+            ; push rbp
+            ; mov rbp, rsp
+            ; push Rq(VMCTX)
+        );
+        for reg in INTEGER_ARGS_IN_GPRS {
+            dynasm!(self.assembler
+                ; push Rq(reg.rq().unwrap())
+            );
+        }
+        dynasm!(self.assembler
+            ; sub rsp, float_args_size
+        );
+        for (i, reg) in FLOAT_ARGS_IN_GPRS.iter().enumerate() {
+            dynasm!(self.assembler
+                ; movq [rsp + i as i32 * WORD_SIZE as i32], Rx(reg.rx().unwrap())
+            );
+        }
+        dynasm!(self.assembler
+            ; and rsp, -16
+            ; mov esi, func_idx.0 as i32
+            ; call QWORD [Rq(VMCTX) + translate_offset]
+            ; lea rsp, [rbp - saved_size]
+        );
+        for (i, reg) in FLOAT_ARGS_IN_GPRS.iter().enumerate() {
+            dynasm!(self.assembler
+                ; movq Rx(reg.rx().unwrap()), [rsp + i as i32 * WORD_SIZE as i32]
+            );
+        }
+        dynasm!(self.assembler
+            ; add rsp, float_args_size
+        );
+        for reg in INTEGER_ARGS_IN_GPRS.iter().rev() {
+            dynasm!(self.assembler
+                ; pop Rq(reg.rq().unwrap())
+            );
         }
+        dynasm!(self.assembler
+            ; pop Rq(VMCTX)
+            ; pop rbp
+            ; test rax, rax
+            ; jz =>trap
+            ; jmp rax
+            ; =>trap
+            ; ud2
+            ; .align WORD_SIZE as usize
+        );
+        self.lazy_slots.insert(func_idx, self.assembler.offset());
+        dynasm!(self.assembler
+            ; =>slot
+            ; .qword 0
+        );
+
+        self.stats[func_idx].code_size = self.assembler.offset().0 - start.0;
     }
 
     fn finalize(&mut self) {
+        log_debug!("Emitting {} pieces of out-of-line code", self.labels.len());
+        self.start_piece();
+
+        // Sort by creation order within each alignment rather than by the map's order, so
+        // that the layout doesn't change from run to run.
         let mut values = self.labels.values_mut().collect::<Vec<_>>();
-        values.sort_unstable_by_key(|(_, align, _)| *align);
-        for (label, align, func) in values {
+        values.sort_unstable_by_key(|(_, align, order, _)| (*align, *order));
+        for (label, align, _, func) in values {
             if let Some(mut func) = func.take() {
                 dynasm!(self.assembler
                     ; .align *align as usize
@@ -577,20 +1376,56 @@ impl<'module, M> CodeGenSession<'module, M> {
         }
     }
 
+    /// Statistics about the given function, once it's been translated.
+    pub fn function_stats(&self, idx: DefinedFuncIndex) -> &FunctionStats {
+        &self.stats[idx]
+    }
+
     pub fn into_translated_code_section(mut self) -> Result<TranslatedCodeSection, Error> {
         self.finalize();
-        let exec_buf = self
-            .assembler
-            .finalize()
-            .map_err(|_asm| Error::Assembler("assembler error".to_owned()))?;
+        let size = self.assembler.offset().0;
+        let limit = self.options.size_limits.module_size_limit();
+        if size > limit {
+            return Err(Error::ModuleTooLarge { size, limit });
+        }
+        log_debug!(
+            "Generated {} bytes of code for {} functions and {} trampolines",
+            size,
+            self.func_starts.len(),
+            self.trampolines.len()
+        );
+        // Put the functions, the stubs and the out-of-line code together, resolving the calls
+        // and jumps between them
+        let pieces = self
+            .pieces
+            .into_iter()
+            .chain(iter::once(self.assembler.finish()));
+        let code = emitter::link(pieces)?;
+        let exec_buf = CodeBuffer::new(&code, self.options.code_layout.huge_pages)?;
+        // Functions that weren't translated in this session, like all but one when they're
+        // translated lazily, are left empty at the end of the code
         let func_starts = self
             .func_starts
-            .iter()
-            .map(|(offset, _)| offset.unwrap())
-            .collect::<Vec<_>>();
+            .map(|(offset, _)| offset.unwrap_or(AssemblyOffset(size)));
+        if let Some(init) = self.options.coverage.and_then(|c| c.trace_pc_guard_init) {
+            self.coverage_guards.init(init);
+        }
         Ok(TranslatedCodeSection {
             exec_buf,
             func_starts,
+            trampolines: self.trampolines,
+            exit_stubs: self.exit_stubs,
+            unwind: self.unwind,
+            trampoline_unwind: self.trampoline_unwind,
+            stats: self.stats,
+            operator_ranges: self.operator_ranges,
+            coverage_guards: self.coverage_guards,
+            breakpoints: self.breakpoints,
+            trap_sites: self.trap_sites,
+            lazy_slots: self.lazy_slots,
+            hotness_counters: self.hotness_counters,
+            osr_points: self.osr_points,
+            bound_table_elements: self.bound_table_elements,
             op_offset_map: self.op_offset_map,
             // TODO
             relocatable_accesses: vec![],
@@ -611,51 +1446,534 @@ struct RelocateAccess {
     address: RelocateAddress,
 }
 
-pub struct TranslatedCodeSection {
-    exec_buf: ExecutableBuffer,
-    func_starts: Vec<AssemblyOffset>,
-    relocatable_accesses: Vec<RelocateAccess>,
-    op_offset_map: Vec<(AssemblyOffset, Box<dyn Display + Send + Sync>)>,
-}
+/// Emit the body of a stub through which wasm code calls a host function. Wasm code calls
+/// the stub with a host context in place of the `VmCtx`, which holds the address of the host
+/// function at `func_offset` and a flag at `trapped_offset` that the host function sets to
+/// make the stub trap once it returns. The stub realigns the stack for the host, since wasm
+/// code doesn't keep it aligned to 16 bytes as the System V ABI requires, copying any
+/// arguments passed on the stack. Returns where the caller's frame is at each point in the
+/// stub, which begins at `start`.
+///
+/// The host context also holds, at `trap_sp_offset`, the address of the stack pointer that
+/// the `EntryStub` that the wasm code was called through saved. Trapping returns from the
+/// entry stub with that stack pointer, skipping the wasm frames, or executes `ud2` if the
+/// code wasn't called through one.
+fn emit_exit_stub(
+    asm: &mut VecAssembler,
+    start: AssemblyOffset,
+    params: impl IntoIterator<Item = SignlessType>,
+    func_offset: i32,
+    trapped_offset: i32,
+    trap_sp_offset: i32,
+) -> FunctionUnwind {
+    let num_stack_args = arg_locs(params)
+        .into_iter()
+        .filter(|loc| match loc {
+            CCLoc::Stack(_) => true,
+            CCLoc::Reg(_) => false,
+        })
+        .count() as i32;
+    let trap = asm.new_label();
+    let fatal = asm.new_label();
+    // The stub may start with an `endbr64`, which doesn't touch the stack
+    let mut unwind = FunctionUnwind {
+        len: 0,
+        rows: vec![UnwindRow {
+            offset: 0,
+            cfa_offset: WORD_SIZE,
+            cfa_rbp: false,
+            rbp_saved: false,
+            saved_regs: 0,
+        }],
+    };
+    let mut row = |asm: &VecAssembler, cfa_offset, cfa_rbp, rbp_saved| {
+        unwind.push(UnwindRow {
+            offset: (asm.offset().0 - start.0) as u32,
+            cfa_offset,
+            cfa_rbp,
+            rbp_saved,
+            saved_regs: 0,
+        })
+    };
 
-impl TranslatedCodeSection {
-    pub fn func_start(&self, idx: usize) -> *const u8 {
-        let offset = self.func_starts[idx];
-        self.exec_buf.ptr(offset)
+    dynasm!(asm
+        ; push rbp
+    );
+    row(asm, 2 * WORD_SIZE, false, true);
+    dynasm!(asm
+        ; mov rbp, rsp
+    );
+    // From here on `rsp` is realigned, but `rbp` stays put
+    row(asm, 2 * WORD_SIZE, true, true);
+    dynasm!(asm
+        ; push Rq(VMCTX)
+        ; sub rsp, num_stack_args * WORD_SIZE as i32
+        ; and rsp, -16
+    );
+    for i in 0..num_stack_args {
+        dynasm!(asm
+            ; mov rax, [rbp + (i + 2) * WORD_SIZE as i32]
+            ; mov [rsp + i * WORD_SIZE as i32], rax
+        );
     }
+    dynasm!(asm
+        ; call QWORD [Rq(VMCTX) + func_offset]
+        ; mov Rq(VMCTX), [rbp - (WORD_SIZE as i32)]
+        ; cmp BYTE [Rq(VMCTX) + trapped_offset], 0
+        ; jne =>trap
+        ; mov rsp, rbp
+        ; pop rbp
+    );
+    row(asm, WORD_SIZE, false, false);
+    dynasm!(asm
+        ; ret
+        ; =>trap
+    );
+    row(asm, 2 * WORD_SIZE, true, true);
+    dynasm!(asm
+        ; mov rax, [Rq(VMCTX) + trap_sp_offset]
+        ; mov rax, [rax]
+        ; test rax, rax
+        ; jz =>fatal
+        // The entry stub's return address is at the saved stack pointer, and it takes 1 in
+        // `eax` to mean that the call trapped
+        ; mov rsp, rax
+        ; mov eax, 1
+        ; ret
+        ; =>fatal
+        ; ud2
+    );
+    unwind.len = (asm.offset().0 - start.0) as u32;
 
-    pub fn func_range(&self, idx: usize) -> std::ops::Range<usize> {
-        let end = self
-            .func_starts
-            .get(idx + 1)
-            .map(|i| i.0)
-            .unwrap_or(self.exec_buf.len());
+    unwind
+}
 
-        self.func_starts[idx].0..end
-    }
+/// An exit stub assembled on its own, for calling a host function that's stored in a table
+/// rather than imported, and so isn't known when the module is translated. See
+/// `emit_exit_stub`.
+pub struct HostStub {
+    buf: CodeBuffer,
+}
 
-    pub fn funcs<'a>(&'a self) -> impl Iterator<Item = std::ops::Range<usize>> + 'a {
-        (0..self.func_starts.len()).map(move |i| self.func_range(i))
-    }
+impl HostStub {
+    pub fn new(
+        params: impl IntoIterator<Item = SignlessType>,
+        func_offset: i32,
+        trapped_offset: i32,
+        trap_sp_offset: i32,
+    ) -> Result<Self, Error> {
+        let mut assembler = VecAssembler::new();
+        // The stub can be called from modules translated with or without `cet`, and
+        // `endbr64` is a `nop` where CET isn't enabled.
+        emit_endbr64(&mut assembler);
+        emit_exit_stub(
+            &mut assembler,
+            AssemblyOffset(0),
+            params,
+            func_offset,
+            trapped_offset,
+            trap_sp_offset,
+        );
 
-    pub fn buffer(&self) -> &[u8] {
-        &*self.exec_buf
+        let buf = assembler.finalize()?;
+        Ok(HostStub {
+            buf: CodeBuffer::new(&buf, false)?,
+        })
     }
 
-    pub fn disassemble(&self) {
-        crate::disassemble::disassemble(&*self.exec_buf, &self.op_offset_map).unwrap();
+    pub fn ptr(&self) -> *const u8 {
+        self.buf.ptr(AssemblyOffset(0))
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct BlockState {
+/// What an `EntryStub` calls: a function that's passed `data` and calls wasm code, returning
+/// 0.
+pub type EntryCallback = unsafe extern "sysv64" fn(data: *mut u8) -> u32;
+
+/// A stub that the host calls wasm code through so that the code can trap, or a host function
+/// that it calls make it trap, without the trap being fatal. It saves the callee-saved
+/// registers and stores the stack pointer that its callback returns to at `trap_sp`, so that
+/// a trap stub or an exit stub can return straight to it with 1 in `eax`, skipping the frames
+/// in between. The previous
+/// value of `trap_sp` is restored once it returns, so that calls can nest.
+pub struct EntryStub {
+    buf: CodeBuffer,
+}
+
+impl EntryStub {
+    pub fn new() -> Result<Self, Error> {
+        let mut asm = VecAssembler::new();
+        // `rdi` is `trap_sp`, `rsi` the data and `rdx` the callback. The stack is aligned
+        // to 16 bytes once everything is pushed, so the callback's return address is just
+        // below it.
+        dynasm!(asm
+            ; push rbp
+            ; mov rbp, rsp
+            ; push rbx
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+            ; push rdi
+            ; push QWORD [rdi]
+            ; sub rsp, WORD_SIZE as i32
+            ; lea rax, [rsp - WORD_SIZE as i32]
+            ; mov [rdi], rax
+            ; mov rdi, rsi
+            ; call rdx
+            ; add rsp, WORD_SIZE as i32
+            ; pop rcx
+            ; pop rdi
+            ; mov [rdi], rcx
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbx
+            ; pop rbp
+            ; ret
+        );
+
+        let buf = asm.finalize()?;
+        Ok(EntryStub {
+            buf: CodeBuffer::new(&buf, false)?,
+        })
+    }
+
+    /// Call `callback` with `data`, returning whether the wasm code that it called trapped.
+    ///
+    /// # Safety
+    ///
+    /// `trap_sp` must be what the trap stubs and exit stubs of the wasm code find, and the frames between
+    /// the callback and the exit stub mustn't need dropping, since they're skipped.
+    pub unsafe fn call(
+        &self,
+        trap_sp: &AtomicUsize,
+        data: *mut u8,
+        callback: EntryCallback,
+    ) -> bool {
+        let stub: unsafe extern "sysv64" fn(*const AtomicUsize, *mut u8, EntryCallback) -> u32 =
+            mem::transmute(self.buf.ptr(AssemblyOffset(0)));
+        stub(trap_sp, data, callback) != 0
+    }
+}
+
+pub struct TranslatedCodeSection {
+    exec_buf: CodeBuffer,
+    func_starts: IndexVec<DefinedFuncIndex, AssemblyOffset>,
+    trampolines: IndexVec<TrampolineIndex, AssemblyOffset>,
+    exit_stubs: HashMap<ImportedFuncIndex, TrampolineIndex>,
+    unwind: IndexVec<DefinedFuncIndex, FunctionUnwind>,
+    trampoline_unwind: IndexVec<TrampolineIndex, FunctionUnwind>,
+    stats: IndexVec<DefinedFuncIndex, FunctionStats>,
+    operator_ranges: IndexVec<DefinedFuncIndex, Vec<OperatorRange>>,
+    coverage_guards: CoverageGuards,
+    breakpoints: Breakpoints,
+    trap_sites: Vec<TrapSite>,
+    /// Where the stub of each function that's translated lazily keeps the address of its
+    /// code. See `CodeGenSession::lazy_stub`.
+    lazy_slots: HashMap<DefinedFuncIndex, AssemblyOffset>,
+    hotness_counters: HotnessCounters,
+    osr_points: Vec<OsrPoint>,
+    /// The table elements that `call_indirect` was turned into direct calls to.
+    bound_table_elements: HashSet<u32>,
+    relocatable_accesses: Vec<RelocateAccess>,
+    op_offset_map: Vec<(AssemblyOffset, Box<dyn Display + Send + Sync>)>,
+}
+
+impl TranslatedCodeSection {
+    pub fn func_start(&self, idx: DefinedFuncIndex) -> *const u8 {
+        let offset = self.func_starts[idx];
+        self.exec_buf.ptr(offset)
+    }
+
+    /// The bytes of the given function, up to the start of whatever comes after it: the next
+    /// function, the trampolines, or the out-of-line code at the end of the section.
+    pub fn func_range(&self, idx: DefinedFuncIndex) -> std::ops::Range<usize> {
+        let end = self
+            .func_starts
+            .get(DefinedFuncIndex(idx.0 + 1))
+            .or_else(|| self.trampolines.get(TrampolineIndex(0)))
+            .map(|i| i.0)
+            .unwrap_or(self.exec_buf.len());
+
+        self.func_starts[idx].0..end
+    }
+
+    /// Every trap stub, in the order that they appear in the code.
+    pub fn trap_sites(&self) -> &[TrapSite] {
+        &self.trap_sites
+    }
+
+    /// Why the code traps if it traps at `offset`, or `None` if there's no trap stub there.
+    /// This is how a signal handler can tell what went wrong.
+    pub fn trap_code(&self, offset: usize) -> Option<ir::TrapCode> {
+        self.trap_sites
+            .binary_search_by_key(&offset, |site| site.offset)
+            .ok()
+            .map(|i| self.trap_sites[i].code)
+    }
+
+    /// Make the lazy stub of the given function jump straight to `target`, once the function
+    /// has been translated.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be the function's code, and stay valid as long as this code section.
+    pub(crate) unsafe fn set_lazy_target(&self, idx: DefinedFuncIndex, target: *const u8) {
+        let slot = self.lazy_slots[&idx];
+        self.exec_buf.patch_word(slot.0, target as u64);
+    }
+
+    /// Whether the given function was emitted as a lazy stub that hasn't jumped anywhere
+    /// yet, because the function hasn't been translated.
+    pub(crate) fn is_lazy_stub(&self, idx: DefinedFuncIndex) -> bool {
+        self.lazy_slots.get(&idx).map_or(false, |slot| {
+            let target = self.exec_buf.ptr(*slot) as *const AtomicU64;
+            unsafe { (*target).load(Ordering::SeqCst) == 0 }
+        })
+    }
+
+    pub fn trampoline(&self, idx: TrampolineIndex) -> *const u8 {
+        self.exec_buf.ptr(self.trampolines[idx])
+    }
+
+    /// The start of the stub for calling the given import, if it's a host function. See
+    /// `CodeGenSession::exit_stub`.
+    pub fn exit_stub(&self, import: ImportedFuncIndex) -> Option<*const u8> {
+        self.exit_stubs
+            .get(&import)
+            .map(|&idx| self.trampoline(idx))
+    }
+
+    pub fn funcs<'a>(&'a self) -> impl Iterator<Item = std::ops::Range<usize>> + 'a {
+        self.func_starts
+            .iter()
+            .map(move |(i, _)| self.func_range(i))
+    }
+
+    /// Where the caller's frame is at each point in the given function.
+    pub fn unwind_info(&self, idx: DefinedFuncIndex) -> &FunctionUnwind {
+        &self.unwind[idx]
+    }
+
+    /// An `.eh_frame` section describing every function and exit stub at its final address,
+    /// ready to be passed to `__register_frame`. The code section must outlive the
+    /// registration.
+    pub fn eh_frame(&self) -> Vec<u8> {
+        unwind::eh_frame(
+            self.unwind
+                .iter()
+                .map(|(i, unwind)| (self.func_start(i), unwind))
+                .chain(
+                    self.trampoline_unwind
+                        .iter()
+                        .map(|(i, unwind)| (self.trampoline(i), unwind)),
+                ),
+        )
+    }
+
+    pub fn function_stats(&self, idx: DefinedFuncIndex) -> &FunctionStats {
+        &self.stats[idx]
+    }
+
+    /// The number of machine instructions in the given function's body. This disassembles
+    /// the function, so it's relatively slow.
+    pub fn instruction_count(&self, idx: DefinedFuncIndex) -> Result<usize, Error> {
+        let start = self.func_starts[idx].0;
+        let body = &self.buffer()[start..start + self.stats[idx].code_size];
+        Ok(crate::disassemble::instruction_count(body)?)
+    }
+
+    /// The guard for every basic block, if the code was generated with a coverage hook.
+    pub fn coverage_guards(&self) -> &CoverageGuards {
+        &self.coverage_guards
+    }
+
+    /// How often each function has run, if the code was generated with
+    /// `CodeGenOptions::tiering`.
+    pub fn hotness_counters(&self) -> &HotnessCounters {
+        &self.hotness_counters
+    }
+
+    /// The header of every loop, in the order that they appear in the code. There are only
+    /// any if the code was generated with `CodeGenOptions::tiering`.
+    pub fn osr_points(&self) -> &[OsrPoint] {
+        &self.osr_points
+    }
+
+    /// The point at the header of the given loop in `func`, as passed to the tier-up hook.
+    pub fn osr_point(&self, func: DefinedFuncIndex, loop_index: u32) -> Option<&OsrPoint> {
+        self.osr_points
+            .iter()
+            .find(|point| point.func == func && point.loop_index == loop_index)
+    }
+
+    /// Whether a `call_indirect` was turned into a direct call to the function at `index` in
+    /// the table, so that changing the element wouldn't change what it calls.
+    pub fn is_bound_table_element(&self, index: u32) -> bool {
+        self.bound_table_elements.contains(&index)
+    }
+
+    /// Every breakpoint site, in the order that they appear in the code. There are only any
+    /// if the code was generated with `CodeGenOptions::debug`.
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        self.breakpoints.sites()
+    }
+
+    /// Enable or disable the breakpoint before the wasm instruction at `wasm_offset` in
+    /// `func`, returning whether there was one. Breakpoints can be set while the code is
+    /// running, including from the breakpoint hook, but a thread that's about to reach a
+    /// site as it's patched may or may not stop there.
+    pub fn set_breakpoint(
+        &self,
+        func: DefinedFuncIndex,
+        wasm_offset: usize,
+        enabled: bool,
+    ) -> bool {
+        self.breakpoints.set(
+            &self.exec_buf,
+            |site| site.func == func && site.wasm_offset == wasm_offset,
+            enabled,
+        ) > 0
+    }
+
+    /// Enable or disable every breakpoint, which is how a debugger single-steps.
+    pub fn set_all_breakpoints(&self, enabled: bool) {
+        self.breakpoints.set(&self.exec_buf, |_| true, enabled);
+    }
+
+    pub fn breakpoint_enabled(&self, breakpoint: &Breakpoint) -> bool {
+        self.breakpoints.is_enabled(&self.exec_buf, breakpoint)
+    }
+
+    /// Where each wasm value is when execution is stopped at `breakpoint`: the function's
+    /// locals, starting with its parameters, and then the operand stack from the bottom up.
+    /// Read them with `BreakpointFrame::read`.
+    pub fn value_locations(&self, breakpoint: &Breakpoint) -> &[DebugLocation] {
+        self.breakpoints.value_locations(breakpoint)
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &*self.exec_buf
+    }
+
+    /// A hash of the generated code and where each function and trampoline starts in it,
+    /// which is stable across processes and versions of Rust. Code generated with
+    /// `CodeGenOptions::deterministic` has the same hash whenever it's generated from the
+    /// same module with the same options, so this can be used to key a cache or to check that
+    /// two builds agree.
+    pub fn content_hash(&self) -> u64 {
+        // 64-bit FNV-1a
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let offsets = self
+            .func_starts
+            .iter()
+            .map(|(_, offset)| offset)
+            .chain(self.trampolines.iter().map(|(_, offset)| offset))
+            .flat_map(|offset| (offset.0 as u64).to_le_bytes().to_vec());
+
+        self.buffer()
+            .iter()
+            .cloned()
+            .chain(offsets)
+            .fold(OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+            })
+    }
+
+    /// Write the disassembly of the whole section to `out`. The code generated for each
+    /// microwasm operator is preceded by the operator and the offset in the module of the wasm
+    /// operator that it was translated from.
+    pub fn write_disassembly(&self, out: &mut dyn fmt::Write) -> Result<(), Error> {
+        crate::disassemble::disassemble(out, &*self.exec_buf, 0, &self.op_offset_map)
+    }
+
+    pub fn disassembly(&self) -> Result<String, Error> {
+        let mut out = String::new();
+        self.write_disassembly(&mut out)?;
+        Ok(out)
+    }
+
+    /// The disassembly of just the given function, in the same format as `disassembly` and
+    /// with addresses relative to the start of the section.
+    pub fn function_disassembly(&self, idx: DefinedFuncIndex) -> Result<String, Error> {
+        let range = self.func_range(idx);
+        let ops_start = self
+            .op_offset_map
+            .iter()
+            .position(|(offset, _)| offset.0 >= range.start)
+            .unwrap_or(self.op_offset_map.len());
+        let ops_end = self
+            .op_offset_map
+            .iter()
+            .position(|(offset, _)| offset.0 >= range.end)
+            .unwrap_or(self.op_offset_map.len());
+
+        let mut out = String::new();
+        crate::disassemble::disassemble(
+            &mut out,
+            &self.buffer()[range.clone()],
+            range.start as u64,
+            &self.op_offset_map[ops_start..ops_end],
+        )?;
+        Ok(out)
+    }
+
+    pub fn disassemble(&self) {
+        print!("{}", self.disassembly().unwrap());
+    }
+
+    /// The code generated for each operator in the given function, if the code was generated
+    /// with `CodeGenOptions::record_operator_ranges`.
+    pub fn operator_ranges(&self, idx: DefinedFuncIndex) -> &[OperatorRange] {
+        &self.operator_ranges[idx]
+    }
+
+    /// The microwasm for the given function, as `microwasm::dis` prints it, with the
+    /// instructions generated for each operator alongside it. Without
+    /// `CodeGenOptions::record_operator_ranges` this is just the instructions.
+    pub fn side_by_side(&self, idx: DefinedFuncIndex) -> Result<String, Error> {
+        let start = self.func_starts[idx].0;
+        let end = start + self.stats[idx].code_size;
+
+        let mut out = String::new();
+        crate::disassemble::side_by_side(
+            &mut out,
+            idx,
+            &self.buffer()[start..end],
+            start as u64,
+            &self.operator_ranges[idx],
+        )?;
+        Ok(out)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BlockState {
     pub stack: Stack,
     pub depth: StackDepth,
     pub regs: Registers,
+    /// Stack slots (counted in words from the starting value of SP, like `depth`) whose
+    /// values have been dropped and that can be overwritten instead of pushing a new slot.
+    free_slots: Vec<u32>,
+    /// Slots at or below this depth may be referred to by a calling convention, so they are
+    /// never reused.
+    slot_floor: u32,
 }
 
 type Stack = Vec<ValueLocation>;
 
+/// What a logic operation on a value that's known to be 0 or 1 and a constant is.
+enum BoolOp {
+    /// The value.
+    Keep(ValueLocation),
+    /// `i32.eqz` of the value.
+    Invert(ValueLocation),
+}
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 enum LabelValue {
     I32(i32),
@@ -675,18 +1993,59 @@ impl From<Value> for LabelValue {
 
 type Labels = HashMap<
     (u32, Either<TypeId, (LabelValue, Option<LabelValue>)>),
-    (Label, u32, Option<Box<dyn FnMut(&mut Assembler)>>),
+    (Label, u32, usize, Option<Box<dyn FnMut(&mut VecAssembler)>>),
 >;
 
-pub struct Context<'this, M> {
-    pub asm: &'this mut Assembler,
+pub struct Context<'this, M, E = VecAssembler> {
+    pub asm: Peephole<'this, E>,
     reloc_sink: &'this mut dyn binemit::RelocSink,
     module_context: &'this M,
-    current_function: u32,
-    func_starts: &'this Vec<(Option<AssemblyOffset>, DynamicLabel)>,
+    current_function: DefinedFuncIndex,
+    func_starts: &'this IndexVec<DefinedFuncIndex, (Option<AssemblyOffset>, DynamicLabel)>,
     /// Each push and pop on the value stack increments or decrements this value by 1 respectively.
     pub block_state: BlockState,
     labels: &'this mut Labels,
+    frame_pointer: FramePointer,
+    unwind: &'this mut FunctionUnwind,
+    coverage: Option<Coverage>,
+    coverage_guards: &'this mut CoverageGuards,
+    trace_hooks: Option<TraceHooks>,
+    /// Where this function returns through once the exit hook has been called, if there's
+    /// one and the function returns from anywhere but the end of its body.
+    exit_label: Option<Label>,
+    /// The stub for each reason that this function can trap for, which are emitted after
+    /// the function's body so that the checks don't break up the code that doesn't trap.
+    trap_labels: Vec<(ir::TrapCode, Label)>,
+    trap_sites: &'this mut Vec<TrapSite>,
+    /// The number of loops started so far in this function.
+    loops: u32,
+    breakpoint_hook: Option<BreakpointHook>,
+    breakpoints: &'this mut Breakpoints,
+    deterministic: bool,
+    trap_on_overflow: bool,
+    cet: bool,
+    /// The callee-saved registers that the prologue saved, which is none of them unless the
+    /// function makes calls.
+    callee_saved: &'static [GPR],
+    /// How much each local is used, from the bottom of the stack, which decides which of
+    /// them are kept in registers. Empty if it isn't known.
+    local_uses: Vec<u32>,
+    /// Set by `take_reg` when there wasn't a register to take, which fails translation.
+    out_of_registers: bool,
+    /// The stack slots that the prologue reserved for spilled values, counted like
+    /// `StackDepth`. Any of them that no value lives in can be spilled to in any block.
+    spill_slots: Range<u32>,
+    stats: &'this mut FunctionStats,
+    operator_ranges: Option<&'this mut Vec<OperatorRange>>,
+    size_limits: CodeSizeLimits,
+    tiering: Option<Tiering>,
+    profile_operators: bool,
+    /// This function's hotness counter, which is only used with `tiering`.
+    hotness_counter: *mut u64,
+    osr_points: &'this mut Vec<OsrPoint>,
+    static_table: &'this StaticTable,
+    bound_table_elements: &'this mut HashSet<u32>,
+    call_linkage: &'this CallLinkage,
 }
 
 /// Label in code.
@@ -717,7 +2076,7 @@ macro_rules! int_div {
 
             if let (Some(dividend), Some(divisor)) = (dividend.$imm_fn(), divisor.$imm_fn()) {
                 if divisor == 0 {
-                    self.trap();
+                    self.trap(ir::TrapCode::IntegerDivisionByZero);
                     self.push(ValueLocation::Immediate((0 as $unsigned_ty).into()));
                 } else {
                     self.push(ValueLocation::Immediate(
@@ -728,6 +2087,15 @@ macro_rules! int_div {
                 return;
             }
 
+            let power_of_two = divisor
+                .$imm_fn()
+                .filter(|&d| (d as $unsigned_ty).is_power_of_two())
+                .map(|d| d.trailing_zeros());
+            if let Some(shift) = power_of_two {
+                self.div_by_power_of_two(Type::for_::<$signed_ty>(), Signedness::Unsigned, dividend, shift);
+                return;
+            }
+
             let (div, rem, saved) = self.$full_div_u(divisor, dividend);
 
             self.free_value(rem);
@@ -735,7 +2103,7 @@ macro_rules! int_div {
             let div = match div {
                 ValueLocation::Reg(div)  => {
                     if saved.clone().any(|dst| dst == div) {
-                        let new = self.take_reg(I32).unwrap();
+                        let new = self.take_reg(I32);
                         dynasm!(self.asm
                             ; mov Rq(new.rq().unwrap()), Rq(div.rq().unwrap())
                         );
@@ -761,7 +2129,7 @@ macro_rules! int_div {
 
             if let (Some(dividend), Some(divisor)) = (dividend.$imm_fn(), divisor.$imm_fn()) {
                 if divisor == 0 {
-                    self.trap();
+                    self.trap(ir::TrapCode::IntegerDivisionByZero);
                     self.push(ValueLocation::Immediate((0 as $signed_ty).into()));
                 } else {
                     self.push(ValueLocation::Immediate(
@@ -772,6 +2140,15 @@ macro_rules! int_div {
                 return;
             }
 
+            let power_of_two = divisor
+                .$imm_fn()
+                .filter(|&d| d > 0 && (d as $unsigned_ty).is_power_of_two())
+                .map(|d| d.trailing_zeros());
+            if let Some(shift) = power_of_two {
+                self.div_by_power_of_two(Type::for_::<$signed_ty>(), Signedness::Signed, dividend, shift);
+                return;
+            }
+
             let (div, rem, saved) = self.$full_div_s(divisor, dividend);
 
             self.free_value(rem);
@@ -779,7 +2156,7 @@ macro_rules! int_div {
             let div = match div {
                 ValueLocation::Reg(div)  => {
                     if saved.clone().any(|dst| dst == div) {
-                        let new = self.take_reg(I32).unwrap();
+                        let new = self.take_reg(I32);
                         dynasm!(self.asm
                             ; mov Rq(new.rq().unwrap()), Rq(div.rq().unwrap())
                         );
@@ -803,7 +2180,7 @@ macro_rules! int_div {
 
             if let (Some(dividend), Some(divisor)) = (dividend.$imm_fn(), divisor.$imm_fn()) {
                 if divisor == 0 {
-                    self.trap();
+                    self.trap(ir::TrapCode::IntegerDivisionByZero);
                     self.push(ValueLocation::Immediate((0 as $unsigned_ty).into()));
                 } else {
                     self.push(ValueLocation::Immediate(
@@ -813,6 +2190,15 @@ macro_rules! int_div {
                 return;
             }
 
+            let power_of_two = divisor
+                .$imm_fn()
+                .filter(|&d| (d as $unsigned_ty).is_power_of_two())
+                .map(|d| d.trailing_zeros());
+            if let Some(shift) = power_of_two {
+                self.rem_by_power_of_two(Type::for_::<$signed_ty>(), Signedness::Unsigned, dividend, shift);
+                return;
+            }
+
             let (div, rem, saved) = self.$full_div_u(divisor, dividend);
 
             self.free_value(div);
@@ -820,7 +2206,7 @@ macro_rules! int_div {
             let rem = match rem {
                 ValueLocation::Reg(rem)  => {
                     if saved.clone().any(|dst| dst == rem) {
-                        let new = self.take_reg(I32).unwrap();
+                        let new = self.take_reg(I32);
                         dynasm!(self.asm
                             ; mov Rq(new.rq().unwrap()), Rq(rem.rq().unwrap())
                         );
@@ -844,7 +2230,7 @@ macro_rules! int_div {
 
             if let (Some(dividend), Some(divisor)) = (dividend.$imm_fn(), divisor.$imm_fn()) {
                 if divisor == 0 {
-                    self.trap();
+                    self.trap(ir::TrapCode::IntegerDivisionByZero);
                     self.push(ValueLocation::Immediate((0 as $signed_ty).into()));
                 } else {
                     self.push(ValueLocation::Immediate((dividend % divisor).into()));
@@ -852,6 +2238,15 @@ macro_rules! int_div {
                 return;
             }
 
+            let power_of_two = divisor
+                .$imm_fn()
+                .filter(|&d| d > 0 && (d as $unsigned_ty).is_power_of_two())
+                .map(|d| d.trailing_zeros());
+            if let Some(shift) = power_of_two {
+                self.rem_by_power_of_two(Type::for_::<$signed_ty>(), Signedness::Signed, dividend, shift);
+                return;
+            }
+
             let is_neg1 = self.create_label();
 
             let current_depth = self.block_state.depth.clone();
@@ -868,7 +2263,7 @@ macro_rules! int_div {
                     false
                 }
                 ValueLocation::Reg(_) => {
-                    let reg = self.into_reg(GPRType::Rq, &mut divisor).unwrap();
+                    let reg = self.into_reg(GPRType::Rq, &mut divisor);
                     dynasm!(self.asm
                         ; cmp $reg_ty(reg.rq().unwrap()), -1
                     );
@@ -906,7 +2301,7 @@ macro_rules! int_div {
             let rem = match rem {
                 ValueLocation::Reg(rem) => {
                     if saved.clone().any(|dst| dst == rem) {
-                        let new = self.take_reg(I32).unwrap();
+                        let new = self.take_reg(I32);
                         dynasm!(self.asm
                             ; mov Rq(new.rq().unwrap()), Rq(rem.rq().unwrap())
                         );
@@ -945,7 +2340,7 @@ macro_rules! int_div {
 
 macro_rules! unop {
     ($name:ident, $instr:ident, $reg_ty:tt, $typ:ty, $const_fallback:expr) => {
-        pub fn $name(&mut self) {
+        fn $name(&mut self) {
             let mut val = self.pop();
 
             let out_val = match val {
@@ -954,16 +2349,16 @@ macro_rules! unop {
                         ($const_fallback(imm.as_int().unwrap() as $typ) as $typ).into()
                     ),
                 ValueLocation::Stack(offset) => {
+                    let temp = self.take_reg(Type::for_::<$typ>());
                     let offset = self.adjusted_offset(offset);
-                    let temp = self.take_reg(Type::for_::<$typ>()).unwrap();
                     dynasm!(self.asm
                         ; $instr $reg_ty(temp.rq().unwrap()), [rsp + offset]
                     );
                     ValueLocation::Reg(temp)
                 }
                 ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                    let reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
-                    let temp = self.take_reg(Type::for_::<$typ>()).unwrap();
+                    let reg = self.into_reg(GPRType::Rq, &mut val);
+                    let temp = self.take_reg(Type::for_::<$typ>());
                     dynasm!(self.asm
                         ; $instr $reg_ty(temp.rq().unwrap()), $reg_ty(reg.rq().unwrap())
                     );
@@ -999,8 +2394,8 @@ macro_rules! conversion {
                         $const_fallback(imm.$const_ty_fn().unwrap()).into()
                     ),
                 ValueLocation::Stack(offset) => {
+                    let temp = self.take_reg(Type::for_::<$out_typ>());
                     let offset = self.adjusted_offset(offset);
-                    let temp = self.take_reg(Type::for_::<$out_typ>()).unwrap();
                     dynasm!(self.asm
                         ; $instr $out_reg_ty(temp.$out_reg_fn().unwrap()), [rsp + offset]
                     );
@@ -1008,8 +2403,8 @@ macro_rules! conversion {
                     ValueLocation::Reg(temp)
                 }
                 ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                    let reg = self.into_reg(Type::for_::<$in_typ>(), &mut val).unwrap();
-                    let temp = self.take_reg(Type::for_::<$out_typ>()).unwrap();
+                    let reg = self.into_reg(Type::for_::<$in_typ>(), &mut val);
+                    let temp = self.take_reg(Type::for_::<$out_typ>());
 
                     dynasm!(self.asm
                         ; $instr $out_reg_ty(temp.$out_reg_fn().unwrap()), $in_reg_ty(reg.$in_reg_fn().unwrap())
@@ -1036,7 +2431,7 @@ macro_rules! shift {
             if let Some(imm) = count.immediate() {
                 if let Some(imm) = imm.as_int() {
                     if let Ok(imm) = i8::try_from(imm) {
-                        let reg = self.into_temp_reg($ty, &mut val).unwrap();
+                        let reg = self.into_temp_reg($ty, &mut val);
 
                         dynasm!(self.asm
                             ; $instr $reg_ty(reg.rq().unwrap()), imm
@@ -1048,7 +2443,7 @@ macro_rules! shift {
             }
 
             if val == ValueLocation::Reg(RCX) {
-                let new = self.take_reg($ty).unwrap();
+                let new = self.take_reg($ty);
                 self.copy_value(val, CCLoc::Reg(new));
                 self.free_value(val);
                 val = ValueLocation::Reg(new);
@@ -1063,7 +2458,7 @@ macro_rules! shift {
                     let out = if self.block_state.regs.is_free(RCX) {
                         None
                     } else {
-                        let new_reg = self.take_reg(I32).unwrap();
+                        let new_reg = self.take_reg(I32);
                         dynasm!(self.asm
                             ; mov Rq(new_reg.rq().unwrap()), rcx
                         );
@@ -1072,7 +2467,7 @@ macro_rules! shift {
 
                     match other {
                         ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                            let gpr = self.into_reg(I32, &mut count).unwrap();
+                            let gpr = self.into_reg(I32, &mut count);
                             dynasm!(self.asm
                                 ; mov cl, Rb(gpr.rq().unwrap())
                             );
@@ -1098,7 +2493,7 @@ macro_rules! shift {
             self.block_state.regs.mark_used(RCX);
             count = ValueLocation::Reg(RCX);
 
-            let reg = self.into_temp_reg($ty, &mut val).unwrap();
+            let reg = self.into_temp_reg($ty, &mut val);
 
             dynasm!(self.asm
                 ; $instr $reg_ty(reg.rq().unwrap()), cl
@@ -1124,6 +2519,10 @@ macro_rules! cmp_i32 {
             let mut right = self.pop();
             let mut left = self.pop();
 
+            if self.compare_bool_with_zero($flags, left, right) {
+                return;
+            }
+
             let out = if let Some(i) = left.imm_i32() {
                 match right {
                     ValueLocation::Stack(offset) => {
@@ -1135,10 +2534,8 @@ macro_rules! cmp_i32 {
                         ValueLocation::Cond($reverse_flags)
                     }
                     ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                        let rreg = self.into_reg(I32, &mut right).unwrap();
-                        dynasm!(self.asm
-                            ; cmp Rd(rreg.rq().unwrap()), i
-                        );
+                        let rreg = self.into_reg(I32, &mut right);
+                        self.asm.cmp_ri(false, rreg.rq().unwrap(), i);
                         ValueLocation::Cond($reverse_flags)
                     }
                     ValueLocation::Immediate(right) => {
@@ -1151,8 +2548,20 @@ macro_rules! cmp_i32 {
                         )
                     }
                 }
+            } else if let (ValueLocation::Stack(offset), Some(i)) = (left, right.imm_i32()) {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp DWORD [rsp + offset], i
+                );
+                ValueLocation::Cond($flags)
+            } else if let (ValueLocation::Stack(offset), Some(rreg)) = (left, right.int_reg()) {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp DWORD [rsp + offset], Rd(rreg.rq().unwrap())
+                );
+                ValueLocation::Cond($flags)
             } else {
-                let lreg = self.into_reg(I32, &mut left).unwrap();
+                let lreg = self.into_reg(I32, &mut left);
 
                 match right {
                     ValueLocation::Stack(offset) => {
@@ -1162,15 +2571,11 @@ macro_rules! cmp_i32 {
                         );
                     }
                     ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                        let rreg = self.into_reg(I32, &mut right).unwrap();
-                        dynasm!(self.asm
-                            ; cmp Rd(lreg.rq().unwrap()), Rd(rreg.rq().unwrap())
-                        );
+                        let rreg = self.into_reg(I32, &mut right);
+                        self.asm.cmp_rr(false, lreg.rq().unwrap(), rreg.rq().unwrap());
                     }
                     ValueLocation::Immediate(i) => {
-                        dynasm!(self.asm
-                            ; cmp Rd(lreg.rq().unwrap()), i.as_i32().unwrap()
-                        );
+                        self.asm.cmp_ri(false, lreg.rq().unwrap(), i.as_i32().unwrap());
                     }
                 }
 
@@ -1200,7 +2605,7 @@ macro_rules! cmp_i64 {
                                 ; cmp QWORD [rsp + offset], i
                             );
                         } else {
-                            let lreg = self.into_reg(I32, &mut left).unwrap();
+                            let lreg = self.into_reg(I32, &mut left);
                             dynasm!(self.asm
                                 ; cmp QWORD [rsp + offset], Rq(lreg.rq().unwrap())
                             );
@@ -1208,16 +2613,12 @@ macro_rules! cmp_i64 {
                         ValueLocation::Cond($reverse_flags)
                     }
                     ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                        let rreg = self.into_reg(I32, &mut right).unwrap();
+                        let rreg = self.into_reg(I32, &mut right);
                         if let Some(i) = i.try_into() {
-                            dynasm!(self.asm
-                                ; cmp Rq(rreg.rq().unwrap()), i
-                            );
+                            self.asm.cmp_ri(true, rreg.rq().unwrap(), i);
                         } else {
-                            let lreg = self.into_reg(I32, &mut left).unwrap();
-                            dynasm!(self.asm
-                                ; cmp Rq(rreg.rq().unwrap()), Rq(lreg.rq().unwrap())
-                            );
+                            let lreg = self.into_reg(I32, &mut left);
+                            self.asm.cmp_rr(true, rreg.rq().unwrap(), lreg.rq().unwrap());
                         }
                         ValueLocation::Cond($reverse_flags)
                     }
@@ -1231,8 +2632,22 @@ macro_rules! cmp_i64 {
                         )
                     }
                 }
+            } else if let (ValueLocation::Stack(offset), Some(i)) =
+                (left, right.imm_i64().and_then(|i| i.try_into()))
+            {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp QWORD [rsp + offset], i
+                );
+                ValueLocation::Cond($flags)
+            } else if let (ValueLocation::Stack(offset), Some(rreg)) = (left, right.int_reg()) {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp QWORD [rsp + offset], Rq(rreg.rq().unwrap())
+                );
+                ValueLocation::Cond($flags)
             } else {
-                let lreg = self.into_reg(I64, &mut left).unwrap();
+                let lreg = self.into_reg(I64, &mut left);
 
                 match right {
                     ValueLocation::Stack(offset) => {
@@ -1242,22 +2657,16 @@ macro_rules! cmp_i64 {
                         );
                     }
                     ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                        let rreg = self.into_reg(I32, &mut right).unwrap();
-                        dynasm!(self.asm
-                            ; cmp Rq(lreg.rq().unwrap()), Rq(rreg.rq().unwrap())
-                        );
+                        let rreg = self.into_reg(I32, &mut right);
+                        self.asm.cmp_rr(true, lreg.rq().unwrap(), rreg.rq().unwrap());
                     }
                     ValueLocation::Immediate(i) => {
                         let i = i.as_i64().unwrap();
                         if let Some(i) = i.try_into() {
-                            dynasm!(self.asm
-                                    ; cmp Rq(lreg.rq().unwrap()), i
-                            );
+                            self.asm.cmp_ri(true, lreg.rq().unwrap(), i);
                         } else {
-                            let rreg = self.into_reg(I32, &mut right).unwrap();
-                            dynasm!(self.asm
-                                ; cmp Rq(lreg.rq().unwrap()), Rq(rreg.rq().unwrap())
-                            );
+                            let rreg = self.into_reg(I32, &mut right);
+                            self.asm.cmp_rr(true, lreg.rq().unwrap(), rreg.rq().unwrap());
                         }
                     }
                 }
@@ -1310,12 +2719,21 @@ macro_rules! eq_float {
                 _ =>  (right, left)
             };
 
-            let lreg = self.into_temp_reg(GPRType::Rx, &mut left).unwrap();
-            let rreg = self.into_reg(GPRType::Rx, &mut right).unwrap();
-            let out = self.take_reg(I32).unwrap();
+            let lreg = self.into_temp_reg(GPRType::Rx, &mut left);
+            if let ValueLocation::Stack(offset) = right {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; $instr Rx(lreg.rx().unwrap()), [rsp + offset]
+                );
+            } else {
+                let rreg = self.into_reg(GPRType::Rx, &mut right);
+                dynasm!(self.asm
+                    ; $instr Rx(lreg.rx().unwrap()), Rx(rreg.rx().unwrap())
+                );
+            }
+            let out = self.take_reg(I32);
 
             dynasm!(self.asm
-                ; $instr Rx(lreg.rx().unwrap()), Rx(rreg.rx().unwrap())
                 ; movd Rd(out.rq().unwrap()), Rx(lreg.rx().unwrap())
                 ; and Rd(out.rq().unwrap()), 1
             );
@@ -1356,8 +2774,8 @@ macro_rules! minmax_float {
                 _ =>  (right, left)
             };
 
-            let lreg = self.into_temp_reg(GPRType::Rx, &mut left).unwrap();
-            let rreg = self.into_reg(GPRType::Rx, &mut right).unwrap();
+            let lreg = self.into_temp_reg(GPRType::Rx, &mut left);
+            let rreg = self.into_reg(GPRType::Rx, &mut right);
 
             dynasm!(self.asm
                 ; $cmpinstr Rx(lreg.rx().unwrap()), Rx(rreg.rx().unwrap())
@@ -1404,8 +2822,8 @@ macro_rules! cmp_float {
                 ValueLocation::Immediate(0i32.into())
             }
         } else {
-            let lreg = this.into_reg(GPRType::Rx, left).unwrap();
-            let result = this.take_reg(I32).unwrap();
+            let lreg = this.into_reg(GPRType::Rx, left);
+            let result = this.take_reg(I32);
 
             match right {
                 ValueLocation::Stack(offset) => {
@@ -1418,7 +2836,7 @@ macro_rules! cmp_float {
                     );
                 }
                 right => {
-                    let rreg = this.into_reg(GPRType::Rx, right).unwrap();
+                    let rreg = this.into_reg(GPRType::Rx, right);
 
                     dynasm!(this.asm
                         ; xor Rq(result.rq().unwrap()), Rq(result.rq().unwrap())
@@ -1486,8 +2904,8 @@ macro_rules! binop_i32 {
             rq,
             I32,
             imm_i32,
-            |this: &mut Context<_>, op1: GPR, i| dynasm!(this.asm
-                ; $instr Rd(op1.rq().unwrap()), i
+            |this: &mut Context<_, _>, op1: GPR, i| emit_binop!(
+                @imm $instr, Rd, this, op1.rq().unwrap(), i
             )
         );
     };
@@ -1503,8 +2921,8 @@ macro_rules! commutative_binop_i32 {
             rq,
             I32,
             imm_i32,
-            |this: &mut Context<_>, op1: GPR, i| dynasm!(this.asm
-                ; $instr Rd(op1.rq().unwrap()), i
+            |this: &mut Context<_, _>, op1: GPR, i| emit_binop!(
+                @imm $instr, Rd, this, op1.rq().unwrap(), i
             )
         );
     };
@@ -1520,8 +2938,8 @@ macro_rules! binop_i64 {
             rq,
             I64,
             imm_i64,
-            |this: &mut Context<_>, op1: GPR, i| dynasm!(this.asm
-                ; $instr Rq(op1.rq().unwrap()), i
+            |this: &mut Context<_, _>, op1: GPR, i| emit_binop!(
+                @imm $instr, Rq, this, op1.rq().unwrap(), i
             )
         );
     };
@@ -1537,8 +2955,8 @@ macro_rules! commutative_binop_i64 {
             rq,
             I64,
             imm_i64,
-            |this: &mut Context<_>, op1: GPR, i| dynasm!(this.asm
-                ; $instr Rq(op1.rq().unwrap()), i
+            |this: &mut Context<_, _>, op1: GPR, i| emit_binop!(
+                @imm $instr, Rq, this, op1.rq().unwrap(), i
             )
         );
     };
@@ -1636,9 +3054,69 @@ macro_rules! commutative_binop {
     };
 }
 
+/// Whether an instruction that writes to a register of this kind zeroes its upper 32 bits,
+/// like every 32-bit operation does.
+macro_rules! zero_extends {
+    (Rd) => {
+        true
+    };
+    ($reg_ty:tt) => {
+        false
+    };
+}
+
+/// Emit `$instr $dst, $src` on `$this.asm`, where `$src` is a register or stack `Operand`, or
+/// `$instr $dst, $imm` with `@imm`. Integer `add`s are emitted as an `Instruction`, anything
+/// else with `dynasm!`.
+macro_rules! emit_binop {
+    (@imm add, $reg_ty:tt, $this:expr, $dst:expr, $imm:expr) => {
+        emit_binop!(add, $reg_ty, $this, $dst, Operand::Imm($imm))
+    };
+    (@imm $instr:ident, $reg_ty:tt, $this:expr, $dst:expr, $imm:expr) => {
+        dynasm!($this.asm
+            ; $instr $reg_ty($dst), $imm
+        )
+    };
+    (add, Rd, $this:expr, $dst:expr, $src:expr) => {
+        $this.asm.instruction(Instruction::Add {
+            wide: false,
+            dst: Operand::Reg($dst),
+            src: $src,
+        })
+    };
+    (add, Rq, $this:expr, $dst:expr, $src:expr) => {
+        $this.asm.instruction(Instruction::Add {
+            wide: true,
+            dst: Operand::Reg($dst),
+            src: $src,
+        })
+    };
+    ($instr:ident, $reg_ty:tt, $this:expr, $dst:expr, $src:expr) => {
+        match $src {
+            Operand::Reg(src) => dynasm!($this.asm
+                ; $instr $reg_ty($dst), $reg_ty(src)
+            ),
+            Operand::Stack(offset) => dynasm!($this.asm
+                ; $instr $reg_ty($dst), [rsp + offset]
+            ),
+            Operand::Imm(_) => unreachable!("immediates are emitted with `@imm`"),
+        }
+    };
+}
+
 macro_rules! binop {
     ($name:ident, $instr:ident, $const_fallback:expr, $reg_ty:tt, $reg_fn:ident, $ty:expr, $imm_fn:ident, $direct_imm:expr) => {
-        binop!($name, $instr, $const_fallback, $reg_ty, $reg_fn, $ty, $imm_fn, $direct_imm, |a, b| (a, b));
+        binop!(
+            $name,
+            $instr,
+            $const_fallback,
+            $reg_ty,
+            $reg_fn,
+            $ty,
+            $imm_fn,
+            $direct_imm,
+            |a, b| (a, b)
+        );
     };
     ($name:ident, $instr:ident, $const_fallback:expr, $reg_ty:tt, $reg_fn:ident, $ty:expr, $imm_fn:ident, $direct_imm:expr, $map_op:expr) => {
         pub fn $name(&mut self) {
@@ -1647,37 +3125,51 @@ macro_rules! binop {
 
             if let Some(i1) = left.$imm_fn() {
                 if let Some(i0) = right.$imm_fn() {
-                    self.block_state.stack.push(ValueLocation::Immediate($const_fallback(i1, i0).into()));
+                    self.block_state
+                        .stack
+                        .push(ValueLocation::Immediate($const_fallback(i1, i0).into()));
                     return;
                 }
             }
 
             let (mut left, mut right) = $map_op(left, right);
-            let lreg = self.into_temp_reg($ty, &mut left).unwrap();
+            let lreg = self.into_temp_reg($ty, &mut left);
 
             match right {
                 ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
                     // This handles the case where we (for example) have a float in an `Rq` reg
-                    let right_reg = self.into_reg($ty, &mut right).unwrap();
-                    dynasm!(self.asm
-                        ; $instr $reg_ty(lreg.$reg_fn().unwrap()), $reg_ty(right_reg.$reg_fn().unwrap())
+                    let right_reg = self.into_reg($ty, &mut right);
+                    emit_binop!(
+                        $instr,
+                        $reg_ty,
+                        self,
+                        lreg.$reg_fn().unwrap(),
+                        Operand::Reg(right_reg.$reg_fn().unwrap())
                     );
                 }
                 ValueLocation::Stack(offset) => {
                     let offset = self.adjusted_offset(offset);
-                    dynasm!(self.asm
-                        ; $instr $reg_ty(lreg.$reg_fn().unwrap()), [rsp + offset]
+                    emit_binop!(
+                        $instr,
+                        $reg_ty,
+                        self,
+                        lreg.$reg_fn().unwrap(),
+                        Operand::Stack(offset)
                     );
                 }
                 ValueLocation::Immediate(i) => {
                     if let Some(i) = i.as_int().and_then(|i| i.try_into()) {
                         $direct_imm(&mut *self, lreg, i);
                     } else {
-                        let scratch = self.take_reg($ty).unwrap();
+                        let scratch = self.take_reg($ty);
                         self.immediate_to_reg(scratch, i);
 
-                        dynasm!(self.asm
-                            ; $instr $reg_ty(lreg.$reg_fn().unwrap()), $reg_ty(scratch.$reg_fn().unwrap())
+                        emit_binop!(
+                            $instr,
+                            $reg_ty,
+                            self,
+                            lreg.$reg_fn().unwrap(),
+                            Operand::Reg(scratch.$reg_fn().unwrap())
                         );
 
                         self.block_state.regs.release(scratch);
@@ -1686,20 +3178,24 @@ macro_rules! binop {
             }
 
             self.free_value(right);
+            self.block_state
+                .regs
+                .set_zero_extended(lreg, zero_extends!($reg_ty));
             self.push(left);
         }
-    }
+    };
 }
 
 macro_rules! load {
     (@inner $name:ident, $rtype:expr, $reg_ty:tt, $emit_fn:expr) => {
-        pub fn $name(&mut self, offset: u32) {
-            fn load_to_reg<_M: ModuleContext>(
-                ctx: &mut Context<_M>,
+        pub fn $name(&mut self, memory_index: u32, offset: u32, bounds_checked: bool) {
+            fn load_to_reg<_M: ModuleContext, _E: Emitter>(
+                ctx: &mut Context<_M, _E>,
+                mem_index: u32,
+                bounds_checked: bool,
                 dst: GPR,
                 (offset, runtime_offset): (i32, Result<i32, GPR>)
             ) {
-                let mem_index = 0;
                 let reg_offset = ctx.module_context
                     .defined_memory_index(mem_index)
                     .map(|index| (
@@ -1720,47 +3216,8 @@ macro_rules! load {
 
                 let vmctx = GPR::Rq(VMCTX);
 
-                if ctx.module_context.emit_memory_bounds_check() {
-                    let trap_label = ctx.trap_label();
-                    let addr_reg = match runtime_offset {
-                        Ok(imm) => {
-                            let addr_reg = ctx.take_reg(I64).unwrap();
-                            dynasm!(ctx.asm
-                                ; mov Rq(addr_reg.rq().unwrap()), QWORD imm as i64 + offset as i64
-                            );
-                            addr_reg
-                        }
-                        Err(gpr) => {
-                            if offset == 0 {
-                                ctx.to_reg(I32, ValueLocation::Reg(gpr)).unwrap()
-                            } else if offset > 0 {
-                                let addr_reg = ctx.take_reg(I64).unwrap();
-                                dynasm!(ctx.asm
-                                    ; lea Rq(addr_reg.rq().unwrap()), [Rq(gpr.rq().unwrap()) + offset]
-                                );
-                                addr_reg
-                            } else {
-                                let addr_reg = ctx.take_reg(I64).unwrap();
-                                let offset_reg = ctx.take_reg(I64).unwrap();
-                                dynasm!(ctx.asm
-                                    ; mov Rd(offset_reg.rq().unwrap()), offset
-                                    ; mov Rq(addr_reg.rq().unwrap()), Rq(gpr.rq().unwrap())
-                                    ; add Rq(addr_reg.rq().unwrap()), Rq(offset_reg.rq().unwrap())
-                                );
-                                ctx.block_state.regs.release(offset_reg);
-                                addr_reg
-                            }
-                        }
-                    };
-                    dynasm!(ctx.asm
-                        ; cmp [
-                            Rq(reg.unwrap_or(vmctx).rq().unwrap()) +
-                                mem_offset +
-                                ctx.module_context.vmmemory_definition_current_length() as i32
-                        ], Rq(addr_reg.rq().unwrap())
-                        ; jna =>trap_label.0
-                    );
-                    ctx.block_state.regs.release(addr_reg);
+                if !bounds_checked {
+                    ctx.memory_bounds_check(mem_index, reg, mem_offset, (offset, runtime_offset));
                 }
 
                 let mem_ptr_reg = ctx.take_reg(I64).unwrap();
@@ -1778,21 +3235,15 @@ macro_rules! load {
                 ctx.block_state.regs.release(mem_ptr_reg);
             }
 
-            let base = self.pop();
+            let mut base = self.pop();
 
-            let temp = self.take_reg($rtype).unwrap();
+            let temp = self.take_reg($rtype);
 
-            match base {
-                ValueLocation::Immediate(i) => {
-                    load_to_reg(self, temp, (offset as _, Ok(i.as_i32().unwrap())));
-                }
-                mut base => {
-                    let gpr = self.into_reg(I32, &mut base).unwrap();
-                    load_to_reg(self, temp, (offset as _, Err(gpr)));
-                    self.free_value(base);
-                }
-            }
+            let address = self.memory_address(memory_index, &mut base, offset);
+            load_to_reg(self, memory_index, bounds_checked, temp, address);
+            self.free_value(base);
 
+            self.block_state.regs.set_zero_extended(temp, zero_extends!($reg_ty));
             self.push(ValueLocation::Reg(temp));
         }
     };
@@ -1801,7 +3252,7 @@ macro_rules! load {
             $name,
             $rtype,
             $reg_ty,
-            |ctx: &mut Context<_>, dst: GPR, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32| {
+            |ctx: &mut Context<_, _>, dst: GPR, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32| {
                 match runtime_offset {
                     Ok(imm) => {
                         dynasm!(ctx.asm
@@ -1822,7 +3273,7 @@ macro_rules! load {
             $name,
             $rtype,
             $reg_ty,
-            |ctx: &mut Context<_>, dst: GPR, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32| {
+            |ctx: &mut Context<_, _>, dst: GPR, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32| {
                 match (dst, runtime_offset) {
                     (GPR::Rq(r), Ok(imm)) => {
                         dynasm!(ctx.asm
@@ -1865,13 +3316,14 @@ macro_rules! load {
 
 macro_rules! store {
     (@inner $name:ident, $int_reg_ty:tt, $match_offset:expr, $size:ident) => {
-        pub fn $name(&mut self, offset: u32) {
-            fn store_from_reg<_M: ModuleContext>(
-                ctx: &mut Context<_M>,
+        pub fn $name(&mut self, memory_index: u32, offset: u32, bounds_checked: bool) {
+            fn store_from_reg<_M: ModuleContext, _E: Emitter>(
+                ctx: &mut Context<_M, _E>,
+                mem_index: u32,
+                bounds_checked: bool,
                 src: GPR,
                 (offset, runtime_offset): (i32, Result<i32, GPR>)
             ) {
-                let mem_index = 0;
                 let reg_offset = ctx.module_context
                     .defined_memory_index(mem_index)
                     .map(|index| (
@@ -1892,47 +3344,8 @@ macro_rules! store {
 
                 let vmctx = GPR::Rq(VMCTX);
 
-                if ctx.module_context.emit_memory_bounds_check() {
-                    let trap_label = ctx.trap_label();
-                    let addr_reg = match runtime_offset {
-                        Ok(imm) => {
-                            let addr_reg = ctx.take_reg(I64).unwrap();
-                            dynasm!(ctx.asm
-                                ; mov Rq(addr_reg.rq().unwrap()), QWORD imm as i64 + offset as i64
-                            );
-                            addr_reg
-                        }
-                        Err(gpr) => {
-                            if offset == 0 {
-                                ctx.to_reg(I32, ValueLocation::Reg(gpr)).unwrap()
-                            } else if offset > 0 {
-                                let addr_reg = ctx.take_reg(I64).unwrap();
-                                dynasm!(ctx.asm
-                                    ; lea Rq(addr_reg.rq().unwrap()), [Rq(gpr.rq().unwrap()) + offset]
-                                );
-                                addr_reg
-                            } else {
-                                let addr_reg = ctx.take_reg(I64).unwrap();
-                                let offset_reg = ctx.take_reg(I64).unwrap();
-                                dynasm!(ctx.asm
-                                    ; mov Rd(offset_reg.rq().unwrap()), offset
-                                    ; mov Rq(addr_reg.rq().unwrap()), Rq(gpr.rq().unwrap())
-                                    ; add Rq(addr_reg.rq().unwrap()), Rq(offset_reg.rq().unwrap())
-                                );
-                                ctx.block_state.regs.release(offset_reg);
-                                addr_reg
-                            }
-                        }
-                    };
-                    dynasm!(ctx.asm
-                        ; cmp Rq(addr_reg.rq().unwrap()), [
-                            Rq(reg.unwrap_or(vmctx).rq().unwrap()) +
-                                mem_offset +
-                                ctx.module_context.vmmemory_definition_current_length() as i32
-                        ]
-                        ; jae =>trap_label.0
-                    );
-                    ctx.block_state.regs.release(addr_reg);
+                if !bounds_checked {
+                    ctx.memory_bounds_check(mem_index, reg, mem_offset, (offset, runtime_offset));
                 }
 
                 let mem_ptr_reg = ctx.take_reg(I64).unwrap();
@@ -1951,32 +3364,23 @@ macro_rules! store {
                 ctx.block_state.regs.release(src);
             }
 
-            assert!(offset <= i32::max_value() as u32);
-
             let mut src = self.pop();
-            let base = self.pop();
+            let mut base = self.pop();
 
             // `store_from_reg` frees `src`
             // TODO: Would it be better to free it outside `store_from_reg`?
-            let src_reg = self.into_reg(None, &mut src).unwrap();
+            let src_reg = self.into_reg(None, &mut src);
 
-            match base {
-                ValueLocation::Immediate(i) => {
-                    store_from_reg(self, src_reg, (offset as i32, Ok(i.as_i32().unwrap())));
-                }
-                mut base => {
-                    let gpr = self.into_reg(I32, &mut base).unwrap();
-                    store_from_reg(self, src_reg, (offset as i32, Err(gpr)));
-                    self.free_value(base);
-                }
-            }
+            let address = self.memory_address(memory_index, &mut base, offset);
+            store_from_reg(self, memory_index, bounds_checked, src_reg, address);
+            self.free_value(base);
         }
     };
     ($name:ident, $int_reg_ty:tt, NONE, $size:ident) => {
         store!(@inner
             $name,
             $int_reg_ty,
-            |ctx: &mut Context<_>, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32, src| {
+            |ctx: &mut Context<_, _>, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32, src| {
                 let src_reg = ctx.into_temp_reg(GPRType::Rq, &mut ValueLocation::Reg(src)).unwrap();
 
                 match runtime_offset {
@@ -2001,7 +3405,7 @@ macro_rules! store {
         store!(@inner
             $name,
             $int_reg_ty,
-            |ctx: &mut Context<_>, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32, src| {
+            |ctx: &mut Context<_, _>, mem_ptr_reg: GPR, runtime_offset: Result<i32, GPR>, offset: i32, src| {
                 match (runtime_offset, src) {
                     (Ok(imm), GPR::Rq(r)) => {
                         dynasm!(ctx.asm
@@ -2067,14 +3471,22 @@ pub struct VirtualCallingConvention {
     pub depth: StackDepth,
 }
 
-impl<'this, M: ModuleContext> Context<'this, M> {
+impl<'this, M: ModuleContext, E: Emitter> Context<'this, M, E> {
+    /// Spill a value on the stack to free a register of the given type, returning false if
+    /// there's no value whose spilling would free one. The victim is the deepest value whose
+    /// register is only held by the stack, since the deepest values are used last, and a
+    /// register that's also held by an operand that was already popped stays in use however
+    /// many of its values are spilled.
     fn free_reg(&mut self, type_: GPRType) -> bool {
-        let pos = if let Some(pos) = self
-            .block_state
-            .stack
-            .iter()
-            .position(|r| r.reg().map(|reg| reg.type_() == type_).unwrap_or(false))
-        {
+        let stack = &self.block_state.stack;
+        let regs = &self.block_state.regs;
+        let pos = if let Some(pos) = stack.iter().position(|loc| match loc.reg() {
+            Some(reg) if reg.type_() == type_ => {
+                let held_by_stack = stack.iter().filter(|&other| other == loc).count();
+                held_by_stack == regs.num_usages(reg) as usize
+            }
+            _ => false,
+        }) {
             pos
         } else {
             return false;
@@ -2096,7 +3508,12 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         true
     }
 
-    fn take_reg(&mut self, r: impl Into<GPRType>) -> Option<GPR> {
+    /// Take a free register of the given type, spilling values on the stack until one is
+    /// free. This only fails if every register of the type is held by an operand that was
+    /// already popped, which happens when values are moved out of the stack into registers
+    /// of their own, as `serialize_args` does, and callers that can do without a register
+    /// use this to find out.
+    fn try_take_reg(&mut self, r: impl Into<GPRType>) -> Option<GPR> {
         let r = r.into();
         loop {
             if let Some(gpr) = self.block_state.regs.take(r) {
@@ -2109,6 +3526,25 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         }
     }
 
+    /// `try_take_reg`, for callers that need a register. No operator holds enough operands
+    /// for it to fail, but if it does then translation fails with `Error::OutOfRegisters`
+    /// once the operator is done, and one of the registers in use is shared in the meantime
+    /// so that the code that's thrown away can still be generated.
+    fn take_reg(&mut self, r: impl Into<GPRType>) -> GPR {
+        let r = r.into();
+        if let Some(gpr) = self.try_take_reg(r) {
+            return gpr;
+        }
+
+        self.out_of_registers = true;
+        let gpr = match r {
+            GPRType::Rq => RAX,
+            GPRType::Rx => XMM0,
+        };
+        self.block_state.regs.mark_used(gpr);
+        gpr
+    }
+
     pub fn virtual_calling_convention(&self) -> VirtualCallingConvention {
         VirtualCallingConvention {
             stack: self.block_state.stack.clone(),
@@ -2116,9 +3552,24 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         }
     }
 
+    /// The state after `serialize_args` returned `cc`, for a target that takes the values
+    /// where they are rather than in a calling convention of its own.
+    pub fn serialized_calling_convention(
+        &self,
+        cc: &BlockCallingConvention,
+    ) -> VirtualCallingConvention {
+        let mut stack = self.block_state.stack.clone();
+        stack.extend(cc.arguments.iter().map(|&loc| ValueLocation::from(loc)));
+
+        VirtualCallingConvention {
+            stack,
+            depth: cc.stack_depth,
+        }
+    }
+
     /// Create a new undefined label.
     pub fn create_label(&mut self) -> Label {
-        Label(self.asm.new_dynamic_label())
+        Label(self.asm.new_label())
     }
 
     pub fn define_host_fn(&mut self, host_fn: *const u8) {
@@ -2195,8 +3646,33 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     //       That would mean that `eqz` and `eq` with a const 0 argument don't
     //       result in different code. It would also allow us to generate better
     //       code for `neq` and `gt_u` with const 0 operand
-    pub fn i32_eqz(&mut self) {
-        let mut val = self.pop();
+    /// Set the zero flag to whether `val` is zero and free it. Values on the stack are
+    /// compared with 0 in place instead of being loaded first.
+    fn test_zero(&mut self, ty: SignlessType, mut val: ValueLocation) {
+        match val {
+            ValueLocation::Stack(offset) => {
+                let offset = self.adjusted_offset(offset);
+                if ty == I32 {
+                    dynasm!(self.asm
+                        ; cmp DWORD [rsp + offset], 0
+                    );
+                } else {
+                    dynasm!(self.asm
+                        ; cmp QWORD [rsp + offset], 0
+                    );
+                }
+            }
+            _ => {
+                let reg = self.into_reg(ty, &mut val);
+                self.asm.test(ty == I64, reg.rq().unwrap());
+            }
+        }
+
+        self.free_value(val);
+    }
+
+    pub fn i32_eqz(&mut self) {
+        let val = self.pop();
 
         if let ValueLocation::Immediate(Value::I32(i)) = val {
             self.push(ValueLocation::Immediate(
@@ -2210,22 +3686,16 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             return;
         }
 
-        let reg = self.into_reg(I32, &mut val).unwrap();
-        let out = self.take_reg(I32).unwrap();
-
-        dynasm!(self.asm
-            ; xor Rd(out.rq().unwrap()), Rd(out.rq().unwrap())
-            ; test Rd(reg.rq().unwrap()), Rd(reg.rq().unwrap())
-            ; setz Rb(out.rq().unwrap())
-        );
-
-        self.free_value(val);
+        // We leave the result in the flags so that a following `br_if` or `select`
+        // can consume it directly, it only gets materialized with `sete` if needed.
+        self.materialize_top_cond();
+        self.test_zero(I32, val);
 
-        self.push(ValueLocation::Reg(out));
+        self.push(ValueLocation::Cond(cc::EQUAL));
     }
 
     pub fn i64_eqz(&mut self) {
-        let mut val = self.pop();
+        let val = self.pop();
 
         if let ValueLocation::Immediate(Value::I64(i)) = val {
             self.push(ValueLocation::Immediate(
@@ -2239,53 +3709,47 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             return;
         }
 
-        let reg = self.into_reg(I64, &mut val).unwrap();
-        let out = self.take_reg(I64).unwrap();
-
-        dynasm!(self.asm
-            ; xor Rd(out.rq().unwrap()), Rd(out.rq().unwrap())
-            ; test Rq(reg.rq().unwrap()), Rq(reg.rq().unwrap())
-            ; setz Rb(out.rq().unwrap())
-        );
-
-        self.free_value(val);
+        // We leave the result in the flags so that a following `br_if` or `select`
+        // can consume it directly, it only gets materialized with `sete` if needed.
+        self.materialize_top_cond();
+        self.test_zero(I64, val);
 
-        self.push(ValueLocation::Reg(out));
+        self.push(ValueLocation::Cond(cc::EQUAL));
     }
 
     fn br_on_cond_code(&mut self, label: Label, cond: CondCode) {
-        match cond {
-            cc::EQUAL => dynasm!(self.asm
+        self.asm.jump(|asm| match cond {
+            cc::EQUAL => dynasm!(asm
                 ; je =>label.0
             ),
-            cc::NOT_EQUAL => dynasm!(self.asm
+            cc::NOT_EQUAL => dynasm!(asm
                 ; jne =>label.0
             ),
-            cc::GT_U => dynasm!(self.asm
+            cc::GT_U => dynasm!(asm
                 ; ja =>label.0
             ),
-            cc::GE_U => dynasm!(self.asm
+            cc::GE_U => dynasm!(asm
                 ; jae =>label.0
             ),
-            cc::LT_U => dynasm!(self.asm
+            cc::LT_U => dynasm!(asm
                 ; jb =>label.0
             ),
-            cc::LE_U => dynasm!(self.asm
+            cc::LE_U => dynasm!(asm
                 ; jbe =>label.0
             ),
-            cc::GT_S => dynasm!(self.asm
+            cc::GT_S => dynasm!(asm
                 ; jg =>label.0
             ),
-            cc::GE_S => dynasm!(self.asm
+            cc::GE_S => dynasm!(asm
                 ; jge =>label.0
             ),
-            cc::LT_S => dynasm!(self.asm
+            cc::LT_S => dynasm!(asm
                 ; jl =>label.0
             ),
-            cc::LE_S => dynasm!(self.asm
+            cc::LE_S => dynasm!(asm
                 ; jle =>label.0
             ),
-        }
+        });
     }
 
     /// Pops i32 predicate and branches to the specified label
@@ -2295,7 +3759,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         target: impl Into<BrTarget<Label>>,
         pass_args: impl FnOnce(&mut Self),
     ) {
-        let mut val = self.pop();
+        let val = self.pop();
         let label = target
             .into()
             .label()
@@ -2305,17 +3769,12 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         let cond = match val {
             ValueLocation::Cond(cc) => !cc,
             _ => {
-                let predicate = self.into_reg(I32, &mut val).unwrap();
-                dynasm!(self.asm
-                    ; test Rd(predicate.rq().unwrap()), Rd(predicate.rq().unwrap())
-                );
+                self.test_zero(I32, val);
 
                 CondCode::ZF0
             }
         };
 
-        self.free_value(val);
-
         pass_args(self);
 
         self.br_on_cond_code(label, cond);
@@ -2328,7 +3787,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         target: impl Into<BrTarget<Label>>,
         pass_args: impl FnOnce(&mut Self),
     ) {
-        let mut val = self.pop();
+        let val = self.pop();
         let label = target
             .into()
             .label()
@@ -2338,17 +3797,12 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         let cond = match val {
             ValueLocation::Cond(cc) => cc,
             _ => {
-                let predicate = self.into_reg(I32, &mut val).unwrap();
-                dynasm!(self.asm
-                    ; test Rd(predicate.rq().unwrap()), Rd(predicate.rq().unwrap())
-                );
+                self.test_zero(I32, val);
 
                 CondCode::ZF1
             }
         };
 
-        self.free_value(val);
-
         pass_args(self);
 
         self.br_on_cond_code(label, cond);
@@ -2364,7 +3818,13 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         }
     }
 
-    /// If `default` is `None` then the default is just continuing execution
+    /// If `default` is `None` then the default is just continuing execution.
+    ///
+    /// Tables where the targets change at most `MAX_BR_TABLE_COMPARES` times, which includes
+    /// every table with that many targets, are lowered to a compare and a conditional jump
+    /// for each run of indices with the same target. Anything bigger gets a jump table, which
+    /// takes the same time for any index but costs an indirect jump, which is hard to
+    /// predict.
     pub fn br_table<I>(
         &mut self,
         targets: I,
@@ -2375,7 +3835,6 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         I::IntoIter: ExactSizeIterator,
     {
         let mut targets = targets.into_iter();
-        let count = targets.len();
 
         let mut selector = self.pop();
 
@@ -2385,85 +3844,40 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             if let Some(target) = targets.nth(imm as _).or(Some(default)).and_then(|a| a) {
                 match target {
                     BrTarget::Label(label) => self.br(label),
-                    BrTarget::Return => {
-                        dynasm!(self.asm
-                            ; ret
-                        );
-                    }
+                    BrTarget::Return => self.ret(),
                 }
             }
         } else {
             let end_label = self.create_label();
-
-            if count > 0 {
-                let (selector_reg, pop_selector) = self
-                    .into_temp_reg(GPRType::Rq, &mut selector)
-                    .map(|r| (r, false))
-                    .unwrap_or_else(|| {
-                        self.push_physical(ValueLocation::Reg(RAX));
-                        self.block_state.regs.mark_used(RAX);
-                        (RAX, true)
-                    });
-
-                let (tmp, pop_tmp) = if let Some(reg) = self.take_reg(I64) {
-                    (reg, false)
-                } else {
-                    let out_reg = if selector_reg == RAX { RCX } else { RAX };
-
-                    self.push_physical(ValueLocation::Reg(out_reg));
-                    self.block_state.regs.mark_used(out_reg);
-
-                    (out_reg, true)
-                };
-
-                self.immediate_to_reg(tmp, (count as u32).into());
-                dynasm!(self.asm
-                    ; cmp Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
-                    ; cmova Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
-                    ; lea Rq(tmp.rq().unwrap()), [>start_label]
-                    ; lea Rq(selector_reg.rq().unwrap()), [
-                        Rq(selector_reg.rq().unwrap()) * 5
-                    ]
-                    ; add Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
-                );
-
-                if pop_tmp {
-                    dynasm!(self.asm
-                        ; pop Rq(tmp.rq().unwrap())
-                    );
-                } else {
-                    self.block_state.regs.release(tmp);
-                }
-
-                if pop_selector {
-                    dynasm!(self.asm
-                        ; pop Rq(selector_reg.rq().unwrap())
-                    );
-                }
-
-                dynasm!(self.asm
-                    ; jmp Rq(selector_reg.rq().unwrap())
-                ; start_label:
-                );
-
-                for target in targets {
-                    let label = target
+            let targets = targets
+                .map(|target| {
+                    target
                         .map(|target| self.target_to_label(target))
-                        .unwrap_or(end_label);
-                    dynasm!(self.asm
-                        ; jmp =>label.0
-                    );
+                        .unwrap_or(end_label)
+                })
+                .collect::<Vec<_>>();
+
+            // The last index of each run of indices with the same target
+            let mut runs: Vec<(u32, Label)> = vec![];
+            for (i, &label) in targets.iter().enumerate() {
+                match runs.last_mut() {
+                    Some((last, run_label)) if *run_label == label => *last = i as u32,
+                    _ => runs.push((i as u32, label)),
                 }
             }
 
+            let compared =
+                runs.len() <= MAX_BR_TABLE_COMPARES && self.br_table_compares(&mut selector, &runs);
+            if !compared && !targets.is_empty() {
+                self.br_table_jump_table(&mut selector, &targets);
+            }
+
             if let Some(def) = default {
                 match def {
                     BrTarget::Label(label) => dynasm!(self.asm
                         ; jmp =>label.0
                     ),
-                    BrTarget::Return => dynasm!(self.asm
-                        ; ret
-                    ),
+                    BrTarget::Return => self.ret(),
                 }
             }
 
@@ -2473,24 +3887,118 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.free_value(selector);
     }
 
+    /// Jump to the label of each run of indices that `selector` is in, as listed by
+    /// `br_table`, falling through if it's past the end of the table. Returns false without
+    /// emitting anything if the selector would have to be moved to a register and there's
+    /// none free.
+    fn br_table_compares(&mut self, selector: &mut ValueLocation, runs: &[(u32, Label)]) -> bool {
+        if let ValueLocation::Stack(offset) = *selector {
+            let offset = self.adjusted_offset(offset);
+            for &(last, label) in runs {
+                dynasm!(self.asm
+                    ; cmp DWORD [rsp + offset], last as i32
+                    ; jbe =>label.0
+                );
+            }
+            return true;
+        }
+
+        let reg = self.into_reg(I32, selector);
+        // Earlier runs have already been ruled out, so each only needs comparing against its
+        // last index
+        for &(last, label) in runs {
+            dynasm!(self.asm
+                ; cmp Rd(reg.rq().unwrap()), last as i32
+                ; jbe =>label.0
+            );
+        }
+
+        true
+    }
+
+    /// Jump through a table of jumps to `targets`, or past its end if `selector` is out of
+    /// range.
+    fn br_table_jump_table(&mut self, selector: &mut ValueLocation, targets: &[Label]) {
+        let count = targets.len();
+
+        let selector_reg = self.into_temp_reg(GPRType::Rq, selector);
+
+        let (tmp, pop_tmp) = if let Some(reg) = self.try_take_reg(I64) {
+            (reg, false)
+        } else {
+            let out_reg = if selector_reg == RAX { RCX } else { RAX };
+
+            self.push_physical(ValueLocation::Reg(out_reg));
+            self.block_state.regs.mark_used(out_reg);
+
+            (out_reg, true)
+        };
+
+        self.immediate_to_reg(tmp, (count as u32).into());
+        dynasm!(self.asm
+            ; cmp Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
+            ; cmova Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
+            ; lea Rq(tmp.rq().unwrap()), [>start_label]
+        );
+        // Each entry is a `jmp rel32`, after an `endbr64` with `cet`
+        if self.cet {
+            dynasm!(self.asm
+                ; lea Rq(selector_reg.rq().unwrap()), [
+                    Rq(selector_reg.rq().unwrap()) * 9
+                ]
+            );
+        } else {
+            dynasm!(self.asm
+                ; lea Rq(selector_reg.rq().unwrap()), [
+                    Rq(selector_reg.rq().unwrap()) * 5
+                ]
+            );
+        }
+        dynasm!(self.asm
+            ; add Rq(selector_reg.rq().unwrap()), Rq(tmp.rq().unwrap())
+        );
+
+        if pop_tmp {
+            dynasm!(self.asm
+                ; pop Rq(tmp.rq().unwrap())
+            );
+        } else {
+            self.block_state.regs.release(tmp);
+        }
+
+        dynasm!(self.asm
+            ; jmp Rq(selector_reg.rq().unwrap())
+        ; start_label:
+        );
+
+        for label in targets {
+            if self.cet {
+                emit_endbr64(&mut self.asm);
+            }
+            dynasm!(self.asm
+                ; jmp =>label.0
+            );
+        }
+        // Out-of-range selectors land just past the table
+        if self.cet {
+            emit_endbr64(&mut self.asm);
+        }
+    }
+
     fn set_stack_depth(&mut self, depth: StackDepth) {
         if self.block_state.depth.0 != depth.0 {
             let diff = depth.0 as i32 - self.block_state.depth.0 as i32;
             let emit_lea = if diff.abs() == 1 {
                 if self.block_state.depth.0 < depth.0 {
                     for _ in 0..diff {
-                        dynasm!(self.asm
-                            ; push rax
-                        );
+                        self.asm.push_rq(RAX.rq().unwrap());
                     }
 
                     false
                 } else if self.block_state.depth.0 > depth.0 {
-                    if let Some(trash) = self.take_reg(I64) {
+                    if let Some(trash) = self.try_take_reg(I64) {
                         for _ in 0..self.block_state.depth.0 - depth.0 {
-                            dynasm!(self.asm
-                                ; pop Rq(trash.rq().unwrap())
-                            );
+                            self.asm.pop_discard(trash.rq().unwrap());
                         }
                         self.block_state.regs.release(trash);
 
@@ -2505,18 +4013,28 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 true
             };
 
+            let probed = emit_lea && self.probe_stack(depth);
             if emit_lea {
-                dynasm!(self.asm
-                    ; lea rsp, [rsp + (self.block_state.depth.0 as i32 - depth.0 as i32) * WORD_SIZE as i32]
+                self.asm.adjust_rsp(
+                    (self.block_state.depth.0 as i32 - depth.0 as i32) * WORD_SIZE as i32,
                 );
             }
 
-            self.block_state.depth = depth;
+            self.set_depth(depth);
+            if probed {
+                dynasm!(self.asm
+                    ; or DWORD [rsp], 0
+                );
+            }
+            self.trim_free_slots();
         }
     }
 
     fn do_pass_block_args(&mut self, cc: &BlockCallingConvention) {
         let args = &cc.arguments;
+        // The slots that arguments have been passed in, which mustn't be spilled to while the
+        // rest are passed
+        let mut passed_slots = vec![];
         for &dst in args.iter().rev().take(self.block_state.stack.len()) {
             if let CCLoc::Reg(r) = dst {
                 if !self.block_state.regs.is_free(r)
@@ -2529,8 +4047,41 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
                 self.block_state.regs.mark_used(r);
             }
+            if let CCLoc::Stack(offset) = dst {
+                passed_slots.push(-offset as u32);
+                self.block_state
+                    .free_slots
+                    .retain(|slot| !passed_slots.contains(slot));
+                self.vacate_stack_slot(offset);
+            }
             self.pop_into(dst);
+            self.block_state
+                .free_slots
+                .retain(|slot| !passed_slots.contains(slot));
+        }
+    }
+
+    /// Move any value under the top of the stack that lives in the slot at `offset`, other
+    /// than the top value itself, into a register, so that the slot can be overwritten.
+    fn vacate_stack_slot(&mut self, offset: i32) {
+        let slot = ValueLocation::Stack(offset);
+        let (top, rest) = match self.block_state.stack.split_last() {
+            Some((&top, rest)) => (top, rest),
+            None => return,
+        };
+        if top == slot || !rest.contains(&slot) {
+            return;
+        }
+
+        let reg = self.take_reg(I64);
+        self.copy_value(slot, CCLoc::Reg(reg));
+        for value in &mut self.block_state.stack {
+            if *value == slot {
+                *value = ValueLocation::Reg(reg);
+                self.block_state.regs.mark_used(reg);
+            }
         }
+        self.block_state.regs.release(reg);
     }
 
     pub fn pass_block_args(&mut self, cc: &BlockCallingConvention) {
@@ -2590,6 +4141,56 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         }
     }
 
+    /// Move the top values on the stack, which have types `params`, into the locations that
+    /// the block calling convention pins them to (see `BLOCK_GPRS`). Unlike `serialize_args`,
+    /// these only depend on the types, the stack depth and how much the locals are used over
+    /// the whole function, not on where the values happen to be when the label is first
+    /// branched to, so every caller of a join passes its values in the same registers. A
+    /// loop body is generated before any back-edge, so back-edges can't choose the layout
+    /// and have to move their values into this one.
+    pub fn serialize_pinned_args(&mut self, params: &[SignlessType]) -> BlockCallingConvention {
+        let mut gprs = BLOCK_GPRS.iter();
+        let mut xmms = BLOCK_XMMS.iter();
+        let mut depth = self.block_state.depth;
+
+        // The registers go to the values that deserve them most, which are the ones at the
+        // bottom of the stack unless we know how much the locals are used
+        let first = self.block_state.stack.len() - params.len();
+        let mut by_priority = (0..params.len())
+            .filter_map(|i| Some((self.register_priority(first + i)?, i)))
+            .collect::<Vec<_>>();
+        by_priority.sort_by_key(|&(priority, i)| (cmp::Reverse(priority), i));
+        let mut regs = vec![None; params.len()];
+        for (_, i) in by_priority {
+            regs[i] = match params[i] {
+                I32 | I64 => gprs.next(),
+                F32 | F64 => xmms.next(),
+            };
+        }
+
+        let arguments = regs
+            .into_iter()
+            .map(|reg| {
+                reg.map(|&reg| CCLoc::Reg(reg)).unwrap_or_else(|| {
+                    depth.reserve(1);
+                    CCLoc::Stack(-(depth.0 as i32))
+                })
+            })
+            .collect();
+
+        let cc = BlockCallingConvention {
+            stack_depth: depth,
+            arguments,
+        };
+
+        // Make room for the parameters that live on the stack first, so that anything that
+        // we spill while moving the values can't land on top of them.
+        self.set_stack_depth(depth);
+        self.pass_block_args(&cc);
+
+        cc
+    }
+
     pub fn get_global(&mut self, global_idx: u32) {
         let (reg, offset) = self
             .module_context
@@ -2602,7 +4203,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 )
             })
             .unwrap_or_else(|| {
-                let reg = self.take_reg(I64).unwrap();
+                let reg = self.take_reg(I64);
 
                 dynasm!(self.asm
                     ; mov Rq(reg.rq().unwrap()), [
@@ -2614,7 +4215,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (Some(reg), 0)
             });
 
-        let out = self.take_reg(GPRType::Rq).unwrap();
+        let out = self.take_reg(GPRType::Rq);
         let vmctx = GPR::Rq(VMCTX);
 
         // TODO: Are globals necessarily aligned to 128 bits? We can load directly to an XMM reg if so
@@ -2642,7 +4243,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 )
             })
             .unwrap_or_else(|| {
-                let reg = self.take_reg(I64).unwrap();
+                let reg = self.take_reg(I64);
 
                 dynasm!(self.asm
                     ; mov Rq(reg.rq().unwrap()), [
@@ -2654,7 +4255,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (Some(reg), 0)
             });
 
-        let val_reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
+        let val_reg = self.into_reg(GPRType::Rq, &mut val);
         let vmctx = GPR::Rq(VMCTX);
 
         // We always use `Rq` (even for floats) since the globals are not necessarily aligned to 128 bits
@@ -2674,11 +4275,17 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     fn immediate_to_reg(&mut self, reg: GPR, val: Value) {
         match reg {
             GPR::Rq(r) => {
-                let val = val.as_bytes();
+                // `i32`s are zero-extended like the results of any other 32-bit operation
+                let val = val
+                    .as_i32()
+                    .map(|i| i64::from(i as u32))
+                    .unwrap_or_else(|| val.as_bytes());
                 if (val as u64) <= u32::max_value() as u64 {
-                    dynasm!(self.asm
-                        ; mov Rd(r), val as i32
-                    );
+                    self.asm.instruction(Instruction::Mov {
+                        wide: false,
+                        dst: Operand::Reg(r),
+                        src: Operand::Imm(val as i32),
+                    });
                 } else {
                     dynasm!(self.asm
                         ; mov Rq(r), QWORD val
@@ -2698,6 +4305,18 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     // the arguments the wrong way around. In the future we want to have a `ReadLocation` and `WriteLocation`
     // so we statically can't write to a literal so this will become a non-issue.
     fn copy_value(&mut self, src: ValueLocation, dst: CCLoc) {
+        let is_bool = self.is_known_bool(src);
+        let is_zero_extended = self.is_known_zero_extended(src);
+        self.emit_copy(src, dst);
+        if let CCLoc::Reg(reg) = dst {
+            self.block_state.regs.set_bool(reg, is_bool);
+            self.block_state
+                .regs
+                .set_zero_extended(reg, is_zero_extended);
+        }
+    }
+
+    fn emit_copy(&mut self, src: ValueLocation, dst: CCLoc) {
         match (src, dst) {
             (ValueLocation::Cond(cond), CCLoc::Stack(o)) => {
                 let offset = self.adjusted_offset(o);
@@ -2779,7 +4398,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     }
                 }
                 GPR::Rx(_) => {
-                    let temp = CCLoc::Reg(self.take_reg(I32).unwrap());
+                    let temp = CCLoc::Reg(self.take_reg(I32));
                     self.copy_value(src, temp);
                     let temp = temp.into();
                     self.copy_value(temp, dst);
@@ -2787,16 +4406,18 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 }
             },
             (ValueLocation::Stack(in_offset), CCLoc::Stack(out_offset)) => {
-                let in_offset = self.adjusted_offset(in_offset);
-                let out_offset = self.adjusted_offset(out_offset);
                 if in_offset != out_offset {
-                    if let Some(gpr) = self.take_reg(I64) {
+                    if let Some(gpr) = self.try_take_reg(I64) {
+                        let in_offset = self.adjusted_offset(in_offset);
+                        let out_offset = self.adjusted_offset(out_offset);
                         dynasm!(self.asm
                             ; mov Rq(gpr.rq().unwrap()), [rsp + in_offset]
                             ; mov [rsp + out_offset], Rq(gpr.rq().unwrap())
                         );
                         self.block_state.regs.release(gpr);
                     } else {
+                        let in_offset = self.adjusted_offset(in_offset);
+                        let out_offset = self.adjusted_offset(out_offset);
                         dynasm!(self.asm
                             ; push rax
                             ; mov rax, [rsp + in_offset + WORD_SIZE as i32]
@@ -2813,9 +4434,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     GPR::Rq(in_reg) => {
                         // We can always use `Rq` here for now because stack slots are in multiples of
                         // 8 bytes
-                        dynasm!(self.asm
-                            ; mov [rsp + out_offset], Rq(in_reg)
-                        );
+                        self.asm.store_rq(out_offset, in_reg);
                     }
                     GPR::Rx(in_reg) => {
                         // We can always use `movq` here for now because stack slots are in multiples of
@@ -2830,12 +4449,15 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 // TODO: Floats
                 let i = i.as_bytes();
                 let out_offset = self.adjusted_offset(out_offset);
-                if (i as u64) <= u32::max_value() as u64 {
-                    dynasm!(self.asm
-                        ; mov DWORD [rsp + out_offset], i as i32
-                    );
+                // Write the whole slot, since 64-bit values can be read back from it
+                if let Ok(i) = i32::try_from(i) {
+                    self.asm.instruction(Instruction::Mov {
+                        wide: true,
+                        dst: Operand::Stack(out_offset),
+                        src: Operand::Imm(i),
+                    });
                 } else {
-                    if let Some(scratch) = self.take_reg(I64) {
+                    if let Some(scratch) = self.try_take_reg(I64) {
                         dynasm!(self.asm
                             ; mov Rq(scratch.rq().unwrap()), QWORD i
                             ; mov [rsp + out_offset], Rq(scratch.rq().unwrap())
@@ -2858,9 +4480,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     GPR::Rq(out_reg) => {
                         // We can always use `Rq` here for now because stack slots are in multiples of
                         // 8 bytes
-                        dynasm!(self.asm
-                            ; mov Rq(out_reg), [rsp + in_offset]
-                        );
+                        self.asm.load_rq(out_reg, in_offset);
                     }
                     GPR::Rx(out_reg) => {
                         // We can always use `movq` here for now because stack slots are in multiples of
@@ -2875,9 +4495,11 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 if in_reg != out_reg {
                     match (in_reg, out_reg) {
                         (GPR::Rq(in_reg), GPR::Rq(out_reg)) => {
-                            dynasm!(self.asm
-                                ; mov Rq(out_reg), Rq(in_reg)
-                            );
+                            if self.block_state.regs.is_zero_extended(GPR::Rq(in_reg)) {
+                                self.asm.mov_rd(out_reg, in_reg);
+                            } else {
+                                self.asm.mov_rq(out_reg, in_reg);
+                            }
                         }
                         (GPR::Rx(in_reg), GPR::Rq(out_reg)) => {
                             dynasm!(self.asm
@@ -2909,25 +4531,41 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     /// Multiple labels can be defined at the same position. However, a label
     /// can be defined only once.
     pub fn define_label(&mut self, label: Label) {
-        self.asm.dynamic_label(label.0);
+        self.asm.define_label(label.0);
+        // Code that branches here could have left anything in the registers
+        for &reg in SCRATCH_REGS {
+            self.block_state.regs.forget(reg);
+        }
+    }
+
+    /// Define a label that nothing branches to, so that it's only reached by falling through
+    /// from the code before it.
+    pub fn define_fallthrough_label(&mut self, label: Label) {
+        self.asm.define_fallthrough_label(label.0);
+        for &reg in SCRATCH_REGS {
+            if self.block_state.regs.is_free(reg) {
+                self.block_state.regs.forget(reg);
+            }
+        }
     }
 
     pub fn set_state(&mut self, state: VirtualCallingConvention) {
-        self.block_state.regs = Registers::new();
+        self.block_state.regs = self.block_state.regs.cleared();
         for elem in &state.stack {
             if let ValueLocation::Reg(r) = elem {
                 self.block_state.regs.mark_used(*r);
             }
         }
         self.block_state.stack = state.stack;
-        self.block_state.depth = state.depth;
+        self.set_depth(state.depth);
+        self.reset_free_slots();
     }
 
     pub fn apply_cc(&mut self, cc: &BlockCallingConvention) {
         let stack = cc.arguments.iter();
 
         self.block_state.stack = Vec::with_capacity(stack.size_hint().0);
-        self.block_state.regs = Registers::new();
+        self.block_state.regs = self.block_state.regs.cleared();
 
         for &elem in stack {
             if let CCLoc::Reg(r) = elem {
@@ -2937,7 +4575,178 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             self.block_state.stack.push(elem.into());
         }
 
-        self.block_state.depth = cc.stack_depth;
+        self.set_depth(cc.stack_depth);
+        self.reset_free_slots();
+    }
+
+    /// The displacement and index of the access to `base` plus `offset`, with the index
+    /// either a constant or a register, so that `load_to_reg` and `store_from_reg` can fold
+    /// as much of the address as possible into the addressing mode. If this has to compute
+    /// the address in a new register it replaces `base` with that, so freeing `base` frees
+    /// whatever the access used.
+    ///
+    /// An `i32.add` of a constant to the base can't be folded in the same way, since that
+    /// wraps at 32 bits and adding the offset doesn't.
+    fn memory_address(
+        &mut self,
+        memory_index: u32,
+        base: &mut ValueLocation,
+        offset: u32,
+    ) -> (i32, Result<i32, GPR>) {
+        if self.module_context.memory_index_type(memory_index) == I64 {
+            return self.memory_address_64(base, offset);
+        }
+
+        if let Some(i) = base.imm_i32() {
+            let address = u64::from(i as u32) + u64::from(offset);
+            if address <= i32::max_value() as u64 {
+                return (0, Ok(address as i32));
+            }
+
+            let reg = self.take_reg(I64);
+            dynasm!(self.asm
+                ; mov Rq(reg.rq().unwrap()), QWORD address as i64
+            );
+            *base = ValueLocation::Reg(reg);
+            return (0, Err(reg));
+        }
+
+        let gpr = self.into_reg(I32, base);
+        if offset <= i32::max_value() as u32 {
+            return (offset as i32, Err(gpr));
+        }
+
+        // Too big for a displacement, so it has to be added to the base
+        let reg = self.take_reg(I64);
+        dynasm!(self.asm
+            ; mov Rd(reg.rq().unwrap()), offset as i32
+            ; add Rq(reg.rq().unwrap()), Rq(gpr.rq().unwrap())
+        );
+        self.free_value(*base);
+        *base = ValueLocation::Reg(reg);
+        (0, Err(reg))
+    }
+
+    /// Trap unless the access at `offset` plus `runtime_offset` is in bounds of the memory
+    /// whose definition is `mem_offset` bytes from `def_reg`, or from the `VmCtx` if that's
+    /// `None`. If the memory has a fixed size, the check compares against it as a constant and
+    /// is left out entirely for constant addresses that leave room for the widest access.
+    fn memory_bounds_check(
+        &mut self,
+        memory_index: u32,
+        def_reg: Option<GPR>,
+        mem_offset: i32,
+        (offset, runtime_offset): (i32, Result<i32, GPR>),
+    ) {
+        if !self.module_context.emit_memory_bounds_check()
+            && self.module_context.memory_index_type(memory_index) != I64
+        {
+            return;
+        }
+
+        let fixed_size = self
+            .module_context
+            .memory_fixed_size(memory_index)
+            .filter(|&size| size <= i32::max_value() as u64);
+
+        if let (Some(size), Ok(imm)) = (fixed_size, runtime_offset) {
+            if (i64::from(offset) + i64::from(imm) + 8) as u64 <= size {
+                return;
+            }
+        }
+
+        let trap_label = self.trap_label(ir::TrapCode::HeapOutOfBounds);
+        let current_length = self.module_context.vmmemory_definition_current_length() as i32;
+        let def_reg = def_reg.unwrap_or(GPR::Rq(VMCTX));
+
+        match runtime_offset {
+            Ok(imm) => {
+                dynasm!(self.asm
+                    ; cmp QWORD [
+                        Rq(def_reg.rq().unwrap()) + mem_offset + current_length
+                    ], offset + imm
+                    ; jna =>trap_label.0
+                );
+            }
+            Err(gpr) => {
+                let addr_reg = if offset == 0 {
+                    self.to_reg(I32, ValueLocation::Reg(gpr))
+                } else {
+                    let addr_reg = self.take_reg(I64);
+                    dynasm!(self.asm
+                        ; lea Rq(addr_reg.rq().unwrap()), [Rq(gpr.rq().unwrap()) + offset]
+                    );
+                    addr_reg
+                };
+
+                match fixed_size {
+                    Some(size) => {
+                        dynasm!(self.asm
+                            ; cmp Rq(addr_reg.rq().unwrap()), size as i32
+                            ; jae =>trap_label.0
+                        );
+                    }
+                    None => {
+                        dynasm!(self.asm
+                            ; cmp Rq(addr_reg.rq().unwrap()), [
+                                Rq(def_reg.rq().unwrap()) + mem_offset + current_length
+                            ]
+                            ; jae =>trap_label.0
+                        );
+                    }
+                }
+                self.block_state.regs.release(addr_reg);
+            }
+        }
+    }
+
+    /// `memory_address` for a memory indexed with `i64`. Adding the offset to an index that
+    /// big can overflow, which has to trap rather than wrap around to an address that passes
+    /// the bounds check, so the offset is never folded into the displacement.
+    fn memory_address_64(
+        &mut self,
+        base: &mut ValueLocation,
+        offset: u32,
+    ) -> (i32, Result<i32, GPR>) {
+        if let Some(i) = base.imm_i64() {
+            match (i as u64).checked_add(u64::from(offset)) {
+                Some(address) if address <= i32::max_value() as u64 => {
+                    return (0, Ok(address as i32));
+                }
+                Some(address) => {
+                    let reg = self.take_reg(I64);
+                    dynasm!(self.asm
+                        ; mov Rq(reg.rq().unwrap()), QWORD address as i64
+                    );
+                    *base = ValueLocation::Reg(reg);
+                    return (0, Err(reg));
+                }
+                None => {
+                    // Never in bounds, so the access that follows is unreachable
+                    let trap_label = self.trap_label(ir::TrapCode::HeapOutOfBounds);
+                    dynasm!(self.asm
+                        ; jmp =>trap_label.0
+                    );
+                    return (0, Ok(0));
+                }
+            }
+        }
+
+        let gpr = self.into_reg(I64, base);
+        if offset == 0 {
+            return (0, Err(gpr));
+        }
+
+        let reg = self.take_reg(I64);
+        let trap_label = self.trap_label(ir::TrapCode::HeapOutOfBounds);
+        dynasm!(self.asm
+            ; mov Rd(reg.rq().unwrap()), offset as i32
+            ; add Rq(reg.rq().unwrap()), Rq(gpr.rq().unwrap())
+            ; jb =>trap_label.0
+        );
+        self.free_value(*base);
+        *base = ValueLocation::Reg(reg);
+        (0, Err(reg))
     }
 
     load!(i32_load, GPRType::Rq, Rd, movd, mov, DWORD);
@@ -2962,46 +4771,84 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     store!(store32, Rd, movd, DWORD);
     store!(store64, Rq, movq, QWORD);
 
+    /// Move `value` into a stack slot: one that the prologue reserved or that a dropped value
+    /// left free if there is one, otherwise a new one pushed onto the stack.
     fn push_physical(&mut self, mut value: ValueLocation) -> ValueLocation {
-        let out_offset = -(self.block_state.depth.0 as i32 + 1);
+        if let ValueLocation::Reg(r) = value {
+            self.stats.spills += 1;
+            log_trace!(
+                "Spilling {} at depth {}, with {} in use",
+                r,
+                self.block_state.depth.0,
+                self.block_state.regs
+            );
+        }
+
+        if let Some(slot) = self.block_state.free_slots.pop() {
+            let out_offset = -(slot as i32);
+            self.copy_value(value, CCLoc::Stack(out_offset));
+            self.free_value(value);
+            return ValueLocation::Stack(out_offset);
+        }
+
         match value {
             ValueLocation::Reg(_) | ValueLocation::Immediate(_) | ValueLocation::Cond(_) => {
-                if let Some(gpr) = self.into_reg(GPRType::Rq, &mut value) {
+                // This is how `free_reg` spills, so it mustn't need a register to
+                if value.reg().map(|r| r.type_()) != Some(GPRType::Rq) {
+                    if let Some(gpr) = self.try_take_reg(GPRType::Rq) {
+                        self.copy_value(value, CCLoc::Reg(gpr));
+                        self.free_value(value);
+                        value = ValueLocation::Reg(gpr);
+                    }
+                }
+                // Finding a register can spill other values, so the slot that we push to
+                // isn't known until after that.
+                let out_offset = -(self.block_state.depth.0 as i32 + 1);
+                if let ValueLocation::Reg(GPR::Rq(gpr)) = value {
                     dynasm!(self.asm
-                        ; push Rq(gpr.rq().unwrap())
+                        ; push Rq(gpr)
                     );
+                    self.reserve_depth(1);
                 } else {
                     dynasm!(self.asm
                         ; push rax
                     );
+                    self.reserve_depth(1);
 
                     self.copy_value(value, CCLoc::Stack(out_offset));
                 }
 
                 self.free_value(value);
+
+                ValueLocation::Stack(out_offset)
             }
             ValueLocation::Stack(o) => {
+                let out_offset = -(self.block_state.depth.0 as i32 + 1);
                 let offset = self.adjusted_offset(o);
                 dynasm!(self.asm
                     ; push QWORD [rsp + offset]
                 );
+                self.reserve_depth(1);
+
+                ValueLocation::Stack(out_offset)
             }
         }
-
-        self.block_state.depth.reserve(1);
-
-        ValueLocation::Stack(out_offset)
     }
 
-    fn push(&mut self, value: ValueLocation) {
+    /// If the value on the top of the stack only lives in the flags, move it into a register
+    /// so that the flags can be overwritten.
+    fn materialize_top_cond(&mut self) {
         if let Some(mut top) = self.block_state.stack.pop() {
             if let ValueLocation::Cond(_) = top {
-                self.into_reg(I32, &mut top).unwrap();
+                self.into_reg(I32, &mut top);
             }
 
             self.block_state.stack.push(top);
         }
+    }
 
+    fn push(&mut self, value: ValueLocation) {
+        self.materialize_top_cond();
         self.block_state.stack.push(value);
     }
 
@@ -3009,6 +4856,103 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.block_state.stack.pop().expect("Stack is empty")
     }
 
+    /// The value on top of the stack if it's a constant, so that branches on it can be
+    /// decided during translation.
+    pub fn top_constant(&self) -> Option<Value> {
+        match self.block_state.stack.last() {
+            Some(ValueLocation::Immediate(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The function that a `call_indirect` of type `type_index` calls, if the index on top of
+    /// the stack is a constant and the element there can't change. The index is dropped and
+    /// the element recorded as bound to the call, which can then be made directly.
+    pub fn devirtualize(&mut self, type_index: u32) -> Option<u32> {
+        let index = self.top_constant()?.as_i32()? as u32;
+        let function_index = *self.static_table.get(&index)?;
+
+        // Calls to a function of the wrong type trap, so they're left to do that
+        let expected = self.module_context.signature(type_index);
+        let actual = self.module_context.func_type(function_index);
+        let same_types = |a: &[<M::Signature as Signature>::Type],
+                          b: &[<M::Signature as Signature>::Type]| {
+            a.iter()
+                .map(SigType::to_microwasm_type)
+                .eq(b.iter().map(SigType::to_microwasm_type))
+        };
+        if !same_types(expected.params(), actual.params())
+            || !same_types(expected.returns(), actual.returns())
+        {
+            return None;
+        }
+
+        self.drop(0..=0);
+        self.bound_table_elements.insert(index);
+        Some(function_index)
+    }
+
+    /// Whether the value on top of the stack is known to be 0 or 1.
+    pub fn top_is_known_bool(&self) -> bool {
+        self.block_state
+            .stack
+            .last()
+            .map(|&val| self.is_known_bool(val))
+            .unwrap_or(false)
+    }
+
+    /// Compute `base + (index << shift) + displacement` with a single `lea`, where `index` is
+    /// the value on top of the stack and `base` the one below it, for the shifts that `lea`
+    /// can scale by. Returns whether it pushed the result, which it doesn't if either is a
+    /// constant, since then the shift and adds fold, or if we trap on overflow, since `lea`
+    /// doesn't set any flags.
+    pub fn add_scaled(&mut self, ty: SignlessType, shift: u32, displacement: i32) -> bool {
+        let operands = self.block_state.stack.len() - 2;
+        if self.trap_on_overflow
+            || shift < 1
+            || shift > 3
+            || self.block_state.stack[operands..]
+                .iter()
+                .any(|val| val.immediate().is_some())
+        {
+            return false;
+        }
+
+        let mut index = self.pop();
+        let mut base = self.pop();
+        let index_reg = self.into_reg(ty, &mut index).rq().unwrap();
+        let base_reg = self.into_reg(ty, &mut base).rq().unwrap();
+        let out = self.take_reg(ty);
+
+        match (ty, shift) {
+            (I32, 1) => dynasm!(self.asm
+                ; lea Rd(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 2 + displacement]
+            ),
+            (I32, 2) => dynasm!(self.asm
+                ; lea Rd(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 4 + displacement]
+            ),
+            (I32, _) => dynasm!(self.asm
+                ; lea Rd(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 8 + displacement]
+            ),
+            (_, 1) => dynasm!(self.asm
+                ; lea Rq(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 2 + displacement]
+            ),
+            (_, 2) => dynasm!(self.asm
+                ; lea Rq(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 4 + displacement]
+            ),
+            (_, _) => dynasm!(self.asm
+                ; lea Rq(out.rq().unwrap()), [Rq(base_reg) + Rq(index_reg) * 8 + displacement]
+            ),
+        }
+
+        self.free_value(index);
+        self.free_value(base);
+        self.block_state.regs.set_zero_extended(out, ty == I32);
+        self.push(ValueLocation::Reg(out));
+
+        true
+    }
+
     pub fn drop(&mut self, range: RangeInclusive<u32>) {
         let mut repush = Vec::with_capacity(*range.start() as _);
 
@@ -3016,14 +4960,143 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             repush.push(self.pop());
         }
 
+        let mut dropped_slots = Vec::new();
         for _ in range {
             let val = self.pop();
+            if let ValueLocation::Stack(o) = val {
+                dropped_slots.push(o);
+            }
             self.free_value(val);
         }
 
         for v in repush.into_iter().rev() {
             self.push(v);
         }
+
+        for o in dropped_slots {
+            self.release_stack_slot(o);
+        }
+        self.pop_free_slots();
+    }
+
+    /// Pop the free slots on top of the physical stack off it, so that dropping the values
+    /// that were spilled last gives their stack back straight away rather than at the end of
+    /// the block.
+    fn pop_free_slots(&mut self) {
+        let mut depth = self.block_state.depth.0;
+        while depth > self.block_state.slot_floor && self.block_state.free_slots.contains(&depth) {
+            depth -= 1;
+        }
+
+        let popped = self.block_state.depth.0 - depth;
+        if popped > 0 {
+            self.asm.adjust_rsp((popped * WORD_SIZE) as i32);
+            self.free_depth(popped);
+        }
+    }
+
+    /// Make the slot at `offset` available for reuse, as long as it's one of the spill slots
+    /// or belongs to the current block, and no other value still lives there.
+    fn release_stack_slot(&mut self, offset: i32) {
+        let slot = -offset;
+        let reusable =
+            self.spill_slots.contains(&(slot as u32)) || slot as u32 > self.block_state.slot_floor;
+        if slot > 0
+            && reusable
+            && slot as u32 <= self.block_state.depth.0
+            && !self.block_state.free_slots.contains(&(slot as u32))
+            && !self
+                .block_state
+                .stack
+                .contains(&ValueLocation::Stack(offset))
+        {
+            self.block_state.free_slots.push(slot as u32);
+        }
+    }
+
+    /// Move `rsp` down towards `depth` a page at a time while it's more than a page away,
+    /// touching each page on the way, so that a frame bigger than the guard page at the end of
+    /// the stack hits it rather than skipping over it into whatever's mapped below. Returns
+    /// whether it did, in which case the rest of the frame must be touched once it's there.
+    fn probe_stack(&mut self, depth: StackDepth) -> bool {
+        let page_words = STACK_PROBE_INTERVAL / WORD_SIZE;
+        let mut probed = false;
+
+        while depth.0 > self.block_state.depth.0 + page_words {
+            let mut next = self.block_state.depth;
+            next.reserve(page_words);
+            self.asm.adjust_rsp(-(STACK_PROBE_INTERVAL as i32));
+            self.set_depth(next);
+            dynasm!(self.asm
+                ; or DWORD [rsp], 0
+            );
+            probed = true;
+        }
+
+        probed
+    }
+
+    /// Set the statically-known stack depth. This must be called right after the code that
+    /// moves `rsp`, so that the unwind information is correct for every instruction.
+    fn set_depth(&mut self, depth: StackDepth) {
+        self.block_state.depth = depth;
+        self.record_depth(depth);
+    }
+
+    /// Account for `slots` words having been pushed onto the stack.
+    fn reserve_depth(&mut self, slots: u32) {
+        let mut depth = self.block_state.depth;
+        depth.reserve(slots);
+        self.set_depth(depth);
+    }
+
+    /// Account for `slots` words having been popped off the stack.
+    fn free_depth(&mut self, slots: u32) {
+        let mut depth = self.block_state.depth;
+        depth.free(slots);
+        self.set_depth(depth);
+        self.trim_free_slots();
+    }
+
+    /// Tell the unwinder that the stack is `depth` words deep from the current position on,
+    /// without changing the depth that we generate code for.
+    fn record_depth(&mut self, depth: StackDepth) {
+        let func_start = self.func_starts[self.current_function].0.unwrap();
+        // The callee-saved registers are pushed straight after the frame pointer
+        let saved_regs = depth
+            .0
+            .saturating_sub(1 + self.frame_pointer.saved_words())
+            .min(self.callee_saved.len() as u32);
+        self.stats.max_stack_size = self.stats.max_stack_size.max(depth.0 * WORD_SIZE);
+        self.unwind.push(UnwindRow {
+            offset: (self.asm.offset().0 - func_start.0) as u32,
+            cfa_offset: depth.0 * WORD_SIZE,
+            cfa_rbp: false,
+            rbp_saved: self.frame_pointer == FramePointer::Preserve && depth.0 > 1,
+            saved_regs: saved_regs as u8,
+        });
+    }
+
+    /// Forget about free slots that were popped off the physical stack.
+    fn trim_free_slots(&mut self) {
+        let depth = self.block_state.depth.0;
+        self.block_state.free_slots.retain(|&slot| slot <= depth);
+    }
+
+    /// Start a new block: everything that was pushed onto the physical stack may be referred
+    /// to by a calling convention from now on, so only the spill slots that no value lives in
+    /// are free. `pass_block_args` moves a value out of the way of an argument that's
+    /// passed in its slot, so a spill slot can be reused in any block.
+    fn reset_free_slots(&mut self) {
+        self.block_state.slot_floor = self.block_state.depth.0;
+        let depth = self.block_state.depth.0;
+        let stack = &self.block_state.stack;
+        self.block_state.free_slots = self
+            .spill_slots
+            .clone()
+            .rev()
+            .filter(|&slot| slot <= depth && !stack.contains(&ValueLocation::Stack(-(slot as i32))))
+            .collect();
     }
 
     fn pop_into(&mut self, dst: CCLoc) {
@@ -3037,54 +5110,81 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             ValueLocation::Reg(r) => {
                 self.block_state.regs.release(r);
             }
-            // TODO: Refcounted stack slots
+            ValueLocation::Stack(offset) => {
+                self.release_stack_slot(offset);
+            }
             _ => {}
         }
     }
 
     /// Puts this value into a register so that it can be efficiently read
-    fn into_reg(&mut self, ty: impl Into<Option<GPRType>>, val: &mut ValueLocation) -> Option<GPR> {
-        let out = self.to_reg(ty, *val)?;
+    fn into_reg(&mut self, ty: impl Into<Option<GPRType>>, val: &mut ValueLocation) -> GPR {
+        let out = self.to_reg(ty, *val);
         self.free_value(*val);
         *val = ValueLocation::Reg(out);
-        Some(out)
+        out
     }
 
     /// Clones this value into a register so that it can be efficiently read
-    fn to_reg(&mut self, ty: impl Into<Option<GPRType>>, val: ValueLocation) -> Option<GPR> {
+    fn to_reg(&mut self, ty: impl Into<Option<GPRType>>, val: ValueLocation) -> GPR {
         let ty = ty.into();
         match val {
             ValueLocation::Reg(r) if ty.map(|t| t == r.type_()).unwrap_or(true) => {
                 self.block_state.regs.mark_used(r);
-                Some(r)
+                r
             }
             val => {
-                let scratch = self.take_reg(ty.unwrap_or(GPRType::Rq))?;
+                let scratch = self.take_reg(ty.unwrap_or(GPRType::Rq));
 
                 self.copy_value(val, CCLoc::Reg(scratch));
 
-                Some(scratch)
+                scratch
             }
         }
     }
 
     /// Puts this value into a temporary register so that operations
     /// on that register don't write to a local.
-    fn into_temp_reg(
-        &mut self,
-        ty: impl Into<Option<GPRType>>,
-        val: &mut ValueLocation,
-    ) -> Option<GPR> {
-        let out = self.to_temp_reg(ty, *val)?;
+    fn into_temp_reg(&mut self, ty: impl Into<Option<GPRType>>, val: &mut ValueLocation) -> GPR {
+        let out = self.to_temp_reg(ty, *val);
         self.free_value(*val);
         *val = ValueLocation::Reg(out);
-        Some(out)
+        out
     }
 
     fn into_temp_loc(&mut self, ty: impl Into<Option<GPRType>>, val: &mut ValueLocation) -> CCLoc {
         match val {
             _ => {
-                if let Some(gpr) = self.into_temp_reg(ty, val) {
+                let ty: Option<GPRType> = ty.into();
+                let is_bool = self.is_known_bool(*val);
+                let is_zero_extended = self.is_known_zero_extended(*val);
+                // Values that are popped to be serialized keep their registers, so there may
+                // not be one left, in which case the value goes on the stack
+                let reusable = match *val {
+                    ValueLocation::Reg(r) => {
+                        self.block_state.regs.num_usages(r) <= 1
+                            && ty.map(|t| t == r.type_()).unwrap_or(true)
+                    }
+                    _ => false,
+                };
+                let gpr = if reusable {
+                    Some(self.into_temp_reg(ty, val))
+                } else {
+                    self.try_take_reg(ty.unwrap_or(GPRType::Rq))
+                };
+
+                if let Some(gpr) = gpr {
+                    if !reusable {
+                        self.copy_value(*val, CCLoc::Reg(gpr));
+                        self.free_value(*val);
+                        *val = ValueLocation::Reg(gpr);
+                        self.block_state.regs.forget(gpr);
+                    }
+                    // Nothing writes to it, so it's still the same value
+                    self.block_state.regs.set_bool(gpr, is_bool);
+                    self.block_state
+                        .regs
+                        .set_zero_extended(gpr, is_zero_extended);
                     CCLoc::Reg(gpr)
                 } else {
                     let out = CCLoc::Stack(self.push_physical(*val).stack().unwrap());
@@ -3097,26 +5197,31 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
     /// Clones this value into a temporary register so that operations
     /// on that register don't write to a local.
-    fn to_temp_reg(&mut self, ty: impl Into<Option<GPRType>>, val: ValueLocation) -> Option<GPR> {
+    fn to_temp_reg(&mut self, ty: impl Into<Option<GPRType>>, val: ValueLocation) -> GPR {
         // If we have `None` as the type then it always matches (`.unwrap_or(true)`)
-        match val {
+        let reg = match val {
             ValueLocation::Reg(r) => {
                 let ty = ty.into();
                 let type_matches = ty.map(|t| t == r.type_()).unwrap_or(true);
 
                 if self.block_state.regs.num_usages(r) <= 1 && type_matches {
                     self.block_state.regs.mark_used(r);
-                    Some(r)
+                    r
                 } else {
-                    let scratch = self.take_reg(ty.unwrap_or(GPRType::Rq))?;
+                    let scratch = self.take_reg(ty.unwrap_or(GPRType::Rq));
 
                     self.copy_value(val, CCLoc::Reg(scratch));
 
-                    Some(scratch)
+                    scratch
                 }
             }
             val => self.to_reg(ty, val),
-        }
+        };
+
+        // The caller is going to write to it, so we don't know anything about its value
+        self.block_state.regs.forget(reg);
+
+        reg
     }
 
     pub fn f32_neg(&mut self) {
@@ -3127,7 +5232,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee32::from_bits((-f32::from_bits(i.to_bits())).to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
             let const_label = self.aligned_label(16, LabelValue::I32(SIGN_MASK_F32 as i32));
 
             dynasm!(self.asm
@@ -3148,7 +5253,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee64::from_bits((-f64::from_bits(i.to_bits())).to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
             let const_label = self.aligned_label(16, LabelValue::I64(SIGN_MASK_F64 as i64));
 
             dynasm!(self.asm
@@ -3169,7 +5274,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee32::from_bits(f32::from_bits(i.to_bits()).abs().to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
             let const_label = self.aligned_label(16, LabelValue::I32(REST_MASK_F32 as i32));
 
             dynasm!(self.asm
@@ -3190,7 +5295,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee64::from_bits(f64::from_bits(i.to_bits()).abs().to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
             let const_label = self.aligned_label(16, LabelValue::I64(REST_MASK_F64 as i64));
 
             dynasm!(self.asm
@@ -3211,7 +5316,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee32::from_bits(f32::from_bits(i.to_bits()).sqrt().to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
 
             dynasm!(self.asm
                 ; sqrtss Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
@@ -3231,7 +5336,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee64::from_bits(f64::from_bits(i.to_bits()).sqrt().to_bits()).into(),
             )
         } else {
-            let reg = self.into_temp_reg(GPRType::Rx, &mut val).unwrap();
+            let reg = self.into_temp_reg(GPRType::Rx, &mut val);
 
             dynasm!(self.asm
                 ; sqrtsd Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
@@ -3255,8 +5360,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 .into(),
             )
         } else {
-            let lreg = self.into_temp_reg(GPRType::Rx, &mut left).unwrap();
-            let rreg = self.into_reg(GPRType::Rx, &mut right).unwrap();
+            let lreg = self.into_temp_reg(GPRType::Rx, &mut left);
+            let rreg = self.into_reg(GPRType::Rx, &mut right);
             let sign_mask = self.aligned_label(16, LabelValue::I32(SIGN_MASK_F32 as i32));
             let rest_mask = self.aligned_label(16, LabelValue::I32(REST_MASK_F32 as i32));
 
@@ -3286,8 +5391,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 .into(),
             )
         } else {
-            let lreg = self.into_temp_reg(GPRType::Rx, &mut left).unwrap();
-            let rreg = self.into_reg(GPRType::Rx, &mut right).unwrap();
+            let lreg = self.into_temp_reg(GPRType::Rx, &mut left);
+            let rreg = self.into_reg(GPRType::Rx, &mut right);
             let sign_mask = self.aligned_label(16, LabelValue::I64(SIGN_MASK_F64 as i64));
             let rest_mask = self.aligned_label(16, LabelValue::I64(REST_MASK_F64 as i64));
 
@@ -3313,8 +5418,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 ValueLocation::Immediate(imm.as_i32().unwrap().leading_zeros().into())
             }
             ValueLocation::Stack(offset) => {
+                let temp = self.take_reg(I32);
                 let offset = self.adjusted_offset(offset);
-                let temp = self.take_reg(I32).unwrap();
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3322,7 +5427,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     );
                     ValueLocation::Reg(temp)
                 } else {
-                    let temp_2 = self.take_reg(I32).unwrap();
+                    let temp_2 = self.take_reg(I32);
 
                     dynasm!(self.asm
                         ; bsr Rd(temp.rq().unwrap()), [rsp + offset]
@@ -3336,8 +5441,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 }
             }
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_reg(GPRType::Rq, &mut val);
+                let temp = self.take_reg(I32);
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3369,8 +5474,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 ValueLocation::Immediate((imm.as_i64().unwrap().leading_zeros() as u64).into())
             }
             ValueLocation::Stack(offset) => {
+                let temp = self.take_reg(I64);
                 let offset = self.adjusted_offset(offset);
-                let temp = self.take_reg(I64).unwrap();
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3378,7 +5483,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     );
                     ValueLocation::Reg(temp)
                 } else {
-                    let temp_2 = self.take_reg(I64).unwrap();
+                    let temp_2 = self.take_reg(I64);
 
                     dynasm!(self.asm
                         ; bsr Rq(temp.rq().unwrap()), [rsp + offset]
@@ -3392,8 +5497,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 }
             }
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
-                let temp = self.take_reg(I64).unwrap();
+                let reg = self.into_reg(GPRType::Rq, &mut val);
+                let temp = self.take_reg(I64);
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3425,8 +5530,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 ValueLocation::Immediate(imm.as_i32().unwrap().trailing_zeros().into())
             }
             ValueLocation::Stack(offset) => {
+                let temp = self.take_reg(I32);
                 let offset = self.adjusted_offset(offset);
-                let temp = self.take_reg(I32).unwrap();
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3434,7 +5539,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     );
                     ValueLocation::Reg(temp)
                 } else {
-                    let temp_zero_val = self.take_reg(I32).unwrap();
+                    let temp_zero_val = self.take_reg(I32);
 
                     dynasm!(self.asm
                         ; bsf Rd(temp.rq().unwrap()), [rsp + offset]
@@ -3446,8 +5551,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 }
             }
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_reg(GPRType::Rq, &mut val);
+                let temp = self.take_reg(I32);
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3477,8 +5582,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 ValueLocation::Immediate((imm.as_i64().unwrap().trailing_zeros() as u64).into())
             }
             ValueLocation::Stack(offset) => {
+                let temp = self.take_reg(I64);
                 let offset = self.adjusted_offset(offset);
-                let temp = self.take_reg(I64).unwrap();
 
                 if is_x86_feature_detected!("lzcnt") {
                     dynasm!(self.asm
@@ -3486,7 +5591,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     );
                     ValueLocation::Reg(temp)
                 } else {
-                    let temp_zero_val = self.take_reg(I64).unwrap();
+                    let temp_zero_val = self.take_reg(I64);
 
                     dynasm!(self.asm
                         ; bsf Rq(temp.rq().unwrap()), [rsp + offset]
@@ -3498,8 +5603,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 }
             }
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let reg = self.into_reg(GPRType::Rq, &mut val).unwrap();
-                let temp = self.take_reg(I64).unwrap();
+                let reg = self.into_reg(GPRType::Rq, &mut val);
+                let temp = self.take_reg(I64);
 
                 dynasm!(self.asm
                     ; bsf Rq(temp.rq().unwrap()), Rq(reg.rq().unwrap())
@@ -3519,12 +5624,14 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
         let out = if let ValueLocation::Immediate(imm) = val {
             ValueLocation::Immediate((imm.as_i32().unwrap() as u32 as u64).into())
+        } else if self.is_known_zero_extended(val) {
+            // It's already the `i64` that we want. Values that came from `i32.wrap/i64`, or
+            // that we don't know about, still need to have their upper half zeroed.
+            self.push(val);
+            return;
         } else {
-            let new_reg = self.take_reg(I64).unwrap();
+            let new_reg = self.take_reg(I64);
 
-            // TODO: Track set-ness of bits - we can make this a no-op in most cases
-            //       but we have to make this unconditional just in case this value
-            //       came from a truncate.
             match val {
                 ValueLocation::Reg(GPR::Rx(rxreg)) => {
                     dynasm!(self.asm
@@ -3547,6 +5654,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 ValueLocation::Immediate(_) => unreachable!(),
             }
 
+            self.block_state.regs.set_zero_extended(new_reg, true);
             ValueLocation::Reg(new_reg)
         };
 
@@ -3559,7 +5667,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         let val = self.pop();
 
         self.free_value(val);
-        let new_reg = self.take_reg(I64).unwrap();
+        let new_reg = self.take_reg(I64);
 
         let out = if let ValueLocation::Immediate(imm) = val {
             self.block_state.regs.release(new_reg);
@@ -3593,7 +5701,18 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.push(out);
     }
 
-    unop!(i32_popcnt, popcnt, Rd, u32, u32::count_ones);
+    unop!(i32_popcnt_inline, popcnt, Rd, u32, u32::count_ones);
+
+    /// Not every x86_64 CPU has `popcnt`, so on those that don't this calls the runtime
+    /// unless the operand is a constant.
+    pub fn i32_popcnt(&mut self) {
+        if is_x86_feature_detected!("popcnt") || self.top_constant().is_some() {
+            self.i32_popcnt_inline();
+        } else {
+            self.call_builtin(BuiltinFunction::I32Popcnt, iter::once(I32), iter::once(I32));
+        }
+    }
+
     conversion!(
         f64_from_f32,
         cvtss2sd,
@@ -3626,24 +5745,25 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f32::from_bits(imm.as_f32().unwrap().to_bits()) as i32).into(),
             ),
             _ => {
-                let reg = self.into_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(4, LabelValue::I32(SIGN_MASK_F32 as i32));
                 let float_cmp_mask = self.aligned_label(16, LabelValue::I32(0xcf000000u32 as i32));
                 let zero = self.aligned_label(16, LabelValue::I32(0));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; cvttss2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rd(temp.rq().unwrap()), [=>sign_mask.0]
                     ; jne >ret
                     ; ucomiss Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; ucomiss Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
-                    ; jnae =>trap_label.0
+                    ; jnae =>overflow_label.0
                     ; ucomiss Rx(reg.rx().unwrap()), [=>zero.0]
-                    ; jnb =>trap_label.0
+                    ; jnb =>overflow_label.0
                 ; ret:
                 );
 
@@ -3664,26 +5784,27 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f32::from_bits(imm.as_f32().unwrap().to_bits()) as i32).into(),
             ),
             _ => {
-                let reg = self.into_temp_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_temp_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(4, LabelValue::I32(SIGN_MASK_F32 as i32));
                 let float_cmp_mask = self.aligned_label(16, LabelValue::I32(0x4f000000u32 as i32));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; ucomiss Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
                     ; jae >else_
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; cvttss2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rd(temp.rq().unwrap()), Rd(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; jmp >ret
                 ; else_:
                     ; subss Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
                     ; cvttss2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rd(temp.rq().unwrap()), Rd(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; add Rq(temp.rq().unwrap()), [=>sign_mask.0]
                 ; ret:
                 );
@@ -3705,25 +5826,26 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f64::from_bits(imm.as_f64().unwrap().to_bits()) as i32).into(),
             ),
             _ => {
-                let reg = self.into_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(4, LabelValue::I32(SIGN_MASK_F32 as i32));
                 let float_cmp_mask =
                     self.aligned_label(16, LabelValue::I64(0xc1e0000000200000u64 as i64));
                 let zero = self.aligned_label(16, LabelValue::I64(0));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; cvttsd2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rd(temp.rq().unwrap()), [=>sign_mask.0]
                     ; jne >ret
                     ; ucomisd Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; ucomisd Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
-                    ; jna =>trap_label.0
+                    ; jna =>overflow_label.0
                     ; ucomisd Rx(reg.rx().unwrap()), [=>zero.0]
-                    ; jnb =>trap_label.0
+                    ; jnb =>overflow_label.0
                 ; ret:
                 );
 
@@ -3744,27 +5866,28 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f64::from_bits(imm.as_f64().unwrap().to_bits()) as u32).into(),
             ),
             _ => {
-                let reg = self.into_temp_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_temp_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(4, LabelValue::I32(SIGN_MASK_F32 as i32));
                 let float_cmp_mask =
                     self.aligned_label(16, LabelValue::I64(0x41e0000000000000u64 as i64));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; ucomisd Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
                     ; jae >else_
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; cvttsd2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rd(temp.rq().unwrap()), Rd(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; jmp >ret
                 ; else_:
                     ; subsd Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
                     ; cvttsd2si Rd(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rd(temp.rq().unwrap()), Rd(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; add Rq(temp.rq().unwrap()), [=>sign_mask.0]
                 ; ret:
                 );
@@ -3835,24 +5958,25 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f32::from_bits(imm.as_f32().unwrap().to_bits()) as i64).into(),
             ),
             _ => {
-                let reg = self.into_temp_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_temp_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(16, LabelValue::I64(SIGN_MASK_F64 as i64));
                 let float_cmp_mask = self.aligned_label(16, LabelValue::I32(0xdf000000u32 as i32));
                 let zero = self.aligned_label(16, LabelValue::I64(0));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; cvttss2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rq(temp.rq().unwrap()), [=>sign_mask.0]
                     ; jne >ret
                     ; ucomiss Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; ucomiss Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
-                    ; jnae =>trap_label.0
+                    ; jnae =>overflow_label.0
                     ; ucomiss Rx(reg.rx().unwrap()), [=>zero.0]
-                    ; jnb =>trap_label.0
+                    ; jnb =>overflow_label.0
                 ; ret:
                 );
 
@@ -3873,25 +5997,26 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f64::from_bits(imm.as_f64().unwrap().to_bits()) as i64).into(),
             ),
             _ => {
-                let reg = self.into_reg(F32, &mut val).unwrap();
-                let temp = self.take_reg(I32).unwrap();
+                let reg = self.into_reg(F32, &mut val);
+                let temp = self.take_reg(I32);
 
                 let sign_mask = self.aligned_label(8, LabelValue::I64(SIGN_MASK_F64 as i64));
                 let float_cmp_mask =
                     self.aligned_label(16, LabelValue::I64(0xc3e0000000000000u64 as i64));
                 let zero = self.aligned_label(16, LabelValue::I64(0));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; cvttsd2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rq(temp.rq().unwrap()), [=>sign_mask.0]
                     ; jne >ret
                     ; ucomisd Rx(reg.rx().unwrap()), Rx(reg.rx().unwrap())
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; ucomisd Rx(reg.rx().unwrap()), [=>float_cmp_mask.0]
-                    ; jnae =>trap_label.0
+                    ; jnae =>overflow_label.0
                     ; ucomisd Rx(reg.rx().unwrap()), [=>zero.0]
-                    ; jnb =>trap_label.0
+                    ; jnb =>overflow_label.0
                 ; ret:
                 );
 
@@ -3912,26 +6037,27 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f32::from_bits(imm.as_f32().unwrap().to_bits()) as u64).into(),
             ),
             _ => {
-                let reg = self.into_reg(F32, &mut val).unwrap();
+                let reg = self.into_reg(F32, &mut val);
 
-                let temp = self.take_reg(I64).unwrap();
+                let temp = self.take_reg(I64);
                 let sign_mask = self.aligned_label(16, LabelValue::I64(SIGN_MASK_F64 as i64));
                 let u64_trunc_f32_const = self.aligned_label(16, LabelValue::I32(0x5F000000));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; comiss Rx(reg.rx().unwrap()), [=>u64_trunc_f32_const.0]
                     ; jae >large
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; cvttss2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rq(temp.rq().unwrap()), Rq(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; jmp >cont
                 ; large:
                     ; subss Rx(reg.rx().unwrap()), [=>u64_trunc_f32_const.0]
                     ; cvttss2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; test Rq(temp.rq().unwrap()), Rq(temp.rq().unwrap())
-                    ; js =>trap_label.0
+                    ; js =>overflow_label.0
                     ; add Rq(temp.rq().unwrap()), [=>sign_mask.0]
                 ; cont:
                 );
@@ -3953,27 +6079,28 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 (f64::from_bits(imm.as_f64().unwrap().to_bits()) as u64).into(),
             ),
             _ => {
-                let reg = self.into_reg(F64, &mut val).unwrap();
-                let temp = self.take_reg(I64).unwrap();
+                let reg = self.into_reg(F64, &mut val);
+                let temp = self.take_reg(I64);
 
                 let sign_mask = self.aligned_label(16, LabelValue::I64(SIGN_MASK_F64 as i64));
                 let u64_trunc_f64_const =
                     self.aligned_label(16, LabelValue::I64(0x43e0000000000000));
-                let trap_label = self.trap_label();
+                let nan_label = self.trap_label(ir::TrapCode::BadConversionToInteger);
+                let overflow_label = self.trap_label(ir::TrapCode::IntegerOverflow);
 
                 dynasm!(self.asm
                     ; comisd Rx(reg.rx().unwrap()), [=>u64_trunc_f64_const.0]
                     ; jnb >large
-                    ; jp =>trap_label.0
+                    ; jp =>nan_label.0
                     ; cvttsd2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rq(temp.rq().unwrap()), 0
                     ; jge >cont
-                    ; jmp =>trap_label.0
+                    ; jmp =>overflow_label.0
                 ; large:
                     ; subsd Rx(reg.rx().unwrap()), [=>u64_trunc_f64_const.0]
                     ; cvttsd2si Rq(temp.rq().unwrap()), Rx(reg.rx().unwrap())
                     ; cmp Rq(temp.rq().unwrap()), 0
-                    ; jnge =>trap_label.0
+                    ; jnge =>overflow_label.0
                     ; add Rq(temp.rq().unwrap()), [=>sign_mask.0]
                 ; cont:
                 );
@@ -3995,9 +6122,9 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee32::from_bits((imm.as_i32().unwrap() as u32 as f32).to_bits()).into(),
             ),
             _ => {
-                let reg = self.into_reg(I32, &mut val).unwrap();
+                let reg = self.into_reg(I32, &mut val);
 
-                let temp = self.take_reg(F32).unwrap();
+                let temp = self.take_reg(F32);
 
                 dynasm!(self.asm
                     ; mov Rd(reg.rq().unwrap()), Rd(reg.rq().unwrap())
@@ -4021,8 +6148,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee64::from_bits((imm.as_i32().unwrap() as u32 as f64).to_bits()).into(),
             ),
             _ => {
-                let reg = self.into_reg(I32, &mut val).unwrap();
-                let temp = self.take_reg(F64).unwrap();
+                let reg = self.into_reg(I32, &mut val);
+                let temp = self.take_reg(F64);
 
                 dynasm!(self.asm
                     ; mov Rd(reg.rq().unwrap()), Rd(reg.rq().unwrap())
@@ -4046,9 +6173,9 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee32::from_bits((imm.as_i64().unwrap() as u64 as f32).to_bits()).into(),
             ),
             _ => {
-                let reg = self.into_reg(I64, &mut val).unwrap();
-                let out = self.take_reg(F32).unwrap();
-                let temp = self.take_reg(I64).unwrap();
+                let reg = self.into_reg(I64, &mut val);
+                let out = self.take_reg(F32);
+                let temp = self.take_reg(I64);
 
                 dynasm!(self.asm
                     ; test Rq(reg.rq().unwrap()), Rq(reg.rq().unwrap())
@@ -4084,10 +6211,10 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 Ieee64::from_bits((imm.as_i64().unwrap() as u64 as f64).to_bits()).into(),
             ),
             _ => {
-                let reg = self.into_reg(I64, &mut val).unwrap();
+                let reg = self.into_reg(I64, &mut val);
 
-                let out = self.take_reg(F32).unwrap();
-                let temp = self.take_reg(I64).unwrap();
+                let out = self.take_reg(F32);
+                let temp = self.take_reg(I64);
 
                 dynasm!(self.asm
                     ; test Rq(reg.rq().unwrap()), Rq(reg.rq().unwrap())
@@ -4180,21 +6307,236 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.push(out);
     }
 
-    unop!(i64_popcnt, popcnt, Rq, u64, |a: u64| a.count_ones() as u64);
+    unop!(i64_popcnt_inline, popcnt, Rq, u64, |a: u64| a.count_ones()
+        as u64);
+
+    pub fn i64_popcnt(&mut self) {
+        if is_x86_feature_detected!("popcnt") || self.top_constant().is_some() {
+            self.i64_popcnt_inline();
+        } else {
+            self.call_builtin(BuiltinFunction::I64Popcnt, iter::once(I64), iter::once(I64));
+        }
+    }
 
     // TODO: Use `lea` when the LHS operand isn't a temporary but both of the operands
     //       are in registers.
-    commutative_binop_i32!(i32_add, add, i32::wrapping_add);
-    commutative_binop_i32!(i32_and, and, |a, b| a & b);
-    commutative_binop_i32!(i32_or, or, |a, b| a | b);
-    commutative_binop_i32!(i32_xor, xor, |a, b| a ^ b);
-    binop_i32!(i32_sub, sub, i32::wrapping_sub);
+    commutative_binop_i32!(i32_add_wrapping, add, i32::wrapping_add);
+    commutative_binop_i32!(i32_and_ints, and, |a, b| a & b);
+    commutative_binop_i32!(i32_or_ints, or, |a, b| a | b);
+    commutative_binop_i32!(i32_xor_ints, xor, |a, b| a ^ b);
+    binop_i32!(i32_sub_wrapping, sub, i32::wrapping_sub);
+
+    pub fn i32_add(&mut self) {
+        self.check_overflow(Self::i32_add_wrapping, |a, b| {
+            a.as_i32()
+                .unwrap()
+                .checked_add(b.as_i32().unwrap())
+                .is_none()
+        });
+    }
+
+    pub fn i32_sub(&mut self) {
+        if !self.trap_on_overflow && self.sub_from_immediate(I32) {
+            return;
+        }
+
+        self.check_overflow(Self::i32_sub_wrapping, |a, b| {
+            a.as_i32()
+                .unwrap()
+                .checked_sub(b.as_i32().unwrap())
+                .is_none()
+        });
+    }
+
+    pub fn i32_and(&mut self) {
+        self.bool_logic_op(Self::i32_and_ints, |bool_, other| match other {
+            1 => Some(BoolOp::Keep(bool_)),
+            _ => None,
+        });
+    }
+
+    pub fn i32_or(&mut self) {
+        self.bool_logic_op(Self::i32_or_ints, |bool_, other| match other {
+            0 => Some(BoolOp::Keep(bool_)),
+            _ => None,
+        });
+    }
+
+    pub fn i32_xor(&mut self) {
+        self.bool_logic_op(Self::i32_xor_ints, |bool_, other| match other {
+            0 => Some(BoolOp::Keep(bool_)),
+            1 => Some(BoolOp::Invert(bool_)),
+            _ => None,
+        });
+    }
+
+    /// `and`, `or` or `xor`, emitted by `op`. If one operand is known to be 0 or 1 and the
+    /// other is a constant, `simplify` can say what the result is without any code. If both
+    /// are known to be 0 or 1 then so is the result, and since these instructions set the
+    /// flags like `test` does on their result, a `br_if` or `select` on it doesn't need to
+    /// test it again.
+    fn bool_logic_op(
+        &mut self,
+        op: fn(&mut Self),
+        simplify: impl FnOnce(ValueLocation, i32) -> Option<BoolOp>,
+    ) {
+        let right = self.pop();
+        let left = self.pop();
+
+        let simplified = match (left.imm_i32(), right.imm_i32()) {
+            (Some(_), Some(_)) => None,
+            (Some(i), None) if self.is_known_bool(right) => simplify(right, i),
+            (None, Some(i)) if self.is_known_bool(left) => simplify(left, i),
+            _ => None,
+        };
+
+        match simplified {
+            Some(BoolOp::Keep(val)) => self.push(val),
+            Some(BoolOp::Invert(val)) => {
+                self.push(val);
+                self.i32_eqz();
+            }
+            None => {
+                let bools = self.is_known_bool(left) && self.is_known_bool(right);
+
+                self.push(left);
+                self.push(right);
+                op(self);
+
+                if let (true, Some(&ValueLocation::Reg(reg))) =
+                    (bools, self.block_state.stack.last())
+                {
+                    self.block_state.regs.set_bool(reg, true);
+                    // The operation was the last instruction that `op` emitted
+                    self.asm.assume_tested(false, reg.rq().unwrap());
+                }
+            }
+        }
+    }
+
+    /// `c - x` for a constant `c` that fits in an `imm32` and an `x` that isn't constant, as
+    /// `-x + c`, so that `c` doesn't need a register of its own. This sets the overflow flag
+    /// differently from `sub` (`-x` overflows on its own for the most negative `x`), so it
+    /// can't be used when overflow traps. Returns whether it pushed the result.
+    fn sub_from_immediate(&mut self, ty: SignlessType) -> bool {
+        let operands = self.block_state.stack.len() - 2;
+        let imm = match self.block_state.stack[operands..] {
+            [ValueLocation::Immediate(left), right] if right.immediate().is_none() => match ty {
+                I32 => left.as_i32(),
+                _ => left.as_i64().and_then(|i| i.try_into()),
+            },
+            _ => None,
+        };
+        let imm = match imm {
+            Some(imm) => imm,
+            None => return false,
+        };
+
+        let mut right = self.pop();
+        self.pop();
+        let reg = self.into_temp_reg(ty, &mut right);
+        if ty == I32 {
+            dynasm!(self.asm
+                ; neg Rd(reg.rq().unwrap())
+            );
+            if imm != 0 {
+                dynasm!(self.asm
+                    ; add Rd(reg.rq().unwrap()), imm
+                );
+            }
+        } else {
+            dynasm!(self.asm
+                ; neg Rq(reg.rq().unwrap())
+            );
+            if imm != 0 {
+                dynasm!(self.asm
+                    ; add Rq(reg.rq().unwrap()), imm
+                );
+            }
+        }
+        self.block_state.regs.set_zero_extended(reg, ty == I32);
+        self.push(right);
+
+        true
+    }
+
+    /// Whether the upper 32 bits of `val` are known to be zero once it's in a register.
+    fn is_known_zero_extended(&self, val: ValueLocation) -> bool {
+        match val {
+            ValueLocation::Cond(_) => true,
+            ValueLocation::Reg(reg) => self.block_state.regs.is_zero_extended(reg),
+            ValueLocation::Immediate(i) => i.as_i32().is_some() || (i.as_bytes() as u64) >> 32 == 0,
+            ValueLocation::Stack(_) => false,
+        }
+    }
+
+    /// Whether `val` is known to be 0 or 1.
+    fn is_known_bool(&self, val: ValueLocation) -> bool {
+        match val {
+            ValueLocation::Cond(_) => true,
+            ValueLocation::Reg(reg) => self.block_state.regs.is_bool(reg),
+            ValueLocation::Immediate(i) => i.as_int().map(|i| i == 0 || i == 1).unwrap_or(false),
+            ValueLocation::Stack(_) => false,
+        }
+    }
+
+    /// `x != 0` is just `x` and `x == 0` is `i32.eqz x` when `x` is known to be 0 or 1, like
+    /// the comparisons that languages with integer booleans use to test conditions. Returns
+    /// whether it pushed the result.
+    fn compare_bool_with_zero(
+        &mut self,
+        cond: CondCode,
+        left: ValueLocation,
+        right: ValueLocation,
+    ) -> bool {
+        let val = match (left.imm_i32(), right.imm_i32()) {
+            (Some(0), None) => right,
+            (None, Some(0)) => left,
+            _ => return false,
+        };
+        if !self.is_known_bool(val) {
+            return false;
+        }
+
+        match cond {
+            cc::NOT_EQUAL => self.push(val),
+            cc::EQUAL => {
+                self.push(val);
+                self.i32_eqz();
+            }
+            _ => return false,
+        }
+
+        true
+    }
 
-    commutative_binop_i64!(i64_add, add, i64::wrapping_add);
+    commutative_binop_i64!(i64_add_wrapping, add, i64::wrapping_add);
     commutative_binop_i64!(i64_and, and, |a, b| a & b);
     commutative_binop_i64!(i64_or, or, |a, b| a | b);
     commutative_binop_i64!(i64_xor, xor, |a, b| a ^ b);
-    binop_i64!(i64_sub, sub, i64::wrapping_sub);
+    binop_i64!(i64_sub_wrapping, sub, i64::wrapping_sub);
+
+    pub fn i64_add(&mut self) {
+        self.check_overflow(Self::i64_add_wrapping, |a, b| {
+            a.as_i64()
+                .unwrap()
+                .checked_add(b.as_i64().unwrap())
+                .is_none()
+        });
+    }
+
+    pub fn i64_sub(&mut self) {
+        if !self.trap_on_overflow && self.sub_from_immediate(I64) {
+            return;
+        }
+
+        self.check_overflow(Self::i64_sub_wrapping, |a, b| {
+            a.as_i64()
+                .unwrap()
+                .checked_sub(b.as_i64().unwrap())
+                .is_none()
+        });
+    }
 
     commutative_binop_f32!(f32_add, addss, |a, b| a + b);
     commutative_binop_f32!(f32_mul, mulss, |a, b| a * b);
@@ -4228,39 +6570,23 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     binop_f32!(f32_div, divss, |a, b| a / b);
 
     pub fn f32_ceil(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::CeilF32),
-            iter::once(F32),
-            iter::once(F32),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F32Ceil, iter::once(F32), iter::once(F32));
     }
 
     pub fn f32_floor(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::FloorF32),
-            iter::once(F32),
-            iter::once(F32),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F32Floor, iter::once(F32), iter::once(F32));
     }
 
     pub fn f32_nearest(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::NearestF32),
+        self.call_builtin(
+            BuiltinFunction::F32Nearest,
             iter::once(F32),
             iter::once(F32),
-            true,
         );
     }
 
     pub fn f32_trunc(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::TruncF32),
-            iter::once(F32),
-            iter::once(F32),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F32Trunc, iter::once(F32), iter::once(F32));
     }
 
     commutative_binop_f64!(f64_add, addsd, |a, b| a + b);
@@ -4295,39 +6621,23 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     binop_f64!(f64_div, divsd, |a, b| a / b);
 
     pub fn f64_ceil(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::CeilF64),
-            iter::once(F64),
-            iter::once(F64),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F64Ceil, iter::once(F64), iter::once(F64));
     }
 
     pub fn f64_floor(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::FloorF64),
-            iter::once(F64),
-            iter::once(F64),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F64Floor, iter::once(F64), iter::once(F64));
     }
 
     pub fn f64_nearest(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::NearestF64),
+        self.call_builtin(
+            BuiltinFunction::F64Nearest,
             iter::once(F64),
             iter::once(F64),
-            true,
         );
     }
 
     pub fn f64_trunc(&mut self) {
-        self.relocated_function_call(
-            &ir::ExternalName::LibCall(ir::LibCall::TruncF64),
-            iter::once(F64),
-            iter::once(F64),
-            true,
-        );
+        self.call_builtin(BuiltinFunction::F64Trunc, iter::once(F64), iter::once(F64));
     }
 
     shift!(
@@ -4408,7 +6718,9 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             dynasm!(self.asm
                 ; pop Rq(gpr.rq().unwrap())
             );
-            self.block_state.depth.free(1);
+            self.free_depth(1);
+            // What we knew about the register was about the values used by the division
+            self.block_state.regs.forget(gpr);
             // DON'T MARK IT USED HERE! See comment in `full_div`
         }
     }
@@ -4456,7 +6768,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.block_state.regs.mark_used(RAX);
         self.block_state.regs.mark_used(RDX);
         if divisor == ValueLocation::Reg(RAX) || divisor == ValueLocation::Reg(RDX) {
-            let new_reg = self.take_reg(GPRType::Rq).unwrap();
+            let new_reg = self.take_reg(GPRType::Rq);
             self.copy_value(divisor, CCLoc::Reg(new_reg));
             self.free_value(divisor);
 
@@ -4471,7 +6783,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             dynasm!(self.asm
                 ; push rax
             );
-            self.block_state.depth.reserve(1);
+            self.reserve_depth(1);
             // DON'T FREE THIS REGISTER HERE - since we don't
             // remove it from the stack freeing the register
             // here will cause `take_reg` to allocate it.
@@ -4484,7 +6796,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             dynasm!(self.asm
                 ; push rdx
             );
-            self.block_state.depth.reserve(1);
+            self.reserve_depth(1);
             // DON'T FREE THIS REGISTER HERE - since we don't
             // remove it from the stack freeing the register
             // here will cause `take_reg` to allocate it.
@@ -4505,6 +6817,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
         do_div(self, &mut divisor);
         self.free_value(divisor);
+        self.block_state.regs.forget(RAX);
+        self.block_state.regs.forget(RDX);
 
         assert!(!self.block_state.regs.is_free(RAX));
         assert!(!self.block_state.regs.is_free(RDX));
@@ -4512,6 +6826,121 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         (ValueLocation::Reg(RAX), ValueLocation::Reg(RDX), saved)
     }
 
+    /// Jump to a trap stub if dividing the value in `rax` by `divisor` would fault, which
+    /// it does for a divisor of 0 and, for signed division, for the most negative value
+    /// divided by -1. A trap stub can return to the host, where the fault can only be caught
+    /// by a signal handler.
+    fn check_divisor(
+        &mut self,
+        ty: SignlessType,
+        signedness: Signedness,
+        divisor: &mut ValueLocation,
+    ) {
+        let signed = signedness == Signedness::Signed;
+        let known = match ty {
+            I32 => divisor.imm_i32().map(i64::from),
+            _ => divisor.imm_i64(),
+        };
+        if let ValueLocation::Cond(_) = divisor {
+            self.into_reg(ty, divisor);
+        }
+
+        match known {
+            Some(0) => {
+                self.trap(ir::TrapCode::IntegerDivisionByZero);
+                return;
+            }
+            Some(-1) if signed => {
+                // `dividend - 1` overflows exactly when the dividend is the most negative value
+                let overflow = self.trap_label(ir::TrapCode::IntegerOverflow);
+                match ty {
+                    I32 => dynasm!(self.asm
+                        ; cmp eax, 1
+                        ; jo =>overflow.0
+                    ),
+                    _ => dynasm!(self.asm
+                        ; cmp rax, 1
+                        ; jo =>overflow.0
+                    ),
+                }
+                return;
+            }
+            Some(_) => return,
+            None => {}
+        }
+
+        let zero = self.trap_label(ir::TrapCode::IntegerDivisionByZero);
+        let overflow = self.trap_label(ir::TrapCode::IntegerOverflow);
+        let not_neg1 = self.create_label();
+        match (ty, *divisor) {
+            (I32, ValueLocation::Stack(offset)) => {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp DWORD [rsp + offset], 0
+                    ; je =>zero.0
+                );
+                if signed {
+                    dynasm!(self.asm
+                        ; cmp DWORD [rsp + offset], -1
+                    );
+                }
+            }
+            (_, ValueLocation::Stack(offset)) => {
+                let offset = self.adjusted_offset(offset);
+                dynasm!(self.asm
+                    ; cmp QWORD [rsp + offset], 0
+                    ; je =>zero.0
+                );
+                if signed {
+                    dynasm!(self.asm
+                        ; cmp QWORD [rsp + offset], -1
+                    );
+                }
+            }
+            (I32, _) => {
+                let r = self.into_reg(ty, divisor).rq().unwrap();
+                dynasm!(self.asm
+                    ; test Rd(r), Rd(r)
+                    ; jz =>zero.0
+                );
+                if signed {
+                    dynasm!(self.asm
+                        ; cmp Rd(r), -1
+                    );
+                }
+            }
+            (_, _) => {
+                let r = self.into_reg(ty, divisor).rq().unwrap();
+                dynasm!(self.asm
+                    ; test Rq(r), Rq(r)
+                    ; jz =>zero.0
+                );
+                if signed {
+                    dynasm!(self.asm
+                        ; cmp Rq(r), -1
+                    );
+                }
+            }
+        }
+
+        if signed {
+            dynasm!(self.asm
+                ; jne =>not_neg1.0
+            );
+            match ty {
+                I32 => dynasm!(self.asm
+                    ; cmp eax, 1
+                    ; jo =>overflow.0
+                ),
+                _ => dynasm!(self.asm
+                    ; cmp rax, 1
+                    ; jo =>overflow.0
+                ),
+            }
+            self.define_label(not_neg1);
+        }
+    }
+
     fn i32_full_div_u(
         &mut self,
         divisor: ValueLocation,
@@ -4521,20 +6950,23 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         ValueLocation,
         impl Iterator<Item = GPR> + Clone + 'this,
     ) {
-        self.full_div(divisor, dividend, |this, divisor| match divisor {
-            ValueLocation::Stack(offset) => {
-                let offset = this.adjusted_offset(*offset);
-                dynasm!(this.asm
-                    ; xor edx, edx
-                    ; div DWORD [rsp + offset]
-                );
-            }
-            ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let r = this.into_reg(I32, divisor).unwrap();
-                dynasm!(this.asm
-                    ; xor edx, edx
-                    ; div Rd(r.rq().unwrap())
-                );
+        self.full_div(divisor, dividend, |this, divisor| {
+            this.check_divisor(I32, Signedness::Unsigned, divisor);
+            match divisor {
+                ValueLocation::Stack(offset) => {
+                    let offset = this.adjusted_offset(*offset);
+                    dynasm!(this.asm
+                        ; xor edx, edx
+                        ; div DWORD [rsp + offset]
+                    );
+                }
+                ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
+                    let r = this.into_reg(I32, divisor);
+                    dynasm!(this.asm
+                        ; xor edx, edx
+                        ; div Rd(r.rq().unwrap())
+                    );
+                }
             }
         })
     }
@@ -4548,20 +6980,23 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         ValueLocation,
         impl Iterator<Item = GPR> + Clone + 'this,
     ) {
-        self.full_div(divisor, dividend, |this, divisor| match divisor {
-            ValueLocation::Stack(offset) => {
-                let offset = this.adjusted_offset(*offset);
-                dynasm!(this.asm
-                    ; cdq
-                    ; idiv DWORD [rsp + offset]
-                );
-            }
-            ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let r = this.into_reg(I32, divisor).unwrap();
-                dynasm!(this.asm
-                    ; cdq
-                    ; idiv Rd(r.rq().unwrap())
-                );
+        self.full_div(divisor, dividend, |this, divisor| {
+            this.check_divisor(I32, Signedness::Signed, divisor);
+            match divisor {
+                ValueLocation::Stack(offset) => {
+                    let offset = this.adjusted_offset(*offset);
+                    dynasm!(this.asm
+                        ; cdq
+                        ; idiv DWORD [rsp + offset]
+                    );
+                }
+                ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
+                    let r = this.into_reg(I32, divisor);
+                    dynasm!(this.asm
+                        ; cdq
+                        ; idiv Rd(r.rq().unwrap())
+                    );
+                }
             }
         })
     }
@@ -4575,20 +7010,23 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         ValueLocation,
         impl Iterator<Item = GPR> + Clone + 'this,
     ) {
-        self.full_div(divisor, dividend, |this, divisor| match divisor {
-            ValueLocation::Stack(offset) => {
-                let offset = this.adjusted_offset(*offset);
-                dynasm!(this.asm
-                    ; xor rdx, rdx
-                    ; div QWORD [rsp + offset]
-                );
-            }
-            ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let r = this.into_reg(I64, divisor).unwrap();
-                dynasm!(this.asm
-                    ; xor rdx, rdx
-                    ; div Rq(r.rq().unwrap())
-                );
+        self.full_div(divisor, dividend, |this, divisor| {
+            this.check_divisor(I64, Signedness::Unsigned, divisor);
+            match divisor {
+                ValueLocation::Stack(offset) => {
+                    let offset = this.adjusted_offset(*offset);
+                    dynasm!(this.asm
+                        ; xor rdx, rdx
+                        ; div QWORD [rsp + offset]
+                    );
+                }
+                ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
+                    let r = this.into_reg(I64, divisor);
+                    dynasm!(this.asm
+                        ; xor rdx, rdx
+                        ; div Rq(r.rq().unwrap())
+                    );
+                }
             }
         })
     }
@@ -4602,27 +7040,282 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         ValueLocation,
         impl Iterator<Item = GPR> + Clone + 'this,
     ) {
-        self.full_div(divisor, dividend, |this, divisor| match divisor {
-            ValueLocation::Stack(offset) => {
-                let offset = this.adjusted_offset(*offset);
-                dynasm!(this.asm
-                    ; cqo
-                    ; idiv QWORD [rsp + offset]
-                );
+        self.full_div(divisor, dividend, |this, divisor| {
+            this.check_divisor(I64, Signedness::Signed, divisor);
+            match divisor {
+                ValueLocation::Stack(offset) => {
+                    let offset = this.adjusted_offset(*offset);
+                    dynasm!(this.asm
+                        ; cqo
+                        ; idiv QWORD [rsp + offset]
+                    );
+                }
+                ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
+                    let r = this.into_reg(I64, divisor);
+                    dynasm!(this.asm
+                        ; cqo
+                        ; idiv Rq(r.rq().unwrap())
+                    );
+                }
             }
-            ValueLocation::Immediate(_) | ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let r = this.into_reg(I64, divisor).unwrap();
-                dynasm!(this.asm
-                    ; cqo
-                    ; idiv Rq(r.rq().unwrap())
+        })
+    }
+
+    /// Emit the operator `wrapping`, whose code must end with the instruction that computes
+    /// its result, and with `trap_on_overflow` trap if that overflowed. Operators on two
+    /// constants are folded, so `overflows` decides whether they trap instead.
+    fn check_overflow(&mut self, wrapping: fn(&mut Self), overflows: fn(Value, Value) -> bool) {
+        if !self.trap_on_overflow {
+            return wrapping(self);
+        }
+
+        let operands = self.block_state.stack.len() - 2;
+        let constants = match self.block_state.stack[operands..] {
+            [ValueLocation::Immediate(left), ValueLocation::Immediate(right)] => {
+                Some((left, right))
+            }
+            _ => None,
+        };
+
+        wrapping(self);
+
+        match constants {
+            Some((left, right)) => {
+                if overflows(left, right) {
+                    self.trap(ARITHMETIC_OVERFLOW);
+                }
+            }
+            None => {
+                let overflow = self.trap_label(ARITHMETIC_OVERFLOW);
+                dynasm!(self.asm
+                    ; jo =>overflow.0
                 );
             }
-        })
+        }
+    }
+
+    pub fn i32_mul(&mut self) {
+        if !self.trap_on_overflow && self.mul_by_immediate(I32) {
+            return;
+        }
+
+        self.check_overflow(Self::i32_mul_wrapping, |a, b| {
+            a.as_i32()
+                .unwrap()
+                .checked_mul(b.as_i32().unwrap())
+                .is_none()
+        });
+    }
+
+    pub fn i64_mul(&mut self) {
+        if !self.trap_on_overflow && self.mul_by_immediate(I64) {
+            return;
+        }
+
+        self.check_overflow(Self::i64_mul_wrapping, |a, b| {
+            a.as_i64()
+                .unwrap()
+                .checked_mul(b.as_i64().unwrap())
+                .is_none()
+        });
+    }
+
+    /// Multiplication by a constant power of two is a shift, and by 3, 5 or 9 an `lea` that
+    /// adds the value to a multiple of itself. Neither sets the overflow flag like `imul`
+    /// does, so this can't be used when trapping on overflow. Returns whether it pushed the
+    /// result.
+    fn mul_by_immediate(&mut self, ty: SignlessType) -> bool {
+        let operands = self.block_state.stack.len() - 2;
+        let (val, imm) = match self.block_state.stack[operands..] {
+            [val, ValueLocation::Immediate(imm)] | [ValueLocation::Immediate(imm), val]
+                if val.immediate().is_none() =>
+            {
+                (val, imm)
+            }
+            _ => return false,
+        };
+        // Multiplication wraps, so the multiplier can be treated as unsigned
+        let multiplier = match ty {
+            I32 => imm.as_i32().map(|i| u64::from(i as u32)),
+            _ => imm.as_i64().map(|i| i as u64),
+        };
+        let multiplier = match multiplier {
+            Some(m) if m.is_power_of_two() || m == 3 || m == 5 || m == 9 => m,
+            _ => return false,
+        };
+
+        self.pop();
+        self.pop();
+
+        if multiplier == 1 {
+            self.push(val);
+            return true;
+        }
+
+        if multiplier.is_power_of_two() {
+            self.push(val);
+            self.push_shift_count(ty, multiplier.trailing_zeros());
+            if ty == I32 {
+                self.i32_shl();
+            } else {
+                self.i64_shl();
+            }
+            return true;
+        }
+
+        let mut val = val;
+        let reg = self.into_temp_reg(ty, &mut val);
+        match (ty, multiplier) {
+            (I32, 3) => dynasm!(self.asm
+                ; lea Rd(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 3]
+            ),
+            (I32, 5) => dynasm!(self.asm
+                ; lea Rd(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 5]
+            ),
+            (I32, _) => dynasm!(self.asm
+                ; lea Rd(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 9]
+            ),
+            (_, 3) => dynasm!(self.asm
+                ; lea Rq(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 3]
+            ),
+            (_, 5) => dynasm!(self.asm
+                ; lea Rq(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 5]
+            ),
+            (_, _) => dynasm!(self.asm
+                ; lea Rq(reg.rq().unwrap()), [Rq(reg.rq().unwrap()) * 9]
+            ),
+        }
+        self.block_state.regs.set_zero_extended(reg, ty == I32);
+        self.push(val);
+
+        true
+    }
+
+    /// Push the constant count of a shift of a value of type `ty`.
+    fn push_shift_count(&mut self, ty: SignlessType, count: u32) {
+        if ty == I32 {
+            self.push(ValueLocation::Immediate((count as i32).into()));
+        } else {
+            self.push(ValueLocation::Immediate((count as i64).into()));
+        }
+    }
+
+    /// Compute `(dividend + bias) >> shift` in a new register, where `bias` is
+    /// `(1 << shift) - 1` if `dividend` is negative and 0 otherwise. Adding the bias makes the
+    /// arithmetic shift round towards zero like `idiv` instead of towards negative infinity.
+    fn biased_shift(&mut self, ty: SignlessType, dividend: GPR, shift: u32) -> GPR {
+        let temp = self.take_reg(ty);
+        if ty == I32 {
+            dynasm!(self.asm
+                ; mov Rd(temp.rq().unwrap()), Rd(dividend.rq().unwrap())
+                ; sar Rd(temp.rq().unwrap()), 31
+                ; shr Rd(temp.rq().unwrap()), (32 - shift) as i8
+                ; add Rd(temp.rq().unwrap()), Rd(dividend.rq().unwrap())
+                ; sar Rd(temp.rq().unwrap()), shift as i8
+            );
+        } else {
+            dynasm!(self.asm
+                ; mov Rq(temp.rq().unwrap()), Rq(dividend.rq().unwrap())
+                ; sar Rq(temp.rq().unwrap()), 63
+                ; shr Rq(temp.rq().unwrap()), (64 - shift) as i8
+                ; add Rq(temp.rq().unwrap()), Rq(dividend.rq().unwrap())
+                ; sar Rq(temp.rq().unwrap()), shift as i8
+            );
+        }
+        temp
+    }
+
+    /// Division by `1 << shift` is a shift right, which is all we need for unsigned
+    /// division. The divisor can't be 0 or -1, so this never traps.
+    fn div_by_power_of_two(
+        &mut self,
+        ty: SignlessType,
+        signedness: Signedness,
+        mut dividend: ValueLocation,
+        shift: u32,
+    ) {
+        if shift == 0 {
+            self.push(dividend);
+            return;
+        }
+
+        match signedness {
+            Signedness::Unsigned => {
+                self.push(dividend);
+                self.push_shift_count(ty, shift);
+                if ty == I32 {
+                    self.i32_shr_u();
+                } else {
+                    self.i64_shr_u();
+                }
+            }
+            Signedness::Signed => {
+                let reg = self.into_reg(ty, &mut dividend);
+                let quotient = self.biased_shift(ty, reg, shift);
+                self.block_state.regs.set_zero_extended(quotient, ty == I32);
+                self.free_value(dividend);
+                self.push(ValueLocation::Reg(quotient));
+            }
+        }
+    }
+
+    /// The remainder of division by `1 << shift` is the low bits of the dividend for
+    /// unsigned division. For signed division it takes the sign of the dividend, so it's the
+    /// dividend minus the quotient shifted back left.
+    fn rem_by_power_of_two(
+        &mut self,
+        ty: SignlessType,
+        signedness: Signedness,
+        mut dividend: ValueLocation,
+        shift: u32,
+    ) {
+        if shift == 0 {
+            self.free_value(dividend);
+            if ty == I32 {
+                self.push(ValueLocation::Immediate(0i32.into()));
+            } else {
+                self.push(ValueLocation::Immediate(0i64.into()));
+            }
+            return;
+        }
+
+        match signedness {
+            Signedness::Unsigned => {
+                self.push(dividend);
+                if ty == I32 {
+                    self.push(ValueLocation::Immediate(((1u32 << shift) - 1).into()));
+                    self.i32_and();
+                } else {
+                    self.push(ValueLocation::Immediate(((1u64 << shift) - 1).into()));
+                    self.i64_and();
+                }
+            }
+            Signedness::Signed => {
+                let reg = self.into_reg(ty, &mut dividend);
+                let rem = self.biased_shift(ty, reg, shift);
+                if ty == I32 {
+                    dynasm!(self.asm
+                        ; shl Rd(rem.rq().unwrap()), shift as i8
+                        ; neg Rd(rem.rq().unwrap())
+                        ; add Rd(rem.rq().unwrap()), Rd(reg.rq().unwrap())
+                    );
+                } else {
+                    dynasm!(self.asm
+                        ; shl Rq(rem.rq().unwrap()), shift as i8
+                        ; neg Rq(rem.rq().unwrap())
+                        ; add Rq(rem.rq().unwrap()), Rq(reg.rq().unwrap())
+                    );
+                }
+                self.block_state.regs.set_zero_extended(rem, ty == I32);
+                self.free_value(dividend);
+                self.push(ValueLocation::Reg(rem));
+            }
+        }
     }
 
     // `i32_mul` needs to be separate because the immediate form of the instruction
     // has a different syntax to the immediate form of the other instructions.
-    pub fn i32_mul(&mut self) {
+    fn i32_mul_wrapping(&mut self) {
         let right = self.pop();
         let left = self.pop();
 
@@ -4648,25 +7341,24 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
         let out = match right {
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let rreg = self.into_reg(I32, &mut right).unwrap();
-                let lreg = self.into_temp_reg(I32, &mut left).unwrap();
+                let rreg = self.into_reg(I32, &mut right);
+                let lreg = self.into_temp_reg(I32, &mut left);
                 dynasm!(self.asm
                     ; imul Rd(lreg.rq().unwrap()), Rd(rreg.rq().unwrap())
                 );
                 left
             }
             ValueLocation::Stack(offset) => {
+                let lreg = self.into_temp_reg(I32, &mut left);
                 let offset = self.adjusted_offset(offset);
-
-                let lreg = self.into_temp_reg(I32, &mut left).unwrap();
                 dynasm!(self.asm
                     ; imul Rd(lreg.rq().unwrap()), [rsp + offset]
                 );
                 left
             }
             ValueLocation::Immediate(i) => {
-                let lreg = self.into_reg(I32, &mut left).unwrap();
-                let new_reg = self.take_reg(I32).unwrap();
+                let lreg = self.into_reg(I32, &mut left);
+                let new_reg = self.take_reg(I32);
                 dynasm!(self.asm
                     ; imul Rd(new_reg.rq().unwrap()), Rd(lreg.rq().unwrap()), i.as_i32().unwrap()
                 );
@@ -4681,7 +7373,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
     // `i64_mul` needs to be separate because the immediate form of the instruction
     // has a different syntax to the immediate form of the other instructions.
-    pub fn i64_mul(&mut self) {
+    fn i64_mul_wrapping(&mut self) {
         let right = self.pop();
         let left = self.pop();
 
@@ -4707,17 +7399,16 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
         let out = match right {
             ValueLocation::Reg(_) | ValueLocation::Cond(_) => {
-                let rreg = self.into_reg(I64, &mut right).unwrap();
-                let lreg = self.into_temp_reg(I64, &mut left).unwrap();
+                let rreg = self.into_reg(I64, &mut right);
+                let lreg = self.into_temp_reg(I64, &mut left);
                 dynasm!(self.asm
                     ; imul Rq(lreg.rq().unwrap()), Rq(rreg.rq().unwrap())
                 );
                 left
             }
             ValueLocation::Stack(offset) => {
+                let lreg = self.into_temp_reg(I64, &mut left);
                 let offset = self.adjusted_offset(offset);
-
-                let lreg = self.into_temp_reg(I64, &mut left).unwrap();
                 dynasm!(self.asm
                     ; imul Rq(lreg.rq().unwrap()), [rsp + offset]
                 );
@@ -4726,8 +7417,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             ValueLocation::Immediate(i) => {
                 let i = i.as_i64().unwrap();
                 if let Some(i) = i.try_into() {
-                    let new_reg = self.take_reg(I64).unwrap();
-                    let lreg = self.into_reg(I64, &mut left).unwrap();
+                    let new_reg = self.take_reg(I64);
+                    let lreg = self.into_reg(I64, &mut left);
 
                     dynasm!(self.asm
                         ; imul Rq(new_reg.rq().unwrap()), Rq(lreg.rq().unwrap()), i
@@ -4737,8 +7428,8 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
                     ValueLocation::Reg(new_reg)
                 } else {
-                    let rreg = self.into_reg(I64, &mut right).unwrap();
-                    let lreg = self.into_temp_reg(I64, &mut left).unwrap();
+                    let rreg = self.into_reg(I64, &mut right);
+                    let lreg = self.into_temp_reg(I64, &mut left);
                     dynasm!(self.asm
                         ; imul Rq(lreg.rq().unwrap()), Rq(rreg.rq().unwrap())
                     );
@@ -4865,7 +7556,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     }
 
     pub fn select(&mut self) {
-        let mut cond = self.pop();
+        let cond = self.pop();
         let mut else_ = self.pop();
         let mut then = self.pop();
 
@@ -4884,11 +7575,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         let cond_code = match cond {
             ValueLocation::Cond(cc) => cc,
             _ => {
-                let cond_reg = self.into_reg(I32, &mut cond).unwrap();
-                dynasm!(self.asm
-                    ; test Rd(cond_reg.rq().unwrap()), Rd(cond_reg.rq().unwrap())
-                );
-                self.free_value(cond);
+                self.test_zero(I32, cond);
 
                 cc::NOT_EQUAL
             }
@@ -4897,13 +7584,13 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         let else_ = if let ValueLocation::Stack(offset) = else_ {
             CCLoc::Stack(offset)
         } else {
-            CCLoc::Reg(self.into_reg(I32, &mut else_).unwrap())
+            CCLoc::Reg(self.into_reg(I32, &mut else_))
         };
 
         let then = if let ValueLocation::Stack(offset) = then {
             CCLoc::Stack(offset)
         } else {
-            CCLoc::Reg(self.into_reg(I32, &mut then).unwrap())
+            CCLoc::Reg(self.into_reg(I32, &mut then))
         };
 
         let out_gpr = match (then, else_) {
@@ -4920,7 +7607,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                 else_reg
             }
             (then, else_) => {
-                let out = self.take_reg(GPRType::Rq).unwrap();
+                let out = self.take_reg(GPRType::Rq);
                 self.copy_value(else_.into(), CCLoc::Reg(out));
                 self.cmov(cond_code, out, then);
 
@@ -4935,6 +7622,10 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     }
 
     pub fn pick(&mut self, depth: u32) {
+        // The flags only stay valid until the code for the next operator, which could be the
+        // one that uses this copy
+        self.materialize_top_cond();
+
         let idx = self.block_state.stack.len() - 1 - depth as usize;
         let v = self.block_state.stack[idx];
 
@@ -4961,13 +7652,13 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     ) {
         let locs = arg_locs(args);
 
-        self.save_volatile(..locs.len());
+        self.save_volatile(locs.len()..);
 
         if preserve_vmctx {
             dynasm!(self.asm
                 ; push Rq(VMCTX)
             );
-            self.block_state.depth.reserve(1);
+            self.reserve_depth(1);
         }
 
         let depth = self.block_state.depth.clone();
@@ -4975,28 +7666,19 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.pass_outgoing_args(&locs);
         // 2 bytes for the 64-bit `mov` opcode + register ident, the rest is the immediate
         self.reloc_sink.reloc_external(
-            (self.asm.offset().0
-                - self.func_starts[self.current_function as usize]
-                    .0
-                    .unwrap()
-                    .0) as u32
-                + 2,
+            (self.asm.offset().0 - self.func_starts[self.current_function].0.unwrap().0) as u32 + 2,
             binemit::Reloc::Abs8,
             name,
             0,
         );
-        let temp = self.take_reg(I64).unwrap();
+        let temp = self.take_reg(I64);
         dynasm!(self.asm
             ; mov Rq(temp.rq().unwrap()), QWORD 0xdeadbeefdeadbeefu64 as i64
             ; call Rq(temp.rq().unwrap())
         );
         self.block_state.regs.release(temp);
 
-        for i in locs {
-            self.free_value(i.into());
-        }
-
-        self.push_function_returns(rets);
+        self.finish_call(locs, rets);
 
         if preserve_vmctx {
             self.set_stack_depth(depth);
@@ -5004,142 +7686,383 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             dynasm!(self.asm
                 ; pop Rq(VMCTX)
             );
-            self.block_state.depth.free(1);
+            self.free_depth(1);
         }
     }
 
-    // TODO: Other memory indices
-    pub fn memory_size(&mut self) {
-        let memory_index = 0;
-        if let Some(defined_memory_index) = self.module_context.defined_memory_index(memory_index) {
-            self.push(ValueLocation::Immediate(defined_memory_index.into()));
-            self.relocated_function_call(
-                &magic::get_memory32_size_name(),
-                iter::once(I32),
-                iter::once(I32),
-                true,
+    pub fn memory_size(&mut self, memory_index: u32) {
+        let (reg, offset) = self.memory_definition(memory_index);
+        let out = self.take_reg(I64);
+
+        dynasm!(self.asm
+            ; mov Rq(out.rq().unwrap()), [
+                Rq(reg.unwrap_or(GPR::Rq(VMCTX)).rq().unwrap()) +
+                    offset +
+                    self.module_context.vmmemory_definition_current_length() as i32
+            ]
+            ; shr Rq(out.rq().unwrap()), 16
+        );
+
+        if let Some(reg) = reg {
+            self.block_state.regs.release(reg);
+        }
+
+        self.push(ValueLocation::Reg(out));
+    }
+
+    /// Memory is only ever accessed through its definition, so the runtime can move it when
+    /// it grows.
+    pub fn memory_grow(&mut self, memory_index: u32) {
+        if self.module_context.memory_index_type(memory_index) == I64 {
+            self.call_builtin(
+                BuiltinFunction::MemoryGrow64,
+                iter::once(I64),
+                iter::once(I64),
             );
         } else {
-            self.push(ValueLocation::Immediate(memory_index.into()));
-            self.relocated_function_call(
-                &magic::get_imported_memory32_size_name(),
+            self.call_builtin(
+                BuiltinFunction::MemoryGrow,
                 iter::once(I32),
                 iter::once(I32),
-                true,
             );
         }
     }
 
-    // TODO: Other memory indices
-    pub fn memory_grow(&mut self) {
-        let memory_index = 0;
-        if let Some(defined_memory_index) = self.module_context.defined_memory_index(memory_index) {
-            self.push(ValueLocation::Immediate(defined_memory_index.into()));
-            self.relocated_function_call(
-                &magic::get_memory32_grow_name(),
-                iter::once(I32).chain(iter::once(I32)),
-                iter::once(I32),
-                true,
-            );
-        } else {
-            self.push(ValueLocation::Immediate(memory_index.into()));
-            self.relocated_function_call(
-                &magic::get_imported_memory32_grow_name(),
-                iter::once(I32).chain(iter::once(I32)),
-                iter::once(I32),
-                true,
+    /// Where to find the definition of the given memory: a register holding the address of an
+    /// imported memory's definition, or `None` for the `VmCtx`, and the offset from it.
+    fn memory_definition(&mut self, memory_index: u32) -> (Option<GPR>, i32) {
+        if let Some(index) = self.module_context.defined_memory_index(memory_index) {
+            return (
+                None,
+                self.module_context.vmctx_vmmemory_definition(index) as i32,
             );
         }
-    }
 
-    // TODO: Use `ArrayVec`?
-    // TODO: This inefficiently duplicates registers but it's not really possible
-    //       to double up stack space right now.
-    /// Saves volatile (i.e. caller-saved) registers before a function call, if they are used.
-    fn save_volatile(&mut self, bounds: impl std::ops::RangeBounds<usize>) {
-        self.save_regs(SCRATCH_REGS, ..);
+        let reg = self.take_reg(I64);
+        dynasm!(self.asm
+            ; mov Rq(reg.rq().unwrap()), [
+                Rq(VMCTX) + self.module_context.vmctx_vmmemory_import_from(memory_index) as i32
+            ]
+        );
+
+        (Some(reg), 0)
     }
 
-    fn save_regs<I>(&mut self, regs: &I, bounds: impl std::ops::RangeBounds<usize>)
-    where
-        for<'a> &'a I: IntoIterator<Item = &'a GPR>,
-        I: ?Sized,
-    {
-        use std::ops::Bound::*;
+    /// Where to find the definition of the given table: a register holding the address of an
+    /// imported table's definition, or `None` for the `VmCtx`, and the offset from it.
+    fn table_definition(&mut self, table_index: u32) -> (Option<GPR>, i32) {
+        if let Some(index) = self.module_context.defined_table_index(table_index) {
+            return (
+                None,
+                self.module_context.vmctx_vmtable_definition(index) as i32,
+            );
+        }
 
-        let mut stack = mem::replace(&mut self.block_state.stack, vec![]);
-        let (start, end) = (
-            match bounds.end_bound() {
-                Unbounded => 0,
-                Included(v) => stack.len().saturating_sub(1 + v),
-                Excluded(v) => stack.len().saturating_sub(*v),
-            },
-            match bounds.start_bound() {
-                Unbounded => stack.len(),
-                Included(v) => stack.len().saturating_sub(*v),
-                Excluded(v) => stack.len().saturating_sub(1 + v),
-            },
+        let reg = self.take_reg(I64);
+        dynasm!(self.asm
+            ; mov Rq(reg.rq().unwrap()), [
+                Rq(VMCTX) + self.module_context.vmctx_vmtable_import_from(table_index) as i32
+            ]
         );
 
-        let mut slice = &mut stack[start..end];
+        (Some(reg), 0)
+    }
 
-        loop {
-            if let Some((first, rest)) = slice.split_first_mut() {
-                if let ValueLocation::Reg(vreg) = *first {
-                    if regs.into_iter().any(|r| *r == vreg) {
-                        let old = *first;
-                        *first = self.push_physical(old);
-                        for val in &mut *rest {
-                            if *val == old {
-                                self.free_value(*val);
-                                *val = *first;
-                            }
-                        }
-                    }
-                }
+    /// Trap unless the `I32` in `index` is in bounds for the given table.
+    fn check_table_index(&mut self, table_index: u32, index: GPR) {
+        let fail = self.trap_label(ir::TrapCode::TableOutOfBounds).0;
+        let (reg, offset) = self.table_definition(table_index);
 
-                slice = rest;
-            } else {
-                break;
-            }
-        }
+        dynasm!(self.asm
+            ; cmp Rd(index.rq().unwrap()), [
+                Rq(reg.unwrap_or(GPR::Rq(VMCTX)).rq().unwrap()) +
+                    offset +
+                    self.module_context.vmtable_definition_current_elements() as i32
+            ]
+            ; jae =>fail
+        );
 
-        mem::replace(&mut self.block_state.stack, stack);
+        if let Some(reg) = reg {
+            self.block_state.regs.release(reg);
+        }
     }
 
-    /// Write the arguments to the callee to the registers and the stack using the SystemV
-    /// calling convention.
-    fn pass_outgoing_args(&mut self, out_locs: &[CCLoc]) {
-        // TODO: Do alignment here
-        let total_stack_space = out_locs
-            .iter()
-            .flat_map(|&l| {
-                if let CCLoc::Stack(offset) = l {
-                    if offset > 0 {
-                        Some(offset as u32)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .max()
-            .unwrap_or(0);
-        let mut depth = self.block_state.depth.0 + total_stack_space;
+    pub fn table_size(&mut self, table_index: u32) {
+        let (reg, offset) = self.table_definition(table_index);
+        let out = self.take_reg(I32);
 
-        if depth & 1 != 0 {
-            self.set_stack_depth(StackDepth(self.block_state.depth.0 + 1));
-            depth += 1;
+        dynasm!(self.asm
+            ; mov Rd(out.rq().unwrap()), [
+                Rq(reg.unwrap_or(GPR::Rq(VMCTX)).rq().unwrap()) +
+                    offset +
+                    self.module_context.vmtable_definition_current_elements() as i32
+            ]
+        );
+
+        if let Some(reg) = reg {
+            self.block_state.regs.release(reg);
         }
 
-        let mut pending = Vec::<(ValueLocation, CCLoc)>::with_capacity(out_locs.len());
+        self.push(ValueLocation::Reg(out));
+    }
 
-        for &loc in out_locs.iter().rev() {
-            let val = self.pop();
+    pub fn table_get(&mut self, table_index: u32) {
+        let mut index = self.pop();
+        let index_reg = self.into_reg(I32, &mut index);
+        self.check_table_index(table_index, index_reg);
+        self.push(index);
 
-            pending.push((val, loc));
-        }
+        self.call_builtin(BuiltinFunction::TableGet, iter::once(I32), iter::once(I64));
+    }
+
+    pub fn table_set(&mut self, table_index: u32) {
+        let value = self.pop();
+        let mut index = self.pop();
+        let index_reg = self.into_reg(I32, &mut index);
+        self.check_table_index(table_index, index_reg);
+        self.push(index);
+        self.push(value);
+
+        self.call_builtin(BuiltinFunction::TableSet, vec![I32, I64], iter::empty());
+    }
+
+    pub fn table_grow(&mut self, _table_index: u32) {
+        self.call_builtin(BuiltinFunction::TableGrow, iter::once(I32), iter::once(I32));
+    }
+
+    /// Call the runtime's implementation of `builtin` with the `VmCtx` and arguments from the
+    /// stack, and push its results.
+    fn call_builtin(
+        &mut self,
+        builtin: BuiltinFunction,
+        arg_types: impl IntoIterator<Item = SignlessType>,
+        return_types: impl IntoIterator<Item = SignlessType>,
+    ) {
+        let deterministic = self.deterministic;
+        self.call_with_vmctx(
+            |ctx| {
+                if deterministic {
+                    let offset = ctx.module_context.vmctx_builtin_function(builtin) as i32;
+                    dynasm!(ctx.asm
+                        ; mov rax, [Rq(VMCTX) + offset]
+                    );
+                } else {
+                    dynasm!(ctx.asm
+                        ; mov rax, QWORD ctx.module_context.builtin_function(builtin) as i64
+                    );
+                }
+            },
+            arg_types,
+            return_types,
+        );
+    }
+
+    /// Call the function that the embedder registered to implement the operator `name`, with
+    /// the `VmCtx` and the operator's operands from the stack, and push its result.
+    pub fn call_libcall(
+        &mut self,
+        name: &str,
+        arg_types: impl IntoIterator<Item = SignlessType>,
+        return_types: impl IntoIterator<Item = SignlessType>,
+    ) {
+        assert!(
+            !self.deterministic,
+            "Operator libcalls are called by address, so they can't be deterministic"
+        );
+        let func = self
+            .module_context
+            .operator_libcall(name)
+            .unwrap_or_else(|| panic!("No libcall registered for {}", name));
+
+        self.call_with_vmctx(
+            |ctx| {
+                dynasm!(ctx.asm
+                    ; mov rax, QWORD func as i64
+                );
+            },
+            arg_types,
+            return_types,
+        );
+    }
+
+    /// Call a Rust function with the `VmCtx` and arguments from the stack, and push its
+    /// results. `load_address` puts the function's address in `rax`. Rust functions expect
+    /// the stack to be aligned to 16 bytes, so they're called through a stub that realigns it.
+    fn call_with_vmctx(
+        &mut self,
+        load_address: impl FnOnce(&mut Self),
+        arg_types: impl IntoIterator<Item = SignlessType>,
+        return_types: impl IntoIterator<Item = SignlessType>,
+    ) {
+        let locs = arg_locs(arg_types);
+
+        dynasm!(self.asm
+            ; push Rq(VMCTX)
+        );
+        self.reserve_depth(1);
+        let depth = self.block_state.depth.clone();
+
+        self.save_volatile(locs.len()..);
+        self.pass_outgoing_args(&locs);
+
+        let stub = self.label(|asm: &mut VecAssembler| {
+            dynasm!(asm
+                ; push rbp
+                ; mov rbp, rsp
+                ; and rsp, -16
+                ; call rax
+                ; mov rsp, rbp
+                ; pop rbp
+                ; ret
+            );
+        });
+
+        // `RAX` isn't used to pass arguments, and everything else in it was just saved
+        load_address(self);
+        dynasm!(self.asm
+            ; call =>stub.0
+        );
+
+        self.finish_call(locs, return_types);
+
+        self.set_stack_depth(depth);
+        dynasm!(self.asm
+            ; pop Rq(VMCTX)
+        );
+        self.free_depth(1);
+    }
+
+    // TODO: Use `ArrayVec`?
+    // TODO: This inefficiently duplicates registers but it's not really possible
+    //       to double up stack space right now.
+    /// Saves volatile (i.e. caller-saved) registers before a function call, if they are used.
+    ///
+    /// `bounds` are counted from the top of the stack, so callers pass `num_args..` to skip
+    /// the arguments - they're consumed by the call and so are dead afterwards, and saving
+    /// them would just mean reloading them from the stack into the argument registers.
+    fn save_volatile(&mut self, bounds: impl std::ops::RangeBounds<usize>) {
+        self.save_regs(SCRATCH_REGS, bounds);
+    }
+
+    /// Release the registers of `locals`, counted from the bottom of the stack, which the
+    /// function never reads again, so that the next call doesn't have to save them. They're
+    /// replaced with zeroes of their types, which are as good as any value since nothing
+    /// reads them.
+    pub fn discard_dead_locals(&mut self, locals: &[(u32, SignlessType)]) {
+        for &(local, ty) in locals {
+            let val = match self.block_state.stack.get(local as usize) {
+                Some(&val @ ValueLocation::Reg(_)) => val,
+                _ => continue,
+            };
+
+            self.free_value(val);
+            self.block_state.stack[local as usize] =
+                ValueLocation::Immediate(Value::default_for_type(ty));
+        }
+    }
+
+    fn save_regs<I>(&mut self, regs: &I, bounds: impl std::ops::RangeBounds<usize>)
+    where
+        for<'a> &'a I: IntoIterator<Item = &'a GPR>,
+        I: ?Sized,
+    {
+        use std::ops::Bound::*;
+
+        let mut stack = mem::replace(&mut self.block_state.stack, vec![]);
+        let (start, end) = (
+            match bounds.end_bound() {
+                Unbounded => 0,
+                Included(v) => stack.len().saturating_sub(1 + v),
+                Excluded(v) => stack.len().saturating_sub(*v),
+            },
+            match bounds.start_bound() {
+                Unbounded => stack.len(),
+                Included(v) => stack.len().saturating_sub(*v),
+                Excluded(v) => stack.len().saturating_sub(1 + v),
+            },
+        );
+
+        let mut slice = &mut stack[start..end];
+
+        loop {
+            if let Some((first, rest)) = slice.split_first_mut() {
+                if let ValueLocation::Reg(vreg) = *first {
+                    if regs.into_iter().any(|r| *r == vreg) {
+                        let old = *first;
+                        *first = self.save_value(old, regs);
+                        for val in &mut *rest {
+                            if *val == old {
+                                self.free_value(*val);
+                                *val = *first;
+                                if let ValueLocation::Reg(reg) = *first {
+                                    self.block_state.regs.mark_used(reg);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                slice = rest;
+            } else {
+                break;
+            }
+        }
+
+        mem::replace(&mut self.block_state.stack, stack);
+    }
+
+    /// Move `value` out of its register, which is one of `regs`, into a callee-saved register
+    /// if there's one free that isn't one of `regs`, or onto the stack otherwise.
+    fn save_value<I>(&mut self, value: ValueLocation, regs: &I) -> ValueLocation
+    where
+        for<'a> &'a I: IntoIterator<Item = &'a GPR>,
+        I: ?Sized,
+    {
+        if value.reg().map(GPR::type_) == Some(GPRType::Rq) {
+            if let Some(reg) = self.block_state.regs.take_callee_saved() {
+                if regs.into_iter().all(|r| *r != reg) {
+                    self.copy_value(value, CCLoc::Reg(reg));
+                    self.free_value(value);
+                    return ValueLocation::Reg(reg);
+                }
+                self.block_state.regs.release(reg);
+            }
+        }
+
+        self.push_physical(value)
+    }
+
+    /// Write the arguments to the callee to the registers and the stack using the SystemV
+    /// calling convention. Arguments passed on the stack go at the bottom of the frame, where
+    /// the callee expects them above its return address. Their slots are reserved before
+    /// anything is moved, so that values spilled while moving the others can't land in them.
+    fn pass_outgoing_args(&mut self, out_locs: &[CCLoc]) {
+        let num_stack_args = out_locs
+            .iter()
+            .filter(|loc| match loc {
+                CCLoc::Stack(_) => true,
+                CCLoc::Reg(_) => false,
+            })
+            .count() as u32;
+        let mut depth = self.block_state.depth.0 + num_stack_args;
+        // The stack has to be aligned to 16 bytes at the call, so pad above the arguments
+        if depth & 1 != 0 {
+            depth += 1;
+        }
+        self.set_stack_depth(StackDepth(depth));
+
+        let mut pending = Vec::<(ValueLocation, CCLoc)>::with_capacity(out_locs.len());
+
+        for &loc in out_locs.iter().rev() {
+            let val = self.pop();
+            // `arg_locs` counts stack arguments up from the stack pointer at the call
+            let loc = match loc {
+                CCLoc::Stack(index) => CCLoc::Stack(index - depth as i32),
+                CCLoc::Reg(_) => loc,
+            };
+
+            pending.push((val, loc));
+        }
 
         while !pending.is_empty() {
             let start_len = pending.len();
@@ -5187,6 +8110,21 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.set_stack_depth(StackDepth(depth));
     }
 
+    /// Account for a call that has just been emitted: the arguments in `locs` are consumed,
+    /// every scratch register is overwritten, and the results are pushed in the return
+    /// registers.
+    fn finish_call(&mut self, locs: Vec<CCLoc>, returns: impl IntoIterator<Item = SignlessType>) {
+        for i in locs {
+            self.free_value(i.into());
+        }
+
+        // All of the scratch registers are caller-saved, including the return registers,
+        // so `save_volatile` should have moved everything out of them
+        self.block_state.regs.clobber(SCRATCH_REGS);
+
+        self.push_function_returns(returns);
+    }
+
     fn push_function_returns(&mut self, returns: impl IntoIterator<Item = SignlessType>) {
         for loc in ret_locs(returns) {
             if let CCLoc::Reg(reg) = loc {
@@ -5212,7 +8150,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         }
 
         let mut callee = self.pop();
-        let callee_reg = self.into_temp_reg(I32, &mut callee).unwrap();
+        let callee_reg = self.into_temp_reg(I32, &mut callee);
 
         for &loc in &locs {
             if let CCLoc::Reg(r) = loc {
@@ -5220,42 +8158,22 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             }
         }
 
-        self.save_volatile(..locs.len());
+        self.save_volatile(locs.len()..);
 
         dynasm!(self.asm
             ; push Rq(VMCTX)
         );
-        self.block_state.depth.reserve(1);
+        self.reserve_depth(1);
         let depth = self.block_state.depth.clone();
 
         self.pass_outgoing_args(&locs);
 
-        let fail = self.trap_label().0;
-        let table_index = 0;
-        let reg_offset = self
-            .module_context
-            .defined_table_index(table_index)
-            .map(|index| {
-                (
-                    None,
-                    self.module_context.vmctx_vmtable_definition(index) as i32,
-                )
-            });
-
+        let out_of_bounds = self.trap_label(ir::TrapCode::TableOutOfBounds).0;
+        let bad_signature = self.trap_label(ir::TrapCode::BadSignature).0;
         let vmctx = GPR::Rq(VMCTX);
-        let (reg, offset) = reg_offset.unwrap_or_else(|| {
-            let reg = self.take_reg(I64).unwrap();
+        let (reg, offset) = self.table_definition(0);
 
-            dynasm!(self.asm
-                ; mov Rq(reg.rq().unwrap()), [
-                    Rq(VMCTX) + self.module_context.vmctx_vmtable_import_from(table_index) as i32
-                ]
-            );
-
-            (Some(reg), 0)
-        });
-
-        let temp0 = self.take_reg(I64).unwrap();
+        let temp0 = self.take_reg(I64);
 
         dynasm!(self.asm
             ; cmp Rd(callee_reg.rq().unwrap()), [
@@ -5263,7 +8181,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     offset +
                     self.module_context.vmtable_definition_current_elements() as i32
             ]
-            ; jae =>fail
+            ; jae =>out_of_bounds
             ; imul
                 Rd(callee_reg.rq().unwrap()),
                 Rd(callee_reg.rq().unwrap()),
@@ -5279,7 +8197,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
             self.block_state.regs.release(reg);
         }
 
-        let temp1 = self.take_reg(I64).unwrap();
+        let temp1 = self.take_reg(I64);
 
         dynasm!(self.asm
             ; mov Rd(temp1.rq().unwrap()), [
@@ -5292,7 +8210,7 @@ impl<'this, M: ModuleContext> Context<'this, M> {
                     Rq(callee_reg.rq().unwrap()) +
                     self.module_context.vmcaller_checked_anyfunc_type_index() as i32
             ], Rd(temp1.rq().unwrap())
-            ; jne =>fail
+            ; jne =>bad_signature
             ; mov Rq(VMCTX), [
                 Rq(temp0.rq().unwrap()) +
                     Rq(callee_reg.rq().unwrap()) +
@@ -5309,17 +8227,13 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         self.block_state.regs.release(temp1);
         self.free_value(callee);
 
-        for i in locs {
-            self.free_value(i.into());
-        }
-
-        self.push_function_returns(return_types);
+        self.finish_call(locs, return_types);
 
         self.set_stack_depth(depth);
         dynasm!(self.asm
             ; pop Rq(VMCTX)
         );
-        self.block_state.depth.free(1);
+        self.free_depth(1);
     }
 
     pub fn swap(&mut self, depth: u32) {
@@ -5330,47 +8244,64 @@ impl<'this, M: ModuleContext> Context<'this, M> {
     /// Call a function with the given index
     pub fn call_direct(
         &mut self,
-        index: u32,
+        index: FuncIndex,
         arg_types: impl IntoIterator<Item = SignlessType>,
         return_types: impl IntoIterator<Item = SignlessType>,
     ) {
         self.relocated_function_call(
-            &ir::ExternalName::user(0, index),
+            &ir::ExternalName::user(0, index.0),
             arg_types,
             return_types,
             false,
         );
     }
 
-    /// Call a function with the given index
-    pub fn call_direct_self(
+    /// Whether calls to the module's other functions should use `call_direct_local` rather
+    /// than going through the relocation sink.
+    pub fn links_calls(&self) -> bool {
+        *self.call_linkage != CallLinkage::Relocations
+    }
+
+    /// Call a function defined by the module without going through the relocation sink: by
+    /// its label if it's translated in this session, or at the address that the
+    /// `CallLinkage` gives for it if it was translated elsewhere.
+    pub fn call_direct_local(
         &mut self,
-        defined_index: u32,
+        defined_index: DefinedFuncIndex,
         arg_types: impl IntoIterator<Item = SignlessType>,
         return_types: impl IntoIterator<Item = SignlessType>,
     ) {
         let locs = arg_locs(arg_types);
 
-        self.save_volatile(..locs.len());
+        self.save_volatile(locs.len()..);
 
-        let (_, label) = self.func_starts[defined_index as usize];
+        let (_, label) = self.func_starts[defined_index];
+        let address = match self.call_linkage {
+            CallLinkage::Linked(addresses) => addresses.get(&defined_index).cloned(),
+            CallLinkage::Relocations => None,
+        };
 
         self.pass_outgoing_args(&locs);
-        dynasm!(self.asm
-            ; call =>label
-        );
-
-        for i in locs {
-            self.free_value(i.into());
+        if let Some(address) = address {
+            let temp = self.take_reg(I64);
+            dynasm!(self.asm
+                ; mov Rq(temp.rq().unwrap()), QWORD address as i64
+                ; call Rq(temp.rq().unwrap())
+            );
+            self.block_state.regs.release(temp);
+        } else {
+            dynasm!(self.asm
+                ; call =>label
+            );
         }
 
-        self.push_function_returns(return_types);
+        self.finish_call(locs, return_types);
     }
 
     /// Call a function with the given index
     pub fn call_direct_imported(
         &mut self,
-        index: u32,
+        index: ImportedFuncIndex,
         arg_types: impl IntoIterator<Item = SignlessType>,
         return_types: impl IntoIterator<Item = SignlessType>,
     ) {
@@ -5379,77 +8310,682 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         dynasm!(self.asm
             ; push Rq(VMCTX)
         );
-        self.block_state.depth.reserve(1);
+        self.reserve_depth(1);
         let depth = self.block_state.depth.clone();
 
-        self.save_volatile(..locs.len());
+        self.save_volatile(locs.len()..);
         self.pass_outgoing_args(&locs);
 
-        let callee = self.take_reg(I64).unwrap();
+        let callee = self.take_reg(I64);
 
         dynasm!(self.asm
             ; mov Rq(callee.rq().unwrap()), [
-                Rq(VMCTX) + self.module_context.vmctx_vmfunction_import_body(index) as i32
+                Rq(VMCTX) + self.module_context.vmctx_vmfunction_import_body(index.0) as i32
             ]
             ; mov Rq(VMCTX), [
-                Rq(VMCTX) + self.module_context.vmctx_vmfunction_import_vmctx(index) as i32
+                Rq(VMCTX) + self.module_context.vmctx_vmfunction_import_vmctx(index.0) as i32
             ]
             ; call Rq(callee.rq().unwrap())
         );
 
         self.block_state.regs.release(callee);
 
-        for i in locs {
-            self.free_value(i.into());
+        self.finish_call(locs, return_types);
+
+        self.set_stack_depth(depth);
+        dynasm!(self.asm
+            ; pop Rq(VMCTX)
+        );
+        self.free_depth(1);
+    }
+
+    /// Writes the function prologue and stores the arguments as locals. A function that
+    /// makes calls saves the callee-saved registers, and `RBP` if it isn't the frame pointer,
+    /// so that it can keep values that are live across its calls in them rather than
+    /// spilling them. `local_uses` is how much each local is used, weighted by how deep in
+    /// loops the uses are, or empty if that isn't known.
+    /// Emit the prologue. `max_stack_height` is the most values that are ever live at once,
+    /// locals included, or 0 if that isn't known. Unless the function never has more values
+    /// than registers to keep them in, a slot is reserved for each of them with a single
+    /// adjustment of `rsp`, so that spilling a value is a store rather than a push and the
+    /// frame doesn't grow and shrink as values are spilled and dropped.
+    pub fn start_function(
+        &mut self,
+        params: impl IntoIterator<Item = SignlessType>,
+        makes_calls: bool,
+        local_uses: &[u32],
+        max_stack_height: u32,
+    ) {
+        let locs = Vec::from_iter(arg_locs(params));
+
+        self.record_depth(StackDepth(1));
+        if let FramePointer::Preserve = self.frame_pointer {
+            dynasm!(self.asm
+                ; push rbp
+            );
+            self.record_depth(StackDepth(2));
+            dynasm!(self.asm
+                ; mov rbp, rsp
+            );
         }
 
-        self.push_function_returns(return_types);
+        if makes_calls {
+            self.callee_saved = self.frame_pointer.callee_saved();
+            self.block_state.regs = self.block_state.regs.with_callee_saved(self.callee_saved);
 
-        self.set_stack_depth(depth);
+            let mut depth = StackDepth(1 + self.frame_pointer.saved_words());
+            for reg in self.callee_saved {
+                dynasm!(self.asm
+                    ; push Rq(reg.rq().unwrap())
+                );
+                depth.reserve(1);
+                self.record_depth(depth);
+            }
+        }
+
+        self.apply_cc(&BlockCallingConvention::function_start(
+            locs,
+            self.frame_depth(),
+        ));
+
+        let int_regs = SCRATCH_REGS
+            .iter()
+            .filter(|reg| reg.type_() == GPRType::Rq)
+            .count() as u32;
+        if makes_calls || max_stack_height > int_regs {
+            let start = self.block_state.depth.0 + 1;
+            let mut depth = self.block_state.depth;
+            depth.reserve(max_stack_height);
+            self.set_stack_depth(depth);
+            self.spill_slots = start..start + max_stack_height;
+            self.reset_free_slots();
+        }
+
+        self.local_uses = local_uses.to_vec();
+        self.place_params();
+    }
+
+    /// How much the value at `position` from the bottom of the stack deserves a register.
+    /// Values that aren't locals are in use as soon as the code gets to them, so they come
+    /// before any local, and locals that are never used don't need one at all.
+    fn register_priority(&self, position: usize) -> Option<u32> {
+        if self.local_uses.is_empty() {
+            return Some(u32::max_value());
+        }
+        match self.local_uses.get(position) {
+            Some(0) => None,
+            Some(&uses) => Some(uses),
+            None => Some(u32::max_value()),
+        }
+    }
+
+    /// Give registers to the most used parameters rather than to whichever were passed in
+    /// registers. Parameters that are never used are moved to the stack to leave their
+    /// registers free for temporaries, and then the most used of the integer parameters,
+    /// as many as could have been passed in registers, are loaded into registers if they
+    /// were passed on the stack. Float parameters are always passed in registers.
+    fn place_params(&mut self) {
+        let mut int_params = Vec::new();
+        for i in 0..self.block_state.stack.len() {
+            let priority = self.register_priority(i);
+            let val = self.block_state.stack[i];
+            if priority.is_none() && val.reg().is_some() {
+                self.block_state.stack[i] = self.push_physical(val);
+            } else if val.reg().map(GPR::type_) != Some(GPRType::Rx) {
+                int_params.extend(priority.map(|priority| (priority, i)));
+            }
+        }
+
+        int_params.sort_by_key(|&(priority, i)| (cmp::Reverse(priority), i));
+        for &(_, i) in int_params.iter().take(INTEGER_ARGS_IN_GPRS.len()) {
+            let val = self.block_state.stack[i];
+            if let ValueLocation::Stack(_) = val {
+                let reg = match self.block_state.regs.take(GPRType::Rq) {
+                    Some(reg) => reg,
+                    None => break,
+                };
+                self.copy_value(val, CCLoc::Reg(reg));
+                self.block_state.stack[i] = ValueLocation::Reg(reg);
+            }
+        }
+    }
+
+    pub fn frame_pointer(&self) -> FramePointer {
+        self.frame_pointer
+    }
+
+    /// The depth of the stack at the end of the prologue, which has to be restored before
+    /// returning: the return address, the frame pointer if it's kept, and the callee-saved
+    /// registers if they're saved.
+    pub fn frame_depth(&self) -> StackDepth {
+        StackDepth(1 + self.frame_pointer.saved_words() + self.callee_saved.len() as u32)
+    }
+
+    /// Call the coverage hook, if there is one, with the guard of a new block starting here.
+    /// Values can be live in any register or in the flags at the start of a block, so the
+    /// hook is called through a stub that preserves everything (see `emit_preserving_call`).
+    pub fn cover_block(&mut self) {
+        let hook = match self.coverage {
+            Some(coverage) => coverage.trace_pc_guard,
+            None => return,
+        };
+        let guard = self.coverage_guards.next();
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_preserving_call(asm, hook as i64);
+        });
+
+        dynasm!(self.asm
+            ; push Rq(VMCTX)
+        );
+        self.reserve_depth(1);
         dynasm!(self.asm
+            ; mov Rq(VMCTX), QWORD guard as i64
+            ; call =>stub.0
             ; pop Rq(VMCTX)
         );
-        self.block_state.depth.free(1);
+        self.free_depth(1);
     }
 
-    // TODO: Reserve space to store RBX, RBP, and R12..R15 so we can use them
-    //       as scratch registers
-    /// Writes the function prologue and stores the arguments as locals
-    pub fn start_function(&mut self, params: impl IntoIterator<Item = SignlessType>) {
-        let locs = Vec::from_iter(arg_locs(params));
+    /// Call the function entry hook, if there is one. This must come straight after
+    /// `start_function`, while the arguments are still where the caller put them.
+    pub fn trace_function_entry(&mut self) {
+        let hooks = match self.trace_hooks {
+            Some(hooks) => hooks,
+            None => return,
+        };
+        let hook = match hooks.function_entry {
+            Some(hook) => hook,
+            None => return,
+        };
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_preserving_call(asm, hook as i64);
+        });
+
+        let args = if hooks.entry_arguments {
+            self.block_state.stack.clone()
+        } else {
+            vec![]
+        };
+        let num_args = args.len() as u32;
+        let args_size = (num_args * WORD_SIZE) as i32;
+
+        if num_args > 0 {
+            dynasm!(self.asm
+                ; lea rsp, [rsp - args_size]
+            );
+            self.reserve_depth(num_args);
+            // Slot `i` of the array is at `rsp + i * WORD_SIZE`
+            let depth = self.block_state.depth.0;
+            for (i, arg) in args.into_iter().enumerate() {
+                self.copy_value(arg, CCLoc::Stack(i as i32 - depth as i32));
+            }
+        }
+
+        self.call_trace_stub(stub, |this| {
+            if num_args > 0 {
+                // `call_trace_stub` has saved 3 registers below the array
+                dynasm!(this.asm
+                    ; lea rsi, [rsp + 3 * WORD_SIZE as i32]
+                );
+            } else {
+                dynasm!(this.asm
+                    ; xor esi, esi
+                );
+            }
+            dynasm!(this.asm
+                ; mov edx, num_args as i32
+            );
+        });
+
+        if num_args > 0 {
+            dynasm!(self.asm
+                ; lea rsp, [rsp + args_size]
+            );
+            self.free_depth(num_args);
+        }
+    }
+
+    /// Call the function exit hook, if there is one.
+    fn trace_function_exit(&mut self) {
+        let hook = match self.trace_hooks.and_then(|hooks| hooks.function_exit) {
+            Some(hook) => hook,
+            None => return,
+        };
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_preserving_call(asm, hook as i64);
+        });
+
+        self.call_trace_stub(stub, |_| {});
+    }
 
-        self.apply_cc(&BlockCallingConvention::function_start(locs));
+    /// Instrument the header of a new loop, which is reached on every iteration.
+    pub fn start_loop(&mut self) {
+        let loop_index = self.loops;
+        self.loops += 1;
+        self.trace_loop_iteration(loop_index);
+        self.count_hotness(loop_index);
+    }
+
+    /// Call the loop iteration hook, if there is one.
+    fn trace_loop_iteration(&mut self, loop_index: u32) {
+        let hook = match self.trace_hooks.and_then(|hooks| hooks.loop_iteration) {
+            Some(hook) => hook,
+            None => return,
+        };
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_preserving_call(asm, hook as i64);
+        });
+
+        self.call_trace_stub(stub, |this| {
+            dynasm!(this.asm
+                ; mov esi, loop_index as i32
+            );
+        });
+    }
+
+    /// Count an entry to this function towards its hotness, if there's a counter. This must
+    /// come after `start_function`, before any of the function's body.
+    pub fn count_function_entry(&mut self) {
+        self.count_hotness(ENTRY_LOOP_INDEX);
+    }
+
+    /// Increment this function's hotness counter, if there is one, calling the tier-up hook
+    /// if that makes it reach the threshold. Loop headers also get an `OsrPoint`. Values can
+    /// be live in any register or in the flags, so the counter is incremented through a stub
+    /// that preserves the flags. The stub zeroes `rcx` if the threshold has been reached,
+    /// since `jrcxz` can test that without touching the flags.
+    fn count_hotness(&mut self, loop_index: u32) {
+        let tiering = match self.tiering {
+            Some(tiering) => tiering,
+            None => return,
+        };
+
+        if loop_index != ENTRY_LOOP_INDEX {
+            let values = self.debug_locations();
+            self.osr_points.push(OsrPoint {
+                func: self.current_function,
+                loop_index,
+                code_offset: self.asm.offset().0,
+                values,
+                cfa_offset: self.block_state.depth.0 * WORD_SIZE,
+                rbp_saved: self.frame_pointer == FramePointer::Preserve,
+                saved_regs: self.callee_saved.len() as u8,
+            });
+        }
+
+        let threshold = tiering.threshold as i32;
+        let check_threshold = tiering.tier_up.is_some();
+        let increment = self.label(move |asm: &mut VecAssembler| {
+            dynasm!(asm
+                ; pushfq
+                ; add QWORD [rcx], 1
+            );
+            if check_threshold {
+                dynasm!(asm
+                    ; cmp QWORD [rcx], threshold
+                    ; jne >below
+                    ; xor ecx, ecx
+                    ; below:
+                );
+            }
+            dynasm!(asm
+                ; popfq
+                ; ret
+            );
+        });
+
+        let depth = self.block_state.depth;
+        dynasm!(self.asm
+            ; push rcx
+        );
+        self.reserve_depth(1);
+        dynasm!(self.asm
+            ; mov rcx, QWORD self.hotness_counter as i64
+            ; call =>increment.0
+        );
+
+        let tier_up = match tiering.tier_up {
+            Some(tier_up) => tier_up,
+            None => {
+                dynasm!(self.asm
+                    ; pop rcx
+                );
+                self.free_depth(1);
+                return;
+            }
+        };
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_breakpoint_stub(asm, tier_up as i64);
+        });
+        let func = self.module_context.func_index(self.current_function.0);
+        let hot = self.create_label();
+        let done = self.create_label();
+
+        dynasm!(self.asm
+            ; jrcxz =>hot.0
+            ; pop rcx
+            ; jmp =>done.0
+        );
+        self.free_depth(1);
+        self.asm.define_label(hot.0);
+        self.record_depth(StackDepth(depth.0 + 1));
+        // The hook sees the registers and stack pointer as they were at the loop header
+        dynasm!(self.asm
+            ; pop rcx
+        );
+        self.record_depth(depth);
+        dynasm!(self.asm
+            ; call =>stub.0
+            ; .dword func as i32
+            ; .dword loop_index as i32
+        );
+        self.asm.define_label(done.0);
+    }
+
+    /// Call a trace hook's stub with the index of this function as the first argument and
+    /// whatever `set_args` puts in `rsi` and `rdx` as the others. The stub preserves
+    /// everything but the argument registers, which are saved here, and `set_args` mustn't
+    /// touch the flags.
+    fn call_trace_stub(&mut self, stub: Label, set_args: impl FnOnce(&mut Self)) {
+        let func = self.module_context.func_index(self.current_function.0);
+
+        dynasm!(self.asm
+            ; push rdi
+            ; push rsi
+            ; push rdx
+        );
+        self.reserve_depth(3);
+        dynasm!(self.asm
+            ; mov edi, func as i32
+        );
+        set_args(self);
+        dynasm!(self.asm
+            ; call =>stub.0
+            ; pop rdx
+            ; pop rsi
+            ; pop rdi
+        );
+        self.free_depth(3);
+    }
+
+    /// Count an operator in `class` having run, if operators are being profiled. This must
+    /// come before any of the operator's code. An operand may be in the flags, so the
+    /// counter is incremented through a register rather than with an `add` to memory.
+    pub fn count_operator(&mut self, class: OperatorClass) {
+        if !self.profile_operators {
+            return;
+        }
+
+        let offset = self.module_context.vmctx_operator_count(class) as i32;
+        let temp = self.take_reg(I64);
+        dynasm!(self.asm
+            ; mov Rq(temp.rq().unwrap()), [Rq(VMCTX) + offset]
+            ; lea Rq(temp.rq().unwrap()), [Rq(temp.rq().unwrap()) + 1]
+            ; mov [Rq(VMCTX) + offset], Rq(temp.rq().unwrap())
+        );
+        self.block_state.regs.release(temp);
+    }
+
+    /// Where each value on the stack is, for a debugger or a higher tier to read them from the
+    /// native frame. Stack locations are relative to the current stack pointer.
+    fn debug_locations(&self) -> Vec<DebugLocation> {
+        self.block_state
+            .stack
+            .iter()
+            .map(|value| match *value {
+                ValueLocation::Reg(GPR::Rq(r)) => DebugLocation::Gpr(r),
+                ValueLocation::Reg(GPR::Rx(r)) => DebugLocation::Xmm(r),
+                ValueLocation::Stack(offset) => {
+                    DebugLocation::Stack(self.adjusted_offset(offset) as u32)
+                }
+                ValueLocation::Immediate(i) => DebugLocation::Immediate(i.as_bytes() as u64),
+                ValueLocation::Cond(cc) => DebugLocation::Flags(match cc {
+                    cc::EQUAL => FlagCondition::E,
+                    cc::NOT_EQUAL => FlagCondition::Ne,
+                    cc::GT_U => FlagCondition::A,
+                    cc::GE_U => FlagCondition::Ae,
+                    cc::LT_U => FlagCondition::B,
+                    cc::LE_U => FlagCondition::Be,
+                    cc::GT_S => FlagCondition::G,
+                    cc::GE_S => FlagCondition::Ge,
+                    cc::LT_S => FlagCondition::L,
+                    cc::LE_S => FlagCondition::Le,
+                }),
+            })
+            .collect()
+    }
+
+    /// Emit a breakpoint site for the wasm instruction at `wasm_offset`, if breakpoints are
+    /// enabled, and record where each value on the stack is there. The site must come before
+    /// any of the instruction's code, where no value is in a scratch register.
+    pub fn breakpoint_site(&mut self, wasm_offset: usize) {
+        let hook = match self.breakpoint_hook {
+            Some(hook) => hook,
+            None => return,
+        };
+        let stub = self.label(move |asm: &mut VecAssembler| {
+            emit_breakpoint_stub(asm, hook as i64);
+        });
+        let func = self.module_context.func_index(self.current_function.0);
+
+        let code_offset = self.asm.offset().0;
+        let values = self.debug_locations();
+        self.breakpoints.push(
+            Breakpoint {
+                func: self.current_function,
+                wasm_offset,
+                code_offset,
+            },
+            values,
+        );
+        dynasm!(self.asm
+            ; .byte breakpoints::DISABLED[0] as i8
+            ; .byte breakpoints::DISABLED[1] as i8
+            ; call =>stub.0
+            ; .dword func as i32
+            ; .dword wasm_offset as i32
+        );
+        debug_assert_eq!(self.asm.offset().0 - code_offset, breakpoints::SITE_SIZE);
     }
 
     pub fn ret(&mut self) {
+        self.trace_function_exit();
+
+        let mut depth = self.frame_depth();
+        for reg in self.callee_saved.iter().rev() {
+            dynasm!(self.asm
+                ; pop Rq(reg.rq().unwrap())
+            );
+            depth.free(1);
+            self.record_depth(depth);
+        }
+
+        if let FramePointer::Preserve = self.frame_pointer {
+            dynasm!(self.asm
+                ; pop rbp
+            );
+            self.record_depth(StackDepth(1));
+        }
+
         dynasm!(self.asm
             ; ret
         );
+
+        // Any code after this is reached by a jump from where the stack is still as deep as
+        // it was before returning.
+        self.record_depth(self.block_state.depth);
+    }
+
+    pub fn epilogue(&mut self) -> Result<(), Error> {
+        self.check_registers()?;
+
+        if let Some(exit_label) = self.exit_label.take() {
+            self.define_label(exit_label);
+            let frame_depth = self.frame_depth();
+            self.set_depth(frame_depth);
+            self.ret();
+        }
+
+        let trap_return = self.module_context.vmctx_trap_return();
+        for (code, label) in mem::replace(&mut self.trap_labels, Vec::new()) {
+            self.asm.define_label(label.0);
+            if let Some((trap_sp, trap_reason)) = trap_return {
+                // Return from the entry stub that the code was called through, as a failing
+                // host function does, if there is one
+                let fatal = self.create_label();
+                dynasm!(self.asm
+                    ; mov rax, [Rq(VMCTX) + trap_sp as i32]
+                    ; test rax, rax
+                    ; jz =>fatal.0
+                    ; mov DWORD [Rq(VMCTX) + trap_reason as i32], trap_number(code) as i32
+                    ; mov rsp, rax
+                    ; mov eax, 1
+                    ; ret
+                );
+                self.asm.define_label(fatal.0);
+            }
+            self.trap_sites.push(TrapSite {
+                offset: self.asm.offset().0,
+                code,
+            });
+            dynasm!(self.asm
+                ; ud2
+            );
+        }
+
+        self.asm.flush();
+        self.stats.removed_instructions = self.asm.removed();
+
+        let size = self.check_code_size()?;
+        self.unwind.len = size as u32;
+        self.stats.code_size = size;
+
+        let end = self.asm.offset().0;
+        if let Some(last) = self.operator_ranges.as_mut().and_then(|r| r.last_mut()) {
+            last.code.end = end;
+        }
+
+        Ok(())
+    }
+
+    /// Start the range of code generated for `op`, if ranges are being recorded.
+    pub fn record_operator(&mut self, wasm_offset: Option<usize>, op: impl Display) {
+        let offset = self.asm.offset().0;
+        if let Some(ranges) = &mut self.operator_ranges {
+            if let Some(last) = ranges.last_mut() {
+                last.code.end = offset;
+            }
+            ranges.push(OperatorRange {
+                wasm_offset,
+                operator: op.to_string(),
+                code: offset..offset,
+            });
+        }
+    }
+
+    /// Count another operator towards this function's statistics, failing if the code
+    /// generated so far is already over the size limits.
+    pub fn start_operator(&mut self) -> Result<(), Error> {
+        self.stats.operators += 1;
+        self.check_registers()?;
+        self.check_code_size().map(drop)
+    }
+
+    /// Count a block that was skipped because nothing branched to it.
+    pub fn skip_dead_block(&mut self) {
+        self.stats.dead_blocks += 1;
+    }
+
+    /// Record the total cost of the function's operators.
+    pub fn set_cost(&mut self, cost: u64) {
+        self.stats.cost = cost;
+    }
+
+    fn check_registers(&self) -> Result<(), Error> {
+        if self.out_of_registers {
+            Err(Error::OutOfRegisters {
+                func: self.current_function,
+            })
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn epilogue(&mut self) {}
+    /// Returns the size of the function so far.
+    fn check_code_size(&self) -> Result<usize, Error> {
+        let func_start = self.func_starts[self.current_function].0.unwrap();
+        let end = self.asm.offset().0;
+        let size = end - func_start.0;
+
+        if let Some(limit) = self.size_limits.max_function_size {
+            if size > limit {
+                return Err(Error::FunctionTooLarge {
+                    func: self.current_function,
+                    size,
+                    limit,
+                });
+            }
+        }
+        let limit = self.size_limits.module_size_limit();
+        if end > limit {
+            return Err(Error::ModuleTooLarge { size: end, limit });
+        }
+
+        Ok(size)
+    }
 
-    pub fn trap(&mut self) {
-        let trap_label = self.trap_label();
+    pub fn trap(&mut self, code: ir::TrapCode) {
+        let trap_label = self.trap_label(code);
         dynasm!(self.asm
             ; jmp =>trap_label.0
         );
     }
 
-    pub fn trap_label(&mut self) -> Label {
-        self.label(|asm: &mut Assembler| {
-            dynasm!(asm
-                ; ud2
-            );
-        })
+    /// The stub that traps for `code`, which is shared by every check in this function that
+    /// can fail for that reason.
+    pub fn trap_label(&mut self, code: ir::TrapCode) -> Label {
+        if let Some(&(_, label)) = self.trap_labels.iter().find(|(c, _)| *c == code) {
+            return label;
+        }
+
+        let label = self.create_label();
+        self.trap_labels.push((code, label));
+        label
     }
 
     pub fn ret_label(&mut self) -> Label {
-        self.label(|asm: &mut Assembler| {
-            dynasm!(asm
-                ; ret
-            );
-        })
+        // The exit hook is passed the function's index, so the code that calls it can't be
+        // shared between functions
+        if self
+            .trace_hooks
+            .and_then(|hooks| hooks.function_exit)
+            .is_some()
+        {
+            if let Some(label) = self.exit_label {
+                return label;
+            }
+            let label = self.create_label();
+            self.exit_label = Some(label);
+            return label;
+        }
+
+        // The code is shared between functions, so functions that restore the callee-saved
+        // registers need a label of their own
+        let (frame_pointer, callee_saved) = (self.frame_pointer, self.callee_saved);
+        if callee_saved.is_empty() {
+            self.label(move |asm: &mut VecAssembler| {
+                frame_pointer.emit_ret(asm);
+            })
+        } else {
+            self.label(move |asm: &mut VecAssembler| {
+                for reg in callee_saved.iter().rev() {
+                    dynasm!(asm
+                        ; pop Rq(reg.rq().unwrap())
+                    );
+                }
+                frame_pointer.emit_ret(asm);
+            })
+        }
     }
 
     fn label<F>(&mut self, fun: F) -> Label
@@ -5464,13 +9000,14 @@ impl<'this, M: ModuleContext> Context<'this, M> {
         F: IntoLabel,
     {
         let key = fun.key();
-        if let Some((label, _, _)) = self.labels.get(&(align, key)) {
+        if let Some((label, _, _, _)) = self.labels.get(&(align, key)) {
             return *label;
         }
 
         let label = self.create_label();
+        let order = self.labels.len();
         self.labels
-            .insert((align, key), (label, align, Some(fun.callback())));
+            .insert((align, key), (label, align, order, Some(fun.callback())));
 
         label
     }
@@ -5485,23 +9022,134 @@ impl<'this, M: ModuleContext> Context<'this, M> {
 
 trait IntoLabel {
     fn key(&self) -> Either<TypeId, (LabelValue, Option<LabelValue>)>;
-    fn callback(self) -> Box<dyn FnMut(&mut Assembler)>;
+    fn callback(self) -> Box<dyn FnMut(&mut VecAssembler)>;
 }
 
 impl<F> IntoLabel for F
 where
-    F: FnMut(&mut Assembler) + Any,
+    F: FnMut(&mut VecAssembler) + Any,
 {
     fn key(&self) -> Either<TypeId, (LabelValue, Option<LabelValue>)> {
         Either::Left(TypeId::of::<Self>())
     }
 
-    fn callback(self) -> Box<dyn FnMut(&mut Assembler)> {
+    fn callback(self) -> Box<dyn FnMut(&mut VecAssembler)> {
         Box::new(self)
     }
 }
 
-fn const_value(val: LabelValue) -> impl FnMut(&mut Assembler) {
+/// Emit a stub that calls the host function at `hook` with the arguments in `rdi`, `rsi` and
+/// `rdx`, preserving the flags and every other register that the host might clobber. The
+/// stub realigns the stack for the host, so it can be called from anywhere.
+fn emit_preserving_call(asm: &mut VecAssembler, hook: i64) {
+    dynasm!(asm
+        ; push rbp
+        ; mov rbp, rsp
+        ; pushfq
+        ; push rax
+        ; push rcx
+        ; push rdx
+        ; push rsi
+        ; push r8
+        ; push r9
+        ; push r10
+        ; push r11
+        ; sub rsp, 16 * 16
+        ; and rsp, -16
+    );
+    for i in 0..16u8 {
+        dynasm!(asm
+            ; movdqu [rsp + i as i32 * 16], Rx(i)
+        );
+    }
+    dynasm!(asm
+        ; mov rax, QWORD hook
+        ; call rax
+    );
+    for i in 0..16u8 {
+        dynasm!(asm
+            ; movdqu Rx(i), [rsp + i as i32 * 16]
+        );
+    }
+    dynasm!(asm
+        ; lea rsp, [rbp - 9 * WORD_SIZE as i32]
+        ; pop r11
+        ; pop r10
+        ; pop r9
+        ; pop r8
+        ; pop rsi
+        ; pop rdx
+        ; pop rcx
+        ; pop rax
+        ; popfq
+        ; pop rbp
+        ; ret
+    );
+}
+
+fn emit_endbr64(asm: &mut impl Emitter) {
+    asm.extend(&ENDBR64);
+}
+
+/// Emit the stub that enabled breakpoint sites call. It saves every register into a
+/// `BreakpointFrame` for the hook, and restores them from it afterwards. The function index
+/// and wasm offset to pass to the hook follow the call, so the stub returns to just after
+/// them.
+fn emit_breakpoint_stub(asm: &mut VecAssembler, hook: i64) {
+    const XMMS: i32 = 0;
+    const GPRS: i32 = 16 * 16;
+    const RFLAGS: i32 = GPRS + 16 * WORD_SIZE as i32;
+    const RETURN_ADDRESS: i32 = RFLAGS + WORD_SIZE as i32;
+
+    dynasm!(asm
+        ; pushfq
+        ; lea rsp, [rsp - RFLAGS]
+    );
+    for r in (0..16u8).filter(|&r| r != rq::RSP) {
+        dynasm!(asm
+            ; mov [rsp + GPRS + r as i32 * WORD_SIZE as i32], Rq(r)
+        );
+    }
+    for i in 0..16u8 {
+        dynasm!(asm
+            ; movdqu [rsp + XMMS + i as i32 * 16], Rx(i)
+        );
+    }
+    dynasm!(asm
+        // The stack pointer at the site, before the call
+        ; lea rax, [rsp + RETURN_ADDRESS + WORD_SIZE as i32]
+        ; mov [rsp + GPRS + rq::RSP as i32 * WORD_SIZE as i32], rax
+        ; mov rax, [rsp + RETURN_ADDRESS]
+        ; mov edi, [rax]
+        ; mov esi, [rax + 4]
+        // Return to after the data
+        ; lea rax, [rax + 8]
+        ; mov [rsp + RETURN_ADDRESS], rax
+        ; mov rdx, rsp
+        ; mov rbx, rsp
+        ; and rsp, -16
+        ; mov rax, QWORD hook
+        ; call rax
+        ; mov rsp, rbx
+    );
+    for i in 0..16u8 {
+        dynasm!(asm
+            ; movdqu Rx(i), [rsp + XMMS + i as i32 * 16]
+        );
+    }
+    for r in (0..16u8).filter(|&r| r != rq::RSP) {
+        dynasm!(asm
+            ; mov Rq(r), [rsp + GPRS + r as i32 * WORD_SIZE as i32]
+        );
+    }
+    dynasm!(asm
+        ; lea rsp, [rsp + RFLAGS]
+        ; popfq
+        ; ret
+    );
+}
+
+fn const_value(val: LabelValue) -> impl FnMut(&mut VecAssembler) {
     move |asm| match val {
         LabelValue::I32(val) => dynasm!(asm
             ; .dword val
@@ -5512,7 +9160,7 @@ fn const_value(val: LabelValue) -> impl FnMut(&mut Assembler) {
     }
 }
 
-fn const_values(a: LabelValue, b: LabelValue) -> impl FnMut(&mut Assembler) {
+fn const_values(a: LabelValue, b: LabelValue) -> impl FnMut(&mut VecAssembler) {
     move |asm| {
         match a {
             LabelValue::I32(val) => dynasm!(asm
@@ -5538,7 +9186,7 @@ impl IntoLabel for LabelValue {
     fn key(&self) -> Either<TypeId, (LabelValue, Option<LabelValue>)> {
         Either::Right((*self, None))
     }
-    fn callback(self) -> Box<dyn FnMut(&mut Assembler)> {
+    fn callback(self) -> Box<dyn FnMut(&mut VecAssembler)> {
         Box::new(const_value(self))
     }
 }
@@ -5547,7 +9195,7 @@ impl IntoLabel for (LabelValue, LabelValue) {
     fn key(&self) -> Either<TypeId, (LabelValue, Option<LabelValue>)> {
         Either::Right((self.0, Some(self.1)))
     }
-    fn callback(self) -> Box<dyn FnMut(&mut Assembler)> {
+    fn callback(self) -> Box<dyn FnMut(&mut VecAssembler)> {
         Box::new(const_values(self.0, self.1))
     }
 }
@@ -0,0 +1,193 @@
+//! A command-line tool for trying out Lightbeam on a module without writing an embedder.
+//! Modules can be in either the binary or the text format.
+//!
+//! Build it with `cargo build --features cli`.
+
+extern crate lightbeam;
+
+use lightbeam::ValueType;
+use lightbeam::{
+    read_module, translate, translate_only, write_microwasm, DefinedFuncIndex, Instance,
+};
+use std::env;
+use std::io;
+use std::process;
+use std::time::Instant;
+
+const USAGE: &str = "\
+Usage:
+    lightbeam compile <module>              Translate a module and show the code size of each function
+    lightbeam disasm <module>               Show the generated machine code
+    lightbeam microwasm <module>            Show the microwasm that each function is converted to
+    lightbeam run <module> <func> [args...] Call an exported function and print what it returns
+
+Traps are executed as `ud2`, so a function that traps kills the process.";
+
+/// The most integer and float arguments that fit in registers, which is all that `run`
+/// supports.
+const MAX_INT_ARGS: usize = 5;
+const MAX_FLOAT_ARGS: usize = 8;
+
+fn compile(path: &str) -> Result<(), String> {
+    let data = read_module(path).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let module = translate_only(&data).map_err(|e| e.to_string())?;
+    let elapsed = start.elapsed();
+
+    let code_section = match module.code_section() {
+        Some(code_section) => code_section,
+        None => {
+            println!("The module doesn't define any functions");
+            return Ok(());
+        }
+    };
+
+    let num_funcs = code_section.funcs().count();
+    for i in 0..num_funcs {
+        let stats = code_section.function_stats(DefinedFuncIndex(i as u32));
+        println!(
+            "Function {}: {} bytes, {} operators, {} spills",
+            i, stats.code_size, stats.operators, stats.spills
+        );
+    }
+    println!(
+        "Translated {} bytes of wasm in {} functions to {} bytes of code in {:?}",
+        data.len(),
+        num_funcs,
+        code_section.buffer().len(),
+        elapsed
+    );
+
+    Ok(())
+}
+
+fn disasm(path: &str) -> Result<(), String> {
+    let data = read_module(path).map_err(|e| e.to_string())?;
+    let module = translate_only(&data).map_err(|e| e.to_string())?;
+    if let Some(code_section) = module.code_section() {
+        print!("{}", code_section.disassembly().map_err(|e| e.to_string())?);
+    }
+
+    Ok(())
+}
+
+fn microwasm(path: &str) -> Result<(), String> {
+    let data = read_module(path).map_err(|e| e.to_string())?;
+    let stdout = io::stdout();
+    write_microwasm(&data, stdout.lock()).map_err(|e| e.to_string())
+}
+
+/// An argument parsed according to the type of the parameter that it's passed as.
+enum Arg {
+    Int(u64),
+    Float(f64),
+}
+
+fn parse_arg(ty: ValueType, arg: &str) -> Result<Arg, String> {
+    let invalid = || format!("Invalid {:?} argument {:?}", ty, arg);
+    // Integers can be given as either signed or unsigned
+    Ok(match ty {
+        ValueType::I32 => Arg::Int(u64::from(
+            arg.parse::<i32>()
+                .map(|i| i as u32)
+                .or_else(|_| arg.parse::<u32>())
+                .map_err(|_| invalid())?,
+        )),
+        ValueType::I64 => Arg::Int(
+            arg.parse::<i64>()
+                .map(|i| i as u64)
+                .or_else(|_| arg.parse::<u64>())
+                .map_err(|_| invalid())?,
+        ),
+        ValueType::F32 => Arg::Float(f64::from_bits(u64::from(
+            arg.parse::<f32>().map_err(|_| invalid())?.to_bits(),
+        ))),
+        ValueType::F64 => Arg::Float(arg.parse::<f64>().map_err(|_| invalid())?),
+    })
+}
+
+/// Call `func` with every argument in a register, so that one signature works for any
+/// function within the limits on arguments, and print what it returns.
+fn call(instance: &Instance, func: &str, args: &[String]) -> Result<(), String> {
+    let (func, ty) = instance
+        .module()
+        .exported_func(func)
+        .ok_or_else(|| format!("No exported function {:?}", func))?;
+    if ty.params.len() != args.len() {
+        return Err(format!(
+            "The function takes {} arguments but {} were given",
+            ty.params.len(),
+            args.len()
+        ));
+    }
+
+    let mut ints = [0u64; MAX_INT_ARGS];
+    let mut floats = [0f64; MAX_FLOAT_ARGS];
+    let (mut num_ints, mut num_floats) = (0, 0);
+    for (&param, arg) in ty.params.iter().zip(args) {
+        match parse_arg(param, arg)? {
+            Arg::Int(i) if num_ints < MAX_INT_ARGS => {
+                ints[num_ints] = i;
+                num_ints += 1;
+            }
+            Arg::Float(f) if num_floats < MAX_FLOAT_ARGS => {
+                floats[num_floats] = f;
+                num_floats += 1;
+            }
+            _ => return Err("Arguments that are passed on the stack aren't supported".into()),
+        }
+    }
+
+    let [i0, i1, i2, i3, i4] = ints;
+    let [f0, f1, f2, f3, f4, f5, f6, f7] = floats;
+    let args = (i0, i1, i2, i3, i4, f0, f1, f2, f3, f4, f5, f6, f7);
+    // The types were checked against the function's signature above
+    match ty.returns[..] {
+        [] => unsafe { instance.execute_func_unchecked::<_, ()>(func, args) },
+        [ValueType::I32] => println!("{}", unsafe {
+            instance.execute_func_unchecked::<_, u64>(func, args) as i32
+        }),
+        [ValueType::I64] => println!("{}", unsafe {
+            instance.execute_func_unchecked::<_, u64>(func, args) as i64
+        }),
+        [ValueType::F32] => println!(
+            "{}",
+            f32::from_bits(
+                unsafe { instance.execute_func_unchecked::<_, f64>(func, args) }.to_bits() as u32
+            )
+        ),
+        [ValueType::F64] => println!("{}", unsafe {
+            instance.execute_func_unchecked::<_, f64>(func, args)
+        }),
+        _ => return Err(format!("Can't return {:?}", ty.returns)),
+    }
+
+    Ok(())
+}
+
+fn run(path: &str, func: &str, args: &[String]) -> Result<(), String> {
+    let data = read_module(path).map_err(|e| e.to_string())?;
+    let instance = translate(&data).map_err(|e| e.to_string())?;
+    call(&instance, func, args)
+}
+
+fn maybe_main() -> Result<(), String> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["compile", path] => compile(path),
+        ["disasm", path] => disasm(path),
+        ["microwasm", path] => microwasm(path),
+        ["run", path, func, ..] => run(path, func, &args[3..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    if let Err(e) = maybe_main() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,169 @@
+//! Branch hints from the `metadata.code.branch_hint` custom section of the branch hinting
+//! proposal, which producers like LLVM emit from profiles or from `__builtin_expect`.
+//!
+//! A hint says whether the condition of an `if` or `br_if` is likely to be true. We use them
+//! to move blocks that are unlikely to run to the end of the function, so that the code that
+//! does run is contiguous, and to pick which side of a conditional branch gets the `jcc`.
+
+use crate::microwasm::{BrTarget, Operator};
+use std::{collections::HashMap, hash::Hash};
+use wasmparser::{BinaryReader, BinaryReaderError};
+
+pub const SECTION_NAME: &str = "metadata.code.branch_hint";
+
+/// Whether each hinted branch in a function is likely to be taken, by the offset of the
+/// branch from the start of the function's body.
+pub type FunctionBranchHints = HashMap<u32, bool>;
+
+/// The hints for every function in a module, by index in the function index space.
+#[derive(Debug, Default, Clone)]
+pub struct BranchHints {
+    funcs: HashMap<u32, FunctionBranchHints>,
+}
+
+impl BranchHints {
+    /// Parse the contents of the section, after its name.
+    pub fn parse(mut reader: BinaryReader) -> Result<Self, BinaryReaderError> {
+        let mut funcs = HashMap::new();
+        for _ in 0..reader.read_var_u32()? {
+            let func = reader.read_var_u32()?;
+            let mut hints = HashMap::new();
+            for _ in 0..reader.read_var_u32()? {
+                let offset = reader.read_var_u32()?;
+                let position = reader.original_position();
+                if reader.read_var_u32()? != 1 {
+                    return Err(BinaryReaderError {
+                        message: "branch hint must be one byte",
+                        offset: position,
+                    });
+                }
+                let position = reader.original_position();
+                let likely = match reader.read_u8()? {
+                    0 => false,
+                    1 => true,
+                    _ => {
+                        return Err(BinaryReaderError {
+                            message: "branch hint must be 0 or 1",
+                            offset: position,
+                        })
+                    }
+                };
+                hints.insert(offset, likely);
+            }
+            funcs.insert(func, hints);
+        }
+
+        if !reader.eof() {
+            return Err(BinaryReaderError {
+                message: "unexpected data at the end of the branch hint section",
+                offset: reader.original_position(),
+            });
+        }
+
+        Ok(BranchHints { funcs })
+    }
+
+    pub fn func(&self, func_index: u32) -> Option<&FunctionBranchHints> {
+        self.funcs.get(&func_index)
+    }
+}
+
+/// Move each block that a hint says is unlikely to run from right after the `br_if` that
+/// enters it to the end of the function. `likely` is whether the `br_if` from the wasm
+/// operator at the given offset in the module is likely to be taken, if there's a hint.
+///
+/// We only move blocks that the backend can still translate once they come after
+/// everything else: blocks with just the one caller, which leave the function by returning
+/// or trapping rather than branching to a label that would now be behind them.
+pub fn sink_cold_blocks<L>(
+    ops: &mut Vec<(Option<usize>, Operator<L>)>,
+    likely: impl Fn(usize) -> Option<bool>,
+) where
+    L: Hash + Eq + Clone,
+{
+    // Anything after the last operator would be reached by falling off the end of it
+    match ops.last() {
+        Some((_, Operator::Unreachable)) | Some((_, Operator::Br { .. })) => {}
+        Some((_, Operator::BrTable(_))) => {}
+        _ => return,
+    }
+
+    let mut single_caller = HashMap::new();
+    let mut cold = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let unlikely = match &ops[i] {
+            (
+                _,
+                Operator::Block {
+                    label,
+                    num_callers,
+                    has_backwards_callers,
+                    ..
+                },
+            ) => {
+                single_caller.insert(
+                    label.clone(),
+                    *num_callers == Some(1) && !has_backwards_callers,
+                );
+                None
+            }
+            (Some(offset), Operator::BrIf { then, else_ }) => match likely(*offset) {
+                Some(true) => Some(else_.target.clone()),
+                Some(false) => Some(then.target.clone()),
+                None => None,
+            },
+            _ => None,
+        };
+        i += 1;
+
+        match (unlikely, ops.get(i)) {
+            (Some(BrTarget::Label(unlikely)), Some((_, Operator::Label(next))))
+                if unlikely == *next && single_caller.get(next) == Some(&true) => {}
+            _ => continue,
+        }
+
+        // A block at the end of the function is already where we'd move it to
+        let end = match ops[i + 1..].iter().position(|(_, op)| match op {
+            Operator::Label(_) => true,
+            _ => false,
+        }) {
+            Some(len) => i + 1 + len,
+            None => continue,
+        };
+        if !leaves_function(&ops[i + 1..end]) {
+            continue;
+        }
+
+        // Declarations of the blocks after this one have to stay before them
+        let (decls, rest): (Vec<_>, Vec<_>) = ops.drain(i..end).partition(|(_, op)| match op {
+            Operator::Block { .. } => true,
+            _ => false,
+        });
+        log_trace!("Moving {} operators to the end of the function", rest.len());
+        cold.extend(rest);
+        ops.splice(i..i, decls);
+    }
+
+    ops.extend(cold);
+}
+
+/// Whether the straight-line code `ops` returns or traps without branching to any label.
+fn leaves_function<L>(ops: &[(Option<usize>, Operator<L>)]) -> bool {
+    let targets_label = |target: &BrTarget<L>| match target {
+        BrTarget::Label(_) => true,
+        BrTarget::Return => false,
+    };
+
+    let mut leaves = false;
+    for (_, op) in ops {
+        match op {
+            Operator::Br { target } if targets_label(target) => return false,
+            Operator::BrIf { .. } | Operator::BrTable(_) => return false,
+            Operator::Br { .. } | Operator::Unreachable => leaves = true,
+            _ => {}
+        }
+    }
+
+    leaves
+}
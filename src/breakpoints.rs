@@ -0,0 +1,190 @@
+//! Patchable breakpoint sites, so that embedders can build an interactive wasm debugger on
+//! top of Lightbeam.
+//!
+//! When the code is generated with `DebugOptions`, the code for every wasm instruction
+//! starts with a site that jumps over a call to a stub. Enabling the site patches the jump
+//! into a no-op, so that the stub calls the hook before the instruction runs. Enabling every
+//! site single-steps through the module. Each site also records where every wasm value is,
+//! so that the hook can read locals and the operand stack out of the native frame.
+
+use crate::code_buffer::CodeBuffer;
+use crate::index_space::DefinedFuncIndex;
+use std::sync::Mutex;
+
+/// Called when execution reaches an enabled breakpoint, before the instruction at
+/// `wasm_offset` in the module runs. `func` is in the module's function index space, which
+/// counts imports. `frame` holds the registers at the breakpoint, and any changes to them
+/// apart from `rsp` take effect when execution resumes. Like the trace hooks, this is called
+/// on the wasm stack, so it must not unwind.
+pub type BreakpointHook =
+    unsafe extern "C" fn(func: u32, wasm_offset: u32, frame: *mut BreakpointFrame);
+
+/// The registers at a breakpoint, as the stub saves them for the hook.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BreakpointFrame {
+    /// `xmm0` to `xmm15`, low half first.
+    pub xmms: [[u64; 2]; 16],
+    /// The general-purpose registers in the order that they're encoded, from `rax` to `r15`.
+    pub gprs: [u64; 16],
+    pub rflags: u64,
+}
+
+impl BreakpointFrame {
+    const RSP: usize = 4;
+
+    /// Read the value at `location`, as the bits of a 64-bit word. 32-bit values are in the
+    /// low half, and the high half is unspecified.
+    ///
+    /// # Safety
+    ///
+    /// `location` must be one that `TranslatedCodeSection::value_locations` gave for the
+    /// breakpoint that this frame is stopped at.
+    pub unsafe fn read(&self, location: DebugLocation) -> u64 {
+        match location {
+            DebugLocation::Gpr(r) => self.gprs[r as usize],
+            DebugLocation::Xmm(r) => self.xmms[r as usize][0],
+            DebugLocation::Stack(offset) => {
+                *((self.gprs[Self::RSP] + u64::from(offset)) as *const u64)
+            }
+            DebugLocation::Immediate(bits) => bits,
+            DebugLocation::Flags(condition) => condition.holds(self.rflags) as u64,
+        }
+    }
+}
+
+/// Where a wasm value is at a breakpoint.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugLocation {
+    /// A general-purpose register, numbered as it's encoded.
+    Gpr(u8),
+    /// The low half of an XMM register.
+    Xmm(u8),
+    /// A word on the stack, this many bytes above the stack pointer.
+    Stack(u32),
+    /// A constant that the code hasn't needed to materialize, as its bits.
+    Immediate(u64),
+    /// The result of a comparison that's still in the flags, which is 1 if the condition
+    /// holds and 0 otherwise.
+    Flags(FlagCondition),
+}
+
+/// A condition on the flags, named like the `setcc` instruction that tests it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlagCondition {
+    E,
+    Ne,
+    A,
+    Ae,
+    B,
+    Be,
+    G,
+    Ge,
+    L,
+    Le,
+}
+
+impl FlagCondition {
+    pub fn holds(self, rflags: u64) -> bool {
+        let flag = |bit: u32| rflags & (1 << bit) != 0;
+        let (cf, zf, sf, of) = (flag(0), flag(6), flag(7), flag(11));
+
+        match self {
+            FlagCondition::E => zf,
+            FlagCondition::Ne => !zf,
+            FlagCondition::A => !cf && !zf,
+            FlagCondition::Ae => !cf,
+            FlagCondition::B => cf,
+            FlagCondition::Be => cf || zf,
+            FlagCondition::G => !zf && sf == of,
+            FlagCondition::Ge => sf == of,
+            FlagCondition::L => sf != of,
+            FlagCondition::Le => zf || sf != of,
+        }
+    }
+}
+
+/// Options for code that a debugger can stop in.
+#[derive(Debug, Copy, Clone)]
+pub struct DebugOptions {
+    pub breakpoint_hook: BreakpointHook,
+}
+
+// Hooks are compared by address, which is all that the generated code depends on.
+impl PartialEq for DebugOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.breakpoint_hook as usize == other.breakpoint_hook as usize
+    }
+}
+
+impl Eq for DebugOptions {}
+
+/// The size of a site: a `jmp` or `nop`, a `call` to the stub, and the function index and
+/// wasm offset that the stub reads from its return address to pass to the hook.
+pub(crate) const SITE_SIZE: usize = 2 + 5 + 8;
+/// A short `jmp` over the rest of the site.
+pub(crate) const DISABLED: [u8; 2] = [0xeb, (SITE_SIZE - 2) as u8];
+/// A two-byte `nop`, which falls through to the `call`.
+const ENABLED: [u8; 2] = [0x66, 0x90];
+
+/// A place where execution can be stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub func: DefinedFuncIndex,
+    /// The offset in the module of the wasm instruction that this site comes before.
+    pub wasm_offset: usize,
+    /// The offset of the site in the code section.
+    pub code_offset: usize,
+}
+
+/// The breakpoint sites in a code section, in the order that they were generated.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    sites: Vec<Breakpoint>,
+    /// Where the values are at each site.
+    values: Vec<Vec<DebugLocation>>,
+    /// Held while patching, since that changes the protection of whole pages.
+    patching: Mutex<()>,
+}
+
+impl Breakpoints {
+    pub(crate) fn push(&mut self, site: Breakpoint, values: Vec<DebugLocation>) {
+        self.sites.push(site);
+        self.values.push(values);
+    }
+
+    pub fn sites(&self) -> &[Breakpoint] {
+        &self.sites
+    }
+
+    pub(crate) fn value_locations(&self, site: &Breakpoint) -> &[DebugLocation] {
+        let index = self
+            .sites
+            .binary_search_by_key(&site.code_offset, |site| site.code_offset)
+            .expect("Not a breakpoint in this code section");
+        &self.values[index]
+    }
+
+    pub(crate) fn is_enabled(&self, code: &CodeBuffer, site: &Breakpoint) -> bool {
+        code[site.code_offset..site.code_offset + ENABLED.len()] == ENABLED
+    }
+
+    /// Enable or disable every site that `filter` accepts, returning how many it accepted.
+    pub(crate) fn set(
+        &self,
+        code: &CodeBuffer,
+        mut filter: impl FnMut(&Breakpoint) -> bool,
+        enabled: bool,
+    ) -> usize {
+        let _guard = self.patching.lock().unwrap();
+        let bytes = if enabled { ENABLED } else { DISABLED };
+
+        let mut count = 0;
+        for site in self.sites.iter().filter(|site| filter(site)) {
+            unsafe { code.patch(site.code_offset, &bytes) };
+            count += 1;
+        }
+
+        count
+    }
+}
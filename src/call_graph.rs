@@ -0,0 +1,131 @@
+//! The calls between a module's functions, collected while it's translated so that embedders
+//! can tell which functions can run without parsing the wasm themselves.
+//!
+//! Functions are numbered by function index, counting imports. Imported functions don't call
+//! anything as far as we know, since their code isn't in the module. Which functions an
+//! indirect call can reach is decided by signature: any function that the module's element
+//! segments put into a table and that has the type of the call. The host can also put
+//! functions into the table with `Instance::table_set`, which isn't counted.
+
+use crate::error::Error;
+use crate::module::ElementSegment;
+use std::collections::BTreeSet;
+use wasmparser::{FunctionBody, Operator};
+
+/// The calls that each of a module's functions makes. See `CompiledModule::call_graph`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+    /// The functions that each function calls directly, sorted and without duplicates.
+    callees: Vec<Vec<u32>>,
+    /// The types that each function makes indirect calls with, sorted and without
+    /// duplicates.
+    indirect_call_types: Vec<Vec<u32>>,
+    /// The functions in element segments that indirect calls with each type can call,
+    /// indexed by type index.
+    indirectly_callable: Vec<Vec<u32>>,
+}
+
+impl CallGraph {
+    /// Collect the calls made by `bodies`, the bodies of the module's defined functions.
+    /// `func_types` is the type index of every function and `sig_ids` the signature id of
+    /// every type, so that types that are declared more than once still match.
+    pub(crate) fn new(
+        bodies: &[FunctionBody],
+        func_types: &[u32],
+        sig_ids: &[u32],
+        element_segments: &[ElementSegment],
+    ) -> Result<Self, Error> {
+        let num_imports = func_types.len().saturating_sub(bodies.len());
+        let mut callees = vec![vec![]; num_imports];
+        let mut indirect_call_types = vec![vec![]; num_imports];
+
+        for body in bodies {
+            let mut direct = BTreeSet::new();
+            let mut indirect = BTreeSet::new();
+            let mut ops = body.get_operators_reader()?;
+            while !ops.eof() {
+                match ops.read()? {
+                    Operator::Call { function_index } => {
+                        direct.insert(function_index);
+                    }
+                    Operator::CallIndirect { index, .. } => {
+                        indirect.insert(index);
+                    }
+                    _ => {}
+                }
+            }
+            callees.push(direct.into_iter().collect());
+            indirect_call_types.push(indirect.into_iter().collect());
+        }
+
+        let in_tables = element_segments
+            .iter()
+            .flat_map(|segment| segment.elements.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        let indirectly_callable = sig_ids
+            .iter()
+            .map(|&sig_id| {
+                in_tables
+                    .iter()
+                    .cloned()
+                    .filter(|&func| {
+                        func_types
+                            .get(func as usize)
+                            .and_then(|&ty| sig_ids.get(ty as usize))
+                            == Some(&sig_id)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(CallGraph {
+            callees,
+            indirect_call_types,
+            indirectly_callable,
+        })
+    }
+
+    /// The functions that `func` calls directly.
+    pub fn callees(&self, func: u32) -> &[u32] {
+        self.callees
+            .get(func as usize)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// The type indices of the indirect calls that `func` makes.
+    pub fn indirect_call_types(&self, func: u32) -> &[u32] {
+        self.indirect_call_types
+            .get(func as usize)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// The functions that an indirect call with the type at `type_index` can call.
+    pub fn indirectly_callable(&self, type_index: u32) -> &[u32] {
+        self.indirectly_callable
+            .get(type_index as usize)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// Every function that can run once the functions in `roots` are called, including
+    /// them, in order of function index.
+    pub fn reachable(&self, roots: impl IntoIterator<Item = u32>) -> Vec<u32> {
+        let mut reachable = BTreeSet::new();
+        let mut pending = roots.into_iter().collect::<Vec<_>>();
+
+        while let Some(func) = pending.pop() {
+            if !reachable.insert(func) {
+                continue;
+            }
+
+            pending.extend(self.callees(func));
+            for &ty in self.indirect_call_types(func) {
+                pending.extend(self.indirectly_callable(ty));
+            }
+        }
+
+        reachable.into_iter().collect()
+    }
+}
@@ -0,0 +1,258 @@
+//! A C interface to translating and running modules, so that runtimes that aren't written in
+//! Rust can embed Lightbeam. This is only built with the `capi` feature, and the declarations
+//! for C are in `include/lightbeam.h`. Build a library that C code can link against with
+//! `cargo rustc --release --features capi --crate-type cdylib`, or `staticlib`.
+//!
+//! Functions that can fail return `false` or null and leave a message for
+//! `lightbeam_last_error`. Panics are caught rather than unwinding into C.
+//!
+//! Traps are still executed as `ud2`, which raises `SIGILL`. An embedder that handles the
+//! signal can pass the faulting address to `lightbeam_trap_code` to find out why it trapped.
+
+use crate::backend::ARITHMETIC_OVERFLOW;
+use crate::module::{translate, Instance, ValueType};
+use cranelift_codegen::ir::TrapCode;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+/// The kinds of `Value`, which match `lightbeam_kind` in the header.
+pub const I32: u32 = 0;
+pub const I64: u32 = 1;
+pub const F32: u32 = 2;
+pub const F64: u32 = 3;
+
+/// A wasm value passed to or returned from `lightbeam_call`. Integers are zero-extended to 64
+/// bits and floats are stored as their bit patterns.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub kind: u32,
+    pub bits: u64,
+}
+
+/// The most integer and float arguments that fit in registers, which is all that
+/// `lightbeam_call` supports.
+const MAX_INT_ARGS: usize = 5;
+const MAX_FLOAT_ARGS: usize = 8;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    // Messages come from our own errors, which don't contain nul bytes
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Run `f`, reporting an error or a panic through `lightbeam_last_error`.
+fn catch<T>(f: impl FnOnce() -> Result<T, String>) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(out)) => Some(out),
+        Ok(Err(message)) => {
+            set_last_error(message);
+            None
+        }
+        Err(_) => {
+            set_last_error("Lightbeam panicked".into());
+            None
+        }
+    }
+}
+
+fn kind(ty: ValueType) -> u32 {
+    match ty {
+        ValueType::I32 => I32,
+        ValueType::I64 => I64,
+        ValueType::F32 => F32,
+        ValueType::F64 => F64,
+    }
+}
+
+/// The number that the header gives each trap code in `lightbeam_trap`.
+fn trap_number(code: TrapCode) -> u32 {
+    match code {
+        TrapCode::StackOverflow => 1,
+        TrapCode::HeapOutOfBounds => 2,
+        TrapCode::TableOutOfBounds => 3,
+        TrapCode::OutOfBounds => 4,
+        TrapCode::IndirectCallToNull => 5,
+        TrapCode::BadSignature => 6,
+        TrapCode::IntegerOverflow => 7,
+        TrapCode::IntegerDivisionByZero => 8,
+        TrapCode::BadConversionToInteger => 9,
+        TrapCode::UnreachableCodeReached => 10,
+        TrapCode::Interrupt => 11,
+        code if code == ARITHMETIC_OVERFLOW => 13,
+        TrapCode::User(_) => 12,
+    }
+}
+
+/// Translate the `len` bytes of the binary module at `data` and instantiate it. Returns null
+/// on failure. Free the instance with `lightbeam_instance_delete`.
+#[no_mangle]
+pub unsafe extern "C" fn lightbeam_translate(data: *const u8, len: usize) -> *mut Instance {
+    catch(|| {
+        if data.is_null() {
+            return Err("No module was given".into());
+        }
+        let instance = translate(slice::from_raw_parts(data, len)).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(instance)))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Free an instance returned by `lightbeam_translate`. Does nothing if `instance` is null.
+#[no_mangle]
+pub unsafe extern "C" fn lightbeam_instance_delete(instance: *mut Instance) {
+    if !instance.is_null() {
+        drop(Box::from_raw(instance));
+    }
+}
+
+/// Look up the function exported as the nul-terminated `name`, storing its index in `func`.
+#[no_mangle]
+pub unsafe extern "C" fn lightbeam_get_func(
+    instance: *const Instance,
+    name: *const c_char,
+    func: *mut u32,
+) -> bool {
+    catch(|| {
+        let name = CStr::from_ptr(name)
+            .to_str()
+            .map_err(|_| "Export names must be UTF-8".to_string())?;
+        let (index, _) = (*instance)
+            .module()
+            .exported_func(name)
+            .ok_or_else(|| format!("No exported function {:?}", name))?;
+        *func = index;
+        Ok(())
+    })
+    .is_some()
+}
+
+/// Call the function `func` with the `num_args` values at `args`, which must match its
+/// parameters. If it returns a value, that's stored in `result`, which can otherwise be null.
+#[no_mangle]
+pub unsafe extern "C" fn lightbeam_call(
+    instance: *const Instance,
+    func: u32,
+    args: *const Value,
+    num_args: usize,
+    result: *mut Value,
+) -> bool {
+    catch(|| {
+        let instance = &*instance;
+        let args = if num_args == 0 {
+            &[]
+        } else {
+            slice::from_raw_parts(args, num_args)
+        };
+        let ty = instance
+            .module()
+            .defined_func_type(func)
+            .ok_or_else(|| format!("No function {}", func))?;
+        let matches = ty.params.len() == args.len()
+            && ty
+                .params
+                .iter()
+                .zip(args)
+                .all(|(&param, arg)| kind(param) == arg.kind);
+        if !matches {
+            return Err(format!(
+                "The arguments don't match the function's parameters {:?}",
+                ty.params
+            ));
+        }
+
+        // Pass every argument in a register, so that one signature works for any function
+        // within the limits on arguments
+        let mut ints = [0u64; MAX_INT_ARGS];
+        let mut floats = [0f64; MAX_FLOAT_ARGS];
+        let (mut num_ints, mut num_floats) = (0, 0);
+        for arg in args {
+            match arg.kind {
+                I32 | I64 if num_ints < MAX_INT_ARGS => {
+                    ints[num_ints] = arg.bits;
+                    num_ints += 1;
+                }
+                F32 | F64 if num_floats < MAX_FLOAT_ARGS => {
+                    floats[num_floats] = f64::from_bits(arg.bits);
+                    num_floats += 1;
+                }
+                _ => return Err("Arguments that are passed on the stack aren't supported".into()),
+            }
+        }
+
+        let [i0, i1, i2, i3, i4] = ints;
+        let [f0, f1, f2, f3, f4, f5, f6, f7] = floats;
+        let args = (i0, i1, i2, i3, i4, f0, f1, f2, f3, f4, f5, f6, f7);
+        let value = match ty.returns[..] {
+            [] => {
+                instance.execute_func_unchecked::<_, ()>(func, args);
+                return Ok(());
+            }
+            [ValueType::I32] => Value {
+                kind: I32,
+                bits: u64::from(instance.execute_func_unchecked::<_, u64>(func, args) as u32),
+            },
+            [ValueType::I64] => Value {
+                kind: I64,
+                bits: instance.execute_func_unchecked::<_, u64>(func, args),
+            },
+            [ValueType::F32] => Value {
+                kind: F32,
+                bits: u64::from(
+                    instance
+                        .execute_func_unchecked::<_, f64>(func, args)
+                        .to_bits() as u32,
+                ),
+            },
+            [ValueType::F64] => Value {
+                kind: F64,
+                bits: instance
+                    .execute_func_unchecked::<_, f64>(func, args)
+                    .to_bits(),
+            },
+            _ => return Err(format!("Can't return {:?}", ty.returns)),
+        };
+        if !result.is_null() {
+            *result = value;
+        }
+        Ok(())
+    })
+    .is_some()
+}
+
+/// Why the instance's code traps at `pc`, as a `lightbeam_trap`, or 0 if there isn't a trap
+/// there. This is meant to be called from a `SIGILL` handler with the faulting address.
+#[no_mangle]
+pub unsafe extern "C" fn lightbeam_trap_code(instance: *const Instance, pc: *const u8) -> u32 {
+    let code_section = match (*instance).module().code_section() {
+        Some(code_section) => code_section,
+        None => return 0,
+    };
+    let start = code_section.buffer().as_ptr() as usize;
+    (pc as usize)
+        .checked_sub(start)
+        .and_then(|offset| code_section.trap_code(offset))
+        .map(trap_number)
+        .unwrap_or(0)
+}
+
+/// The message for the last error on this thread, or null if nothing has failed. The string
+/// is valid until the next call that fails on the same thread.
+#[no_mangle]
+pub extern "C" fn lightbeam_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|message| message.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
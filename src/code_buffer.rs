@@ -0,0 +1,370 @@
+//! Executable memory holding the finished machine code of a module.
+//!
+//! The code that the assembler produces is copied to memory that we map ourselves, so that
+//! it's made executable in a way that the platform allows:
+//!
+//! - Normally the memory is mapped writable, filled and then made executable instead, so
+//!   that it's never writable and executable at once.
+//! - On macOS it's mapped with `MAP_JIT`, which the hardened runtime requires of generated
+//!   code (along with the `com.apple.security.cs.allow-jit` entitlement). Where the hardware
+//!   supports it, writes are only enabled on the thread doing them, with
+//!   `pthread_jit_write_protect_np`.
+//! - On Linux, SELinux without `execmem` and PaX's `MPROTECT` refuse to make writable
+//!   anonymous memory executable. There the code is put in a memfd that's mapped twice,
+//!   executable for running it and writable for patching it.
+//!
+//! The assembler only ever writes to a `Vec<u8>`, so this is the only executable memory
+//! that translating a module maps.
+
+use crate::error::Error;
+use dynasmrt::AssemblyOffset;
+use std::{
+    io, mem,
+    ops::Deref,
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+pub struct CodeBuffer(MappedBuffer);
+
+impl CodeBuffer {
+    /// Copy the code that the assembler produced to memory mapped for running it. With
+    /// `huge_pages`, the memory is aligned to and advised to be backed by 2 MiB huge pages,
+    /// which improves iTLB behaviour for very large modules.
+    pub fn new(code: &[u8], huge_pages: bool) -> Result<Self, Error> {
+        let align = if huge_pages {
+            HUGE_PAGE_SIZE
+        } else {
+            page_size()
+        };
+
+        MappedBuffer::new(code, align, huge_pages)
+            .map(CodeBuffer)
+            .map_err(|e| Error::Assembler(format!("Couldn't map memory for the code: {}", e)))
+    }
+
+    pub fn ptr(&self, offset: AssemblyOffset) -> *const u8 {
+        self[offset.0..].as_ptr()
+    }
+
+    /// Overwrite the code at `offset` with `bytes`. Wherever the platform allows it, the
+    /// code stays executable while this runs so that other threads can keep running it.
+    ///
+    /// # Safety
+    ///
+    /// The new code must be valid wherever execution might be when it's written, and
+    /// nothing else may change the protection of these pages at the same time.
+    pub unsafe fn patch(&self, offset: usize, bytes: &[u8]) {
+        self.write_with(offset, bytes.len(), |dst| {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len())
+        });
+    }
+
+    /// Overwrite the aligned word at `offset` with `word` in a single store, so that other
+    /// threads reading it see either the old or the new value.
+    ///
+    /// # Safety
+    ///
+    /// As for `patch`.
+    pub unsafe fn patch_word(&self, offset: usize, word: u64) {
+        assert_eq!(offset % mem::size_of::<u64>(), 0);
+        self.write_with(offset, mem::size_of::<u64>(), |dst| {
+            (*(dst as *const AtomicU64)).store(word, Ordering::SeqCst)
+        });
+    }
+
+    /// Call `write` with where to write `len` bytes at `offset`.
+    unsafe fn write_with(&self, offset: usize, len: usize, write: impl FnOnce(*mut u8)) {
+        assert!(offset + len <= self.len());
+        self.0.write_with(offset, len, write);
+    }
+}
+
+impl Deref for CodeBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Write to memory that's readable and executable. The pages are made writable as well as
+/// executable for the duration, or only writable if the OS doesn't allow both at once.
+unsafe fn write_protected(start: *mut u8, len: usize, write: impl FnOnce(*mut u8)) {
+    let page_size = page_size();
+    let page_start = start as usize & !(page_size - 1);
+    let protect_len = round_up(start as usize + len, page_size) - page_start;
+
+    let rx = libc::PROT_READ | libc::PROT_EXEC;
+    if libc::mprotect(page_start as *mut _, protect_len, rx | libc::PROT_WRITE) != 0 {
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        assert_eq!(libc::mprotect(page_start as *mut _, protect_len, rw), 0);
+    }
+    write(start);
+    assert_eq!(libc::mprotect(page_start as *mut _, protect_len, rx), 0);
+}
+
+/// Map `len` bytes starting on a multiple of `align`, by mapping more than that and trimming
+/// the mapping on either side.
+unsafe fn map_aligned(len: usize, align: usize, prot: i32, flags: i32) -> io::Result<*mut u8> {
+    let extra = if align > page_size() { align } else { 0 };
+    let raw_len = len + extra;
+    let raw = libc::mmap(ptr::null_mut(), raw_len, prot, flags, -1, 0);
+    if raw == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let raw = raw as usize;
+    let start = round_up(raw, align);
+    let end = start + len;
+    if start > raw {
+        libc::munmap(raw as *mut _, start - raw);
+    }
+    if raw + raw_len > end {
+        libc::munmap(end as *mut _, raw + raw_len - end);
+    }
+
+    Ok(start as *mut u8)
+}
+
+/// How the code in a `MappedBuffer` is written to once it's executable.
+enum Writes {
+    /// By making its pages writable for the duration.
+    Mprotect,
+    /// Through a second mapping of the same memfd, which is writable.
+    #[cfg(target_os = "linux")]
+    Alias(*mut u8),
+    /// By enabling writes to `MAP_JIT` memory for the current thread.
+    #[cfg(target_os = "macos")]
+    JitWriteProtect,
+    /// Directly, since `MAP_JIT` memory is writable and executable at once on machines that
+    /// don't support enabling writes per thread.
+    #[cfg(target_os = "macos")]
+    Direct,
+}
+
+/// Read-only, executable memory that we mapped for the code.
+pub struct MappedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    map_len: usize,
+    writes: Writes,
+}
+
+// The memory is only written after construction by `CodeBuffer::patch`.
+unsafe impl Send for MappedBuffer {}
+unsafe impl Sync for MappedBuffer {}
+
+impl MappedBuffer {
+    fn new(code: &[u8], align: usize, huge_pages: bool) -> io::Result<Self> {
+        let map_len = round_up(code.len().max(1), align);
+
+        unsafe {
+            #[cfg(target_os = "macos")]
+            {
+                if let Ok(buf) = Self::map_jit(code, align, map_len) {
+                    return Ok(buf);
+                }
+            }
+
+            let start = map_aligned(
+                map_len,
+                align,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            )?;
+
+            // This fails if transparent huge pages are disabled, in which case we still have
+            // a correctly-aligned mapping that we can use.
+            #[cfg(target_os = "linux")]
+            {
+                if huge_pages {
+                    libc::madvise(start as *mut _, map_len, libc::MADV_HUGEPAGE);
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = huge_pages;
+
+            ptr::copy_nonoverlapping(code.as_ptr(), start, code.len());
+
+            if libc::mprotect(start as *mut _, map_len, libc::PROT_READ | libc::PROT_EXEC) == 0 {
+                return Ok(MappedBuffer {
+                    ptr: start,
+                    len: code.len(),
+                    map_len,
+                    writes: Writes::Mprotect,
+                });
+            }
+
+            let e = io::Error::last_os_error();
+            libc::munmap(start as *mut _, map_len);
+            #[cfg(target_os = "linux")]
+            {
+                log_debug!(
+                    "Couldn't make anonymous memory executable ({}), using a memfd",
+                    e
+                );
+                Self::map_alias(code, align, map_len)
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                Err(e)
+            }
+        }
+    }
+
+    /// Map the code from a memfd, executable where it runs and writable somewhere else.
+    #[cfg(target_os = "linux")]
+    unsafe fn map_alias(code: &[u8], align: usize, map_len: usize) -> io::Result<Self> {
+        let fd = libc::memfd_create(b"lightbeam\0".as_ptr() as *const _, libc::MFD_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The mappings keep the memory alive without the file descriptor
+        let buf = Self::map_fd(fd, code, align, map_len);
+        libc::close(fd);
+        buf
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn map_fd(fd: i32, code: &[u8], align: usize, map_len: usize) -> io::Result<Self> {
+        if libc::ftruncate(fd, map_len as libc::off_t) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let writable = libc::mmap(
+            ptr::null_mut(),
+            map_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if writable == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Reserve an aligned range and then replace it with the executable mapping
+        let start = match map_aligned(
+            map_len,
+            align,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        ) {
+            Ok(start) => start,
+            Err(e) => {
+                libc::munmap(writable, map_len);
+                return Err(e);
+            }
+        };
+        let executable = libc::mmap(
+            start as *mut _,
+            map_len,
+            libc::PROT_READ | libc::PROT_EXEC,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            fd,
+            0,
+        );
+        if executable == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            libc::munmap(writable, map_len);
+            libc::munmap(start as *mut _, map_len);
+            return Err(e);
+        }
+
+        ptr::copy_nonoverlapping(code.as_ptr(), writable as *mut u8, code.len());
+
+        Ok(MappedBuffer {
+            ptr: start,
+            len: code.len(),
+            map_len,
+            writes: Writes::Alias(writable as *mut u8),
+        })
+    }
+
+    /// Map the code with `MAP_JIT`, for the hardened runtime.
+    #[cfg(target_os = "macos")]
+    unsafe fn map_jit(code: &[u8], align: usize, map_len: usize) -> io::Result<Self> {
+        let start = map_aligned(
+            map_len,
+            align,
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+            libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_JIT,
+        )?;
+
+        let writes = if libc::pthread_jit_write_protect_supported_np() != 0 {
+            Writes::JitWriteProtect
+        } else {
+            Writes::Direct
+        };
+        let buf = MappedBuffer {
+            ptr: start,
+            len: code.len(),
+            map_len,
+            writes,
+        };
+        buf.write_with(0, code.len(), |dst| {
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len())
+        });
+
+        Ok(buf)
+    }
+
+    /// The same as `new`, but always mapping the code from a memfd as if anonymous memory
+    /// couldn't be made executable.
+    #[cfg(all(test, target_os = "linux"))]
+    pub fn aliased(code: &[u8]) -> io::Result<Self> {
+        let page_size = page_size();
+        unsafe { Self::map_alias(code, page_size, round_up(code.len().max(1), page_size)) }
+    }
+
+    /// Call `write` with where to write `len` bytes at `offset`.
+    pub(crate) unsafe fn write_with(&self, offset: usize, len: usize, write: impl FnOnce(*mut u8)) {
+        let start = self.ptr.add(offset);
+        match self.writes {
+            Writes::Mprotect => write_protected(start, len, write),
+            #[cfg(target_os = "linux")]
+            Writes::Alias(writable) => write(writable.add(offset)),
+            #[cfg(target_os = "macos")]
+            Writes::JitWriteProtect => {
+                libc::pthread_jit_write_protect_np(0);
+                write(start);
+                libc::pthread_jit_write_protect_np(1);
+            }
+            #[cfg(target_os = "macos")]
+            Writes::Direct => write(start),
+        }
+    }
+}
+
+impl Deref for MappedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for MappedBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut _, self.map_len);
+            #[cfg(target_os = "linux")]
+            {
+                if let Writes::Alias(writable) = self.writes {
+                    libc::munmap(writable as *mut _, self.map_len);
+                }
+            }
+        }
+    }
+}
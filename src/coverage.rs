@@ -0,0 +1,92 @@
+//! Coverage instrumentation in the style of SanitizerCoverage's `trace-pc-guard` mode, so that
+//! coverage-guided fuzzers can see which parts of a wasm module their inputs reach.
+//!
+//! Every basic block gets a 32-bit guard, and the generated code calls a hook with the
+//! address of the block's guard whenever the block is entered. Passing
+//! `__sanitizer_cov_trace_pc_guard` and `__sanitizer_cov_trace_pc_guard_init` from a
+//! sanitizer runtime makes the module's blocks show up alongside those of the host.
+
+use std::sync::atomic::AtomicU32;
+
+/// Called at the start of every basic block with the address of its guard.
+pub type TracePcGuard = unsafe extern "C" fn(guard: *mut u32);
+
+/// Called once for every contiguous range of guards, as `[start, stop)`, before any of the
+/// code that uses them can run.
+pub type TracePcGuardInit = unsafe extern "C" fn(start: *mut u32, stop: *mut u32);
+
+/// The hooks to call from instrumented code.
+#[derive(Debug, Copy, Clone)]
+pub struct Coverage {
+    pub trace_pc_guard: TracePcGuard,
+    pub trace_pc_guard_init: Option<TracePcGuardInit>,
+}
+
+// Hooks are compared by address, which is all that the generated code depends on.
+impl PartialEq for Coverage {
+    fn eq(&self, other: &Self) -> bool {
+        self.trace_pc_guard as usize == other.trace_pc_guard as usize
+            && self.trace_pc_guard_init.map(|f| f as usize)
+                == other.trace_pc_guard_init.map(|f| f as usize)
+    }
+}
+
+impl Eq for Coverage {}
+
+const CHUNK_SIZE: usize = 1024;
+
+/// The guards for the blocks in a code section. Generated code refers to guards by their
+/// address, so they're allocated in fixed-size chunks that never move.
+#[derive(Debug, Default)]
+pub struct CoverageGuards {
+    chunks: Vec<Box<[AtomicU32]>>,
+    /// The number of guards handed out from the last chunk.
+    used: usize,
+}
+
+impl CoverageGuards {
+    /// Allocate the guard for a new block.
+    pub(crate) fn next(&mut self) -> *mut u32 {
+        if self.chunks.is_empty() || self.used == CHUNK_SIZE {
+            self.chunks
+                .push((0..CHUNK_SIZE).map(|_| AtomicU32::new(0)).collect());
+            self.used = 0;
+        }
+
+        let guard = &self.chunks.last().unwrap()[self.used];
+        self.used += 1;
+        guard as *const AtomicU32 as *mut u32
+    }
+
+    /// The guards that have been allocated, as contiguous ranges.
+    fn ranges(&self) -> impl Iterator<Item = &[AtomicU32]> + '_ {
+        let last = self.chunks.len().saturating_sub(1);
+        self.chunks.iter().enumerate().map(move |(i, chunk)| {
+            if i == last {
+                &chunk[..self.used]
+            } else {
+                &chunk[..]
+            }
+        })
+    }
+
+    pub(crate) fn init(&self, init: TracePcGuardInit) {
+        for range in self.ranges() {
+            let start = range.as_ptr() as *mut u32;
+            unsafe { init(start, start.add(range.len())) };
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges().map(<[_]>::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every guard in the order that their blocks were generated.
+    pub fn iter(&self) -> impl Iterator<Item = &AtomicU32> + '_ {
+        self.ranges().flatten()
+    }
+}
@@ -0,0 +1,58 @@
+//! Turning `call_indirect` into a direct call when the function that it calls is known
+//! during translation.
+//!
+//! That's the case when the index into the table is a constant and the element at that index
+//! can't change: it's initialized by an active element segment at a constant offset, and
+//! nothing in the module writes to the table with `table.set`, `table.init` or
+//! `table.copy`. Calls that would trap, because the index is out of bounds, the element is
+//! null or the signatures don't match, are left alone. The host could still change elements
+//! with `Instance::table_set`, so it's refused for the elements that calls were bound to.
+
+use crate::error::Error;
+use crate::module::{ElementSegment, ElementSegmentKind, SegmentOffset};
+use std::collections::HashMap;
+use wasmparser::{FunctionBody, Operator};
+
+/// The function index of every table element that can't change, by its index in the table.
+pub type StaticTable = HashMap<u32, u32>;
+
+/// The elements of the table that can't change, or `None` if code in the module writes to
+/// the table.
+pub fn static_table(
+    element_segments: &[ElementSegment],
+    bodies: &[FunctionBody],
+) -> Result<Option<StaticTable>, Error> {
+    for body in bodies {
+        let mut ops = body.get_operators_reader()?;
+        while !ops.eof() {
+            match ops.read()? {
+                Operator::TableSet { .. } | Operator::TableInit { .. } | Operator::TableCopy => {
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Segments are copied in order, so later ones overwrite earlier ones
+    let mut table = StaticTable::new();
+    for segment in element_segments {
+        match segment.kind {
+            ElementSegmentKind::Active {
+                table_index: 0,
+                offset: SegmentOffset::Const(offset),
+            } => {
+                for (i, &func) in segment.elements.iter().enumerate() {
+                    table.insert(offset + i as u32, func);
+                }
+            }
+            ElementSegmentKind::Active {
+                table_index: 0,
+                offset: SegmentOffset::Global(_),
+            } => return Ok(None),
+            _ => {}
+        }
+    }
+
+    Ok(Some(table))
+}
@@ -1,19 +1,25 @@
+use crate::backend::OperatorRange;
+use crate::error::Error;
+use crate::index_space::DefinedFuncIndex;
 use capstone::prelude::*;
 use dynasmrt::AssemblyOffset;
-use std::error::Error;
 use std::fmt::{Display, Write};
 
+/// Write the disassembly of `mem`, which starts at `address`, to `out`. Each of `ops` is
+/// written before the first instruction at or after its offset, so they must be sorted.
 pub fn disassemble(
+    out: &mut dyn Write,
     mem: &[u8],
+    address: u64,
     mut ops: &[(AssemblyOffset, impl Display)],
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error> {
     let mut cs = Capstone::new()
         .x86()
         .mode(arch::x86::ArchMode::Mode64)
         .build()?;
 
-    println!("{} bytes:", mem.len());
-    let insns = cs.disasm_all(&mem, 0x0)?;
+    writeln!(out, "{} bytes:", mem.len())?;
+    let insns = cs.disasm_all(&mem, address)?;
     for i in insns.iter() {
         let mut line = String::new();
 
@@ -23,7 +29,7 @@ pub fn disassemble(
             if let Some((offset, op)) = ops.first() {
                 if offset.0 as u64 <= address {
                     ops = &ops[1..];
-                    println!("{}", op);
+                    writeln!(out, "{}", op)?;
                 } else {
                     break;
                 }
@@ -48,8 +54,80 @@ pub fn disassemble(
             write!(&mut line, "{}", s)?;
         }
 
-        println!("{}", line);
+        writeln!(out, "{}", line)?;
     }
 
     Ok(())
 }
+
+/// Write the operators in `ranges` in a column alongside the instructions generated for
+/// each of them, for the function `func` whose code is `mem`, starting at `address`.
+pub fn side_by_side(
+    out: &mut dyn Write,
+    func: DefinedFuncIndex,
+    mem: &[u8],
+    address: u64,
+    ranges: &[OperatorRange],
+) -> Result<(), Error> {
+    let mut cs = Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .build()?;
+
+    writeln!(out, ".fn_{}:", func)?;
+    let insns = cs.disasm_all(&mem, address)?;
+    let mut insns = insns.iter().peekable();
+
+    let write_row = |out: &mut dyn Write, op: &str, insn: &capstone::Insn| {
+        writeln!(
+            out,
+            "{:40} | {:4x}:\t{}\t{}",
+            op,
+            insn.address(),
+            insn.mnemonic().unwrap_or(""),
+            insn.op_str().unwrap_or("")
+        )
+    };
+
+    // The prologue comes before the first operator
+    let prologue_end = ranges.first().map(|r| r.code.start as u64);
+    while let Some(insn) = insns.peek() {
+        if prologue_end
+            .map(|end| insn.address() >= end)
+            .unwrap_or(false)
+        {
+            break;
+        }
+        write_row(out, "", insn)?;
+        insns.next();
+    }
+
+    for range in ranges {
+        let mut op = Some(&range.operator[..]);
+        while let Some(insn) = insns.peek() {
+            if insn.address() >= range.code.end as u64 {
+                break;
+            }
+            write_row(out, op.take().unwrap_or(""), insn)?;
+            insns.next();
+        }
+        if let Some(op) = op {
+            writeln!(out, "{}", op)?;
+        }
+    }
+
+    for insn in insns {
+        write_row(out, "", &insn)?;
+    }
+
+    Ok(())
+}
+
+pub fn instruction_count(mem: &[u8]) -> Result<usize, capstone::Error> {
+    let mut cs = Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .build()?;
+
+    Ok(cs.disasm_all(mem, 0x0)?.len())
+}
@@ -0,0 +1,799 @@
+//! The lowest layer of code generation: a sink for raw machine code bytes plus just enough
+//! label support to express branches. The backend only needs these few operations to be
+//! encoder-agnostic, so anything implementing `Emitter` (`VecAssembler`, a different encoder
+//! or a recording emitter for tests) can sit underneath it.
+//!
+//! `dynasm!` encodes instructions into calls to methods of these names, so it can assemble
+//! into any `Emitter`. `dynasmrt`'s own assembler isn't used, since it assembles into memory
+//! that it maps executable, which the policies that `CodeBuffer` works around can refuse;
+//! `VecAssembler` assembles into a `Vec<u8>` and `CodeBuffer` makes the only executable
+//! mapping.
+//!
+//! The most common instructions are emitted as `Instruction`s rather than bytes, so that an
+//! emitter can see what was emitted and with which operands instead of having to decode it.
+
+use crate::error::Error;
+use dynasm::dynasm;
+use dynasmrt::AssemblyOffset;
+use std::{
+    collections::HashMap,
+    iter,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// `mov r/m32, r32`.
+const MOV_RM_R: u8 = 0x89;
+const REX: u8 = 0x40;
+/// The prefix that extends the register encoded in `r/m` to `r8` to `r15`.
+const REX_B: u8 = 0x41;
+/// The prefix that extends the register encoded in `reg` to `r8` to `r15`.
+const REX_R: u8 = 0x44;
+
+/// An operand of an `Instruction`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    /// `[rsp + offset]`.
+    Stack(i32),
+    Imm(i32),
+}
+
+/// An instruction together with its operands. `wide` instructions work on 64-bit values,
+/// the others on 32-bit ones.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Mov {
+        wide: bool,
+        dst: Operand,
+        src: Operand,
+    },
+    Add {
+        wide: bool,
+        dst: Operand,
+        src: Operand,
+    },
+    Cmp {
+        wide: bool,
+        left: Operand,
+        right: Operand,
+    },
+    /// `test reg, reg`.
+    Test { wide: bool, reg: u8 },
+}
+
+impl Instruction {
+    /// Encode this instruction into `asm`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if x86-64 has no encoding for this combination of operands, like a `mov` from
+    /// one stack slot to another.
+    pub fn encode<A: Emitter + ?Sized>(self, asm: &mut A) {
+        use self::Operand::{Imm, Reg, Stack};
+
+        match self {
+            Instruction::Mov {
+                wide: true,
+                dst: Reg(dst),
+                src: Reg(src),
+            } => dynasm!(asm
+                ; mov Rq(dst), Rq(src)
+            ),
+            Instruction::Mov {
+                wide: false,
+                dst: Reg(dst),
+                src: Reg(src),
+            } => {
+                // dynasm always emits a REX prefix for dynamic registers, which this can do
+                // without
+                let rex = (if src >= 8 { REX_R } else { 0 }) | (if dst >= 8 { REX_B } else { 0 });
+                if rex != 0 {
+                    asm.push(REX | rex);
+                }
+                asm.push(MOV_RM_R);
+                asm.push(0xc0 | (src & 7) << 3 | (dst & 7));
+            }
+            Instruction::Mov {
+                wide: true,
+                dst: Reg(dst),
+                src: Stack(offset),
+            } => dynasm!(asm
+                ; mov Rq(dst), [rsp + offset]
+            ),
+            Instruction::Mov {
+                wide: false,
+                dst: Reg(dst),
+                src: Stack(offset),
+            } => dynasm!(asm
+                ; mov Rd(dst), [rsp + offset]
+            ),
+            Instruction::Mov {
+                wide: true,
+                dst: Reg(dst),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; mov Rq(dst), imm
+            ),
+            Instruction::Mov {
+                wide: false,
+                dst: Reg(dst),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; mov Rd(dst), imm
+            ),
+            Instruction::Mov {
+                wide: true,
+                dst: Stack(offset),
+                src: Reg(src),
+            } => dynasm!(asm
+                ; mov [rsp + offset], Rq(src)
+            ),
+            Instruction::Mov {
+                wide: false,
+                dst: Stack(offset),
+                src: Reg(src),
+            } => dynasm!(asm
+                ; mov [rsp + offset], Rd(src)
+            ),
+            Instruction::Mov {
+                wide: true,
+                dst: Stack(offset),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; mov QWORD [rsp + offset], imm
+            ),
+            Instruction::Mov {
+                wide: false,
+                dst: Stack(offset),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; mov DWORD [rsp + offset], imm
+            ),
+            Instruction::Add {
+                wide: true,
+                dst: Reg(dst),
+                src: Reg(src),
+            } => dynasm!(asm
+                ; add Rq(dst), Rq(src)
+            ),
+            Instruction::Add {
+                wide: false,
+                dst: Reg(dst),
+                src: Reg(src),
+            } => dynasm!(asm
+                ; add Rd(dst), Rd(src)
+            ),
+            Instruction::Add {
+                wide: true,
+                dst: Reg(dst),
+                src: Stack(offset),
+            } => dynasm!(asm
+                ; add Rq(dst), [rsp + offset]
+            ),
+            Instruction::Add {
+                wide: false,
+                dst: Reg(dst),
+                src: Stack(offset),
+            } => dynasm!(asm
+                ; add Rd(dst), [rsp + offset]
+            ),
+            Instruction::Add {
+                wide: true,
+                dst: Reg(dst),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; add Rq(dst), imm
+            ),
+            Instruction::Add {
+                wide: false,
+                dst: Reg(dst),
+                src: Imm(imm),
+            } => dynasm!(asm
+                ; add Rd(dst), imm
+            ),
+            Instruction::Cmp {
+                wide: true,
+                left: Reg(left),
+                right: Reg(right),
+            } => dynasm!(asm
+                ; cmp Rq(left), Rq(right)
+            ),
+            Instruction::Cmp {
+                wide: false,
+                left: Reg(left),
+                right: Reg(right),
+            } => dynasm!(asm
+                ; cmp Rd(left), Rd(right)
+            ),
+            Instruction::Cmp {
+                wide: true,
+                left: Reg(left),
+                right: Stack(offset),
+            } => dynasm!(asm
+                ; cmp Rq(left), [rsp + offset]
+            ),
+            Instruction::Cmp {
+                wide: false,
+                left: Reg(left),
+                right: Stack(offset),
+            } => dynasm!(asm
+                ; cmp Rd(left), [rsp + offset]
+            ),
+            Instruction::Cmp {
+                wide: true,
+                left: Reg(left),
+                right: Imm(imm),
+            } => dynasm!(asm
+                ; cmp Rq(left), imm
+            ),
+            Instruction::Cmp {
+                wide: false,
+                left: Reg(left),
+                right: Imm(imm),
+            } => dynasm!(asm
+                ; cmp Rd(left), imm
+            ),
+            Instruction::Test { wide: true, reg } => dynasm!(asm
+                ; test Rq(reg), Rq(reg)
+            ),
+            Instruction::Test { wide: false, reg } => dynasm!(asm
+                ; test Rd(reg), Rd(reg)
+            ),
+            _ => panic!("`{:?}` can't be encoded", self),
+        }
+    }
+}
+
+/// A label created at runtime, which `dynasm!` refers to as `=>label`. Every label is
+/// different from every other, whichever emitter created it, so that code emitted into one
+/// emitter can refer to labels defined in another once they're put together.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DynamicLabel(usize);
+
+impl DynamicLabel {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        DynamicLabel(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// How `dynasm!` describes a displacement to a label: how many bytes before the end of the
+/// instruction it ends, and how many bytes it is. The displacement is relative to the end of
+/// the instruction.
+pub type Relocation = (u8, u8);
+
+/// Interface to a machine code emitter. These are the methods that `dynasm!` calls, plus
+/// `new_label`, `emit_rel32` and `instruction`.
+pub trait Emitter: Extend<u8> + for<'a> Extend<&'a u8> {
+    /// The number of bytes emitted so far.
+    fn offset(&self) -> AssemblyOffset;
+
+    /// Append a byte to the code.
+    fn push(&mut self, byte: u8);
+
+    fn push_i8(&mut self, value: i8) {
+        self.push(value as u8);
+    }
+
+    fn push_i16(&mut self, value: i16) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    fn push_i32(&mut self, value: i32) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    fn push_i64(&mut self, value: i64) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    fn push_u64(&mut self, value: u64) {
+        self.extend(&value.to_le_bytes());
+    }
+
+    /// Append raw bytes to the code.
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        self.extend(bytes);
+    }
+
+    /// Pad the code with `nop`s up to a multiple of `alignment`.
+    fn align(&mut self, alignment: usize);
+
+    /// Create a label that isn't bound to any position yet.
+    fn new_label(&mut self) -> DynamicLabel {
+        DynamicLabel::new()
+    }
+
+    /// Bind `label` to the current offset. A label can be defined only once.
+    fn define_label(&mut self, label: DynamicLabel);
+
+    /// `=>label:` in `dynasm!`.
+    fn dynamic_label(&mut self, label: DynamicLabel) {
+        self.define_label(label);
+    }
+
+    /// `->name:` in `dynasm!`: a label that can be referred to from anywhere.
+    fn global_label(&mut self, name: &'static str);
+
+    /// `name:` in `dynasm!`: a label that can be referred to from the code just before or
+    /// after it, and can be defined again further on.
+    fn local_label(&mut self, name: &'static str);
+
+    /// A displacement to `label`, which is patched once its position is known.
+    fn dynamic_reloc(&mut self, label: DynamicLabel, kind: Relocation);
+
+    /// A displacement to the global label `name`.
+    fn global_reloc(&mut self, name: &'static str, kind: Relocation);
+
+    /// A displacement to the next definition of the local label `name`.
+    fn forward_reloc(&mut self, name: &'static str, kind: Relocation);
+
+    /// A displacement to the last definition of the local label `name`.
+    fn backward_reloc(&mut self, name: &'static str, kind: Relocation);
+
+    /// A displacement to `target`, an offset in the code.
+    fn bare_reloc(&mut self, target: usize, kind: Relocation);
+
+    /// Emit a 32-bit displacement to `label`, relative to the end of the displacement. The
+    /// value is patched once the label's position is known.
+    fn emit_rel32(&mut self, label: DynamicLabel) {
+        self.push_i32(0);
+        self.dynamic_reloc(label, (0, 4));
+    }
+
+    /// Emit `instruction`.
+    fn instruction(&mut self, instruction: Instruction) {
+        instruction.encode(self);
+    }
+}
+
+/// Where a displacement is in the code: the offset of the end of the instruction that it's
+/// in, which it's relative to, and where it is in that instruction.
+#[derive(Debug, Copy, Clone)]
+struct PatchLoc {
+    end: usize,
+    kind: Relocation,
+}
+
+impl PatchLoc {
+    /// Write the displacement to `target` into `code`, which starts at offset `base`.
+    fn patch(self, code: &mut [u8], base: usize, target: usize) {
+        let (before_end, size) = (self.kind.0 as usize, self.kind.1 as usize);
+        let start = self.end - before_end - size - base;
+        let value = (target as u64).wrapping_sub(self.end as u64);
+        code[start..start + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+}
+
+/// A label that a displacement that can't be patched straight away refers to.
+#[derive(Debug, Copy, Clone)]
+enum RelocTarget {
+    Dynamic(DynamicLabel),
+    Global(&'static str),
+    /// A local label that was never defined again after the displacement, which can't be
+    /// resolved by linking either.
+    Local(&'static str),
+}
+
+/// An `Emitter` that assembles into a `Vec<u8>`, which is never executable. Displacements to
+/// labels that aren't defined yet are patched by `finalize`, or by `link` if the labels are
+/// in a different `VecAssembler`'s code.
+#[derive(Debug, Default)]
+pub struct VecAssembler {
+    /// The offset that the code starts at, so that offsets in it can be used once it's
+    /// linked after the code before it.
+    base: usize,
+    code: Vec<u8>,
+    labels: HashMap<DynamicLabel, usize>,
+    global_labels: HashMap<&'static str, usize>,
+    local_labels: HashMap<&'static str, usize>,
+    /// Displacements to local labels that haven't been defined again yet.
+    forward_relocs: HashMap<&'static str, Vec<PatchLoc>>,
+    relocs: Vec<(PatchLoc, RelocTarget)>,
+}
+
+impl VecAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An assembler for code that will be linked at `base`, straight after other code.
+    /// Offsets, labels and alignment are all relative to the start of the linked code.
+    pub fn starting_at(base: usize) -> Self {
+        VecAssembler {
+            base,
+            ..Self::default()
+        }
+    }
+
+    /// Patch every displacement and return the code.
+    pub fn finalize(self) -> Result<Vec<u8>, Error> {
+        link(iter::once(self.finish()))
+    }
+
+    /// Patch the displacements to labels that this code defines, leaving the others to be
+    /// patched by `link`.
+    pub fn finish(self) -> Piece {
+        let VecAssembler {
+            base,
+            mut code,
+            labels,
+            global_labels,
+            forward_relocs,
+            relocs: all_relocs,
+            ..
+        } = self;
+
+        let mut relocs = forward_relocs
+            .into_iter()
+            .flat_map(|(name, locs)| {
+                locs.into_iter()
+                    .map(move |loc| (loc, RelocTarget::Local(name)))
+            })
+            .collect::<Vec<_>>();
+        for (loc, target) in all_relocs {
+            let offset = match target {
+                RelocTarget::Dynamic(label) => labels.get(&label),
+                RelocTarget::Global(name) => global_labels.get(name),
+                RelocTarget::Local(_) => None,
+            };
+            match offset {
+                Some(&offset) => loc.patch(&mut code, base, offset),
+                None => relocs.push((loc, target)),
+            }
+        }
+
+        Piece {
+            base,
+            code,
+            labels,
+            global_labels,
+            relocs,
+        }
+    }
+
+    fn patch_loc(&self, kind: Relocation) -> PatchLoc {
+        PatchLoc {
+            end: self.offset().0,
+            kind,
+        }
+    }
+}
+
+/// Code that a `VecAssembler` finished, which may still refer to labels in other code.
+#[derive(Debug)]
+pub struct Piece {
+    base: usize,
+    code: Vec<u8>,
+    labels: HashMap<DynamicLabel, usize>,
+    global_labels: HashMap<&'static str, usize>,
+    /// Displacements to labels that this code doesn't define.
+    relocs: Vec<(PatchLoc, RelocTarget)>,
+}
+
+/// Put `pieces` of code together, the first starting at 0 and each starting where the one
+/// before it ends, and patch the displacements from each to labels in the others.
+pub fn link(pieces: impl IntoIterator<Item = Piece>) -> Result<Vec<u8>, Error> {
+    let mut code = vec![];
+    let mut labels = HashMap::new();
+    let mut global_labels = HashMap::new();
+    let mut relocs = vec![];
+    for piece in pieces {
+        assert_eq!(piece.base, code.len(), "Code must be linked in order");
+        code.extend(piece.code);
+        for (label, offset) in piece.labels {
+            if labels.insert(label, offset).is_some() {
+                panic!("Label {:?} is defined twice", label);
+            }
+        }
+        for (name, offset) in piece.global_labels {
+            if global_labels.insert(name, offset).is_some() {
+                panic!("Global label `{}` is defined twice", name);
+            }
+        }
+        relocs.extend(piece.relocs);
+    }
+
+    for (loc, target) in relocs {
+        let offset = match target {
+            RelocTarget::Dynamic(label) => labels.get(&label),
+            RelocTarget::Global(name) => global_labels.get(name),
+            RelocTarget::Local(name) => {
+                return Err(Error::Assembler(format!("Unknown local label `{}`", name)))
+            }
+        };
+        match offset {
+            Some(&offset) => loc.patch(&mut code, 0, offset),
+            None => return Err(Error::Assembler(format!("Undefined label {:?}", target))),
+        }
+    }
+
+    Ok(code)
+}
+
+impl Extend<u8> for VecAssembler {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = u8>,
+    {
+        self.code.extend(iter);
+    }
+}
+
+impl<'a> Extend<&'a u8> for VecAssembler {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = &'a u8>,
+    {
+        self.code.extend(iter);
+    }
+}
+
+impl Emitter for VecAssembler {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.base + self.code.len())
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn align(&mut self, alignment: usize) {
+        while self.offset().0 % alignment != 0 {
+            self.code.push(0x90);
+        }
+    }
+
+    fn define_label(&mut self, label: DynamicLabel) {
+        let offset = self.offset().0;
+        if self.labels.insert(label, offset).is_some() {
+            panic!("Label {:?} is defined twice", label);
+        }
+    }
+
+    fn global_label(&mut self, name: &'static str) {
+        let offset = self.offset().0;
+        if self.global_labels.insert(name, offset).is_some() {
+            panic!("Global label `{}` is defined twice", name);
+        }
+    }
+
+    fn local_label(&mut self, name: &'static str) {
+        let offset = self.offset().0;
+        for loc in self.forward_relocs.remove(name).unwrap_or_default() {
+            loc.patch(&mut self.code, self.base, offset);
+        }
+        self.local_labels.insert(name, offset);
+    }
+
+    fn dynamic_reloc(&mut self, label: DynamicLabel, kind: Relocation) {
+        let loc = self.patch_loc(kind);
+        self.relocs.push((loc, RelocTarget::Dynamic(label)));
+    }
+
+    fn global_reloc(&mut self, name: &'static str, kind: Relocation) {
+        let loc = self.patch_loc(kind);
+        self.relocs.push((loc, RelocTarget::Global(name)));
+    }
+
+    fn forward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        let loc = self.patch_loc(kind);
+        self.forward_relocs.entry(name).or_default().push(loc);
+    }
+
+    fn backward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        let target = *self
+            .local_labels
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown local label `{}`", name));
+        let loc = self.patch_loc(kind);
+        loc.patch(&mut self.code, self.base, target);
+    }
+
+    fn bare_reloc(&mut self, target: usize, kind: Relocation) {
+        let loc = self.patch_loc(kind);
+        loc.patch(&mut self.code, self.base, target);
+    }
+}
+
+/// A single call made on a `RecordingEmitter`.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Emission {
+    Bytes(Vec<u8>),
+    DefineLabel(DynamicLabel),
+    GlobalLabel(&'static str),
+    LocalLabel(&'static str),
+    Rel32(DynamicLabel),
+    /// A relocation emitted by `dynasm!` for the displacement in the bytes just before it,
+    /// like the one at the end of `jmp =>label`.
+    Reloc(LabelRef, Relocation),
+    Instruction(Instruction),
+}
+
+/// What a relocation recorded by a `RecordingEmitter` refers to.
+#[cfg(test)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LabelRef {
+    Dynamic(DynamicLabel),
+    Global(&'static str),
+    /// The next definition of a local label.
+    Forward(&'static str),
+    /// The last definition of a local label.
+    Backward(&'static str),
+    /// An offset in the code.
+    Bare(usize),
+}
+
+/// Emitter that doesn't produce executable code but just records the sequence of emissions,
+/// so that instruction selection can be checked without executing or disassembling anything.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingEmitter {
+    pub emissions: Vec<Emission>,
+    offset: usize,
+}
+
+#[cfg(test)]
+impl RecordingEmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All bytes emitted so far, with displacements left as zero.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.emissions
+            .iter()
+            .flat_map(|e| match e {
+                Emission::Bytes(bytes) => bytes.clone(),
+                Emission::Rel32(_) => vec![0; 4],
+                Emission::DefineLabel(_)
+                | Emission::GlobalLabel(_)
+                | Emission::LocalLabel(_)
+                | Emission::Reloc(..) => vec![],
+                Emission::Instruction(instruction) => encoded(*instruction),
+            })
+            .collect()
+    }
+
+    /// The instructions emitted so far, without any code emitted as bytes.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.emissions
+            .iter()
+            .filter_map(|e| match e {
+                Emission::Instruction(instruction) => Some(*instruction),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Emit everything recorded so far into `asm`, which produces the same code as emitting
+    /// it there in the first place would have.
+    pub fn replay(&self, asm: &mut impl Emitter) {
+        for emission in &self.emissions {
+            match emission {
+                Emission::Bytes(bytes) => asm.emit_bytes(bytes),
+                Emission::DefineLabel(label) => asm.define_label(*label),
+                Emission::GlobalLabel(name) => asm.global_label(name),
+                Emission::LocalLabel(name) => asm.local_label(name),
+                Emission::Rel32(label) => asm.emit_rel32(*label),
+                Emission::Reloc(target, kind) => match *target {
+                    LabelRef::Dynamic(label) => asm.dynamic_reloc(label, *kind),
+                    LabelRef::Global(name) => asm.global_reloc(name, *kind),
+                    LabelRef::Forward(name) => asm.forward_reloc(name, *kind),
+                    LabelRef::Backward(name) => asm.backward_reloc(name, *kind),
+                    LabelRef::Bare(target) => asm.bare_reloc(target, *kind),
+                },
+                Emission::Instruction(instruction) => asm.instruction(*instruction),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn encoded(instruction: Instruction) -> Vec<u8> {
+    let mut asm = VecAssembler::new();
+    instruction.encode(&mut asm);
+    asm.finalize().unwrap()
+}
+
+#[cfg(test)]
+impl Extend<u8> for RecordingEmitter {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = u8>,
+    {
+        for byte in iter {
+            self.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a> Extend<&'a u8> for RecordingEmitter {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = &'a u8>,
+    {
+        self.extend(iter.into_iter().cloned());
+    }
+}
+
+#[cfg(test)]
+impl Emitter for RecordingEmitter {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.offset)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.offset += 1;
+        // Consecutive bytes are recorded together, like they would be emitted by
+        // `emit_bytes`
+        if let Some(Emission::Bytes(bytes)) = self.emissions.last_mut() {
+            bytes.push(byte);
+        } else {
+            self.emissions.push(Emission::Bytes(vec![byte]));
+        }
+    }
+
+    fn align(&mut self, alignment: usize) {
+        while self.offset % alignment != 0 {
+            self.push(0x90);
+        }
+    }
+
+    fn define_label(&mut self, label: DynamicLabel) {
+        self.emissions.push(Emission::DefineLabel(label));
+    }
+
+    fn global_label(&mut self, name: &'static str) {
+        self.emissions.push(Emission::GlobalLabel(name));
+    }
+
+    fn local_label(&mut self, name: &'static str) {
+        self.emissions.push(Emission::LocalLabel(name));
+    }
+
+    fn dynamic_reloc(&mut self, label: DynamicLabel, kind: Relocation) {
+        self.emissions
+            .push(Emission::Reloc(LabelRef::Dynamic(label), kind));
+    }
+
+    fn global_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.emissions
+            .push(Emission::Reloc(LabelRef::Global(name), kind));
+    }
+
+    fn forward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.emissions
+            .push(Emission::Reloc(LabelRef::Forward(name), kind));
+    }
+
+    fn backward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.emissions
+            .push(Emission::Reloc(LabelRef::Backward(name), kind));
+    }
+
+    fn bare_reloc(&mut self, target: usize, kind: Relocation) {
+        self.emissions
+            .push(Emission::Reloc(LabelRef::Bare(target), kind));
+    }
+
+    fn emit_rel32(&mut self, label: DynamicLabel) {
+        self.offset += 4;
+        self.emissions.push(Emission::Rel32(label));
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        self.offset += encoded(instruction).len();
+        self.emissions.push(Emission::Instruction(instruction));
+    }
+}
@@ -1,4 +1,7 @@
+use crate::index_space::DefinedFuncIndex;
+use crate::module::ExecutionError;
 use capstone;
+use std::fmt;
 use wasmparser::BinaryReaderError;
 
 #[derive(Fail, PartialEq, Eq, Clone, Debug)]
@@ -11,6 +14,47 @@ pub enum Error {
 
     #[fail(display = "Input error: {}", _0)]
     Input(String),
+
+    #[fail(display = "Instantiation error: {:?}", _0)]
+    Instantiation(ExecutionError),
+
+    #[fail(
+        display = "No {} was provided for the import {}.{}",
+        kind, module, field
+    )]
+    UnresolvedImport {
+        module: String,
+        field: String,
+        /// What the module imports, such as "function".
+        kind: &'static str,
+    },
+
+    #[fail(
+        display = "Import {}.{} doesn't match the module: {}",
+        module, field, reason
+    )]
+    IncompatibleImport {
+        module: String,
+        field: String,
+        reason: String,
+    },
+
+    #[fail(
+        display = "Function {} is over the limit of {} bytes of code",
+        func, limit
+    )]
+    FunctionTooLarge {
+        func: DefinedFuncIndex,
+        /// The size the function had reached when translation stopped.
+        size: usize,
+        limit: usize,
+    },
+
+    #[fail(display = "Module is over the limit of {} bytes of code", limit)]
+    ModuleTooLarge { size: usize, limit: usize },
+
+    #[fail(display = "Ran out of registers translating function {}", func)]
+    OutOfRegisters { func: DefinedFuncIndex },
 }
 
 impl From<BinaryReaderError> for Error {
@@ -20,6 +64,12 @@ impl From<BinaryReaderError> for Error {
     }
 }
 
+impl From<fmt::Error> for Error {
+    fn from(e: fmt::Error) -> Self {
+        Error::Disassembler(e.to_string())
+    }
+}
+
 impl From<capstone::Error> for Error {
     fn from(e: capstone::Error) -> Self {
         Error::Disassembler(e.to_string())
@@ -1,15 +1,21 @@
 use crate::backend::{
-    ret_locs, BlockCallingConvention, CodeGenSession, Context, Label, Registers, ValueLocation,
+    ret_locs, BlockCallingConvention, CodeGenSession, Context, Label, ValueLocation,
     VirtualCallingConvention,
 };
+use crate::branch_hints;
+use crate::emitter::Emitter;
 use crate::error::Error;
+use crate::index_space::{DefinedFuncIndex, FuncIndex, FuncKind};
+use crate::inline;
 use crate::microwasm::*;
 use crate::module::{ModuleContext, SigType, Signature};
-use cranelift_codegen::binemit;
-use dynasmrt::DynasmApi;
+use crate::profiling::OperatorClass;
+use cranelift_codegen::{binemit, ir::TrapCode};
 use either::{Either, Left, Right};
 use multi_mut::HashMapMultiMut;
-use std::{collections::HashMap, fmt, hash::Hash, mem};
+use std::{
+    cell::Cell, collections::HashMap, convert::TryFrom, fmt, hash::Hash, iter, mem, time::Instant,
+};
 
 #[derive(Debug)]
 struct Block {
@@ -22,6 +28,9 @@ struct Block {
     num_callers: Option<u32>,
     actual_num_callers: u32,
     has_backwards_callers: bool,
+    /// The types of the parameters, which the first `br` to a label with more than one caller
+    /// pins to the locations of the block calling convention.
+    param_types: Option<Vec<SignlessType>>,
 }
 
 impl Block {
@@ -31,21 +40,177 @@ impl Block {
     }
 }
 
-const DISASSEMBLE: bool = false;
+/// What a pass over a function's wasm finds out about its calls and locals before it's
+/// translated.
+#[derive(Debug, Default)]
+struct FunctionSummary {
+    /// Whether the function should save the callee-saved registers to keep values across
+    /// calls.
+    makes_calls: bool,
+    /// How much each local is used, as passed to `Context::start_function`.
+    local_uses: Vec<u32>,
+    /// The most values that are ever live at once, locals included, which is how many slots
+    /// `Context::start_function` reserves for spilling them.
+    max_stack_height: u32,
+    /// The locals that are never read again after the call at each offset, with their types,
+    /// for the calls that there are any for. Their values don't have to be kept across the
+    /// call.
+    dead_after_call: HashMap<usize, Vec<(u32, SignlessType)>>,
+}
 
 pub fn translate_wasm<M>(
     session: &mut CodeGenSession<M>,
     reloc_sink: &mut dyn binemit::RelocSink,
-    func_idx: u32,
+    func_idx: DefinedFuncIndex,
     body: &wasmparser::FunctionBody,
 ) -> Result<(), Error>
 where
     M: ModuleContext,
     for<'any> &'any M::Signature: Into<OpSig>,
 {
-    let ty = session.module_context.defined_func_type(func_idx);
+    let module_context = session.module_context;
+    let ty = module_context.defined_func_type(func_idx.0);
+    let hints = module_context.branch_hints(module_context.func_index(func_idx.0));
+    let body_start = body.range().start;
+    let likely =
+        move |wasm_offset: usize| hints?.get(&((wasm_offset - body_start) as u32)).cloned();
+
+    let inline_bodies = session.inline_bodies();
+
+    // Which operators are counted has to be decided from the wasm, since microwasm has
+    // branches that don't correspond to any wasm branch. Inlined calls aren't counted, since
+    // nothing is called.
+    let mut classes = HashMap::new();
+    if session.profiles_operators() {
+        let mut ops = body.get_operators_reader()?;
+        while !ops.eof() {
+            let (op, offset) = ops.read_with_offset()?;
+            if let wasmparser::Operator::Call { function_index } = op {
+                if inline_bodies.contains_key(&function_index) {
+                    continue;
+                }
+            }
+            if let Some(class) = OperatorClass::of(&op) {
+                classes.insert(offset, class);
+            }
+        }
+    }
+    let class = |wasm_offset: usize| classes.get(&wasm_offset).cloned();
+
+    // Only functions that make calls save the callee-saved registers, since they're only
+    // worth the pushes and pops if there are values that have to be kept across a call.
+    // Locals are given registers in order of how often they're read or written (which is
+    // what becomes `pick`s and `swap`s in microwasm), with each level of loop nesting
+    // counting for `LOOP_WEIGHT` times as much.
+    const LOOP_WEIGHT: u32 = 8;
+    let mut makes_calls = false;
+    let mut local_types = ty
+        .params()
+        .iter()
+        .map(SigType::to_microwasm_type)
+        .collect::<Vec<_>>();
+    for local in body.get_locals_reader()? {
+        let (count, ty) = local?;
+        let ty = SignlessType::from_wasm(ty)
+            .ok_or_else(|| Error::Input(format!("Local has type {:?}", ty)))?;
+        local_types.extend(iter::repeat(ty).take(count as usize));
+    }
+    let num_locals = local_types.len();
+    let mut local_uses = vec![0u32; num_locals];
+    let mut blocks = vec![];
+    let mut weight = 1u32;
+    // For working out which locals are dead after each call: the offsets that each local is
+    // read at, and the offset of each call with the start of the outermost loop around it,
+    // if any.
+    let mut reads = vec![vec![]; num_locals];
+    let mut calls = vec![];
+    let mut outer_loop = None::<(usize, usize)>;
+    let mut ops = body.get_operators_reader()?;
+    while !ops.eof() {
+        let (op, offset) = ops.read_with_offset()?;
+        match op {
+            wasmparser::Operator::Call { function_index } => {
+                if !inline_bodies.contains_key(&function_index) {
+                    makes_calls = true;
+                    calls.push((offset, outer_loop.map(|(start, _)| start)));
+                }
+            }
+            wasmparser::Operator::CallIndirect { .. } => {
+                makes_calls = true;
+                calls.push((offset, outer_loop.map(|(start, _)| start)));
+            }
+            wasmparser::Operator::GetLocal { local_index }
+            | wasmparser::Operator::SetLocal { local_index }
+            | wasmparser::Operator::TeeLocal { local_index } => {
+                if let Some(uses) = local_uses.get_mut(local_index as usize) {
+                    *uses = uses.saturating_add(weight);
+                }
+                if let wasmparser::Operator::GetLocal { .. } = op {
+                    if let Some(reads) = reads.get_mut(local_index as usize) {
+                        reads.push(offset);
+                    }
+                }
+            }
+            wasmparser::Operator::Block { .. } | wasmparser::Operator::If { .. } => {
+                blocks.push(weight);
+            }
+            wasmparser::Operator::Loop { .. } => {
+                if outer_loop.is_none() {
+                    outer_loop = Some((offset, blocks.len()));
+                }
+                blocks.push(weight);
+                weight = weight.saturating_mul(LOOP_WEIGHT);
+            }
+            wasmparser::Operator::End => {
+                if let Some(outer) = blocks.pop() {
+                    weight = outer;
+                }
+                if outer_loop.map(|(_, depth)| depth) == Some(blocks.len()) {
+                    outer_loop = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // A local is still live after a call if it's read anywhere after the call or, if the call
+    // is in a loop, anywhere in the loop, since the loop can go round and read it again.
+    // Debuggers can read any local at a breakpoint, so they're all kept then.
+    let mut dead_after_call = HashMap::new();
+    if !session.has_breakpoints() {
+        for (offset, loop_start) in calls {
+            let live_from = loop_start.unwrap_or(offset);
+            let dead = reads
+                .iter()
+                .enumerate()
+                .filter(|(_, reads)| reads.last().map_or(true, |&last| last < live_from))
+                .map(|(local, _)| (local as u32, local_types[local]))
+                .collect::<Vec<_>>();
+            if !dead.is_empty() {
+                dead_after_call.insert(offset, dead);
+            }
+        }
+    }
+
+    // Spilled values go in slots that the prologue reserves all at once, so how many values
+    // are live at most has to be known before the function is translated
+    let mut sizing = MicrowasmConv::new(
+        session.module_context,
+        ty.params().iter().map(SigType::to_microwasm_type),
+        ty.returns().iter().map(SigType::to_microwasm_type),
+        body,
+    );
+    sizing.by_ref().map_while(Result::ok).for_each(drop);
+    let max_stack_height = sizing.max_stack_height() as u32;
+
+    let summary = FunctionSummary {
+        makes_calls,
+        local_uses,
+        dead_after_call,
+        max_stack_height,
+    };
 
-    if DISASSEMBLE {
+    if log_trace_enabled!() {
         let microwasm_conv = MicrowasmConv::new(
             session.module_context,
             ty.params().iter().map(SigType::to_microwasm_type),
@@ -53,40 +218,114 @@ where
             body,
         );
 
+        // Errors are reported when the function is translated below
+        let mut microwasm = Vec::new();
         let _ = crate::microwasm::dis(
-            std::io::stdout(),
-            func_idx,
-            microwasm_conv.flat_map(|ops| ops.unwrap()),
+            &mut microwasm,
+            func_idx.0,
+            microwasm_conv.map_while(Result::ok).flatten(),
         );
+        log_trace!("Microwasm:\n{}", String::from_utf8_lossy(&microwasm));
     }
 
-    let microwasm_conv = MicrowasmConv::new(
+    let mut microwasm_conv = MicrowasmConv::new(
         session.module_context,
         ty.params().iter().map(SigType::to_microwasm_type),
         ty.returns().iter().map(SigType::to_microwasm_type),
         body,
     );
+    // The body ends at the first operator that can't be converted, and the error is returned
+    // in place of whatever translating what came before it did
+    let mut conv_error = None;
+    let error = &mut conv_error;
+    let body = iter::from_fn(move || {
+        let ops = match microwasm_conv.next()? {
+            Ok(ops) => ops,
+            Err(e) => {
+                *error = Some(e);
+                return None;
+            }
+        };
+        let wasm_offset = microwasm_conv.wasm_offset();
+        Some(ops.into_iter().map(move |op| (wasm_offset, op)))
+    })
+    .flatten();
+    let body = inline::inline_calls(body, &inline_bodies);
+
+    let result = if hints.is_some() {
+        let mut body = body.collect::<Vec<_>>();
+        branch_hints::sink_cold_blocks(&mut body, likely);
+        translate_with_offsets(session, reloc_sink, func_idx, body, likely, class, &summary)
+    } else {
+        translate_with_offsets(session, reloc_sink, func_idx, body, likely, class, &summary)
+    };
+
+    match conv_error {
+        Some(e) => Err(e.into()),
+        None => result,
+    }
+}
 
-    translate(
+pub fn translate<M, I, L: Send + Sync + 'static>(
+    session: &mut CodeGenSession<M>,
+    reloc_sink: &mut dyn binemit::RelocSink,
+    func_idx: DefinedFuncIndex,
+    body: I,
+) -> Result<(), Error>
+where
+    M: ModuleContext,
+    I: IntoIterator<Item = Operator<L>>,
+    L: Hash + Clone + Eq,
+    BrTarget<L>: std::fmt::Display,
+{
+    translate_with_offsets(
         session,
         reloc_sink,
         func_idx,
-        microwasm_conv.flat_map(|i| i.expect("TODO: Make this not panic")),
+        body.into_iter().map(|op| (None, op)),
+        |_| None,
+        |_| None,
+        &FunctionSummary::default(),
     )
 }
 
-pub fn translate<M, I, L: Send + Sync + 'static>(
+/// Like `translate`, but with each operator paired with the offset in the module of the wasm
+/// operator that it was translated from, if any, for the disassembly. `likely` is whether
+/// the `br_if` from the wasm operator at an offset is likely to be taken, if it's hinted, and
+/// `class` is the class of the wasm operator at an offset, if it's counted. `summary` is what's
+/// known about the function's calls and locals from its wasm, if anything.
+fn translate_with_offsets<M, I, L: Send + Sync + 'static>(
     session: &mut CodeGenSession<M>,
     reloc_sink: &mut dyn binemit::RelocSink,
-    func_idx: u32,
+    func_idx: DefinedFuncIndex,
     body: I,
+    likely: impl Fn(usize) -> Option<bool>,
+    class: impl Fn(usize) -> Option<OperatorClass>,
+    summary: &FunctionSummary,
 ) -> Result<(), Error>
 where
     M: ModuleContext,
-    I: IntoIterator<Item = Operator<L>>,
+    I: IntoIterator<Item = (Option<usize>, Operator<L>)>,
     L: Hash + Clone + Eq,
     BrTarget<L>: std::fmt::Display,
 {
+    fn branches_to<L: Eq>(op: &Operator<L>, label: &L) -> bool {
+        let is_label = |target: &BrTarget<L>| match target {
+            BrTarget::Label(l) => l == label,
+            BrTarget::Return => false,
+        };
+
+        match op {
+            Operator::Br { target } => is_label(target),
+            Operator::BrIf { then, else_ } => is_label(&then.target) || is_label(&else_.target),
+            Operator::BrTable(BrTable { targets, default }) => targets
+                .iter()
+                .chain(std::iter::once(default))
+                .any(|target| is_label(&target.target)),
+            _ => false,
+        }
+    }
+
     fn drop_elements<T>(stack: &mut Vec<T>, depths: std::ops::RangeInclusive<u32>) {
         let _ = (|| {
             let start = stack
@@ -105,16 +344,26 @@ where
         })();
     }
 
-    let func_type = session.module_context.defined_func_type(func_idx);
-    let mut body = body.into_iter().peekable();
+    let start_time = Instant::now();
+    log_debug!("Translating function {}", func_idx);
+    let func_type = session.module_context.defined_func_type(func_idx.0);
+    // Operators are weighed as they're read, so that operators that are fused into the one
+    // before them are counted too.
+    let cost_model = session.cost_model();
+    let cost = Cell::new(0u64);
+    let body = body
+        .into_iter()
+        .inspect(|(_, op)| cost.set(cost.get() + u64::from(cost_model.cost(op))));
+    let mut body = itertools::multipeek(body);
 
     let module_context = &*session.module_context;
     let mut op_offset_map = mem::replace(&mut session.op_offset_map, vec![]);
     let ctx = &mut session.new_context(func_idx, reloc_sink);
-    op_offset_map.push((
-        ctx.asm.offset(),
-        Box::new(format!("Function {}:", func_idx)),
-    ));
+    let header = match module_context.func_name(module_context.func_index(func_idx.0)) {
+        Some(name) => format!("Function {} ({}):", func_idx, name),
+        None => format!("Function {}:", func_idx),
+    };
+    op_offset_map.push((ctx.asm.offset(), Box::new(header)));
 
     let params = func_type
         .params()
@@ -122,7 +371,15 @@ where
         .map(|t| t.to_microwasm_type())
         .collect::<Vec<_>>();
 
-    ctx.start_function(params.iter().cloned());
+    ctx.start_function(
+        params.iter().cloned(),
+        summary.makes_calls,
+        &summary.local_uses,
+        summary.max_stack_height,
+    );
+    ctx.trace_function_entry();
+    ctx.count_function_entry();
+    ctx.cover_block();
 
     let mut blocks = HashMap::<BrTarget<L>, Block>::new();
 
@@ -133,23 +390,71 @@ where
         Block {
             label: BrTarget::Return,
             params: num_returns as u32,
-            calling_convention: Some(Left(BlockCallingConvention::function_start(ret_locs(
-                func_type.returns().iter().map(|t| t.to_microwasm_type()),
-            )))),
+            calling_convention: Some(Left(BlockCallingConvention::function_start(
+                ret_locs(func_type.returns().iter().map(|t| t.to_microwasm_type())),
+                ctx.frame_depth(),
+            ))),
             is_next: false,
             has_backwards_callers: false,
             actual_num_callers: 0,
             num_callers: None,
+            param_types: None,
         },
     );
 
-    while let Some(op) = body.next() {
-        if let Some(Operator::Label(label)) = body.peek() {
-            let block = blocks
-                .get_mut(&BrTarget::Label(label.clone()))
-                .expect("Label defined before being declared");
-            block.is_next = true;
+    // Whether control can reach the point after the last operator that we emitted. This
+    // starts out `true` so that we handle bodies without any operators at all.
+    let mut falls_through = true;
+    let mut num_ops = 0usize;
+    let mut last_breakpoint = None;
+    // The unconditional branch that a `br_if` on a constant was folded into.
+    let mut folded_br = None;
+    // Whether the last operator branched to the label right after it, which it falls through
+    // to without a jump.
+    let mut falls_into_next = false;
+
+    while let Some((wasm_offset, op)) = folded_br.take().or_else(|| body.next()) {
+        num_ops += 1;
+        ctx.start_operator()?;
+        ctx.record_operator(wasm_offset, DisOp(&op));
+        log_trace!(
+            "{} ({:?}, stack {:?}, registers {})",
+            op,
+            ctx.block_state.depth,
+            ctx.block_state.stack,
+            ctx.block_state.regs
+        );
+
+        // Dead blocks generate no code, so every label up to and including the first live one
+        // after this operator is where execution would fall through to.
+        let fell_through = mem::replace(&mut falls_into_next, false);
+        body.reset_peek();
+        let mut in_dead_block = false;
+        loop {
+            match body.peek() {
+                Some((_, Operator::Label(label))) => {
+                    let block = match blocks.get_mut(&BrTarget::Label(label.clone())) {
+                        Some(block) => block,
+                        // Declared inside a dead block, so it can't be branched to from here
+                        None if in_dead_block => break,
+                        None => panic!("Label defined before being declared"),
+                    };
+                    block.is_next = true;
+                    falls_into_next = match op {
+                        Operator::Br { .. } | Operator::BrIf { .. } => branches_to(&op, label),
+                        _ => false,
+                    };
+
+                    if block.actual_num_callers != 0 || branches_to(&op, label) {
+                        break;
+                    }
+                    in_dead_block = true;
+                }
+                Some(_) if in_dead_block => {}
+                _ => break,
+            }
         }
+        body.reset_peek();
 
         macro_rules! assert_ge {
             ($left:expr, $right:expr) => ({
@@ -185,7 +490,7 @@ where
                         assert_ge!(num_cc_params, block.params as usize);
                     }
                 } else {
-                    let mut actual_regs = Registers::new();
+                    let mut actual_regs = ctx.block_state.regs.cleared();
                     for val in &ctx.block_state.stack {
                         if let ValueLocation::Reg(gpr) = val {
                             actual_regs.mark_used(*gpr);
@@ -203,29 +508,54 @@ where
 
         assertions!();
 
-        struct DisassemblyOpFormatter<Label>(Operator<Label>);
+        /// Formats an operator and the offset of the wasm operator that it came from.
+        struct DisassemblyOpFormatter<Label>(Option<usize>, Operator<Label>);
 
         impl<Label> fmt::Display for DisassemblyOpFormatter<Label>
         where
             Operator<Label>: fmt::Display,
         {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                match self.0 {
-                    Operator::Label(_) => write!(f, "{}", self.0),
-                    Operator::Block { .. } => write!(f, "{:5}\t{}", "", self.0),
-                    _ => write!(f, "{:5}\t  {}", "", self.0),
+                let wasm_offset = self
+                    .0
+                    .map(|offset| format!("@{:#x}", offset))
+                    .unwrap_or_default();
+                match self.1 {
+                    Operator::Label(_) => write!(f, "{}", self.1),
+                    Operator::Block { .. } => write!(f, "{:8}\t{}", wasm_offset, self.1),
+                    _ => write!(f, "{:8}\t  {}", wasm_offset, self.1),
                 }
             }
         }
 
         op_offset_map.push((
             ctx.asm.offset(),
-            Box::new(DisassemblyOpFormatter(op.clone())),
+            Box::new(DisassemblyOpFormatter(wasm_offset, op.clone())),
         ));
 
+        falls_through = match op {
+            Operator::Unreachable | Operator::Br { .. } | Operator::BrTable(_) => false,
+            _ => true,
+        };
+
+        // The first of the operators that a wasm instruction was translated into that
+        // generates code gets the breakpoint for it, and counts it.
+        match (&op, wasm_offset) {
+            (Operator::Label(_), _) | (Operator::Block { .. }, _) | (_, None) => {}
+            (_, Some(offset)) => {
+                if last_breakpoint != Some(offset) {
+                    ctx.breakpoint_site(offset);
+                    if let Some(class) = class(offset) {
+                        ctx.count_operator(class);
+                    }
+                    last_breakpoint = Some(offset);
+                }
+            }
+        }
+
         match op {
             Operator::Unreachable => {
-                ctx.trap();
+                ctx.trap(TrapCode::UnreachableCodeReached);
             }
             Operator::Label(label) => {
                 use std::collections::hash_map::Entry;
@@ -239,9 +569,13 @@ where
                         //       blocks without callers are illegal, but that's not reasonably possible for
                         //       Microwasm generated from Wasm.
                         if block.actual_num_callers == 0 {
+                            // Nothing can reach this block, so skip everything up to the next
+                            // label without generating any code for it.
+                            ctx.skip_dead_block();
                             loop {
+                                body.reset_peek();
                                 let done = match body.peek() {
-                                    Some(Operator::Label(_)) | None => true,
+                                    Some((_, Operator::Label(_))) | None => true,
                                     Some(_) => false,
                                 };
 
@@ -249,7 +583,7 @@ where
                                     break;
                                 }
 
-                                let skipped = body.next();
+                                let skipped = body.next().map(|(_, op)| op);
 
                                 // We still want to honour block definitions even in unreachable code
                                 if let Some(Operator::Block {
@@ -270,11 +604,15 @@ where
                                             has_backwards_callers,
                                             actual_num_callers: 0,
                                             num_callers,
+                                            param_types: Some(params),
                                         },
                                     );
                                 }
                             }
 
+                            falls_through = false;
+                            // Whether we fell through to the label after this block as well
+                            falls_into_next = fell_through;
                             continue;
                         }
 
@@ -291,7 +629,19 @@ where
                             _ => assert_eq!(block.params as usize, ctx.block_state.stack.len()),
                         }
 
-                        ctx.define_label(block.label.label().unwrap().clone());
+                        let label = block.label.label().unwrap().clone();
+                        if fell_through
+                            && block.actual_num_callers == 1
+                            && !block.has_backwards_callers
+                        {
+                            ctx.define_fallthrough_label(label);
+                        } else {
+                            ctx.define_label(label);
+                        }
+                        ctx.cover_block();
+                        if block.has_backwards_callers {
+                            ctx.start_loop();
+                        }
 
                         block.has_backwards_callers
                     };
@@ -321,6 +671,7 @@ where
                         has_backwards_callers,
                         actual_num_callers: 0,
                         num_callers,
+                        param_types: Some(params),
                     },
                 );
             }
@@ -336,10 +687,15 @@ where
                         is_next,
                         label: BrTarget::Label(l),
                         calling_convention,
+                        param_types,
+                        params,
                         ..
                     } => {
                         let cc = if should_serialize_args {
-                            *calling_convention = Some(Left(ctx.serialize_args(block.params)));
+                            *calling_convention = Some(Left(match param_types {
+                                Some(param_types) => ctx.serialize_pinned_args(param_types),
+                                None => ctx.serialize_args(*params),
+                            }));
                             None
                         } else {
                             calling_convention
@@ -367,6 +723,26 @@ where
                     _ => unimplemented!(),
                 }
             }
+            Operator::BrIf { then, else_ } if ctx.top_constant().is_some() => {
+                // Only one side can be taken, so branch there unconditionally and leave the
+                // other side without this caller, which lets it be skipped if it has no others.
+                let taken = match ctx.top_constant().and_then(Value::as_i32) {
+                    Some(0) => else_,
+                    _ => then,
+                };
+
+                ctx.drop(0..=0);
+                if let Some(to_drop) = taken.to_drop {
+                    ctx.drop(to_drop);
+                }
+
+                folded_br = Some((
+                    wasm_offset,
+                    Operator::Br {
+                        target: taken.target,
+                    },
+                ));
+            }
             Operator::BrIf { then, else_ } => {
                 let (then_block, else_block) = blocks.pair_mut(&then.target, &else_.target);
                 // TODO: If actual_num_callers == num_callers then we can remove this block from the hashmap.
@@ -402,17 +778,22 @@ where
                             (ref mut then_cc @ None, then_to_drop),
                             (ref mut else_cc @ None, else_to_drop),
                         ) => {
-                            let virt_cc = if !then_block_should_serialize_args
-                                || !else_block_should_serialize_args
+                            let cc = if then_block_should_serialize_args
+                                || else_block_should_serialize_args
                             {
-                                Some(ctx.virtual_calling_convention())
+                                Some(ctx.serialize_args(max_params))
                             } else {
                                 None
                             };
-                            let cc = if then_block_should_serialize_args
-                                || else_block_should_serialize_args
+                            // Serializing can move values and push to the stack, so the
+                            // other side has to start from the state after it.
+                            let virt_cc = if !then_block_should_serialize_args
+                                || !else_block_should_serialize_args
                             {
-                                Some(ctx.serialize_args(max_params))
+                                Some(match &cc {
+                                    Some(cc) => ctx.serialized_calling_convention(cc),
+                                    None => ctx.virtual_calling_convention(),
+                                })
                             } else {
                                 None
                             };
@@ -457,6 +838,13 @@ where
                     ((false, then), (true, _)) => {
                         ctx.br_if_true(then, f);
                     }
+                    // Jump away on the condition that's unlikely to hold
+                    ((false, then), (false, else_))
+                        if wasm_offset.and_then(&likely) == Some(true) =>
+                    {
+                        ctx.br_if_false(else_, f);
+                        ctx.br(then);
+                    }
                     ((false, then), (false, else_)) => {
                         ctx.br_if_true(then, f);
                         ctx.br(else_);
@@ -649,6 +1037,69 @@ where
             Operator::Lt(SF64) => ctx.f64_lt(),
             Operator::Le(SF64) => ctx.f64_le(),
             Operator::Drop(range) => ctx.drop(range),
+            Operator::Const(Value::I32(imm @ 0..=1)) if ctx.top_is_known_bool() => {
+                // Comparing a value that's known to be 0 or 1 with 0, or combining it with 0 or 1,
+                // often gives the value itself or its `i32.eqz`. Doing that here means that we
+                // don't need to materialize it first if it's a condition.
+                body.reset_peek();
+                let invert = match (imm, body.peek().map(|(_, op)| op)) {
+                    (0, Some(Operator::Ne(I32)))
+                    | (0, Some(Operator::Or(Size::_32)))
+                    | (0, Some(Operator::Xor(Size::_32)))
+                    | (1, Some(Operator::And(Size::_32))) => Some(false),
+                    (0, Some(Operator::Eq(I32))) | (1, Some(Operator::Xor(Size::_32))) => {
+                        Some(true)
+                    }
+                    _ => None,
+                };
+
+                match invert {
+                    Some(invert) => {
+                        body.next();
+                        num_ops += 1;
+                        if invert {
+                            ctx.i32_eqz();
+                        }
+                    }
+                    None => ctx.const_(Value::I32(imm)),
+                }
+            }
+            Operator::Const(shift) if shift.as_int().map(|s| s >= 1 && s <= 3).unwrap_or(false) => {
+                // `base + (index << shift) + displacement` is a single `lea`, which is common
+                // when indexing into arrays.
+                let ty = shift.type_();
+                body.reset_peek();
+                let shifts = match body.peek() {
+                    Some((_, Operator::Shl(size))) => Type::Int(*size) == ty,
+                    _ => false,
+                };
+                let adds = shifts
+                    && match body.peek() {
+                        Some((_, Operator::Add(add_ty))) => *add_ty == ty,
+                        _ => false,
+                    };
+                let displacement = match body.peek() {
+                    Some((_, Operator::Const(disp))) if adds && disp.type_() == ty => {
+                        disp.as_int().and_then(|disp| i32::try_from(disp).ok())
+                    }
+                    _ => None,
+                };
+                let displacement = displacement.filter(|_| match body.peek() {
+                    Some((_, Operator::Add(add_ty))) => *add_ty == ty,
+                    _ => false,
+                });
+
+                let count = shift.as_int().unwrap() as u32;
+                if adds && ctx.add_scaled(ty, count, displacement.unwrap_or(0)) {
+                    let fused = if displacement.is_some() { 4 } else { 2 };
+                    for _ in 0..fused {
+                        body.next();
+                    }
+                    num_ops += fused;
+                } else {
+                    ctx.const_(shift);
+                }
+            }
             Operator::Const(val) => ctx.const_(val),
             Operator::I32WrapFromI64 => ctx.i32_wrap_from_i64(),
             Operator::I32ReinterpretFromF32 => ctx.i32_reinterpret_from_f32(),
@@ -746,113 +1197,175 @@ where
             Operator::Load8 {
                 ty: sint::U32,
                 memarg,
-            } => ctx.i32_load8_u(memarg.offset),
+            } => ctx.i32_load8_u(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load16 {
                 ty: sint::U32,
                 memarg,
-            } => ctx.i32_load16_u(memarg.offset),
+            } => ctx.i32_load16_u(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load8 {
                 ty: sint::I32,
                 memarg,
-            } => ctx.i32_load8_s(memarg.offset),
+            } => ctx.i32_load8_s(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load16 {
                 ty: sint::I32,
                 memarg,
-            } => ctx.i32_load16_s(memarg.offset),
+            } => ctx.i32_load16_s(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load8 {
                 ty: sint::U64,
                 memarg,
-            } => ctx.i64_load8_u(memarg.offset),
+            } => ctx.i64_load8_u(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load16 {
                 ty: sint::U64,
                 memarg,
-            } => ctx.i64_load16_u(memarg.offset),
+            } => ctx.i64_load16_u(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load8 {
                 ty: sint::I64,
                 memarg,
-            } => ctx.i64_load8_s(memarg.offset),
+            } => ctx.i64_load8_s(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load16 {
                 ty: sint::I64,
                 memarg,
-            } => ctx.i64_load16_s(memarg.offset),
+            } => ctx.i64_load16_s(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load32 {
                 sign: Signedness::Unsigned,
                 memarg,
-            } => ctx.i64_load32_u(memarg.offset),
+            } => ctx.i64_load32_u(memarg.memory_index, memarg.offset, memarg.bounds_checked),
             Operator::Load32 {
                 sign: Signedness::Signed,
                 memarg,
-            } => ctx.i64_load32_s(memarg.offset),
-            Operator::Load { ty: I32, memarg } => ctx.i32_load(memarg.offset),
-            Operator::Load { ty: F32, memarg } => ctx.f32_load(memarg.offset),
-            Operator::Load { ty: I64, memarg } => ctx.i64_load(memarg.offset),
-            Operator::Load { ty: F64, memarg } => ctx.f64_load(memarg.offset),
-            Operator::Store8 { ty: _, memarg } => ctx.store8(memarg.offset),
-            Operator::Store16 { ty: _, memarg } => ctx.store16(memarg.offset),
+            } => ctx.i64_load32_s(memarg.memory_index, memarg.offset, memarg.bounds_checked),
+            Operator::Load { ty: I32, memarg } => {
+                ctx.i32_load(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
+            Operator::Load { ty: F32, memarg } => {
+                ctx.f32_load(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
+            Operator::Load { ty: I64, memarg } => {
+                ctx.i64_load(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
+            Operator::Load { ty: F64, memarg } => {
+                ctx.f64_load(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
+            Operator::Store8 { ty: _, memarg } => {
+                ctx.store8(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
+            Operator::Store16 { ty: _, memarg } => {
+                ctx.store16(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
             Operator::Store32 { memarg }
             | Operator::Store { ty: I32, memarg }
-            | Operator::Store { ty: F32, memarg } => ctx.store32(memarg.offset),
+            | Operator::Store { ty: F32, memarg } => {
+                ctx.store32(memarg.memory_index, memarg.offset, memarg.bounds_checked)
+            }
             Operator::Store { ty: I64, memarg } | Operator::Store { ty: F64, memarg } => {
-                ctx.store64(memarg.offset)
+                ctx.store64(memarg.memory_index, memarg.offset, memarg.bounds_checked)
             }
             Operator::GetGlobal(idx) => ctx.get_global(idx),
             Operator::SetGlobal(idx) => ctx.set_global(idx),
             Operator::Select => {
                 ctx.select();
             }
-            Operator::MemorySize { reserved: _ } => {
-                ctx.memory_size();
+            Operator::MemorySize {
+                reserved: memory_index,
+            } => {
+                ctx.memory_size(memory_index);
             }
-            Operator::MemoryGrow { reserved: _ } => {
-                ctx.memory_grow();
+            Operator::MemoryGrow {
+                reserved: memory_index,
+            } => {
+                ctx.memory_grow(memory_index);
             }
+            Operator::TableGet { table_index } => ctx.table_get(table_index),
+            Operator::TableSet { table_index } => ctx.table_set(table_index),
+            Operator::TableGrow { table_index } => ctx.table_grow(table_index),
+            Operator::TableSize { table_index } => ctx.table_size(table_index),
             Operator::Call { function_index } => {
+                if let Some(dead) = wasm_offset.and_then(|o| summary.dead_after_call.get(&o)) {
+                    ctx.discard_dead_locals(dead);
+                }
+
                 let callee_ty = module_context.func_type(function_index);
+                let params = callee_ty.params().iter().map(|t| t.to_microwasm_type());
+                let returns = callee_ty.returns().iter().map(|t| t.to_microwasm_type());
 
-                if let Some(defined_index) = module_context.defined_func_index(function_index) {
-                    if function_index == func_idx {
-                        ctx.call_direct_self(
-                            defined_index,
-                            callee_ty.params().iter().map(|t| t.to_microwasm_type()),
-                            callee_ty.returns().iter().map(|t| t.to_microwasm_type()),
-                        );
-                    } else {
-                        ctx.call_direct(
-                            function_index,
-                            callee_ty.params().iter().map(|t| t.to_microwasm_type()),
-                            callee_ty.returns().iter().map(|t| t.to_microwasm_type()),
-                        );
+                match module_context.func_kind(FuncIndex(function_index)) {
+                    FuncKind::Defined(defined_index)
+                        if defined_index == func_idx || ctx.links_calls() =>
+                    {
+                        ctx.call_direct_local(defined_index, params, returns);
+                    }
+                    FuncKind::Defined(_) => {
+                        ctx.call_direct(FuncIndex(function_index), params, returns);
+                    }
+                    FuncKind::Imported(imported_index) => {
+                        if !module_context.lower_intrinsic(imported_index, ctx) {
+                            ctx.call_direct_imported(imported_index, params, returns);
+                        }
                     }
-                } else {
-                    ctx.call_direct_imported(
-                        function_index,
-                        callee_ty.params().iter().map(|t| t.to_microwasm_type()),
-                        callee_ty.returns().iter().map(|t| t.to_microwasm_type()),
-                    );
                 }
             }
+            Operator::Libcall {
+                name,
+                params,
+                returns,
+            } => ctx.call_libcall(name, params, returns),
             Operator::CallIndirect {
                 type_index,
                 table_index,
             } => {
                 assert_eq!(table_index, 0);
 
+                if let Some(dead) = wasm_offset.and_then(|o| summary.dead_after_call.get(&o)) {
+                    ctx.discard_dead_locals(dead);
+                }
+
                 let callee_ty = module_context.signature(type_index);
 
-                // TODO: this implementation assumes that this function is locally defined.
+                if let Some(function_index) = ctx.devirtualize(type_index) {
+                    let params = callee_ty.params().iter().map(|t| t.to_microwasm_type());
+                    let returns = callee_ty.returns().iter().map(|t| t.to_microwasm_type());
 
-                ctx.call_indirect(
-                    type_index,
-                    callee_ty.params().iter().map(|t| t.to_microwasm_type()),
-                    callee_ty.returns().iter().map(|t| t.to_microwasm_type()),
-                );
+                    match module_context.func_kind(FuncIndex(function_index)) {
+                        FuncKind::Defined(defined_index) => {
+                            ctx.call_direct_local(defined_index, params, returns);
+                        }
+                        FuncKind::Imported(imported_index) => {
+                            if !module_context.lower_intrinsic(imported_index, ctx) {
+                                ctx.call_direct_imported(imported_index, params, returns);
+                            }
+                        }
+                    }
+                } else {
+                    // TODO: this implementation assumes that this function is locally defined.
+
+                    ctx.call_indirect(
+                        type_index,
+                        callee_ty.params().iter().map(|t| t.to_microwasm_type()),
+                        callee_ty.returns().iter().map(|t| t.to_microwasm_type()),
+                    );
+                }
+            }
+        }
+    }
+
+    // Microwasm generated from Wasm always ends with a branch or a trap, but we still want
+    // degenerate bodies to produce valid code instead of falling through into whatever
+    // comes after this function.
+    if falls_through {
+        match &blocks[&BrTarget::Return].calling_convention {
+            Some(Left(cc)) if num_ops == 0 && num_returns == 0 => {
+                ctx.pass_block_args(cc);
+                ctx.ret();
             }
+            _ => ctx.trap(TrapCode::UnreachableCodeReached),
         }
     }
 
-    ctx.epilogue();
+    ctx.set_cost(cost.get());
+    ctx.epilogue()?;
 
     mem::replace(&mut session.op_offset_map, op_offset_map);
+    session.function_translated(func_idx, start_time.elapsed());
 
     Ok(())
 }
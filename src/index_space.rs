@@ -0,0 +1,143 @@
+//! Typed indices for the functions in a module, so that an index into one index space can't be
+//! used for another. Wasm's function index space has the imported functions first, followed by
+//! the functions defined in the code section, and the code we generate also contains
+//! trampolines that don't have a wasm index at all.
+
+use std::{
+    fmt,
+    iter::FromIterator,
+    marker::PhantomData,
+    ops::{Index, IndexMut},
+};
+
+pub trait EntityIndex: Copy {
+    fn new(index: usize) -> Self;
+    fn index(self) -> usize;
+}
+
+macro_rules! entity_index {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u32);
+
+        impl EntityIndex for $name {
+            fn new(index: usize) -> Self {
+                $name(index as u32)
+            }
+
+            fn index(self) -> usize {
+                self.0 as usize
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+entity_index!(
+    /// An index into the module's whole function index space, as used by `call`.
+    FuncIndex
+);
+entity_index!(
+    /// An imported function, counting from the first import. Since imports come first in the
+    /// function index space this is also its `FuncIndex`.
+    ImportedFuncIndex
+);
+entity_index!(
+    /// A function defined by the module, counting from the first body in the code section.
+    DefinedFuncIndex
+);
+entity_index!(
+    /// Code generated for the module that isn't a wasm function, such as an exit stub.
+    TrampolineIndex
+);
+
+/// Which part of the function index space a function is in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FuncKind {
+    Imported(ImportedFuncIndex),
+    Defined(DefinedFuncIndex),
+}
+
+/// A vector that can only be indexed by `I`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexVec<I, T> {
+    items: Vec<T>,
+    _index: PhantomData<I>,
+}
+
+impl<I: EntityIndex, T> IndexVec<I, T> {
+    pub fn new() -> Self {
+        IndexVec {
+            items: Vec::new(),
+            _index: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Add an item, returning its index.
+    pub fn push(&mut self, item: T) -> I {
+        self.items.push(item);
+        I::new(self.items.len() - 1)
+    }
+
+    pub fn get(&self, index: I) -> Option<&T> {
+        self.items.get(index.index())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.items.iter()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (I, &T)> + '_ {
+        self.items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (I::new(i), item))
+    }
+
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> IndexVec<I, U> {
+        self.items.iter().map(f).collect()
+    }
+}
+
+impl<I: EntityIndex, T> Default for IndexVec<I, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: EntityIndex, T> FromIterator<T> for IndexVec<I, T> {
+    fn from_iter<It: IntoIterator<Item = T>>(iter: It) -> Self {
+        IndexVec {
+            items: iter.into_iter().collect(),
+            _index: PhantomData,
+        }
+    }
+}
+
+impl<I: EntityIndex, T> Index<I> for IndexVec<I, T> {
+    type Output = T;
+
+    fn index(&self, index: I) -> &T {
+        &self.items[index.index()]
+    }
+}
+
+impl<I: EntityIndex, T> IndexMut<I> for IndexVec<I, T> {
+    fn index_mut(&mut self, index: I) -> &mut T {
+        &mut self.items[index.index()]
+    }
+}
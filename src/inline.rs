@@ -0,0 +1,160 @@
+//! Inlining of tiny functions, like the accessors that Rust and C++ compilers leave behind
+//! in wasm, at the microwasm level.
+//!
+//! Only functions without any control flow are inlined: a straight line of operators
+//! followed by the return. Their bodies have no labels, so they can be spliced in place of a
+//! call without renaming anything, and the values that they leave below their results are
+//! dropped where they'd have returned. Inlined calls don't call trace hooks or count towards
+//! the callee's hotness, since the callee's own code doesn't run.
+
+use crate::error::Error;
+use crate::index_space::DefinedFuncIndex;
+use crate::microwasm::{BrTarget, MicrowasmConv, OpSig, Operator, OperatorFromWasm};
+use crate::module::{ModuleContext, SigType, Signature};
+use std::collections::HashMap;
+use wasmparser::FunctionBody;
+
+/// The bodies of the functions that calls can be replaced with, by function index.
+pub type InlineBodies = HashMap<u32, Vec<OperatorFromWasm>>;
+
+/// How `op` changes the height of the stack, or `None` if a function containing it can't be
+/// inlined.
+fn stack_effect<L>(op: &Operator<L>) -> Option<i32> {
+    use crate::microwasm::Operator::*;
+
+    Some(match op {
+        Const(_) | Pick(_) | GetGlobal(_) | MemorySize { .. } => 1,
+        Swap(_)
+        | Load { .. }
+        | Load8 { .. }
+        | Load16 { .. }
+        | Load32 { .. }
+        | MemoryGrow { .. }
+        | Eqz(_)
+        | Clz(_)
+        | Ctz(_)
+        | Popcnt(_)
+        | Abs(_)
+        | Neg(_)
+        | Ceil(_)
+        | Floor(_)
+        | Trunc(_)
+        | Nearest(_)
+        | Sqrt(_)
+        | I32WrapFromI64
+        | ITruncFromF { .. }
+        | FConvertFromI { .. }
+        | F32DemoteFromF64
+        | F64PromoteFromF32
+        | I32ReinterpretFromF32
+        | I64ReinterpretFromF64
+        | F32ReinterpretFromI32
+        | F64ReinterpretFromI64
+        | Extend { .. } => 0,
+        SetGlobal(_) | Eq(_) | Ne(_) | Lt(_) | Gt(_) | Le(_) | Ge(_) | Add(_) | Sub(_) | Mul(_)
+        | Div(_) | Rem(_) | And(_) | Or(_) | Xor(_) | Shl(_) | Shr(_) | Rotl(_) | Rotr(_)
+        | Min(_) | Max(_) | Copysign(_) => -1,
+        Select | Store { .. } | Store8 { .. } | Store16 { .. } | Store32 { .. } => -2,
+        Drop(range) => -((range.end() - range.start() + 1) as i32),
+        _ => return None,
+    })
+}
+
+/// The operators to replace a call to a function with, if it has no control flow and no
+/// more than `max_size` operators. `ops` is the function's whole body, which starts with
+/// its `num_params` parameters on the stack.
+fn inline_body<L>(
+    mut ops: Vec<Operator<L>>,
+    num_params: u32,
+    num_returns: u32,
+    max_size: usize,
+) -> Option<Vec<Operator<L>>> {
+    match ops.pop() {
+        Some(Operator::Br {
+            target: BrTarget::Return,
+        }) => {}
+        _ => return None,
+    }
+    if ops.len() > max_size {
+        return None;
+    }
+
+    let mut height = num_params as i32;
+    for op in &ops {
+        height += stack_effect(op)?;
+    }
+
+    // The return passes on the results and discards everything below them
+    let height = height as u32;
+    if height > num_returns {
+        ops.push(Operator::Drop(num_returns..=height - 1));
+    }
+
+    Some(ops)
+}
+
+/// Find the functions that calls can be replaced with among those with the given bodies.
+pub fn inline_bodies<M: ModuleContext>(
+    module_context: &M,
+    bodies: &[FunctionBody],
+    max_size: usize,
+) -> Result<InlineBodies, Error>
+where
+    for<'any> &'any M::Signature: Into<OpSig>,
+{
+    let mut inline_bodies = HashMap::new();
+
+    for (idx, body) in bodies.iter().enumerate() {
+        let func_idx = DefinedFuncIndex(idx as u32);
+        let ty = module_context.defined_func_type(func_idx.0);
+        let conv = MicrowasmConv::new(
+            module_context,
+            ty.params().iter().map(SigType::to_microwasm_type),
+            ty.returns().iter().map(SigType::to_microwasm_type),
+            body,
+        );
+        let mut ops = vec![];
+        for op in conv {
+            ops.extend(op?);
+            // Don't bother converting the rest of a function that's clearly too big
+            if ops.len() > max_size + 1 {
+                break;
+            }
+        }
+
+        let num_params = ty.params().len() as u32;
+        let num_returns = ty.returns().len() as u32;
+        if let Some(ops) = inline_body(ops, num_params, num_returns, max_size) {
+            log_debug!("Inlining calls to function {}", func_idx);
+            inline_bodies.insert(module_context.func_index(func_idx.0), ops);
+        }
+    }
+
+    Ok(inline_bodies)
+}
+
+/// Replace every call to a function in `inline_bodies` with its body. The inlined operators
+/// take the wasm offset of the call.
+pub fn inline_calls<'a, I>(
+    body: I,
+    inline_bodies: &'a InlineBodies,
+) -> impl Iterator<Item = (Option<usize>, OperatorFromWasm)> + 'a
+where
+    I: IntoIterator<Item = (Option<usize>, OperatorFromWasm)>,
+    I::IntoIter: 'a,
+{
+    body.into_iter().flat_map(move |(wasm_offset, op)| {
+        let inlined = match &op {
+            Operator::Call { function_index } => inline_bodies.get(function_index),
+            _ => None,
+        };
+        match inlined {
+            Some(ops) => ops
+                .iter()
+                .cloned()
+                .map(|op| (wasm_offset, op))
+                .collect::<Vec<_>>(),
+            None => vec![(wasm_offset, op)],
+        }
+    })
+}
@@ -6,7 +6,7 @@ extern crate smallvec;
 extern crate capstone;
 extern crate either;
 extern crate failure;
-pub extern crate wasmparser;
+extern crate wasmparser;
 #[macro_use]
 extern crate failure_derive;
 #[macro_use]
@@ -14,28 +14,91 @@ extern crate memoffset;
 extern crate dynasm;
 extern crate dynasmrt;
 extern crate itertools;
-#[cfg(test)]
+extern crate libc;
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate lazy_static;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+#[cfg(any(test, feature = "wat"))]
 extern crate wabt;
 // Just so we can implement `Signature` for `cranelift_codegen::ir::Signature`
 extern crate cranelift_codegen;
 extern crate multi_mut;
 
+#[macro_use]
+mod logging;
+
 mod backend;
+mod branch_hints;
+mod breakpoints;
+mod call_graph;
+mod code_buffer;
+mod coverage;
+mod devirtualize;
 mod disassemble;
+mod emitter;
 mod error;
 mod function_body;
+mod index_space;
+mod inline;
+mod linear_memory;
+mod memory64;
+mod metrics;
 mod microwasm;
 mod module;
+mod peephole;
+mod profiling;
+mod signatures;
+mod tiering;
+mod trace_hooks;
 mod translate_sections;
+mod unwind;
+mod vmctx;
+#[cfg(feature = "wat")]
+mod wat;
+
+#[cfg(feature = "capi")]
+pub mod capi;
 
 #[cfg(test)]
 mod tests;
 
-pub use crate::backend::CodeGenSession;
+pub use crate::backend::{
+    CallLinkage, CodeGenOptions, CodeGenSession, CodeLayout, CodeSizeLimits, Context, FramePointer,
+    FunctionStats, OperatorRange, TranslatedCodeSection, TrapSite, ARITHMETIC_OVERFLOW,
+    MAX_CODE_SIZE,
+};
+pub use crate::branch_hints::FunctionBranchHints;
+pub use crate::breakpoints::{
+    Breakpoint, BreakpointFrame, BreakpointHook, DebugLocation, DebugOptions, FlagCondition,
+};
+pub use crate::call_graph::CallGraph;
+pub use crate::coverage::{Coverage, CoverageGuards, TracePcGuard, TracePcGuardInit};
+pub use crate::emitter::Emitter;
+pub use crate::error::Error;
 pub use crate::function_body::translate_wasm as translate_function;
-pub use crate::module::{translate, ExecutableModule, ModuleContext, Signature, TranslatedModule};
+pub use crate::index_space::{
+    DefinedFuncIndex, FuncIndex, FuncKind, ImportedFuncIndex, IndexVec, TrampolineIndex,
+};
+pub use crate::linear_memory::{MemoryGrowth, MemoryStyle};
+pub use crate::metrics::CompilationMetrics;
+pub use crate::microwasm::CostModel;
+pub use crate::module::{
+    translate, translate_only, translate_only_with, write_microwasm, BuiltinFunction,
+    CompiledModule, CustomSection, DataSegment, DataSegmentKind, ElementSegment,
+    ElementSegmentKind, ExecutionError, FuncType, GlobalType, HostError, HostFunc, HostFunctions,
+    HostGlobal, HostMemory, Instance, InstanceImports, IntoHostFunc, IntrinsicLowering, Intrinsics,
+    Libcalls, ModuleContext, OperatorLibcalls, RuntimeFunc, SegmentOffset, SigType, Signature,
+    SimpleContext, TranslateOptions, ValueType,
+};
+pub use crate::profiling::{OperatorClass, OperatorCounts};
+pub use crate::tiering::{HotnessCounters, OsrPoint, TierUp, Tiering, ENTRY_LOOP_INDEX};
+pub use crate::trace_hooks::{FunctionEntry, FunctionExit, LoopIteration, TraceHooks};
+pub use crate::unwind::{FunctionUnwind, UnwindRow};
+pub use crate::vmctx::{VmCtx, VmCtxLayout, VERSION as VMCTX_VERSION};
+#[cfg(feature = "wat")]
+pub use crate::wat::{read_module, translate_path, translate_str};
+pub use cranelift_codegen::ir::TrapCode;
@@ -0,0 +1,192 @@
+//! The bytes backing a linear memory, allocated according to how generated code keeps its
+//! accesses in bounds.
+
+use crate::module::BoxSlice;
+use std::{fmt, ptr, sync::Arc};
+
+/// How generated code keeps memory accesses in bounds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryStyle {
+    /// Compare every address against the memory's current length before accessing it.
+    BoundsChecked,
+    /// Surround the memory with enough inaccessible address space that no address a wasm
+    /// access can form reaches anything else, and rely on the resulting fault instead of a
+    /// compare and branch per access. Each memory reserves `GUARDED_RESERVATION` bytes of
+    /// address space, and out-of-bounds accesses fault like every other trap does.
+    GuardPages,
+}
+
+impl Default for MemoryStyle {
+    fn default() -> Self {
+        MemoryStyle::BoundsChecked
+    }
+}
+
+/// Accesses add a 32-bit address to the memory's base and a memory offset encoded as a
+/// signed 32-bit displacement, so they can reach 2 GiB below the base and 6 GiB above it.
+const GUARD_BELOW: usize = 2 << 30;
+pub const GUARDED_RESERVATION: usize = 8 << 30;
+
+/// Whether `memory.grow` may grow an instance's memory, on top of the maximum size that the
+/// module declares for it.
+#[derive(Clone)]
+pub enum MemoryGrowth {
+    /// Grow up to the declared maximum.
+    Allow,
+    /// Never grow.
+    Deny,
+    /// Grow to at most the given number of pages.
+    Limit(u32),
+    /// Ask the callback, which is given the current and requested sizes in pages. It's called
+    /// while wasm code is on the stack, so it mustn't call into the instance.
+    Callback(Arc<dyn Fn(u32, u32) -> bool + Send + Sync>),
+}
+
+impl Default for MemoryGrowth {
+    fn default() -> Self {
+        MemoryGrowth::Allow
+    }
+}
+
+impl fmt::Debug for MemoryGrowth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryGrowth::Allow => write!(f, "Allow"),
+            MemoryGrowth::Deny => write!(f, "Deny"),
+            MemoryGrowth::Limit(pages) => f.debug_tuple("Limit").field(pages).finish(),
+            MemoryGrowth::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+impl MemoryGrowth {
+    pub(crate) fn allows(&self, old_pages: u32, new_pages: u32) -> bool {
+        match self {
+            MemoryGrowth::Allow => true,
+            MemoryGrowth::Deny => false,
+            MemoryGrowth::Limit(max) => new_pages <= *max,
+            MemoryGrowth::Callback(callback) => callback(old_pages, new_pages),
+        }
+    }
+}
+
+pub enum LinearMemory {
+    Heap(BoxSlice<u8>),
+    Guarded(GuardedMemory),
+}
+
+impl LinearMemory {
+    /// Allocate `len` zeroed bytes in the given style, returning `None` if the memory or
+    /// address space can't be reserved.
+    pub fn new(style: MemoryStyle, len: usize) -> Option<Self> {
+        match style {
+            MemoryStyle::BoundsChecked => {
+                Some(LinearMemory::Heap(vec![0; len].into_boxed_slice().into()))
+            }
+            MemoryStyle::GuardPages => GuardedMemory::new(len).map(LinearMemory::Guarded),
+        }
+    }
+
+    pub fn style(&self) -> MemoryStyle {
+        match self {
+            LinearMemory::Heap(_) => MemoryStyle::BoundsChecked,
+            LinearMemory::Guarded(_) => MemoryStyle::GuardPages,
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        match self {
+            LinearMemory::Heap(mem) => mem.as_mut_ptr(),
+            LinearMemory::Guarded(mem) => mem.ptr,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            LinearMemory::Heap(mem) => mem.len(),
+            LinearMemory::Guarded(mem) => mem.len,
+        }
+    }
+
+    /// Grow the memory to `new_len` zeroed bytes, returning whether it could be. Heap memory
+    /// moves when it grows, so its address has to be read again afterwards.
+    pub fn grow(&mut self, new_len: usize) -> bool {
+        match self {
+            LinearMemory::Heap(mem) => {
+                let old = std::mem::replace(mem, BoxSlice::from(Vec::new().into_boxed_slice()));
+                let mut bytes = old.into_vec();
+                bytes.resize(new_len, 0);
+                *mem = bytes.into_boxed_slice().into();
+                true
+            }
+            LinearMemory::Guarded(mem) => mem.grow(new_len),
+        }
+    }
+}
+
+/// Memory at the start of a `GUARDED_RESERVATION`-byte region of address space, of which
+/// only the memory itself is accessible.
+pub struct GuardedMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Like a `Box<[u8]>`, this uniquely owns its memory.
+unsafe impl Send for GuardedMemory {}
+unsafe impl Sync for GuardedMemory {}
+
+impl GuardedMemory {
+    fn new(len: usize) -> Option<Self> {
+        if len > GUARDED_RESERVATION - GUARD_BELOW {
+            return None;
+        }
+
+        unsafe {
+            let raw = libc::mmap(
+                ptr::null_mut(),
+                GUARDED_RESERVATION,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            );
+            if raw == libc::MAP_FAILED {
+                return None;
+            }
+
+            let ptr = (raw as *mut u8).add(GUARD_BELOW);
+            if len > 0
+                && libc::mprotect(ptr as *mut _, len, libc::PROT_READ | libc::PROT_WRITE) != 0
+            {
+                libc::munmap(raw, GUARDED_RESERVATION);
+                return None;
+            }
+
+            Some(GuardedMemory { ptr, len })
+        }
+    }
+
+    /// Make more of the reservation accessible. The memory stays where it is.
+    fn grow(&mut self, new_len: usize) -> bool {
+        if new_len > GUARDED_RESERVATION - GUARD_BELOW {
+            return false;
+        }
+        if new_len > self.len {
+            let start = unsafe { self.ptr.add(self.len) };
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            if unsafe { libc::mprotect(start as *mut _, new_len - self.len, prot) } != 0 {
+                return false;
+            }
+        }
+        self.len = new_len;
+        true
+    }
+}
+
+impl Drop for GuardedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.sub(GUARD_BELOW) as *mut _, GUARDED_RESERVATION);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Logging for debugging the compiler itself, through the `log` crate.
+//!
+//! Translation logs every operator that it reads and emits, so these macros only log in
+//! debug builds. In release builds they compile to nothing, not even a check of the log
+//! level, and their arguments aren't evaluated.
+
+/// Log at `Debug` level in debug builds. Use this for events once per function or less.
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) {
+            debug!($($arg)*);
+        }
+    };
+}
+
+/// Log at `Trace` level in debug builds. Use this for events per operator or instruction.
+macro_rules! log_trace {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) {
+            trace!($($arg)*);
+        }
+    };
+}
+
+/// Whether `log_trace!` would log anything for the current module, for output that's
+/// expensive to produce.
+macro_rules! log_trace_enabled {
+    () => {
+        cfg!(debug_assertions) && log_enabled!(log::Level::Trace)
+    };
+}
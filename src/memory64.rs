@@ -0,0 +1,235 @@
+//! Validation of modules whose memory is indexed with `i64`, as in the memory64 proposal.
+//!
+//! `wasmparser` only knows about 32-bit memories, so rather than leave these modules
+//! unvalidated we lower them to a 32-bit module that's valid exactly when the original is, and
+//! validate that. An address is wrapped to `i32` right before each access, so the validator
+//! still checks that every address is an `i64`, and the `i32` that `memory.size` and
+//! `memory.grow` return is extended back to `i64`. Stores have their value under the address,
+//! so it's stashed in a local of its own type that's added to the function while the address
+//! is wrapped. Data segments at an `i64.const` offset are given an `i32.const` one.
+
+use crate::error::Error;
+use wasmparser::{
+    CodeSectionReader, DataKind, DataSectionReader, ModuleReader, Operator, SectionCode, Type,
+};
+
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_CONST: u8 = 0x41;
+const END: u8 = 0x0b;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_U: u8 = 0xad;
+
+/// The types of the scratch locals added to each function, in order.
+const SCRATCH_TYPES: [(u8, Type); 4] = [
+    (0x7f, Type::I32),
+    (0x7e, Type::I64),
+    (0x7d, Type::F32),
+    (0x7c, Type::F64),
+];
+
+/// Rewrite `data`, a module with 64-bit memories, into one with 32-bit memories that
+/// `wasmparser` can validate in its place. Sections other than the code and data sections
+/// are copied as they are.
+pub fn lower(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut reader = ModuleReader::new(data)?;
+    let mut out = data[..reader.current_position()].to_vec();
+    let mut types = Vec::new();
+    let mut func_types = Vec::new();
+
+    while !reader.eof() {
+        let header_start = reader.current_position();
+        let section = reader.read()?;
+        let range = section.range();
+
+        match section.code {
+            SectionCode::Type => {
+                for ty in section.get_type_section_reader()? {
+                    types.push(ty?.params.len() as u32);
+                }
+            }
+            SectionCode::Function => {
+                for ty in section.get_function_section_reader()? {
+                    func_types.push(ty?);
+                }
+            }
+            SectionCode::Code => {
+                let code = section.get_code_section_reader()?;
+                let params = func_types
+                    .iter()
+                    .map(|&ty| types.get(ty as usize).cloned().unwrap_or(0));
+                let contents = lower_code(data, code, params)?;
+                push_section(&mut out, data[header_start], &contents);
+                continue;
+            }
+            SectionCode::Data => {
+                let contents = lower_data(data, section.get_data_section_reader()?)?;
+                push_section(&mut out, data[header_start], &contents);
+                continue;
+            }
+            _ => {}
+        }
+
+        out.extend_from_slice(&data[header_start..range.end]);
+    }
+
+    Ok(out)
+}
+
+/// Lower the bodies in the code section, where `params` is how many parameters each
+/// function has, in order.
+fn lower_code(
+    data: &[u8],
+    mut code: CodeSectionReader,
+    mut params: impl Iterator<Item = u32>,
+) -> Result<Vec<u8>, Error> {
+    let mut contents = Vec::new();
+    write_var_u32(&mut contents, code.get_count());
+
+    for _ in 0..code.get_count() {
+        let body = code.read()?;
+        let mut locals = body.get_locals_reader()?;
+        let mut lowered = Vec::new();
+        let mut scratch = params.next().unwrap_or(0);
+
+        write_var_u32(
+            &mut lowered,
+            locals
+                .get_count()
+                .saturating_add(SCRATCH_TYPES.len() as u32),
+        );
+        let locals_start = locals.original_position();
+        for _ in 0..locals.get_count() {
+            scratch = scratch.saturating_add(locals.read()?.0);
+        }
+        lowered.extend_from_slice(&data[locals_start..locals.original_position()]);
+        for &(ty, _) in &SCRATCH_TYPES {
+            write_var_u32(&mut lowered, 1);
+            lowered.push(ty);
+        }
+        let scratch_local = |ty: Type| {
+            let offset = SCRATCH_TYPES.iter().position(|&(_, t)| t == ty).unwrap();
+            scratch.saturating_add(offset as u32)
+        };
+
+        let mut ops = body.get_operators_reader()?;
+        while !ops.eof() {
+            let (op, start) = ops.read_with_offset()?;
+            let raw = &data[start..ops.original_position()];
+
+            let stored = match op {
+                Operator::I32Store { .. }
+                | Operator::I32Store8 { .. }
+                | Operator::I32Store16 { .. } => Some(Type::I32),
+                Operator::I64Store { .. }
+                | Operator::I64Store8 { .. }
+                | Operator::I64Store16 { .. }
+                | Operator::I64Store32 { .. } => Some(Type::I64),
+                Operator::F32Store { .. } => Some(Type::F32),
+                Operator::F64Store { .. } => Some(Type::F64),
+                _ => None,
+            };
+
+            if let Some(ty) = stored {
+                let local = scratch_local(ty);
+                lowered.push(LOCAL_SET);
+                write_var_u32(&mut lowered, local);
+                lowered.push(I32_WRAP_I64);
+                lowered.push(LOCAL_GET);
+                write_var_u32(&mut lowered, local);
+                lowered.extend_from_slice(raw);
+                continue;
+            }
+
+            match op {
+                Operator::I32Load { .. }
+                | Operator::I32Load8S { .. }
+                | Operator::I32Load8U { .. }
+                | Operator::I32Load16S { .. }
+                | Operator::I32Load16U { .. }
+                | Operator::I64Load { .. }
+                | Operator::I64Load8S { .. }
+                | Operator::I64Load8U { .. }
+                | Operator::I64Load16S { .. }
+                | Operator::I64Load16U { .. }
+                | Operator::I64Load32S { .. }
+                | Operator::I64Load32U { .. }
+                | Operator::F32Load { .. }
+                | Operator::F64Load { .. } => {
+                    lowered.push(I32_WRAP_I64);
+                    lowered.extend_from_slice(raw);
+                }
+                Operator::MemorySize { .. } => {
+                    lowered.extend_from_slice(raw);
+                    lowered.push(I64_EXTEND_I32_U);
+                }
+                Operator::MemoryGrow { .. } => {
+                    lowered.push(I32_WRAP_I64);
+                    lowered.extend_from_slice(raw);
+                    lowered.push(I64_EXTEND_I32_U);
+                }
+                _ => lowered.extend_from_slice(raw),
+            }
+        }
+
+        write_var_u32(&mut contents, lowered.len() as u32);
+        contents.extend_from_slice(&lowered);
+    }
+
+    Ok(contents)
+}
+
+/// Lower the offsets of the active segments in the data section.
+fn lower_data(data: &[u8], mut segments: DataSectionReader) -> Result<Vec<u8>, Error> {
+    let mut contents = Vec::new();
+    write_var_u32(&mut contents, segments.get_count());
+
+    for _ in 0..segments.get_count() {
+        let start = segments.original_position();
+        let segment = segments.read()?;
+        let end = segments.original_position();
+
+        let init_expr = match segment.kind {
+            DataKind::Active { init_expr, .. } => init_expr,
+            DataKind::Passive => {
+                contents.extend_from_slice(&data[start..end]);
+                continue;
+            }
+        };
+
+        let mut ops = init_expr.get_operators_reader();
+        let expr_start = ops.original_position();
+        let is_i64_const = match (ops.read()?, ops.read()?) {
+            (Operator::I64Const { .. }, Operator::End) => true,
+            _ => false,
+        };
+
+        if is_i64_const {
+            contents.extend_from_slice(&data[start..expr_start]);
+            contents.extend_from_slice(&[I32_CONST, 0, END]);
+            contents.extend_from_slice(&data[ops.original_position()..end]);
+        } else {
+            contents.extend_from_slice(&data[start..end]);
+        }
+    }
+
+    Ok(contents)
+}
+
+fn push_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    write_var_u32(out, contents.len() as u32);
+    out.extend_from_slice(contents);
+}
+
+fn write_var_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
@@ -0,0 +1,20 @@
+//! Telemetry about translation, for embedders tracking how compile time trades off against
+//! the quality of the generated code.
+
+use crate::backend::FunctionStats;
+use crate::index_space::DefinedFuncIndex;
+use std::{fmt, time::Duration};
+
+/// Receives measurements of each function as it's translated. Register an implementation
+/// with `TranslateOptions::metrics` or `CodeGenSession::set_metrics`.
+pub trait CompilationMetrics: Send + Sync {
+    /// Called once `func` has been translated, with the time that took, including converting
+    /// it to microwasm, and statistics about the code that was generated.
+    fn function_translated(&self, func: DefinedFuncIndex, time: Duration, stats: &FunctionStats);
+}
+
+impl fmt::Debug for dyn CompilationMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CompilationMetrics")
+    }
+}
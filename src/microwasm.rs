@@ -1,6 +1,7 @@
 use crate::module::{ModuleContext, SigType, Signature};
 use smallvec::SmallVec;
 use std::{
+    collections::{HashMap, HashSet},
     convert::TryInto,
     fmt,
     iter::{self, FromIterator},
@@ -22,20 +23,31 @@ where
 {
     writeln!(out, ".fn_{}:", function_name)?;
 
-    let p = "      ";
     for op in microwasm {
-        if op.is_label() {
-            writeln!(out, "{}", op)?;
-        } else if op.is_block() {
-            writeln!(out, "{}", op)?;
-        } else {
-            writeln!(out, "{}{}", p, op)?;
-        }
+        writeln!(out, "{}", DisOp(&op))?;
     }
 
     Ok(())
 }
 
+/// Formats a single operator the way that `dis` does.
+pub struct DisOp<'a, L>(pub &'a Operator<L>);
+
+impl<L> fmt::Display for DisOp<'_, L>
+where
+    BrTarget<L>: fmt::Display,
+    L: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = "      ";
+        if self.0.is_label() || self.0.is_block() {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}{}", p, self.0)
+        }
+    }
+}
+
 /// A constant value embedded in the instructions
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Value {
@@ -107,7 +119,7 @@ impl Value {
         }
     }
 
-    fn default_for_type(ty: SignlessType) -> Self {
+    pub fn default_for_type(ty: SignlessType) -> Self {
         match ty {
             Type::Int(Size::_32) => Value::I32(0),
             Type::Int(Size::_64) => Value::I64(0),
@@ -284,6 +296,8 @@ impl SignlessType {
             Type::I64 => Some(I64),
             Type::F32 => Some(F32),
             Type::F64 => Some(F64),
+            // References are pointers, with null as 0
+            Type::AnyRef | Type::AnyFunc => Some(I64),
             Type::EmptyBlockType => None,
             _ => unimplemented!(),
         }
@@ -423,6 +437,10 @@ impl From<WasmIeee64> for Ieee64 {
 pub struct MemoryImmediate {
     pub flags: u32,
     pub offset: u32,
+    pub memory_index: u32,
+    /// An earlier access in the same straight-line code has already checked that this one
+    /// is in bounds, so it doesn't have to be checked again.
+    pub bounds_checked: bool,
 }
 
 impl From<WasmMemoryImmediate> for MemoryImmediate {
@@ -430,6 +448,9 @@ impl From<WasmMemoryImmediate> for MemoryImmediate {
         MemoryImmediate {
             flags: other.flags,
             offset: other.offset,
+            // Only multi-memory modules can access any memory other than the first
+            memory_index: 0,
+            bounds_checked: false,
         }
     }
 }
@@ -476,6 +497,14 @@ pub enum Operator<Label> {
     Call {
         function_index: u32,
     },
+    /// Call the function that the embedder registered to implement the wasm operator `name`,
+    /// with the `VmCtx` and the operator's operands, and push its result. See
+    /// `ModuleContext::operator_libcall`.
+    Libcall {
+        name: &'static str,
+        params: Vec<SignlessType>,
+        returns: Vec<SignlessType>,
+    },
     /// Pop an `i32` off the top of the stack, index into the table at `table_index` and call that function
     CallIndirect {
         type_index: u32,
@@ -540,6 +569,18 @@ pub enum Operator<Label> {
     MemoryGrow {
         reserved: u32,
     },
+    TableGet {
+        table_index: u32,
+    },
+    TableSet {
+        table_index: u32,
+    },
+    TableGrow {
+        table_index: u32,
+    },
+    TableSize {
+        table_index: u32,
+    },
     Const(Value),
     Eq(SignlessType),
     Ne(SignlessType),
@@ -642,6 +683,66 @@ impl<L> Operator<L> {
     }
 }
 
+/// How much running each kind of operator costs, in arbitrary units, for embedders that
+/// estimate how much work a function does before running it. Operators that aren't in any
+/// other class cost `default`, and `Block` and `Label` cost nothing, since they only mark
+/// where blocks start and don't run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    pub default: u32,
+    /// Every load from linear memory, whatever its width.
+    pub load: u32,
+    /// Every store to linear memory, whatever its width.
+    pub store: u32,
+    /// `br`, `br_if` and `br_table`.
+    pub branch: u32,
+    /// `call` and calls to libcalls.
+    pub call: u32,
+    pub call_indirect: u32,
+    /// Integer and float `div` and integer `rem`.
+    pub div: u32,
+    /// `memory.grow` and `table.grow`, which call into the runtime.
+    pub grow: u32,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            default: 1,
+            load: 3,
+            store: 3,
+            branch: 2,
+            call: 10,
+            call_indirect: 15,
+            div: 10,
+            grow: 100,
+        }
+    }
+}
+
+impl CostModel {
+    /// The cost of `op` in this model.
+    pub fn cost<L>(&self, op: &Operator<L>) -> u32 {
+        match op {
+            Operator::Block { .. } | Operator::Label(..) => 0,
+            Operator::Load { .. }
+            | Operator::Load8 { .. }
+            | Operator::Load16 { .. }
+            | Operator::Load32 { .. } => self.load,
+            Operator::Store { .. }
+            | Operator::Store8 { .. }
+            | Operator::Store16 { .. }
+            | Operator::Store32 { .. } => self.store,
+            Operator::Br { .. } | Operator::BrIf { .. } | Operator::BrTable(..) => self.branch,
+            Operator::Call { .. } | Operator::Libcall { .. } => self.call,
+            Operator::CallIndirect { .. } => self.call_indirect,
+            Operator::Div(..) | Operator::Rem(..) => self.div,
+            Operator::MemoryGrow { .. } | Operator::TableGrow { .. } => self.grow,
+            _ => self.default,
+        }
+    }
+}
+
 impl<L> fmt::Display for Operator<L>
 where
     BrTarget<L>: fmt::Display,
@@ -692,6 +793,7 @@ where
                 write!(f, "], {}", default)
             }
             Operator::Call { function_index } => write!(f, "call {}", function_index),
+            Operator::Libcall { name, .. } => write!(f, "libcall {}", name),
             Operator::CallIndirect { .. } => write!(f, "call_indirect"),
             Operator::Drop(range) => {
                 write!(f, "drop")?;
@@ -749,6 +851,10 @@ where
             }
             Operator::MemorySize { .. } => write!(f, "memory.size"),
             Operator::MemoryGrow { .. } => write!(f, "memory.grow"),
+            Operator::TableGet { table_index } => write!(f, "table.get {}", table_index),
+            Operator::TableSet { table_index } => write!(f, "table.set {}", table_index),
+            Operator::TableGrow { table_index } => write!(f, "table.grow {}", table_index),
+            Operator::TableSize { table_index } => write!(f, "table.size {}", table_index),
             Operator::Const(val) => write!(f, "const {}", val),
             Operator::Eq(ty) => write!(f, "{}.eq", ty),
             Operator::Ne(ty) => write!(f, "{}.ne", ty),
@@ -965,13 +1071,390 @@ impl ControlFrame {
     }
 }
 
+/// What to do with an operator that only exists to store a value to a local that's overwritten
+/// before it's read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DeadStore {
+    /// Skip the operator entirely: it's the store itself, or it has no side effects and is part
+    /// of computing the stored value.
+    Skip,
+    /// The stored value must still be computed since computing it has side effects (it may
+    /// trap, for example), but it can be dropped instead of stored.
+    Drop,
+}
+
+/// The number of values an operator pops and pushes, if it has no side effects and can't trap.
+fn pure_op_arity(op: &WasmOperator) -> Option<(u32, u32)> {
+    use self::WasmOperator::*;
+
+    match op {
+        I32Const { .. }
+        | I64Const { .. }
+        | F32Const { .. }
+        | F64Const { .. }
+        | GetLocal { .. }
+        | GetGlobal { .. } => Some((0, 1)),
+        I32Eqz | I32Clz | I32Ctz | I32Popcnt | I64Eqz | I64Clz | I64Ctz | I64Popcnt | F32Abs
+        | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg
+        | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt | I32WrapI64 | I64ExtendSI32
+        | I64ExtendUI32 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64
+        | F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64
+        | F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32
+        | F64ReinterpretI64 => Some((1, 1)),
+        I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+        | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU
+        | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne | F64Lt | F64Gt | F64Le
+        | F64Ge | I32Add | I32Sub | I32Mul | I32And | I32Or | I32Xor | I32Shl | I32ShrS
+        | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub | I64Mul | I64And | I64Or | I64Xor
+        | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div
+        | F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max
+        | F64Copysign => Some((2, 1)),
+        Select => Some((3, 1)),
+        _ => None,
+    }
+}
+
+/// Find stores to locals that are overwritten before they're read, keyed by the index of each
+/// affected operator in the body. We only look within straight-line code, which is enough for
+/// the unoptimized code that a baseline compiler mostly sees, and all we need to know there is
+/// whether there's a `get_local` between two stores.
+fn dead_local_stores(body: &FunctionBody) -> wasmparser::Result<HashMap<usize, DeadStore>> {
+    let ops = body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<_>>>()?;
+    let mut dead = HashMap::new();
+    let mut last_stores = HashMap::<u32, usize>::new();
+
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            WasmOperator::GetLocal { local_index } => {
+                last_stores.remove(&local_index);
+            }
+            WasmOperator::SetLocal { local_index } | WasmOperator::TeeLocal { local_index } => {
+                let store = match last_stores.insert(local_index, i) {
+                    Some(store) => store,
+                    None => continue,
+                };
+
+                // A dead `tee_local` still leaves its value on the stack, so it's just a no-op.
+                if let WasmOperator::TeeLocal { .. } = ops[store] {
+                    dead.insert(store, DeadStore::Skip);
+                    continue;
+                }
+
+                // Walk back over the operators that compute the stored value. Every pure
+                // operator pushes one value, so `needed` can't underflow.
+                let mut needed = 1;
+                let mut start = store;
+                while needed > 0 {
+                    let (pops, pushes) =
+                        match start.checked_sub(1).and_then(|i| pure_op_arity(&ops[i])) {
+                            Some(arity) => arity,
+                            None => break,
+                        };
+                    start -= 1;
+                    needed = needed + pops - pushes;
+                }
+
+                if needed == 0 {
+                    for op_index in start..=store {
+                        dead.insert(op_index, DeadStore::Skip);
+                    }
+                } else {
+                    dead.insert(store, DeadStore::Drop);
+                }
+            }
+            WasmOperator::Block { .. }
+            | WasmOperator::Loop { .. }
+            | WasmOperator::If { .. }
+            | WasmOperator::Else
+            | WasmOperator::End
+            | WasmOperator::Br { .. }
+            | WasmOperator::BrIf { .. }
+            | WasmOperator::BrTable { .. }
+            | WasmOperator::Return
+            | WasmOperator::Unreachable => last_stores.clear(),
+            _ => {}
+        }
+    }
+
+    Ok(dead)
+}
+
+/// The offset and size of a memory access, and how many of its operands are above the
+/// address on the stack.
+fn memory_access(op: &WasmOperator) -> Option<(u32, u32, u32)> {
+    use self::WasmOperator::*;
+
+    match op {
+        I32Load8S { memarg }
+        | I32Load8U { memarg }
+        | I64Load8S { memarg }
+        | I64Load8U { memarg } => Some((memarg.offset, 1, 0)),
+        I32Load16S { memarg }
+        | I32Load16U { memarg }
+        | I64Load16S { memarg }
+        | I64Load16U { memarg } => Some((memarg.offset, 2, 0)),
+        I32Load { memarg } | F32Load { memarg } | I64Load32S { memarg } | I64Load32U { memarg } => {
+            Some((memarg.offset, 4, 0))
+        }
+        I64Load { memarg } | F64Load { memarg } => Some((memarg.offset, 8, 0)),
+        I32Store8 { memarg } | I64Store8 { memarg } => Some((memarg.offset, 1, 1)),
+        I32Store16 { memarg } | I64Store16 { memarg } => Some((memarg.offset, 2, 1)),
+        I32Store { memarg } | F32Store { memarg } | I64Store32 { memarg } => {
+            Some((memarg.offset, 4, 1))
+        }
+        I64Store { memarg } | F64Store { memarg } => Some((memarg.offset, 8, 1)),
+        _ => None,
+    }
+}
+
+/// The local that the address of an access right after `ops` is read straight from, if it
+/// is, given how many of the access's operands are above the address.
+fn access_base(ops: &[WasmOperator], above: u32) -> Option<u32> {
+    // Walk back over the operators that compute the operands above the address, as in
+    // `dead_local_stores`
+    let mut needed = above;
+    let mut start = ops.len();
+    while needed > 0 {
+        start = start.checked_sub(1)?;
+        let (pops, pushes) = pure_op_arity(&ops[start])?;
+        needed = needed + pops - pushes;
+    }
+
+    match ops.get(start.checked_sub(1)?)? {
+        WasmOperator::GetLocal { local_index } => Some(*local_index),
+        _ => None,
+    }
+}
+
+/// Find memory accesses whose bounds checks are redundant, by the index of each in the body.
+/// A check traps unless the accessed address is below the memory's length and memories never
+/// shrink, so an access at a local plus an offset doesn't have to be checked if every byte it
+/// touches is below an address at the same local that's already been checked. Like
+/// `dead_local_stores` this only looks within straight-line code, where accesses to the fields
+/// of a struct at the same base are common.
+fn redundant_bounds_checks(body: &FunctionBody) -> wasmparser::Result<HashSet<usize>> {
+    let ops = body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<_>>>()?;
+    let mut redundant = HashSet::new();
+    // The biggest offset that's been checked from each local
+    let mut checked = HashMap::<u32, u32>::new();
+
+    for (i, op) in ops.iter().enumerate() {
+        if let Some((offset, size, above)) = memory_access(op) {
+            let local_index = match access_base(&ops[..i], above) {
+                Some(local_index) => local_index,
+                None => continue,
+            };
+
+            let last_byte = u64::from(offset) + u64::from(size) - 1;
+            match checked.get(&local_index) {
+                Some(&checked_offset) if last_byte <= u64::from(checked_offset) => {
+                    redundant.insert(i);
+                }
+                _ => {
+                    let checked_offset = checked.entry(local_index).or_insert(offset);
+                    *checked_offset = offset.max(*checked_offset);
+                }
+            }
+
+            continue;
+        }
+
+        match *op {
+            WasmOperator::SetLocal { local_index } | WasmOperator::TeeLocal { local_index } => {
+                checked.remove(&local_index);
+            }
+            WasmOperator::Block { .. }
+            | WasmOperator::Loop { .. }
+            | WasmOperator::If { .. }
+            | WasmOperator::Else
+            | WasmOperator::End
+            | WasmOperator::Br { .. }
+            | WasmOperator::BrIf { .. }
+            | WasmOperator::BrTable { .. }
+            | WasmOperator::Return
+            | WasmOperator::Unreachable => checked.clear(),
+            _ => {}
+        }
+    }
+
+    Ok(redundant)
+}
+
+/// The name in the text format of a numeric operator, which the embedder can implement with
+/// a libcall. These are all the operators without immediates or side effects other than
+/// trapping.
+fn libcall_name(op: &WasmOperator) -> Option<&'static str> {
+    macro_rules! names {
+        ($($op:ident => $name:expr,)*) => {
+            match op {
+                $(WasmOperator::$op => Some($name),)*
+                _ => None,
+            }
+        };
+    }
+
+    names! {
+        I32Eqz => "i32.eqz",
+        I32Eq => "i32.eq",
+        I32Ne => "i32.ne",
+        I32LtS => "i32.lt_s",
+        I32LtU => "i32.lt_u",
+        I32GtS => "i32.gt_s",
+        I32GtU => "i32.gt_u",
+        I32LeS => "i32.le_s",
+        I32LeU => "i32.le_u",
+        I32GeS => "i32.ge_s",
+        I32GeU => "i32.ge_u",
+        I64Eqz => "i64.eqz",
+        I64Eq => "i64.eq",
+        I64Ne => "i64.ne",
+        I64LtS => "i64.lt_s",
+        I64LtU => "i64.lt_u",
+        I64GtS => "i64.gt_s",
+        I64GtU => "i64.gt_u",
+        I64LeS => "i64.le_s",
+        I64LeU => "i64.le_u",
+        I64GeS => "i64.ge_s",
+        I64GeU => "i64.ge_u",
+        F32Eq => "f32.eq",
+        F32Ne => "f32.ne",
+        F32Lt => "f32.lt",
+        F32Gt => "f32.gt",
+        F32Le => "f32.le",
+        F32Ge => "f32.ge",
+        F64Eq => "f64.eq",
+        F64Ne => "f64.ne",
+        F64Lt => "f64.lt",
+        F64Gt => "f64.gt",
+        F64Le => "f64.le",
+        F64Ge => "f64.ge",
+        I32Clz => "i32.clz",
+        I32Ctz => "i32.ctz",
+        I32Popcnt => "i32.popcnt",
+        I32Add => "i32.add",
+        I32Sub => "i32.sub",
+        I32Mul => "i32.mul",
+        I32DivS => "i32.div_s",
+        I32DivU => "i32.div_u",
+        I32RemS => "i32.rem_s",
+        I32RemU => "i32.rem_u",
+        I32And => "i32.and",
+        I32Or => "i32.or",
+        I32Xor => "i32.xor",
+        I32Shl => "i32.shl",
+        I32ShrS => "i32.shr_s",
+        I32ShrU => "i32.shr_u",
+        I32Rotl => "i32.rotl",
+        I32Rotr => "i32.rotr",
+        I64Clz => "i64.clz",
+        I64Ctz => "i64.ctz",
+        I64Popcnt => "i64.popcnt",
+        I64Add => "i64.add",
+        I64Sub => "i64.sub",
+        I64Mul => "i64.mul",
+        I64DivS => "i64.div_s",
+        I64DivU => "i64.div_u",
+        I64RemS => "i64.rem_s",
+        I64RemU => "i64.rem_u",
+        I64And => "i64.and",
+        I64Or => "i64.or",
+        I64Xor => "i64.xor",
+        I64Shl => "i64.shl",
+        I64ShrS => "i64.shr_s",
+        I64ShrU => "i64.shr_u",
+        I64Rotl => "i64.rotl",
+        I64Rotr => "i64.rotr",
+        F32Abs => "f32.abs",
+        F32Neg => "f32.neg",
+        F32Ceil => "f32.ceil",
+        F32Floor => "f32.floor",
+        F32Trunc => "f32.trunc",
+        F32Nearest => "f32.nearest",
+        F32Sqrt => "f32.sqrt",
+        F32Add => "f32.add",
+        F32Sub => "f32.sub",
+        F32Mul => "f32.mul",
+        F32Div => "f32.div",
+        F32Min => "f32.min",
+        F32Max => "f32.max",
+        F32Copysign => "f32.copysign",
+        F64Abs => "f64.abs",
+        F64Neg => "f64.neg",
+        F64Ceil => "f64.ceil",
+        F64Floor => "f64.floor",
+        F64Trunc => "f64.trunc",
+        F64Nearest => "f64.nearest",
+        F64Sqrt => "f64.sqrt",
+        F64Add => "f64.add",
+        F64Sub => "f64.sub",
+        F64Mul => "f64.mul",
+        F64Div => "f64.div",
+        F64Min => "f64.min",
+        F64Max => "f64.max",
+        F64Copysign => "f64.copysign",
+        I32WrapI64 => "i32.wrap_i64",
+        I32TruncSF32 => "i32.trunc_f32_s",
+        I32TruncUF32 => "i32.trunc_f32_u",
+        I32TruncSF64 => "i32.trunc_f64_s",
+        I32TruncUF64 => "i32.trunc_f64_u",
+        I64ExtendSI32 => "i64.extend_i32_s",
+        I64ExtendUI32 => "i64.extend_i32_u",
+        I64TruncSF32 => "i64.trunc_f32_s",
+        I64TruncUF32 => "i64.trunc_f32_u",
+        I64TruncSF64 => "i64.trunc_f64_s",
+        I64TruncUF64 => "i64.trunc_f64_u",
+        F32ConvertSI32 => "f32.convert_i32_s",
+        F32ConvertUI32 => "f32.convert_i32_u",
+        F32ConvertSI64 => "f32.convert_i64_s",
+        F32ConvertUI64 => "f32.convert_i64_u",
+        F32DemoteF64 => "f32.demote_f64",
+        F64ConvertSI32 => "f64.convert_i32_s",
+        F64ConvertUI32 => "f64.convert_i32_u",
+        F64ConvertSI64 => "f64.convert_i64_s",
+        F64ConvertUI64 => "f64.convert_i64_u",
+        F64PromoteF32 => "f64.promote_f32",
+        I32ReinterpretF32 => "i32.reinterpret_f32",
+        I64ReinterpretF64 => "i64.reinterpret_f64",
+        F32ReinterpretI32 => "f32.reinterpret_i32",
+        F64ReinterpretI64 => "f64.reinterpret_i64",
+        I32Extend8S => "i32.extend8_s",
+        I32Extend16S => "i32.extend16_s",
+        I64Extend8S => "i64.extend8_s",
+        I64Extend16S => "i64.extend16_s",
+        I64Extend32S => "i64.extend32_s",
+        I32TruncSSatF32 => "i32.trunc_sat_f32_s",
+        I32TruncUSatF32 => "i32.trunc_sat_f32_u",
+        I32TruncSSatF64 => "i32.trunc_sat_f64_s",
+        I32TruncUSatF64 => "i32.trunc_sat_f64_u",
+        I64TruncSSatF32 => "i64.trunc_sat_f32_s",
+        I64TruncUSatF32 => "i64.trunc_sat_f32_u",
+        I64TruncSSatF64 => "i64.trunc_sat_f64_s",
+        I64TruncUSatF64 => "i64.trunc_sat_f64_u",
+    }
+}
+
 pub struct MicrowasmConv<'a, 'b, M> {
     // TODO: Maybe have a `ConvInner` type and have this wrap an `Option` so that
     //       we can dealloc everything when we've finished emitting
     is_done: bool,
     consts_to_emit: Option<Vec<Value>>,
     stack: Vec<SignlessType>,
+    /// The most values that `stack` has held so far.
+    max_stack_height: usize,
     internal: OperatorsReader<'a>,
+    /// The index of the next operator in `internal`.
+    op_index: usize,
+    /// Where the last operator read from `internal` is in the module.
+    wasm_offset: Option<usize>,
+    dead_stores: HashMap<usize, DeadStore>,
+    /// The memory accesses that don't have to be bounds-checked, by index in `internal`.
+    redundant_bounds_checks: HashSet<usize>,
     module: &'b M,
     current_id: u32,
     control_frames: Vec<ControlFrame>,
@@ -1064,12 +1547,18 @@ where
 
         let mut out = Self {
             is_done: false,
+            max_stack_height: locals.len(),
             stack: locals,
             module: context,
             consts_to_emit: Some(consts),
             internal: reader
                 .get_operators_reader()
                 .expect("Failed to get operators reader"),
+            op_index: 0,
+            wasm_offset: None,
+            dead_stores: dead_local_stores(reader).expect("Failed to read operators"),
+            redundant_bounds_checks: redundant_bounds_checks(reader)
+                .expect("Failed to read operators"),
             current_id: 0,
             control_frames: vec![],
             unreachable: false,
@@ -1086,6 +1575,40 @@ where
         out
     }
 
+    /// The offset in the module of the wasm operator that the last microwasm returned was
+    /// translated from, or `None` if it wasn't translated from any operator.
+    pub fn wasm_offset(&self) -> Option<usize> {
+        self.wasm_offset
+    }
+
+    /// The most values, locals included, that have been live at once in what's been
+    /// converted so far. Once the whole body has been converted this is how many stack slots
+    /// are enough to spill every value that the function has.
+    pub fn max_stack_height(&self) -> usize {
+        self.max_stack_height
+    }
+
+    fn note_stack_height(&mut self) {
+        self.max_stack_height = self.max_stack_height.max(self.stack.len());
+    }
+
+    fn read_op(&mut self) -> wasmparser::Result<WasmOperator<'a>> {
+        let offset = self.internal.original_position();
+        self.wasm_offset = Some(offset);
+        let op = self.internal.read()?;
+        self.op_index += 1;
+        log_trace!("Wasm @{:#x}: {:?} (stack {:?})", offset, op, self.stack);
+        Ok(op)
+    }
+
+    /// The immediate of the memory access at `op_index`.
+    fn memarg(&self, op_index: usize, memarg: WasmMemoryImmediate) -> MemoryImmediate {
+        MemoryImmediate {
+            bounds_checked: self.redundant_bounds_checks.contains(&op_index),
+            ..memarg.into()
+        }
+    }
+
     fn op_sig(&self, op: &WasmOperator) -> OpSig {
         use self::SigT::T;
         use std::iter::{empty as none, once};
@@ -1117,6 +1640,9 @@ where
             once(a.into()).chain(once(b.into())).chain(once(c.into()))
         }
 
+        // Loads and stores only ever access memory 0
+        let index = self.module.memory_index_type(0);
+
         macro_rules! sig {
             (@iter $a:expr, $b:expr, $c:expr) => { three($a, $b, $c) };
             (@iter $a:expr, $b:expr) => { two($a, $b) };
@@ -1177,14 +1703,14 @@ where
             WasmOperator::GetGlobal { global_index } => sig!(() -> (self.module.global_type(*global_index).to_microwasm_type())),
             WasmOperator::SetGlobal { global_index } => sig!((self.module.global_type(*global_index).to_microwasm_type()) -> ()),
 
-            WasmOperator::F32Load { .. } => sig!((I32) -> (F32)),
-            WasmOperator::F64Load { .. } => sig!((I32) -> (F64)),
+            WasmOperator::F32Load { .. } => sig!((index) -> (F32)),
+            WasmOperator::F64Load { .. } => sig!((index) -> (F64)),
 
             WasmOperator::I32Load { .. }
             | WasmOperator::I32Load8S { .. }
             | WasmOperator::I32Load8U { .. }
             | WasmOperator::I32Load16S { .. }
-            | WasmOperator::I32Load16U { .. } => sig!((I32) -> (I32)),
+            | WasmOperator::I32Load16U { .. } => sig!((index) -> (I32)),
 
             WasmOperator::I64Load { .. }
             | WasmOperator::I64Load8S { .. }
@@ -1192,28 +1718,39 @@ where
             | WasmOperator::I64Load16S { .. }
             | WasmOperator::I64Load16U { .. }
             | WasmOperator::I64Load32S { .. }
-            | WasmOperator::I64Load32U { .. } => sig!((I32) -> (I64)),
+            | WasmOperator::I64Load32U { .. } => sig!((index) -> (I64)),
 
-            WasmOperator::F32Store { .. } => sig!((I32, F32) -> ()),
-            WasmOperator::F64Store { .. } => sig!((I32, F64) -> ()),
+            WasmOperator::F32Store { .. } => sig!((index, F32) -> ()),
+            WasmOperator::F64Store { .. } => sig!((index, F64) -> ()),
             WasmOperator::I32Store { .. }
             | WasmOperator::I32Store8 { .. }
-            | WasmOperator::I32Store16 { .. } => sig!((I32, I32) -> ()),
+            | WasmOperator::I32Store16 { .. } => sig!((index, I32) -> ()),
             WasmOperator::I64Store { .. }
             | WasmOperator::I64Store8 { .. }
             | WasmOperator::I64Store16 { .. }
-            | WasmOperator::I64Store32 { .. } => sig!((I32, I64) -> ()),
+            | WasmOperator::I64Store32 { .. } => sig!((index, I64) -> ()),
 
-            WasmOperator::MemorySize { .. } => sig!(() -> (I32)),
-            WasmOperator::MemoryGrow { .. } => sig!((I32) -> (I32)),
+            WasmOperator::MemorySize { reserved } => {
+                sig!(() -> (self.module.memory_index_type(*reserved)))
+            }
+            WasmOperator::MemoryGrow { reserved } => {
+                let index = self.module.memory_index_type(*reserved);
+
+                sig!((index) -> (index))
+            }
+
+            WasmOperator::TableGet { .. } => sig!((I32) -> (I64)),
+            WasmOperator::TableSet { .. } => sig!((I32, I64) -> ()),
+            WasmOperator::TableGrow { .. } => sig!((I32) -> (I32)),
+            WasmOperator::TableSize { .. } => sig!(() -> (I32)),
 
             WasmOperator::I32Const { .. } => sig!(() -> (I32)),
             WasmOperator::I64Const { .. } => sig!(() -> (I64)),
             WasmOperator::F32Const { .. } => sig!(() -> (F32)),
             WasmOperator::F64Const { .. } => sig!(() -> (F64)),
 
-            WasmOperator::RefNull => unimplemented!(),
-            WasmOperator::RefIsNull => unimplemented!(),
+            WasmOperator::RefNull => sig!(() -> (I64)),
+            WasmOperator::RefIsNull => sig!((I64) -> (I32)),
 
             // All comparison operators remove 2 elements and push 1
             WasmOperator::I32Eqz => sig!((I32) -> (I32)),
@@ -1340,9 +1877,14 @@ where
 
             WasmOperator::I32Extend8S => sig!((I32) -> (I32)),
             WasmOperator::I32Extend16S => sig!((I32) -> (I32)),
-            WasmOperator::I64Extend8S => sig!((I32) -> (I64)),
-            WasmOperator::I64Extend16S => sig!((I32) -> (I64)),
-            WasmOperator::I64Extend32S => sig!((I32) -> (I64)),
+            WasmOperator::I64Extend8S => sig!((I64) -> (I64)),
+            WasmOperator::I64Extend16S => sig!((I64) -> (I64)),
+            WasmOperator::I64Extend32S => sig!((I64) -> (I64)),
+
+            WasmOperator::I32TruncSSatF32 | WasmOperator::I32TruncUSatF32 => sig!((F32) -> (I32)),
+            WasmOperator::I32TruncSSatF64 | WasmOperator::I32TruncUSatF64 => sig!((F64) -> (I32)),
+            WasmOperator::I64TruncSSatF32 | WasmOperator::I64TruncUSatF32 => sig!((F32) -> (I64)),
+            WasmOperator::I64TruncSSatF64 | WasmOperator::I64TruncUSatF64 => sig!((F64) -> (I64)),
 
             _ => unimplemented!(),
         }
@@ -1401,6 +1943,8 @@ where
             };
             self.stack.push(ty);
         }
+
+        self.note_stack_height();
     }
 
     fn block_params(&self) -> Vec<SignlessType> {
@@ -1458,7 +2002,7 @@ where
             // very complicated so we just do basic code removal here and leave
             // the removal of uncalled blocks to the backend.
             return Some(Ok(loop {
-                let op = match self.internal.read() {
+                let op = match self.read_op() {
                     Err(e) => return Some(Err(e)),
                     Ok(o) => o,
                 };
@@ -1492,6 +2036,7 @@ where
 
                             self.stack.truncate(block.arguments as _);
                             self.stack.extend(block.returns);
+                            self.note_stack_height();
 
                             let end_label = (block.id, NameTag::End);
 
@@ -1518,13 +2063,43 @@ where
             }));
         }
 
-        let op = match self.internal.read() {
+        let op_index = self.op_index;
+        let op = match self.read_op() {
             Err(e) => return Some(Err(e)),
             Ok(o) => o,
         };
 
+        let op = match self.dead_stores.get(&op_index) {
+            Some(DeadStore::Skip) => return Some(Ok(smallvec![])),
+            Some(DeadStore::Drop) => WasmOperator::Drop,
+            None => op,
+        };
+
         let op_sig = self.op_sig(&op);
 
+        if let Some(name) =
+            libcall_name(&op).filter(|name| self.module.operator_libcall(name).is_some())
+        {
+            let concrete = |types: &[SigT]| {
+                types
+                    .iter()
+                    .map(|ty| match ty {
+                        SigT::Concrete(ty) => *ty,
+                        SigT::T => unreachable!("Numeric operators have concrete types"),
+                    })
+                    .collect()
+            };
+            let params = concrete(&op_sig.input);
+            let returns = concrete(&op_sig.output);
+
+            self.apply_op(op_sig);
+            return Some(Ok(smallvec![Operator::Libcall {
+                name,
+                params,
+                returns
+            }]));
+        }
+
         self.apply_op(op_sig);
 
         Some(Ok(match op {
@@ -1620,6 +2195,7 @@ where
 
                 self.stack.truncate(block.arguments as _);
                 self.stack.extend(block.returns.iter().cloned());
+                self.note_stack_height();
 
                 if let ControlFrameKind::If {
                     has_else: false, ..
@@ -1790,99 +2366,111 @@ where
 
             WasmOperator::I32Load { memarg } => smallvec![Operator::Load {
                 ty: I32,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::I64Load { memarg } => smallvec![Operator::Load {
                 ty: I64,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::F32Load { memarg } => smallvec![Operator::Load {
                 ty: F32,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::F64Load { memarg } => smallvec![Operator::Load {
                 ty: F64,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::I32Load8S { memarg } => smallvec![Operator::Load8 {
                 ty: sint::I32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I32Load8U { memarg } => smallvec![Operator::Load8 {
                 ty: sint::U32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I32Load16S { memarg } => smallvec![Operator::Load16 {
                 ty: sint::I32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I32Load16U { memarg } => smallvec![Operator::Load16 {
                 ty: sint::U32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load8S { memarg } => smallvec![Operator::Load8 {
                 ty: sint::I64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load8U { memarg } => smallvec![Operator::Load8 {
                 ty: sint::U64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load16S { memarg } => smallvec![Operator::Load16 {
                 ty: sint::I64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load16U { memarg } => smallvec![Operator::Load16 {
                 ty: sint::U64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load32S { memarg } => smallvec![Operator::Load32 {
                 sign: Signedness::Signed,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Load32U { memarg } => smallvec![Operator::Load32 {
                 sign: Signedness::Unsigned,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
 
             WasmOperator::I32Store { memarg } => smallvec![Operator::Store {
                 ty: I32,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::I64Store { memarg } => smallvec![Operator::Store {
                 ty: I64,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::F32Store { memarg } => smallvec![Operator::Store {
                 ty: F32,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::F64Store { memarg } => smallvec![Operator::Store {
                 ty: F64,
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
 
             WasmOperator::I32Store8 { memarg } => smallvec![Operator::Store8 {
                 ty: Size::_32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I32Store16 { memarg } => smallvec![Operator::Store16 {
                 ty: Size::_32,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Store8 { memarg } => smallvec![Operator::Store8 {
                 ty: Size::_64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Store16 { memarg } => smallvec![Operator::Store16 {
                 ty: Size::_64,
-                memarg: memarg.into(),
+                memarg: self.memarg(op_index, memarg),
             }],
             WasmOperator::I64Store32 { memarg } => smallvec![Operator::Store32 {
-                memarg: memarg.into()
+                memarg: self.memarg(op_index, memarg)
             }],
             WasmOperator::MemorySize { reserved } => smallvec![Operator::MemorySize { reserved }],
             WasmOperator::MemoryGrow { reserved } => smallvec![Operator::MemoryGrow { reserved }],
+            WasmOperator::TableGet { table } => {
+                smallvec![Operator::TableGet { table_index: table }]
+            }
+            WasmOperator::TableSet { table } => {
+                smallvec![Operator::TableSet { table_index: table }]
+            }
+            WasmOperator::TableGrow { table } => {
+                smallvec![Operator::TableGrow { table_index: table }]
+            }
+            WasmOperator::TableSize { table } => {
+                smallvec![Operator::TableSize { table_index: table }]
+            }
             WasmOperator::I32Const { value } => smallvec![Operator::Const(Value::I32(value))],
             WasmOperator::I64Const { value } => smallvec![Operator::Const(Value::I64(value))],
             WasmOperator::F32Const { value } => {
@@ -1891,8 +2479,8 @@ where
             WasmOperator::F64Const { value } => {
                 smallvec![Operator::Const(Value::F64(value.into()))]
             }
-            WasmOperator::RefNull => unimplemented!("{:?}", op),
-            WasmOperator::RefIsNull => unimplemented!("{:?}", op),
+            WasmOperator::RefNull => smallvec![Operator::Const(Value::I64(0))],
+            WasmOperator::RefIsNull => smallvec![Operator::Eqz(Size::_64)],
             WasmOperator::I32Eqz => smallvec![Operator::Eqz(Size::_32)],
             WasmOperator::I32Eq => smallvec![Operator::Eq(I32)],
             WasmOperator::I32Ne => smallvec![Operator::Ne(I32)],
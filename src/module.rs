@@ -1,46 +1,137 @@
-use crate::backend::TranslatedCodeSection;
+use crate::backend::{
+    trap_code, CodeGenOptions, Context, EntryStub, HostStub, TranslatedCodeSection,
+};
+use crate::branch_hints::{self, BranchHints, FunctionBranchHints};
+use crate::call_graph::CallGraph;
 use crate::error::Error;
+use crate::index_space::{DefinedFuncIndex, FuncIndex, FuncKind, ImportedFuncIndex, IndexVec};
+use crate::linear_memory::{LinearMemory, MemoryGrowth, MemoryStyle};
+use crate::memory64;
+use crate::metrics::CompilationMetrics;
 use crate::microwasm;
+use crate::profiling::{OperatorClass, OperatorCounts};
+use crate::signatures;
 use crate::translate_sections;
+use crate::vmctx::{self, ImportedFunc, MemoryDefinition, VmCtx, VmCtxBox, VmCtxLayout};
 use cranelift_codegen::{
     ir::{self, AbiParam, Signature as CraneliftSignature},
     isa,
 };
-use std::{convert::TryInto, mem};
-use wasmparser::{FuncType, MemoryType, ModuleReader, SectionCode, Type};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    ffi::{CStr, CString},
+    fmt, io, mem,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+use wasmparser::{
+    ExternalKind, FunctionBody, ImportSectionEntryType, MemoryType, ModuleReader, Operator,
+    OperatorValidatorConfig, Parser, ParserState, Section, SectionCode, TableType,
+    ValidatingParser, ValidatingParserConfig, WasmDecoder,
+};
+
+/// The type of a wasm value.
+///
+/// This, `FuncType` and `GlobalType` are used in our API in place of `wasmparser`'s types,
+/// which change with most of its releases, so that upgrading it only changes the conversions
+/// from them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl ValueType {
+    pub(crate) fn from_wasm(ty: wasmparser::Type) -> Result<Self, Error> {
+        match ty {
+            wasmparser::Type::I32 => Ok(ValueType::I32),
+            wasmparser::Type::I64 => Ok(ValueType::I64),
+            wasmparser::Type::F32 => Ok(ValueType::F32),
+            wasmparser::Type::F64 => Ok(ValueType::F64),
+            other => Err(Error::Input(format!("Unsupported value type {:?}", other))),
+        }
+    }
+}
+
+/// The parameters and returns of a function.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FuncType {
+    pub params: Box<[ValueType]>,
+    pub returns: Box<[ValueType]>,
+}
+
+impl FuncType {
+    pub(crate) fn from_wasm(ty: &wasmparser::FuncType) -> Result<Self, Error> {
+        let types = |types: &[wasmparser::Type]| {
+            types
+                .iter()
+                .map(|&ty| ValueType::from_wasm(ty))
+                .collect::<Result<_, _>>()
+        };
+
+        Ok(FuncType {
+            params: types(&ty.params)?,
+            returns: types(&ty.returns)?,
+        })
+    }
+}
+
+/// The type of a global.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct GlobalType {
+    pub content_type: ValueType,
+    pub mutable: bool,
+}
+
+impl GlobalType {
+    pub(crate) fn from_wasm(ty: wasmparser::GlobalType) -> Result<Self, Error> {
+        Ok(GlobalType {
+            content_type: ValueType::from_wasm(ty.content_type)?,
+            mutable: ty.mutable,
+        })
+    }
+}
 
 pub trait AsValueType {
-    const TYPE: Type;
+    const TYPE: ValueType;
 }
 
 pub trait TypeList {
-    const TYPE_LIST: &'static [Type];
+    const TYPE_LIST: &'static [ValueType];
 }
 
 impl<T> TypeList for T
 where
     T: AsValueType,
 {
-    const TYPE_LIST: &'static [Type] = &[T::TYPE];
+    const TYPE_LIST: &'static [ValueType] = &[T::TYPE];
 }
 
 impl AsValueType for i32 {
-    const TYPE: Type = Type::I32;
+    const TYPE: ValueType = ValueType::I32;
 }
 impl AsValueType for i64 {
-    const TYPE: Type = Type::I64;
+    const TYPE: ValueType = ValueType::I64;
 }
 impl AsValueType for u32 {
-    const TYPE: Type = Type::I32;
+    const TYPE: ValueType = ValueType::I32;
 }
 impl AsValueType for u64 {
-    const TYPE: Type = Type::I64;
+    const TYPE: ValueType = ValueType::I64;
 }
 impl AsValueType for f32 {
-    const TYPE: Type = Type::F32;
+    const TYPE: ValueType = ValueType::F32;
 }
 impl AsValueType for f64 {
-    const TYPE: Type = Type::F64;
+    const TYPE: ValueType = ValueType::F64;
 }
 
 pub trait FunctionArgs<O> {
@@ -69,7 +160,7 @@ macro_rules! impl_function_args {
         }
 
         impl<$first: AsValueType, $($rest: AsValueType),*> TypeList for ($first, $($rest),*) {
-            const TYPE_LIST: &'static [Type] = &[$first::TYPE, $($rest::TYPE),*];
+            const TYPE_LIST: &'static [ValueType] = &[$first::TYPE, $($rest::TYPE),*];
         }
 
         impl_function_args!($($rest),*);
@@ -88,39 +179,440 @@ macro_rules! impl_function_args {
         }
 
         impl TypeList for () {
-            const TYPE_LIST: &'static [Type] = &[];
+            const TYPE_LIST: &'static [ValueType] = &[];
         }
     };
 }
 
 impl_function_args!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S);
 
+/// Returned by a host function to trap the wasm code that called it. The call into the
+/// wasm code returns it as `ExecutionError::HostFunctionFailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostError(pub String);
+
+/// A function that wasm code can call through an import. It's called through a shim that
+/// takes the `HostImport` in place of the `VmCtx`, followed by the wasm arguments.
+#[derive(Clone)]
+pub struct HostFunc {
+    shim: *const u8,
+    func: Arc<dyn Any + Send + Sync>,
+    params: &'static [ValueType],
+    returns: &'static [ValueType],
+}
+
+// The shim is a plain function and the host function is `Send + Sync`.
+unsafe impl Send for HostFunc {}
+unsafe impl Sync for HostFunc {}
+
+/// Closures that can be imported by wasm code, taking the `VmCtx` of the calling instance
+/// followed by the wasm arguments.
+pub trait IntoHostFunc<Args, T> {
+    fn into_host_func(self) -> HostFunc;
+}
+
+macro_rules! impl_into_host_func {
+    ($($arg:ident),*) => {
+        impl<HostFn, Ret, $($arg),*> IntoHostFunc<($($arg,)*), Ret> for HostFn
+        where
+            HostFn: Fn(&VmCtx $(, $arg)*) -> Result<Ret, HostError> + Send + Sync + 'static,
+            Ret: TypeList + Default,
+            ($($arg,)*): TypeList,
+        {
+            fn into_host_func(self) -> HostFunc {
+                #[allow(non_snake_case)]
+                unsafe extern "sysv64" fn shim<HostFn, Ret $(, $arg)*>(
+                    import: *const HostImport
+                    $(, $arg: $arg)*
+                ) -> Ret
+                where
+                    HostFn: Fn(&VmCtx $(, $arg)*) -> Result<Ret, HostError>,
+                    Ret: Default,
+                {
+                    let import = &*import;
+                    let func = &*(import.func as *const HostFn);
+
+                    // Panics must not unwind into wasm frames, so they trap too.
+                    let error = match panic::catch_unwind(AssertUnwindSafe(|| {
+                        func(&*import.caller $(, $arg)*)
+                    })) {
+                        Ok(Ok(out)) => return out,
+                        Ok(Err(e)) => e,
+                        Err(payload) => HostError(
+                            payload
+                                .downcast_ref::<&str>()
+                                .map(|message| message.to_string())
+                                .or_else(|| payload.downcast_ref::<String>().cloned())
+                                .unwrap_or_else(|| "Host function panicked".to_string()),
+                        ),
+                    };
+                    *(*import.caller)
+                        .host_error
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(error);
+                    import.trapped.store(true, Ordering::Relaxed);
+                    Ret::default()
+                }
+
+                HostFunc {
+                    shim: shim::<HostFn, Ret $(, $arg)*> as *const u8,
+                    func: Arc::new(self),
+                    params: <($($arg,)*)>::TYPE_LIST,
+                    returns: Ret::TYPE_LIST,
+                }
+            }
+        }
+    };
+}
+
+macro_rules! impl_into_host_func_upto {
+    ($first:ident $(, $rest:ident)*) => {
+        impl_into_host_func!($first $(, $rest)*);
+        impl_into_host_func_upto!($($rest),*);
+    };
+    () => {
+        impl_into_host_func!();
+    };
+}
+
+impl_into_host_func_upto!(A, B, C, D, E, F, G, H);
+
+/// The context that an imported host function is called with, one per instance and import.
+#[repr(C)]
+pub(crate) struct HostImport {
+    shim: *const u8,
+    /// The host function, kept alive by the instance's `HostFunc`.
+    func: *const (),
+    caller: *const VmCtx,
+    /// Set by the shim if the host function fails, to make the exit stub trap. Cleared
+    /// whenever the instance is called.
+    trapped: AtomicBool,
+    /// The caller's `VmCtx::trap_sp`, which the exit stub traps to.
+    trap_sp: *const AtomicUsize,
+}
+
+// The pointers are only read, and are kept alive by the instance.
+unsafe impl Send for HostImport {}
+unsafe impl Sync for HostImport {}
+
+impl HostImport {
+    pub(crate) fn offset_of_shim() -> i32 {
+        offset_of!(HostImport, shim)
+            .try_into()
+            .expect("Offset exceeded size of i32")
+    }
+
+    pub(crate) fn offset_of_trapped() -> i32 {
+        offset_of!(HostImport, trapped)
+            .try_into()
+            .expect("Offset exceeded size of i32")
+    }
+
+    pub(crate) fn offset_of_trap_sp() -> i32 {
+        offset_of!(HostImport, trap_sp)
+            .try_into()
+            .expect("Offset exceeded size of i32")
+    }
+}
+
+/// Compiled code along with everything else that's shared between instances of a module.
+///
+/// Nothing in it is changed by running the code, since memories, tables and globals belong to
+/// each `Instance`, so it's `Send` and `Sync` and one translation can be shared between
+/// threads that each have their own instances.
 #[derive(Default)]
-pub struct TranslatedModule {
+pub struct CompiledModule {
     translated_code_section: Option<TranslatedCodeSection>,
     ctx: SimpleContext,
-    // TODO: Should we wrap this in a `Mutex` so that calling functions from multiple
-    //       threads doesn't cause data races?
+    /// The type of the memory that each instance gets, if the module has one.
     memory: Option<MemoryType>,
+    /// The module and field name that the memory is imported as, if it isn't defined by the
+    /// module.
+    memory_import: Option<(String, String)>,
+    /// The module and field name that each imported global is imported as, indexed by global
+    /// index.
+    global_imports: Vec<(String, String)>,
+    /// The module and field name that each imported function is imported as, indexed by
+    /// function index.
+    func_imports: Vec<(String, String)>,
+    table: Option<TableType>,
+    data_segments: Vec<DataSegment>,
+    element_segments: Vec<ElementSegment>,
+    exports: HashMap<String, (ExternalKind, u32)>,
+    custom_sections: Vec<CustomSection>,
+    /// Set if the module's functions are translated when they're first called.
+    lazy: Option<LazyFunctions>,
+    /// The calls into the module's code that haven't returned yet.
+    calls: CallGate,
+    call_graph: CallGraph,
 }
 
-impl TranslatedModule {
-    pub fn instantiate(self) -> ExecutableModule {
-        let mem_size = self.memory.map(|m| m.limits.initial).unwrap_or(0) as usize;
-        let mem: BoxSlice<_> = vec![0u8; mem_size * WASM_PAGE_SIZE]
-            .into_boxed_slice()
-            .into();
+/// What's needed to translate the functions of a module translated with
+/// `TranslateOptions::lazy` once they're called.
+struct LazyFunctions {
+    /// The body of each function and where it starts in the module.
+    bodies: IndexVec<DefinedFuncIndex, (usize, Vec<u8>)>,
+    codegen: CodeGenOptions,
+    metrics: Option<Arc<dyn CompilationMetrics>>,
+    /// The code of each function that's been translated. The lock also stops two threads
+    /// calling the same function from both translating it.
+    translated: Mutex<HashMap<DefinedFuncIndex, TranslatedCodeSection>>,
+}
 
-        let ctx = if mem.len > 0 {
-            Some(Box::new(VmCtx { mem }) as Box<VmCtx>)
+/// Counts the calls into a module's code that are running, so that it's only unloaded once
+/// they've all returned.
+#[derive(Default)]
+struct CallGate {
+    running: Mutex<usize>,
+    /// Set once the module is being unloaded, after which no more calls are let in.
+    closed: AtomicBool,
+    /// Notified when the last running call returns.
+    returned: Condvar,
+}
+
+impl CallGate {
+    /// Count a call until the returned guard is dropped, unless the module is being unloaded.
+    fn enter(&self) -> Option<ActiveCall> {
+        let mut running = self.running.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        *running += 1;
+        Some(ActiveCall(self))
+    }
+
+    fn close(&self) {
+        let mut running = self.running.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        while *running != 0 {
+            running = self.returned.wait(running).unwrap();
+        }
+    }
+}
+
+/// A call counted by a `CallGate`. It's dropped when the call returns, or when a host function
+/// that it called panics.
+struct ActiveCall<'a>(&'a CallGate);
+
+impl Drop for ActiveCall<'_> {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.0.returned.notify_all();
+        }
+    }
+}
+
+/// A custom section, which wasm gives no meaning to but which tools use for things like debug
+/// information and the features and tools that a module was built with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomSection {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Where an active data segment is placed in memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SegmentOffset {
+    Const(u32),
+    Global(u32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataSegmentKind {
+    /// Only copied into memory by `memory.init`.
+    Passive,
+    /// Copied into memory at instantiation.
+    Active {
+        memory_index: u32,
+        offset: SegmentOffset,
+    },
+}
+
+/// A data segment. Its id is its index in the module's data section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSegment {
+    pub kind: DataSegmentKind,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ElementSegmentKind {
+    /// Only copied into a table by `table.init`.
+    Passive,
+    /// Copied into a table at instantiation.
+    Active {
+        table_index: u32,
+        offset: SegmentOffset,
+    },
+}
+
+/// An element segment. Its id is its index in the module's element section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementSegment {
+    pub kind: ElementSegmentKind,
+    /// Function indices, including imported functions.
+    pub elements: Vec<u32>,
+}
+
+/// The initial value of a defined global.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum GlobalInit {
+    /// The bits of a constant, zero-extended to 64 bits.
+    Const(u64),
+    /// The value of another global, which must be imported.
+    Global(u32),
+}
+
+impl CompiledModule {
+    /// Instantiate a module that won't be instantiated again. Use `Instance::new` to create
+    /// several instances sharing the same code.
+    pub fn instantiate(self) -> Instance {
+        Instance::new(Arc::new(self))
+    }
+
+    /// How many calls into the module's code from the host are running, across all threads and
+    /// instances. A call back into the module from a host function that it called counts as
+    /// another one.
+    pub fn active_calls(&self) -> usize {
+        *self.calls.running.lock().unwrap()
+    }
+
+    /// Whether `unload` was called, after which the module's functions can't be called.
+    pub fn is_unloaded(&self) -> bool {
+        self.calls.closed.load(Ordering::SeqCst)
+    }
+
+    /// Free the module's code once none of it is running. If there are other handles to the
+    /// module, such as those held by its instances, it's returned back untouched, so that it
+    /// can still be called and be unloaded again once they're dropped. Otherwise calls into
+    /// the module fail with `ExecutionError::Unloaded` from then on, and this waits for those
+    /// that are already running to return.
+    ///
+    /// Calling this from a host function that the module called waits forever. References
+    /// to the module's functions that were stored in the tables of other modules' instances
+    /// aren't tracked, so they must be removed before it's unloaded.
+    pub fn unload(module: Arc<Self>) -> Result<(), Arc<Self>> {
+        let module = Arc::try_unwrap(module)?;
+        module.calls.close();
+        Ok(())
+    }
+
+    /// The module's data segments, indexed by segment id.
+    pub fn data_segments(&self) -> &[DataSegment] {
+        &self.data_segments
+    }
+
+    /// The module's element segments, indexed by segment id.
+    pub fn element_segments(&self) -> &[ElementSegment] {
+        &self.element_segments
+    }
+
+    /// The calls that the module's functions make to each other.
+    pub fn call_graph(&self) -> &CallGraph {
+        &self.call_graph
+    }
+
+    /// The function exported as `name` and its type, if the module defines it. The index is
+    /// among the module's defined functions, as `Instance::execute_func` takes.
+    pub fn exported_func(&self, name: &str) -> Option<(u32, &FuncType)> {
+        match self.exports.get(name) {
+            Some(&(ExternalKind::Function, func_index)) => {
+                let defined = self.ctx.defined_func_index(func_index)?;
+                Some((defined, self.ctx.defined_func_type(defined)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The type of the function at `func_idx` among the module's defined functions.
+    pub fn defined_func_type(&self, func_idx: u32) -> Option<&FuncType> {
+        if func_idx < self.ctx.defined_func_count() {
+            Some(self.ctx.defined_func_type(func_idx))
         } else {
             None
-        };
+        }
+    }
+
+    /// Every custom section in the module, in the order that they appear in it.
+    pub fn custom_sections(&self) -> &[CustomSection] {
+        &self.custom_sections
+    }
+
+    /// The contents of the first custom section called `name`.
+    pub fn custom_section(&self, name: &str) -> Option<&[u8]> {
+        self.custom_sections
+            .iter()
+            .find(|section| section.name == name)
+            .map(|section| &section.data[..])
+    }
+
+    /// The name that the module's name section gives the function at `func_index`, which
+    /// counts imports.
+    pub fn func_name(&self, func_index: u32) -> Option<&str> {
+        self.ctx.func_name(func_index)
+    }
 
-        ExecutableModule {
-            module: self,
-            context: ctx,
+    /// The code generated for the module's functions, or `None` if it doesn't define any.
+    pub fn code_section(&self) -> Option<&TranslatedCodeSection> {
+        self.translated_code_section.as_ref()
+    }
+
+    /// Whether the function at `func_idx` among the module's defined functions has been
+    /// translated. This is only false for functions of a module translated with
+    /// `TranslateOptions::lazy` that haven't been called yet.
+    pub fn is_translated(&self, func_idx: u32) -> bool {
+        self.translated_code_section
+            .as_ref()
+            .map_or(true, |code| !code.is_lazy_stub(DefinedFuncIndex(func_idx)))
+    }
+
+    /// Translate a function of a module translated with `TranslateOptions::lazy` ahead of
+    /// its first call, if it hasn't been translated already.
+    pub fn translate_function(&self, func_idx: u32) -> Result<(), Error> {
+        if func_idx >= self.ctx.defined_func_count() {
+            return Err(Error::Input(format!("No function {}", func_idx)));
+        }
+        if self.lazy.is_some() {
+            self.translate_lazily(DefinedFuncIndex(func_idx))?;
         }
+        Ok(())
+    }
+
+    /// Translate a function of a lazily-translated module and make its stub jump to the
+    /// result, returning the address of its code.
+    fn translate_lazily(&self, func_idx: DefinedFuncIndex) -> Result<*const u8, Error> {
+        let lazy = self.lazy.as_ref().expect("module isn't translated lazily");
+        let mut translated = lazy
+            .translated
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(code) = translated.get(&func_idx) {
+            return Ok(code.func_start(func_idx));
+        }
+
+        let (offset, body) = &lazy.bodies[func_idx];
+        let code = translate_sections::lazy_function(
+            func_idx,
+            &FunctionBody::new(*offset, body),
+            &self.ctx,
+            self.translated_code_section
+                .as_ref()
+                .expect("no code section"),
+            lazy.codegen.clone(),
+            lazy.metrics.clone(),
+        )?;
+        let start = code.func_start(func_idx);
+        unsafe {
+            self.translated_code_section
+                .as_ref()
+                .expect("no code section")
+                .set_lazy_target(func_idx, start);
+        }
+        translated.insert(func_idx, code);
+
+        Ok(start)
     }
 
     pub fn disassemble(&self) {
@@ -131,118 +623,1575 @@ impl TranslatedModule {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum ExecutionError {
-    FuncIndexOutOfBounds,
-    TypeMismatch,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutionError {
+    FuncIndexOutOfBounds,
+    TableIndexOutOfBounds,
+    TypeMismatch,
+    /// The module imports something that wasn't provided when instantiating it.
+    MissingImport,
+    /// Something provided as an import doesn't match what the module expects, such as a
+    /// memory smaller than the module's minimum size.
+    IncompatibleImport,
+    /// An active segment doesn't fit in the memory or table it initializes, which traps
+    /// during instantiation.
+    SegmentOutOfBounds,
+    /// The instance's memory couldn't be allocated, or its address space reserved.
+    OutOfMemory,
+    /// A table element that calls were bound to with `CodeGenOptions::devirtualize_calls`
+    /// can't be changed.
+    BoundTableElement,
+    /// An access to the instance's memory from the host is outside of its current size.
+    MemoryOutOfBounds,
+    /// The module is being unloaded, so its functions can't be called.
+    Unloaded,
+    /// A host function that the wasm code called returned this error or panicked, which
+    /// trapped the wasm code.
+    HostFunctionFailed(HostError),
+    /// The wasm code trapped for this reason.
+    Trap(ir::TrapCode),
+}
+
+/// A host function returned by `Instance::host_func_ref`, with the context and stub that
+/// wasm code calls it through.
+struct HostFuncRef {
+    _func: HostFunc,
+    import: Box<HostImport>,
+    _stub: HostStub,
+}
+
+/// The state of one instantiation of a `CompiledModule`.
+///
+/// Running the instance's code changes its memory and globals without any synchronization,
+/// so an `Instance` is `Send` but not `Sync`. To run a module on several threads give each
+/// one its own instance of the shared module.
+pub struct Instance {
+    module: Arc<CompiledModule>,
+    context: VmCtxBox,
+    /// The host function called through each of `host_imports`.
+    _host_funcs: Vec<HostFunc>,
+    /// Pointed to by the `VmCtx`.
+    host_imports: Vec<Box<HostImport>>,
+    /// Pointed to by references returned by `host_func_ref`.
+    host_func_refs: Vec<HostFuncRef>,
+    /// Pointed to by the `VmCtx`.
+    _imported_memory: Option<Arc<HostMemory>>,
+    /// Pointed to by the `VmCtx`, indexed by global index.
+    imported_globals: Vec<Arc<HostGlobal>>,
+}
+
+impl Instance {
+    /// Create an instance of `module` with its own memory, table and `VmCtx`.
+    ///
+    /// # Panics
+    ///
+    /// If the module imports a memory, a global or a function that wasn't bound when it was
+    /// translated, which must be provided with `Instance::with_imports`, or if instantiation
+    /// traps.
+    pub fn new(module: Arc<CompiledModule>) -> Self {
+        Self::with_imports(module, &InstanceImports::default())
+            .expect("Failed to instantiate module")
+    }
+
+    /// Create an instance of `module`, using `imports` for anything that the module imports
+    /// other than functions bound by `TranslateOptions::host_functions`. Active data segments
+    /// are copied into memory, failing with `SegmentOutOfBounds` if one doesn't fit. An
+    /// imported memory must be allocated in the module's `MemoryStyle`.
+    pub fn with_imports(
+        module: Arc<CompiledModule>,
+        imports: &InstanceImports,
+    ) -> Result<Self, ExecutionError> {
+        Self::link(module, imports).map_err(|e| match e {
+            Error::UnresolvedImport { .. } => ExecutionError::MissingImport,
+            Error::IncompatibleImport { .. } => ExecutionError::IncompatibleImport,
+            Error::Instantiation(e) => e,
+            e => unreachable!("Unexpected instantiation error: {}", e),
+        })
+    }
+
+    /// `with_imports`, but with errors that say which import couldn't be resolved and why:
+    /// `UnresolvedImport` if nothing was registered for it and `IncompatibleImport` if what
+    /// was doesn't have the type that the module expects. Other failures are returned as
+    /// `Error::Instantiation`.
+    pub fn link(module: Arc<CompiledModule>, imports: &InstanceImports) -> Result<Self, Error> {
+        let min_mem_size = module.memory.map(|m| m.limits.initial).unwrap_or(0) as usize;
+        let memory_style = module.ctx.memory_style;
+        let (mut memory, imported_memory) = match &module.memory_import {
+            Some((module_name, field)) => {
+                let imported = imports
+                    .memory(module_name, field)
+                    .ok_or_else(|| unresolved_import(module_name, field, "memory"))?;
+                if imported.len() < min_mem_size * WASM_PAGE_SIZE {
+                    return Err(incompatible_import(
+                        module_name,
+                        field,
+                        format!(
+                            "The memory is {} bytes but the module needs at least {}",
+                            imported.len(),
+                            min_mem_size * WASM_PAGE_SIZE
+                        ),
+                    ));
+                }
+                if memory_style == MemoryStyle::GuardPages
+                    && imported.style() != Some(MemoryStyle::GuardPages)
+                {
+                    return Err(incompatible_import(
+                        module_name,
+                        field,
+                        "The module was translated for memory with guard pages".to_string(),
+                    ));
+                }
+                (None, Some(imported.clone()))
+            }
+            None => (
+                Some(
+                    LinearMemory::new(memory_style, min_mem_size * WASM_PAGE_SIZE)
+                        .ok_or(Error::Instantiation(ExecutionError::OutOfMemory))?,
+                ),
+                None,
+            ),
+        };
+        let mem = memory
+            .as_mut()
+            .map(|memory| MemoryDefinition {
+                len: memory.len(),
+                ptr: memory.as_mut_ptr(),
+            })
+            .unwrap_or(MemoryDefinition {
+                len: 0,
+                ptr: ptr::null_mut(),
+            });
+        let imported_mem = imported_memory
+            .as_ref()
+            .map(|imported| &imported.definition as *const MemoryDefinition)
+            .unwrap_or(ptr::null());
+
+        let host_funcs = module
+            .ctx
+            .imports
+            .iter()
+            .zip(&module.func_imports)
+            .filter_map(|((index, import), (module_name, field))| match import {
+                FuncImport::Host(func) => Some(Ok(func.clone())),
+                FuncImport::Linked => {
+                    Some(imports.linked_func(module_name, field, module.ctx.func_type(index.0)))
+                }
+                FuncImport::Intrinsic(_) => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let imported_globals = module
+            .global_imports
+            .iter()
+            .zip(&module.ctx.globals)
+            .map(|((module_name, field), ty)| {
+                let global = imports
+                    .global(module_name, field)
+                    .ok_or_else(|| unresolved_import(module_name, field, "global"))?;
+                if (global.ty.content_type, global.ty.mutable) != (ty.content_type, ty.mutable) {
+                    let mutability = |mutable| if mutable { "mutable" } else { "constant" };
+                    return Err(incompatible_import(
+                        module_name,
+                        field,
+                        format!(
+                            "The module expects a {} global of type {:?} but it's a {} {:?}",
+                            mutability(ty.mutable),
+                            ty.content_type,
+                            mutability(global.ty.mutable),
+                            global.ty.content_type
+                        ),
+                    ));
+                }
+                Ok(global.clone())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let table_size = module.table.map(|t| t.limits.initial).unwrap_or(0) as usize;
+        let table: BoxSlice<_> = vec![RuntimeFunc::NULL; table_size]
+            .into_boxed_slice()
+            .into();
+
+        let mut context = VmCtxBox::new(
+            VmCtx {
+                version: vmctx::VERSION,
+                table,
+                table_maximum: module
+                    .table
+                    .and_then(|t| t.limits.maximum)
+                    .unwrap_or(u32::max_value()),
+                func_refs: Default::default(),
+                builtins: module.ctx.libcalls.addresses(),
+                mem,
+                imported_mem,
+                owned_memory: memory,
+                memory_maximum: module
+                    .memory
+                    .and_then(|m| m.limits.maximum)
+                    .unwrap_or(MAX_WASM_PAGES)
+                    .min(MAX_WASM_PAGES),
+                memory_growth: MemoryGrowth::default(),
+                module: &*module,
+                operator_counts: [0; OperatorClass::COUNT],
+                stack_limit: 0,
+                trap_reason: 0,
+                host_state: None,
+                trap_sp: AtomicUsize::new(0),
+                host_error: Mutex::new(None),
+            },
+            module.ctx.vmctx_layout(),
+            &module.ctx.sig_ids,
+        );
+
+        let host_imports = host_funcs
+            .iter()
+            .map(|func| {
+                Box::new(HostImport {
+                    shim: func.shim,
+                    func: Arc::as_ptr(&func.func) as *const (),
+                    caller: context.as_ptr() as *const VmCtx,
+                    trapped: AtomicBool::new(false),
+                    trap_sp: unsafe { &(*(context.as_ptr() as *const VmCtx)).trap_sp },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (global, entry) in imported_globals.iter().zip(context.global_imports_mut()) {
+            *entry = &global.value;
+        }
+
+        let mut host_imports_iter = host_imports.iter();
+        for ((index, import), entry) in module.ctx.imports.iter().zip(context.imports_mut()) {
+            match import {
+                FuncImport::Host(_) | FuncImport::Linked => {
+                    let host_import = host_imports_iter.next().unwrap();
+                    *entry = ImportedFunc {
+                        body: module
+                            .translated_code_section
+                            .as_ref()
+                            .expect("no code section")
+                            .exit_stub(index)
+                            .expect("no exit stub for host function"),
+                        vmctx: &**host_import as *const HostImport as *const u8,
+                    };
+                }
+                FuncImport::Intrinsic(_) => {}
+            }
+        }
+
+        let mut instance = Instance {
+            module,
+            context,
+            _host_funcs: host_funcs,
+            host_imports,
+            host_func_refs: vec![],
+            _imported_memory: imported_memory,
+            imported_globals,
+        };
+
+        instance.init_globals();
+
+        // Segments are written in order, elements first, so those before an out-of-bounds
+        // segment are still written. This is only visible if the memory is imported.
+        instance.init_elements().map_err(Error::Instantiation)?;
+        instance.init_data().map_err(Error::Instantiation)?;
+
+        Ok(instance)
+    }
+
+    /// The value of an imported global, which is all that initializers can refer to.
+    fn imported_global_value(&self, global_index: u32) -> u64 {
+        self.imported_globals[global_index as usize].get()
+    }
+
+    /// Where an active segment starts in its memory or table.
+    fn segment_offset(&self, offset: SegmentOffset) -> usize {
+        match offset {
+            SegmentOffset::Const(offset) => offset as usize,
+            SegmentOffset::Global(index) => self.imported_global_value(index) as u32 as usize,
+        }
+    }
+
+    fn init_globals(&mut self) {
+        let module = self.module.clone();
+        let values = module
+            .ctx
+            .global_inits
+            .iter()
+            .map(|init| match *init {
+                GlobalInit::Const(bits) => bits,
+                GlobalInit::Global(index) => self.imported_global_value(index),
+            })
+            .collect::<Vec<_>>();
+
+        self.context.globals_mut().copy_from_slice(&values);
+    }
+
+    fn init_elements(&mut self) -> Result<(), ExecutionError> {
+        let module = self.module.clone();
+
+        for segment in &module.element_segments {
+            if let ElementSegmentKind::Active {
+                table_index,
+                offset,
+            } = segment.kind
+            {
+                assert_eq!(table_index, 0, "Multiple tables not yet implemented");
+
+                let offset = self.segment_offset(offset);
+                let funcs = segment
+                    .elements
+                    .iter()
+                    .map(|&func_index| self.func_ref_at(FuncIndex(func_index)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let dst = offset
+                    .checked_add(funcs.len())
+                    .and_then(|end| self.context.table_mut().get_mut(offset..end))
+                    .ok_or(ExecutionError::SegmentOutOfBounds)?;
+                dst.copy_from_slice(&funcs);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the active data segments into the instance's memory. The module's copy of the
+    /// segments is never written to, so every instance starts with the same data whatever
+    /// earlier instances did to their memory.
+    fn init_data(&mut self) -> Result<(), ExecutionError> {
+        for segment in &self.module.data_segments {
+            if let DataSegmentKind::Active {
+                memory_index,
+                offset,
+            } = segment.kind
+            {
+                assert_eq!(memory_index, 0, "Multiple memories not yet implemented");
+
+                let offset = self.segment_offset(offset);
+                let memory = self.context.get_mut().memory_mut();
+                let dst = offset
+                    .checked_add(segment.data.len())
+                    .and_then(|end| memory.get_mut(offset..end))
+                    .ok_or(ExecutionError::SegmentOutOfBounds)?;
+                dst.copy_from_slice(&segment.data);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn module(&self) -> &Arc<CompiledModule> {
+        &self.module
+    }
+
+    /// Executes the function _without checking types_. This can cause undefined
+    /// memory to be accessed.
+    ///
+    /// # Panics
+    ///
+    /// If the module is being unloaded, or if the function traps.
+    pub unsafe fn execute_func_unchecked<Args: FunctionArgs<T>, T>(
+        &self,
+        func_idx: u32,
+        args: Args,
+    ) -> T {
+        let _call = self
+            .module
+            .calls
+            .enter()
+            .expect("Called a function of a module that's being unloaded");
+
+        match self.call_func(func_idx, args) {
+            Ok(out) => out,
+            Err(ExecutionError::HostFunctionFailed(HostError(message))) => {
+                panic!("Host function failed: {}", message)
+            }
+            Err(e) => panic!("Function trapped: {:?}", e),
+        }
+    }
+
+    /// Like `execute_func_unchecked`, but returns an error rather than panicking if the
+    /// module is being unloaded or the function traps.
+    pub unsafe fn try_execute_func_unchecked<Args: FunctionArgs<T>, T>(
+        &self,
+        func_idx: u32,
+        args: Args,
+    ) -> Result<T, ExecutionError> {
+        let _call = self.module.calls.enter().ok_or(ExecutionError::Unloaded)?;
+
+        self.call_func(func_idx, args)
+    }
+
+    /// Call a function, which the caller must have counted with the module's `CallGate`,
+    /// through the `EntryStub`, so that it can trap, or a host function that it calls make it
+    /// trap with an error.
+    unsafe fn call_func<Args: FunctionArgs<T>, T>(
+        &self,
+        func_idx: u32,
+        args: Args,
+    ) -> Result<T, ExecutionError> {
+        /// What the entry stub's callback calls and where it puts the result.
+        struct Call<Args: FunctionArgs<T>, T> {
+            func: Option<(Args, Args::FuncType)>,
+            vm_ctx: *const u8,
+            out: Option<T>,
+        }
+
+        // Nothing in this frame needs dropping, since it's skipped if the call traps.
+        unsafe extern "sysv64" fn callback<Args: FunctionArgs<T>, T>(data: *mut u8) -> u32 {
+            let call = &mut *(data as *mut Call<Args, T>);
+            let (args, func) = call.func.take().unwrap();
+            call.out = Some(args.call(func, call.vm_ctx));
+            0
+        }
+
+        lazy_static! {
+            static ref ENTRY_STUB: EntryStub =
+                EntryStub::new().expect("Couldn't assemble the entry stub");
+        }
+
+        let code_section = self
+            .module
+            .translated_code_section
+            .as_ref()
+            .expect("no code section");
+        let start_buf = code_section.func_start(DefinedFuncIndex(func_idx));
+
+        for import in self
+            .host_imports
+            .iter()
+            .chain(self.host_func_refs.iter().map(|func_ref| &func_ref.import))
+        {
+            import.trapped.store(false, Ordering::Relaxed);
+        }
+
+        let context = &*(self.context.as_ptr() as *const VmCtx);
+        let mut call = Call::<Args, T> {
+            func: Some((args, Args::into_func(start_buf))),
+            vm_ctx: self.context.as_ptr(),
+            out: None,
+        };
+        let trapped = ENTRY_STUB.call(
+            &context.trap_sp,
+            &mut call as *mut Call<Args, T> as *mut u8,
+            callback::<Args, T>,
+        );
+
+        match call.out {
+            Some(out) if !trapped => return Ok(out),
+            _ => {}
+        }
+
+        let host_error = context
+            .host_error
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(error) = host_error {
+            return Err(ExecutionError::HostFunctionFailed(error));
+        }
+
+        // The trap stub wrote this behind the reference's back
+        let trap_reason = ptr::read_volatile(&context.trap_reason);
+        Err(match trap_code(trap_reason) {
+            Some(code) => ExecutionError::Trap(code),
+            None => {
+                ExecutionError::HostFunctionFailed(HostError("Host function failed".to_string()))
+            }
+        })
+    }
+
+    pub fn execute_func<Args: FunctionArgs<T> + TypeList, T: TypeList>(
+        &self,
+        func_idx: u32,
+        args: Args,
+    ) -> Result<T, ExecutionError> {
+        let module = &self.module;
+
+        if func_idx >= module.ctx.defined_func_count() {
+            return Err(ExecutionError::FuncIndexOutOfBounds);
+        }
+
+        let type_index = module.ctx.func_type_index(module.ctx.func_index(func_idx));
+
+        // TODO: Handle "compatible" types (i.e. f32 and i32)
+        if signatures::lookup(Args::TYPE_LIST, T::TYPE_LIST)
+            != Some(module.ctx.sig_ids[type_index as usize])
+        {
+            return Err(ExecutionError::TypeMismatch);
+        }
+
+        let _call = module.calls.enter().ok_or(ExecutionError::Unloaded)?;
+
+        unsafe { self.call_func(func_idx, args) }
+    }
+
+    /// A reference to one of this module's functions, to be stored in a table.
+    pub fn func_ref(&self, func_idx: u32) -> Result<RuntimeFunc, ExecutionError> {
+        let module = &self.module;
+
+        if func_idx >= module.ctx.defined_func_count() {
+            return Err(ExecutionError::FuncIndexOutOfBounds);
+        }
+
+        self.func_ref_at(FuncIndex(module.ctx.func_index(func_idx)))
+    }
+
+    /// A reference to any function in the module's function index space, including imports.
+    /// Intrinsics have no code of their own, so they can't be referred to.
+    fn func_ref_at(&self, func_index: FuncIndex) -> Result<RuntimeFunc, ExecutionError> {
+        let ctx = &self.module.ctx;
+        let sig_id = ctx.sig_ids[ctx.func_type_index(func_index.0) as usize];
+
+        let (func_start, vmctx) = match ctx.func_kind(func_index) {
+            FuncKind::Defined(index) => {
+                (self.code_section().func_start(index), self.context.as_ptr())
+            }
+            FuncKind::Imported(index) => {
+                let import = self.context.imports()[index.0 as usize];
+                if import.body.is_null() {
+                    return Err(ExecutionError::IncompatibleImport);
+                }
+                (import.body, import.vmctx)
+            }
+        };
+
+        Ok(RuntimeFunc {
+            func_start,
+            vmctx,
+            sig_id,
+        })
+    }
+
+    /// A reference to a host function, to be stored in a table. It's called through an exit
+    /// stub like an imported host function, getting this instance's `VmCtx`, and if it
+    /// returns an error or panics, the wasm code that called it traps and the call into this
+    /// instance returns the error. Signature ids are shared between modules, so the reference
+    /// can also be stored in the table of another instance, but it's only valid as long as
+    /// this one, and it can only fail while this instance is being called.
+    pub fn host_func_ref<Args, T>(
+        &mut self,
+        func: impl IntoHostFunc<Args, T>,
+    ) -> Result<RuntimeFunc, Error> {
+        let func = func.into_host_func();
+        let stub = HostStub::new(
+            func.params.iter().map(SigType::to_microwasm_type),
+            HostImport::offset_of_shim(),
+            HostImport::offset_of_trapped(),
+            HostImport::offset_of_trap_sp(),
+        )?;
+        let import = Box::new(HostImport {
+            shim: func.shim,
+            func: Arc::as_ptr(&func.func) as *const (),
+            caller: self.context.as_ptr() as *const VmCtx,
+            trapped: AtomicBool::new(false),
+            trap_sp: unsafe { &(*(self.context.as_ptr() as *const VmCtx)).trap_sp },
+        });
+        let runtime_func = RuntimeFunc {
+            func_start: stub.ptr(),
+            vmctx: &*import as *const HostImport as *const u8,
+            sig_id: signatures::intern(func.params, func.returns),
+        };
+
+        self.host_func_refs.push(HostFuncRef {
+            _func: func,
+            import,
+            _stub: stub,
+        });
+        Ok(runtime_func)
+    }
+
+    /// Attach state to the instance, which host functions that it calls can get with
+    /// `VmCtx::host_state`. This replaces any state that was attached before.
+    pub fn set_host_state<T: Any + Send + Sync>(&mut self, state: T) {
+        self.context.get_mut().host_state = Some(Box::new(state));
+    }
+
+    /// The state attached to the instance with `set_host_state`, if it's a `T`.
+    pub fn host_state<T: Any>(&self) -> Option<&T> {
+        unsafe { (*(self.context.as_ptr() as *const VmCtx)).host_state() }
+    }
+
+    /// Take back the state attached to the instance with `set_host_state`.
+    pub fn take_host_state(&mut self) -> Option<Box<dyn Any + Send + Sync>> {
+        self.context.get_mut().host_state.take()
+    }
+
+    /// Decide whether `memory.grow` may grow the instance's memory. Memory that the instance
+    /// imports never grows.
+    pub fn set_memory_growth(&mut self, growth: MemoryGrowth) {
+        self.context.get_mut().memory_growth = growth;
+    }
+
+    /// The current number of elements in the module's table.
+    pub fn table_size(&self) -> u32 {
+        self.context.table().len() as u32
+    }
+
+    /// Read an element of the module's table, returning `None` for null elements.
+    pub fn table_get(&self, index: u32) -> Result<Option<RuntimeFunc>, ExecutionError> {
+        let element = self
+            .context
+            .table()
+            .get(index as usize)
+            .ok_or(ExecutionError::TableIndexOutOfBounds)?;
+
+        Ok(if element.is_null() {
+            None
+        } else {
+            Some(*element)
+        })
+    }
+
+    /// Write an element of the module's table, with `None` storing a null element.
+    pub fn table_set(
+        &mut self,
+        index: u32,
+        func: Option<RuntimeFunc>,
+    ) -> Result<(), ExecutionError> {
+        if let Some(code_section) = &self.module.translated_code_section {
+            if code_section.is_bound_table_element(index) {
+                return Err(ExecutionError::BoundTableElement);
+            }
+        }
+
+        let element = self
+            .context
+            .table_mut()
+            .get_mut(index as usize)
+            .ok_or(ExecutionError::TableIndexOutOfBounds)?;
+
+        *element = func.unwrap_or(RuntimeFunc::NULL);
+
+        Ok(())
+    }
+
+    /// Append `delta` copies of `init` to the module's table, returning its previous size, or
+    /// `None` if that would exceed the table's maximum size.
+    pub fn table_grow(&mut self, delta: u32, init: Option<RuntimeFunc>) -> Option<u32> {
+        self.context
+            .get_mut()
+            .grow_table(delta, init.unwrap_or(RuntimeFunc::NULL))
+    }
+
+    /// The memory exported as `name`, whether it's defined by the module or imported. Wasm
+    /// code writes to memory through shared references to the instance, so the memory can only
+    /// be accessed while no wasm code is running.
+    pub fn memory_mut(&mut self, name: &str) -> Option<&mut [u8]> {
+        match self.module.exports.get(name) {
+            Some(&(ExternalKind::Memory, 0)) => Some(self.context.get_mut().memory_mut()),
+            _ => None,
+        }
+    }
+
+    /// The instance's memory, whether it's defined by the module or imported.
+    fn memory(&self) -> &[u8] {
+        unsafe { (*(self.context.as_ptr() as *const VmCtx)).memory() }
+    }
+
+    /// `len` bytes of the instance's memory starting at `offset`. Like `memory_mut`, this
+    /// shouldn't be called while wasm code is running in the instance.
+    pub fn read_memory(&self, offset: u32, len: u32) -> Result<Vec<u8>, ExecutionError> {
+        let start = offset as usize;
+        self.memory()
+            .get(start..start + len as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or(ExecutionError::MemoryOutOfBounds)
+    }
+
+    /// Copy `data` into the instance's memory at `offset`, writing nothing if it doesn't
+    /// all fit.
+    pub fn write_memory(&mut self, offset: u32, data: &[u8]) -> Result<(), ExecutionError> {
+        let start = offset as usize;
+        self.context
+            .get_mut()
+            .memory_mut()
+            .get_mut(start..start + data.len())
+            .ok_or(ExecutionError::MemoryOutOfBounds)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Read a little-endian `u32` from the instance's memory, which needn't be aligned.
+    pub fn read_u32(&self, offset: u32) -> Result<u32, ExecutionError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.read_memory(offset, 4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Write a little-endian `u32` to the instance's memory, which needn't be aligned.
+    pub fn write_u32(&mut self, offset: u32, value: u32) -> Result<(), ExecutionError> {
+        self.write_memory(offset, &value.to_le_bytes())
+    }
+
+    /// Read a little-endian `u64` from the instance's memory, which needn't be aligned.
+    pub fn read_u64(&self, offset: u32) -> Result<u64, ExecutionError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.read_memory(offset, 8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Write a little-endian `u64` to the instance's memory, which needn't be aligned.
+    pub fn write_u64(&mut self, offset: u32, value: u64) -> Result<(), ExecutionError> {
+        self.write_memory(offset, &value.to_le_bytes())
+    }
+
+    /// Read a nul-terminated string starting at `offset`, which must end before the end of
+    /// the instance's memory.
+    pub fn read_c_str(&self, offset: u32) -> Result<CString, ExecutionError> {
+        let rest = self
+            .memory()
+            .get(offset as usize..)
+            .ok_or(ExecutionError::MemoryOutOfBounds)?;
+        let len = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(ExecutionError::MemoryOutOfBounds)?;
+        Ok(CString::new(&rest[..len]).expect("string contains a nul"))
+    }
+
+    /// Write `string` to the instance's memory at `offset`, followed by a nul.
+    pub fn write_c_str(&mut self, offset: u32, string: &CStr) -> Result<(), ExecutionError> {
+        self.write_memory(offset, string.to_bytes_with_nul())
+    }
+
+    pub fn disassemble(&self) {
+        self.module.disassemble();
+    }
+
+    /// How many operators of each class this instance has run. See
+    /// `CodeGenOptions::profile_operators`.
+    pub fn operator_counts(&self) -> OperatorCounts {
+        unsafe { (*(self.context.as_ptr() as *const VmCtx)).operator_counts() }
+    }
+
+    /// Set every operator count back to 0.
+    pub fn reset_operator_counts(&mut self) {
+        self.context.get_mut().operator_counts = [0; OperatorClass::COUNT];
+    }
+
+    pub fn code_section(&self) -> &TranslatedCodeSection {
+        self.module
+            .translated_code_section
+            .as_ref()
+            .expect("no code section")
+    }
+}
+
+pub(crate) struct BoxSlice<T> {
+    pub(crate) len: usize,
+    pub(crate) ptr: *mut T,
+}
+
+impl<T> From<Box<[T]>> for BoxSlice<T> {
+    fn from(mut other: Box<[T]>) -> Self {
+        let out = BoxSlice {
+            len: other.len(),
+            ptr: other.as_mut_ptr(),
+        };
+        mem::forget(other);
+        out
+    }
+}
+
+unsafe impl<T: Send> Send for BoxSlice<T> {}
+unsafe impl<T: Sync> Sync for BoxSlice<T> {}
+
+impl<T> BoxSlice<T> {
+    pub(crate) fn into_vec(self) -> Vec<T> {
+        let out = unsafe { Vec::from_raw_parts(self.ptr, self.len, self.len) };
+        mem::forget(self);
+        out
+    }
+}
+
+impl<T> std::ops::Deref for BoxSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T> std::ops::DerefMut for BoxSlice<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T> Drop for BoxSlice<T> {
+    fn drop(&mut self) {
+        unsafe { Vec::from_raw_parts(self.ptr, self.len, self.len) };
+    }
+}
+
+/// A function reference as stored in a table, laid out the way that `call_indirect` expects.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RuntimeFunc {
+    func_start: *const u8,
+    vmctx: *const u8,
+    sig_id: u32,
+}
+
+// Function references only point at code and at contexts, neither of which are mutated
+// through them.
+unsafe impl Send for RuntimeFunc {}
+unsafe impl Sync for RuntimeFunc {}
+
+impl RuntimeFunc {
+    pub(crate) const NULL: RuntimeFunc = RuntimeFunc {
+        func_start: ptr::null(),
+        vmctx: ptr::null(),
+        sig_id: signatures::NULL_SIG_ID,
+    };
+
+    pub(crate) fn is_null(&self) -> bool {
+        self.sig_id == signatures::NULL_SIG_ID
+    }
+
+    pub fn offset_of_func_start() -> u8 {
+        offset_of!(RuntimeFunc, func_start)
+            .try_into()
+            .expect("Offset exceeded size of u8")
+    }
+
+    pub fn offset_of_vmctx() -> u8 {
+        offset_of!(RuntimeFunc, vmctx)
+            .try_into()
+            .expect("Offset exceeded size of u8")
+    }
+
+    pub fn offset_of_sig_id() -> u8 {
+        offset_of!(RuntimeFunc, sig_id)
+            .try_into()
+            .expect("Offset exceeded size of u8")
+    }
+}
+
+/// Linear memory allocated by the embedder, which instances can import. The embedder can
+/// keep using the memory through `as_ptr`, but mustn't access it while wasm code is running
+/// in an instance that imports it.
+pub struct HostMemory {
+    definition: MemoryDefinition,
+    /// The memory, if it was allocated by `HostMemory::new` or `HostMemory::guarded`.
+    owned: Option<LinearMemory>,
+}
+
+// The memory is only accessed through instances, which already require synchronization, or
+// by the embedder through a raw pointer.
+unsafe impl Send for HostMemory {}
+unsafe impl Sync for HostMemory {}
+
+impl HostMemory {
+    /// Allocate zeroed memory of the given number of wasm pages.
+    pub fn new(pages: u32) -> Self {
+        Self::with_style(MemoryStyle::BoundsChecked, pages).expect("Failed to allocate memory")
+    }
+
+    /// Allocate zeroed memory of the given number of wasm pages surrounded by guard pages,
+    /// which modules translated with `MemoryStyle::GuardPages` require. Returns `None` if the
+    /// address space can't be reserved.
+    pub fn guarded(pages: u32) -> Option<Self> {
+        Self::with_style(MemoryStyle::GuardPages, pages)
+    }
+
+    fn with_style(style: MemoryStyle, pages: u32) -> Option<Self> {
+        let mut memory = LinearMemory::new(style, pages as usize * WASM_PAGE_SIZE)?;
+        Some(HostMemory {
+            definition: MemoryDefinition {
+                len: memory.len(),
+                ptr: memory.as_mut_ptr(),
+            },
+            owned: Some(memory),
+        })
+    }
+
+    /// Use an existing buffer as memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes of `len` bytes for as long as the
+    /// `HostMemory` exists.
+    pub unsafe fn from_raw_parts(ptr: *mut u8, len: usize) -> Self {
+        HostMemory {
+            definition: MemoryDefinition { len, ptr },
+            owned: None,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.definition.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.definition.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How the memory was allocated, if it was allocated by us.
+    fn style(&self) -> Option<MemoryStyle> {
+        self.owned.as_ref().map(LinearMemory::style)
+    }
+}
+
+/// A global allocated by the embedder, which instances can import. Every instance that
+/// imports the same `HostGlobal` reads and writes the same value, so it can be used to share
+/// state such as a stack pointer between modules that are linked together.
+pub struct HostGlobal {
+    ty: GlobalType,
+    /// The bits of the value, zero-extended to 64 bits. Generated code reads and writes this
+    /// directly.
+    value: AtomicU64,
+}
+
+impl HostGlobal {
+    /// A global of type `content_type` holding `bits`, which for floats are the bits of the
+    /// value. Wasm code can only set it if it's `mutable` and the module imports it as mutable.
+    pub fn new(content_type: ValueType, mutable: bool, bits: u64) -> Self {
+        HostGlobal {
+            ty: GlobalType {
+                content_type,
+                mutable,
+            },
+            value: AtomicU64::new(bits),
+        }
+    }
+
+    pub fn content_type(&self) -> ValueType {
+        self.ty.content_type
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.ty.mutable
+    }
+
+    /// The bits of the value. Only the low 32 bits are meaningful for 32-bit types.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Set the value, which instances that import the global see the next time that they read
+    /// it. This can be done whatever the mutability of the global is.
+    pub fn set(&self, bits: u64) {
+        self.value.store(bits, Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for HostGlobal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostGlobal")
+            .field("ty", &self.ty)
+            .field("value", &self.get())
+            .finish()
+    }
+}
+
+/// Functions, memories and globals that instances can import, keyed by import module and
+/// field name. They're resolved against the module's imports when it's instantiated with
+/// `Instance::with_imports` or `Instance::link`.
+#[derive(Default, Clone)]
+pub struct InstanceImports {
+    funcs: HashMap<(String, String), HostFunc>,
+    memories: HashMap<(String, String), Arc<HostMemory>>,
+    globals: HashMap<(String, String), Arc<HostGlobal>>,
+}
+
+impl InstanceImports {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `func` importable as `module.field`. Unlike functions registered with
+    /// `TranslateOptions::host_functions`, which are bound when the module is translated, this
+    /// can give each instance of a module a different function. Calls to it go through an exit
+    /// stub either way, and if it returns an error or panics, the wasm code that called it
+    /// traps.
+    pub fn register_func<Args, T>(
+        &mut self,
+        module: &str,
+        field: &str,
+        func: impl IntoHostFunc<Args, T>,
+    ) -> &mut Self {
+        self.funcs.insert(
+            (module.to_string(), field.to_string()),
+            func.into_host_func(),
+        );
+        self
+    }
+
+    pub fn func(&self, module: &str, field: &str) -> Option<&HostFunc> {
+        self.funcs.get(&(module.to_string(), field.to_string()))
+    }
+
+    /// The function registered as `module.field`, if it has the type `ty` that the module
+    /// imports it with.
+    fn linked_func(&self, module: &str, field: &str, ty: &FuncType) -> Result<HostFunc, Error> {
+        let func = self
+            .func(module, field)
+            .ok_or_else(|| unresolved_import(module, field, "function"))?;
+        if (&ty.params[..], &ty.returns[..]) != (func.params, func.returns) {
+            return Err(incompatible_import(
+                module,
+                field,
+                format!(
+                    "The module imports it with type {:?} -> {:?} but the host function has \
+                     type {:?} -> {:?}",
+                    ty.params, ty.returns, func.params, func.returns
+                ),
+            ));
+        }
+        Ok(func.clone())
+    }
+
+    /// Make `memory` importable as `module.field`.
+    pub fn register_memory(
+        &mut self,
+        module: &str,
+        field: &str,
+        memory: Arc<HostMemory>,
+    ) -> &mut Self {
+        self.memories
+            .insert((module.to_string(), field.to_string()), memory);
+        self
+    }
+
+    pub fn memory(&self, module: &str, field: &str) -> Option<&Arc<HostMemory>> {
+        self.memories.get(&(module.to_string(), field.to_string()))
+    }
+
+    /// Make `global` importable as `module.field`. Registering the same global with several
+    /// `InstanceImports`, or using them for several instances, shares it between them.
+    pub fn register_global(
+        &mut self,
+        module: &str,
+        field: &str,
+        global: Arc<HostGlobal>,
+    ) -> &mut Self {
+        self.globals
+            .insert((module.to_string(), field.to_string()), global);
+        self
+    }
+
+    pub fn global(&self, module: &str, field: &str) -> Option<&Arc<HostGlobal>> {
+        self.globals.get(&(module.to_string(), field.to_string()))
+    }
+}
+
+impl fmt::Debug for InstanceImports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                self.funcs
+                    .keys()
+                    .chain(self.memories.keys())
+                    .chain(self.globals.keys())
+                    .map(|(module, field)| format!("{}.{}", module, field)),
+            )
+            .finish()
+    }
+}
+
+fn unresolved_import(module: &str, field: &str, kind: &'static str) -> Error {
+    Error::UnresolvedImport {
+        module: module.to_string(),
+        field: field.to_string(),
+        kind,
+    }
+}
+
+fn incompatible_import(module: &str, field: &str, reason: String) -> Error {
+    Error::IncompatibleImport {
+        module: module.to_string(),
+        field: field.to_string(),
+        reason,
+    }
+}
+
+mod builtins {
+    use super::{BuiltinFunction, RuntimeFunc, VmCtx};
+    use crate::index_space::DefinedFuncIndex;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        ptr,
+    };
+
+    /// Indexed by `BuiltinFunction`.
+    pub const ADDRESSES: [*const u8; BuiltinFunction::COUNT] = [
+        table_get as *const u8,
+        table_set as *const u8,
+        table_grow as *const u8,
+        compile_lazily as *const u8,
+        memory_grow as *const u8,
+        memory_grow_64 as *const u8,
+        f32_ceil as *const u8,
+        f32_floor as *const u8,
+        f32_nearest as *const u8,
+        f32_trunc as *const u8,
+        f64_ceil as *const u8,
+        f64_floor as *const u8,
+        f64_nearest as *const u8,
+        f64_trunc as *const u8,
+        i32_popcnt as *const u8,
+        i64_popcnt as *const u8,
+    ];
+
+    pub unsafe extern "sysv64" fn table_get(vmctx: *const VmCtx, index: u32) -> u64 {
+        let vmctx = &*vmctx;
+        vmctx.func_ref_value(vmctx.table[index as usize])
+    }
+
+    pub unsafe extern "sysv64" fn table_set(vmctx: *mut VmCtx, index: u32, value: u64) {
+        let vmctx = &mut *vmctx;
+        vmctx.table[index as usize] = if value == 0 {
+            RuntimeFunc::NULL
+        } else {
+            *(value as *const RuntimeFunc)
+        };
+    }
+
+    /// Returns -1 if the table can't grow, like `memory.grow`.
+    pub unsafe extern "sysv64" fn table_grow(vmctx: *mut VmCtx, delta: u32) -> u32 {
+        let vmctx = &mut *vmctx;
+        vmctx
+            .grow_table(delta, RuntimeFunc::NULL)
+            .unwrap_or(u32::max_value())
+    }
+
+    /// Returns -1 if the memory can't grow. A growth callback that panics denies growth.
+    pub unsafe extern "sysv64" fn memory_grow(vmctx: *mut VmCtx, delta: u32) -> u32 {
+        let vmctx = &mut *vmctx;
+        panic::catch_unwind(AssertUnwindSafe(|| vmctx.grow_memory(delta)))
+            .ok()
+            .and_then(|old_pages| old_pages)
+            .unwrap_or(u32::max_value())
+    }
+
+    /// `memory_grow` for memories indexed with `i64`. Memory can't be bigger than
+    /// `MAX_WASM_PAGES`, so a delta that doesn't fit in 32 bits never succeeds.
+    pub unsafe extern "sysv64" fn memory_grow_64(vmctx: *mut VmCtx, delta: u64) -> u64 {
+        if delta > u64::from(u32::max_value()) {
+            return u64::max_value();
+        }
+
+        match memory_grow(vmctx, delta as u32) {
+            old_pages if old_pages == u32::max_value() => u64::max_value(),
+            old_pages => u64::from(old_pages),
+        }
+    }
+
+    macro_rules! float_builtins {
+        ($($ty:ident { $ceil:ident, $floor:ident, $nearest:ident, $trunc:ident })*) => {
+            $(
+                pub extern "sysv64" fn $ceil(_vmctx: *const VmCtx, x: $ty) -> $ty {
+                    x.ceil()
+                }
+
+                pub extern "sysv64" fn $floor(_vmctx: *const VmCtx, x: $ty) -> $ty {
+                    x.floor()
+                }
+
+                /// Rounds to the nearest integer, with ties going to the even one, unlike
+                /// `round`, which rounds them away from zero.
+                pub extern "sysv64" fn $nearest(_vmctx: *const VmCtx, x: $ty) -> $ty {
+                    let rounded = x.round();
+                    if (x - x.trunc()).abs() == 0.5 && rounded % 2.0 != 0.0 {
+                        (rounded - x.signum()).copysign(x)
+                    } else {
+                        rounded
+                    }
+                }
+
+                pub extern "sysv64" fn $trunc(_vmctx: *const VmCtx, x: $ty) -> $ty {
+                    x.trunc()
+                }
+            )*
+        };
+    }
+
+    float_builtins! {
+        f32 { f32_ceil, f32_floor, f32_nearest, f32_trunc }
+        f64 { f64_ceil, f64_floor, f64_nearest, f64_trunc }
+    }
+
+    pub extern "sysv64" fn i32_popcnt(_vmctx: *const VmCtx, x: u32) -> u32 {
+        x.count_ones()
+    }
+
+    pub extern "sysv64" fn i64_popcnt(_vmctx: *const VmCtx, x: u64) -> u64 {
+        u64::from(x.count_ones())
+    }
+
+    /// Returns null if the function can't be translated, which makes its stub trap. We
+    /// mustn't unwind into wasm code, so panics are treated the same way.
+    pub unsafe extern "sysv64" fn compile_lazily(vmctx: *const VmCtx, func_idx: u32) -> *const u8 {
+        let module = &*(*vmctx).module;
+        let func_idx = DefinedFuncIndex(func_idx);
+        match panic::catch_unwind(AssertUnwindSafe(|| module.translate_lazily(func_idx))) {
+            Ok(Ok(start)) => start,
+            Ok(Err(e)) => {
+                log_debug!("Failed to translate function {}: {}", func_idx, e);
+                ptr::null()
+            }
+            Err(_) => ptr::null(),
+        }
+    }
+}
+
+/// Emits inline code for a call to an imported function. The call's arguments are on top of
+/// the value stack and the lowering must replace them with the function's results, usually by
+/// calling the `Context` methods for the operators that implement it.
+pub type IntrinsicLowering = for<'a, 'b> fn(&'a mut Context<'b, SimpleContext>);
+
+/// Imported functions that should be compiled to inline code rather than calls, such as
+/// `math.sqrt` lowered to a single `sqrtsd`.
+#[derive(Default, Clone)]
+pub struct Intrinsics {
+    lowerings: HashMap<(String, String), IntrinsicLowering>,
+}
+
+impl Intrinsics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lower calls to the function imported as `module.field` with `lowering`. The lowering
+    /// must match the import's declared type, which isn't known until the module is read.
+    pub fn register(
+        &mut self,
+        module: &str,
+        field: &str,
+        lowering: IntrinsicLowering,
+    ) -> &mut Self {
+        self.lowerings
+            .insert((module.to_string(), field.to_string()), lowering);
+        self
+    }
+
+    pub fn get(&self, module: &str, field: &str) -> Option<IntrinsicLowering> {
+        self.lowerings
+            .get(&(module.to_string(), field.to_string()))
+            .cloned()
+    }
+}
+
+impl fmt::Debug for Intrinsics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                self.lowerings
+                    .keys()
+                    .map(|(module, field)| format!("{}.{}", module, field)),
+            )
+            .finish()
+    }
+}
+
+/// Options for translating a whole module.
+#[derive(Debug, Default, Clone)]
+pub struct TranslateOptions {
+    pub codegen: CodeGenOptions,
+    pub memory_style: MemoryStyle,
+    /// Imports that are lowered inline. These take precedence over host functions.
+    pub intrinsics: Intrinsics,
+    pub host_functions: HostFunctions,
+    /// Functions that implement operators in place of Lightbeam's own code for them.
+    pub operator_libcalls: OperatorLibcalls,
+    /// The functions that generated code calls into the runtime for.
+    pub libcalls: Libcalls,
+    pub metrics: Option<Arc<dyn CompilationMetrics>>,
+    /// Emit a stub for each function that translates it when it's first called, so that
+    /// loading a module only has to validate it. Functions that are never called are never
+    /// translated. This can't be combined with `CodeGenOptions::debug`.
+    pub lazy: bool,
+    /// Index the module's memory with `i64` rather than `i32`, as in the memory64 proposal,
+    /// so that addresses, `memory.size` and `memory.grow` are all `i64`. The binary format
+    /// that we can parse has no way to mark a memory as 64-bit, so this applies to every
+    /// memory in the module. `wasmparser` can't validate such modules itself, so they're
+    /// validated in the form of an equivalent module with 32-bit memories, and offsets in
+    /// validation errors are offsets in that module. Accesses to 64-bit memories are always
+    /// bounds-checked, whatever the `memory_style`.
+    pub memory64: bool,
+    /// Compile bounds checks for a defined memory whose minimum and maximum are equal against
+    /// its size as a constant, leaving them out for constant addresses that are in bounds.
+    /// The code this generates assumes that the memory never changes size, so an embedder
+    /// with its own runtime must not grow it past its maximum.
+    pub fixed_size_memory: bool,
+    /// Reject modules that use a float operator whose result can be a NaN, since which NaN it
+    /// is depends on the hardware and Lightbeam doesn't canonicalize them. What's left gives
+    /// bit-for-bit the same results on every machine, for embedders that need full
+    /// determinism. Moving, comparing and converting floats and `abs`, `neg` and `copysign`,
+    /// which only change the sign bit, are still allowed.
+    pub deterministic_floats: bool,
+    /// Don't generate code for functions that can't be reached from the module's exports,
+    /// its start function or its element segments, as found by its `CallGraph`. They're
+    /// given a body that traps instead, so calling one by its index with
+    /// `Instance::execute_func` traps as if it were `unreachable`. Functions translated
+    /// `lazy`ily are only translated once they're called anyway, so this has no effect then.
+    pub elide_dead_functions: bool,
 }
 
-pub struct ExecutableModule {
-    module: TranslatedModule,
-    context: Option<Box<VmCtx>>,
+/// Functions that implement wasm operators in place of Lightbeam's own code for them, keyed
+/// by the operator's name in the text format, such as `f32.nearest`. This lets modules use
+/// operators that Lightbeam can't compile yet, like `i32.trunc_sat_f32_s`. Only numeric
+/// operators, which have no immediates, can be replaced, and names of other operators are
+/// ignored. Calls are left out when their result isn't used, like any other numeric operator.
+#[derive(Default, Clone)]
+pub struct OperatorLibcalls {
+    funcs: HashMap<String, usize>,
 }
 
-impl ExecutableModule {
-    /// Executes the function _without checking types_. This can cause undefined
-    /// memory to be accessed.
-    pub unsafe fn execute_func_unchecked<Args: FunctionArgs<T>, T>(
-        &self,
-        func_idx: u32,
-        args: Args,
-    ) -> T {
-        let code_section = self
-            .module
-            .translated_code_section
-            .as_ref()
-            .expect("no code section");
-        let start_buf = code_section.func_start(func_idx as usize);
+impl OperatorLibcalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        args.call(
-            Args::into_func(start_buf),
-            self.context
-                .as_ref()
-                .map(|ctx| (&**ctx) as *const VmCtx as *const u8)
-                .unwrap_or(std::ptr::null()),
-        )
+    /// Implement the operator `name` with `func`, which is called with the `VmCtx` followed by
+    /// the operator's operands and returns its result, using the System V calling convention.
+    /// `i32.trunc_sat_f32_s` is implemented by an `extern "sysv64" fn(*const VmCtx, f32) -> i32`,
+    /// for example. It can't unwind or trap.
+    ///
+    /// # Safety
+    ///
+    /// `func` must have the operator's signature and stay valid for as long as any code that
+    /// calls it.
+    pub unsafe fn register(&mut self, name: &str, func: *const u8) -> &mut Self {
+        self.funcs.insert(name.to_string(), func as usize);
+        self
     }
 
-    pub fn execute_func<Args: FunctionArgs<T> + TypeList, T: TypeList>(
-        &self,
-        func_idx: u32,
-        args: Args,
-    ) -> Result<T, ExecutionError> {
-        let module = &self.module;
+    pub fn get(&self, name: &str) -> Option<*const u8> {
+        self.funcs.get(name).map(|&func| func as *const u8)
+    }
+}
 
-        if func_idx as usize >= module.ctx.func_ty_indicies.len() {
-            return Err(ExecutionError::FuncIndexOutOfBounds);
-        }
+impl fmt::Debug for OperatorLibcalls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.funcs.keys()).finish()
+    }
+}
 
-        let type_ = module.ctx.func_type(func_idx);
+/// The functions that generated code calls into the runtime for, indexed by
+/// `BuiltinFunction`. Code calls them by address, or through the copy in its instance's
+/// `VmCtx` if it's deterministic. The math functions, which implement the float rounding
+/// operators and `popcnt` on CPUs without it, can be replaced with the typed setters, for
+/// example to use the embedder's libm. The others work on the runtime's own data structures,
+/// like the table and memory, and can't be.
+#[derive(Clone, Debug)]
+pub struct Libcalls {
+    addresses: [usize; BuiltinFunction::COUNT],
+}
 
-        // TODO: Handle "compatible" types (i.e. f32 and i32)
-        if (&type_.params[..], &type_.returns[..]) != (Args::TYPE_LIST, T::TYPE_LIST) {
-            return Err(ExecutionError::TypeMismatch);
+impl Default for Libcalls {
+    fn default() -> Self {
+        let mut addresses = [0; BuiltinFunction::COUNT];
+        for (address, &builtin) in addresses.iter_mut().zip(&builtins::ADDRESSES) {
+            *address = builtin as usize;
         }
+        Libcalls { addresses }
+    }
+}
 
-        Ok(unsafe { self.execute_func_unchecked(func_idx, args) })
+impl Libcalls {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn disassemble(&self) {
-        self.module.disassemble();
+    pub fn get(&self, builtin: BuiltinFunction) -> *const u8 {
+        self.addresses[builtin as usize] as *const u8
+    }
+
+    fn addresses(&self) -> [*const u8; BuiltinFunction::COUNT] {
+        let mut addresses = [ptr::null(); BuiltinFunction::COUNT];
+        for (address, &builtin) in addresses.iter_mut().zip(&self.addresses) {
+            *address = builtin as *const u8;
+        }
+        addresses
     }
 }
 
-struct BoxSlice<T> {
-    len: usize,
-    ptr: *mut T,
+macro_rules! libcall_setters {
+    ($($setter:ident: $builtin:ident, $ty:ty;)*) => {
+        impl Libcalls {
+            $(
+                pub fn $setter(
+                    &mut self,
+                    func: extern "sysv64" fn(*const VmCtx, $ty) -> $ty,
+                ) -> &mut Self {
+                    self.addresses[BuiltinFunction::$builtin as usize] = func as usize;
+                    self
+                }
+            )*
+        }
+    };
 }
 
-impl<T> From<Box<[T]>> for BoxSlice<T> {
-    fn from(mut other: Box<[T]>) -> Self {
-        let out = BoxSlice {
-            len: other.len(),
-            ptr: other.as_mut_ptr(),
-        };
-        mem::forget(other);
-        out
-    }
+libcall_setters! {
+    set_f32_ceil: F32Ceil, f32;
+    set_f32_floor: F32Floor, f32;
+    set_f32_nearest: F32Nearest, f32;
+    set_f32_trunc: F32Trunc, f32;
+    set_f64_ceil: F64Ceil, f64;
+    set_f64_floor: F64Floor, f64;
+    set_f64_nearest: F64Nearest, f64;
+    set_f64_trunc: F64Trunc, f64;
+    set_i32_popcnt: I32Popcnt, u32;
+    set_i64_popcnt: I64Popcnt, u64;
 }
 
-unsafe impl<T: Send> Send for BoxSlice<T> {}
-unsafe impl<T: Sync> Sync for BoxSlice<T> {}
+/// Host functions that wasm modules can import, keyed by import module and field name.
+#[derive(Default, Clone)]
+pub struct HostFunctions {
+    funcs: HashMap<(String, String), HostFunc>,
+}
 
-impl<T> Drop for BoxSlice<T> {
-    fn drop(&mut self) {
-        unsafe { Vec::from_raw_parts(self.ptr, self.len, self.len) };
+impl HostFunctions {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
 
-pub struct VmCtx {
-    mem: BoxSlice<u8>,
-}
+    /// Make `func` importable as `module.field`. If it returns an error or panics, the wasm
+    /// code that called it traps.
+    pub fn register<Args, T>(
+        &mut self,
+        module: &str,
+        field: &str,
+        func: impl IntoHostFunc<Args, T>,
+    ) -> &mut Self {
+        self.funcs.insert(
+            (module.to_string(), field.to_string()),
+            func.into_host_func(),
+        );
+        self
+    }
 
-impl VmCtx {
-    pub fn offset_of_memory_ptr() -> u32 {
-        offset_of!(VmCtx, mem.ptr)
-            .try_into()
-            .expect("Offset exceeded size of u32")
+    pub fn get(&self, module: &str, field: &str) -> Option<&HostFunc> {
+        self.funcs.get(&(module.to_string(), field.to_string()))
     }
+}
 
-    pub fn offset_of_memory_len() -> u32 {
-        offset_of!(VmCtx, mem.len)
-            .try_into()
-            .expect("Offset exceeded size of u32")
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                self.funcs
+                    .keys()
+                    .map(|(module, field)| format!("{}.{}", module, field)),
+            )
+            .finish()
     }
 }
 
-#[derive(Default, Debug)]
+/// How calls to an imported function are compiled.
+#[derive(Clone)]
+enum FuncImport {
+    Intrinsic(IntrinsicLowering),
+    /// Called through an exit stub.
+    Host(HostFunc),
+    /// Called through an exit stub like `Host`, but the host function is only given when the
+    /// module is instantiated, by `InstanceImports::register_func`.
+    Linked,
+}
+
+#[derive(Default)]
 pub struct SimpleContext {
     types: Vec<FuncType>,
+    /// The process-wide signature id of each of `types`.
+    sig_ids: Vec<u32>,
+    /// The type of every function, imported functions first.
     func_ty_indicies: Vec<u32>,
+    imports: IndexVec<ImportedFuncIndex, FuncImport>,
+    imported_memories: u32,
+    /// The type of every global, imported globals first.
+    globals: Vec<GlobalType>,
+    imported_globals: u32,
+    /// The initial value of every defined global.
+    global_inits: Vec<GlobalInit>,
+    data_count: Option<u32>,
+    memory_style: MemoryStyle,
+    memory64: bool,
+    operator_libcalls: OperatorLibcalls,
+    libcalls: Libcalls,
+    /// The size in pages of the defined memory, if it can't grow and bounds checks may rely on
+    /// that.
+    fixed_memory_pages: Option<u32>,
+    func_names: HashMap<u32, String>,
+    branch_hints: BranchHints,
+}
+
+impl fmt::Debug for SimpleContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleContext")
+            .field("types", &self.types)
+            .field("sig_ids", &self.sig_ids)
+            .field("func_ty_indicies", &self.func_ty_indicies)
+            .field("imported_funcs", &self.imports.len())
+            .field("imported_memories", &self.imported_memories)
+            .field("globals", &self.globals)
+            .field("imported_globals", &self.imported_globals)
+            .field("global_inits", &self.global_inits)
+            .field("data_count", &self.data_count)
+            .field("memory_style", &self.memory_style)
+            .field("memory64", &self.memory64)
+            .field("operator_libcalls", &self.operator_libcalls)
+            .field("libcalls", &self.libcalls)
+            .field("fixed_memory_pages", &self.fixed_memory_pages)
+            .field("func_names", &self.func_names)
+            .field("branch_hints", &self.branch_hints)
+            .finish()
+    }
+}
+
+impl SimpleContext {
+    /// Read the types, functions, tables, memories and globals of the wasm module in `data`,
+    /// without translating any code, for embedders that translate functions themselves with
+    /// `translate_function`. Imported functions are resolved with `options` just as when
+    /// the module is translated, so they must be intrinsics or host functions.
+    pub fn new(data: &[u8], options: &TranslateOptions) -> Result<Self, Error> {
+        Ok(translate_module(data, options.clone(), false)?.ctx)
+    }
+
+    /// Where the parts of an instance's `VmCtx` that depend on the module are.
+    pub fn vmctx_layout(&self) -> VmCtxLayout {
+        VmCtxLayout {
+            num_imported_funcs: self.imports.len() as u32,
+            num_imported_globals: self.imported_globals,
+            num_defined_globals: self.global_inits.len() as u32,
+            num_types: self.types.len() as u32,
+        }
+    }
+
+    pub(crate) fn defined_func_count(&self) -> u32 {
+        (self.func_ty_indicies.len() - self.imports.len()) as u32
+    }
+
+    /// The parameters of each imported host function, which each need an exit stub.
+    pub(crate) fn host_import_params(
+        &self,
+    ) -> impl Iterator<Item = (ImportedFuncIndex, &[ValueType])> + '_ {
+        self.imports
+            .iter()
+            .filter_map(move |(index, import)| match import {
+                FuncImport::Host(func) => Some((index, func.params)),
+                FuncImport::Linked => Some((index, &self.func_type(index.0).params[..])),
+                FuncImport::Intrinsic(_) => None,
+            })
+    }
+
+    /// The number of data segments declared by the DataCount section, if there is one.
+    pub fn data_count(&self) -> Option<u32> {
+        self.data_count
+    }
 }
 
 pub const WASM_PAGE_SIZE: usize = 65_536;
+/// The most pages that a 32-bit memory can have.
+pub const MAX_WASM_PAGES: u32 = 65_536;
 
+/// A function signature, as a `ModuleContext` represents them. Implemented for our own
+/// signatures and Cranelift's.
 pub trait Signature {
     type Type: SigType;
 
@@ -250,6 +2199,7 @@ pub trait Signature {
     fn returns(&self) -> &[Self::Type];
 }
 
+/// A value type in a `Signature`.
 pub trait SigType {
     fn to_microwasm_type(&self) -> microwasm::SignlessType;
 }
@@ -299,14 +2249,19 @@ impl Signature for CraneliftSignature {
     }
 }
 
-impl SigType for wasmparser::Type {
+impl SigType for ValueType {
     fn to_microwasm_type(&self) -> microwasm::SignlessType {
-        microwasm::Type::from_wasm(*self).unwrap()
+        match self {
+            ValueType::I32 => microwasm::I32,
+            ValueType::I64 => microwasm::I64,
+            ValueType::F32 => microwasm::F32,
+            ValueType::F64 => microwasm::F64,
+        }
     }
 }
 
 impl Signature for FuncType {
-    type Type = wasmparser::Type;
+    type Type = ValueType;
 
     fn params(&self) -> &[Self::Type] {
         &*self.params
@@ -317,125 +2272,323 @@ impl Signature for FuncType {
     }
 }
 
+/// Operations that generated code calls into the runtime for, rather than emitting inline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BuiltinFunction {
+    /// `table.get`, given an index that's already been bounds-checked.
+    TableGet,
+    /// `table.set`, given an index that's already been bounds-checked.
+    TableSet,
+    TableGrow,
+    /// Translate a function of a module translated with `TranslateOptions::lazy`, given its
+    /// index among the module's defined functions. Returns the address of its code, or null
+    /// if it couldn't be translated.
+    CompileLazily,
+    /// `memory.grow`, which returns the memory's previous size in pages or -1 if it can't
+    /// grow. The memory's definition is updated to its new base and length.
+    MemoryGrow,
+    /// `memory.grow` for a memory indexed with `i64`, which returns `u64::MAX` if it can't
+    /// grow.
+    MemoryGrow64,
+    F32Ceil,
+    F32Floor,
+    F32Nearest,
+    F32Trunc,
+    F64Ceil,
+    F64Floor,
+    F64Nearest,
+    F64Trunc,
+    /// `i32.popcnt`, for CPUs that don't have the `popcnt` instruction.
+    I32Popcnt,
+    /// `i64.popcnt`, for CPUs that don't have the `popcnt` instruction.
+    I64Popcnt,
+}
+
+impl BuiltinFunction {
+    pub const COUNT: usize = 16;
+}
+
+/// Everything that translating a function needs to know about the module that it's in and
+/// about the runtime that it runs in.
+///
+/// Index spaces are the module's: imports come first, then what the module defines. The
+/// `vmctx_*` methods give the offsets from the `VmCtx` pointer, which generated code keeps in
+/// `rdi`, of the runtime's data structures, and the `vm*` methods the offsets of fields within
+/// them. The runtime decides how those are laid out, so an embedder with its own runtime
+/// implements this trait to describe it and translates functions with `translate_function`.
+/// `SimpleContext` describes Lightbeam's own runtime, around `VmCtx` and `Instance`.
 pub trait ModuleContext {
     type Signature: Signature;
     type GlobalType: SigType;
 
+    /// Where a defined global's value is kept.
     fn vmctx_vmglobal_definition(&self, index: u32) -> u32;
+    /// Where the address of an imported global's value is kept.
     fn vmctx_vmglobal_import_from(&self, index: u32) -> u32;
+    /// Where the address of an imported memory's definition is kept.
     fn vmctx_vmmemory_import_from(&self, memory_index: u32) -> u32;
+    /// Where a defined memory's definition, holding its base and length, is kept.
     fn vmctx_vmmemory_definition(&self, defined_memory_index: u32) -> u32;
+    /// Where a defined memory's base address is kept.
     fn vmctx_vmmemory_definition_base(&self, defined_memory_index: u32) -> u32;
+    /// Where a defined memory's current length in bytes is kept.
     fn vmctx_vmmemory_definition_current_length(&self, defined_memory_index: u32) -> u32;
+    /// The offset of the base address within a memory's definition.
     fn vmmemory_definition_base(&self) -> u8;
+    /// The offset of the current length within a memory's definition.
     fn vmmemory_definition_current_length(&self) -> u8;
+    /// Where the address of an imported table's definition is kept.
     fn vmctx_vmtable_import_from(&self, table_index: u32) -> u32;
+    /// Where a defined table's definition, holding its base and length, is kept.
     fn vmctx_vmtable_definition(&self, defined_table_index: u32) -> u32;
+    /// The address of the function that implements `builtin`. It's called with the `VmCtx`
+    /// followed by the instruction's operands, and with a stack aligned for Rust code.
+    fn builtin_function(&self, builtin: BuiltinFunction) -> *const u8;
+    /// Where the `VmCtx` holds the address of `builtin`, for code that mustn't embed it.
+    fn vmctx_builtin_function(&self, builtin: BuiltinFunction) -> u32;
+    /// Where the `VmCtx` holds the number of operators in `class` that have run.
+    fn vmctx_operator_count(&self, class: OperatorClass) -> u32;
+    /// Where the `VmCtx` holds the stack pointer that trapping code returns to, and where
+    /// the code stores why it trapped before returning, or `None` if traps should always
+    /// execute `ud2`. A stack pointer of 0 means that there's nothing to return to.
+    fn vmctx_trap_return(&self) -> Option<(u32, u32)> {
+        None
+    }
+    /// Where a defined table's base address is kept.
     fn vmctx_vmtable_definition_base(&self, defined_table_index: u32) -> u32;
+    /// Where a defined table's current number of elements is kept.
     fn vmctx_vmtable_definition_current_elements(&self, defined_table_index: u32) -> u32;
+    /// Where the address of an imported function's code is kept.
     fn vmctx_vmfunction_import_body(&self, func_index: u32) -> u32;
+    /// Where the context that an imported function is called with in place of the `VmCtx`
+    /// is kept.
     fn vmctx_vmfunction_import_vmctx(&self, func_index: u32) -> u32;
+    /// The offset of the base address within a table's definition.
     fn vmtable_definition_base(&self) -> u8;
+    /// The offset of the current number of elements within a table's definition.
     fn vmtable_definition_current_elements(&self) -> u8;
+    /// Where the runtime's id for the signature with the given type index is kept. Table
+    /// elements with the same id have the same signature, which `call_indirect` checks.
     fn vmctx_vmshared_signature_id(&self, signature_idx: u32) -> u32;
+    /// The offset of the signature id within a table element.
     fn vmcaller_checked_anyfunc_type_index(&self) -> u8;
+    /// The offset of the function's address within a table element.
     fn vmcaller_checked_anyfunc_func_ptr(&self) -> u8;
+    /// The offset of the context that the function is called with within a table element.
     fn vmcaller_checked_anyfunc_vmctx(&self) -> u8;
+    /// The size of a table element.
     fn size_of_vmcaller_checked_anyfunc(&self) -> u8;
 
+    /// The index of the table among those defined by the module, or `None` if it's imported.
     fn defined_table_index(&self, table_index: u32) -> Option<u32>;
+    /// The index of the memory among those defined by the module, or `None` if it's imported.
     fn defined_memory_index(&self, index: u32) -> Option<u32>;
 
+    /// The index of the global among those defined by the module, or `None` if it's imported.
     fn defined_global_index(&self, global_index: u32) -> Option<u32>;
+    /// The type of the given global's value.
     fn global_type(&self, global_index: u32) -> &Self::GlobalType;
 
+    /// The index into the Type section of the given function's signature.
     fn func_type_index(&self, func_idx: u32) -> u32;
+    /// The signature with the given index into the Type section.
     fn signature(&self, index: u32) -> &Self::Signature;
 
+    /// The index of the given defined function in the module's function index space.
     fn func_index(&self, defined_func_index: u32) -> u32;
+    /// The index of the function among those defined by the module, or `None` if it's
+    /// imported.
     fn defined_func_index(&self, func_index: u32) -> Option<u32>;
 
+    /// The signature of the given defined function.
     fn defined_func_type(&self, func_idx: u32) -> &Self::Signature {
-        // TODO: This assumes that there are no imported functions.
         self.func_type(self.func_index(func_idx))
     }
 
+    /// The signature of the given function in the module's function index space.
     fn func_type(&self, func_idx: u32) -> &Self::Signature {
         self.signature(self.func_type_index(func_idx))
     }
 
+    /// Whether the given function is imported or defined by this module.
+    fn func_kind(&self, func_index: FuncIndex) -> FuncKind {
+        match self.defined_func_index(func_index.0) {
+            Some(defined_index) => FuncKind::Defined(DefinedFuncIndex(defined_index)),
+            None => FuncKind::Imported(ImportedFuncIndex(func_index.0)),
+        }
+    }
+
+    /// Whether memory accesses have to be checked against the memory's length, rather than
+    /// relying on guard pages to catch them.
     fn emit_memory_bounds_check(&self) -> bool {
         true
     }
+
+    /// The type of the addresses used to access the given memory, which is `I64` for memories
+    /// from the memory64 proposal. Guard pages can't cover a 64-bit address space, so
+    /// accesses to those are bounds-checked even if `emit_memory_bounds_check` is `false`.
+    fn memory_index_type(&self, _memory_index: u32) -> microwasm::SignlessType {
+        microwasm::I32
+    }
+
+    /// The size in bytes of the given memory if it never changes, so that bounds checks can
+    /// compare against it as a constant rather than loading the memory's current length.
+    fn memory_fixed_size(&self, _memory_index: u32) -> Option<u64> {
+        None
+    }
+
+    /// The address of the function that the embedder registered to implement the numeric
+    /// operator `name`, such as `f32.nearest`, in place of Lightbeam's own code for it. It's
+    /// called like a builtin, with the `VmCtx` followed by the operator's operands. See
+    /// `OperatorLibcalls`.
+    fn operator_libcall(&self, _name: &str) -> Option<*const u8> {
+        None
+    }
+
+    /// The name of the given function, if the module has a name section that names it.
+    fn func_name(&self, _func_index: u32) -> Option<&str> {
+        None
+    }
+
+    /// The branch hints for the given function, if the module has any.
+    fn branch_hints(&self, _func_index: u32) -> Option<&FunctionBranchHints> {
+        None
+    }
+
+    /// Emit inline code for a call to the given imported function, returning `false` if it
+    /// should be called normally instead.
+    fn lower_intrinsic(&self, _index: ImportedFuncIndex, _ctx: &mut Context<Self>) -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
 }
 
 impl ModuleContext for SimpleContext {
     type Signature = FuncType;
-    type GlobalType = wasmparser::Type;
+    type GlobalType = ValueType;
 
-    // TODO: We don't support external functions yet
-    fn func_index(&self, func_idx: u32) -> u32 {
-        func_idx
+    fn func_index(&self, defined_func_index: u32) -> u32 {
+        defined_func_index + self.imports.len() as u32
     }
 
     fn defined_func_index(&self, func_idx: u32) -> Option<u32> {
-        Some(func_idx)
+        func_idx.checked_sub(self.imports.len() as u32)
+    }
+
+    fn emit_memory_bounds_check(&self) -> bool {
+        self.memory_style == MemoryStyle::BoundsChecked
+    }
+
+    fn memory_index_type(&self, _memory_index: u32) -> microwasm::SignlessType {
+        if self.memory64 {
+            microwasm::I64
+        } else {
+            microwasm::I32
+        }
+    }
+
+    fn memory_fixed_size(&self, memory_index: u32) -> Option<u64> {
+        self.defined_memory_index(memory_index)
+            .and(self.fixed_memory_pages)
+            .map(|pages| u64::from(pages) * WASM_PAGE_SIZE as u64)
+    }
+
+    fn operator_libcall(&self, name: &str) -> Option<*const u8> {
+        self.operator_libcalls.get(name)
+    }
+
+    fn func_name(&self, func_index: u32) -> Option<&str> {
+        self.func_names.get(&func_index).map(|name| &name[..])
+    }
+
+    fn branch_hints(&self, func_index: u32) -> Option<&FunctionBranchHints> {
+        self.branch_hints.func(func_index)
+    }
+
+    fn lower_intrinsic(&self, index: ImportedFuncIndex, ctx: &mut Context<Self>) -> bool {
+        match self.imports.get(index) {
+            Some(FuncImport::Intrinsic(lowering)) => {
+                lowering(ctx);
+                true
+            }
+            _ => false,
+        }
     }
 
     fn func_type_index(&self, func_idx: u32) -> u32 {
         self.func_ty_indicies[func_idx as usize]
     }
 
-    fn defined_global_index(&self, _index: u32) -> Option<u32> {
-        unimplemented!()
+    fn defined_global_index(&self, index: u32) -> Option<u32> {
+        index.checked_sub(self.imported_globals)
     }
 
-    fn global_type(&self, _global_index: u32) -> &Self::GlobalType {
-        unimplemented!()
+    fn global_type(&self, global_index: u32) -> &Self::GlobalType {
+        &self.globals[global_index as usize].content_type
     }
 
     fn signature(&self, index: u32) -> &Self::Signature {
         &self.types[index as usize]
     }
 
-    fn vmctx_vmglobal_definition(&self, _index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmglobal_definition(&self, index: u32) -> u32 {
+        self.vmctx_layout().offset_of_global(index)
     }
 
-    fn vmctx_vmglobal_import_from(&self, _index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmglobal_import_from(&self, index: u32) -> u32 {
+        self.vmctx_layout().offset_of_global_import(index)
     }
 
-    fn defined_memory_index(&self, _index: u32) -> Option<u32> {
-        unimplemented!()
+    fn defined_memory_index(&self, index: u32) -> Option<u32> {
+        index.checked_sub(self.imported_memories)
     }
 
     fn defined_table_index(&self, index: u32) -> Option<u32> {
         Some(index)
     }
 
-    fn vmctx_vmfunction_import_body(&self, _func_index: u32) -> u32 {
-        unimplemented!()
+    fn builtin_function(&self, builtin: BuiltinFunction) -> *const u8 {
+        self.libcalls.get(builtin)
     }
-    fn vmctx_vmfunction_import_vmctx(&self, _func_index: u32) -> u32 {
-        unimplemented!()
+
+    fn vmctx_builtin_function(&self, builtin: BuiltinFunction) -> u32 {
+        VmCtx::offset_of_builtin(builtin)
+    }
+
+    fn vmctx_operator_count(&self, class: OperatorClass) -> u32 {
+        VmCtx::offset_of_operator_count(class)
+    }
+
+    fn vmctx_trap_return(&self) -> Option<(u32, u32)> {
+        Some((VmCtx::offset_of_trap_sp(), VmCtx::offset_of_trap_reason()))
+    }
+
+    fn vmctx_vmfunction_import_body(&self, func_index: u32) -> u32 {
+        self.vmctx_layout().offset_of_import_body(func_index)
+    }
+    fn vmctx_vmfunction_import_vmctx(&self, func_index: u32) -> u32 {
+        self.vmctx_layout().offset_of_import_vmctx(func_index)
     }
 
     fn vmctx_vmtable_import_from(&self, _table_index: u32) -> u32 {
         unimplemented!()
     }
 
-    fn vmctx_vmmemory_definition(&self, _defined_memory_index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmmemory_definition(&self, defined_memory_index: u32) -> u32 {
+        assert_eq!(defined_memory_index, 0);
+        VmCtx::offset_of_memory()
     }
-    fn vmctx_vmmemory_import_from(&self, _memory_index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmmemory_import_from(&self, memory_index: u32) -> u32 {
+        assert_eq!(memory_index, 0);
+        VmCtx::offset_of_imported_memory()
     }
     fn vmmemory_definition_base(&self) -> u8 {
-        unimplemented!()
+        (VmCtx::offset_of_memory_ptr() - VmCtx::offset_of_memory()) as u8
     }
     fn vmmemory_definition_current_length(&self) -> u8 {
-        unimplemented!()
+        (VmCtx::offset_of_memory_len() - VmCtx::offset_of_memory()) as u8
     }
     fn vmctx_vmmemory_definition_base(&self, defined_memory_index: u32) -> u32 {
         assert_eq!(defined_memory_index, 0);
@@ -447,191 +2600,584 @@ impl ModuleContext for SimpleContext {
         VmCtx::offset_of_memory_len()
     }
 
-    fn vmctx_vmtable_definition(&self, _defined_table_index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmtable_definition(&self, defined_table_index: u32) -> u32 {
+        assert_eq!(defined_table_index, 0);
+        VmCtx::offset_of_table()
     }
 
-    fn vmctx_vmtable_definition_base(&self, _defined_table_index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmtable_definition_base(&self, defined_table_index: u32) -> u32 {
+        assert_eq!(defined_table_index, 0);
+        VmCtx::offset_of_table_ptr()
     }
 
-    fn vmctx_vmtable_definition_current_elements(&self, _defined_table_index: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmtable_definition_current_elements(&self, defined_table_index: u32) -> u32 {
+        assert_eq!(defined_table_index, 0);
+        VmCtx::offset_of_table_len()
     }
 
     fn vmtable_definition_base(&self) -> u8 {
-        unimplemented!()
+        (VmCtx::offset_of_table_ptr() - VmCtx::offset_of_table()) as u8
     }
 
     fn vmtable_definition_current_elements(&self) -> u8 {
-        unimplemented!()
+        (VmCtx::offset_of_table_len() - VmCtx::offset_of_table()) as u8
     }
 
     fn vmcaller_checked_anyfunc_vmctx(&self) -> u8 {
-        unimplemented!()
+        RuntimeFunc::offset_of_vmctx()
     }
 
     fn vmcaller_checked_anyfunc_type_index(&self) -> u8 {
-        unimplemented!()
+        RuntimeFunc::offset_of_sig_id()
     }
 
     fn vmcaller_checked_anyfunc_func_ptr(&self) -> u8 {
-        unimplemented!()
+        RuntimeFunc::offset_of_func_start()
     }
 
     fn size_of_vmcaller_checked_anyfunc(&self) -> u8 {
-        unimplemented!()
+        mem::size_of::<RuntimeFunc>() as u8
     }
 
-    fn vmctx_vmshared_signature_id(&self, _signature_idx: u32) -> u32 {
-        unimplemented!()
+    fn vmctx_vmshared_signature_id(&self, signature_idx: u32) -> u32 {
+        self.vmctx_layout().offset_of_sig_id(signature_idx)
     }
-
-    // TODO: type of a global
 }
 
-pub fn translate(data: &[u8]) -> Result<ExecutableModule, Error> {
-    translate_only(data).map(|m| m.instantiate())
+pub fn translate(data: &[u8]) -> Result<Instance, Error> {
+    let module = Arc::new(translate_only(data)?);
+    Instance::link(module, &InstanceImports::default())
 }
 
 /// Translate from a slice of bytes holding a wasm module.
-pub fn translate_only(data: &[u8]) -> Result<TranslatedModule, Error> {
+pub fn translate_only(data: &[u8]) -> Result<CompiledModule, Error> {
+    translate_only_with(data, TranslateOptions::default())
+}
+
+/// Check that `data` is a well-formed and valid module, so that bad input is rejected with an
+/// error rather than tripping over assumptions made during translation.
+fn validate(data: &[u8]) -> Result<(), Error> {
+    let config = ValidatingParserConfig {
+        operator_config: OperatorValidatorConfig {
+            enable_threads: false,
+            enable_reference_types: true,
+            enable_simd: false,
+            enable_bulk_memory: true,
+        },
+        mutable_global_imports: true,
+    };
+    let mut parser = ValidatingParser::new(data, Some(config));
+
+    loop {
+        match *parser.read() {
+            ParserState::EndWasm => return Ok(()),
+            ParserState::Error(e) => return Err(e.into()),
+            _ => {}
+        }
+    }
+}
+
+/// Check that no function in `data` uses an operator whose result can be a NaN with a sign
+/// or payload that wasm leaves up to the implementation.
+fn check_deterministic_floats(data: &[u8]) -> Result<(), Error> {
+    let mut parser = Parser::new(data);
+    let mut func_idx = DefinedFuncIndex(0);
+
+    loop {
+        match *parser.read() {
+            ParserState::EndWasm => return Ok(()),
+            ParserState::Error(e) => return Err(e.into()),
+            ParserState::EndFunctionBody => func_idx.0 += 1,
+            ParserState::CodeOperator(ref op) if produces_nan(op) => {
+                return Err(Error::Input(format!(
+                    "Function {} uses {:?}, which doesn't give deterministic NaNs",
+                    func_idx, op
+                )));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn produces_nan(op: &Operator) -> bool {
+    match op {
+        Operator::F32Add
+        | Operator::F32Sub
+        | Operator::F32Mul
+        | Operator::F32Div
+        | Operator::F32Min
+        | Operator::F32Max
+        | Operator::F32Sqrt
+        | Operator::F32Ceil
+        | Operator::F32Floor
+        | Operator::F32Trunc
+        | Operator::F32Nearest
+        | Operator::F32DemoteF64
+        | Operator::F64Add
+        | Operator::F64Sub
+        | Operator::F64Mul
+        | Operator::F64Div
+        | Operator::F64Min
+        | Operator::F64Max
+        | Operator::F64Sqrt
+        | Operator::F64Ceil
+        | Operator::F64Floor
+        | Operator::F64Trunc
+        | Operator::F64Nearest
+        | Operator::F64PromoteF32 => true,
+        _ => false,
+    }
+}
+
+/// Compile functions given directly as microwasm rather than as a wasm module, so that the
+/// backend can be tested with minimal fixtures. Function `i` has type `types[i]`.
+#[cfg(test)]
+pub(crate) fn translate_microwasm<L>(
+    types: Vec<FuncType>,
+    funcs: Vec<Vec<microwasm::Operator<L>>>,
+) -> Result<CompiledModule, Error>
+where
+    L: std::hash::Hash + Clone + Eq + Send + Sync + 'static,
+    microwasm::BrTarget<L>: fmt::Display,
+{
+    let mut output = CompiledModule::default();
+    output.ctx.func_ty_indicies = (0..types.len() as u32).collect();
+    output.ctx.sig_ids = types
+        .iter()
+        .map(|ty| signatures::intern(&ty.params, &ty.returns))
+        .collect();
+    output.ctx.types = types;
+    output.translated_code_section = Some(translate_sections::microwasm_code(funcs, &output.ctx)?);
+
+    Ok(output)
+}
+
+/// Convert every function in a wasm module to microwasm, without generating any code for
+/// them, so that tests can check the converter's output directly.
+pub(crate) fn translate_to_microwasm(
+    data: &[u8],
+) -> Result<Vec<Vec<microwasm::OperatorFromWasm>>, Error> {
+    use crate::microwasm::MicrowasmConv;
+
+    let module = translate_module(data, TranslateOptions::default(), false)?;
     let mut reader = ModuleReader::new(data)?;
-    let mut output = TranslatedModule::default();
+    let mut funcs = vec![];
+
+    while !reader.eof() {
+        let section = reader.read()?;
+        if let SectionCode::Code = section.code {
+            for (i, body) in section.get_code_section_reader()?.into_iter().enumerate() {
+                let body = body?;
+                let ty = module.ctx.defined_func_type(i as u32);
+                let conv = MicrowasmConv::new(
+                    &module.ctx,
+                    ty.params().iter().map(SigType::to_microwasm_type),
+                    ty.returns().iter().map(SigType::to_microwasm_type),
+                    &body,
+                );
+                let mut ops = vec![];
+                for op in conv {
+                    ops.extend(op?);
+                }
+                funcs.push(ops);
+            }
+        }
+    }
 
-    reader.skip_custom_sections()?;
-    if reader.eof() {
-        return Ok(output);
+    Ok(funcs)
+}
+
+/// The indices of the functions in a wasm module that calls would be inlined to with
+/// `CodeGenOptions::max_inlined_size` set to `max_size`, so that tests can check which are.
+#[cfg(test)]
+pub(crate) fn inlined_functions(data: &[u8], max_size: usize) -> Result<Vec<u32>, Error> {
+    let module = translate_module(data, TranslateOptions::default(), false)?;
+    let mut reader = ModuleReader::new(data)?;
+    let mut inlined = vec![];
+
+    while !reader.eof() {
+        let section = reader.read()?;
+        if let SectionCode::Code = section.code {
+            let bodies = section
+                .get_code_section_reader()?
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+            inlined.extend(crate::inline::inline_bodies(&module.ctx, &bodies, max_size)?.keys());
+        }
+    }
+
+    inlined.sort();
+    Ok(inlined)
+}
+
+/// Write the microwasm that each function in a wasm module is converted to before generating
+/// code for it, for debugging the conversion. Functions are numbered by their index among the
+/// module's defined functions.
+pub fn write_microwasm(data: &[u8], mut out: impl io::Write) -> Result<(), Error> {
+    for (i, ops) in translate_to_microwasm(data)?.into_iter().enumerate() {
+        microwasm::dis(&mut out, i, ops).map_err(|e| Error::Disassembler(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Translate from a slice of bytes holding a wasm module, with the given options.
+pub fn translate_only_with(
+    data: &[u8],
+    options: TranslateOptions,
+) -> Result<CompiledModule, Error> {
+    translate_module(data, options, true)
+}
+
+/// Translate every section, generating code for the functions only if `generate_code` is
+/// set.
+fn translate_module(
+    data: &[u8],
+    options: TranslateOptions,
+    generate_code: bool,
+) -> Result<CompiledModule, Error> {
+    if options.memory64 {
+        validate(&memory64::lower(data)?)?;
+    } else {
+        validate(data)?;
+    }
+    if options.deterministic_floats {
+        check_deterministic_floats(data)?;
     }
-    let mut section = reader.read()?;
+
+    let mut reader = ModuleReader::new(data)?;
+    let mut output = CompiledModule::default();
+    output.ctx.memory_style = options.memory_style;
+    output.ctx.memory64 = options.memory64;
+    output.ctx.operator_libcalls = options.operator_libcalls.clone();
+    output.ctx.libcalls = options.libcalls.clone();
+    read_custom_sections(data, &mut output)?;
+
+    let mut section = match next_section(&mut reader)? {
+        Some(section) => section,
+        None => return Ok(output),
+    };
+    let mut start_func = None;
 
     if let SectionCode::Type = section.code {
         let types_reader = section.get_type_section_reader()?;
         output.ctx.types = translate_sections::type_(types_reader)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        output.ctx.sig_ids = output
+            .ctx
+            .types
+            .iter()
+            .map(|ty| signatures::intern(&ty.params, &ty.returns))
+            .collect();
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Import = section.code {
         let imports = section.get_import_section_reader()?;
-        translate_sections::import(imports)?;
+        for import in translate_sections::import(imports)? {
+            if let ImportSectionEntryType::Memory(ty) = import.ty {
+                if output.memory.is_some() {
+                    return Err(Error::Input(
+                        "Multiple memories not yet implemented".to_string(),
+                    ));
+                }
+                output.memory = Some(ty);
+                output.memory_import = Some((import.module.to_string(), import.field.to_string()));
+                output.ctx.imported_memories += 1;
+            }
+
+            if let ImportSectionEntryType::Global(ty) = import.ty {
+                output.ctx.globals.push(GlobalType::from_wasm(ty)?);
+                output.ctx.imported_globals += 1;
+                output
+                    .global_imports
+                    .push((import.module.to_string(), import.field.to_string()));
+            }
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
+            // TODO: Other kinds of import are ignored
+            if let ImportSectionEntryType::Function(type_index) = import.ty {
+                let func_import = if let Some(lowering) =
+                    options.intrinsics.get(import.module, import.field)
+                {
+                    FuncImport::Intrinsic(lowering)
+                } else if let Some(func) = options.host_functions.get(import.module, import.field) {
+                    let ty = output.ctx.types.get(type_index as usize).ok_or_else(|| {
+                        Error::Input(format!("Type index {} out of bounds", type_index))
+                    })?;
+                    if (&ty.params[..], &ty.returns[..]) != (func.params, func.returns) {
+                        return Err(Error::Input(format!(
+                            "Imported function {}.{} has type {:?} -> {:?} but the host function \
+                             has type {:?} -> {:?}",
+                            import.module,
+                            import.field,
+                            ty.params,
+                            ty.returns,
+                            func.params,
+                            func.returns
+                        )));
+                    }
+
+                    FuncImport::Host(func.clone())
+                } else {
+                    FuncImport::Linked
+                };
+
+                output.ctx.func_ty_indicies.push(type_index);
+                output.ctx.imports.push(func_import);
+                output
+                    .func_imports
+                    .push((import.module.to_string(), import.field.to_string()));
+            }
         }
-        section = reader.read()?;
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Function = section.code {
         let functions = section.get_function_section_reader()?;
-        output.ctx.func_ty_indicies = translate_sections::function(functions)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        output
+            .ctx
+            .func_ty_indicies
+            .extend(translate_sections::function(functions)?);
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Table = section.code {
         let tables = section.get_table_section_reader()?;
-        translate_sections::table(tables)?;
+        let tables = translate_sections::table(tables)?;
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
+        if tables.len() > 1 {
+            return Err(Error::Input(
+                "Multiple tables not yet implemented".to_string(),
+            ));
         }
-        section = reader.read()?;
+
+        output.table = tables.first().cloned();
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Memory = section.code {
         let memories = section.get_memory_section_reader()?;
         let mem = translate_sections::memory(memories)?;
 
-        assert!(
-            mem.len() <= 1,
-            "Multiple memory sections not yet unimplemented"
-        );
+        if mem.len() + output.memory.iter().count() > 1 {
+            return Err(Error::Input(
+                "Multiple memories not yet implemented".to_string(),
+            ));
+        }
 
         if !mem.is_empty() {
             let mem = mem[0];
-            assert_eq!(Some(mem.limits.initial), mem.limits.maximum);
+            if options.fixed_size_memory && mem.limits.maximum == Some(mem.limits.initial) {
+                output.ctx.fixed_memory_pages = Some(mem.limits.initial);
+            }
             output.memory = Some(mem);
         }
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Global = section.code {
         let globals = section.get_global_section_reader()?;
-        translate_sections::global(globals)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
+        for (ty, init) in translate_sections::global(globals)? {
+            output.ctx.globals.push(ty);
+            output.ctx.global_inits.push(init);
         }
-        section = reader.read()?;
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Export = section.code {
         let exports = section.get_export_section_reader()?;
-        translate_sections::export(exports)?;
-
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        output.exports = translate_sections::export(exports)?
+            .into_iter()
+            .map(|export| (export.field.to_string(), (export.kind, export.index)))
+            .collect();
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Start = section.code {
         let start = section.get_start_section_content()?;
         translate_sections::start(start)?;
+        start_func = Some(start);
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Element = section.code {
         let elements = section.get_element_section_reader()?;
-        translate_sections::element(elements)?;
+        output.element_segments = translate_sections::element(elements)?;
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
-        }
-        section = reader.read()?;
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
-    if let SectionCode::Code = section.code {
-        let code = section.get_code_section_reader()?;
-        output.translated_code_section = Some(translate_sections::code(code, &output.ctx)?);
+    if let SectionCode::DataCount = section.code {
+        let count = section.get_data_count_section_content()?;
+        output.ctx.data_count = Some(translate_sections::data_count(count)?);
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
+    }
 
-        reader.skip_custom_sections()?;
-        if reader.eof() {
-            return Ok(output);
+    if let SectionCode::Code = section.code {
+        let bodies = section
+            .get_code_section_reader()?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        output.call_graph = CallGraph::new(
+            &bodies,
+            &output.ctx.func_ty_indicies,
+            &output.ctx.sig_ids,
+            &output.element_segments,
+        )?;
+
+        if generate_code && options.lazy {
+            let code = section.get_code_section_reader()?;
+            let (stubs, bodies) =
+                translate_sections::lazy_code(code, &output.ctx, options.codegen.clone())?;
+            output.translated_code_section = Some(stubs);
+            output.lazy = Some(LazyFunctions {
+                bodies,
+                codegen: options.codegen.clone(),
+                metrics: options.metrics.clone(),
+                translated: Mutex::new(HashMap::new()),
+            });
+        } else if generate_code {
+            let elided = if options.elide_dead_functions {
+                unreachable_functions(&output, start_func)
+            } else {
+                HashSet::new()
+            };
+            let code = section.get_code_section_reader()?;
+            output.translated_code_section = Some(translate_sections::code(
+                code,
+                &output.ctx,
+                &output.element_segments,
+                &elided,
+                options.codegen,
+                options.metrics.clone(),
+            )?);
         }
-        section = reader.read()?;
+
+        section = match next_section(&mut reader)? {
+            Some(section) => section,
+            None => return Ok(output),
+        };
     }
 
     if let SectionCode::Data = section.code {
         let data = section.get_data_section_reader()?;
-        translate_sections::data(data)?;
+        output.data_segments = translate_sections::data(data, output.ctx.data_count)?;
     }
 
-    assert!(reader.eof());
+    assert!(next_section(&mut reader)?.is_none());
 
     Ok(output)
 }
+
+/// The defined functions that can't be reached from the module's exports, its start function
+/// or its element segments.
+fn unreachable_functions(
+    module: &CompiledModule,
+    start_func: Option<u32>,
+) -> HashSet<DefinedFuncIndex> {
+    let exported = module
+        .exports
+        .values()
+        .filter_map(|&(kind, index)| match kind {
+            ExternalKind::Function => Some(index),
+            _ => None,
+        });
+    let in_tables = module
+        .element_segments
+        .iter()
+        .flat_map(|segment| segment.elements.iter().cloned());
+    let reachable = module
+        .call_graph
+        .reachable(exported.chain(start_func).chain(in_tables));
+
+    let num_imports = module.ctx.imports.len() as u32;
+    (num_imports..module.ctx.func_ty_indicies.len() as u32)
+        .filter(|func| reachable.binary_search(func).is_err())
+        .map(|func| DefinedFuncIndex(func - num_imports))
+        .collect()
+}
+
+/// Read the next section that isn't a custom section, or `None` at the end of the module.
+fn next_section<'a>(reader: &mut ModuleReader<'a>) -> Result<Option<Section<'a>>, Error> {
+    reader.skip_custom_sections()?;
+    if reader.eof() {
+        return Ok(None);
+    }
+    Ok(Some(reader.read()?))
+}
+
+/// Read every custom section in the module, and the ones that affect translation. This has
+/// to happen before we translate any code, since the name section comes after the code.
+fn read_custom_sections(data: &[u8], output: &mut CompiledModule) -> Result<(), Error> {
+    let mut reader = ModuleReader::new(data)?;
+    while !reader.eof() {
+        let section = reader.read()?;
+        let name = match section.code {
+            SectionCode::Custom { name, .. } => name,
+            _ => continue,
+        };
+
+        // Custom sections can't make a module invalid, so we just don't use malformed ones
+        match name {
+            "name" => match section
+                .get_name_section_reader()
+                .map_err(Error::from)
+                .and_then(translate_sections::names)
+            {
+                Ok(names) => output.ctx.func_names = names,
+                Err(e) => log_debug!("Ignoring malformed name section: {}", e),
+            },
+            branch_hints::SECTION_NAME => match BranchHints::parse(section.get_binary_reader()) {
+                Ok(hints) => output.ctx.branch_hints = hints,
+                Err(e) => log_debug!("Ignoring malformed branch hints: {}", e),
+            },
+            _ => {}
+        }
+
+        let range = section.range();
+        output.custom_sections.push(CustomSection {
+            name: name.to_string(),
+            data: data[range.start..range.end].to_vec(),
+        });
+    }
+
+    Ok(())
+}
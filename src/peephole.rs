@@ -0,0 +1,476 @@
+//! A peephole layer between the backend's lowering decisions and the assembler. The backend
+//! emits most instructions straight into the assembler as soon as it decides on them, which
+//! leaves moves of values to where they already are, pushes that are immediately popped
+//! again and comparisons of values that were just compared. The instructions that produce
+//! these go through `Peephole` instead, which holds back a `push` in case the next
+//! instruction pops it and keeps track of which registers and stack slots hold the same
+//! value and what's in the flags, so that instructions that change nothing are dropped.
+//!
+//! Anything else emitted through `Peephole` acts as a barrier: the pending `push` is emitted
+//! first and nothing is remembered across it, since it could change any register, the stack
+//! or the flags. Jumps are the exception, since the code after a conditional jump still
+//! sees everything as it was. Defining a label is a barrier too, since execution can arrive
+//! there from elsewhere, unless the only way to reach it is by falling through.
+
+use crate::emitter::{DynamicLabel, Emitter, Instruction, Operand, Relocation, VecAssembler};
+use dynasm::dynasm;
+use dynasmrt::AssemblyOffset;
+
+const WORD_SIZE: i32 = 8;
+const PUSH: u8 = 0x50;
+const POP: u8 = 0x58;
+/// The prefix that extends the register encoded in the opcode to `r8` to `r15`.
+const REX_B: u8 = 0x41;
+
+/// A comparison whose result is in the flags.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Cmp {
+    /// Whether this compares 64-bit values rather than 32-bit ones.
+    wide: bool,
+    left: u8,
+    right: CmpOperand,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CmpOperand {
+    Reg(u8),
+    Imm(i32),
+    /// `test left, left`, which sets the flags like comparing with 0.
+    Itself,
+}
+
+impl Cmp {
+    fn reads(&self, reg: u8) -> bool {
+        self.left == reg || self.right == CmpOperand::Reg(reg)
+    }
+}
+
+pub struct Peephole<'a, E = VecAssembler> {
+    asm: &'a mut E,
+    /// A `push` of this register that hasn't been emitted yet.
+    pending_push: Option<u8>,
+    /// Pairs of registers that are known to hold the same value.
+    copies: Vec<(u8, u8)>,
+    /// Stack slots, as offsets from `rsp`, that are known to hold the same value as a
+    /// register.
+    slots: Vec<(i32, u8)>,
+    /// The last comparison, if neither the flags nor its operands have changed since.
+    flags: Option<Cmp>,
+    /// The number of instructions that were dropped.
+    removed: usize,
+}
+
+impl<'a, E: Emitter> Peephole<'a, E> {
+    pub fn new(asm: &'a mut E) -> Self {
+        Peephole {
+            asm,
+            pending_push: None,
+            copies: vec![],
+            slots: vec![],
+            flags: None,
+            removed: 0,
+        }
+    }
+
+    /// The number of instructions that were dropped because they had no effect.
+    pub fn removed(&self) -> usize {
+        self.removed
+    }
+
+    /// Emit anything that's being held back, so that the code so far is complete.
+    pub fn flush(&mut self) {
+        if let Some(reg) = self.pending_push.take() {
+            self.emit_push_pop(PUSH, reg);
+            self.slots.clear();
+        }
+    }
+
+    /// Emit `push` or `pop` in its shortest form, which `pending_len` relies on.
+    fn emit_push_pop(&mut self, opcode: u8, reg: u8) {
+        if reg >= 8 {
+            self.asm.push(REX_B);
+        }
+        self.asm.push(opcode + (reg & 7));
+    }
+
+    /// Emit everything held back and forget everything known about the registers, before
+    /// code that this layer can't see into.
+    fn barrier(&mut self) {
+        self.flush();
+        self.copies.clear();
+        self.slots.clear();
+        self.flags = None;
+    }
+
+    /// Note that `reg` was overwritten.
+    fn clobber(&mut self, reg: u8) {
+        self.copies.retain(|&(a, b)| a != reg && b != reg);
+        self.slots.retain(|&(_, r)| r != reg);
+        if self.flags.map(|cmp| cmp.reads(reg)).unwrap_or(false) {
+            self.flags = None;
+        }
+    }
+
+    fn same_value(&self, a: u8, b: u8) -> bool {
+        a == b || self.copies.contains(&(a, b)) || self.copies.contains(&(b, a))
+    }
+
+    /// Emit `instruction`. The moves and comparisons that this layer knows about go through
+    /// the methods for them, so they're dropped if they have no effect. Any other
+    /// instruction is a barrier.
+    pub fn instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Mov {
+                wide: true,
+                dst: Operand::Reg(dst),
+                src: Operand::Reg(src),
+            } => self.mov_rq(dst, src),
+            Instruction::Mov {
+                wide: false,
+                dst: Operand::Reg(dst),
+                src: Operand::Reg(src),
+            } => self.mov_rd(dst, src),
+            Instruction::Mov {
+                wide: true,
+                dst: Operand::Reg(dst),
+                src: Operand::Stack(offset),
+            } => self.load_rq(dst, offset),
+            Instruction::Mov {
+                wide: true,
+                dst: Operand::Stack(offset),
+                src: Operand::Reg(src),
+            } => self.store_rq(offset, src),
+            Instruction::Cmp {
+                wide,
+                left: Operand::Reg(left),
+                right: Operand::Reg(right),
+            } => self.cmp_rr(wide, left, right),
+            Instruction::Cmp {
+                wide,
+                left: Operand::Reg(left),
+                right: Operand::Imm(right),
+            } => self.cmp_ri(wide, left, right),
+            Instruction::Test { wide, reg } => self.test(wide, reg),
+            _ => {
+                self.barrier();
+                self.asm.instruction(instruction);
+            }
+        }
+    }
+
+    /// `mov dst, src` with 64-bit registers.
+    pub fn mov_rq(&mut self, dst: u8, src: u8) {
+        if self.same_value(dst, src) {
+            self.removed += 1;
+            return;
+        }
+
+        self.flush();
+        self.asm.instruction(Instruction::Mov {
+            wide: true,
+            dst: Operand::Reg(dst),
+            src: Operand::Reg(src),
+        });
+        self.clobber(dst);
+        self.copies.push((dst, src));
+    }
+
+    /// `mov dst, src` with 32-bit registers, which is shorter than `mov_rq` and does the same
+    /// thing when the upper half of `src` is zero.
+    pub fn mov_rd(&mut self, dst: u8, src: u8) {
+        if self.same_value(dst, src) {
+            self.removed += 1;
+            return;
+        }
+
+        self.flush();
+        self.asm.instruction(Instruction::Mov {
+            wide: false,
+            dst: Operand::Reg(dst),
+            src: Operand::Reg(src),
+        });
+        self.clobber(dst);
+        self.copies.push((dst, src));
+    }
+
+    /// `mov dst, [rsp + offset]` with a 64-bit register.
+    pub fn load_rq(&mut self, dst: u8, offset: i32) {
+        self.flush();
+        let known = self
+            .slots
+            .iter()
+            .find(|&&(slot, _)| slot == offset)
+            .map(|&(_, reg)| reg);
+        if let Some(src) = known {
+            // The value's already in a register, so copy it from there instead
+            self.mov_rq(dst, src);
+            return;
+        }
+
+        self.asm.instruction(Instruction::Mov {
+            wide: true,
+            dst: Operand::Reg(dst),
+            src: Operand::Stack(offset),
+        });
+        self.clobber(dst);
+        self.slots.push((offset, dst));
+    }
+
+    /// `mov [rsp + offset], src` with a 64-bit register.
+    pub fn store_rq(&mut self, offset: i32, src: u8) {
+        self.flush();
+        let known = self
+            .slots
+            .iter()
+            .any(|&(slot, reg)| slot == offset && self.same_value(reg, src));
+        if known {
+            self.removed += 1;
+            return;
+        }
+
+        self.asm.instruction(Instruction::Mov {
+            wide: true,
+            dst: Operand::Stack(offset),
+            src: Operand::Reg(src),
+        });
+        self.slots.retain(|&(slot, _)| slot != offset);
+        self.slots.push((offset, src));
+    }
+
+    /// Move the stack pointer up by `bytes`, or down if it's negative.
+    pub fn adjust_rsp(&mut self, mut bytes: i32) {
+        // A slot that was only just pushed doesn't need to be pushed at all
+        if bytes >= WORD_SIZE && self.pending_push.take().is_some() {
+            bytes -= WORD_SIZE;
+            self.removed += 1;
+
+            if bytes == 0 {
+                self.removed += 1;
+                return;
+            }
+        }
+
+        self.flush();
+        dynasm!(self.asm
+            ; lea rsp, [rsp + bytes]
+        );
+        self.slots.clear();
+    }
+
+    /// `push reg`, which is held back in case the next instruction pops it again.
+    pub fn push_rq(&mut self, reg: u8) {
+        self.flush();
+        self.pending_push = Some(reg);
+    }
+
+    /// `pop reg`.
+    pub fn pop_rq(&mut self, reg: u8) {
+        if self.pending_push == Some(reg) {
+            self.pending_push = None;
+            self.removed += 2;
+            return;
+        }
+
+        self.flush();
+        self.emit_push_pop(POP, reg);
+        self.clobber(reg);
+        self.slots.clear();
+    }
+
+    /// Pop a word that nothing needs into `trash`, which must be a free register.
+    pub fn pop_discard(&mut self, trash: u8) {
+        if self.pending_push.take().is_some() {
+            self.removed += 2;
+            return;
+        }
+
+        self.emit_push_pop(POP, trash);
+        self.clobber(trash);
+        self.slots.clear();
+    }
+
+    /// `cmp left, right` with 32-bit registers, or 64-bit ones if `wide`.
+    pub fn cmp_rr(&mut self, wide: bool, left: u8, right: u8) {
+        self.cmp(Cmp {
+            wide,
+            left,
+            right: CmpOperand::Reg(right),
+        });
+    }
+
+    /// `cmp left, right` with a 32-bit register, or a 64-bit one if `wide`.
+    pub fn cmp_ri(&mut self, wide: bool, left: u8, right: i32) {
+        self.cmp(Cmp {
+            wide,
+            left,
+            right: CmpOperand::Imm(right),
+        });
+    }
+
+    /// `test reg, reg` with a 32-bit register, or a 64-bit one if `wide`.
+    pub fn test(&mut self, wide: bool, reg: u8) {
+        self.cmp(Cmp {
+            wide,
+            left: reg,
+            right: CmpOperand::Itself,
+        });
+    }
+
+    /// Note that the instruction that was just emitted left the flags as `test reg, reg`
+    /// would, like `and`, `or` and `xor` do for their result.
+    pub fn assume_tested(&mut self, wide: bool, reg: u8) {
+        self.flush();
+        self.flags = Some(Cmp {
+            wide,
+            left: reg,
+            right: CmpOperand::Itself,
+        });
+    }
+
+    fn cmp(&mut self, cmp: Cmp) {
+        if self.flags == Some(cmp) {
+            self.removed += 1;
+            return;
+        }
+
+        self.flush();
+        let left = Operand::Reg(cmp.left);
+        self.asm.instruction(match cmp.right {
+            CmpOperand::Reg(right) => Instruction::Cmp {
+                wide: cmp.wide,
+                left,
+                right: Operand::Reg(right),
+            },
+            CmpOperand::Imm(right) => Instruction::Cmp {
+                wide: cmp.wide,
+                left,
+                right: Operand::Imm(right),
+            },
+            CmpOperand::Itself => Instruction::Test {
+                wide: cmp.wide,
+                reg: cmp.left,
+            },
+        });
+        self.flags = Some(cmp);
+    }
+
+    /// Emit a jump with `emit`. Jumps don't change the registers or the flags, so unlike
+    /// other code this keeps what's known about them for the code that falls through.
+    pub fn jump(&mut self, emit: impl FnOnce(&mut Self)) {
+        self.flush();
+        let copies = self.copies.clone();
+        let slots = self.slots.clone();
+        let flags = self.flags;
+
+        emit(self);
+
+        self.copies = copies;
+        self.slots = slots;
+        self.flags = flags;
+    }
+
+    /// Define a label that's only reached by falling through from the code before it, so
+    /// that what's known about the registers and the flags there still holds after it.
+    pub fn define_fallthrough_label(&mut self, label: DynamicLabel) {
+        self.jump(|this| this.define_label(label));
+    }
+
+    /// Where the next instruction will be once whatever's held back has been emitted.
+    fn pending_len(&self) -> usize {
+        match self.pending_push {
+            // `push` of `r8` to `r15` needs a REX prefix
+            Some(reg) if reg >= 8 => 2,
+            Some(_) => 1,
+            None => 0,
+        }
+    }
+}
+
+impl<E: Emitter> Extend<u8> for Peephole<'_, E> {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = u8>,
+    {
+        self.barrier();
+        self.asm.extend(iter);
+    }
+}
+
+impl<'b, E: Emitter> Extend<&'b u8> for Peephole<'_, E> {
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = &'b u8>,
+    {
+        self.barrier();
+        self.asm.extend(iter);
+    }
+}
+
+impl<E: Emitter> Emitter for Peephole<'_, E> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.asm.offset().0 + self.pending_len())
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.barrier();
+        self.asm.push(byte);
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.barrier();
+        self.asm.align(alignment);
+    }
+
+    fn new_label(&mut self) -> DynamicLabel {
+        self.asm.new_label()
+    }
+
+    fn define_label(&mut self, label: DynamicLabel) {
+        self.barrier();
+        self.asm.define_label(label);
+    }
+
+    fn global_label(&mut self, name: &'static str) {
+        self.barrier();
+        self.asm.global_label(name);
+    }
+
+    fn local_label(&mut self, name: &'static str) {
+        self.barrier();
+        self.asm.local_label(name);
+    }
+
+    fn dynamic_reloc(&mut self, label: DynamicLabel, kind: Relocation) {
+        self.barrier();
+        self.asm.dynamic_reloc(label, kind);
+    }
+
+    fn global_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.barrier();
+        self.asm.global_reloc(name, kind);
+    }
+
+    fn forward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.barrier();
+        self.asm.forward_reloc(name, kind);
+    }
+
+    fn backward_reloc(&mut self, name: &'static str, kind: Relocation) {
+        self.barrier();
+        self.asm.backward_reloc(name, kind);
+    }
+
+    fn bare_reloc(&mut self, target: usize, kind: Relocation) {
+        self.barrier();
+        self.asm.bare_reloc(target, kind);
+    }
+
+    fn emit_rel32(&mut self, label: DynamicLabel) {
+        self.barrier();
+        self.asm.emit_rel32(label);
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        Peephole::instruction(self, instruction);
+    }
+}
@@ -0,0 +1,81 @@
+//! Counting how often each kind of wasm operator runs, so that embedders get a cheap profile
+//! of what a module spends its time on without any external tools.
+//!
+//! With `CodeGenOptions::profile_operators`, the code for every operator in an
+//! `OperatorClass` starts by incrementing the counter for its class. The counters are kept
+//! in the `VmCtx`, so each instance has its own, and can be read with
+//! `Instance::operator_counts`.
+
+use wasmparser::Operator;
+
+/// The kinds of wasm operators that are counted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OperatorClass {
+    /// Every load from linear memory, whatever its width.
+    Load,
+    /// Every store to linear memory, whatever its width.
+    Store,
+    /// `call` and `call_indirect`.
+    Call,
+    /// `br`, `br_if`, `br_table` and `if`.
+    Branch,
+}
+
+impl OperatorClass {
+    pub const COUNT: usize = 4;
+
+    /// The class of `op`, if it's in one.
+    pub fn of(op: &Operator) -> Option<Self> {
+        use wasmparser::Operator::*;
+
+        Some(match op {
+            I32Load { .. }
+            | I64Load { .. }
+            | F32Load { .. }
+            | F64Load { .. }
+            | I32Load8S { .. }
+            | I32Load8U { .. }
+            | I32Load16S { .. }
+            | I32Load16U { .. }
+            | I64Load8S { .. }
+            | I64Load8U { .. }
+            | I64Load16S { .. }
+            | I64Load16U { .. }
+            | I64Load32S { .. }
+            | I64Load32U { .. } => OperatorClass::Load,
+            I32Store { .. }
+            | I64Store { .. }
+            | F32Store { .. }
+            | F64Store { .. }
+            | I32Store8 { .. }
+            | I32Store16 { .. }
+            | I64Store8 { .. }
+            | I64Store16 { .. }
+            | I64Store32 { .. } => OperatorClass::Store,
+            Call { .. } | CallIndirect { .. } => OperatorClass::Call,
+            Br { .. } | BrIf { .. } | BrTable { .. } | If { .. } => OperatorClass::Branch,
+            _ => return None,
+        })
+    }
+}
+
+/// How many operators of each class an instance has run.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OperatorCounts {
+    pub loads: u64,
+    pub stores: u64,
+    pub calls: u64,
+    pub branches: u64,
+}
+
+impl OperatorCounts {
+    /// Read the counters as the `VmCtx` keeps them, indexed by `OperatorClass`.
+    pub(crate) fn from_counters(counters: &[u64; OperatorClass::COUNT]) -> Self {
+        OperatorCounts {
+            loads: counters[OperatorClass::Load as usize],
+            stores: counters[OperatorClass::Store as usize],
+            calls: counters[OperatorClass::Call as usize],
+            branches: counters[OperatorClass::Branch as usize],
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Ids for function signatures that are the same for structurally-equal signatures across
+//! every module in the process.
+//!
+//! `call_indirect` checks that the function it calls has the expected signature by comparing
+//! the id stored with the table element against the id of the expected type, and the typed
+//! entry points compare the id of the function's type with that of the Rust types that it's
+//! called with. Since ids are interned process-wide, a function reference from one module can
+//! be stored in the table of another and called through it. Ids are never freed, but there
+//! are only as many as there are distinct signatures.
+
+use crate::module::ValueType;
+use std::{collections::HashMap, sync::RwLock};
+
+lazy_static! {
+    static ref SIGNATURES: RwLock<HashMap<Box<[u8]>, u32>> = Default::default();
+}
+
+/// Signature id of null table elements, which doesn't match any signature so that calls to
+/// them trap.
+pub const NULL_SIG_ID: u32 = u32::max_value();
+
+/// Signatures are keyed by the discriminants of their types, with the parameters separated
+/// from the returns by a byte that isn't one.
+fn key(params: &[ValueType], returns: &[ValueType]) -> Box<[u8]> {
+    params
+        .iter()
+        .map(|&ty| ty as u8)
+        .chain(Some(u8::max_value()))
+        .chain(returns.iter().map(|&ty| ty as u8))
+        .collect()
+}
+
+/// The id of the signature with the given parameters and returns, giving it one if it
+/// doesn't have one yet.
+pub fn intern(params: &[ValueType], returns: &[ValueType]) -> u32 {
+    let key = key(params, returns);
+    if let Some(&id) = SIGNATURES.read().unwrap().get(&key) {
+        return id;
+    }
+
+    let mut signatures = SIGNATURES.write().unwrap();
+    let next = signatures.len() as u32;
+    assert_ne!(next, NULL_SIG_ID, "Too many distinct signatures");
+    *signatures.entry(key).or_insert(next)
+}
+
+/// The id of the signature with the given parameters and returns, if it has one. Signatures
+/// that don't have one yet aren't the type of any function, so nothing can match them.
+pub fn lookup(params: &[ValueType], returns: &[ValueType]) -> Option<u32> {
+    SIGNATURES
+        .read()
+        .unwrap()
+        .get(&key(params, returns))
+        .cloned()
+}
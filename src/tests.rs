@@ -1,12 +1,66 @@
-use super::{module::ExecutionError, translate, ExecutableModule};
+use super::{
+    module::{translate_only_with, ExecutionError},
+    translate, CodeGenOptions, Instance, TranslateOptions,
+};
 use wabt;
 
-fn translate_wat(wat: &str) -> ExecutableModule {
+/// A result that can be compared with an expected value. Floats compare equal if they're
+/// both NaN, since which NaN an operation gives isn't something that the tests check.
+trait SameValue {
+    fn same_value(&self, other: &Self) -> bool;
+}
+
+impl SameValue for i32 {
+    fn same_value(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl SameValue for i64 {
+    fn same_value(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl SameValue for f32 {
+    fn same_value(&self, other: &Self) -> bool {
+        self == other || (self.is_nan() && other.is_nan())
+    }
+}
+
+impl SameValue for f64 {
+    fn same_value(&self, other: &Self) -> bool {
+        self == other || (self.is_nan() && other.is_nan())
+    }
+}
+
+fn same_result<T: SameValue>(result: Result<T, ExecutionError>, expected: T) -> bool {
+    match result {
+        Ok(result) => result.same_value(&expected),
+        Err(_) => false,
+    }
+}
+
+fn translate_wat(wat: &str) -> Instance {
     let wasm = wabt::wat2wasm(wat).unwrap();
     let compiled = translate(&wasm).unwrap();
     compiled
 }
 
+/// Translate and instantiate a module with the given code generation options.
+fn translate_with(wasm: &[u8], codegen: CodeGenOptions) -> Instance {
+    let options = TranslateOptions {
+        codegen,
+        ..Default::default()
+    };
+    translate_only_with(wasm, options).unwrap().instantiate()
+}
+
+/// The same as `translate_with`, for a module in the text format.
+fn translate_wat_with(wat: &str, codegen: CodeGenOptions) -> Instance {
+    translate_with(&wabt::wat2wasm(wat).unwrap(), codegen)
+}
+
 /// Execute the first function in the module.
 fn execute_wat(wat: &str, a: u32, b: u32) -> u32 {
     let translated = translate_wat(wat);
@@ -19,19 +73,32 @@ fn empty() {
     let _ = translate_wat("(module (func))");
 }
 
+#[test]
+fn degenerate_bodies() {
+    let translated = translate_wat(
+        "(module
+            (func)
+            (func (result i32) unreachable)
+            (func (param i32) (result i32) (get_local 0)))",
+    );
+
+    assert_eq!(translated.execute_func::<(), ()>(0, ()), Ok(()));
+    assert_eq!(translated.execute_func::<(i32,), i32>(2, (5,)), Ok(5));
+}
+
 mod op32 {
-    use super::{translate_wat, ExecutableModule};
+    use super::{translate_wat, Instance};
 
     macro_rules! binop_test {
         ($op:ident, $func:expr) => {
             mod $op {
-                use super::{translate_wat, ExecutableModule};
+                use super::{translate_wat, Instance};
                 use std::sync::Once;
 
                 const OP: &str = stringify!($op);
 
                 lazy_static! {
-                    static ref AS_PARAMS: ExecutableModule = translate_wat(&format!(
+                    static ref AS_PARAMS: Instance = translate_wat(&format!(
                         "(module (func (param i32) (param i32) (result i32)
                             (i32.{op} (get_local 0) (get_local 1))))",
                         op = OP
@@ -83,11 +150,11 @@ mod op32 {
     macro_rules! unop_test {
         ($name:ident, $func:expr) => {
             mod $name {
-                use super::{translate_wat, ExecutableModule};
+                use super::{translate_wat, Instance};
                 use std::sync::Once;
 
                 lazy_static! {
-                    static ref AS_PARAM: ExecutableModule = translate_wat(concat!(
+                    static ref AS_PARAM: Instance = translate_wat(concat!(
                         "(module (func (param i32) (result i32)
                             (i32.",
                         stringify!($name),
@@ -144,7 +211,7 @@ mod op32 {
 }
 
 mod op64 {
-    use super::{translate_wat, ExecutableModule};
+    use super::{translate_wat, Instance};
 
     macro_rules! binop_test {
         ($op:ident, $func:expr) => {
@@ -152,13 +219,13 @@ mod op64 {
         };
         ($op:ident, $func:expr, $retty:ident) => {
             mod $op {
-                use super::{translate_wat, ExecutableModule};
+                use super::{translate_wat, Instance};
 
                 const RETTY: &str = stringify!($retty);
                 const OP: &str = stringify!($op);
 
                 lazy_static! {
-                    static ref AS_PARAMS: ExecutableModule = translate_wat(&format!("
+                    static ref AS_PARAMS: Instance = translate_wat(&format!("
                         (module (func (param i64) (param i64) (result {retty})
                             (i64.{op} (get_local 0) (get_local 1))))
                     ", retty = RETTY, op = OP));
@@ -212,11 +279,11 @@ mod op64 {
         };
         ($name:ident, $func:expr, $out_ty:ty) => {
             mod $name {
-                use super::{translate_wat, ExecutableModule};
+                use super::{translate_wat, Instance};
                 use std::sync::Once;
 
                 lazy_static! {
-                    static ref AS_PARAM: ExecutableModule = translate_wat(concat!(
+                    static ref AS_PARAM: Instance = translate_wat(concat!(
                         "(module (func (param i64) (result ",
                         stringify!($out_ty),
                         ")
@@ -291,7 +358,7 @@ mod op64 {
 }
 
 mod opf32 {
-    use super::{translate_wat, ExecutableModule};
+    use super::{same_result, translate_wat, Instance};
 
     macro_rules! binop_test {
         ($op:ident, $func:expr) => {
@@ -299,13 +366,13 @@ mod opf32 {
         };
         ($op:ident, $func:expr, $retty:ident) => {
             mod $op {
-                use super::{translate_wat, ExecutableModule};
+                use super::{same_result, translate_wat, Instance};
 
                 const RETTY: &str = stringify!($retty);
                 const OP: &str = stringify!($op);
 
                 lazy_static! {
-                    static ref AS_PARAMS: ExecutableModule = translate_wat(&format!("
+                    static ref AS_PARAMS: Instance = translate_wat(&format!("
                         (module (func (param f32) (param f32) (result {retty})
                             (f32.{op} (get_local 0) (get_local 1))))
                     ", retty = RETTY, op = OP));
@@ -313,14 +380,14 @@ mod opf32 {
 
                 quickcheck! {
                     fn as_params(a: f32, b: f32) -> bool {
-                        AS_PARAMS.execute_func::<(f32, f32), $retty>(0, (a, b)) == Ok($func(a, b) as $retty)
+                        same_result(AS_PARAMS.execute_func::<(f32, f32), $retty>(0, (a, b)), $func(a, b) as $retty)
                     }
 
                     fn lit_lit(a: f32, b: f32) -> bool {
-                        translate_wat(&format!("
+                        same_result(translate_wat(&format!("
                             (module (func (result {retty})
                                 (f32.{op} (f32.const {left}) (f32.const {right}))))
-                        ", retty = RETTY, op = OP, left = a, right = b)).execute_func::<(), $retty>(0, ()) == Ok($func(a, b) as $retty)
+                        ", retty = RETTY, op = OP, left = a, right = b)).execute_func::<(), $retty>(0, ()), $func(a, b) as $retty)
                     }
 
                     fn lit_reg(a: f32, b: f32) -> bool {
@@ -333,7 +400,7 @@ mod opf32 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(f32,), $retty>(0, (b,)) == Ok($func(a, b) as $retty)
+                        same_result(translated.execute_func::<(f32,), $retty>(0, (b,)), $func(a, b) as $retty)
                     }
 
                     fn reg_lit(a: f32, b: f32) -> bool {
@@ -346,7 +413,7 @@ mod opf32 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(f32,), $retty>(0, (a,)) == Ok($func(a, b) as $retty)
+                        same_result(translated.execute_func::<(f32,), $retty>(0, (a,)), $func(a, b) as $retty)
                     }
                 }
             }
@@ -359,11 +426,11 @@ mod opf32 {
         };
         ($name:ident, $func:expr, $out_ty:ty) => {
             mod $name {
-                use super::{translate_wat, ExecutableModule};
+                use super::{same_result, translate_wat, Instance};
                 use std::sync::Once;
 
                 lazy_static! {
-                    static ref AS_PARAM: ExecutableModule = translate_wat(concat!(
+                    static ref AS_PARAM: Instance = translate_wat(concat!(
                         "(module (func (param f32) (result ",
                         stringify!($out_ty),
                         ")
@@ -377,7 +444,7 @@ mod opf32 {
                     fn as_param(a: f32) -> bool {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| AS_PARAM.disassemble());
-                        AS_PARAM.execute_func::<(f32,), $out_ty>(0, (a,)) == Ok($func(a))
+                        same_result(AS_PARAM.execute_func::<(f32,), $out_ty>(0, (a,)), $func(a))
                     }
 
                     fn lit(a: f32) -> bool {
@@ -388,7 +455,7 @@ mod opf32 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(), $out_ty>(0, ()) == Ok($func(a))
+                        same_result(translated.execute_func::<(), $out_ty>(0, ()), $func(a))
                     }
                 }
             }
@@ -402,13 +469,23 @@ mod opf32 {
     binop_test!(lt, |a, b| a < b, i32);
     binop_test!(ge, |a, b| a >= b, i32);
     binop_test!(le, |a, b| a <= b, i32);
+    binop_test!(eq, |a, b| a == b, i32);
+    binop_test!(ne, |a, b| a != b, i32);
+    binop_test!(div, |a, b| a / b);
+    binop_test!(min, |a: f32, b| a.min(b));
+    binop_test!(max, |a: f32, b| a.max(b));
+    binop_test!(copysign, |a: f32, b| a.copysign(b));
 
     unop_test!(neg, |a: f32| -a);
     unop_test!(abs, |a: f32| a.abs());
+    unop_test!(sqrt, |a: f32| a.sqrt());
+    unop_test!(ceil, |a: f32| a.ceil());
+    unop_test!(floor, |a: f32| a.floor());
+    unop_test!(trunc, |a: f32| a.trunc());
 }
 
 mod opf64 {
-    use super::{translate_wat, ExecutableModule};
+    use super::{same_result, translate_wat, Instance};
 
     macro_rules! binop_test {
         ($op:ident, $func:expr) => {
@@ -416,13 +493,13 @@ mod opf64 {
         };
         ($op:ident, $func:expr, $retty:ident) => {
             mod $op {
-                use super::{translate_wat, ExecutableModule};
+                use super::{same_result, translate_wat, Instance};
 
                 const RETTY: &str = stringify!($retty);
                 const OP: &str = stringify!($op);
 
                 lazy_static! {
-                    static ref AS_PARAMS: ExecutableModule = translate_wat(&format!("
+                    static ref AS_PARAMS: Instance = translate_wat(&format!("
                         (module (func (param f64) (param f64) (result {retty})
                             (f64.{op} (get_local 0) (get_local 1))))
                     ", retty = RETTY, op = OP));
@@ -430,14 +507,14 @@ mod opf64 {
 
                 quickcheck! {
                     fn as_params(a: f64, b: f64) -> bool {
-                        AS_PARAMS.execute_func::<(f64, f64), $retty>(0, (a, b)) == Ok($func(a, b) as $retty)
+                        same_result(AS_PARAMS.execute_func::<(f64, f64), $retty>(0, (a, b)), $func(a, b) as $retty)
                     }
 
                     fn lit_lit(a: f64, b: f64) -> bool {
-                        translate_wat(&format!("
+                        same_result(translate_wat(&format!("
                             (module (func (result {retty})
                                 (f64.{op} (f64.const {left}) (f64.const {right}))))
-                        ", retty = RETTY, op = OP, left = a, right = b)).execute_func::<(), $retty>(0, ()) == Ok($func(a, b) as $retty)
+                        ", retty = RETTY, op = OP, left = a, right = b)).execute_func::<(), $retty>(0, ()), $func(a, b) as $retty)
                     }
 
                     fn lit_reg(a: f64, b: f64) -> bool {
@@ -450,7 +527,7 @@ mod opf64 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(f64,), $retty>(0, (b,)) == Ok($func(a, b) as $retty)
+                        same_result(translated.execute_func::<(f64,), $retty>(0, (b,)), $func(a, b) as $retty)
                     }
 
                     fn reg_lit(a: f64, b: f64) -> bool {
@@ -463,7 +540,7 @@ mod opf64 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(f64,), $retty>(0, (a,)) == Ok($func(a, b) as $retty)
+                        same_result(translated.execute_func::<(f64,), $retty>(0, (a,)), $func(a, b) as $retty)
                     }
                 }
             }
@@ -476,11 +553,11 @@ mod opf64 {
         };
         ($name:ident, $func:expr, $out_ty:ty) => {
             mod $name {
-                use super::{translate_wat, ExecutableModule};
+                use super::{same_result, translate_wat, Instance};
                 use std::sync::Once;
 
                 lazy_static! {
-                    static ref AS_PARAM: ExecutableModule = translate_wat(concat!(
+                    static ref AS_PARAM: Instance = translate_wat(concat!(
                         "(module (func (param f64) (result ",
                         stringify!($out_ty),
                         ")
@@ -494,7 +571,7 @@ mod opf64 {
                     fn as_param(a: f64) -> bool {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| AS_PARAM.disassemble());
-                        AS_PARAM.execute_func::<(f64,), $out_ty>(0, (a,)) == Ok($func(a))
+                        same_result(AS_PARAM.execute_func::<(f64,), $out_ty>(0, (a,)), $func(a))
                     }
 
                     fn lit(a: f64) -> bool {
@@ -505,7 +582,7 @@ mod opf64 {
                         static ONCE: Once = Once::new();
                         ONCE.call_once(|| translated.disassemble());
 
-                        translated.execute_func::<(), $out_ty>(0, ()) == Ok($func(a))
+                        same_result(translated.execute_func::<(), $out_ty>(0, ()), $func(a))
                     }
                 }
             }
@@ -519,9 +596,259 @@ mod opf64 {
     binop_test!(lt, |a, b| a < b, i32);
     binop_test!(ge, |a, b| a >= b, i32);
     binop_test!(le, |a, b| a <= b, i32);
+    binop_test!(eq, |a, b| a == b, i32);
+    binop_test!(ne, |a, b| a != b, i32);
+    binop_test!(div, |a, b| a / b);
+    binop_test!(min, |a: f64, b| a.min(b));
+    binop_test!(max, |a: f64, b| a.max(b));
+    binop_test!(copysign, |a: f64, b| a.copysign(b));
 
     unop_test!(neg, |a: f64| -a);
     unop_test!(abs, |a: f64| a.abs());
+    unop_test!(sqrt, |a: f64| a.sqrt());
+    unop_test!(ceil, |a: f64| a.ceil());
+    unop_test!(floor, |a: f64| a.floor());
+    unop_test!(trunc, |a: f64| a.trunc());
+}
+
+mod signatures {
+    use super::{same_result, translate_wat, Instance};
+    use std::iter;
+
+    /// The number of each type of parameter in `MANY_PARAMS`, which is enough that some of
+    /// the integers are passed on the stack, and as many floats as can be passed at all.
+    const PAIRS: usize = 8;
+
+    lazy_static! {
+        /// Function `i` returns parameter `i`.
+        static ref MIXED: Instance = translate_wat(
+            "(module
+                (func (param i32 i64 f32 f64) (result i32) (get_local 0))
+                (func (param i32 i64 f32 f64) (result i64) (get_local 1))
+                (func (param i32 i64 f32 f64) (result f32) (get_local 2))
+                (func (param i32 i64 f32 f64) (result f64) (get_local 3)))",
+        );
+        /// Function `i` returns parameter `i`, where the parameters alternate between `i64`
+        /// and `f64`.
+        static ref MANY_PARAMS: Instance = translate_wat(&format!(
+            "(module {})",
+            (0..PAIRS * 2)
+                .map(|i| format!(
+                    "(func (param {}) (result {}) (get_local {}))",
+                    "i64 f64 ".repeat(PAIRS),
+                    if i % 2 == 0 { "i64" } else { "f64" },
+                    i
+                ))
+                .collect::<String>()
+        ));
+    }
+
+    quickcheck! {
+        fn mixed_params(a: i32, b: i64, c: f32, d: f64) -> bool {
+            let args = (a, b, c, d);
+
+            same_result(MIXED.execute_func(0, args), a)
+                && same_result(MIXED.execute_func(1, args), b)
+                && same_result(MIXED.execute_func(2, args), c)
+                && same_result(MIXED.execute_func(3, args), d)
+        }
+
+        fn many_params(ints: Vec<i64>, floats: Vec<f64>) -> bool {
+            let ints = ints.into_iter().chain(iter::repeat(1)).take(PAIRS).collect::<Vec<_>>();
+            let floats = floats
+                .into_iter()
+                .chain(iter::repeat(1.5))
+                .take(PAIRS)
+                .collect::<Vec<_>>();
+            let (i, f) = (&ints, &floats);
+            let args = (
+                i[0], f[0], i[1], f[1], i[2], f[2], i[3], f[3], i[4], f[4], i[5], f[5], i[6], f[6],
+                i[7], f[7],
+            );
+
+            (0..PAIRS).all(|n| {
+                same_result(MANY_PARAMS.execute_func(n as u32 * 2, args), i[n])
+                    && same_result(MANY_PARAMS.execute_func(n as u32 * 2 + 1, args), f[n])
+            })
+        }
+    }
+
+    #[test]
+    fn checks_signature() {
+        assert!(MIXED
+            .execute_func::<(i32, i64, f32, f64), i64>(0, (1, 2, 3., 4.))
+            .is_err());
+        assert!(MIXED
+            .execute_func::<(i64, i32, f32, f64), i32>(0, (1, 2, 3., 4.))
+            .is_err());
+        assert!(MIXED
+            .execute_func::<(i32, i64, f32), i32>(0, (1, 2, 3.))
+            .is_err());
+    }
+}
+
+mod loop_headers {
+    use super::translate_wat;
+    use std::fmt::Write;
+
+    /// A loop that runs `iterations` times, carrying `locals` round it. Each iteration adds
+    /// local `b` to local `a` for every `(a, b)` in `adds`, then permutes the locals with
+    /// `permutation`, so that every back-edge has to move values between locations.
+    struct CarriedLoop {
+        /// Whether each local is an `f64` rather than an `i64`.
+        locals: Vec<bool>,
+        adds: Vec<(usize, usize)>,
+        permutation: Vec<usize>,
+    }
+
+    impl CarriedLoop {
+        fn new(types: Vec<bool>, adds: Vec<(u8, u8)>, swaps: Vec<(u8, u8)>) -> Self {
+            let n = types.len();
+            let same_type = |a: usize, b: usize| types[a] == types[b];
+            let pairs = |pairs: Vec<(u8, u8)>| {
+                pairs
+                    .into_iter()
+                    .map(|(a, b)| (a as usize % n, b as usize % n))
+                    .filter(|&(a, b)| same_type(a, b))
+                    .collect::<Vec<_>>()
+            };
+
+            let mut permutation = (0..n).collect::<Vec<_>>();
+            for (a, b) in pairs(swaps) {
+                permutation.swap(a, b);
+            }
+
+            CarriedLoop {
+                adds: pairs(adds),
+                locals: types,
+                permutation,
+            }
+        }
+
+        fn initial(&self, i: usize) -> u64 {
+            i as u64 * 7 + 3
+        }
+
+        /// A function that runs the loop as many times as its parameter says and returns a
+        /// checksum of the locals, treating `f64`s as their bits.
+        fn wat(&self) -> String {
+            let ty = |i: usize| if self.locals[i] { "f64" } else { "i64" };
+            let local = |i: usize| i + 1;
+            let mut out = "(module (func (param i32) (result i64)".to_string();
+            for i in 0..self.locals.len() {
+                write!(out, " (local {})", ty(i)).unwrap();
+            }
+            for i in 0..self.locals.len() {
+                write!(
+                    out,
+                    " (set_local {} ({}.const {}))",
+                    local(i),
+                    ty(i),
+                    self.initial(i)
+                )
+                .unwrap();
+            }
+
+            out.push_str(
+                " (block $done (loop $top
+                    (br_if $done (i32.eqz (get_local 0)))
+                    (set_local 0 (i32.sub (get_local 0) (i32.const 1)))",
+            );
+            for &(a, b) in &self.adds {
+                write!(
+                    out,
+                    " (set_local {a} ({ty}.add (get_local {a}) (get_local {b})))",
+                    a = local(a),
+                    b = local(b),
+                    ty = ty(a)
+                )
+                .unwrap();
+            }
+            for &from in &self.permutation {
+                write!(out, " (get_local {})", local(from)).unwrap();
+            }
+            for to in (0..self.locals.len()).rev() {
+                write!(out, " (set_local {})", local(to)).unwrap();
+            }
+            out.push_str(" (br $top)))");
+
+            out.push_str(" (i64.const 0)");
+            for i in 0..self.locals.len() {
+                let value = if self.locals[i] {
+                    format!("(i64.reinterpret/f64 (get_local {}))", local(i))
+                } else {
+                    format!("(get_local {})", local(i))
+                };
+                write!(out, " (i64.mul (i64.const 31)) (i64.add {})", value).unwrap();
+            }
+
+            out.push_str("))");
+            out
+        }
+
+        fn run(&self, iterations: u32) -> u64 {
+            let mut values = (0..self.locals.len())
+                .map(|i| {
+                    if self.locals[i] {
+                        (self.initial(i) as f64).to_bits()
+                    } else {
+                        self.initial(i)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            for _ in 0..iterations {
+                for &(a, b) in &self.adds {
+                    values[a] = if self.locals[a] {
+                        (f64::from_bits(values[a]) + f64::from_bits(values[b])).to_bits()
+                    } else {
+                        values[a].wrapping_add(values[b])
+                    };
+                }
+                values = self.permutation.iter().map(|&from| values[from]).collect();
+            }
+
+            values
+                .into_iter()
+                .fold(0u64, |acc, value| acc.wrapping_mul(31).wrapping_add(value))
+        }
+    }
+
+    quickcheck! {
+        fn carried_values(
+            types: Vec<bool>,
+            adds: Vec<(u8, u8)>,
+            swaps: Vec<(u8, u8)>,
+            iterations: u8
+        ) -> bool {
+            if types.is_empty() {
+                return true;
+            }
+
+            let lp = CarriedLoop::new(types, adds, swaps);
+            let iterations = u32::from(iterations % 8);
+            let translated = translate_wat(&lp.wat());
+
+            translated.execute_func::<(u32,), u64>(0, (iterations,)) == Ok(lp.run(iterations))
+        }
+    }
+
+    /// More locals of each type than there are registers to pin them to, so that some of the
+    /// loop's parameters live on the stack.
+    #[test]
+    fn more_parameters_than_registers() {
+        let types = (0..40).map(|i| i % 3 == 0).collect::<Vec<_>>();
+        let pairs = |step: u8| (0..40).map(|i| (i, (i + step) % 40)).collect::<Vec<_>>();
+        let lp = CarriedLoop::new(types, pairs(3), pairs(6));
+        let translated = translate_wat(&lp.wat());
+
+        for iterations in 0..5 {
+            assert_eq!(
+                translated.execute_func::<(u32,), u64>(0, (iterations,)),
+                Ok(lp.run(iterations))
+            );
+        }
+    }
 }
 
 quickcheck! {
@@ -542,7 +869,7 @@ quickcheck! {
         "#;
 
         lazy_static! {
-            static ref TRANSLATED: ExecutableModule = {let out = translate_wat(CODE); out.disassemble(); out};
+            static ref TRANSLATED: Instance = {let out = translate_wat(CODE); out.disassemble(); out};
         }
 
         let out = TRANSLATED.execute_func::<(u32, u32), u32>(0, (a, b));
@@ -652,6 +979,71 @@ fn brif_block_passthru() {
     assert_eq!(execute_wat(code, 0, 3), 6);
 }
 
+// Tests that `eqz` feeding directly into `br_if` branches on the flags correctly,
+// including when another comparison result is live underneath it.
+#[test]
+fn brif_eqz() {
+    let code = r#"
+(module
+  (func (param i32) (param i32) (result i32)
+    (block (result i32)
+        (i32.lt_u (get_local 0) (get_local 1))
+        (i32.eqz (get_local 0))
+        br_if 0
+        (i32.eqz (get_local 1))
+        i32.add
+    )
+  )
+)
+    "#;
+    assert_eq!(execute_wat(code, 0, 3), 1);
+    assert_eq!(execute_wat(code, 1, 3), 1);
+    assert_eq!(execute_wat(code, 3, 1), 0);
+    assert_eq!(execute_wat(code, 3, 0), 1);
+}
+
+// Enough live locals to force spilling, which are then overwritten so that the slots of the
+// dropped values get reused for later spills.
+#[test]
+fn stack_slot_reuse() {
+    const LOCALS: u32 = 24;
+
+    let mut body = String::new();
+    for i in 0..LOCALS {
+        body += &format!(
+            "(set_local {} (i32.add (get_local 0) (i32.const {})))\n",
+            i + 2,
+            i
+        );
+    }
+    for i in 0..LOCALS {
+        body += &format!(
+            "(set_local {} (i32.mul (get_local {}) (get_local 1)))\n",
+            i + 2,
+            (i + 1) % LOCALS + 2
+        );
+    }
+    body += "(get_local 2)\n";
+    for i in 1..LOCALS {
+        body += &format!("(get_local {}) i32.add\n", i + 2);
+    }
+
+    let code = format!(
+        "(module (func (param i32) (param i32) (result i32) (local {}) {}))",
+        "i32 ".repeat(LOCALS as usize),
+        body
+    );
+
+    for &(a, b) in &[(0u32, 1u32), (3, 7), (100, 2)] {
+        let mut locals = (0..LOCALS).map(|i| a + i).collect::<Vec<_>>();
+        for i in 0..LOCALS as usize {
+            locals[i] = locals[(i + 1) % LOCALS as usize].wrapping_mul(b);
+        }
+        let expected = locals.iter().fold(0u32, |acc, &l| acc.wrapping_add(l));
+        assert_eq!(execute_wat(&code, a, b), expected);
+    }
+}
+
 quickcheck! {
     #[test]
     fn literals(a: i32, b: i64, c: i32, d: i64) -> bool {
@@ -735,6 +1127,25 @@ fn wrong_type() {
     );
 }
 
+#[test]
+fn defined_globals() {
+    let code = r#"
+(module
+  (global $count (mut i64) (i64.const 40))
+  (global $scale f64 (f64.const 0.5))
+  (func (result i64)
+    (set_global $count (i64.add (get_global $count) (i64.const 1)))
+    (get_global $count))
+  (func (result f64)
+    (get_global $scale)))
+    "#;
+
+    let translated = translate_wat(code);
+    assert_eq!(translated.execute_func::<(), i64>(0, ()), Ok(41));
+    assert_eq!(translated.execute_func::<(), i64>(0, ()), Ok(42));
+    assert_eq!(translated.execute_func::<(), f64>(1, ()), Ok(0.5));
+}
+
 #[test]
 fn wrong_index() {
     let code = r#"
@@ -976,14 +1387,54 @@ fn br_table() {
     assert_eq!(translated.execute_func::<_, u32>(0, (8u32,)), Ok(126));
 }
 
+/// Tables with few runs of indices with the same target are lowered to compares, and others
+/// to a jump table.
+#[test]
+fn br_table_lowerings() {
+    fn table(targets: &str) -> String {
+        format!(
+            r"
+(module
+  (func (param $i i32) (result i32)
+    (block $4
+      (block $3
+        (block $2
+          (block $1
+            (block $0
+              (br_table {} (get_local $i)))
+            (return (i32.const 10)))
+          (return (i32.const 11)))
+        (return (i32.const 12)))
+      (return (i32.const 13)))
+    (i32.const 14)))
+",
+            targets
+        )
+    }
+
+    let runs = translate_wat(&table("$0 $0 $0 $1 $1 $1 $1 $0 $4"));
+    let expected = [10, 10, 10, 11, 11, 11, 11, 10, 14, 14];
+    for (i, &expected) in expected.iter().enumerate() {
+        assert_eq!(runs.execute_func::<_, u32>(0, (i as u32,)), Ok(expected));
+    }
+    assert_eq!(runs.execute_func::<_, u32>(0, (u32::max_value(),)), Ok(14));
+
+    let dense = translate_wat(&table("$2 $0 $3 $1 $4 $2 $3"));
+    let expected = [12, 10, 13, 11, 14, 12, 13, 13];
+    for (i, &expected) in expected.iter().enumerate() {
+        assert_eq!(dense.execute_func::<_, u32>(0, (i as u32,)), Ok(expected));
+    }
+    assert_eq!(dense.execute_func::<_, u32>(0, (u32::max_value(),)), Ok(13));
+}
+
 macro_rules! test_select {
     ($name:ident, $ty:ident) => {
         mod $name {
-            use super::{translate_wat, ExecutableModule};
+            use super::{translate_wat, Instance};
             use std::sync::Once;
 
             lazy_static! {
-                static ref AS_PARAMS: ExecutableModule = translate_wat(&format!(
+                static ref AS_PARAMS: Instance = translate_wat(&format!(
                     "
                     (module
                         (func (param {ty}) (param {ty}) (param i32) (result {ty})
@@ -1024,11 +1475,6572 @@ macro_rules! test_select {
 test_select!(select32, i32);
 test_select!(select64, i64);
 
-#[cfg(feature = "bench")]
-mod benches {
-    extern crate test;
+mod validation {
+    use crate::module::translate_only;
 
-    use super::{translate, wabt, FIBONACCI, FIBONACCI_OPT};
+    fn unvalidated_wasm(wat: &str) -> Vec<u8> {
+        wabt::Wat2Wasm::new()
+            .validate(false)
+            .convert(wat)
+            .unwrap()
+            .as_ref()
+            .to_vec()
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(translate_only(b"\0asm\x01\0\0\0\x01").is_err());
+        assert!(translate_only(b"\0wasm\x01\0\0\0").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let wasm = unvalidated_wasm("(module (func (result i32) (i64.const 0)))");
+        assert!(translate_only(&wasm).is_err());
+
+        let wasm = unvalidated_wasm("(module (func (call 1)))");
+        assert!(translate_only(&wasm).is_err());
+    }
+
+    #[test]
+    fn unsupported() {
+        let wasm = unvalidated_wasm("(module (memory 1) (memory 1))");
+        assert!(translate_only(&wasm).is_err());
+
+        let wasm = unvalidated_wasm("(module (table 1 anyfunc) (table 1 anyfunc))");
+        assert!(translate_only(&wasm).is_err());
+    }
+}
+
+mod data_segments {
+    use super::translate_wat;
+    use crate::error::Error;
+    use crate::module::{translate_only, DataSegment, DataSegmentKind, SegmentOffset};
+    use crate::{translate, ExecutionError, HostMemory, Instance, InstanceImports};
+    use std::sync::Arc;
+
+    const HEADER: &[u8] = &[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+    // One memory with one page as both initial and maximum size
+    const MEMORY: &[u8] = &[0x05, 0x04, 0x01, 0x01, 0x01, 0x01];
+
+    fn module(sections: &[&[u8]]) -> Vec<u8> {
+        let mut out = HEADER.to_vec();
+        out.extend(MEMORY);
+        for section in sections {
+            out.extend(*section);
+        }
+        out
+    }
+
+    #[test]
+    fn passive() {
+        let wasm = module(&[
+            &[0x0c, 0x01, 0x01],
+            &[0x0b, 0x06, 0x01, 0x01, 0x03, b'a', b'b', b'c'],
+        ]);
+        let translated = translate_only(&wasm).unwrap();
+
+        assert_eq!(
+            translated.data_segments(),
+            &[DataSegment {
+                kind: DataSegmentKind::Passive,
+                data: b"abc".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn active() {
+        let wasm = module(&[&[
+            0x0b, 0x09, 0x01, 0x00, 0x41, 0x10, 0x0b, 0x03, b'a', b'b', b'c',
+        ]]);
+        let translated = translate_only(&wasm).unwrap();
+
+        assert_eq!(
+            translated.data_segments(),
+            &[DataSegment {
+                kind: DataSegmentKind::Active {
+                    memory_index: 0,
+                    offset: SegmentOffset::Const(16),
+                },
+                data: b"abc".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn data_count_mismatch() {
+        let wasm = module(&[
+            &[0x0c, 0x01, 0x02],
+            &[0x0b, 0x06, 0x01, 0x01, 0x03, b'a', b'b', b'c'],
+        ]);
+
+        assert!(translate_only(&wasm).is_err());
+    }
+
+    #[test]
+    fn initialized() {
+        let mut translated = translate_wat(
+            r#"
+(module
+  (memory (export "mem") 1 1)
+  (data (i32.const 16) "abc")
+  (data (i32.const 17) "xy")
+  (func (param i32) (result i32)
+    (i32.load8_u (get_local 0))))
+"#,
+        );
+
+        assert_eq!(
+            translated.execute_func::<_, u32>(0, (16u32,)),
+            Ok(b'a' as u32)
+        );
+        assert_eq!(
+            translated.execute_func::<_, u32>(0, (18u32,)),
+            Ok(b'y' as u32)
+        );
+        assert_eq!(&translated.memory_mut("mem").unwrap()[15..20], b"\0axy\0");
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (memory 1 1)
+  (data (i32.const 65535) "ab"))
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            translate(&wasm).err(),
+            Some(Error::Instantiation(ExecutionError::SegmentOutOfBounds))
+        );
+    }
+
+    #[test]
+    fn earlier_segments_written() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (import "env" "mem" (memory 1))
+  (data (i32.const 0) "ok")
+  (data (i32.const 65536) "!"))
+"#,
+        )
+        .unwrap();
+        let memory = Arc::new(HostMemory::new(1));
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", memory.clone());
+
+        assert_eq!(
+            Instance::with_imports(Arc::new(translate_only(&wasm).unwrap()), &imports).err(),
+            Some(ExecutionError::SegmentOutOfBounds)
+        );
+        assert_eq!(unsafe { *memory.as_ptr() }, b'o');
+    }
+}
+
+mod tables {
+    use super::translate_wat;
+    use crate::error::Error;
+    use crate::{
+        module::translate_only_with, translate, ExecutionError, HostError, HostFunctions,
+        TranslateOptions, VmCtx,
+    };
+
+    const CODE: &str = r#"
+(module
+  (type $unop (func (param i32) (result i32)))
+  (table 2 4 anyfunc)
+  (func (param i32) (param i32) (result i32)
+    (call_indirect (type $unop) (get_local 1) (get_local 0)))
+  (func (type $unop)
+    (i32.add (get_local 0) (i32.const 1))))
+"#;
+
+    #[test]
+    fn populate_from_host() {
+        let mut translated = translate_wat(CODE);
+        translated.disassemble();
+
+        let factor = 3i32;
+        let incr = translated.func_ref(1).unwrap();
+        let scale = translated
+            .host_func_ref(move |_: &VmCtx, x: i32| Ok(factor * x))
+            .unwrap();
+
+        assert_eq!(translated.table_size(), 2);
+        assert_eq!(translated.table_get(0), Ok(None));
+
+        translated.table_set(0, Some(incr)).unwrap();
+        translated.table_set(1, Some(scale)).unwrap();
+        assert_eq!(translated.table_get(1), Ok(Some(scale)));
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 5u32)), Ok(6));
+        assert_eq!(translated.execute_func::<_, u32>(0, (1u32, 5u32)), Ok(15));
+
+        assert_eq!(translated.table_grow(2, Some(incr)), Some(2));
+        assert_eq!(translated.table_size(), 4);
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32, 7u32)), Ok(8));
+
+        assert_eq!(translated.table_grow(1, None), None);
+        assert_eq!(
+            translated.table_set(4, None),
+            Err(ExecutionError::TableIndexOutOfBounds)
+        );
+    }
+
+    // Like an imported host function, one in a table is called with the `VmCtx` of its
+    // instance rather than a context of its own.
+    #[test]
+    fn host_func_gets_vmctx() {
+        let mut translated = translate_wat(CODE);
+        translated.set_host_state(3i32);
+        let scale = translated
+            .host_func_ref(|ctx: &VmCtx, x: i32| {
+                let factor = ctx
+                    .host_state::<i32>()
+                    .ok_or_else(|| HostError("no state".to_string()))?;
+                Ok(factor * x)
+            })
+            .unwrap();
+
+        translated.table_set(0, Some(scale)).unwrap();
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 5u32)), Ok(15));
+    }
+
+    #[test]
+    fn populate_from_other_module() {
+        let mut translated = translate_wat(CODE);
+        let other = translate_wat(
+            r#"
+(module
+  (func (param i32) (result i32)
+    (i32.mul (get_local 0) (get_local 0))))
+"#,
+        );
+
+        translated
+            .table_set(0, Some(other.func_ref(0).unwrap()))
+            .unwrap();
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 5u32)), Ok(25));
+    }
+
+    #[test]
+    fn element_segments() {
+        let translated = translate_wat(
+            r#"
+(module
+  (type $unop (func (param i32) (result i32)))
+  (table 3 3 anyfunc)
+  (elem (i32.const 1) $double $incr)
+  (elem (i32.const 2) $double)
+  (func (param i32) (param i32) (result i32)
+    (call_indirect (type $unop) (get_local 1) (get_local 0)))
+  (func $incr (type $unop)
+    (i32.add (get_local 0) (i32.const 1)))
+  (func $double (type $unop)
+    (i32.mul (get_local 0) (i32.const 2))))
+"#,
+        );
+
+        assert_eq!(translated.table_get(0), Ok(None));
+        assert_eq!(
+            translated.table_get(1),
+            Ok(Some(translated.func_ref(2).unwrap()))
+        );
+        assert_eq!(translated.execute_func::<_, u32>(0, (1u32, 5u32)), Ok(10));
+        assert_eq!(translated.execute_func::<_, u32>(0, (2u32, 5u32)), Ok(10));
+    }
+
+    #[test]
+    fn imported_element() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (type $binop (func (param i32 i32) (result i32)))
+  (import "env" "sub" (func $sub (type $binop)))
+  (table 1 1 anyfunc)
+  (elem (i32.const 0) $sub)
+  (func (param i32 i32) (result i32)
+    (call_indirect (type $binop) (get_local 0) (get_local 1) (i32.const 0))))
+"#,
+        )
+        .unwrap();
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("env", "sub", |_: &VmCtx, a: i32, b: i32| Ok(a - b));
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        assert_eq!(translated.execute_func::<_, i32>(0, (5, 7)), Ok(-2));
+    }
+
+    // wabt doesn't support the reference types proposal, so this is assembled by hand
+    #[rustfmt::skip]
+    pub(super) const REFERENCE_TYPES: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        // Types: [i32] -> [i32], [i32 i32] -> [], [i32 i32] -> [i32], [i32] -> []
+        0x01, 0x15, 0x04,
+        0x60, 0x01, 0x7f, 0x01, 0x7f,
+        0x60, 0x02, 0x7f, 0x7f, 0x00,
+        0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+        0x60, 0x01, 0x7f, 0x00,
+        // Functions
+        0x03, 0x07, 0x06, 0x01, 0x00, 0x00, 0x03, 0x00, 0x02,
+        // (table 2 4 anyfunc)
+        0x04, 0x05, 0x01, 0x70, 0x01, 0x02, 0x04,
+        // (elem (i32.const 0) $incr)
+        0x09, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x04,
+        0x0a, 0x38, 0x06,
+        // (func $copy (param $dst i32) (param $src i32)
+        //   (table.set (get_local $dst) (table.get (get_local $src))))
+        0x0a, 0x00, 0x20, 0x00, 0x20, 0x01, 0x25, 0x00, 0x26, 0x00, 0x0b,
+        // (func $check (param i32) (result i32) (drop (table.get (get_local 0))) (get_local 0))
+        0x09, 0x00, 0x20, 0x00, 0x25, 0x00, 0x1a, 0x20, 0x00, 0x0b,
+        // (func $grow (param i32) (result i32) (table.grow (get_local 0)))
+        0x07, 0x00, 0x20, 0x00, 0xfc, 0x0f, 0x00, 0x0b,
+        // (func $clear (param i32) (table.set (get_local 0) (ref.null)))
+        0x07, 0x00, 0x20, 0x00, 0xd0, 0x26, 0x00, 0x0b,
+        // (func $incr (param i32) (result i32) (i32.add (get_local 0) (i32.const 1)))
+        0x07, 0x00, 0x20, 0x00, 0x41, 0x01, 0x6a, 0x0b,
+        // (func (param i32 i32) (result i32)
+        //   (call_indirect (type 0) (get_local 1) (get_local 0)))
+        0x09, 0x00, 0x20, 0x01, 0x20, 0x00, 0x11, 0x00, 0x00, 0x0b,
+    ];
+
+    #[test]
+    fn table_instructions() {
+        let translated = translate(REFERENCE_TYPES).unwrap();
+        translated.disassemble();
+
+        assert_eq!(translated.execute_func::<_, i32>(5, (0, 5)), Ok(6));
+        assert_eq!(translated.execute_func::<_, u32>(1, (1u32,)), Ok(1));
+        assert_eq!(translated.table_get(1), Ok(None));
+
+        assert_eq!(translated.execute_func::<_, ()>(0, (1u32, 0u32)), Ok(()));
+        assert_eq!(translated.table_get(1), translated.table_get(0));
+        assert_eq!(translated.execute_func::<_, i32>(5, (1, 7)), Ok(8));
+
+        assert_eq!(translated.execute_func::<_, ()>(3, (0u32,)), Ok(()));
+        assert_eq!(translated.table_get(0), Ok(None));
+
+        assert_eq!(translated.execute_func::<_, i32>(2, (2,)), Ok(2));
+        assert_eq!(translated.table_size(), 4);
+        assert_eq!(translated.execute_func::<_, u32>(1, (3u32,)), Ok(3));
+        assert_eq!(translated.table_get(3), Ok(None));
+        assert_eq!(translated.execute_func::<_, i32>(2, (1,)), Ok(-1));
+    }
+
+    #[test]
+    fn element_out_of_bounds() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (table 1 1 anyfunc)
+  (elem (i32.const 0) $f $f)
+  (func $f))
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            translate(&wasm).err(),
+            Some(Error::Instantiation(ExecutionError::SegmentOutOfBounds))
+        );
+    }
+}
+
+mod simple_context {
+    use crate::{
+        translate_function, CodeGenSession, DefinedFuncIndex, ModuleContext, SimpleContext,
+        TranslateOptions, ValueType,
+    };
+    use cranelift_codegen::{binemit, ir};
+    use std::{mem, ptr};
+    use wasmparser::{ModuleReader, SectionCode};
+
+    const CODE: &str = r#"
+(module
+  (import "env" "base" (global i32))
+  (global $count (mut i64) (i64.const 0))
+  (memory 1 1)
+  (func $incr (param i32) (result i32)
+    (i32.add (get_local 0) (i32.const 1)))
+  (func (param i32) (result i32)
+    (call $incr (get_local 0))))
+"#;
+
+    /// Records calls to other functions, which an embedder would link itself.
+    #[derive(Default)]
+    struct CallSink(Vec<ir::ExternalName>);
+
+    impl binemit::RelocSink for CallSink {
+        fn reloc_ebb(&mut self, _: binemit::CodeOffset, _: binemit::Reloc, _: binemit::CodeOffset) {
+            unreachable!()
+        }
+
+        fn reloc_external(
+            &mut self,
+            _: binemit::CodeOffset,
+            _: binemit::Reloc,
+            name: &ir::ExternalName,
+            _: binemit::Addend,
+        ) {
+            self.0.push(name.clone());
+        }
+
+        fn reloc_jt(&mut self, _: binemit::CodeOffset, _: binemit::Reloc, _: ir::JumpTable) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn describes_the_module() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let ctx = SimpleContext::new(&wasm, &TranslateOptions::default()).unwrap();
+
+        assert_eq!(ctx.defined_func_index(1), Some(1));
+        assert_eq!(&ctx.func_type(1).params[..], &[ValueType::I32]);
+        assert_eq!(ctx.defined_global_index(0), None);
+        assert_eq!(ctx.defined_global_index(1), Some(0));
+        assert_eq!(*ctx.global_type(0), ValueType::I32);
+        assert_eq!(*ctx.global_type(1), ValueType::I64);
+        assert_eq!(ctx.defined_memory_index(0), Some(0));
+
+        let layout = ctx.vmctx_layout();
+        assert_eq!(layout.num_defined_globals, 1);
+        assert_eq!(ctx.vmctx_vmglobal_definition(0), layout.offset_of_global(0));
+        assert!(ctx.vmctx_vmshared_signature_id(0) > layout.offset_of_global(0));
+    }
+
+    #[test]
+    fn translates_functions() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let ctx = SimpleContext::new(&wasm, &TranslateOptions::default()).unwrap();
+
+        let mut reader = ModuleReader::new(&wasm).unwrap();
+        let mut session = CodeGenSession::new(2, &ctx);
+        let mut calls = CallSink::default();
+        while !reader.eof() {
+            let section = reader.read().unwrap();
+            if let SectionCode::Code = section.code {
+                for (i, body) in section
+                    .get_code_section_reader()
+                    .unwrap()
+                    .into_iter()
+                    .enumerate()
+                {
+                    let body = body.unwrap();
+                    translate_function(&mut session, &mut calls, DefinedFuncIndex(i as u32), &body)
+                        .unwrap();
+                }
+            }
+        }
+        assert_eq!(calls.0, vec![ir::ExternalName::user(0, 0)]);
+
+        let code = session.into_translated_code_section().unwrap();
+        let incr: extern "sysv64" fn(*const u8, u32) -> u32 =
+            unsafe { mem::transmute(code.func_start(DefinedFuncIndex(0))) };
+        assert_eq!(incr(ptr::null(), 41), 42);
+    }
+}
+
+mod instances {
+    use crate::{module::translate_only, DefinedFuncIndex, Instance};
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (table 1 1 anyfunc)
+  (memory 1 1)
+  (func (param i32)
+    (i32.store (i32.const 0) (get_local 0)))
+  (func (result i32)
+    (i32.load (i32.const 0))))
+"#;
+
+    #[test]
+    fn share_code_not_state() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let module = Arc::new(translate_only(&wasm).unwrap());
+        let mut a = Instance::new(module.clone());
+        let b = Instance::new(module);
+
+        assert!(Arc::ptr_eq(a.module(), b.module()));
+        assert_eq!(
+            a.code_section().func_start(DefinedFuncIndex(0)),
+            b.code_section().func_start(DefinedFuncIndex(0))
+        );
+
+        assert_eq!(a.execute_func::<_, ()>(0, (5u32,)), Ok(()));
+        assert_eq!(a.execute_func::<_, u32>(1, ()), Ok(5));
+        assert_eq!(b.execute_func::<_, u32>(1, ()), Ok(0));
+
+        let func = a.func_ref(1).unwrap();
+        a.table_set(0, Some(func)).unwrap();
+        assert_eq!(a.table_get(0), Ok(Some(func)));
+        assert_eq!(b.table_get(0), Ok(None));
+    }
+}
+
+mod custom_sections {
+    use crate::{module::translate_only, CustomSection};
+
+    fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+        // Both lengths fit in a single byte of LEB128
+        let mut section = vec![0, (1 + name.len() + data.len()) as u8, name.len() as u8];
+        section.extend_from_slice(name.as_bytes());
+        section.extend_from_slice(data);
+        section
+    }
+
+    #[test]
+    fn kept_in_order() {
+        let mut wasm = wabt::Wat2Wasm::new()
+            .write_debug_names(true)
+            .convert(
+                r#"
+(module
+  (memory 1 1)
+  (func $first (result i32) (i32.const 1))
+  (func $add (param i32 i32) (result i32) (i32.add (get_local 0) (get_local 1)))
+  (data (i32.const 0) "\2a"))
+"#,
+            )
+            .unwrap()
+            .as_ref()
+            .to_vec();
+        wasm.extend(custom_section("target_features", b"\x01+\x04simd"));
+        wasm.extend(custom_section("target_features", b"\x00"));
+
+        let module = translate_only(&wasm).unwrap();
+        let names = module
+            .custom_sections()
+            .iter()
+            .map(|section| &section.name[..])
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["name", "target_features", "target_features"]);
+        assert_eq!(
+            module.custom_sections()[1],
+            CustomSection {
+                name: "target_features".to_string(),
+                data: b"\x01+\x04simd".to_vec(),
+            }
+        );
+        assert_eq!(
+            module.custom_section("target_features"),
+            Some(&b"\x01+\x04simd"[..])
+        );
+        assert_eq!(module.custom_section("producers"), None);
+
+        assert_eq!(module.func_name(0), Some("first"));
+        assert_eq!(module.func_name(1), Some("add"));
+        assert_eq!(module.func_name(2), None);
+
+        let instance = module.instantiate();
+        let disassembly = instance.code_section().disassembly().unwrap();
+        assert!(disassembly.contains("Function 1 (add):"), "{}", disassembly);
+        assert_eq!(instance.execute_func::<_, u32>(1, (2u32, 3u32)), Ok(5));
+    }
+
+    #[test]
+    fn malformed_name_section() {
+        let mut wasm = wabt::wat2wasm("(module (func (result i32) (i32.const 1)))").unwrap();
+        wasm.extend(custom_section("name", b"\x01\xff"));
+
+        let module = translate_only(&wasm).unwrap();
+        assert_eq!(module.custom_section("name"), Some(&b"\x01\xff"[..]));
+        assert_eq!(module.func_name(0), None);
+        assert_eq!(module.instantiate().execute_func::<_, u32>(0, ()), Ok(1));
+    }
+}
+
+#[cfg(feature = "wat")]
+mod wat {
+    use crate::{translate_path, translate_str};
+    use std::{env, fs, process};
+
+    const ADD: &str = r#"
+(module
+  (func (param i32 i32) (result i32) (i32.add (get_local 0) (get_local 1))))
+"#;
+
+    #[test]
+    fn from_str() {
+        let instance = translate_str(ADD).unwrap();
+        assert_eq!(instance.execute_func::<_, u32>(0, (2u32, 3u32)), Ok(5));
+
+        assert!(translate_str("(module (func (i32.add)))").is_err());
+        assert!(translate_str("(module").is_err());
+    }
+
+    #[test]
+    fn from_path() {
+        let dir = env::temp_dir();
+        let wat_path = dir.join(format!("lightbeam-{}.wat", process::id()));
+        let wasm_path = dir.join(format!("lightbeam-{}.wasm", process::id()));
+        fs::write(&wat_path, ADD).unwrap();
+        fs::write(&wasm_path, wabt::wat2wasm(ADD).unwrap()).unwrap();
+
+        for path in &[&wat_path, &wasm_path] {
+            let instance = translate_path(path).unwrap();
+            assert_eq!(instance.execute_func::<_, u32>(0, (2u32, 3u32)), Ok(5));
+            fs::remove_file(path).unwrap();
+        }
+
+        assert!(translate_path(&wat_path).is_err());
+    }
+}
+
+#[cfg(feature = "capi")]
+mod capi {
+    use crate::capi::*;
+    use std::ffi::CStr;
+    use std::ptr;
+
+    const MODULE: &str = r#"
+(module
+  (func (export "add") (param i32 i64) (result i64)
+    (i64.add (i64.extend_u/i32 (get_local 0)) (get_local 1)))
+  (func (export "scale") (param f32 f64) (result f64)
+    (f64.mul (f64.promote/f32 (get_local 0)) (get_local 1)))
+  (func (export "trap") (unreachable)))
+"#;
+
+    fn last_error() -> String {
+        let error = lightbeam_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn get_func(instance: *const crate::Instance, name: &[u8]) -> Option<u32> {
+        let mut func = 0;
+        if unsafe { lightbeam_get_func(instance, name.as_ptr() as _, &mut func) } {
+            Some(func)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn call() {
+        let wasm = wabt::wat2wasm(MODULE).unwrap();
+        let instance = unsafe { lightbeam_translate(wasm.as_ptr(), wasm.len()) };
+        assert!(!instance.is_null());
+
+        let add = get_func(instance, b"add\0").unwrap();
+        let args = [
+            Value {
+                kind: I32,
+                bits: u64::from(u32::max_value()),
+            },
+            Value { kind: I64, bits: 1 },
+        ];
+        let mut result = Value { kind: 0, bits: 0 };
+        assert!(unsafe { lightbeam_call(instance, add, args.as_ptr(), 2, &mut result) });
+        assert_eq!(
+            result,
+            Value {
+                kind: I64,
+                bits: 1 << 32
+            }
+        );
+
+        let scale = get_func(instance, b"scale\0").unwrap();
+        let args = [
+            Value {
+                kind: F32,
+                bits: u64::from(1.5f32.to_bits()),
+            },
+            Value {
+                kind: F64,
+                bits: 4f64.to_bits(),
+            },
+        ];
+        assert!(unsafe { lightbeam_call(instance, scale, args.as_ptr(), 2, &mut result) });
+        assert_eq!(f64::from_bits(result.bits), 6.);
+
+        // The arguments are checked against the function's type
+        assert!(!unsafe { lightbeam_call(instance, add, args.as_ptr(), 2, &mut result) });
+        assert!(last_error().contains("parameters"));
+        assert!(!unsafe { lightbeam_call(instance, add, ptr::null(), 0, &mut result) });
+        assert!(!unsafe { lightbeam_call(instance, 3, ptr::null(), 0, &mut result) });
+        assert_eq!(get_func(instance, b"sub\0"), None);
+        assert!(last_error().contains("sub"));
+
+        unsafe { lightbeam_instance_delete(instance) };
+    }
+
+    #[test]
+    fn invalid_module() {
+        let instance = unsafe { lightbeam_translate(b"\0asm".as_ptr(), 4) };
+        assert!(instance.is_null());
+        assert!(!last_error().is_empty());
+    }
+
+    #[test]
+    fn trap_codes() {
+        let wasm = wabt::wat2wasm(MODULE).unwrap();
+        let instance = unsafe { lightbeam_translate(wasm.as_ptr(), wasm.len()) };
+        let code_section = unsafe { &*instance }.code_section();
+        let start = code_section.buffer().as_ptr();
+
+        let site = code_section.trap_sites()[0];
+        let code = unsafe { lightbeam_trap_code(instance, start.add(site.offset)) };
+        assert_eq!(code, 10);
+        assert_eq!(unsafe { lightbeam_trap_code(instance, start) }, 0);
+        assert_eq!(unsafe { lightbeam_trap_code(instance, ptr::null()) }, 0);
+
+        unsafe { lightbeam_instance_delete(instance) };
+    }
+}
+
+mod dead_stores {
+    use super::translate_wat;
+    use crate::DefinedFuncIndex;
+
+    const CODE: &str = r#"
+(module
+  (func (param i32) (result i32) (local i32)
+    (set_local 1 (i32.mul (get_local 0) (i32.const 3)))
+    (set_local 1 (i32.add (get_local 0) (i32.const 1)))
+    (get_local 1))
+  (func (param i32) (result i32) (local i32)
+    (set_local 1 (i32.add (get_local 0) (i32.const 1)))
+    (get_local 1))
+  (func (param i32) (result i32) (local i32)
+    (set_local 1 (i32.div_u (i32.const 10) (get_local 0)))
+    (drop (tee_local 1 (get_local 0)))
+    (set_local 1 (i32.add (get_local 1) (get_local 0)))
+    (set_local 1 (i32.mul (get_local 1) (i32.const 2)))
+    (get_local 1)))
+"#;
+
+    #[test]
+    fn pure_store_removed() {
+        let translated = translate_wat(CODE);
+        translated.disassemble();
+
+        let code = translated.code_section();
+        assert_eq!(
+            code.func_range(DefinedFuncIndex(0)).len(),
+            code.func_range(DefinedFuncIndex(1)).len()
+        );
+
+        for x in 0..10 {
+            assert_eq!(translated.execute_func::<_, u32>(0, (x,)), Ok(x + 1));
+        }
+    }
+
+    // The first store may trap so it must still be computed, and the `tee_local` is followed
+    // by a read so it must be kept.
+    #[test]
+    fn side_effects_kept() {
+        let translated = translate_wat(CODE);
+
+        for x in 1..10 {
+            assert_eq!(translated.execute_func::<_, u32>(2, (x,)), Ok(x * 4));
+        }
+    }
+}
+
+mod frame_pointer {
+    use super::{iterative_fib_baseline, translate_wat_with, FIBONACCI};
+    use crate::{index_space::DefinedFuncIndex, CodeGenOptions, FramePointer, Instance};
+
+    fn translate_wat(wat: &str) -> Instance {
+        translate_wat_with(
+            wat,
+            CodeGenOptions {
+                frame_pointer: FramePointer::Preserve,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// The instructions in `disassembly`, without their addresses and encodings.
+    fn instructions(disassembly: &str) -> Vec<String> {
+        disassembly
+            .lines()
+            .filter_map(|line| line.splitn(3, '\t').nth(2))
+            .map(|insn| insn.trim_end().replace('\t', " "))
+            .collect()
+    }
+
+    fn function_instructions(instance: &Instance, func: u32) -> Vec<String> {
+        let code = instance.code_section();
+        instructions(&code.function_disassembly(DefinedFuncIndex(func)).unwrap())
+    }
+
+    /// Check that every `ret` in `insns` is straight after `rbp` is restored.
+    fn assert_restores_rbp(insns: &[String]) {
+        assert!(insns.iter().any(|insn| insn == "ret"), "{:#?}", insns);
+        for pair in insns.windows(2).filter(|pair| pair[1] == "ret") {
+            assert_eq!(pair[0], "pop rbp", "{:#?}", insns);
+        }
+    }
+
+    #[test]
+    fn calls() {
+        let translated = translate_wat(FIBONACCI);
+
+        let insns = function_instructions(&translated, 0);
+        assert_eq!(insns[..2], ["push rbp", "mov rbp, rsp"]);
+        assert_restores_rbp(&insns);
+        // The frame pointer isn't allocated to values
+        assert_eq!(
+            insns.iter().filter(|insn| insn.contains("rbp")).count(),
+            2 + insns.iter().filter(|insn| *insn == "ret").count()
+        );
+
+        for x in 0..20 {
+            assert_eq!(
+                translated.execute_func::<_, u32>(0, (x,)),
+                Ok(iterative_fib_baseline(x))
+            );
+        }
+    }
+
+    // Returns through `br_if` and `br_table` go through the shared return label, which must
+    // restore the frame pointer too.
+    #[test]
+    fn early_returns() {
+        let translated = translate_wat(
+            "(module
+                (func (param i32) (param i32) (result i32)
+                    (br_if 0 (i32.const 1) (get_local 0))
+                    drop
+                    (block (result i32)
+                        (br_table 0 1 (i32.const 2) (get_local 1)))
+                    drop
+                    (i32.const 3)))",
+        );
+
+        assert_eq!(
+            function_instructions(&translated, 0)[..2],
+            ["push rbp", "mov rbp, rsp"]
+        );
+        // Including the shared return label after the function
+        let disassembly = translated.code_section().disassembly().unwrap();
+        assert_restores_rbp(&instructions(&disassembly));
+
+        assert_eq!(translated.execute_func::<_, u32>(0, (1u32, 0u32)), Ok(1));
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 0u32)), Ok(3));
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 1u32)), Ok(2));
+    }
+
+    // Arguments passed on the stack are above the saved frame pointer.
+    #[test]
+    fn stack_args() {
+        let translated = translate_wat(
+            "(module
+                (func (param i32 i32 i32 i32 i32 i32 i32) (result i32)
+                    (i32.sub (get_local 6) (get_local 5))))",
+        );
+
+        let insns = function_instructions(&translated, 0);
+        assert_eq!(insns[..2], ["push rbp", "mov rbp, rsp"]);
+        assert_restores_rbp(&insns);
+
+        assert_eq!(
+            translated.execute_func::<_, u32>(0, (1u32, 2u32, 3u32, 4u32, 5u32, 6u32, 10u32)),
+            Ok(4)
+        );
+    }
+
+    // Without a frame pointer, `rbp` is saved along with the other callee-saved registers by
+    // functions that make calls, so that it can hold one more value across them.
+    #[test]
+    fn omitted_rbp_is_allocated() {
+        let translated = translate_wat_with(
+            "(module
+                (func $id (param i64) (result i64) (get_local 0))
+                (func (param i64 i64 i64 i64 i64) (result i64) (local i64)
+                    (set_local 5 (i64.mul (get_local 0) (get_local 1)))
+                    (drop (call $id (i64.const 0)))
+                    (i64.add
+                        (i64.add
+                            (i64.add (get_local 0) (get_local 1))
+                            (i64.add (get_local 2) (get_local 3)))
+                        (i64.add (get_local 4) (get_local 5)))))",
+            CodeGenOptions {
+                frame_pointer: FramePointer::Omit,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            translated.execute_func::<_, u64>(1, (1u64, 2u64, 3u64, 4u64, 5u64)),
+            Ok(17)
+        );
+
+        // Six values are live across the call, and they're all kept in registers
+        let code = translated.code_section();
+        assert_eq!(code.function_stats(DefinedFuncIndex(1)).spills, 0);
+
+        let insns = function_instructions(&translated, 1);
+        assert!(insns.iter().any(|insn| insn == "push rbp"), "{:#?}", insns);
+        assert!(
+            insns.iter().any(|insn| insn.contains("rbp")
+                && !insn.starts_with("push")
+                && !insn.starts_with("pop")),
+            "{:#?}",
+            insns
+        );
+        assert_restores_rbp(&insns);
+
+        let unwind = code.unwind_info(DefinedFuncIndex(1));
+        assert!(unwind.rows.iter().any(|row| row.saved_regs == 6));
+        assert!(unwind.rows.iter().all(|row| !row.rbp_saved));
+
+        // Leaf functions still don't touch it
+        assert!(function_instructions(&translated, 0)
+            .iter()
+            .all(|insn| !insn.contains("rbp")));
+    }
+}
+
+mod code_layout {
+    use super::{iterative_fib_baseline, translate_wat, translate_wat_with, FIBONACCI};
+    use crate::{index_space::DefinedFuncIndex, CodeGenOptions, CodeLayout, TrapCode};
+
+    #[test]
+    fn aligned_on_huge_pages() {
+        let translated = translate_wat_with(
+            FIBONACCI,
+            CodeGenOptions {
+                code_layout: CodeLayout {
+                    function_alignment: 64,
+                    huge_pages: true,
+                },
+                ..Default::default()
+            },
+        );
+        translated.disassemble();
+
+        for x in 0..20 {
+            assert_eq!(
+                translated.execute_func::<_, u32>(0, (x,)),
+                Ok(iterative_fib_baseline(x))
+            );
+        }
+    }
+
+    #[test]
+    fn traps_share_a_stub_per_reason_after_the_body() {
+        let instance = translate_wat(
+            r#"
+(module
+  (memory 1 1)
+  (func (param i32 f32) (result i32)
+    (i32.add
+      (i32.add (i32.load (get_local 0)) (i32.load offset=4 (get_local 0)))
+      (i32.add (i32.trunc_s/f32 (get_local 1)) (i32.trunc_u/f32 (get_local 1)))))
+  (func (param i32) (result i32)
+    (if (get_local 0) (then (unreachable)))
+    (i32.load8_u (get_local 0))))
+"#,
+        );
+        let code = instance.code_section();
+
+        for (func, codes) in [
+            &[
+                TrapCode::HeapOutOfBounds,
+                TrapCode::BadConversionToInteger,
+                TrapCode::IntegerOverflow,
+            ][..],
+            &[TrapCode::UnreachableCodeReached, TrapCode::HeapOutOfBounds][..],
+        ]
+        .iter()
+        .enumerate()
+        {
+            let func = DefinedFuncIndex(func as u32);
+            let range = code.func_range(func);
+            let sites = code
+                .trap_sites()
+                .iter()
+                .filter(|site| range.contains(&site.offset))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                sites.iter().map(|site| site.code).collect::<Vec<_>>(),
+                *codes
+            );
+
+            // The stubs are the last thing in the function, one `ud2` after another
+            let end = range.start + code.function_stats(func).code_size;
+            for (i, site) in sites.iter().enumerate() {
+                assert_eq!(site.offset, end - 2 * (sites.len() - i));
+                assert_eq!(code.buffer()[site.offset..site.offset + 2], [0x0f, 0x0b]);
+                assert_eq!(code.trap_code(site.offset), Some(site.code));
+            }
+        }
+        assert_eq!(code.trap_code(0), None);
+
+        assert_eq!(instance.execute_func::<_, u32>(1, (0,)), Ok(0));
+    }
+}
+
+mod stack_probes {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    /// `or dword [rsp], 0`.
+    const PROBE: [u8; 4] = [0x83, 0x0c, 0x24, 0x00];
+
+    /// A loop that keeps `locals` unused locals alive, which get a stack slot each.
+    fn sum_with_locals(locals: usize) -> String {
+        format!(
+            r#"
+(module
+  (func (param i32) (result i32) (local i32) (local{})
+    (loop $top
+      (set_local 1 (i32.add (get_local 1) (get_local 0)))
+      (set_local 0 (i32.sub (get_local 0) (i32.const 1)))
+      (br_if $top (get_local 0)))
+    (get_local 1)))
+"#,
+            " i64".repeat(locals)
+        )
+    }
+
+    fn probes(locals: usize) -> usize {
+        let instance = translate_wat(&sum_with_locals(locals));
+        assert_eq!(instance.execute_func::<_, u32>(0, (10u32,)), Ok(55));
+
+        let code = instance.code_section();
+        code.buffer()[code.func_range(DefinedFuncIndex(0))]
+            .windows(PROBE.len())
+            .filter(|window| *window == PROBE)
+            .count()
+    }
+
+    #[test]
+    fn large_frames_touch_every_page() {
+        assert_eq!(probes(16), 0);
+        // Each page on the way down, then the end of the frame
+        assert_eq!(probes(1200), 3);
+    }
+}
+
+mod trap_on_overflow {
+    use super::translate_wat_with;
+    use crate::{
+        index_space::DefinedFuncIndex, CodeGenOptions, ExecutionError, Instance, TrapCode,
+        ARITHMETIC_OVERFLOW,
+    };
+
+    const CODE: &str = r#"
+(module
+  (func (param i32 i32) (result i32)
+    (i32.add (get_local 0) (get_local 1)))
+  (func (param i64) (result i64)
+    (i64.mul (get_local 0) (i64.const 3)))
+  (func (result i32)
+    (i32.sub (i32.const 0x80000000) (i32.const 1)))
+  (func (result i32)
+    (i32.sub (i32.const 5) (i32.const 1))))
+"#;
+
+    fn translate(trap_on_overflow: bool) -> Instance {
+        translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                trap_on_overflow,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn trap_codes(instance: &Instance, func: u32) -> Vec<TrapCode> {
+        let code = instance.code_section();
+        let range = code.func_range(DefinedFuncIndex(func));
+        code.trap_sites()
+            .iter()
+            .filter(|site| range.contains(&site.offset))
+            .map(|site| site.code)
+            .collect()
+    }
+
+    #[test]
+    fn checked() {
+        let instance = translate(true);
+
+        for func in 0..3 {
+            assert_eq!(trap_codes(&instance, func), [ARITHMETIC_OVERFLOW]);
+        }
+        // Constants that don't overflow are folded without a check
+        assert_eq!(trap_codes(&instance, 3), []);
+
+        assert_eq!(instance.execute_func::<_, i32>(0, (2, 3)), Ok(5));
+        assert_eq!(instance.execute_func::<_, i64>(1, (-7i64,)), Ok(-21));
+        assert_eq!(instance.execute_func::<(), i32>(3, ()), Ok(4));
+        assert_eq!(
+            instance.execute_func::<(), i32>(2, ()),
+            Err(ExecutionError::Trap(ARITHMETIC_OVERFLOW))
+        );
+    }
+
+    #[test]
+    fn wrapping_by_default() {
+        let instance = translate(false);
+
+        for func in 0..4 {
+            assert_eq!(trap_codes(&instance, func), []);
+        }
+        assert_eq!(
+            instance.execute_func::<(), i32>(2, ()),
+            Ok(i32::max_value())
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod code_buffer {
+    use crate::code_buffer::MappedBuffer;
+    use std::mem;
+
+    /// `mov eax, 42; ret`.
+    const RETURN_42: [u8; 6] = [0xb8, 42, 0, 0, 0, 0xc3];
+
+    #[test]
+    fn patched_through_a_memfd() {
+        let buf = MappedBuffer::aliased(&RETURN_42).unwrap();
+        let func: extern "sysv64" fn() -> u32 = unsafe { mem::transmute(buf.as_ptr()) };
+        assert_eq!(func(), 42);
+
+        unsafe { buf.write_with(1, 1, |dst| *dst = 7) };
+        assert_eq!(func(), 7);
+    }
+}
+
+mod lazy {
+    use crate::{module::translate_only_with, CompiledModule, Instance, TranslateOptions};
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (type $unary (func (param i32) (result i32)))
+  (table 1 1 anyfunc)
+  (elem (i32.const 0) $double)
+  (memory 1 1)
+  (func $fib (param i32) (result i32)
+    (if (result i32) (i32.lt_u (get_local 0) (i32.const 2))
+      (then (get_local 0))
+      (else
+        (i32.add
+          (call $fib (i32.sub (get_local 0) (i32.const 1)))
+          (call $fib (i32.sub (get_local 0) (i32.const 2)))))))
+  (func (param i32 i64 f64 f32 i32) (result f64)
+    (f64.add
+      (f64.add
+        (f64.add (f64.convert_s/i32 (get_local 0)) (f64.convert_s/i64 (get_local 1)))
+        (f64.add (get_local 2) (f64.promote/f32 (get_local 3))))
+      (f64.convert_s/i32 (get_local 4))))
+  (func $double (param i32) (result i32)
+    (i32.mul (get_local 0) (i32.const 2)))
+  (func (param i32) (result i32)
+    (call_indirect (type $unary) (get_local 0) (i32.const 0)))
+  (func (param i32) (result i32)
+    (i32.store (i32.const 8) (get_local 0))
+    (i32.load (i32.const 8)))
+  (func (param i32) (result i32)
+    (i32.add (call $fib (get_local 0)) (call $double (get_local 0)))))
+"#;
+
+    fn translate_lazily() -> CompiledModule {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let options = TranslateOptions {
+            lazy: true,
+            ..Default::default()
+        };
+        translate_only_with(&wasm, options).unwrap()
+    }
+
+    #[test]
+    fn translated_when_called() {
+        let module = Arc::new(translate_lazily());
+        let instance = Instance::new(module.clone());
+        assert!((0..6).all(|i| !module.is_translated(i)));
+
+        assert_eq!(instance.execute_func::<_, u32>(0, (10u32,)), Ok(55));
+        assert!(module.is_translated(0));
+        assert!(!module.is_translated(1));
+        // Calls after the first go straight to the translated code
+        assert_eq!(instance.execute_func::<_, u32>(0, (12u32,)), Ok(144));
+
+        assert_eq!(
+            instance.execute_func::<_, f64>(1, (1i32, 2i64, 3.5f64, 0.25f32, 5i32)),
+            Ok(11.75)
+        );
+        assert_eq!(instance.execute_func::<_, u32>(4, (7u32,)), Ok(7));
+        assert!(!module.is_translated(2));
+    }
+
+    #[test]
+    fn called_through_a_table() {
+        let module = Arc::new(translate_lazily());
+        let instance = Instance::new(module.clone());
+
+        assert_eq!(instance.execute_func::<_, u32>(3, (21u32,)), Ok(42));
+        assert!(module.is_translated(2));
+        assert!(module.is_translated(3));
+    }
+
+    #[test]
+    fn translated_ahead_of_time() {
+        let module = Arc::new(translate_lazily());
+        module.translate_function(2).unwrap();
+        assert!(module.is_translated(2));
+        assert!(module.translate_function(6).is_err());
+
+        let instance = Instance::new(module.clone());
+        assert_eq!(instance.execute_func::<_, u32>(2, (4u32,)), Ok(8));
+    }
+
+    #[test]
+    fn shared_between_instances() {
+        let module = Arc::new(translate_lazily());
+        let first = Instance::new(module.clone());
+        let second = Instance::new(module.clone());
+
+        assert_eq!(first.execute_func::<_, u32>(0, (7u32,)), Ok(13));
+        assert_eq!(second.execute_func::<_, u32>(0, (8u32,)), Ok(21));
+    }
+
+    #[test]
+    fn calls_other_functions() {
+        // Eagerly, calls are linked to the other functions in the same code section
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let instance = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        assert_eq!(instance.execute_func::<_, u32>(5, (10u32,)), Ok(55 + 20));
+
+        // Lazily, they go through the stubs of the functions that they call
+        let module = Arc::new(translate_lazily());
+        let instance = Instance::new(module.clone());
+        assert_eq!(instance.execute_func::<_, u32>(5, (10u32,)), Ok(55 + 20));
+        assert!(module.is_translated(0));
+        assert!(module.is_translated(2));
+        assert_eq!(instance.execute_func::<_, u32>(5, (6u32,)), Ok(8 + 12));
+    }
+}
+
+mod inlining {
+    use super::translate_wat_with;
+    use crate::{module::inlined_functions, CodeGenOptions};
+
+    const CODE: &str = r#"
+(module
+  (memory 1 1)
+  (func $get (param i32) (result i32)
+    (i32.load offset=8 (get_local 0)))
+  (func $set (param i32 i32)
+    (i32.store offset=8 (get_local 0) (get_local 1)))
+  (func $second (param i32 i64) (result i64)
+    (get_local 1))
+  (func (param i32 i32) (result i32)
+    (call $set (get_local 0) (get_local 1))
+    (i32.add
+      (call $get (get_local 0))
+      (i32.wrap/i64 (call $second (get_local 1) (i64.const 100))))))
+"#;
+
+    #[test]
+    fn calls_to_tiny_functions() {
+        let instance = translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                max_inlined_size: Some(8),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(instance.execute_func::<_, u32>(3, (16u32, 5u32)), Ok(105));
+        assert_eq!(instance.execute_func::<_, u32>(3, (32u32, 7u32)), Ok(107));
+    }
+
+    #[test]
+    fn only_small_straight_line_functions() {
+        const CODE: &str = r#"
+(module
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (i32.const 1)))
+  (func (param i32) (result i32)
+    (i32.mul (i32.add (i32.add (get_local 0) (i32.const 1)) (i32.const 2)) (i32.const 3)))
+  (func (param i32) (result i32)
+    (if (result i32) (get_local 0) (then (i32.const 1)) (else (i32.const 2))))
+  (func (param i32) (result i32)
+    (loop (result i32) (get_local 0)))
+  (func (param i32) (result i32)
+    (block (result i32) (br 0 (get_local 0)))))
+"#;
+
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        assert_eq!(inlined_functions(&wasm, 3).unwrap(), vec![0]);
+        assert_eq!(inlined_functions(&wasm, 8).unwrap(), vec![0, 1]);
+    }
+}
+
+mod devirtualization {
+    use super::translate_wat_with;
+    use crate::{module::ExecutionError, CodeGenOptions, Instance};
+
+    fn translate(code: &str) -> Instance {
+        translate_wat_with(
+            code,
+            CodeGenOptions {
+                devirtualize_calls: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn constant_indices() {
+        let mut instance = translate(
+            r#"
+(module
+  (type $unary (func (param i32) (result i32)))
+  (table 3 3 anyfunc)
+  (elem (i32.const 0) $double $negate)
+  (func $double (param i32) (result i32)
+    (i32.mul (get_local 0) (i32.const 2)))
+  (func $negate (param i32) (result i32)
+    (i32.sub (i32.const 0) (get_local 0)))
+  (func (param i32) (result i32)
+    (i32.add
+      (call_indirect (type $unary) (get_local 0) (i32.const 0))
+      (call_indirect (type $unary) (get_local 0) (i32.const 1)))))
+"#,
+        );
+
+        assert_eq!(instance.execute_func::<_, i32>(2, (5,)), Ok(5));
+        assert_eq!(instance.execute_func::<_, i32>(2, (-3,)), Ok(-3));
+
+        assert!(instance.code_section().is_bound_table_element(0));
+        assert!(instance.code_section().is_bound_table_element(1));
+        assert_eq!(
+            instance.table_set(1, None),
+            Err(ExecutionError::BoundTableElement)
+        );
+        assert_eq!(instance.table_set(2, None), Ok(()));
+    }
+
+    #[test]
+    fn other_calls_are_indirect() {
+        let mut instance = translate(
+            r#"
+(module
+  (type $unary (func (param i32) (result i32)))
+  (type $binary (func (param i32 i32) (result i32)))
+  (table 2 2 anyfunc)
+  (elem (i32.const 0) $double $negate)
+  (func $double (param i32) (result i32)
+    (i32.mul (get_local 0) (i32.const 2)))
+  (func $negate (param i32) (result i32)
+    (i32.sub (i32.const 0) (get_local 0)))
+  (func (param i32) (result i32)
+    (call_indirect (type $unary) (get_local 0) (get_local 0)))
+  (func (param i32) (result i32)
+    (call_indirect (type $binary) (get_local 0) (get_local 0) (i32.const 1))))
+"#,
+        );
+
+        // Neither the call with a variable index nor the one that traps with a bad signature
+        // is bound to an element
+        assert!(!instance.code_section().is_bound_table_element(0));
+        assert!(!instance.code_section().is_bound_table_element(1));
+
+        assert_eq!(instance.execute_func::<_, i32>(2, (1,)), Ok(-1));
+        instance
+            .table_set(1, instance.table_get(0).unwrap())
+            .unwrap();
+        assert_eq!(instance.execute_func::<_, i32>(2, (1,)), Ok(2));
+    }
+}
+
+mod branch_hints {
+    use super::translate_with;
+    use crate::{index_space::DefinedFuncIndex, CodeGenOptions, Instance};
+
+    fn leb(mut value: u32, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn read_leb(wasm: &[u8], pos: &mut usize) -> u32 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = wasm[*pos];
+            *pos += 1;
+            value |= u32::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    /// Where the code section starts, including its id.
+    fn code_section(wasm: &[u8]) -> usize {
+        let mut pos = 8;
+        loop {
+            let start = pos;
+            let id = wasm[pos];
+            pos += 1;
+            let len = read_leb(wasm, &mut pos) as usize;
+            if id == 10 {
+                return start;
+            }
+            pos += len;
+        }
+    }
+
+    /// The offset of the first occurrence of `pattern` in the body of `func`, from the start
+    /// of the body.
+    fn offset_in_body(wasm: &[u8], func: usize, pattern: &[u8]) -> u32 {
+        let mut pos = code_section(wasm) + 1;
+        read_leb(wasm, &mut pos);
+        read_leb(wasm, &mut pos);
+        for _ in 0..func {
+            pos += read_leb(wasm, &mut pos) as usize;
+        }
+        let len = read_leb(wasm, &mut pos) as usize;
+        let body = &wasm[pos..pos + len];
+        body.windows(pattern.len())
+            .position(|window| window == pattern)
+            .unwrap() as u32
+    }
+
+    /// Add a branch hint section with `(function, offset, hint)` for each hinted branch.
+    fn with_hints(wasm: &[u8], hints: &[(u32, u32, u8)]) -> Vec<u8> {
+        let mut contents = vec![];
+        let name = b"metadata.code.branch_hint";
+        leb(name.len() as u32, &mut contents);
+        contents.extend_from_slice(name);
+        let funcs = {
+            let mut funcs = hints.iter().map(|&(func, _, _)| func).collect::<Vec<_>>();
+            funcs.dedup();
+            funcs
+        };
+        leb(funcs.len() as u32, &mut contents);
+        for func in funcs {
+            let func_hints = hints
+                .iter()
+                .filter(|hint| hint.0 == func)
+                .collect::<Vec<_>>();
+            leb(func, &mut contents);
+            leb(func_hints.len() as u32, &mut contents);
+            for &&(_, offset, hint) in &func_hints {
+                leb(offset, &mut contents);
+                leb(1, &mut contents);
+                contents.push(hint);
+            }
+        }
+
+        let code = code_section(wasm);
+        let mut out = wasm[..code].to_vec();
+        out.push(0);
+        leb(contents.len() as u32, &mut out);
+        out.extend(contents);
+        out.extend_from_slice(&wasm[code..]);
+        out
+    }
+
+    fn translate(wasm: &[u8]) -> Instance {
+        translate_with(
+            wasm,
+            CodeGenOptions {
+                record_operator_ranges: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn last_operator(instance: &Instance, func: u32) -> String {
+        let ranges = instance
+            .code_section()
+            .operator_ranges(DefinedFuncIndex(func));
+        ranges.last().unwrap().operator.trim().to_string()
+    }
+
+    const IF: &[u8] = &[0x04, 0x40];
+    const BR_IF: &[u8] = &[0x0d, 0x00];
+
+    const COLD_PATHS: &str = r#"
+(module
+  (func (param i32) (result i32)
+    (if (i32.gt_u (get_local 0) (i32.const 1000))
+      (then (unreachable)))
+    (i32.mul (get_local 0) (i32.const 3)))
+  (func (param i32) (result i32)
+    (block
+      (br_if 0 (get_local 0))
+      (return (i32.const 7)))
+    (i32.add (get_local 0) (i32.const 1))))
+"#;
+
+    #[test]
+    fn unlikely_blocks_go_at_the_end() {
+        let wasm = wabt::wat2wasm(COLD_PATHS).unwrap();
+        let hinted = with_hints(
+            &wasm,
+            &[
+                (0, offset_in_body(&wasm, 0, IF), 0),
+                (1, offset_in_body(&wasm, 1, BR_IF), 1),
+            ],
+        );
+
+        let plain = translate(&wasm);
+        let hinted = translate(&hinted);
+        for instance in &[&plain, &hinted] {
+            for &x in &[0, 1, 5, 1000] {
+                assert_eq!(instance.execute_func::<_, u32>(0, (x,)), Ok(x * 3));
+                let expected = if x == 0 { 7 } else { x + 1 };
+                assert_eq!(instance.execute_func::<_, u32>(1, (x,)), Ok(expected));
+            }
+        }
+
+        assert_eq!(last_operator(&plain, 0), "br .return");
+        assert_eq!(last_operator(&hinted, 0), "unreachable");
+        assert_eq!(last_operator(&plain, 1), "br .return");
+        assert!(
+            hinted
+                .code_section()
+                .operator_ranges(DefinedFuncIndex(1))
+                .iter()
+                .rev()
+                .take(3)
+                .any(|range| range.operator.trim() == "const 7i32"),
+            "{:#?}",
+            hinted.code_section().operator_ranges(DefinedFuncIndex(1))
+        );
+    }
+
+    quickcheck! {
+        fn hints_dont_change_results(hints: Vec<bool>, x: u32) -> bool {
+            const WAT: &str = r#"
+(module
+  (func (param i32) (result i32) (local i32)
+    (if (i32.and (get_local 0) (i32.const 1))
+      (then (set_local 1 (i32.const 10)))
+      (else (set_local 1 (i32.const 20))))
+    (block
+      (br_if 0 (i32.and (get_local 0) (i32.const 2)))
+      (return (i32.add (get_local 1) (i32.const 1))))
+    (if (i32.and (get_local 0) (i32.const 4))
+      (then (return (get_local 0))))
+    (loop
+      (set_local 1 (i32.add (get_local 1) (i32.const 3)))
+      (br_if 0 (i32.lt_u (get_local 1) (i32.const 40))))
+    (get_local 1)))
+"#;
+            let wasm = wabt::wat2wasm(WAT).unwrap();
+            let body = {
+                let mut pos = code_section(&wasm) + 1;
+                read_leb(&wasm, &mut pos);
+                read_leb(&wasm, &mut pos);
+                let len = read_leb(&wasm, &mut pos) as usize;
+                &wasm[pos..pos + len]
+            };
+            let branches = body
+                .windows(2)
+                .enumerate()
+                .filter(|(_, window)| *window == IF || *window == BR_IF)
+                .map(|(offset, _)| offset as u32)
+                .collect::<Vec<_>>();
+            let hints = branches
+                .iter()
+                .zip(hints)
+                .map(|(&offset, hint)| (0, offset, hint as u8))
+                .collect::<Vec<_>>();
+
+            let plain = translate(&wasm);
+            let hinted = translate(&with_hints(&wasm, &hints));
+            plain.execute_func::<_, u32>(0, (x,)) == hinted.execute_func::<_, u32>(0, (x,))
+        }
+    }
+
+    #[test]
+    fn malformed_hints_are_ignored() {
+        let wasm = wabt::wat2wasm(COLD_PATHS).unwrap();
+        let hinted = with_hints(&wasm, &[(0, offset_in_body(&wasm, 0, IF), 2)]);
+
+        let instance = translate(&hinted);
+        assert_eq!(instance.execute_func::<_, u32>(0, (4,)), Ok(12));
+        assert_eq!(last_operator(&instance, 0), "br .return");
+    }
+}
+
+mod constant_conditions {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    /// Branches and selects on `$true` and `$false`, which are constants once templated code
+    /// like this has been specialized.
+    const TEMPLATE: &str = "
+        (module
+            (func (param i32) (result i32)
+                (if (result i32) $true
+                    (then (i32.add (get_local 0) (i32.const 1)))
+                    (else (i32.mul (get_local 0) (get_local 0)))))
+            (func (param i32) (result i32)
+                (if (result i32) $false
+                    (then (i32.add (get_local 0) (i32.const 1)))
+                    (else (i32.mul (get_local 0) (get_local 0)))))
+            (func (param i32) (result i32)
+                (block (result i32)
+                    (drop (br_if 0 (get_local 0) $true))
+                    (i32.const 7)))
+            (func (param i32) (result i32)
+                (block (result i32)
+                    (drop (br_if 0 (get_local 0) $false))
+                    (i32.const 7)))
+            (func (param i32) (result i32)
+                (select (get_local 0) (i32.const 7) $true))
+            (func (param i32) (result i32)
+                (select (get_local 0) (i32.const 7) $false)))
+    ";
+
+    /// The functions in `TEMPLATE` with the conditions already resolved.
+    const RESOLVED: &str = "
+        (module
+            (func (param i32) (result i32) (i32.add (get_local 0) (i32.const 1)))
+            (func (param i32) (result i32) (i32.mul (get_local 0) (get_local 0)))
+            (func (param i32) (result i32) (get_local 0))
+            (func (param i32) (result i32) (i32.const 7))
+            (func (param i32) (result i32) (get_local 0))
+            (func (param i32) (result i32) (i32.const 7)))
+    ";
+
+    fn folded() -> String {
+        TEMPLATE
+            .replace("$true", "(i32.const 1)")
+            .replace("$false", "(i32.const 0)")
+    }
+
+    #[test]
+    fn folds_to_one_side() {
+        let folded = translate_wat(&folded());
+        let resolved = translate_wat(RESOLVED);
+
+        for func in 0..6 {
+            for &arg in &[0, 3, -5] {
+                assert_eq!(
+                    folded.execute_func::<(i32,), i32>(func, (arg,)),
+                    resolved.execute_func::<(i32,), i32>(func, (arg,)),
+                    "function {} with {}",
+                    func,
+                    arg
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn drops_the_untaken_side() {
+        let folded = translate_wat(&folded());
+        let dynamic = translate_wat(
+            &TEMPLATE
+                .replace("$true", "(get_local 0)")
+                .replace("$false", "(get_local 0)"),
+        );
+        let resolved = translate_wat(RESOLVED);
+        let (folded, dynamic, resolved) = (
+            folded.code_section(),
+            dynamic.code_section(),
+            resolved.code_section(),
+        );
+
+        for func in (0..6).map(DefinedFuncIndex) {
+            assert!(
+                folded.function_stats(func).code_size < dynamic.function_stats(func).code_size,
+                "function {}",
+                func
+            );
+        }
+
+        // Nothing is left of an `if` apart from the arm that's taken
+        for func in (0..2).map(DefinedFuncIndex) {
+            assert!(
+                folded.function_stats(func).dead_blocks > 0,
+                "function {}",
+                func
+            );
+            assert_eq!(
+                folded.function_stats(func).code_size,
+                resolved.function_stats(func).code_size,
+                "function {}",
+                func
+            );
+        }
+    }
+}
+
+mod code_size {
+    use super::{iterative_fib_baseline, FIBONACCI};
+    use crate::{
+        index_space::DefinedFuncIndex, module::translate_only_with, CodeGenOptions, CodeSizeLimits,
+        Error, TranslateOptions,
+    };
+
+    fn translate_limited(wat: &str, size_limits: CodeSizeLimits) -> Result<(), Error> {
+        let wasm = wabt::wat2wasm(wat).unwrap();
+        let options = TranslateOptions {
+            codegen: CodeGenOptions {
+                size_limits,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        translate_only_with(&wasm, options).map(drop)
+    }
+
+    /// A small function followed by one with a long chain of multiplications.
+    fn bloated() -> String {
+        let mut body = "(get_local 0)".to_string();
+        for i in 0..200 {
+            body = format!("(i32.mul {} (i32.const {}))", body, i + 3);
+        }
+        format!(
+            "(module (func (result i32) (i32.const 1)) (func (param i32) (result i32) {}))",
+            body
+        )
+    }
+
+    #[test]
+    fn function_stats() {
+        let wasm = wabt::wat2wasm(FIBONACCI).unwrap();
+        let translated = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        let code = translated.code_section();
+        let stats = code.function_stats(DefinedFuncIndex(0));
+        let instructions = code.instruction_count(DefinedFuncIndex(0)).unwrap();
+
+        assert!(stats.operators > 0);
+        assert!(stats.code_size <= code.func_range(DefinedFuncIndex(0)).len());
+        assert!(instructions > 0 && instructions < stats.code_size);
+
+        assert_eq!(
+            translated.execute_func::<_, u32>(0, (10,)),
+            Ok(iterative_fib_baseline(10))
+        );
+    }
+
+    #[test]
+    fn dead_blocks_generate_no_code() {
+        let dead_code = "(drop (i32.mul (get_local 0) (i32.const 5)))
+            (if (get_local 0)
+                (then (drop (call_indirect (param i32) (result i32) (i32.const 1) (i32.const 0))))
+                (else (block (loop (br_if 1 (get_local 0)) (br 0)))))";
+        let wat = format!(
+            "(module
+                (type (func (param i32) (result i32)))
+                (table 1 anyfunc)
+                (func (param i32) (result i32)
+                    (if (get_local 0) (then (unreachable)) (else (unreachable)))
+                    (i32.const 7))
+                (func (param i32) (result i32)
+                    (if (get_local 0) (then (unreachable)) (else (unreachable)))
+                    {}
+                    (i32.const 7)))",
+            dead_code
+        );
+        let wasm = wabt::wat2wasm(&wat).unwrap();
+        let translated = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        let code = translated.code_section();
+        let (without, with) = (
+            code.function_stats(DefinedFuncIndex(0)),
+            code.function_stats(DefinedFuncIndex(1)),
+        );
+
+        assert!(with.dead_blocks > without.dead_blocks, "{:?}", with);
+        assert_eq!(with.code_size, without.code_size);
+    }
+
+    #[test]
+    fn within_limits() {
+        let wat = bloated();
+        let wasm = wabt::wat2wasm(&wat).unwrap();
+        let translated = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        let code = translated.code_section();
+
+        assert_eq!(
+            translate_limited(
+                &wat,
+                CodeSizeLimits {
+                    max_function_size: Some(code.function_stats(DefinedFuncIndex(1)).code_size),
+                    max_module_size: Some(code.buffer().len()),
+                }
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn function_too_large() {
+        let limit = 256;
+        match translate_limited(
+            &bloated(),
+            CodeSizeLimits {
+                max_function_size: Some(limit),
+                max_module_size: None,
+            },
+        ) {
+            Err(Error::FunctionTooLarge {
+                func,
+                size,
+                limit: actual_limit,
+            }) => {
+                assert_eq!(func, DefinedFuncIndex(1));
+                assert_eq!(actual_limit, limit);
+                // Translation stops as soon as the limit is exceeded
+                assert!(size > limit && size < 2 * limit, "{}", size);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn module_too_large() {
+        match translate_limited(
+            &bloated(),
+            CodeSizeLimits {
+                max_function_size: None,
+                max_module_size: Some(256),
+            },
+        ) {
+            Err(Error::ModuleTooLarge { size, limit: 256 }) => assert!(size > 256),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    // Constants and trap stubs are emitted after the last function, so the limit is checked
+    // again once they have been.
+    #[test]
+    fn out_of_line_code_too_large() {
+        let wat = "(module
+            (func (param f32 i32) (result f32)
+                (f32.add
+                    (f32.neg (get_local 0))
+                    (f32.convert_s/i32 (i32.div_s (get_local 1) (get_local 1))))))";
+        let wasm = wabt::wat2wasm(wat).unwrap();
+        let translated = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        let code = translated.code_section();
+        let size = code.buffer().len();
+        let func_end = code.func_range(DefinedFuncIndex(0)).start
+            + code.function_stats(DefinedFuncIndex(0)).code_size;
+        assert!(func_end < size);
+
+        match translate_limited(
+            wat,
+            CodeSizeLimits {
+                max_function_size: None,
+                max_module_size: Some(size - 1),
+            },
+        ) {
+            Err(Error::ModuleTooLarge {
+                size: actual_size,
+                limit,
+            }) => {
+                assert_eq!(actual_size, size);
+                assert_eq!(limit, size - 1);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn module_limit_is_capped() {
+        assert_eq!(
+            translate_limited(
+                &bloated(),
+                CodeSizeLimits {
+                    max_function_size: None,
+                    max_module_size: Some(usize::max_value()),
+                }
+            ),
+            Ok(())
+        );
+    }
+}
+
+mod disassembly {
+    use super::translate_with;
+    use crate::{index_space::DefinedFuncIndex, translate, CodeGenOptions};
+
+    const TWO_FUNCTIONS: &str = r#"
+(module
+  (func (param i32 i32) (result i32) (i32.add (get_local 0) (get_local 1)))
+  (func (param i32 i32) (result i32) (i32.xor (get_local 0) (get_local 1))))
+"#;
+
+    #[test]
+    fn per_function() {
+        let wasm = wabt::wat2wasm(TWO_FUNCTIONS).unwrap();
+        let translated = translate(&wasm).unwrap();
+        let code = translated.code_section();
+
+        let whole = code.disassembly().unwrap();
+        let first = code.function_disassembly(DefinedFuncIndex(0)).unwrap();
+        let second = code.function_disassembly(DefinedFuncIndex(1)).unwrap();
+
+        assert!(whole.contains("Function 0:") && whole.contains("Function 1:"));
+        assert!(first.contains("Function 0:") && !first.contains("Function 1:"));
+        assert!(second.contains("Function 1:") && !second.contains("Function 0:"));
+        assert!(first.contains("\tadd\t") && !first.contains("\txor\t"));
+        assert!(second.contains("\txor\t") && !second.contains("\tadd\t"));
+
+        // Addresses are relative to the start of the section
+        let start = code.func_range(DefinedFuncIndex(1)).start;
+        let first_insn = second
+            .lines()
+            .find(|line| line.contains(":\t"))
+            .unwrap()
+            .trim_start();
+        assert!(
+            first_insn.starts_with(&format!("{:x}:", start)),
+            "{}",
+            second
+        );
+    }
+
+    #[test]
+    fn wasm_offsets() {
+        let wasm = wabt::wat2wasm(TWO_FUNCTIONS).unwrap();
+        let translated = translate(&wasm).unwrap();
+        let disassembly = translated.code_section().disassembly().unwrap();
+
+        for (op, opcode) in &[("i32.add", 0x6a), ("u32.xor", 0x73)] {
+            let line = disassembly
+                .lines()
+                .find(|line| line.contains(op))
+                .unwrap_or_else(|| panic!("No `{}` in {}", op, disassembly));
+            let offset = line
+                .trim()
+                .trim_start_matches("@0x")
+                .split_whitespace()
+                .next();
+            let offset = usize::from_str_radix(offset.unwrap(), 16).unwrap();
+            assert_eq!(wasm[offset], *opcode, "{}", line);
+        }
+    }
+
+    #[test]
+    fn side_by_side() {
+        let wasm = wabt::wat2wasm(TWO_FUNCTIONS).unwrap();
+        let translated = translate_with(
+            &wasm,
+            CodeGenOptions {
+                record_operator_ranges: true,
+                ..Default::default()
+            },
+        );
+        let code = translated.code_section();
+        let ranges = code.operator_ranges(DefinedFuncIndex(0));
+
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].code.end, pair[1].code.start);
+        }
+        assert_eq!(
+            ranges.last().unwrap().code.end,
+            code.func_range(DefinedFuncIndex(0)).start
+                + code.function_stats(DefinedFuncIndex(0)).code_size
+        );
+
+        let add = ranges
+            .iter()
+            .find(|range| range.operator.contains("i32.add"))
+            .unwrap();
+        assert_eq!(wasm[add.wasm_offset.unwrap()], 0x6a);
+
+        let dump = code.side_by_side(DefinedFuncIndex(0)).unwrap();
+        assert!(dump.starts_with(".fn_0:"), "{}", dump);
+        assert!(dump.contains("      i32.add "), "{}", dump);
+        let add_insn = dump.lines().find(|line| line.contains("\tadd\t")).unwrap();
+        let address = add_insn
+            .split('|')
+            .nth(1)
+            .unwrap()
+            .split(':')
+            .next()
+            .unwrap();
+        let address = usize::from_str_radix(address.trim(), 16).unwrap();
+        assert!(add.code.contains(&address), "{}", dump);
+
+        let without_ranges = translate(&wasm).unwrap();
+        assert!(without_ranges
+            .code_section()
+            .operator_ranges(DefinedFuncIndex(0))
+            .is_empty());
+    }
+}
+
+mod metrics {
+    use crate::{
+        index_space::DefinedFuncIndex, module::translate_only_with, CompilationMetrics,
+        FunctionStats, TranslateOptions,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        functions: Mutex<Vec<(DefinedFuncIndex, Duration, FunctionStats)>>,
+    }
+
+    impl CompilationMetrics for Recorder {
+        fn function_translated(
+            &self,
+            func: DefinedFuncIndex,
+            time: Duration,
+            stats: &FunctionStats,
+        ) {
+            self.functions.lock().unwrap().push((func, time, *stats));
+        }
+    }
+
+    #[test]
+    fn reports_every_function() {
+        // Each product is live across the next call, so it has to be saved on the stack. Direct
+        // calls between defined functions aren't supported yet, so call through the table.
+        let mut body = "(get_local 0)".to_string();
+        for i in 0..8 {
+            body = format!(
+                "(i32.add
+                  (i32.mul (get_local 0) (i32.const {}))
+                  (call_indirect (type $t) {} (i32.const 0)))",
+                i + 2,
+                body
+            );
+        }
+        let wat = format!(
+            "(module
+              (type $t (func (param i32) (result i32)))
+              (table anyfunc (elem $id))
+              (func $id (param i32) (result i32) (get_local 0))
+              (func (param i32) (result i32) {}))",
+            body
+        );
+        let wasm = wabt::wat2wasm(&wat).unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        let options = TranslateOptions {
+            metrics: Some(recorder.clone()),
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        let functions = recorder.functions.lock().unwrap();
+        assert_eq!(functions.len(), 2);
+        for (i, (func, _, stats)) in functions.iter().enumerate() {
+            assert_eq!(*func, DefinedFuncIndex(i as u32));
+            assert_eq!(stats, translated.code_section().function_stats(*func));
+            assert!(stats.operators > 0);
+            assert!(stats.code_size > 0);
+        }
+
+        assert_eq!(functions[0].2.spills, 0);
+        assert!(functions[1].2.spills >= 8, "{:?}", functions[1].2);
+
+        // `x + 2x + 3x + ... + 9x`
+        assert_eq!(translated.execute_func::<_, u32>(1, (2u32,)), Ok(90));
+    }
+}
+
+mod coverage {
+    use super::{iterative_fib_baseline, translate_wat_with, FIBONACCI};
+    use crate::{CodeGenOptions, Coverage, Instance};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    unsafe extern "C" fn count(guard: *mut u32) {
+        (*(guard as *const AtomicU32)).fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Number the guards from 100 like the sanitizer runtimes number them from 1, so that we
+    // can tell that this ran.
+    unsafe extern "C" fn number(start: *mut u32, stop: *mut u32) {
+        let mut guard = start;
+        while guard < stop {
+            *guard = 100 + guard.offset_from(start) as u32;
+            guard = guard.add(1);
+        }
+    }
+
+    fn translate_wat(wat: &str, coverage: Coverage) -> Instance {
+        translate_wat_with(
+            wat,
+            CodeGenOptions {
+                coverage: Some(coverage),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn counts_blocks() {
+        let translated = translate_wat(
+            "(module
+                (func (param i32) (param i32) (result i32)
+                    (if (result i32) (get_local 0)
+                        (then (i32.add (get_local 1) (i32.const 1)))
+                        (else (i32.sub (get_local 1) (i32.const 1))))))",
+            Coverage {
+                trace_pc_guard: count,
+                trace_pc_guard_init: None,
+            },
+        );
+        translated.disassemble();
+
+        for _ in 0..3 {
+            assert_eq!(translated.execute_func::<_, u32>(0, (1u32, 5u32)), Ok(6));
+        }
+        assert_eq!(translated.execute_func::<_, u32>(0, (0u32, 5u32)), Ok(4));
+
+        let counts = translated
+            .code_section()
+            .coverage_guards()
+            .iter()
+            .map(|guard| guard.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        assert_eq!(counts[0], 4);
+        assert!(counts.contains(&3));
+        assert!(counts.contains(&1));
+    }
+
+    // Values live across the start of a block must survive the call to the hook.
+    #[test]
+    fn preserves_state() {
+        let translated = translate_wat(
+            FIBONACCI,
+            Coverage {
+                trace_pc_guard: count,
+                trace_pc_guard_init: Some(number),
+            },
+        );
+        let guards = translated.code_section().coverage_guards();
+        assert!(!guards.is_empty());
+        assert!(guards
+            .iter()
+            .enumerate()
+            .all(|(i, guard)| guard.load(Ordering::Relaxed) == 100 + i as u32));
+
+        for x in 0..20 {
+            assert_eq!(
+                translated.execute_func::<_, u32>(0, (x,)),
+                Ok(iterative_fib_baseline(x))
+            );
+        }
+    }
+}
+
+mod trace_hooks {
+    use super::{iterative_fib_baseline, translate_wat_with, FIBONACCI};
+    use crate::{CodeGenOptions, Instance, TraceHooks};
+    use std::slice;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Entry(u32, Vec<u64>),
+        Exit(u32),
+        Loop(u32, u32),
+    }
+
+    lazy_static! {
+        static ref EVENTS: Mutex<Vec<Event>> = Mutex::new(vec![]);
+    }
+
+    unsafe extern "C" fn record_entry(func: u32, args: *const u64, num_args: u32) {
+        let args = slice::from_raw_parts(args, num_args as usize).to_vec();
+        EVENTS.lock().unwrap().push(Event::Entry(func, args));
+    }
+
+    unsafe extern "C" fn record_exit(func: u32) {
+        EVENTS.lock().unwrap().push(Event::Exit(func));
+    }
+
+    unsafe extern "C" fn record_loop(func: u32, loop_index: u32) {
+        EVENTS.lock().unwrap().push(Event::Loop(func, loop_index));
+    }
+
+    fn translate_wat(wat: &str, trace_hooks: TraceHooks) -> Instance {
+        translate_wat_with(
+            wat,
+            CodeGenOptions {
+                trace_hooks: Some(trace_hooks),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn records_calls_and_loops() {
+        let translated = translate_wat(
+            "(module
+                (func (param i32) (result i32)
+                    (i32.mul (get_local 0) (i32.const 2)))
+                (func (param $n i32) (param $x i64) (param $f f64) (result i64)
+                    (if (i32.eqz (get_local $n))
+                        (then (return (get_local $x))))
+                    (loop $top
+                        (set_local $x (i64.add (get_local $x) (i64.const 1)))
+                        (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+                        (br_if $top (get_local $n)))
+                    (loop $never
+                        (br_if $never (i32.const 0)))
+                    (get_local $x)))",
+            TraceHooks {
+                function_entry: Some(record_entry),
+                entry_arguments: true,
+                function_exit: Some(record_exit),
+                loop_iteration: Some(record_loop),
+            },
+        );
+        translated.disassemble();
+
+        let take_events = || std::mem::replace(&mut *EVENTS.lock().unwrap(), vec![]);
+
+        assert_eq!(translated.execute_func::<_, u32>(0, (21u32,)), Ok(42));
+        assert_eq!(take_events(), [Event::Entry(0, vec![21]), Event::Exit(0)]);
+
+        assert_eq!(
+            translated.execute_func::<_, u64>(1, (3u32, 10u64, 1.5f64)),
+            Ok(13)
+        );
+        let events = take_events();
+        match &events[0] {
+            Event::Entry(1, args) => {
+                assert_eq!(args.len(), 3);
+                assert_eq!(args[0] as u32, 3);
+                assert_eq!(args[1], 10);
+                assert_eq!(args[2], 1.5f64.to_bits());
+            }
+            other => panic!("Expected the entry to function 1, got {:?}", other),
+        }
+        assert_eq!(
+            events[1..],
+            [
+                Event::Loop(1, 0),
+                Event::Loop(1, 0),
+                Event::Loop(1, 0),
+                Event::Loop(1, 1),
+                Event::Exit(1),
+            ]
+        );
+
+        // An early return
+        assert_eq!(
+            translated.execute_func::<_, u64>(1, (0u32, 10u64, 0f64)),
+            Ok(10)
+        );
+        let events = take_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1], Event::Exit(1));
+    }
+
+    static CALLS: AtomicU32 = AtomicU32::new(0);
+
+    unsafe extern "C" fn count_entry(_: u32, args: *const u64, num_args: u32) {
+        assert!(args.is_null());
+        assert_eq!(num_args, 0);
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn count_exit(_: u32) {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn count_loop(_: u32, _: u32) {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Values live across the hooks must survive the calls to them.
+    #[test]
+    fn preserves_state() {
+        let translated = translate_wat(
+            FIBONACCI,
+            TraceHooks {
+                function_entry: Some(count_entry),
+                entry_arguments: false,
+                function_exit: Some(count_exit),
+                loop_iteration: Some(count_loop),
+            },
+        );
+
+        for x in 0..20 {
+            assert_eq!(
+                translated.execute_func::<_, u32>(0, (x,)),
+                Ok(iterative_fib_baseline(x))
+            );
+        }
+        assert!(CALLS.load(Ordering::Relaxed) >= 40);
+    }
+}
+
+mod breakpoints {
+    use super::translate_wat_with;
+    use crate::{BreakpointFrame, CodeGenOptions, DebugLocation, DebugOptions, DefinedFuncIndex};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        static ref HITS: Mutex<Vec<(u32, u32)>> = Mutex::new(vec![]);
+        /// The locations to read values from at every breakpoint, if any.
+        static ref LOCATIONS: Mutex<Vec<DebugLocation>> = Mutex::new(vec![]);
+        static ref VALUES: Mutex<Vec<Vec<u32>>> = Mutex::new(vec![]);
+    }
+
+    unsafe extern "C" fn record(func: u32, wasm_offset: u32, frame: *mut BreakpointFrame) {
+        HITS.lock().unwrap().push((func, wasm_offset));
+
+        let locations = LOCATIONS.lock().unwrap();
+        if !locations.is_empty() {
+            let values = locations
+                .iter()
+                .map(|&location| (*frame).read(location) as u32)
+                .collect();
+            VALUES.lock().unwrap().push(values);
+        }
+    }
+
+    fn take_hits() -> Vec<(u32, u32)> {
+        std::mem::replace(&mut *HITS.lock().unwrap(), vec![])
+    }
+
+    #[test]
+    fn stops_at_enabled_breakpoints() {
+        let translated = translate_wat_with(
+            "(module
+                (func (param $n i32) (result i32) (local $acc i32)
+                    (block $done
+                        (loop $top
+                            (br_if $done (i32.eqz (get_local $n)))
+                            (set_local $acc (i32.add (get_local $acc) (get_local $n)))
+                            (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+                            (br $top)))
+                    (get_local $acc)))",
+            CodeGenOptions {
+                debug: Some(DebugOptions {
+                    breakpoint_hook: record,
+                }),
+                ..Default::default()
+            },
+        );
+        let code = translated.code_section();
+
+        let breakpoints = code.breakpoints();
+        assert!(breakpoints.len() > 8);
+        assert!(breakpoints
+            .iter()
+            .all(|breakpoint| breakpoint.func == DefinedFuncIndex(0)));
+        assert!(breakpoints
+            .windows(2)
+            .all(|pair| pair[0].wasm_offset < pair[1].wasm_offset));
+        assert!(breakpoints.iter().all(|b| !code.breakpoint_enabled(b)));
+
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(take_hits(), []);
+
+        // Single-step
+        code.set_all_breakpoints(true);
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        let hits = take_hits();
+        assert_eq!(hits[0], (0, breakpoints[0].wasm_offset as u32));
+        let mut counts = HashMap::<u32, u32>::new();
+        for &(_, offset) in &hits {
+            *counts.entry(offset).or_default() += 1;
+        }
+        // Everything before the `br_if` runs once, everything up to it once more than the
+        // rest of the loop, and everything after the loop once.
+        let mut counts = counts.values().cloned().collect::<Vec<_>>();
+        counts.sort();
+        counts.dedup();
+        assert_eq!(counts, [1, 3, 4]);
+
+        // Stop only at the start of the loop body
+        code.set_all_breakpoints(false);
+        let header = hits
+            .iter()
+            .map(|&(_, offset)| offset)
+            .find(|&offset| hits.iter().filter(|hit| hit.1 == offset).count() == 4)
+            .unwrap();
+        assert!(code.set_breakpoint(DefinedFuncIndex(0), header as usize, true));
+        assert!(!code.set_breakpoint(DefinedFuncIndex(0), usize::max_value(), true));
+        assert_eq!(
+            breakpoints
+                .iter()
+                .filter(|b| code.breakpoint_enabled(b))
+                .count(),
+            1
+        );
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(take_hits(), [(0, header); 4]);
+
+        // Read `$n` and `$acc` every time round the loop
+        let header_breakpoint = breakpoints
+            .iter()
+            .find(|b| b.wasm_offset == header as usize)
+            .unwrap();
+        let locations = code.value_locations(header_breakpoint);
+        assert_eq!(locations.len(), 2);
+        *LOCATIONS.lock().unwrap() = locations.to_vec();
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        LOCATIONS.lock().unwrap().clear();
+        take_hits();
+        assert_eq!(
+            std::mem::replace(&mut *VALUES.lock().unwrap(), vec![]),
+            [vec![3, 0], vec![2, 3], vec![1, 5], vec![0, 6]]
+        );
+
+        code.set_breakpoint(DefinedFuncIndex(0), header as usize, false);
+        assert_eq!(translated.execute_func::<_, u32>(0, (5u32,)), Ok(15));
+        assert_eq!(take_hits(), []);
+    }
+}
+
+mod operator_profiling {
+    use super::translate_wat_with;
+    use crate::{CodeGenOptions, Instance, OperatorCounts};
+
+    const CODE: &str = r#"
+(module
+  (memory 1 1)
+  (func (param $n i32) (result i32) (local $acc i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.eqz (get_local $n)))
+        (i32.store (i32.const 0) (get_local $n))
+        (set_local $acc (i32.add (get_local $acc) (i32.load (i32.const 0))))
+        (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+        (br $top)))
+    (get_local $acc))
+  (func $sum (param $n i32) (result i32)
+    (if (result i32) (i32.eqz (get_local $n))
+      (then (i32.const 0))
+      (else
+        (i32.add (get_local $n) (call $sum (i32.sub (get_local $n) (i32.const 1))))))))
+"#;
+
+    fn translate(profile_operators: bool) -> Instance {
+        translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                profile_operators,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn counts_each_class() {
+        let mut translated = translate(true);
+        assert_eq!(translated.operator_counts(), OperatorCounts::default());
+
+        // The `br_if` runs for each of 3, 2, 1 and 0, and the `br` for all but 0
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(
+            translated.operator_counts(),
+            OperatorCounts {
+                loads: 3,
+                stores: 3,
+                calls: 0,
+                branches: 7,
+            }
+        );
+
+        translated.reset_operator_counts();
+        assert_eq!(translated.execute_func::<_, u32>(1, (3u32,)), Ok(6));
+        assert_eq!(
+            translated.operator_counts(),
+            OperatorCounts {
+                loads: 0,
+                stores: 0,
+                calls: 3,
+                branches: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn only_counted_when_enabled() {
+        let translated = translate(false);
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(translated.execute_func::<_, u32>(1, (3u32,)), Ok(6));
+        assert_eq!(translated.operator_counts(), OperatorCounts::default());
+    }
+}
+
+mod tiering {
+    use super::translate_wat_with;
+    use crate::{
+        BreakpointFrame, CodeGenOptions, DefinedFuncIndex, Instance, OsrPoint, Tiering,
+        ENTRY_LOOP_INDEX,
+    };
+    use std::sync::Mutex;
+
+    const CODE: &str = r#"
+(module
+  (func (param $n i32) (result i32) (local $acc i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.eqz (get_local $n)))
+        (set_local $acc (i32.add (get_local $acc) (get_local $n)))
+        (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+        (br $top)))
+    (get_local $acc))
+  (func (result i32)
+    (i32.const 7)))
+"#;
+
+    lazy_static! {
+        static ref HOT: Mutex<Vec<(u32, u32)>> = Mutex::new(vec![]);
+        /// The points that the hook reads values at.
+        static ref OSR_POINTS: Mutex<Vec<OsrPoint>> = Mutex::new(vec![]);
+        static ref VALUES: Mutex<Vec<Vec<u32>>> = Mutex::new(vec![]);
+    }
+
+    unsafe extern "C" fn tier_up(func: u32, loop_index: u32, frame: *mut BreakpointFrame) {
+        HOT.lock().unwrap().push((func, loop_index));
+
+        let points = OSR_POINTS.lock().unwrap();
+        if let Some(point) = points.iter().find(|point| point.loop_index == loop_index) {
+            let values = point
+                .values
+                .iter()
+                .map(|&location| (*frame).read(location) as u32)
+                .collect();
+            VALUES.lock().unwrap().push(values);
+        }
+    }
+
+    fn translate(tiering: Tiering) -> Instance {
+        translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                tiering: Some(tiering),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn counts_entries_and_iterations() {
+        let translated = translate(Tiering {
+            threshold: 1,
+            tier_up: None,
+        });
+        let counters = translated.code_section().hotness_counters();
+
+        // One entry and a visit to the loop header for each of 3, 2, 1 and 0
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(counters.get(DefinedFuncIndex(0)), 5);
+        assert_eq!(translated.execute_func::<_, u32>(0, (5u32,)), Ok(15));
+        assert_eq!(counters.get(DefinedFuncIndex(0)), 12);
+        assert_eq!(translated.execute_func::<_, u32>(1, ()), Ok(7));
+        assert_eq!(counters.get(DefinedFuncIndex(1)), 1);
+
+        counters.reset(DefinedFuncIndex(0));
+        assert_eq!(counters.get(DefinedFuncIndex(0)), 0);
+    }
+
+    #[test]
+    fn tiers_up_with_the_loop_state() {
+        let translated = translate(Tiering {
+            threshold: 4,
+            tier_up: Some(tier_up),
+        });
+        let code = translated.code_section();
+
+        let points = code.osr_points();
+        assert_eq!(points.len(), 1);
+        assert_eq!(code.osr_point(DefinedFuncIndex(0), 0), Some(&points[0]));
+        assert_eq!(points[0].values.len(), 2);
+        assert!(points[0].cfa_offset >= 8);
+        *OSR_POINTS.lock().unwrap() = points.to_vec();
+
+        // Hot at the third visit to the loop header, when `$n` is 1 and `$acc` is 5
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(translated.execute_func::<_, u32>(0, (3u32,)), Ok(6));
+        assert_eq!(*HOT.lock().unwrap(), [(0, 0)]);
+        assert_eq!(*VALUES.lock().unwrap(), [vec![1, 5]]);
+
+        // Hot on the fourth entry
+        for _ in 0..4 {
+            assert_eq!(translated.execute_func::<_, u32>(1, ()), Ok(7));
+        }
+        assert_eq!(*HOT.lock().unwrap(), [(0, 0), (1, ENTRY_LOOP_INDEX)]);
+
+        // Resetting the counter rearms the hook
+        code.hotness_counters().reset(DefinedFuncIndex(1));
+        for _ in 0..4 {
+            assert_eq!(translated.execute_func::<_, u32>(1, ()), Ok(7));
+        }
+        assert_eq!(HOT.lock().unwrap().len(), 3);
+    }
+}
+
+mod determinism {
+    use super::{tables::REFERENCE_TYPES, translate_with};
+    use crate::{BuiltinFunction, CodeGenOptions, Instance, ModuleContext, SimpleContext};
+
+    // Plenty of out-of-line code: float constants, trap pads and a signature mismatch path.
+    const OUT_OF_LINE: &str = r#"
+(module
+  (type $t (func (param f32) (result f32)))
+  (table anyfunc (elem $scale $shift))
+  (func $scale (param f32) (result f32) (f32.mul (get_local 0) (f32.const 1.5)))
+  (func $shift (param f32) (result f32) (f32.add (get_local 0) (f32.const -2.25)))
+  (func (param i32 i32 f32) (result f32)
+    (f32.add
+      (call_indirect (type $t) (get_local 2) (get_local 0))
+      (f32.convert_s/i32 (i32.div_s (get_local 0) (get_local 1)))))
+  (func (param i64 f64) (result f64)
+    (f64.mul
+      (f64.convert_s/i64 (i64.rem_u (get_local 0) (i64.const 7)))
+      (f64.add (get_local 1) (f64.const 0.125)))))
+"#;
+
+    fn translate_deterministic(wasm: &[u8], deterministic: bool) -> Instance {
+        translate_with(
+            wasm,
+            CodeGenOptions {
+                deterministic,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn reproducible() {
+        let wasm = wabt::wat2wasm(OUT_OF_LINE).unwrap();
+        let first = translate_deterministic(&wasm, true);
+        let second = translate_deterministic(&wasm, true);
+
+        assert_eq!(
+            first.code_section().buffer(),
+            second.code_section().buffer()
+        );
+        assert_eq!(
+            first.code_section().content_hash(),
+            second.code_section().content_hash()
+        );
+        assert_eq!(first.execute_func::<_, f32>(2, (0, 1, 2f32)), Ok(3.));
+        assert_eq!(first.execute_func::<_, f64>(3, (9i64, 1f64)), Ok(2.25));
+    }
+
+    #[test]
+    fn no_embedded_addresses() {
+        let builtins = [
+            BuiltinFunction::TableGet,
+            BuiltinFunction::TableSet,
+            BuiltinFunction::TableGrow,
+            BuiltinFunction::CompileLazily,
+        ];
+        let contains_builtin = |code: &[u8]| {
+            builtins.iter().any(|&builtin| {
+                let address = SimpleContext::default().builtin_function(builtin) as u64;
+                code.windows(8)
+                    .any(|bytes| bytes == &address.to_le_bytes()[..])
+            })
+        };
+
+        let embedded = translate_deterministic(REFERENCE_TYPES, false);
+        assert!(contains_builtin(embedded.code_section().buffer()));
+
+        let translated = translate_deterministic(REFERENCE_TYPES, true);
+        translated.disassemble();
+        assert!(!contains_builtin(translated.code_section().buffer()));
+        assert_ne!(
+            translated.code_section().content_hash(),
+            embedded.code_section().content_hash()
+        );
+
+        assert_eq!(translated.execute_func::<_, ()>(0, (1u32, 0u32)), Ok(()));
+        assert_eq!(translated.execute_func::<_, i32>(5, (1, 7)), Ok(8));
+        assert_eq!(translated.execute_func::<_, i32>(2, (2,)), Ok(2));
+        assert_eq!(translated.execute_func::<_, ()>(3, (0u32,)), Ok(()));
+        assert_eq!(translated.table_get(0), Ok(None));
+    }
+}
+
+mod unwind {
+    use super::{translate_wat, FIBONACCI};
+    use crate::{
+        module::translate_only_with, DefinedFuncIndex, HostError, HostFunctions, TranslateOptions,
+        VmCtx,
+    };
+    use std::{backtrace::Backtrace, cell::RefCell};
+
+    #[test]
+    fn rows_cover_function() {
+        let translated = translate_wat(FIBONACCI);
+        let unwind = translated.code_section().unwind_info(DefinedFuncIndex(0));
+
+        assert_eq!(unwind.rows[0].offset, 0);
+        assert_eq!(unwind.rows[0].cfa_offset, 8);
+        for pair in unwind.rows.windows(2) {
+            assert!(pair[0].offset < pair[1].offset);
+        }
+        assert!(unwind.rows.iter().all(|row| row.offset < unwind.len));
+        assert!(unwind.rows.iter().all(|row| row.cfa_offset % 8 == 0));
+    }
+
+    extern "C" {
+        fn __register_frame(begin: *const u8);
+        fn __deregister_frame(begin: *const u8);
+    }
+
+    thread_local! {
+        static BACKTRACE: RefCell<String> = RefCell::new(String::new());
+    }
+
+    fn capture_backtrace(_: &VmCtx, x: i32) -> Result<i32, HostError> {
+        BACKTRACE.with(|bt| *bt.borrow_mut() = format!("{:?}", Backtrace::force_capture()));
+        Ok(x)
+    }
+
+    // The system unwinder can only get from the host function back to this test through its
+    // exit stub and the wasm frames, which have spilled values and `VmCtx` on the stack, if
+    // it has our unwind information.
+    #[test]
+    fn unwind_through_wasm() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (import "env" "capture" (func $capture (param i32) (result i32)))
+  (func (param i32) (param i32) (result i32)
+    (i32.add
+      (get_local 1)
+      (call $capture (get_local 0)))))
+"#,
+        )
+        .unwrap();
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("env", "capture", capture_backtrace);
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        let eh_frame = translated.code_section().eh_frame();
+        unsafe { __register_frame(eh_frame.as_ptr()) };
+        assert_eq!(translated.execute_func::<_, u32>(0, (1u32, 2u32)), Ok(3));
+        unsafe { __deregister_frame(eh_frame.as_ptr()) };
+
+        BACKTRACE.with(|bt| assert!(bt.borrow().contains("unwind_through_wasm")));
+    }
+}
+
+mod emitter {
+    use crate::emitter::{
+        link, DynamicLabel, Emission, Emitter, Instruction, LabelRef, Operand, RecordingEmitter,
+        VecAssembler,
+    };
+    use dynasm::dynasm;
+
+    // `jmp rel32` to a label defined directly after a `nop`
+    fn emit_jump_over_nop<E: Emitter>(e: &mut E) -> DynamicLabel {
+        let label = e.new_label();
+        e.emit_bytes(&[0xe9]);
+        e.emit_rel32(label);
+        e.emit_bytes(&[0x90]);
+        e.define_label(label);
+        label
+    }
+
+    #[test]
+    fn records_emissions() {
+        let mut rec = RecordingEmitter::new();
+        let label = emit_jump_over_nop(&mut rec);
+
+        assert_eq!(
+            rec.emissions,
+            vec![
+                Emission::Bytes(vec![0xe9]),
+                Emission::Rel32(label),
+                Emission::Bytes(vec![0x90]),
+                Emission::DefineLabel(label),
+            ]
+        );
+        assert_eq!(rec.offset().0, 6);
+    }
+
+    #[test]
+    fn records_instructions() {
+        const RAX: u8 = 0;
+        const R9: u8 = 9;
+
+        let instructions = [
+            Instruction::Mov {
+                wide: false,
+                dst: Operand::Reg(R9),
+                src: Operand::Reg(RAX),
+            },
+            Instruction::Add {
+                wide: true,
+                dst: Operand::Reg(RAX),
+                src: Operand::Stack(16),
+            },
+            Instruction::Cmp {
+                wide: false,
+                left: Operand::Reg(R9),
+                right: Operand::Imm(-1),
+            },
+        ];
+
+        let mut rec = RecordingEmitter::new();
+        let mut asm = VecAssembler::new();
+        for &instruction in &instructions {
+            rec.instruction(instruction);
+            asm.instruction(instruction);
+        }
+        let buf = asm.finalize().unwrap();
+
+        assert_eq!(rec.instructions(), instructions);
+        assert_eq!(rec.offset().0, buf.len());
+        assert_eq!(buf, rec.bytes());
+    }
+
+    #[test]
+    fn assembler_patches_rel32() {
+        let mut rec = RecordingEmitter::new();
+        emit_jump_over_nop(&mut rec);
+
+        let mut asm = VecAssembler::new();
+        emit_jump_over_nop(&mut asm);
+        let buf = asm.finalize().unwrap();
+
+        let mut expected = rec.bytes();
+        expected[1] = 1;
+        assert_eq!(buf, expected);
+    }
+
+    // A loop using every kind of label that `dynasm!` has
+    fn emit_loop<E: Emitter>(e: &mut E) -> DynamicLabel {
+        let exit = e.new_label();
+        dynasm!(e
+            ; ->start:
+            ; top:
+            ; dec ecx
+            ; jz =>exit
+            ; jmp <top
+            ; jmp >next
+            ; next:
+            ; jmp ->start
+            ; =>exit
+            ; ret
+        );
+        exit
+    }
+
+    #[test]
+    fn assembler_patches_labels() {
+        let mut asm = VecAssembler::new();
+        emit_loop(&mut asm);
+
+        assert_eq!(
+            asm.finalize().unwrap(),
+            vec![
+                0xff, 0xc9, // dec ecx
+                0x0f, 0x84, 15, 0, 0, 0, // jz =>exit
+                0xe9, 0xf3, 0xff, 0xff, 0xff, // jmp <top
+                0xe9, 0, 0, 0, 0, // jmp >next
+                0xe9, 0xe9, 0xff, 0xff, 0xff, // jmp ->start
+                0xc3, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn records_labels() {
+        let mut rec = RecordingEmitter::new();
+        let exit = emit_loop(&mut rec);
+
+        let labels = rec
+            .emissions
+            .iter()
+            .filter(|e| match e {
+                Emission::Bytes(_) => false,
+                _ => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                Emission::GlobalLabel("start"),
+                Emission::LocalLabel("top"),
+                Emission::Reloc(LabelRef::Dynamic(exit), (0, 4)),
+                Emission::Reloc(LabelRef::Backward("top"), (0, 4)),
+                Emission::Reloc(LabelRef::Forward("next"), (0, 4)),
+                Emission::LocalLabel("next"),
+                Emission::Reloc(LabelRef::Global("start"), (0, 4)),
+                Emission::DefineLabel(exit),
+            ]
+        );
+        assert_eq!(rec.offset().0, 24);
+
+        // The recording stands in for the assembler: replaying it produces the same code
+        let mut asm = VecAssembler::new();
+        emit_loop(&mut asm);
+        let mut replayed = VecAssembler::new();
+        rec.replay(&mut replayed);
+        assert_eq!(replayed.finalize().unwrap(), asm.finalize().unwrap());
+    }
+
+    // Two functions that call each other, each assembled on its own
+    #[test]
+    fn links_code_assembled_separately() {
+        let (first_start, second_start) = (DynamicLabel::new(), DynamicLabel::new());
+        let mut first = VecAssembler::new();
+        dynasm!(first
+            ; =>first_start
+            ; call =>second_start
+            ; ret
+        );
+        let mut second = VecAssembler::starting_at(first.offset().0);
+        dynasm!(second
+            ; .align 8
+            ; =>second_start
+            ; jmp =>first_start
+        );
+        assert_eq!(
+            link(vec![first.finish(), second.finish()]).unwrap(),
+            vec![
+                0xe8, 3, 0, 0, 0,    // call =>second_start
+                0xc3, // ret
+                0x90, 0x90, // .align 8
+                0xe9, 0xf3, 0xff, 0xff, 0xff, // jmp =>first_start
+            ]
+        );
+
+        let missing = DynamicLabel::new();
+        let mut alone = VecAssembler::new();
+        dynasm!(alone
+            ; jmp =>missing
+        );
+        assert!(alone.finalize().is_err());
+    }
+
+    /// `dynasmrt`'s assemblers map the code that they assemble executable, which the
+    /// policies that `CodeBuffer` works around can refuse, so translation must only ever
+    /// assemble into a `VecAssembler` and leave mapping the code to `CodeBuffer`.
+    #[test]
+    fn translation_never_maps_code_through_dynasmrt() {
+        let sources = [
+            ("backend.rs", include_str!("backend.rs")),
+            ("code_buffer.rs", include_str!("code_buffer.rs")),
+            ("emitter.rs", include_str!("emitter.rs")),
+            ("function_body.rs", include_str!("function_body.rs")),
+            ("module.rs", include_str!("module.rs")),
+            ("peephole.rs", include_str!("peephole.rs")),
+            (
+                "translate_sections.rs",
+                include_str!("translate_sections.rs"),
+            ),
+        ];
+        for &(file, source) in &sources {
+            for api in &[
+                "x64::Assembler",
+                "ExecutableBuffer",
+                "DynasmApi",
+                "new_dynamic_label",
+            ] {
+                assert!(!source.contains(api), "{} uses `{}`", file, api);
+            }
+        }
+
+        let instance = super::translate_wat(
+            r#"
+(module
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (i32.const 1))))
+"#,
+        );
+        assert_eq!(instance.execute_func::<(i32,), i32>(0, (41,)), Ok(42));
+    }
+}
+
+mod peephole {
+    use super::translate_wat;
+    use crate::emitter::{Emitter, VecAssembler};
+    use crate::index_space::DefinedFuncIndex;
+    use crate::peephole::Peephole;
+
+    const RCX: u8 = 1;
+    const RDX: u8 = 2;
+    const RSI: u8 = 6;
+    const R9: u8 = 9;
+
+    /// The code that `emit` produces and the number of instructions that were dropped.
+    fn assemble(emit: impl FnOnce(&mut Peephole)) -> (Vec<u8>, usize) {
+        let mut asm = VecAssembler::new();
+        let removed = {
+            let mut peephole = Peephole::new(&mut asm);
+            emit(&mut peephole);
+            peephole.flush();
+            peephole.removed()
+        };
+        (asm.finalize().unwrap(), removed)
+    }
+
+    #[test]
+    fn drops_pushes_that_are_popped() {
+        assert_eq!(
+            assemble(|p| {
+                p.push_rq(RCX);
+                p.pop_rq(RCX);
+                p.push_rq(RSI);
+                p.pop_discard(RDX);
+            }),
+            (vec![], 4)
+        );
+        // Popping into another register moves the value, so it has to stay
+        assert_eq!(
+            assemble(|p| {
+                p.push_rq(R9);
+                p.pop_rq(RCX);
+            }),
+            (vec![0x41, 0x51, 0x59], 0)
+        );
+    }
+
+    #[test]
+    fn pending_push_counts_towards_offset() {
+        assemble(|p| {
+            p.push_rq(R9);
+            assert_eq!(p.offset().0, 2);
+        });
+    }
+
+    #[test]
+    fn drops_moves_that_change_nothing() {
+        let (expected, _) = assemble(|p| p.mov_rq(RCX, RSI));
+
+        assert_eq!(
+            assemble(|p| {
+                p.mov_rq(RCX, RSI);
+                p.mov_rq(RSI, RCX);
+                p.mov_rq(RCX, RCX);
+            }),
+            (expected, 2)
+        );
+    }
+
+    #[test]
+    fn encodes_32_bit_moves_without_needless_prefixes() {
+        assert_eq!(assemble(|p| p.mov_rd(RCX, RSI)), (vec![0x89, 0xf1], 0));
+        assert_eq!(assemble(|p| p.mov_rd(R9, RCX)), (vec![0x41, 0x89, 0xc9], 0));
+        assert_eq!(assemble(|p| p.mov_rd(RCX, R9)), (vec![0x44, 0x89, 0xc9], 0));
+        assert_eq!(
+            assemble(|p| {
+                p.mov_rd(RCX, RSI);
+                p.mov_rq(RSI, RCX);
+            }),
+            (vec![0x89, 0xf1], 1)
+        );
+    }
+
+    #[test]
+    fn drops_repeated_comparisons() {
+        let (expected, _) = assemble(|p| {
+            p.cmp_rr(false, RSI, RDX);
+            p.mov_rq(RCX, RDX);
+        });
+
+        assert_eq!(
+            assemble(|p| {
+                p.cmp_rr(false, RSI, RDX);
+                p.mov_rq(RCX, RDX);
+                p.cmp_rr(false, RSI, RDX);
+            }),
+            (expected, 1)
+        );
+        // Overwriting an operand means that the comparison has to be done again
+        assert_eq!(
+            assemble(|p| {
+                p.cmp_ri(true, RSI, 5);
+                p.mov_rq(RSI, RDX);
+                p.cmp_ri(true, RSI, 5);
+            })
+            .1,
+            0
+        );
+    }
+
+    #[test]
+    fn other_code_is_a_barrier() {
+        assert_eq!(
+            assemble(|p| {
+                p.mov_rq(RCX, RSI);
+                p.emit_bytes(&[0x90]);
+                p.mov_rq(RSI, RCX);
+            })
+            .1,
+            0
+        );
+        assert_eq!(
+            assemble(|p| {
+                p.mov_rq(RCX, RSI);
+                let label = p.new_label();
+                p.define_label(label);
+                p.mov_rq(RSI, RCX);
+            })
+            .1,
+            0
+        );
+        assert_eq!(
+            assemble(|p| {
+                p.mov_rq(RCX, RSI);
+                let label = p.new_label();
+                p.define_fallthrough_label(label);
+                p.mov_rq(RSI, RCX);
+            })
+            .1,
+            1
+        );
+    }
+
+    #[test]
+    fn removes_redundant_code_in_functions() {
+        let instance = translate_wat(
+            "
+            (module
+                (func (param i32 i32) (result i32)
+                    (if (result i32) (i32.lt_s (get_local 0) (get_local 1))
+                        (then
+                            (select
+                                (get_local 0)
+                                (get_local 1)
+                                (i32.lt_s (get_local 0) (get_local 1))))
+                        (else (i32.const 0))))
+                (func (param i32 i32) (result i32) (local i32)
+                    (block (result i32)
+                        (loop
+                            (set_local 2 (i32.add (get_local 2) (get_local 0)))
+                            (br_if 1 (get_local 2) (i32.gt_s (get_local 2) (get_local 1)))
+                            (br 0))
+                        (unreachable))))
+            ",
+        );
+
+        for func in (0..2).map(DefinedFuncIndex) {
+            assert!(
+                instance
+                    .code_section()
+                    .function_stats(func)
+                    .removed_instructions
+                    > 0,
+                "function {}",
+                func
+            );
+        }
+
+        for &(a, b) in &[(1, 2), (2, 1), (-3, 4), (5, 5)] {
+            assert_eq!(
+                instance.execute_func::<(i32, i32), i32>(0, (a, b)),
+                Ok(if a < b { a } else { 0 })
+            );
+        }
+        for &(step, limit) in &[(1, 10), (3, 100), (7, 0)] {
+            let mut sum = 0;
+            while {
+                sum += step;
+                sum <= limit
+            } {}
+            assert_eq!(
+                instance.execute_func::<(i32, i32), i32>(1, (step, limit)),
+                Ok(sum)
+            );
+        }
+    }
+}
+
+mod known_bools {
+    use super::{translate_wat, Instance};
+    use crate::index_space::DefinedFuncIndex;
+
+    /// Pairs of functions of `(a, b, c)` that compute the same thing, the first in a way that
+    /// only works out to the same code as the second if we know which values are 0 or 1.
+    const PAIRS: &str = "
+        (module
+            (func (param i32 i32 i32) (result i32)
+                (select (get_local 0) (get_local 1)
+                    (i32.ne (i32.lt_s (get_local 0) (get_local 1)) (i32.const 0))))
+            (func (param i32 i32 i32) (result i32)
+                (select (get_local 0) (get_local 1) (i32.lt_s (get_local 0) (get_local 1))))
+
+            (func (param i32 i32 i32) (result i32)
+                (i32.eq (i32.const 0) (i32.gt_u (get_local 0) (get_local 2))))
+            (func (param i32 i32 i32) (result i32)
+                (i32.eqz (i32.gt_u (get_local 0) (get_local 2))))
+
+            (func (param i32 i32 i32) (result i32)
+                (i32.xor (i32.lt_s (get_local 1) (get_local 2)) (i32.const 1)))
+            (func (param i32 i32 i32) (result i32)
+                (i32.eqz (i32.lt_s (get_local 1) (get_local 2))))
+
+            (func (param i32 i32 i32) (result i32)
+                (i32.and (i32.const 1) (i32.ge_s (get_local 0) (get_local 2))))
+            (func (param i32 i32 i32) (result i32)
+                (i32.ge_s (get_local 0) (get_local 2)))
+
+            (func (param i32 i32 i32) (result i32)
+                (block (result i32)
+                    (br_if 0
+                        (i32.const 1)
+                        (i32.ne
+                            (i32.and
+                                (i32.lt_s (get_local 0) (get_local 1))
+                                (i32.lt_s (get_local 1) (get_local 2)))
+                            (i32.const 0)))
+                    (drop)
+                    (i32.const 0)))
+            (func (param i32 i32 i32) (result i32)
+                (block (result i32)
+                    (br_if 0
+                        (i32.const 1)
+                        (i32.and
+                            (i32.lt_s (get_local 0) (get_local 1))
+                            (i32.lt_s (get_local 1) (get_local 2))))
+                    (drop)
+                    (i32.const 0))))
+    ";
+
+    fn expected(func: u32, a: i32, b: i32, c: i32) -> i32 {
+        match func / 2 {
+            0 => {
+                if a < b {
+                    a
+                } else {
+                    b
+                }
+            }
+            1 => (a as u32 <= c as u32) as i32,
+            2 => (b >= c) as i32,
+            3 => (a >= c) as i32,
+            4 => (a < b && b < c) as i32,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn comparisons_with_bools_generate_no_extra_code() {
+        let instance = translate_wat(PAIRS);
+
+        for func in 0..10 {
+            for &(a, b, c) in &[(1, 2, 3), (3, 2, 1), (-1, 0, -1), (4, 4, 4), (0, 5, -5)] {
+                assert_eq!(
+                    instance.execute_func::<(i32, i32, i32), i32>(func, (a, b, c)),
+                    Ok(expected(func, a, b, c)),
+                    "function {} with {:?}",
+                    func,
+                    (a, b, c)
+                );
+            }
+        }
+
+        for func in (0..10).step_by(2) {
+            let stats = |func| {
+                instance
+                    .code_section()
+                    .function_stats(DefinedFuncIndex(func))
+            };
+            assert_eq!(
+                stats(func).code_size,
+                stats(func + 1).code_size,
+                "function {}",
+                func
+            );
+        }
+    }
+
+    quickcheck! {
+        fn logic_on_bools_is_tested_by_the_operation(a: i32, b: i32, c: i32) -> bool {
+            const CODE: &str = "
+                (module
+                    (func (param i32 i32 i32) (result i32)
+                        (select (get_local 0) (get_local 2)
+                            (i32.xor
+                                (i32.or
+                                    (i32.eq (get_local 0) (get_local 1))
+                                    (i32.gt_u (get_local 1) (get_local 2)))
+                                (i32.le_s (get_local 0) (get_local 2))))))
+            ";
+
+            lazy_static! {
+                static ref TRANSLATED: Instance = translate_wat(CODE);
+            }
+
+            let cond = ((a == b) | (b as u32 > c as u32)) ^ (a <= c);
+            TRANSLATED.execute_func::<(i32, i32, i32), i32>(0, (a, b, c))
+                == Ok(if cond { a } else { c })
+        }
+    }
+}
+
+mod zero_extension {
+    use super::{translate_wat, Instance};
+    use crate::index_space::DefinedFuncIndex;
+
+    #[test]
+    fn extending_the_result_of_an_i32_operation_is_free() {
+        let instance = translate_wat(
+            "
+            (module
+                (func (param i32 i32) (result i64)
+                    (i64.extend_u/i32 (i32.add (get_local 0) (get_local 1))))
+                (func (param i32 i32) (result i32)
+                    (i32.add (get_local 0) (get_local 1)))
+                (func (param i32 i32) (result i64)
+                    (i64.extend_u/i32 (i32.lt_s (get_local 0) (get_local 1))))
+                (func (param i32 i32) (result i32)
+                    (i32.lt_s (get_local 0) (get_local 1))))
+            ",
+        );
+
+        for func in (0..4).step_by(2) {
+            let stats = |func| {
+                instance
+                    .code_section()
+                    .function_stats(DefinedFuncIndex(func))
+            };
+            assert_eq!(
+                stats(func).code_size,
+                stats(func + 1).code_size,
+                "function {}",
+                func
+            );
+        }
+
+        assert_eq!(
+            instance.execute_func::<(i32, i32), u64>(0, (-1, -2)),
+            Ok(0xffff_fffd)
+        );
+        assert_eq!(instance.execute_func::<(i32, i32), u64>(2, (-1, 2)), Ok(1));
+    }
+
+    quickcheck! {
+        fn values_that_might_not_be_extended_are(a: i64, b: i32) -> bool {
+            const CODE: &str = "
+                (module
+                    (func (param i64 i32) (result i64)
+                        (i64.extend_u/i32 (i32.wrap/i64 (get_local 0))))
+                    (func (param i64 i32) (result i64)
+                        (i64.extend_u/i32
+                            (select (i32.const -1) (i32.const -2) (get_local 1))))
+                    (func (param i64 i32) (result i64)
+                        (i64.add
+                            (i64.extend_u/i32
+                                (i32.wrap/i64
+                                    (i64.div_s
+                                        (i64.extend_u/i32 (i32.eqz (get_local 1)))
+                                        (i64.const -1))))
+                            (get_local 0))))
+            ";
+
+            lazy_static! {
+                static ref TRANSLATED: Instance = translate_wat(CODE);
+            }
+
+            let run = |func| TRANSLATED.execute_func::<(i64, i32), u64>(func, (a, b));
+            let is_zero = (b == 0) as i64;
+            run(0) == Ok(a as u32 as u64)
+                && run(1) == Ok(if b != 0 { 0xffff_ffff } else { 0xffff_fffe })
+                && run(2) == Ok(((-is_zero) as u32 as u64).wrapping_add(a as u64))
+        }
+    }
+}
+
+mod intrinsics {
+    use crate::{module::translate_only_with, Intrinsics, TranslateOptions};
+
+    const HYPOT: &str = r#"
+(module
+  (import "math" "sqrt" (func $sqrt (param f64) (result f64)))
+  (import "debug" "ignore" (func $ignore (param i32)))
+  (func $hypot (param f64) (param f64) (result f64)
+    (call $ignore (i32.const 1))
+    (call $sqrt
+      (f64.add
+        (f64.mul (get_local 0) (get_local 0))
+        (f64.mul (get_local 1) (get_local 1)))))
+  (func $sum (param i32) (result i32)
+    (if (result i32) (i32.eqz (get_local 0))
+      (then (i32.const 0))
+      (else
+        (i32.add
+          (get_local 0)
+          (call $sum (i32.sub (get_local 0) (i32.const 1))))))))
+    "#;
+
+    fn intrinsics() -> Intrinsics {
+        let mut intrinsics = Intrinsics::new();
+        intrinsics
+            .register("math", "sqrt", |ctx| ctx.f64_sqrt())
+            .register("debug", "ignore", |ctx| ctx.drop(0..=0));
+        intrinsics
+    }
+
+    // Defined functions come after the imports in the function index space, so this also
+    // checks that calls between defined functions still find the right function.
+    #[test]
+    fn lowered_inline() {
+        let wasm = wabt::wat2wasm(HYPOT).unwrap();
+        let options = TranslateOptions {
+            intrinsics: intrinsics(),
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+        translated.disassemble();
+
+        assert_eq!(translated.execute_func::<_, f64>(0, (3.0, 4.0)), Ok(5.0));
+        assert_eq!(translated.execute_func::<_, u32>(1, (10u32,)), Ok(55));
+    }
+
+    #[test]
+    fn unknown_import() {
+        let wasm = wabt::wat2wasm(HYPOT).unwrap();
+        let mut intrinsics = Intrinsics::new();
+        intrinsics.register("math", "sqrt", |ctx| ctx.f64_sqrt());
+        let options = TranslateOptions {
+            intrinsics,
+            ..Default::default()
+        };
+
+        assert!(translate_only_with(&wasm, options).is_err());
+    }
+}
+
+mod microwasm_conv {
+    use crate::microwasm::{BrTarget, BrTargetDrop, Operator, OperatorFromWasm, WasmLabel};
+    use crate::module::translate_to_microwasm;
+    use quickcheck::{Arbitrary, Gen};
+    use std::collections::{HashMap, HashSet};
+    use std::fmt::Write;
+
+    /// A statement in a function made of little but control flow. Branch depths are reduced
+    /// modulo the number of labels in scope when the function is printed.
+    #[derive(Debug, Clone)]
+    enum Stmt {
+        Nop,
+        SetLocal(i32),
+        Block(Vec<Stmt>),
+        Loop(Vec<Stmt>),
+        If(Vec<Stmt>, Vec<Stmt>),
+        Br(u32),
+        BrIf(u32),
+        BrTable(Vec<u32>, u32),
+        Return,
+        Unreachable,
+    }
+
+    const MAX_NESTING: u32 = 4;
+
+    fn gen_stmts<G: Gen>(g: &mut G, nesting: u32) -> Vec<Stmt> {
+        let len = usize::arbitrary(g) % 5;
+        (0..len).map(|_| gen_stmt(g, nesting)).collect()
+    }
+
+    fn gen_stmt<G: Gen>(g: &mut G, nesting: u32) -> Stmt {
+        let kinds = if nesting < MAX_NESTING { 10 } else { 7 };
+        match u32::arbitrary(g) % kinds {
+            0 => Stmt::Nop,
+            1 => Stmt::SetLocal(i32::arbitrary(g)),
+            2 => Stmt::Br(u32::arbitrary(g)),
+            3 => Stmt::BrIf(u32::arbitrary(g)),
+            4 => Stmt::BrTable(Arbitrary::arbitrary(g), u32::arbitrary(g)),
+            5 => Stmt::Return,
+            6 => Stmt::Unreachable,
+            7 => Stmt::Block(gen_stmts(g, nesting + 1)),
+            8 => Stmt::Loop(gen_stmts(g, nesting + 1)),
+            _ => Stmt::If(gen_stmts(g, nesting + 1), gen_stmts(g, nesting + 1)),
+        }
+    }
+
+    impl Arbitrary for Stmt {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            gen_stmt(g, 0)
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+            match self {
+                Stmt::Block(body) | Stmt::Loop(body) => Box::new(body.clone().into_iter()),
+                Stmt::If(then, else_) => Box::new(then.clone().into_iter().chain(else_.clone())),
+                _ => Box::new(std::iter::empty()),
+            }
+        }
+    }
+
+    fn print_stmts(out: &mut String, stmts: &[Stmt], labels: u32) {
+        for stmt in stmts {
+            print_stmt(out, stmt, labels);
+        }
+    }
+
+    /// Print `stmt` inside `labels` blocks, counting the function body.
+    fn print_stmt(out: &mut String, stmt: &Stmt, labels: u32) {
+        let depth = |depth: u32| depth % labels;
+        match stmt {
+            Stmt::Nop => write!(out, "(nop)"),
+            Stmt::SetLocal(value) => write!(out, "(set_local 1 (i32.const {}))", value),
+            Stmt::Block(body) => {
+                out.push_str("(block ");
+                print_stmts(out, body, labels + 1);
+                write!(out, ")")
+            }
+            Stmt::Loop(body) => {
+                out.push_str("(loop ");
+                print_stmts(out, body, labels + 1);
+                write!(out, ")")
+            }
+            Stmt::If(then, else_) => {
+                out.push_str("(if (get_local 0) (then ");
+                print_stmts(out, then, labels + 1);
+                out.push_str(") (else ");
+                print_stmts(out, else_, labels + 1);
+                write!(out, "))")
+            }
+            Stmt::Br(d) => write!(out, "(br {})", depth(*d)),
+            Stmt::BrIf(d) => write!(out, "(br_if {} (get_local 0))", depth(*d)),
+            Stmt::BrTable(targets, default) => {
+                out.push_str("(br_table ");
+                for target in targets {
+                    write!(out, "{} ", depth(*target)).unwrap();
+                }
+                write!(out, "{} (get_local 1))", depth(*default))
+            }
+            Stmt::Return => write!(out, "(return)"),
+            Stmt::Unreachable => write!(out, "(unreachable)"),
+        }
+        .unwrap();
+        out.push('\n');
+    }
+
+    fn print_module(body: &[Stmt]) -> String {
+        let mut out = "(module (func (param i32) (local i32)\n".to_string();
+        print_stmts(&mut out, body, 1);
+        out.push_str("))");
+        out
+    }
+
+    /// Check the invariants of the converter's output for a function with no results: every
+    /// label is declared before it's defined or branched to, defined exactly once if it's
+    /// branched to, and the stack is deep enough for every block's parameters wherever it's
+    /// entered. Nothing but block declarations may come between the end of a block and the
+    /// next label.
+    fn check(ops: &[OperatorFromWasm]) -> Result<(), String> {
+        let mut params = HashMap::<WasmLabel, usize>::new();
+        let mut defined = HashSet::<WasmLabel>::new();
+        let mut targets = HashSet::<WasmLabel>::new();
+        // The parameter starts on the stack, and the converter pushes the other local. This is
+        // `None` when control can't reach this point.
+        let mut height = Some(1);
+
+        for (i, op) in ops.iter().enumerate() {
+            let err = |message: String| Err(format!("{} at op {} ({})", message, i, op));
+
+            let mut check_target = |target: &BrTargetDrop<WasmLabel>, height: usize| {
+                let dropped = target
+                    .to_drop
+                    .as_ref()
+                    .map(|range| (range.end() - range.start() + 1) as usize)
+                    .unwrap_or(0);
+                let needed = match &target.target {
+                    BrTarget::Return => 0,
+                    BrTarget::Label(label) => {
+                        targets.insert(*label);
+                        match params.get(label) {
+                            Some(&params) => params,
+                            None => return Err(format!("{:?} not declared", label)),
+                        }
+                    }
+                };
+                if height < dropped + needed {
+                    return Err(format!(
+                        "stack of {} too shallow to drop {} and pass {}",
+                        height, dropped, needed
+                    ));
+                }
+                Ok(())
+            };
+
+            match (op, height) {
+                (
+                    Operator::Block {
+                        label, params: p, ..
+                    },
+                    _,
+                ) => {
+                    if params.insert(*label, p.len()).is_some() {
+                        return err("label declared twice".into());
+                    }
+                }
+                (Operator::Label(label), None) => match params.get(label) {
+                    Some(&p) => {
+                        if !defined.insert(*label) {
+                            return err("label defined twice".into());
+                        }
+                        height = Some(p);
+                    }
+                    None => return err("label defined before being declared".into()),
+                },
+                (Operator::Label(_), Some(_)) => {
+                    return err("previous block falls through into a label".into())
+                }
+                (_, None) => return err("code after the end of a block".into()),
+                (Operator::Br { target }, Some(h)) => {
+                    if let Err(e) = check_target(&target.clone().into(), h) {
+                        return err(e);
+                    }
+                    height = None;
+                }
+                (Operator::BrIf { then, else_ }, Some(h)) => {
+                    if h == 0 {
+                        return err("no condition".into());
+                    }
+                    for target in &[then, else_] {
+                        if let Err(e) = check_target(target, h - 1) {
+                            return err(e);
+                        }
+                    }
+                    height = None;
+                }
+                (Operator::BrTable(table), Some(h)) => {
+                    if h == 0 {
+                        return err("no index".into());
+                    }
+                    for target in table.targets.iter().chain(Some(&table.default)) {
+                        if let Err(e) = check_target(target, h - 1) {
+                            return err(e);
+                        }
+                    }
+                    height = None;
+                }
+                (Operator::Unreachable, Some(_)) => height = None,
+                (Operator::Const(_), Some(h)) => height = Some(h + 1),
+                (Operator::Pick(depth), Some(h)) | (Operator::Swap(depth), Some(h)) => {
+                    if *depth as usize >= h {
+                        return err(format!("stack of {} too shallow", h));
+                    }
+                    if let Operator::Pick(_) = op {
+                        height = Some(h + 1);
+                    }
+                }
+                (Operator::Drop(range), Some(h)) => {
+                    if *range.end() as usize >= h {
+                        return err(format!("stack of {} too shallow", h));
+                    }
+                    height = Some(h - (range.end() - range.start() + 1) as usize);
+                }
+                (_, Some(_)) => return err("unexpected operator".into()),
+            }
+        }
+
+        if height.is_some() {
+            return Err("the function falls off the end".into());
+        }
+        if let Some(label) = targets.difference(&defined).next() {
+            return Err(format!("{:?} is branched to but never defined", label));
+        }
+
+        Ok(())
+    }
+
+    quickcheck! {
+        fn control_flow_invariants(body: Vec<Stmt>) -> bool {
+            let wat = print_module(&body);
+            let wasm = wabt::wat2wasm(&wat).unwrap();
+            let funcs = translate_to_microwasm(&wasm).unwrap();
+
+            match check(&funcs[0]) {
+                Ok(()) => true,
+                Err(e) => panic!("{}\nin the microwasm for\n{}", e, wat),
+            }
+        }
+    }
+
+    #[test]
+    fn catches_unbalanced_blocks() {
+        let label = (0, crate::microwasm::NameTag::End);
+        let declared = Operator::Block {
+            label,
+            params: vec![],
+            has_backwards_callers: false,
+            num_callers: None,
+        };
+        let br = Operator::Br {
+            target: BrTarget::Label(label),
+        };
+
+        assert!(check(&[br.clone()]).is_err());
+        assert!(check(&[declared.clone(), br.clone()]).is_err());
+        assert!(check(&[
+            declared.clone(),
+            br.clone(),
+            Operator::Label(label),
+            Operator::Label(label)
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn written_for_every_function() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (func (param i32) (result i32) (i32.eqz (get_local 0)))
+  (func (result f64) (f64.const 1.5)))
+"#,
+        )
+        .unwrap();
+
+        let mut out = vec![];
+        crate::write_microwasm(&wasm, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let fn_1 = out.find(".fn_1:").unwrap();
+        assert!(out.starts_with(".fn_0:"));
+        assert!(out[..fn_1].contains("eqz"));
+        assert!(out[fn_1..].contains("const 1.5f64"));
+    }
+
+    #[test]
+    fn redundant_bounds_checks() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (memory 1)
+  (func (param i32 i32) (result i32)
+    (i32.store offset=12 (get_local 0) (get_local 1))
+    (i32.store offset=4 (get_local 0) (i32.const 5))
+    (i32.load16_u offset=10 (get_local 0))
+    (i32.load offset=14 (get_local 0))
+    (i32.load offset=8 (get_local 1))
+    (set_local 0 (get_local 1))
+    (i32.load offset=4 (get_local 0))
+    (block
+      (drop (i32.load (get_local 1))))
+    (drop)
+    (drop)
+    (drop)))
+"#,
+        )
+        .unwrap();
+        let funcs = translate_to_microwasm(&wasm).unwrap();
+        let checked = funcs[0]
+            .iter()
+            .filter_map(|op| match op {
+                Operator::Load { memarg, .. }
+                | Operator::Load16 { memarg, .. }
+                | Operator::Store { memarg, .. } => Some(memarg.bounds_checked),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Only accesses that touch nothing past the biggest offset already checked from the
+        // same local, with no write to the local or control flow in between, are skipped
+        assert_eq!(checked, vec![false, true, true, false, false, false, false]);
+    }
+}
+
+mod microwasm_fixtures {
+    use crate::microwasm::{BrTable, BrTarget, BrTargetDrop, Operator, SignlessType, Value, I32};
+    use crate::module::translate_microwasm;
+    use crate::Instance;
+    use crate::{FuncType, ValueType};
+
+    type Op = Operator<&'static str>;
+
+    /// Compile a single function with the given type, written directly in microwasm.
+    fn compile(params: &[ValueType], returns: &[ValueType], body: Vec<Op>) -> Instance {
+        let ty = FuncType {
+            params: params.into(),
+            returns: returns.into(),
+        };
+        let module = translate_microwasm(vec![ty], vec![body]).unwrap();
+        module.disassemble();
+        module.instantiate()
+    }
+
+    fn block(label: &'static str, params: Vec<SignlessType>) -> Op {
+        Operator::Block {
+            label,
+            params,
+            has_backwards_callers: false,
+            num_callers: Some(1),
+        }
+    }
+
+    fn ret() -> Op {
+        Operator::Br {
+            target: BrTarget::Return,
+        }
+    }
+
+    #[test]
+    fn pick_swap() {
+        // (a, b) -> b - a, leaving the result where `a` was
+        let instance = compile(
+            &[ValueType::I32, ValueType::I32],
+            &[ValueType::I32],
+            vec![
+                Operator::Pick(1),
+                Operator::Sub(I32),
+                Operator::Swap(1),
+                Operator::Drop(0..=0),
+                ret(),
+            ],
+        );
+
+        assert_eq!(instance.execute_func::<_, i32>(0, (3, 10)), Ok(7));
+        assert_eq!(instance.execute_func::<_, i32>(0, (10, 3)), Ok(-7));
+    }
+
+    // `wasmparser` doesn't validate `table.size` correctly, so it's tested here
+    #[test]
+    fn table_size() {
+        let instance = compile(
+            &[ValueType::I32],
+            &[ValueType::I32],
+            vec![
+                Operator::TableGrow { table_index: 0 },
+                Operator::Drop(0..=0),
+                Operator::TableSize { table_index: 0 },
+                ret(),
+            ],
+        );
+
+        assert_eq!(instance.execute_func::<_, u32>(0, (3u32,)), Ok(3));
+        assert_eq!(instance.execute_func::<_, u32>(0, (2u32,)), Ok(5));
+        assert_eq!(instance.table_size(), 5);
+    }
+
+    #[test]
+    fn br_table() {
+        let target = |label| BrTargetDrop {
+            target: BrTarget::Label(label),
+            to_drop: Some(0..=0),
+        };
+        let instance = compile(
+            &[ValueType::I32],
+            &[ValueType::I32],
+            vec![
+                block("zero", vec![]),
+                block("one", vec![]),
+                block("other", vec![]),
+                Operator::Pick(0),
+                Operator::BrTable(BrTable {
+                    targets: vec![target("zero"), target("one")],
+                    default: target("other"),
+                }),
+                Operator::Label("zero"),
+                Operator::Const(Value::I32(100)),
+                ret(),
+                Operator::Label("one"),
+                Operator::Const(Value::I32(200)),
+                ret(),
+                Operator::Label("other"),
+                Operator::Const(Value::I32(300)),
+                ret(),
+            ],
+        );
+
+        assert_eq!(instance.execute_func::<_, u32>(0, (0u32,)), Ok(100));
+        assert_eq!(instance.execute_func::<_, u32>(0, (1u32,)), Ok(200));
+        assert_eq!(instance.execute_func::<_, u32>(0, (2u32,)), Ok(300));
+        assert_eq!(instance.execute_func::<_, u32>(0, (1000u32,)), Ok(300));
+    }
+
+    #[test]
+    fn block_params() {
+        // Pass the sum of the arguments into a block as well as the arguments themselves
+        let instance = compile(
+            &[ValueType::I32, ValueType::I32],
+            &[ValueType::I32],
+            vec![
+                block("end", vec![I32, I32, I32]),
+                Operator::Pick(1),
+                Operator::Pick(1),
+                Operator::Add(I32),
+                Operator::Br {
+                    target: BrTarget::Label("end"),
+                },
+                Operator::Label("end"),
+                Operator::Swap(2),
+                Operator::Drop(0..=1),
+                ret(),
+            ],
+        );
+
+        assert_eq!(instance.execute_func::<_, u32>(0, (2u32, 5u32)), Ok(7));
+    }
+}
+
+mod memories {
+    use crate::index_space::DefinedFuncIndex;
+    use crate::{
+        module::{translate_only, translate_only_with},
+        CompiledModule, ExecutionError, HostMemory, Instance, InstanceImports, MemoryGrowth,
+        MemoryStyle, TranslateOptions, TranslatedCodeSection,
+    };
+    use std::{
+        ffi::CString,
+        sync::{Arc, Mutex},
+    };
+
+    const IMPORTED: &str = r#"
+(module
+  (import "env" "mem" (memory 1))
+  (func (param i32 i32)
+    (i32.store (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load (get_local 0))))
+"#;
+
+    #[test]
+    fn exported() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (memory (export "mem") 1 1)
+  (func (param i32 i32)
+    (i32.store (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load (get_local 0))))
+"#,
+        )
+        .unwrap();
+        let mut instance = translate_only(&wasm).unwrap().instantiate();
+
+        assert_eq!(
+            instance.execute_func::<_, ()>(0, (8u32, 0x1234_5678u32)),
+            Ok(())
+        );
+
+        let memory = instance.memory_mut("mem").unwrap();
+        assert_eq!(memory.len(), 65536);
+        assert_eq!(&memory[8..12], &[0x78, 0x56, 0x34, 0x12]);
+        memory[100] = 42;
+
+        assert_eq!(instance.execute_func::<_, u32>(1, (100u32,)), Ok(42));
+        assert!(instance.memory_mut("other").is_none());
+    }
+
+    #[test]
+    fn imported() {
+        let wasm = wabt::wat2wasm(IMPORTED).unwrap();
+        let module = Arc::new(translate_only(&wasm).unwrap());
+        let memory = Arc::new(HostMemory::new(1));
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", memory.clone());
+
+        let a = Instance::with_imports(module.clone(), &imports).unwrap();
+        let b = Instance::with_imports(module, &imports).unwrap();
+
+        assert_eq!(a.execute_func::<_, ()>(0, (16u32, 77u32)), Ok(()));
+        assert_eq!(b.execute_func::<_, u32>(1, (16u32,)), Ok(77));
+        assert_eq!(unsafe { *memory.as_ptr().add(16) }, 77);
+    }
+
+    #[test]
+    fn host_allocated() {
+        let wasm = wabt::wat2wasm(IMPORTED).unwrap();
+        let mut buffer = vec![0u8; 65536];
+        buffer[4] = 9;
+        let memory = unsafe { HostMemory::from_raw_parts(buffer.as_mut_ptr(), buffer.len()) };
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", Arc::new(memory));
+
+        let instance =
+            Instance::with_imports(Arc::new(translate_only(&wasm).unwrap()), &imports).unwrap();
+
+        assert_eq!(instance.execute_func::<_, u32>(1, (4u32,)), Ok(9));
+        assert_eq!(instance.execute_func::<_, ()>(0, (65532u32, 1u32)), Ok(()));
+        drop(instance);
+        assert_eq!(buffer[65532], 1);
+    }
+
+    const GROWABLE: &str = r#"
+(module
+  (memory 1 3)
+  (func (param i32) (result i32)
+    (memory.grow (get_local 0)))
+  (func (result i32)
+    (memory.size))
+  (func (param i32 i32)
+    (i32.store (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load (get_local 0))))
+"#;
+
+    fn growable(style: MemoryStyle) -> Instance {
+        let options = TranslateOptions {
+            memory_style: style,
+            ..Default::default()
+        };
+        translate_only_with(&wabt::wat2wasm(GROWABLE).unwrap(), options)
+            .unwrap()
+            .instantiate()
+    }
+
+    #[test]
+    fn grow() {
+        for &style in &[MemoryStyle::BoundsChecked, MemoryStyle::GuardPages] {
+            let instance = growable(style);
+
+            assert_eq!(instance.execute_func::<_, ()>(2, (100u32, 7u32)), Ok(()));
+            assert_eq!(instance.execute_func::<_, u32>(0, (1u32,)), Ok(1));
+            assert_eq!(instance.execute_func::<(), u32>(1, ()), Ok(2));
+            assert_eq!(instance.execute_func::<_, u32>(3, (100u32,)), Ok(7));
+            assert_eq!(instance.execute_func::<_, ()>(2, (70000u32, 9u32)), Ok(()));
+            assert_eq!(instance.execute_func::<_, u32>(3, (70000u32,)), Ok(9));
+
+            // The declared maximum is 3 pages
+            assert_eq!(instance.execute_func::<_, i32>(0, (2u32,)), Ok(-1));
+            assert_eq!(instance.execute_func::<_, u32>(0, (0u32,)), Ok(2));
+            assert_eq!(instance.execute_func::<_, u32>(0, (1u32,)), Ok(2));
+            assert_eq!(instance.execute_func::<(), u32>(1, ()), Ok(3));
+        }
+    }
+
+    #[test]
+    fn growth_policies() {
+        let mut instance = growable(MemoryStyle::BoundsChecked);
+        instance.set_memory_growth(MemoryGrowth::Deny);
+        assert_eq!(instance.execute_func::<_, i32>(0, (1u32,)), Ok(-1));
+
+        instance.set_memory_growth(MemoryGrowth::Limit(2));
+        assert_eq!(instance.execute_func::<_, i32>(0, (2u32,)), Ok(-1));
+        assert_eq!(instance.execute_func::<_, i32>(0, (1u32,)), Ok(1));
+
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        instance.set_memory_growth(MemoryGrowth::Callback(Arc::new(move |old, new| {
+            seen.lock().unwrap().push((old, new));
+            new < 3
+        })));
+        assert_eq!(instance.execute_func::<_, i32>(0, (1u32,)), Ok(-1));
+        assert_eq!(*requests.lock().unwrap(), vec![(2, 3)]);
+        assert_eq!(instance.execute_func::<(), u32>(1, ()), Ok(2));
+    }
+
+    const FIXED: &str = r#"
+(module
+  (memory 1 1)
+  (func (result i32)
+    (i32.store offset=8 (i32.const 16) (i32.const 7))
+    (i32.load (i32.const 24)))
+  (func (param i32 i32)
+    (i32.store (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load offset=4 (get_local 0))))
+"#;
+
+    #[test]
+    fn fixed_size() {
+        let wasm = wabt::wat2wasm(FIXED).unwrap();
+        let checked = translate_only(&wasm).unwrap().instantiate();
+        let options = TranslateOptions {
+            fixed_size_memory: true,
+            ..Default::default()
+        };
+        let fixed = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        for instance in &[&checked, &fixed] {
+            assert_eq!(instance.execute_func::<(), u32>(0, ()), Ok(7));
+            assert_eq!(instance.execute_func::<_, ()>(1, (65532u32, 9u32)), Ok(()));
+            assert_eq!(instance.execute_func::<_, u32>(2, (65528u32,)), Ok(9));
+        }
+
+        // Accesses at constant addresses aren't checked at all, and the rest compare against
+        // a constant rather than the memory's length
+        let (checked, fixed) = (checked.code_section(), fixed.code_section());
+        let size = |code: &TranslatedCodeSection, func| {
+            code.function_stats(DefinedFuncIndex(func)).code_size
+        };
+        assert!(size(fixed, 0) < size(checked, 0));
+        assert!(size(fixed, 1) <= size(checked, 1));
+        assert!(size(fixed, 2) <= size(checked, 2));
+    }
+
+    #[test]
+    fn memory64() {
+        // `wabt` doesn't know about 64-bit memories either, so this is only parsed
+        let wasm = wabt::Wat2Wasm::new()
+            .validate(false)
+            .convert(
+                r#"
+(module
+  (memory 1 3)
+  (data (i64.const 16) "\2a")
+  (func (param i64) (result i64)
+    (memory.grow (get_local 0)))
+  (func (result i64)
+    (memory.size))
+  (func (param i64 i32)
+    (i32.store offset=4 (get_local 0) (get_local 1)))
+  (func (param i64) (result i32)
+    (i32.load8_u (get_local 0))))
+"#,
+            )
+            .unwrap();
+        let options = TranslateOptions {
+            memory64: true,
+            ..Default::default()
+        };
+        let instance = translate_only_with(wasm.as_ref(), options)
+            .unwrap()
+            .instantiate();
+
+        assert_eq!(instance.execute_func::<_, u32>(3, (16u64,)), Ok(42));
+        assert_eq!(instance.execute_func::<_, ()>(2, (65528u64, 7u32)), Ok(()));
+        assert_eq!(instance.execute_func::<_, u32>(3, (65532u64,)), Ok(7));
+
+        assert_eq!(instance.execute_func::<_, u64>(0, (1u64,)), Ok(1));
+        assert_eq!(instance.execute_func::<(), u64>(1, ()), Ok(2));
+        assert_eq!(
+            instance.execute_func::<_, ()>(2, (131_064u64, 9u32)),
+            Ok(())
+        );
+        assert_eq!(instance.execute_func::<_, u32>(3, (131_068u64,)), Ok(9));
+
+        assert_eq!(
+            instance.execute_func::<_, u64>(0, (1u64 << 32,)),
+            Ok(u64::max_value())
+        );
+        assert_eq!(
+            instance.execute_func::<_, u64>(0, (2u64,)),
+            Ok(u64::max_value())
+        );
+        assert_eq!(instance.execute_func::<(), u64>(1, ()), Ok(2));
+    }
+
+    #[test]
+    fn memory64_is_validated() {
+        let options = || TranslateOptions {
+            memory64: true,
+            ..Default::default()
+        };
+        let translate = |wat: &str| {
+            let wasm = wabt::Wat2Wasm::new().validate(false).convert(wat).unwrap();
+            translate_only_with(wasm.as_ref(), options())
+        };
+
+        // Addresses into a 64-bit memory have to be `i64`
+        assert!(
+            translate("(module (memory 1) (func (result i32) (i32.load (i32.const 0))))").is_err()
+        );
+        assert!(translate(
+            "(module (memory 1) (func (param i32 i32) (i32.store (get_local 0) (get_local 1))))"
+        )
+        .is_err());
+        assert!(translate("(module (memory 1) (func (result i32) (memory.size)))").is_err());
+
+        // Everything else is still checked as usual
+        assert!(translate(
+            "(module (memory 1) (func (param i64) (result i64) (i64.load (get_local 1))))"
+        )
+        .is_err());
+        assert!(translate(
+            "(module (memory 1) (func (param i64 f64) (f32.store (get_local 0) (get_local 1))))"
+        )
+        .is_err());
+
+        assert!(translate(
+            "(module (memory 1) (func (param i64 f64) (local i32) \
+             (f64.store (get_local 0) (get_local 1)) (set_local 2 (i32.const 1))))"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn host_accessors() {
+        let wasm = wabt::wat2wasm(IMPORTED).unwrap();
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", Arc::new(HostMemory::new(1)));
+        let mut instance =
+            Instance::with_imports(Arc::new(translate_only(&wasm).unwrap()), &imports).unwrap();
+
+        instance.write_u32(8, 0xdead_beef).unwrap();
+        assert_eq!(instance.execute_func::<_, u32>(1, (8u32,)), Ok(0xdead_beef));
+        assert_eq!(instance.execute_func::<_, ()>(0, (13u32, 7u32)), Ok(()));
+        assert_eq!(instance.read_u32(13), Ok(7));
+        assert_eq!(instance.read_u64(9), Ok(0x0000_0007_00de_adbe));
+        assert_eq!(instance.read_memory(8, 2), Ok(vec![0xef, 0xbe]));
+
+        let hello = CString::new("hello").unwrap();
+        instance.write_c_str(100, &hello).unwrap();
+        assert_eq!(instance.read_c_str(100), Ok(hello));
+
+        assert_eq!(
+            instance.read_u32(65533),
+            Err(ExecutionError::MemoryOutOfBounds)
+        );
+        assert_eq!(
+            instance.write_memory(65535, &[1, 2]),
+            Err(ExecutionError::MemoryOutOfBounds)
+        );
+        assert_eq!(instance.read_memory(65535, 1), Ok(vec![0]));
+        instance.write_memory(65535, &[0xff]).unwrap();
+        assert_eq!(
+            instance.read_c_str(65535),
+            Err(ExecutionError::MemoryOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn bad_imports() {
+        let wasm = wabt::wat2wasm(IMPORTED).unwrap();
+        let module = Arc::new(translate_only(&wasm).unwrap());
+
+        assert_eq!(
+            Instance::with_imports(module.clone(), &InstanceImports::new()).err(),
+            Some(ExecutionError::MissingImport)
+        );
+
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", Arc::new(HostMemory::new(0)));
+        assert_eq!(
+            Instance::with_imports(module, &imports).err(),
+            Some(ExecutionError::IncompatibleImport)
+        );
+    }
+
+    #[test]
+    fn constant_addresses_fold_into_the_access() {
+        let wat = r#"
+(module
+  (memory (export "mem") 1 1)
+  (func (param i32)
+    (i32.store offset=16 (i32.const 32) (get_local 0)))
+  (func (param i32)
+    (i32.store (i32.const 48) (get_local 0)))
+  (func (result i32)
+    (i32.load16_u offset=0x7ff0 (i32.const 0x10)))
+  (func (result i32)
+    (i32.load16_u (i32.const 0x8000)))
+  (func (param i32) (result i32)
+    (i32.load (get_local 0))))
+"#;
+        let checked = translate_only(&wabt::wat2wasm(wat).unwrap()).unwrap();
+        let guarded = translate_guarded(wat);
+
+        for module in vec![checked, guarded] {
+            let mut instance = module.instantiate();
+
+            let code = instance.code_section();
+            let size = |func| code.function_stats(DefinedFuncIndex(func)).code_size;
+            assert_eq!(size(0), size(1));
+            assert_eq!(size(2), size(3));
+
+            assert_eq!(instance.execute_func::<_, ()>(0, (0xabcdu32,)), Ok(()));
+            assert_eq!(instance.execute_func::<_, u32>(4, (48u32,)), Ok(0xabcd));
+            assert_eq!(instance.execute_func::<_, ()>(1, (0x1234u32,)), Ok(()));
+            assert_eq!(instance.execute_func::<_, u32>(4, (48u32,)), Ok(0x1234));
+
+            let memory = instance.memory_mut("mem").unwrap();
+            memory[0x8000] = 7;
+            memory[0x8001] = 1;
+            assert_eq!(instance.execute_func::<_, u32>(2, ()), Ok(0x107));
+            assert_eq!(instance.execute_func::<_, u32>(3, ()), Ok(0x107));
+        }
+    }
+
+    #[test]
+    fn offsets_too_big_for_a_displacement() {
+        // These can only be in bounds of memories bigger than 2GiB, so we can't run them
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (memory 1 1)
+  (func (param i32 i32)
+    (i32.store offset=0x80000000 (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load offset=0xfffffff0 (get_local 0)))
+  (func (result i32)
+    (i32.load offset=0x10 (i32.const -1))))
+"#,
+        )
+        .unwrap();
+
+        assert!(translate_only(&wasm).is_ok());
+        assert!(translate_only_with(
+            &wasm,
+            TranslateOptions {
+                memory_style: MemoryStyle::GuardPages,
+                ..Default::default()
+            }
+        )
+        .is_ok());
+    }
+
+    fn translate_guarded(wat: &str) -> CompiledModule {
+        let wasm = wabt::wat2wasm(wat).unwrap();
+        let options = TranslateOptions {
+            memory_style: MemoryStyle::GuardPages,
+            ..Default::default()
+        };
+        translate_only_with(&wasm, options).unwrap()
+    }
+
+    #[test]
+    fn guard_pages() {
+        let wat = r#"
+(module
+  (memory 1 1)
+  (func (param i32 i32)
+    (i32.store offset=4 (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load offset=4 (get_local 0))))
+"#;
+        let checked = translate_only(&wabt::wat2wasm(wat).unwrap())
+            .unwrap()
+            .instantiate();
+        let guarded = translate_guarded(wat).instantiate();
+        guarded.disassemble();
+
+        assert_eq!(guarded.execute_func::<_, ()>(0, (65528u32, 5u32)), Ok(()));
+        assert_eq!(guarded.execute_func::<_, u32>(1, (65528u32,)), Ok(5));
+        assert!(guarded.code_section().buffer().len() < checked.code_section().buffer().len());
+    }
+
+    #[test]
+    fn guarded_imports() {
+        let module = Arc::new(translate_guarded(IMPORTED));
+
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "mem", Arc::new(HostMemory::new(1)));
+        assert_eq!(
+            Instance::with_imports(module.clone(), &imports).err(),
+            Some(ExecutionError::IncompatibleImport)
+        );
+
+        let memory = Arc::new(HostMemory::guarded(1).unwrap());
+        imports.register_memory("env", "mem", memory.clone());
+        let instance = Instance::with_imports(module, &imports).unwrap();
+        assert_eq!(instance.execute_func::<_, ()>(0, (65532u32, 3u32)), Ok(()));
+        assert_eq!(unsafe { *memory.as_ptr().add(65532) }, 3);
+
+        // Bounds-checked modules can use guarded memory too
+        let module = Arc::new(translate_only(&wabt::wat2wasm(IMPORTED).unwrap()).unwrap());
+        let instance = Instance::with_imports(module, &imports).unwrap();
+        assert_eq!(instance.execute_func::<_, u32>(1, (65532u32,)), Ok(3));
+    }
+}
+
+mod host_functions {
+    use crate::{
+        module::translate_only_with, DefinedFuncIndex, ExecutionError, HostError, HostFunctions,
+        ImportedFuncIndex, TranslateOptions, VmCtx,
+    };
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const CODE: &str = r#"
+(module
+  (import "env" "sub" (func $sub (param i32 i32) (result i32)))
+  (import "env" "peek" (func $peek (param i32) (result i32)))
+  (import "env" "misalignment" (func $misalignment (result i32)))
+  (import "env" "weigh" (func $weigh (param i32 i32 i32 i32 i32) (result i64)))
+  (memory 1 1)
+  (func (param i32 i32) (result i32)
+    (call $sub (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.store (i32.const 16) (get_local 0))
+    (call $peek (i32.const 16)))
+  (func (param i32) (result i32)
+    ;; Spill values so that the stack pointer moves by an odd number of words
+    (i32.add
+      (get_local 0)
+      (i32.add
+        (get_local 0)
+        (i32.add (get_local 0) (call $misalignment)))))
+  (func (param i32) (result i64)
+    (call $weigh
+      (get_local 0) (i32.const 2) (i32.const 3) (i32.const 4) (i32.const 5))))
+"#;
+
+    #[repr(align(16))]
+    struct Aligned(u8);
+
+    fn host_functions() -> HostFunctions {
+        let mut funcs = HostFunctions::new();
+        funcs
+            .register("env", "sub", |_: &VmCtx, a: i32, b: i32| Ok(a - b))
+            .register("env", "peek", |ctx: &VmCtx, addr: u32| {
+                let bytes = ctx
+                    .memory()
+                    .get(addr as usize..addr as usize + 4)
+                    .ok_or_else(|| HostError("out of bounds".to_string()))?;
+                Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            })
+            .register("env", "misalignment", |_: &VmCtx| {
+                let aligned = Aligned(0);
+                Ok((&aligned as *const Aligned as usize % 16) as u32)
+            })
+            .register(
+                "env",
+                "weigh",
+                |_: &VmCtx, a: i32, b: i32, c: i32, d: i32, e: i32| {
+                    Ok([a, b, c, d, e]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &x)| i as i64 * x as i64)
+                        .sum::<i64>())
+                },
+            );
+        funcs
+    }
+
+    #[test]
+    fn call_host() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let options = TranslateOptions {
+            host_functions: host_functions(),
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+        translated.disassemble();
+
+        assert_eq!(translated.execute_func::<_, i32>(0, (5, 7)), Ok(-2));
+        assert_eq!(translated.execute_func::<_, u32>(1, (1234u32,)), Ok(1234));
+        assert_eq!(translated.execute_func::<_, u32>(2, (0u32,)), Ok(0));
+        assert_eq!(
+            translated.execute_func::<_, i64>(3, (1,)),
+            Ok(2 + 6 + 12 + 20)
+        );
+    }
+
+    #[test]
+    fn host_state() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (import "env" "count" (func $count (param i32) (result i32)))
+  (func (param i32) (result i32)
+    (call $count (get_local 0))))
+"#,
+        )
+        .unwrap();
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("env", "count", |ctx: &VmCtx, n: u32| {
+            let total = ctx
+                .host_state::<AtomicU32>()
+                .ok_or_else(|| HostError("no state".to_string()))?;
+            Ok(total.fetch_add(n, Ordering::Relaxed) + n)
+        });
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let mut translated = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        translated.set_host_state(AtomicU32::new(10));
+        assert_eq!(translated.execute_func::<_, u32>(0, (5u32,)), Ok(15));
+        assert_eq!(translated.execute_func::<_, u32>(0, (2u32,)), Ok(17));
+        assert_eq!(
+            translated
+                .host_state::<AtomicU32>()
+                .map(|total| total.load(Ordering::Relaxed)),
+            Some(17)
+        );
+        assert!(translated.host_state::<u32>().is_none());
+    }
+
+    // A host function that fails traps the wasm code that called it, skipping the rest of
+    // it, and the instance can still be called afterwards.
+    #[test]
+    fn host_errors() {
+        let wasm = wabt::wat2wasm(
+            r#"
+(module
+  (import "env" "check" (func $check (param i32) (result i32)))
+  (func (param i32) (result i32)
+    (i32.add (call $check (get_local 0)) (i32.const 1))))
+"#,
+        )
+        .unwrap();
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("env", "check", |_: &VmCtx, n: i32| match n {
+            0 => panic!("zero"),
+            n if n < 0 => Err(HostError("negative".to_string())),
+            n => Ok(n),
+        });
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        assert_eq!(
+            translated.execute_func::<_, i32>(0, (-1,)),
+            Err(ExecutionError::HostFunctionFailed(HostError(
+                "negative".to_string()
+            )))
+        );
+        assert_eq!(translated.execute_func::<_, i32>(0, (1,)), Ok(2));
+        assert_eq!(
+            translated.execute_func::<_, i32>(0, (0,)),
+            Err(ExecutionError::HostFunctionFailed(HostError(
+                "zero".to_string()
+            )))
+        );
+        assert_eq!(translated.execute_func::<_, i32>(0, (5,)), Ok(6));
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let mut funcs = host_functions();
+        funcs.register("env", "sub", |_: &VmCtx, a: i64, b: i64| Ok(a - b));
+        let options = TranslateOptions {
+            host_functions: funcs,
+            ..Default::default()
+        };
+
+        assert!(translate_only_with(&wasm, options).is_err());
+    }
+
+    #[test]
+    fn func_ranges_exclude_stubs() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let options = TranslateOptions {
+            host_functions: host_functions(),
+            ..Default::default()
+        };
+        let translated = translate_only_with(&wasm, options).unwrap().instantiate();
+        let code = translated.code_section();
+
+        let last = code.func_range(DefinedFuncIndex(3));
+        let first_stub = code.exit_stub(ImportedFuncIndex(0)).unwrap();
+        assert_eq!(code.buffer()[last.end..].as_ptr(), first_stub);
+        assert_eq!(code.funcs().count(), 4);
+    }
+}
+
+mod imported_globals {
+    use crate::{
+        module::translate_only, ExecutionError, HostGlobal, Instance, InstanceImports, ValueType,
+    };
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (import "env" "sp" (global $sp (mut i32)))
+  (import "env" "base" (global $base i32))
+  (global $start i32 (get_global $base))
+  (memory 1 1)
+  (data (get_global $base) "\2a")
+  (func (param i32) (result i32)
+    (set_global $sp (i32.sub (get_global $sp) (get_local 0)))
+    (get_global $sp))
+  (func (result i32)
+    (i32.load8_u (get_global $start))))
+"#;
+
+    fn imports(sp: &Arc<HostGlobal>) -> InstanceImports {
+        let mut imports = InstanceImports::new();
+        imports
+            .register_global("env", "sp", sp.clone())
+            .register_global(
+                "env",
+                "base",
+                Arc::new(HostGlobal::new(ValueType::I32, false, 8)),
+            );
+        imports
+    }
+
+    #[test]
+    fn shared_between_instances() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let sp = Arc::new(HostGlobal::new(ValueType::I32, true, 1024));
+        let imports = imports(&sp);
+        let a = Instance::with_imports(module.clone(), &imports).unwrap();
+        let b = Instance::with_imports(module, &imports).unwrap();
+
+        assert_eq!(a.execute_func::<_, u32>(0, (16u32,)), Ok(1008));
+        assert_eq!(b.execute_func::<_, u32>(0, (8u32,)), Ok(1000));
+        assert_eq!(sp.get() as u32, 1000);
+
+        sp.set(64);
+        assert_eq!(a.execute_func::<_, u32>(0, (0u32,)), Ok(64));
+    }
+
+    #[test]
+    fn initializers() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let sp = Arc::new(HostGlobal::new(ValueType::I32, true, 0));
+        let instance = Instance::with_imports(module, &imports(&sp)).unwrap();
+
+        assert_eq!(instance.execute_func::<(), u32>(1, ()), Ok(42));
+    }
+
+    #[test]
+    fn bad_imports() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let mut imports = InstanceImports::new();
+        imports.register_global(
+            "env",
+            "sp",
+            Arc::new(HostGlobal::new(ValueType::I32, true, 0)),
+        );
+
+        assert_eq!(
+            Instance::with_imports(module.clone(), &imports).err(),
+            Some(ExecutionError::MissingImport)
+        );
+
+        imports.register_global(
+            "env",
+            "base",
+            Arc::new(HostGlobal::new(ValueType::I32, true, 0)),
+        );
+        assert_eq!(
+            Instance::with_imports(module, &imports).err(),
+            Some(ExecutionError::IncompatibleImport)
+        );
+    }
+}
+
+mod deterministic_floats {
+    use crate::{module::translate_only_with, CompiledModule, Error, TranslateOptions};
+
+    fn translate(code: &str) -> Result<CompiledModule, Error> {
+        let options = TranslateOptions {
+            deterministic_floats: true,
+            ..Default::default()
+        };
+        translate_only_with(&wabt::wat2wasm(code).unwrap(), options)
+    }
+
+    #[test]
+    fn sign_operators_allowed() {
+        let instance = translate(
+            r#"
+(module
+  (func (param f32 f32) (result f32)
+    (f32.copysign (f32.abs (get_local 0)) (f32.neg (get_local 1))))
+  (func (param f64) (result i32)
+    (f64.lt (get_local 0) (f64.convert_s/i32 (i32.trunc_s/f64 (get_local 0))))))
+"#,
+        )
+        .unwrap()
+        .instantiate();
+
+        assert_eq!(
+            instance.execute_func::<_, f32>(0, (-1.5f32, 2f32)),
+            Ok(-1.5)
+        );
+        assert_eq!(instance.execute_func::<_, u32>(1, (2.5f64,)), Ok(0));
+    }
+
+    #[test]
+    fn nan_producing_operators_rejected() {
+        let err = translate(
+            r#"
+(module
+  (func (param f32) (result f32)
+    (f32.neg (get_local 0)))
+  (func (param f64 f64) (result f64)
+    (f64.add (get_local 0) (get_local 1))))
+"#,
+        )
+        .err()
+        .unwrap();
+
+        match err {
+            Error::Input(message) => assert!(message.starts_with("Function 1 uses F64Add")),
+            err => panic!("Unexpected error {}", err),
+        }
+    }
+}
+
+mod linking {
+    use crate::{
+        module::translate_only, Error, ExecutionError, HostGlobal, HostMemory, Instance,
+        InstanceImports, ValueType, VmCtx,
+    };
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (import "env" "scale" (func $scale (param i32) (result i32)))
+  (import "env" "memory" (memory 1))
+  (import "env" "offset" (global $offset i32))
+  (func (param i32) (result i32)
+    (i32.add (call $scale (get_local 0)) (get_global $offset)))
+  (func (result i32)
+    (i32.load (i32.const 0))))
+"#;
+
+    fn imports(factor: i32) -> InstanceImports {
+        let mut imports = InstanceImports::new();
+        imports
+            .register_func("env", "scale", move |_: &VmCtx, a: i32| Ok(a * factor))
+            .register_memory("env", "memory", Arc::new(HostMemory::new(1)))
+            .register_global(
+                "env",
+                "offset",
+                Arc::new(HostGlobal::new(ValueType::I32, false, 1)),
+            );
+        imports
+    }
+
+    #[test]
+    fn resolve_at_instantiation() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let double = Instance::link(module.clone(), &imports(2)).unwrap();
+        let triple = Instance::link(module, &imports(3)).unwrap();
+
+        assert_eq!(double.execute_func::<_, i32>(0, (10,)), Ok(21));
+        assert_eq!(triple.execute_func::<_, i32>(0, (10,)), Ok(31));
+    }
+
+    #[test]
+    fn unresolved() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let mut imports = InstanceImports::new();
+        imports.register_memory("env", "memory", Arc::new(HostMemory::new(1)));
+
+        assert_eq!(
+            Instance::link(module.clone(), &imports).err(),
+            Some(Error::UnresolvedImport {
+                module: "env".to_string(),
+                field: "scale".to_string(),
+                kind: "function",
+            })
+        );
+        assert_eq!(
+            Instance::with_imports(module, &imports).err(),
+            Some(ExecutionError::MissingImport)
+        );
+    }
+
+    #[test]
+    fn incompatible() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let mut wrong_func = imports(2);
+        wrong_func.register_func("env", "scale", |_: &VmCtx, a: i64| Ok(a));
+        match Instance::link(module.clone(), &wrong_func).err() {
+            Some(Error::IncompatibleImport { module, field, .. }) => {
+                assert_eq!((&module[..], &field[..]), ("env", "scale"));
+            }
+            _ => panic!("Expected an incompatible import"),
+        }
+
+        let mut wrong_global = imports(2);
+        wrong_global.register_global(
+            "env",
+            "offset",
+            Arc::new(HostGlobal::new(ValueType::I32, true, 1)),
+        );
+        match Instance::link(module, &wrong_global).err() {
+            Some(Error::IncompatibleImport { field, reason, .. }) => {
+                assert_eq!(field, "offset");
+                assert!(reason.contains("constant"));
+            }
+            _ => panic!("Expected an incompatible import"),
+        }
+    }
+}
+
+mod operator_libcalls {
+    use crate::{
+        module::translate_only_with, OperatorLibcalls, TranslateOptions, VmCtx, VMCTX_VERSION,
+    };
+
+    const CODE: &str = r#"
+(module
+  (func (param f32) (result f32)
+    (f32.nearest (get_local 0)))
+  (func (param i64 i64) (result i64)
+    (i64.rotl (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.popcnt (get_local 0))))
+"#;
+
+    extern "sysv64" fn nearest(vmctx: *const VmCtx, value: f32) -> f32 {
+        assert_eq!(unsafe { (*vmctx).version() }, VMCTX_VERSION);
+        value + 0.25
+    }
+
+    extern "sysv64" fn rotl(_vmctx: *const VmCtx, value: i64, amount: i64) -> i64 {
+        value * 10 + amount
+    }
+
+    #[test]
+    fn replace_operators() {
+        let mut libcalls = OperatorLibcalls::new();
+        unsafe {
+            libcalls
+                .register("f32.nearest", nearest as *const u8)
+                .register("i64.rotl", rotl as *const u8);
+        }
+        let options = TranslateOptions {
+            operator_libcalls: libcalls,
+            ..Default::default()
+        };
+        let instance = translate_only_with(&wabt::wat2wasm(CODE).unwrap(), options)
+            .unwrap()
+            .instantiate();
+
+        assert_eq!(instance.execute_func::<_, f32>(0, (1.5f32,)), Ok(1.75));
+        assert_eq!(instance.execute_func::<_, i64>(1, (4i64, 2i64)), Ok(42));
+        // Operators without a libcall are compiled as usual
+        assert_eq!(instance.execute_func::<_, u32>(2, (0xf0u32,)), Ok(4));
+    }
+}
+
+mod libcalls {
+    use crate::{module::translate_only_with, Libcalls, TranslateOptions, VmCtx};
+
+    const CODE: &str = r#"
+(module
+  (func (param f64) (result f64)
+    (f64.nearest (get_local 0)))
+  (func (param f32) (result f32)
+    (f32.nearest (get_local 0)))
+  (func (param i64) (result i64)
+    (i64.popcnt (get_local 0))))
+"#;
+
+    extern "sysv64" fn nearest(_vmctx: *const VmCtx, value: f32) -> f32 {
+        value + 0.25
+    }
+
+    #[test]
+    fn nearest_rounds_ties_to_even() {
+        let instance = translate_only_with(&wabt::wat2wasm(CODE).unwrap(), Default::default())
+            .unwrap()
+            .instantiate();
+
+        for &(value, rounded) in &[(0.5, 0.), (1.5, 2.), (2.5, 2.), (-2.5, -2.), (2.6, 3.)] {
+            assert_eq!(instance.execute_func::<_, f64>(0, (value,)), Ok(rounded));
+        }
+        let negative_zero = instance.execute_func::<_, f64>(0, (-0.5f64,)).unwrap();
+        assert!(negative_zero == 0. && negative_zero.is_sign_negative());
+    }
+
+    #[test]
+    fn replace_math_function() {
+        let mut libcalls = Libcalls::new();
+        libcalls.set_f32_nearest(nearest);
+        let options = TranslateOptions {
+            libcalls,
+            ..Default::default()
+        };
+        let instance = translate_only_with(&wabt::wat2wasm(CODE).unwrap(), options)
+            .unwrap()
+            .instantiate();
+
+        assert_eq!(instance.execute_func::<_, f32>(1, (1.5f32,)), Ok(1.75));
+        // Other libcalls keep Lightbeam's own implementation
+        assert_eq!(instance.execute_func::<_, f64>(0, (1.5f64,)), Ok(2.));
+        assert_eq!(instance.execute_func::<_, u64>(2, (0xf0f0u64,)), Ok(8));
+    }
+}
+
+mod conditions {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    const CODE: &str = r#"
+(module
+  (func (param i32 i32) (result i32)
+    (select (i32.const 1) (i32.const 2) (i32.lt_s (get_local 0) (get_local 1))))
+  (func (param i32 i32) (result i32)
+    (if (result i32) (i32.lt_u (get_local 0) (get_local 1))
+      (then (i32.const 1))
+      (else (i32.const 2))))
+  (func (param i32 i32) (result i32)
+    (i32.lt_s (get_local 0) (get_local 1))))
+"#;
+
+    /// How many `setcc`s the function's code has.
+    fn setccs(code: &[u8]) -> usize {
+        code.windows(2)
+            .filter(|window| window[0] == 0x0f && window[1] & 0xf0 == 0x90)
+            .count()
+    }
+
+    #[test]
+    fn flags_are_only_materialized_when_needed() {
+        let instance = translate_wat(CODE);
+        let code = instance.code_section();
+        let setccs = |func| setccs(&code.buffer()[code.func_range(DefinedFuncIndex(func))]);
+
+        // `select` and `if` use the flags directly
+        assert_eq!(setccs(0), 0);
+        assert_eq!(setccs(1), 0);
+        // The result of a function is returned in a register
+        assert_eq!(setccs(2), 1);
+
+        assert_eq!(instance.execute_func::<_, u32>(0, (-1i32, 0i32)), Ok(1));
+        assert_eq!(instance.execute_func::<_, u32>(0, (1i32, 0i32)), Ok(2));
+        assert_eq!(instance.execute_func::<_, u32>(1, (-1i32, 0i32)), Ok(2));
+        assert_eq!(instance.execute_func::<_, u32>(1, (0i32, 1i32)), Ok(1));
+        assert_eq!(instance.execute_func::<_, u32>(2, (-1i32, 0i32)), Ok(1));
+    }
+}
+
+mod block_calling_convention {
+    use super::translate_wat;
+
+    const CODE: &str = r#"
+(module
+  (func (param i32 f64) (result f64)
+    (if (result f64) (get_local 0)
+      (then (f64.mul (get_local 1) (f64.const 2)))
+      (else (f64.add (get_local 1) (f64.const 1)))))
+  (func (param i32 i64) (result i64)
+    (block (result i64)
+      (br_if 0 (i64.const 7) (i32.eqz (get_local 0)))
+      (drop)
+      (if (result i64) (i32.gt_u (get_local 0) (i32.const 10))
+        (then (i64.mul (get_local 1) (i64.const 3)))
+        (else (i64.sub (get_local 1) (i64.const 3)))))))
+"#;
+
+    #[test]
+    fn joins() {
+        let instance = translate_wat(CODE);
+
+        assert_eq!(instance.execute_func::<_, f64>(0, (1u32, 1.5f64)), Ok(3.));
+        assert_eq!(instance.execute_func::<_, f64>(0, (0u32, 1.5f64)), Ok(2.5));
+        assert_eq!(instance.execute_func::<_, i64>(1, (0u32, 5i64)), Ok(7));
+        assert_eq!(instance.execute_func::<_, i64>(1, (11u32, 5i64)), Ok(15));
+        assert_eq!(instance.execute_func::<_, i64>(1, (1u32, 5i64)), Ok(2));
+    }
+}
+
+mod float_returns {
+    use crate::{module::translate_only_with, HostFunctions, TranslateOptions, VmCtx};
+
+    // Each result is live across a second call, which clobbers `XMM0`
+    const CODE: &str = r#"
+(module
+  (import "env" "hypot" (func $hypot (param f64 f64) (result f64)))
+  (type $half (func (param f32) (result f32)))
+  (table anyfunc (elem $half))
+  (func $half (param f32) (result f32)
+    (f32.mul (get_local 0) (f32.const 0.5)))
+  (func (param f32) (result f32)
+    (f32.add
+      (call_indirect (type $half) (get_local 0) (i32.const 0))
+      (call_indirect (type $half) (f32.const 3) (i32.const 0))))
+  (func (param f64 f64) (result f64)
+    (f64.sub
+      (call $hypot (get_local 0) (get_local 1))
+      (call $hypot (f64.const 0) (get_local 1)))))
+"#;
+
+    #[test]
+    fn returned_in_xmm0() {
+        let mut host_functions = HostFunctions::new();
+        host_functions.register("env", "hypot", |_: &VmCtx, a: f64, b: f64| Ok(a.hypot(b)));
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let instance = translate_only_with(&wabt::wat2wasm(CODE).unwrap(), options)
+            .unwrap()
+            .instantiate();
+
+        assert_eq!(instance.execute_func::<_, f32>(0, (5f32,)), Ok(2.5));
+        assert_eq!(instance.execute_func::<_, f32>(1, (5f32,)), Ok(4.));
+        assert_eq!(instance.execute_func::<_, f64>(2, (3f64, 4f64)), Ok(1.));
+    }
+}
+
+mod stack_args {
+    use crate::{module::translate_only_with, HostFunctions, TranslateOptions, VmCtx};
+
+    /// `get_local 0 * 1 + get_local 1 * 2 + ...`, which depends on the order of the
+    /// arguments.
+    fn weigh(params: usize) -> String {
+        (1..params).fold("(get_local 0)".to_string(), |acc, i| {
+            format!(
+                "(i32.add {} (i32.mul (get_local {}) (i32.const {})))",
+                acc,
+                i,
+                i + 1
+            )
+        })
+    }
+
+    /// `x * 1, x * 2, ...`, which are all live until the call.
+    fn args(count: usize) -> String {
+        (1..=count)
+            .map(|i| format!("(i32.mul (get_local 0) (i32.const {}))", i))
+            .collect()
+    }
+
+    fn code() -> String {
+        let params9 = " i32".repeat(9);
+        format!(
+            r#"
+(module
+  (import "env" "weigh8" (func $weigh8 (param{params8}) (result i32)))
+  (type $weigh9 (func (param{params9}) (result i32)))
+  (table anyfunc (elem $weigh9))
+  (func $weigh9 (param{params9}) (result i32)
+    {weigh9})
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (call $weigh9 {args9})))
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (call_indirect (type $weigh9) {args9} (i32.const 0))))
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (call $weigh8 {args8}))))
+"#,
+            params8 = " i32".repeat(8),
+            params9 = params9,
+            weigh9 = weigh(9),
+            args9 = args(9),
+            args8 = args(8),
+        )
+    }
+
+    #[test]
+    fn more_args_than_registers() {
+        let mut host_functions = HostFunctions::new();
+        host_functions.register(
+            "env",
+            "weigh8",
+            |_: &VmCtx, a: i32, b: i32, c: i32, d: i32, e: i32, f: i32, g: i32, h: i32| {
+                Ok([a, b, c, d, e, f, g, h]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &x)| (i as i32 + 1) * x)
+                    .sum::<i32>())
+            },
+        );
+        let options = TranslateOptions {
+            host_functions,
+            ..Default::default()
+        };
+        let instance = translate_only_with(&wabt::wat2wasm(code()).unwrap(), options)
+            .unwrap()
+            .instantiate();
+
+        // `1 * 1 + 2 * 2 + ... + 9 * 9`
+        assert_eq!(
+            instance.execute_func::<_, i32>(0, (1, 2, 3, 4, 5, 6, 7, 8, 9)),
+            Ok(285)
+        );
+        // `x + x * (1 * 1 + 2 * 2 + ...)`
+        assert_eq!(instance.execute_func::<_, i32>(1, (3,)), Ok(3 + 3 * 285));
+        assert_eq!(instance.execute_func::<_, i32>(2, (3,)), Ok(3 + 3 * 285));
+        assert_eq!(instance.execute_func::<_, i32>(3, (3,)), Ok(3 + 3 * 204));
+    }
+}
+
+mod cet {
+    use super::translate_wat_with;
+    use crate::{index_space::DefinedFuncIndex, CodeGenOptions, Instance};
+
+    const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+    // Enough targets that the `br_table` gets a jump table
+    const CODE: &str = r#"
+(module
+  (type $t (func (param i32) (result i32)))
+  (table anyfunc (elem $switch))
+  (func $switch (param i32) (result i32)
+    (block (block (block (block (block (block
+      (br_table 0 1 2 3 4 5 (get_local 0)))
+      (return (i32.const 0)))
+      (return (i32.const 1)))
+      (return (i32.const 2)))
+      (return (i32.const 3)))
+      (return (i32.const 4)))
+    (i32.const 5))
+  (func (param i32) (result i32)
+    (call_indirect (type $t) (get_local 0) (i32.const 0))))
+"#;
+
+    fn translate(cet: bool) -> Instance {
+        translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                cet,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn starts_with_endbr64(instance: &Instance, func: u32) -> bool {
+        let code = instance.code_section();
+        code.buffer()[code.func_range(DefinedFuncIndex(func))].starts_with(&ENDBR64)
+    }
+
+    #[test]
+    fn branch_targets() {
+        let instance = translate(true);
+
+        assert!(starts_with_endbr64(&instance, 0));
+        assert!(starts_with_endbr64(&instance, 1));
+        for i in 0..8u32 {
+            assert_eq!(instance.execute_func::<_, u32>(0, (i,)), Ok(i.min(5)));
+            assert_eq!(instance.execute_func::<_, u32>(1, (i,)), Ok(i.min(5)));
+        }
+    }
+
+    #[test]
+    fn off_by_default() {
+        let instance = translate(false);
+
+        assert!(!starts_with_endbr64(&instance, 0));
+        assert_eq!(instance.execute_func::<_, u32>(0, (3u32,)), Ok(3));
+    }
+}
+
+mod spilling {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    /// `x + 2x + 3x + ...` with `count` terms, which keeps every product live until the
+    /// innermost one has been computed.
+    fn sum_of_multiples(ty: &str, count: usize) -> String {
+        let mut body = "(get_local 0)".to_string();
+        for i in 2..=count {
+            body = format!(
+                "({ty}.add ({ty}.mul (get_local 0) ({ty}.const {})) {})",
+                i,
+                body,
+                ty = ty
+            );
+        }
+        format!("(func (param {ty}) (result {ty}) {})", body, ty = ty)
+    }
+
+    #[test]
+    fn more_live_values_than_registers() {
+        let instance = translate_wat(&format!(
+            "(module {} {})",
+            sum_of_multiples("i64", 20),
+            sum_of_multiples("f64", 40)
+        ));
+
+        assert_eq!(instance.execute_func::<_, u64>(0, (3u64,)), Ok(3 * 210));
+        assert_eq!(
+            instance.execute_func::<_, f64>(1, (0.5f64,)),
+            Ok(0.5 * 820.)
+        );
+
+        let code = instance.code_section();
+        for func in 0..2 {
+            assert!(code.function_stats(DefinedFuncIndex(func)).spills > 0);
+        }
+    }
+
+    #[test]
+    fn spills_go_in_the_reserved_frame() {
+        // More products than registers, which are all live until they're dropped
+        let wat = format!(
+            "(module (func (param i64) (result i64) {} {} (get_local 0)))",
+            (0..20)
+                .map(|i| format!("(i64.mul (get_local 0) (i64.const {}))", i))
+                .collect::<String>(),
+            "(drop)".repeat(20)
+        );
+        let instance = translate_wat(&wat);
+        assert_eq!(instance.execute_func::<_, u64>(0, (3u64,)), Ok(3));
+
+        // The prologue reserves a slot for every value at once, so spilling the products
+        // doesn't make the frame any deeper
+        let code = instance.code_section();
+        let unwind = code.unwind_info(DefinedFuncIndex(0));
+        let grows = unwind
+            .rows
+            .windows(2)
+            .filter(|pair| pair[1].cfa_offset > pair[0].cfa_offset)
+            .count();
+        let spills = code.function_stats(DefinedFuncIndex(0)).spills;
+        assert!(spills > 0);
+        assert!(grows < spills as usize, "{:?}", unwind.rows);
+    }
+
+    // Values carried round a loop in stack slots that the body moves from slot to slot, so
+    // passing them back to the loop header overwrites slots that are still to be read.
+    #[test]
+    fn rotating_spilled_loop_values() {
+        const LOCALS: usize = 24;
+
+        let rotate = (0..LOCALS)
+            .map(|i| format!("(get_local {})", (i + 1) % LOCALS + 2))
+            .chain((0..LOCALS).rev().map(|i| format!("(set_local {})", i + 2)))
+            .collect::<String>();
+        let sum = (0..LOCALS).fold("(i64.const 0)".to_string(), |sum, i| {
+            format!(
+                "(i64.add (i64.mul {} (i64.const 31)) (get_local {}))",
+                sum,
+                i + 2
+            )
+        });
+        let init = (0..LOCALS)
+            .map(|i| {
+                format!(
+                    "(set_local {} (i64.add (get_local 0) (i64.const {})))",
+                    i + 2,
+                    i
+                )
+            })
+            .collect::<String>();
+
+        let instance = translate_wat(&format!(
+            "(module
+                (func (param i64 i32) (result i64)
+                    (local {})
+                    {}
+                    (loop $next
+                        {}
+                        (br_if $next (tee_local 1 (i32.sub (get_local 1) (i32.const 1)))))
+                    {}))",
+            "i64 ".repeat(LOCALS),
+            init,
+            rotate,
+            sum
+        ));
+
+        for &(a, n) in &[(0u64, 1u32), (5, 3), (100, 30)] {
+            let mut locals = (0..LOCALS as u64).map(|i| a + i).collect::<Vec<_>>();
+            for _ in 0..n {
+                locals.rotate_left(1);
+            }
+            let expected = locals
+                .iter()
+                .fold(0u64, |sum, &l| sum.wrapping_mul(31).wrapping_add(l));
+            assert_eq!(instance.execute_func::<_, u64>(0, (a, n)), Ok(expected));
+        }
+    }
+
+    // Floats can't be kept in callee-saved registers, so every float that's live across a
+    // call is spilled, but the locals that aren't read after the call aren't live.
+    #[test]
+    fn only_live_values_are_saved_across_calls() {
+        let instance = translate_wat(
+            "(module
+                (func $id (param f64) (result f64) (get_local 0))
+                (func (param f64 f64 f64) (result f64)
+                    (f64.add
+                        (call $id (f64.add (get_local 0) (get_local 1)))
+                        (get_local 2))))",
+        );
+        assert_eq!(
+            instance.execute_func::<_, f64>(1, (1.5f64, 2.0f64, 4.0f64)),
+            Ok(7.5)
+        );
+
+        // Only the last parameter is spilled
+        let code = instance.code_section();
+        assert_eq!(code.function_stats(DefinedFuncIndex(1)).spills, 1);
+    }
+
+    // A local that's read before a call in a loop is read again when the loop goes round,
+    // so it's live after the call.
+    #[test]
+    fn values_read_earlier_in_a_loop_are_live() {
+        let instance = translate_wat(
+            "(module
+                (func $id (param f64) (result f64) (get_local 0))
+                (func (param f64 i32) (result f64) (local f64)
+                    (loop $next
+                        (set_local 2 (f64.add (get_local 2) (call $id (get_local 0))))
+                        (br_if $next (tee_local 1 (i32.sub (get_local 1) (i32.const 1)))))
+                    (get_local 2)))",
+        );
+        assert_eq!(instance.execute_func::<_, f64>(1, (2.5f64, 4u32)), Ok(10.0));
+    }
+
+    // Dead locals of every type are discarded at the call, and have to merge with the
+    // locals on the other branch of the `if` afterwards.
+    #[test]
+    fn dead_locals_keep_their_types() {
+        let instance = translate_wat(
+            "(module
+                (func $id (param i32) (result i32) (get_local 0))
+                (func (param i32 i64 f32 f64) (result i32)
+                    (set_local 0 (i32.add (get_local 0) (i32.const 1)))
+                    (set_local 1 (i64.add (get_local 1) (i64.const 1)))
+                    (set_local 2 (f32.add (get_local 2) (f32.const 1)))
+                    (set_local 3 (f64.add (get_local 3) (f64.const 1)))
+                    (if (result i32) (call $id (i32.wrap/i64 (get_local 1)))
+                        (then (i32.const 1))
+                        (else (i32.const 2)))))",
+        );
+        assert_eq!(
+            instance.execute_func::<_, u32>(1, (1u32, 2u64, 3.0f32, 4.0f64)),
+            Ok(1)
+        );
+        assert_eq!(
+            instance.execute_func::<_, u32>(1, (1u32, u64::max_value(), 3.0f32, 4.0f64)),
+            Ok(2)
+        );
+    }
+
+    // More live integers and floats than there are registers of either kind, both within an
+    // expression and in locals that are passed round a loop, where every local is popped
+    // into a register of its own until they run out.
+    #[test]
+    fn mixed_int_and_float_pressure() {
+        const LOCALS: usize = 16;
+
+        let mut expr = "(f64.const 0)".to_string();
+        for i in 1..=20 {
+            expr = format!(
+                "(f64.add (f64.add (f64.convert_s/i64 (i64.mul (get_local 0) (i64.const {i}))) \
+                 (f64.mul (get_local 1) (f64.const {i}))) {})",
+                expr,
+                i = i
+            );
+        }
+
+        let ints = 3..3 + LOCALS;
+        let floats = 3 + LOCALS..3 + 2 * LOCALS;
+        let updates = ints
+            .clone()
+            .zip(floats.clone())
+            .map(|(i, f)| {
+                format!(
+                    "(set_local {i} (i64.add (get_local {i}) (get_local 0))) \
+                     (set_local {f} (f64.add (get_local {f}) (get_local 1)))",
+                    i = i,
+                    f = f
+                )
+            })
+            .collect::<String>();
+        let sum = ints
+            .zip(floats)
+            .fold("(f64.const 0)".to_string(), |sum, (i, f)| {
+                format!(
+                    "(f64.add {} (f64.add (f64.convert_s/i64 (get_local {})) (get_local {})))",
+                    sum, i, f
+                )
+            });
+
+        let instance = translate_wat(&format!(
+            "(module
+                (func (param i64 f64) (result f64) {})
+                (func (param i64 f64 i32) (result f64)
+                    (local {} {})
+                    (loop $next
+                        {}
+                        (br_if $next (tee_local 2 (i32.sub (get_local 2) (i32.const 1)))))
+                    {}))",
+            expr,
+            "i64 ".repeat(LOCALS),
+            "f64 ".repeat(LOCALS),
+            updates,
+            sum
+        ));
+
+        assert_eq!(
+            instance.execute_func::<_, f64>(0, (3i64, 0.5f64)),
+            Ok(210. * 3.5)
+        );
+        assert_eq!(
+            instance.execute_func::<_, f64>(1, (3i64, 0.5f64, 4u32)),
+            Ok((LOCALS * 4) as f64 * 3.5)
+        );
+
+        let code = instance.code_section();
+        for func in 0..2 {
+            assert!(code.function_stats(DefinedFuncIndex(func)).spills > 0);
+        }
+    }
+}
+
+mod callee_saved {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    #[test]
+    fn values_live_across_calls_stay_in_registers() {
+        let instance = translate_wat(
+            r#"
+            (module
+              (func $inc (param i64) (result i64)
+                (i64.add (get_local 0) (i64.const 1)))
+              (func (param i64 i64) (result i64) (local i64)
+                (loop $loop
+                  (set_local 2
+                    (i64.add
+                      (i64.mul (get_local 0) (get_local 1))
+                      (call $inc (get_local 2))))
+                  (set_local 0 (i64.sub (get_local 0) (i64.const 1)))
+                  (br_if $loop (get_local 0)))
+                (i64.add (get_local 1) (get_local 2))))
+            "#,
+        );
+
+        // 15 + 1, then 10 + 17, then 5 + 28, and then 5 more
+        assert_eq!(instance.execute_func::<_, u64>(1, (3u64, 5u64)), Ok(38));
+
+        // The locals and the product are kept in callee-saved registers over the call
+        let code = instance.code_section();
+        assert_eq!(code.function_stats(DefinedFuncIndex(1)).spills, 0);
+
+        // ...which are restored by the time the function returns
+        let unwind = code.unwind_info(DefinedFuncIndex(1));
+        assert_eq!(unwind.rows.last().unwrap().saved_regs, 0);
+        assert!(unwind.rows.iter().any(|row| row.saved_regs == 6));
+    }
+
+    #[test]
+    fn leaf_functions_dont_save_registers() {
+        let instance = translate_wat(
+            "(module (func (param i64 i64) (result i64) (i64.add (get_local 0) (get_local 1))))",
+        );
+        assert_eq!(instance.execute_func::<_, u64>(0, (3u64, 5u64)), Ok(8));
+
+        let unwind = instance.code_section().unwind_info(DefinedFuncIndex(0));
+        assert!(unwind.rows.iter().all(|row| row.saved_regs == 0));
+    }
+}
+
+mod return_registers {
+    use super::{iterative_fib_baseline, translate_wat, FIBONACCI};
+    use crate::index_space::DefinedFuncIndex;
+
+    // The result of the first recursive call is still in `rax` when the second call is set
+    // up, which overwrites it, so it has to be moved somewhere that the call preserves.
+    #[test]
+    fn fib_keeps_results_out_of_clobbered_registers() {
+        let translated = translate_wat(FIBONACCI);
+        for x in 0..20 {
+            assert_eq!(
+                translated.execute_func::<_, u32>(0, (x,)),
+                Ok(iterative_fib_baseline(x))
+            );
+        }
+
+        let code = translated.code_section();
+        assert_eq!(code.function_stats(DefinedFuncIndex(0)).spills, 0);
+
+        // ...which is a callee-saved register, rather than the stack
+        let disassembly = code.function_disassembly(DefinedFuncIndex(0)).unwrap();
+        assert!(!disassembly.contains("\tpush\trax"), "{}", disassembly);
+    }
+}
+
+mod local_promotion {
+    use super::translate_wat_with;
+    use crate::{CodeGenOptions, DebugLocation, Tiering};
+
+    #[test]
+    fn most_used_locals_get_registers() {
+        // Only the last parameter, which is passed on the stack, and the accumulator are
+        // used in the loop, and most of the parameters aren't used at all
+        let instance = translate_wat_with(
+            r#"
+            (module
+              (func (param i32 i32 i32 i32 i32 i32 i32) (result i32) (local i32)
+                (loop $loop
+                  (set_local 7 (i32.add (get_local 7) (get_local 6)))
+                  (set_local 6 (i32.sub (get_local 6) (i32.const 1)))
+                  (br_if $loop (get_local 6)))
+                (i32.add (get_local 7) (get_local 1))))
+            "#,
+            CodeGenOptions {
+                tiering: Some(Tiering {
+                    threshold: 1000,
+                    tier_up: None,
+                }),
+                ..Default::default()
+            },
+        );
+
+        // `4 + 3 + 2 + 1 + 10`
+        assert_eq!(
+            instance.execute_func::<_, i32>(0, (0, 10, 0, 0, 0, 0, 4)),
+            Ok(20)
+        );
+
+        let points = instance.code_section().osr_points();
+        assert_eq!(points.len(), 1);
+        let in_reg = |local: usize| match points[0].values[local] {
+            DebugLocation::Gpr(_) => true,
+            _ => false,
+        };
+        assert!(in_reg(6) && in_reg(7), "{:?}", points[0].values);
+        assert!(!in_reg(0) && !in_reg(2), "{:?}", points[0].values);
+    }
+}
+
+mod immediate_operands {
+    use super::translate_wat;
+
+    const CODE: &str = r#"
+(module
+  (func (param i32) (result i32)
+    (i32.sub (i32.const 10) (get_local 0)))
+  (func (param i32) (result i32)
+    (i32.sub (i32.const 0) (get_local 0)))
+  (func (param i64) (result i64)
+    (i64.sub (i64.const -5) (get_local 0)))
+  (func (param i64) (result i64)
+    (i64.sub (i64.const 0x100000000) (get_local 0))))
+"#;
+
+    #[test]
+    fn subtract_from_constant() {
+        let instance = translate_wat(CODE);
+
+        for &x in &[0i32, 1, -1, 100, i32::min_value(), i32::max_value()] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(0, (x,)),
+                Ok(10i32.wrapping_sub(x))
+            );
+            assert_eq!(
+                instance.execute_func::<_, i32>(1, (x,)),
+                Ok(x.wrapping_neg())
+            );
+        }
+        for &x in &[0i64, 1, -1, 100, i64::min_value(), i64::max_value()] {
+            assert_eq!(
+                instance.execute_func::<_, i64>(2, (x,)),
+                Ok((-5i64).wrapping_sub(x))
+            );
+            // Doesn't fit in an `imm32`
+            assert_eq!(
+                instance.execute_func::<_, i64>(3, (x,)),
+                Ok(0x1_0000_0000i64.wrapping_sub(x))
+            );
+        }
+    }
+}
+
+mod memory_operands {
+    use super::translate_wat;
+
+    /// The first five parameters are used as much as the sixth, so they keep the registers
+    /// that they're passed in and the sixth stays on the stack, where the comparison reads it.
+    fn compare(ty: &str, cmp: &str, right: &str) -> String {
+        format!(
+            "(func (param{params}) (result i32)
+               (i32.add
+                 ({wrap}
+                   ({ty}.add
+                     ({ty}.add (get_local 1) (get_local 2))
+                     ({ty}.add (get_local 3) (get_local 4))))
+                 ({ty}.{cmp} (get_local 5) {right})))",
+            params = format!(" {}", ty).repeat(6),
+            wrap = if ty == "i64" {
+                "i32.wrap/i64"
+            } else {
+                "block (result i32)"
+            },
+            ty = ty,
+            cmp = cmp,
+            right = right,
+        )
+    }
+
+    #[test]
+    fn compare_with_stack_values() {
+        let instance = translate_wat(&format!(
+            "(module {} {} {} {})",
+            compare("i32", "lt_s", "(get_local 0)"),
+            compare("i32", "gt_u", "(i32.const 7)"),
+            compare("i64", "lt_s", "(get_local 0)"),
+            compare("i64", "eq", "(i64.const -1)"),
+        ));
+
+        for &(x, y) in &[(3i32, 5i32), (5, 3), (-1, 7), (8, 8)] {
+            let args = |x, y| (x, 0, 0, 0, 0, y);
+            assert_eq!(
+                instance.execute_func::<_, i32>(0, args(x, y)),
+                Ok((y < x) as i32)
+            );
+            assert_eq!(
+                instance.execute_func::<_, i32>(1, args(x, y)),
+                Ok((y as u32 > 7) as i32)
+            );
+            let args = |x, y| (x as i64, 0i64, 0i64, 0i64, 0i64, y as i64);
+            assert_eq!(
+                instance.execute_func::<_, i32>(2, args(x, y)),
+                Ok((y < x) as i32)
+            );
+            assert_eq!(
+                instance.execute_func::<_, i32>(3, args(x, y)),
+                Ok((y == -1) as i32)
+            );
+        }
+    }
+}
+
+mod long_branches {
+    use super::translate_wat;
+    use crate::index_space::DefinedFuncIndex;
+
+    const TERMS: i32 = 60;
+
+    /// `x * 3 * 4 * ...`, which takes far more code than a branch with an 8-bit
+    /// displacement can jump over.
+    fn chain() -> String {
+        (0..TERMS).fold("(get_local 0)".to_string(), |acc, i| {
+            format!("(i32.mul {} (i32.const {}))", acc, i + 3)
+        })
+    }
+
+    fn expected(x: i32) -> i32 {
+        (0..TERMS).fold(x, |acc, i| acc.wrapping_mul(i + 3))
+    }
+
+    #[test]
+    fn branches_over_large_blocks() {
+        let instance = translate_wat(&format!(
+            r#"
+(module
+  (func (param i32) (result i32)
+    (block $out (result i32)
+      (drop (br_if $out (i32.const -1) (get_local 0)))
+      {chain}))
+  (func (param i32) (result i32)
+    (if (result i32) (get_local 0)
+      (then {chain})
+      (else (i32.const -1))))
+  (func (param i32) (result i32) (local i32)
+    (loop $top
+      (set_local 1 (i32.add (get_local 1) {chain}))
+      (set_local 0 (i32.sub (get_local 0) (i32.const 1)))
+      (br_if $top (get_local 0)))
+    (get_local 1)))
+"#,
+            chain = chain()
+        ));
+
+        let code = instance.code_section();
+        for func in 0..3 {
+            assert!(code.function_stats(DefinedFuncIndex(func)).code_size > 2 * 128);
+        }
+
+        assert_eq!(instance.execute_func::<_, i32>(0, (0,)), Ok(0));
+        assert_eq!(instance.execute_func::<_, i32>(0, (5,)), Ok(-1));
+        assert_eq!(instance.execute_func::<_, i32>(1, (0,)), Ok(-1));
+        assert_eq!(instance.execute_func::<_, i32>(1, (5,)), Ok(expected(5)));
+        assert_eq!(
+            instance.execute_func::<_, i32>(2, (3,)),
+            Ok(expected(3)
+                .wrapping_add(expected(2))
+                .wrapping_add(expected(1)))
+        );
+    }
+}
+
+mod constant_operands {
+    use super::translate_wat;
+
+    const OPS: &[&str] = &["mul", "div_s", "div_u", "rem_s", "rem_u"];
+
+    fn code(ty: &str, constants: &[i64]) -> String {
+        let funcs = OPS
+            .iter()
+            .flat_map(|op| {
+                constants.iter().map(move |c| {
+                    format!(
+                        "(func (param {ty}) (result {ty}) ({ty}.{} (get_local 0) ({ty}.const {})))",
+                        op,
+                        c,
+                        ty = ty
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        format!("(module {})", funcs.join("\n"))
+    }
+
+    #[test]
+    fn i32_by_constants() {
+        const CONSTANTS: &[i32] = &[1, 2, 3, 5, 8, 9, 16, 1 << 30, -1 << 31, -4, 6];
+        const VALUES: &[i32] = &[0, 1, -1, 7, -7, 100, -100, -1 << 31, !(-1 << 31)];
+
+        let wide = CONSTANTS.iter().map(|&c| c.into()).collect::<Vec<_>>();
+        let instance = translate_wat(&code("i32", &wide));
+        let ops: [fn(i32, i32) -> i32; 5] = [
+            i32::wrapping_mul,
+            i32::wrapping_div,
+            |a, b| (a as u32 / b as u32) as i32,
+            i32::wrapping_rem,
+            |a, b| (a as u32 % b as u32) as i32,
+        ];
+
+        let cases = ops
+            .iter()
+            .flat_map(|op| CONSTANTS.iter().map(move |&c| (op, c)));
+        for (i, (op, c)) in cases.enumerate() {
+            for &x in VALUES {
+                assert_eq!(
+                    instance.execute_func::<_, i32>(i as u32, (x,)),
+                    Ok(op(x, c)),
+                    "{} by {} with {}",
+                    OPS[i / CONSTANTS.len()],
+                    c,
+                    x
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn i64_by_constants() {
+        const CONSTANTS: &[i64] = &[1, 2, 3, 5, 8, 9, 16, 1 << 31, 1 << 40, -1 << 63, -4, 6];
+        const VALUES: &[i64] = &[0, 1, -1, 7, -7, 100, -100, -1 << 63, !(-1 << 63)];
+
+        let instance = translate_wat(&code("i64", CONSTANTS));
+        let ops: [fn(i64, i64) -> i64; 5] = [
+            i64::wrapping_mul,
+            i64::wrapping_div,
+            |a, b| (a as u64 / b as u64) as i64,
+            i64::wrapping_rem,
+            |a, b| (a as u64 % b as u64) as i64,
+        ];
+
+        let cases = ops
+            .iter()
+            .flat_map(|op| CONSTANTS.iter().map(move |&c| (op, c)));
+        for (i, (op, c)) in cases.enumerate() {
+            for &x in VALUES {
+                assert_eq!(
+                    instance.execute_func::<_, i64>(i as u32, (x,)),
+                    Ok(op(x, c)),
+                    "{} by {} with {}",
+                    OPS[i / CONSTANTS.len()],
+                    c,
+                    x
+                );
+            }
+        }
+    }
+}
+
+mod scaled_index {
+    use super::translate_wat;
+
+    const CODE: &str = r#"
+(module
+  (memory 1 1)
+  (func (param i32 i32) (result i32)
+    (i32.add (get_local 0) (i32.shl (get_local 1) (i32.const 2))))
+  (func (param i32 i32) (result i32)
+    (i32.add (i32.add (get_local 0) (i32.shl (get_local 1) (i32.const 3))) (i32.const -12)))
+  (func (param i64 i64) (result i64)
+    (i64.add (i64.add (get_local 0) (i64.shl (get_local 1) (i64.const 1))) (i64.const 1000)))
+  (func (param i64 i64) (result i64)
+    (i64.add (i64.add (get_local 0) (i64.shl (get_local 1) (i64.const 3))) (i64.const 0x100000000)))
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (i32.shl (get_local 0) (i32.const 1))))
+  (func (param i32 i32) (result i32)
+    (i32.store (i32.const 64) (i32.const 12345))
+    (i32.load offset=4 (i32.add (get_local 0) (i32.shl (get_local 1) (i32.const 2))))))
+"#;
+
+    #[test]
+    fn add_shifted_index() {
+        let instance = translate_wat(CODE);
+
+        for &(base, index) in &[
+            (0i32, 0i32),
+            (100, 3),
+            (-1, -1),
+            (1 << 30, 1 << 29),
+            (7, -9),
+        ] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(0, (base, index)),
+                Ok(base.wrapping_add(index.wrapping_shl(2)))
+            );
+            assert_eq!(
+                instance.execute_func::<_, i32>(1, (base, index)),
+                Ok(base.wrapping_add(index.wrapping_shl(3)).wrapping_sub(12))
+            );
+            assert_eq!(
+                instance.execute_func::<_, i32>(4, (base,)),
+                Ok(base.wrapping_mul(3))
+            );
+
+            let (base, index) = (i64::from(base) << 20, i64::from(index) << 33);
+            assert_eq!(
+                instance.execute_func::<_, i64>(2, (base, index)),
+                Ok(base.wrapping_add(index.wrapping_shl(1)).wrapping_add(1000))
+            );
+            assert_eq!(
+                instance.execute_func::<_, i64>(3, (base, index)),
+                Ok(base
+                    .wrapping_add(index.wrapping_shl(3))
+                    .wrapping_add(0x1_0000_0000))
+            );
+        }
+    }
+
+    #[test]
+    fn load_from_shifted_index() {
+        let instance = translate_wat(CODE);
+
+        assert_eq!(instance.execute_func::<_, i32>(5, (44u32, 4u32)), Ok(12345));
+        assert_eq!(instance.execute_func::<_, i32>(5, (0u32, 15u32)), Ok(12345));
+    }
+}
+
+mod test_against_zero {
+    use super::translate_wat;
+
+    const CODE: &str = r#"
+(module
+  (func $id (param i32) (result i32)
+    (get_local 0))
+  (func (param i32 i32 i32) (result i32)
+    (select (get_local 1) (get_local 2) (i32.eqz (get_local 0))))
+  (func (param i64 i32 i32) (result i32)
+    (select (get_local 1) (get_local 2) (i64.eqz (get_local 0))))
+  (func (param i32) (result i32)
+    (block (br_if 0 (i32.eqz (get_local 0))) (return (i32.const 1)))
+    (i32.const 0))
+  (func (param i64) (result i32)
+    (block (br_if 0 (i64.eqz (get_local 0))) (return (i32.const 1)))
+    (i32.const 0))
+  (func (param i32 i32) (result i32)
+    (local i32)
+    (set_local 2 (i32.add (get_local 0) (get_local 1)))
+    (drop (call $id (get_local 1)))
+    (block (br_if 0 (get_local 2)) (return (i32.const 2)))
+    (select (i32.const 3) (i32.const 4) (get_local 2))))
+"#;
+
+    #[test]
+    fn eqz_feeding_select() {
+        let instance = translate_wat(CODE);
+
+        for &x in &[0i32, 1, -1, 1 << 31] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(1, (x, 10, 20)),
+                Ok(if x == 0 { 10 } else { 20 })
+            );
+        }
+        for &x in &[0i64, 1, -1, 1 << 32, 1 << 63] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(2, (x, 10, 20)),
+                Ok(if x == 0 { 10 } else { 20 })
+            );
+        }
+    }
+
+    #[test]
+    fn eqz_feeding_br_if() {
+        let instance = translate_wat(CODE);
+
+        for &x in &[0i32, 1, -1, 1 << 31] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(3, (x,)),
+                Ok((x != 0) as i32)
+            );
+        }
+        // Only the upper half is set for some of these, which a 32-bit test would miss
+        for &x in &[0i64, 1, -1, 1 << 32, 1 << 63] {
+            assert_eq!(
+                instance.execute_func::<_, i32>(4, (x,)),
+                Ok((x != 0) as i32)
+            );
+        }
+    }
+
+    #[test]
+    fn values_that_arent_comparisons() {
+        let instance = translate_wat(CODE);
+
+        assert_eq!(instance.execute_func::<_, i32>(5, (0, 0)), Ok(2));
+        assert_eq!(instance.execute_func::<_, i32>(5, (5, -5)), Ok(2));
+        assert_eq!(instance.execute_func::<_, i32>(5, (5, 6)), Ok(3));
+    }
+}
+
+mod unloading {
+    use crate::{module::translate_only, CompiledModule, Instance};
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (func (param i32) (result i32)
+    (i32.add (get_local 0) (i32.const 1))))
+"#;
+
+    #[test]
+    fn unload_last_handle() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let instance = Instance::new(module.clone());
+        assert_eq!(instance.execute_func::<_, u32>(0, (1u32,)), Ok(2));
+        assert_eq!(module.active_calls(), 0);
+
+        drop(instance);
+        assert!(CompiledModule::unload(module).is_ok());
+    }
+
+    #[test]
+    fn unload_fails_while_an_instance_is_alive() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let instance = Instance::new(module.clone());
+
+        // The instance still holds the module, which can still be called
+        let module = CompiledModule::unload(module).unwrap_err();
+        assert!(!module.is_unloaded());
+        assert_eq!(instance.execute_func::<_, u32>(0, (1u32,)), Ok(2));
+
+        drop(instance);
+        assert!(CompiledModule::unload(module).is_ok());
+    }
+}
+
+mod threads {
+    use crate::{module::translate_only_with, CompiledModule, Instance, TranslateOptions};
+    use std::{sync::Arc, thread};
+
+    const CODE: &str = r#"
+(module
+  (memory 1 1)
+  (func $fib (param i32) (result i32)
+    (if (result i32) (i32.lt_u (get_local 0) (i32.const 2))
+      (then (get_local 0))
+      (else
+        (i32.add
+          (call $fib (i32.sub (get_local 0) (i32.const 1)))
+          (call $fib (i32.sub (get_local 0) (i32.const 2)))))))
+  (func (param i32) (result i32)
+    (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (get_local 0)))
+    (i32.load (i32.const 0))))
+"#;
+
+    const THREADS: u32 = 8;
+
+    fn translate(lazy: bool) -> Arc<CompiledModule> {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let options = TranslateOptions {
+            lazy,
+            ..Default::default()
+        };
+        Arc::new(translate_only_with(&wasm, options).unwrap())
+    }
+
+    fn call_concurrently(module: Arc<CompiledModule>) {
+        let threads = (0..THREADS)
+            .map(|i| {
+                let module = module.clone();
+                thread::spawn(move || {
+                    let instance = Instance::new(module);
+                    for _ in 0..100 {
+                        assert_eq!(instance.execute_func::<_, u32>(0, (20u32,)), Ok(6765));
+                    }
+                    // Each thread has its own memory
+                    for n in 1..=10 {
+                        assert_eq!(instance.execute_func::<_, u32>(1, (i,)), Ok(i * n));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<CompiledModule>();
+        assert_send::<Instance>();
+
+        call_concurrently(translate(false));
+    }
+
+    #[test]
+    fn translated_lazily_from_several_threads() {
+        let module = translate(true);
+        call_concurrently(module.clone());
+
+        assert!(module.is_translated(0));
+        assert!(module.is_translated(1));
+    }
+}
+
+mod instance_isolation {
+    use crate::{module::translate_only, Instance};
+    use std::sync::Arc;
+
+    const CODE: &str = r#"
+(module
+  (type $unary (func (param i32) (result i32)))
+  (memory 1 2)
+  (data (i32.const 16) "\2a\00\00\00")
+  (global $counter (mut i32) (i32.const 7))
+  (table 2 2 anyfunc)
+  (elem (i32.const 0) $double)
+  (func $double (param i32) (result i32)
+    (i32.mul (get_local 0) (i32.const 2)))
+  (func (param i32) (result i32)
+    (i32.store (i32.const 16) (i32.add (i32.load (i32.const 16)) (get_local 0)))
+    (i32.load (i32.const 16)))
+  (func (param i32) (result i32)
+    (set_global $counter (i32.add (get_global $counter) (get_local 0)))
+    (get_global $counter))
+  (func (result i32)
+    (drop (memory.grow (i32.const 1)))
+    (memory.size))
+  (func (param i32) (result i32)
+    (call_indirect (type $unary) (get_local 0) (i32.const 0))))
+"#;
+
+    #[test]
+    fn instances_dont_share_state() {
+        let module = Arc::new(translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap());
+        let mut first = Instance::new(module.clone());
+        let second = Instance::new(module.clone());
+
+        assert_eq!(first.execute_func::<_, u32>(1, (8u32,)), Ok(50));
+        assert_eq!(first.execute_func::<_, u32>(2, (3u32,)), Ok(10));
+        assert_eq!(first.execute_func::<_, u32>(3, ()), Ok(2));
+        first.table_set(0, None).unwrap();
+
+        // The second instance still has the data segment, the global's initial value, its
+        // original memory size and its own table
+        assert_eq!(second.read_u32(16), Ok(42));
+        assert_eq!(second.execute_func::<_, u32>(1, (1u32,)), Ok(43));
+        assert_eq!(second.execute_func::<_, u32>(2, (1u32,)), Ok(8));
+        assert!(second.read_memory(65536, 4).is_err());
+        assert_eq!(second.table_get(0), Ok(Some(second.func_ref(0).unwrap())));
+        assert_eq!(second.execute_func::<_, u32>(4, (5u32,)), Ok(10));
+
+        // A new instance starts from the module's segments, not from either instance's memory
+        let third = Instance::new(module);
+        assert_eq!(third.read_u32(16), Ok(42));
+        assert_eq!(third.execute_func::<_, u32>(2, (0u32,)), Ok(7));
+        assert_ne!(third.table_get(0), second.table_get(0));
+    }
+}
+
+mod cost_model {
+    use super::translate_wat_with;
+    use crate::{index_space::DefinedFuncIndex, CodeGenOptions, CostModel};
+
+    const CODE: &str = r#"
+(module
+  (memory 1)
+  (func (param i32) (result i32)
+    (i32.add (i32.load (get_local 0)) (i32.load offset=4 (get_local 0))))
+  (func (param i32) (result i32)
+    (i32.add
+      (i32.load (get_local 0))
+      (i32.add (i32.load offset=4 (get_local 0)) (i32.load offset=8 (get_local 0)))))
+  (func (param i32 i32) (result i32)
+    (i32.add (get_local 0) (i32.shl (get_local 1) (i32.const 2))))
+  (func (param i32 i32) (result i32)
+    (i32.add (get_local 0) (i32.mul (get_local 1) (i32.const 5))))
+)
+"#;
+
+    fn costs(cost_model: CostModel) -> Vec<u64> {
+        let instance = translate_wat_with(
+            CODE,
+            CodeGenOptions {
+                cost_model,
+                ..Default::default()
+            },
+        );
+        let code = instance.code_section();
+        (0..4)
+            .map(|i| code.function_stats(DefinedFuncIndex(i)).cost)
+            .collect()
+    }
+
+    #[test]
+    fn weighed_by_the_model() {
+        let only_loads = CostModel {
+            default: 0,
+            load: 1,
+            store: 0,
+            branch: 0,
+            call: 0,
+            call_indirect: 0,
+            div: 0,
+            grow: 0,
+        };
+        assert_eq!(&costs(only_loads)[..2], &[2, 3]);
+
+        let expensive_loads = CostModel {
+            load: 100,
+            ..Default::default()
+        };
+        let (cheap, expensive) = (costs(Default::default()), costs(expensive_loads));
+        assert_eq!(expensive[0] - cheap[0], 2 * 97);
+        assert_eq!(expensive[1] - cheap[1], 3 * 97);
+    }
+
+    #[test]
+    fn fused_operators_count() {
+        // The shift and add are fused into a single `lea` and the multiplication and add
+        // aren't, but they're the same number of operators of the same cost.
+        let costs = costs(Default::default());
+        assert!(costs[2] > 0);
+        assert_eq!(costs[2], costs[3]);
+    }
+}
+
+mod stack_size {
+    use crate::{index_space::DefinedFuncIndex, module::translate_only};
+
+    const CODE: &str = r#"
+(module
+  (func $leaf (result i32) (i32.const 1))
+  (func $many_params
+    (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32)
+    (result i32)
+    (i32.add (get_local 0) (get_local 9)))
+  (func (param i32) (result i32)
+    (call $many_params
+      (get_local 0) (get_local 0) (get_local 0) (get_local 0) (get_local 0)
+      (get_local 0) (get_local 0) (get_local 0) (get_local 0) (get_local 0)))
+)
+"#;
+
+    #[test]
+    fn covers_the_whole_frame() {
+        let module = translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap();
+        let code = module.code_section().unwrap();
+        let max_stack_size = |i| code.function_stats(DefinedFuncIndex(i)).max_stack_size;
+
+        // Even a leaf function's frame has the return address
+        assert!(max_stack_size(0) >= 8);
+        // Five of the arguments are passed on the stack
+        assert!(max_stack_size(2) >= max_stack_size(0) + 5 * 8);
+
+        for i in 0..3 {
+            let deepest = code
+                .unwind_info(DefinedFuncIndex(i))
+                .rows
+                .iter()
+                .map(|row| row.cfa_offset)
+                .max()
+                .unwrap();
+            assert!(max_stack_size(i) >= deepest);
+        }
+
+        let instance = module.instantiate();
+        assert_eq!(instance.execute_func::<_, u32>(2, (3u32,)), Ok(6));
+    }
+}
+
+mod call_graph {
+    use crate::module::translate_only;
+
+    const CODE: &str = r#"
+(module
+  (type $binary (func (param i32 i32) (result i32)))
+  (type $unary (func (param i32) (result i32)))
+  (type $unary_again (func (param i32) (result i32)))
+  (import "env" "log" (func $log (param i32)))
+  (table 2 anyfunc)
+  (elem (i32.const 0) $double $add)
+
+  (func $main (param i32) (result i32)
+    (call $log (get_local 0))
+    (i32.add (call $apply (get_local 0)) (call $apply (i32.const 1))))
+  (func $apply (param i32) (result i32)
+    (call_indirect (type $unary_again) (get_local 0) (i32.const 0)))
+  (func $double (type $unary) (i32.mul (get_local 0) (i32.const 2)))
+  (func $unused (type $unary) (get_local 0))
+  (func $add (type $binary) (i32.add (get_local 0) (get_local 1)))
+)
+"#;
+
+    #[test]
+    fn calls_between_functions() {
+        let module = translate_only(&wabt::wat2wasm(CODE).unwrap()).unwrap();
+        let graph = module.call_graph();
+
+        assert_eq!(graph.callees(0), &[] as &[u32]);
+        assert_eq!(graph.callees(1), &[0, 2]);
+        assert_eq!(graph.callees(2), &[] as &[u32]);
+        assert_eq!(graph.indirect_call_types(2), &[2]);
+
+        // Types that are declared twice are the same signature
+        assert_eq!(graph.indirectly_callable(1), &[3]);
+        assert_eq!(graph.indirectly_callable(2), &[3]);
+        assert_eq!(graph.indirectly_callable(0), &[5]);
+
+        assert_eq!(graph.reachable(vec![1]), vec![0, 1, 2, 3]);
+        assert_eq!(graph.reachable(vec![4]), vec![4]);
+    }
+}
+
+mod dead_functions {
+    use crate::{
+        index_space::DefinedFuncIndex, module::translate_only_with, Instance, TranslateOptions,
+    };
+
+    const CODE: &str = r#"
+(module
+  (type $const (func (result i32)))
+  (table 1 anyfunc)
+  (elem (i32.const 0) $in_table)
+  (start $init)
+
+  (func $init)
+  (func (export "main") (param i32) (result i32)
+    (i32.add (call $helper (get_local 0)) (call_indirect (type $const) (i32.const 0))))
+  (func $helper (param i32) (result i32) (i32.mul (get_local 0) (i32.const 3)))
+  (func $in_table (type $const) (i32.const 4))
+  (func $dead (param i32) (result i32)
+    (i32.add
+      (i32.mul (get_local 0) (get_local 0))
+      (i32.div_u (call $dead_too) (get_local 0))))
+  (func $dead_too (result i32) (i32.const 5))
+)
+"#;
+
+    #[test]
+    fn only_unreachable_functions_are_elided() {
+        let wasm = wabt::wat2wasm(CODE).unwrap();
+        let full = translate_only_with(&wasm, Default::default())
+            .unwrap()
+            .instantiate();
+        let elided = translate_only_with(
+            &wasm,
+            TranslateOptions {
+                elide_dead_functions: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .instantiate();
+        let stats =
+            |instance: &Instance, i| *instance.code_section().function_stats(DefinedFuncIndex(i));
+
+        for i in 0..4 {
+            assert_eq!(stats(&elided, i), stats(&full, i));
+        }
+        for i in 4..6 {
+            assert_eq!(stats(&elided, i).operators, 1);
+        }
+        assert!(stats(&elided, 4).code_size < stats(&full, 4).code_size);
+
+        assert_eq!(elided.execute_func::<_, u32>(1, (5u32,)), Ok(19));
+    }
+}
+
+mod instruction_selection {
+    use crate::backend::{CodeGenSession, Context};
+    use crate::emitter::{Emission, Instruction, LabelRef, Operand, RecordingEmitter};
+    use crate::index_space::{DefinedFuncIndex, FuncIndex};
+    use crate::microwasm::{Value, F32, I32, I64};
+    use crate::SimpleContext;
+    use cranelift_codegen::{binemit, ir};
+
+    const RDX: u8 = 2;
+    const RSI: u8 = 6;
+
+    struct NullSink;
+
+    impl binemit::RelocSink for NullSink {
+        fn reloc_ebb(&mut self, _: binemit::CodeOffset, _: binemit::Reloc, _: binemit::CodeOffset) {
+        }
+
+        fn reloc_external(
+            &mut self,
+            _: binemit::CodeOffset,
+            _: binemit::Reloc,
+            _: &ir::ExternalName,
+            _: binemit::Addend,
+        ) {
+        }
+
+        fn reloc_jt(&mut self, _: binemit::CodeOffset, _: binemit::Reloc, _: ir::JumpTable) {}
+    }
+
+    /// Everything that `emit` emits.
+    fn record(
+        emit: impl FnOnce(&mut Context<SimpleContext, RecordingEmitter>),
+    ) -> RecordingEmitter {
+        let module_context = SimpleContext::default();
+        let mut session = CodeGenSession::new(1, &module_context);
+        let mut reloc_sink = NullSink;
+        let mut rec = RecordingEmitter::new();
+        {
+            let mut ctx = session.new_context_with(DefinedFuncIndex(0), &mut reloc_sink, &mut rec);
+            emit(&mut ctx);
+            ctx.asm.flush();
+        }
+        rec
+    }
+
+    /// The instructions that `emit` emits as `Instruction`s, rather than as bytes.
+    fn instructions(
+        emit: impl FnOnce(&mut Context<SimpleContext, RecordingEmitter>),
+    ) -> Vec<Instruction> {
+        record(emit).instructions()
+    }
+
+    #[test]
+    fn i32_add_of_registers() {
+        let instructions = instructions(|ctx| {
+            ctx.start_function(vec![I32, I32], false, &[], 0);
+            ctx.i32_add();
+        });
+
+        assert_eq!(
+            instructions,
+            vec![Instruction::Add {
+                wide: false,
+                dst: Operand::Reg(RSI),
+                src: Operand::Reg(RDX),
+            }]
+        );
+    }
+
+    #[test]
+    fn i32_add_of_immediate() {
+        let instructions = instructions(|ctx| {
+            ctx.start_function(vec![I32, I32], false, &[], 0);
+            // The local is still live, so the result has to go in a different register
+            ctx.pick(1);
+            ctx.const_(Value::I32(5));
+            ctx.i32_add();
+        });
+
+        match &instructions[..] {
+            [Instruction::Mov {
+                dst: Operand::Reg(temp),
+                src: Operand::Reg(RSI),
+                ..
+            }, Instruction::Add {
+                wide: false,
+                dst: Operand::Reg(dst),
+                src: Operand::Imm(5),
+            }] => {
+                assert_ne!(*temp, RSI);
+                assert_eq!(temp, dst);
+            }
+            other => panic!("unexpected instructions: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn i32_add_of_constants_is_folded() {
+        let instructions = instructions(|ctx| {
+            ctx.start_function(vec![], false, &[], 0);
+            ctx.const_(Value::I32(2));
+            ctx.const_(Value::I32(3));
+            ctx.i32_add();
+        });
+
+        assert!(instructions.is_empty(), "{:?}", instructions);
+    }
+
+    #[test]
+    fn pass_outgoing_immediates() {
+        let instructions = instructions(|ctx| {
+            ctx.start_function(vec![], false, &[], 0);
+            ctx.const_(Value::I32(1));
+            ctx.const_(Value::I32(2));
+            ctx.call_direct(FuncIndex(0), vec![I32, I32], vec![]);
+        });
+
+        // The last argument is popped first
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Mov {
+                    wide: false,
+                    dst: Operand::Reg(RDX),
+                    src: Operand::Imm(2),
+                },
+                Instruction::Mov {
+                    wide: false,
+                    dst: Operand::Reg(RSI),
+                    src: Operand::Imm(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pass_outgoing_swapped_registers() {
+        let instructions = instructions(|ctx| {
+            ctx.start_function(vec![I64, I64], false, &[], 0);
+            ctx.swap(1);
+            ctx.call_direct(FuncIndex(0), vec![I64, I64], vec![]);
+        });
+
+        // Each argument is in the other's register, so one of them has to go through the
+        // stack
+        match &instructions[..] {
+            [Instruction::Mov {
+                dst: Operand::Reg(RSI),
+                src: Operand::Reg(RDX),
+                ..
+            }, Instruction::Mov {
+                wide: true,
+                dst: Operand::Reg(RDX),
+                src: Operand::Stack(_),
+            }] => {}
+            other => panic!("unexpected instructions: {:?}", other),
+        }
+    }
+
+    // Code that jumps to a local label, which the recording keeps so that it can be
+    // assembled later
+    #[test]
+    fn records_local_labels() {
+        let rec = record(|ctx| {
+            ctx.start_function(vec![F32], false, &[], 0);
+            ctx.i32_truncate_f32_s();
+        });
+
+        let forward = rec
+            .emissions
+            .iter()
+            .position(|e| match e {
+                Emission::Reloc(LabelRef::Forward("ret"), _) => true,
+                _ => false,
+            })
+            .unwrap();
+        let defined = rec
+            .emissions
+            .iter()
+            .position(|e| *e == Emission::LocalLabel("ret"))
+            .unwrap();
+        assert!(forward < defined);
+    }
+}
+
+mod traps {
+    use super::translate_wat;
+    use crate::{ExecutionError, TrapCode};
+
+    const CODE: &str = r#"
+(module
+  (memory 1)
+  (table anyfunc (elem $nested))
+  (type $unary (func (param i32) (result i32)))
+  (func (param i32) (result i32)
+    (if (get_local 0) (then (unreachable)))
+    (i32.const 7))
+  (func (param i32 i32) (result i32)
+    (i32.div_s (get_local 0) (get_local 1)))
+  (func (param i32) (result i32)
+    (i32.load (get_local 0)))
+  (func (param i32) (result i32)
+    (call_indirect (type $unary) (get_local 0) (i32.const 0)))
+  (func $nested (param i32) (result i32)
+    (i32.add (call 1 (i32.const 10) (get_local 0)) (i32.const 1))))
+"#;
+
+    // A trap returns an error, and the instance can be called again afterwards.
+    #[test]
+    fn trap_codes() {
+        let instance = translate_wat(CODE);
+
+        assert_eq!(
+            instance.execute_func::<_, i32>(0, (1,)),
+            Err(ExecutionError::Trap(TrapCode::UnreachableCodeReached))
+        );
+        assert_eq!(instance.execute_func::<_, i32>(0, (0,)), Ok(7));
+        assert_eq!(
+            instance.execute_func::<_, i32>(1, (1, 0)),
+            Err(ExecutionError::Trap(TrapCode::IntegerDivisionByZero))
+        );
+        assert_eq!(
+            instance.execute_func::<_, i32>(1, (i32::min_value(), -1)),
+            Err(ExecutionError::Trap(TrapCode::IntegerOverflow))
+        );
+        assert_eq!(instance.execute_func::<_, i32>(1, (9, 3)), Ok(3));
+        assert_eq!(
+            instance.execute_func::<_, i32>(2, (0x10000,)),
+            Err(ExecutionError::Trap(TrapCode::HeapOutOfBounds))
+        );
+        assert_eq!(instance.execute_func::<_, i32>(2, (0,)), Ok(0));
+    }
+
+    // Trapping several calls deep skips every frame back to the host.
+    #[test]
+    fn nested() {
+        let instance = translate_wat(CODE);
+
+        assert_eq!(
+            instance.execute_func::<_, i32>(3, (0,)),
+            Err(ExecutionError::Trap(TrapCode::IntegerDivisionByZero))
+        );
+        assert_eq!(instance.execute_func::<_, i32>(3, (5,)), Ok(3));
+        assert_eq!(instance.execute_func::<_, i32>(4, (2,)), Ok(6));
+    }
+}
+
+#[cfg(feature = "bench")]
+mod benches {
+    extern crate test;
+
+    use super::{translate, wabt, FIBONACCI, FIBONACCI_OPT};
+    use crate::{module::translate_only_with, MemoryStyle, TranslateOptions};
+
+    // See `examples/memory_styles.rs` for a comparison of the memory styles on more kernels.
+    const SUM: &str = r#"
+(module
+  (memory 1 1)
+  (func (param $n i32) (result i32) (local $i i32) (local $acc i32)
+    (block $done
+      (loop $top
+        (br_if $done (i32.ge_u (get_local $i) (get_local $n)))
+        (set_local $acc
+          (i32.add (get_local $acc) (i32.load (i32.shl (get_local $i) (i32.const 2)))))
+        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+        (br $top)))
+    (get_local $acc)))
+"#;
+
+    fn bench_sum(b: &mut test::Bencher, memory_style: MemoryStyle) {
+        let wasm = wabt::wat2wasm(SUM).unwrap();
+        let options = TranslateOptions {
+            memory_style,
+            ..Default::default()
+        };
+        let module = translate_only_with(&wasm, options).unwrap().instantiate();
+
+        b.iter(|| module.execute_func::<_, u32>(0, (16384,)));
+    }
+
+    #[bench]
+    fn bench_memory_sum_bounds_checked(b: &mut test::Bencher) {
+        bench_sum(b, MemoryStyle::BoundsChecked);
+    }
+
+    #[bench]
+    fn bench_memory_sum_guard_pages(b: &mut test::Bencher) {
+        bench_sum(b, MemoryStyle::GuardPages);
+    }
 
     #[bench]
     fn bench_fibonacci_compile(b: &mut test::Bencher) {
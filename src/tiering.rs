@@ -0,0 +1,106 @@
+//! Hooks for runtimes that tier up from Lightbeam's code to that of an optimizing compiler,
+//! in the style of Wasmtime's baseline tier.
+//!
+//! With `CodeGenOptions::tiering`, every function gets a hotness counter, which the code
+//! increments on entry to the function and at the header of each of its loops, so once per
+//! iteration. When a function's counter reaches the threshold, the code calls the tier-up
+//! hook. The runtime can then compile the function with a higher tier and switch calls over
+//! to it, and reset the counter to be told again later.
+//!
+//! A function that's hot because it's stuck in a long-running loop only benefits if the
+//! running frame moves to the new code, with on-stack replacement. For that, every loop
+//! header has an `OsrPoint` recording where each wasm value is and how the native frame is
+//! laid out there, and the hook is called from the loop header with every register saved,
+//! just like a breakpoint hook.
+
+use crate::breakpoints::{BreakpointFrame, DebugLocation};
+use crate::index_space::DefinedFuncIndex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Called when a function's hotness counter reaches the threshold. `func` is in the module's
+/// function index space, which counts imports. `loop_index` is the index of the loop whose
+/// header the counter reached the threshold at, numbered from 0 within the function in the
+/// order that loops start, or `ENTRY_LOOP_INDEX`. `frame` holds the registers there, as for a
+/// breakpoint. Like the trace hooks, this is called on the wasm stack, so it must not
+/// unwind.
+pub type TierUp = unsafe extern "C" fn(func: u32, loop_index: u32, frame: *mut BreakpointFrame);
+
+/// The `loop_index` that `TierUp` is called with when the counter reaches the threshold on
+/// entry to the function rather than in a loop.
+pub const ENTRY_LOOP_INDEX: u32 = u32::max_value();
+
+/// Options for counting how often functions run.
+#[derive(Debug, Copy, Clone)]
+pub struct Tiering {
+    /// The count at which `tier_up` is called. The hook is called once, when the counter
+    /// reaches it, rather than every time after.
+    pub threshold: u32,
+    /// Called when a function gets hot. Without a hook, the counters can still be polled
+    /// with `TranslatedCodeSection::hotness`.
+    pub tier_up: Option<TierUp>,
+}
+
+// Hooks are compared by address, which is all that the generated code depends on.
+impl PartialEq for Tiering {
+    fn eq(&self, other: &Self) -> bool {
+        self.threshold == other.threshold
+            && self.tier_up.map(|f| f as usize) == other.tier_up.map(|f| f as usize)
+    }
+}
+
+impl Eq for Tiering {}
+
+/// The hotness counter of every function in a code section. Generated code refers to the
+/// counters by their address, so they're allocated up front and never move. Counters are
+/// incremented without locking, so a count can be lost when two threads run the same
+/// function at once, which doesn't matter for a heuristic.
+#[derive(Debug, Default)]
+pub struct HotnessCounters {
+    counters: Box<[AtomicU64]>,
+}
+
+impl HotnessCounters {
+    pub(crate) fn new(func_count: u32) -> Self {
+        HotnessCounters {
+            counters: (0..func_count).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn counter(&self, func: DefinedFuncIndex) -> *mut u64 {
+        &self.counters[func.0 as usize] as *const AtomicU64 as *mut u64
+    }
+
+    pub fn get(&self, func: DefinedFuncIndex) -> u64 {
+        self.counters[func.0 as usize].load(Ordering::Relaxed)
+    }
+
+    /// Set the counter of `func` back to 0, so that the tier-up hook is called again if it
+    /// reaches the threshold again.
+    pub fn reset(&self, func: DefinedFuncIndex) {
+        self.counters[func.0 as usize].store(0, Ordering::Relaxed);
+    }
+}
+
+/// A loop header that a running frame can be transferred to other code at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsrPoint {
+    pub func: DefinedFuncIndex,
+    /// The loop's index within its function, as passed to `TierUp`.
+    pub loop_index: u32,
+    /// The offset of the loop header in the code section.
+    pub code_offset: usize,
+    /// Where each wasm value is, from the bottom of the stack, starting with the function's
+    /// locals. Stack locations are relative to the stack pointer at the loop header, which
+    /// is the one in the `BreakpointFrame` passed to `TierUp`.
+    pub values: Vec<DebugLocation>,
+    /// The caller's stack pointer from before it called the function is this many bytes
+    /// above the stack pointer at the loop header. The return address is just below it.
+    pub cfa_offset: u32,
+    /// Whether the caller's `rbp` is saved just below the return address.
+    pub rbp_saved: bool,
+    /// How many of the callee-saved registers (`rbx`, then `r12` to `r15`, then `rbp` if the
+    /// frame pointer is omitted) the function saved below the return address and the
+    /// caller's `rbp`, which have to be restored
+    /// before returning from a frame that replaces this one.
+    pub saved_regs: u8,
+}
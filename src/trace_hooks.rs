@@ -0,0 +1,45 @@
+//! Hooks that generated code calls as wasm functions run, so that embedders can trace calls
+//! or measure coverage at the level of wasm functions and loops without interpreting the
+//! module.
+//!
+//! Functions are identified by their index in the module's function index space, which
+//! counts imports. Hooks are called on the wasm stack, which isn't aligned and may be
+//! nearly exhausted, so they should be short and must not unwind.
+
+/// Called on entry to a function, before any of its body runs. If
+/// `TraceHooks::entry_arguments` is set, `args` points to `num_args` words holding the
+/// function's arguments in order, otherwise it's null and `num_args` is 0. Integers are in
+/// the low bits of their word, and floats are passed as their bit patterns. The arguments
+/// are only valid for the duration of the call.
+pub type FunctionEntry = unsafe extern "C" fn(func: u32, args: *const u64, num_args: u32);
+
+/// Called as a function returns, after its results have been computed. Functions that trap
+/// don't return, so this isn't called for them.
+pub type FunctionExit = unsafe extern "C" fn(func: u32);
+
+/// Called every time the header of a loop is reached, including the first. Loops are
+/// numbered from 0 within each function, in the order that they start.
+pub type LoopIteration = unsafe extern "C" fn(func: u32, loop_index: u32);
+
+/// The hooks to call from instrumented code. Each one is optional, and costs nothing when
+/// it isn't set.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TraceHooks {
+    pub function_entry: Option<FunctionEntry>,
+    /// Pass the arguments of every call to `function_entry`.
+    pub entry_arguments: bool,
+    pub function_exit: Option<FunctionExit>,
+    pub loop_iteration: Option<LoopIteration>,
+}
+
+// Hooks are compared by address, which is all that the generated code depends on.
+impl PartialEq for TraceHooks {
+    fn eq(&self, other: &Self) -> bool {
+        self.function_entry.map(|f| f as usize) == other.function_entry.map(|f| f as usize)
+            && self.entry_arguments == other.entry_arguments
+            && self.function_exit.map(|f| f as usize) == other.function_exit.map(|f| f as usize)
+            && self.loop_iteration.map(|f| f as usize) == other.loop_iteration.map(|f| f as usize)
+    }
+}
+
+impl Eq for TraceHooks {}
@@ -1,28 +1,38 @@
-use crate::backend::{CodeGenSession, TranslatedCodeSection};
+use crate::backend::{CallLinkage, CodeGenOptions, CodeGenSession, TranslatedCodeSection};
+use crate::devirtualize;
 use crate::error::Error;
 use crate::function_body;
-use crate::module::SimpleContext;
+use crate::index_space::{DefinedFuncIndex, IndexVec};
+use crate::inline;
+use crate::metrics::CompilationMetrics;
+use crate::microwasm;
+use crate::module::{
+    BuiltinFunction, DataSegment, DataSegmentKind, ElementSegment, ElementSegmentKind, FuncType,
+    GlobalInit, GlobalType, HostImport, ModuleContext, SegmentOffset, SigType, SimpleContext,
+};
 use cranelift_codegen::{binemit, ir};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use wasmparser::{
-    CodeSectionReader, DataSectionReader, ElementSectionReader, ExportSectionReader, FuncType,
-    FunctionSectionReader, GlobalSectionReader, ImportSectionReader, MemorySectionReader,
-    MemoryType, TableSectionReader, TableType, TypeSectionReader,
+    CodeSectionReader, DataKind, DataSectionReader, ElementKind, ElementSectionReader, Export,
+    ExportSectionReader, FunctionBody, FunctionSectionReader, GlobalSectionReader, Import,
+    ImportSectionReader, InitExpr, MemorySectionReader, MemoryType, Name, NameSectionReader,
+    Naming, Operator, TableSectionReader, TableType, TypeSectionReader,
 };
 
 /// Parses the Type section of the wasm module.
 pub fn type_(types_reader: TypeSectionReader) -> Result<Vec<FuncType>, Error> {
     types_reader
         .into_iter()
-        .map(|r| r.map_err(Into::into))
+        .map(|r| FuncType::from_wasm(&r?))
         .collect()
 }
 
 /// Parses the Import section of the wasm module.
-pub fn import(imports: ImportSectionReader) -> Result<(), Error> {
-    for entry in imports {
-        entry?; // TODO
-    }
-    Ok(())
+pub fn import(imports: ImportSectionReader) -> Result<Vec<Import>, Error> {
+    imports.into_iter().map(|r| r.map_err(Into::into)).collect()
 }
 
 /// Parses the Function section of the wasm module.
@@ -46,20 +56,49 @@ pub fn memory(memories: MemorySectionReader) -> Result<Vec<MemoryType>, Error> {
         .collect()
 }
 
-/// Parses the Global section of the wasm module.
-pub fn global(globals: GlobalSectionReader) -> Result<(), Error> {
-    for entry in globals {
-        entry?; // TODO
+/// Parses the Global section of the wasm module, returning the type and initial value of
+/// each global.
+pub fn global(globals: GlobalSectionReader) -> Result<Vec<(GlobalType, GlobalInit)>, Error> {
+    globals
+        .into_iter()
+        .map(|r| {
+            let global = r?;
+            Ok((
+                GlobalType::from_wasm(global.ty)?,
+                global_init(global.init_expr)?,
+            ))
+        })
+        .collect()
+}
+
+fn global_init(init_expr: InitExpr) -> Result<GlobalInit, Error> {
+    let mut ops = init_expr.get_operators_reader();
+
+    let init = match ops.read()? {
+        Operator::I32Const { value } => GlobalInit::Const(u64::from(value as u32)),
+        Operator::I64Const { value } => GlobalInit::Const(value as u64),
+        Operator::F32Const { value } => GlobalInit::Const(u64::from(value.bits())),
+        Operator::F64Const { value } => GlobalInit::Const(value.bits()),
+        Operator::GetGlobal { global_index } => GlobalInit::Global(global_index),
+        other => {
+            return Err(Error::Input(format!(
+                "Unsupported operator in global initializer: {:?}",
+                other
+            )));
+        }
+    };
+
+    match ops.read()? {
+        Operator::End => Ok(init),
+        _ => Err(Error::Input(
+            "Global initializer must be a single constant".to_string(),
+        )),
     }
-    Ok(())
 }
 
 /// Parses the Export section of the wasm module.
-pub fn export(exports: ExportSectionReader) -> Result<(), Error> {
-    for entry in exports {
-        entry?; // TODO
-    }
-    Ok(())
+pub fn export(exports: ExportSectionReader) -> Result<Vec<Export>, Error> {
+    exports.into_iter().map(|r| r.map_err(Into::into)).collect()
 }
 
 /// Parses the Start section of the wasm module.
@@ -69,12 +108,38 @@ pub fn start(_index: u32) -> Result<(), Error> {
 }
 
 /// Parses the Element section of the wasm module.
-pub fn element(elements: ElementSectionReader) -> Result<(), Error> {
-    for entry in elements {
-        entry?;
-    }
+pub fn element(elements: ElementSectionReader) -> Result<Vec<ElementSegment>, Error> {
+    elements
+        .into_iter()
+        .map(|entry| {
+            let entry = entry?;
 
-    Ok(())
+            let kind = match entry.kind {
+                ElementKind::Passive(_) => ElementSegmentKind::Passive,
+                ElementKind::Active {
+                    table_index,
+                    init_expr,
+                } => ElementSegmentKind::Active {
+                    table_index,
+                    offset: segment_offset(init_expr)?,
+                },
+            };
+
+            Ok(ElementSegment {
+                kind,
+                elements: entry
+                    .items
+                    .get_items_reader()?
+                    .into_iter()
+                    .collect::<Result<_, _>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses the DataCount section of the wasm module.
+pub fn data_count(count: u32) -> Result<u32, Error> {
+    Ok(count)
 }
 
 struct UnimplementedRelocSink;
@@ -99,28 +164,290 @@ impl binemit::RelocSink for UnimplementedRelocSink {
     }
 }
 
-/// Parses the Code section of the wasm module.
+/// Parses the Code section of the wasm module. The functions in `elided` are only given a
+/// body that traps, as if it were `unreachable`, since nothing can call them.
 pub fn code(
     code: CodeSectionReader,
     translation_ctx: &SimpleContext,
+    element_segments: &[ElementSegment],
+    elided: &HashSet<DefinedFuncIndex>,
+    options: CodeGenOptions,
+    metrics: Option<Arc<dyn CompilationMetrics>>,
 ) -> Result<TranslatedCodeSection, Error> {
     let func_count = code.get_count();
     let mut session = CodeGenSession::new(func_count, translation_ctx);
+    session.set_options(options);
+    session.set_call_linkage(CallLinkage::Linked(HashMap::new()));
+    if let Some(metrics) = metrics {
+        session.set_metrics(metrics);
+    }
+
+    let bodies = code.into_iter().collect::<Result<Vec<_>, _>>()?;
+    for body in &bodies {
+        validate_data_indices(body, translation_ctx.data_count())?;
+    }
+
+    if let Some(max_size) = options.max_inlined_size {
+        session.set_inline_bodies(inline::inline_bodies(translation_ctx, &bodies, max_size)?);
+    }
+    if options.devirtualize_calls {
+        if let Some(table) = devirtualize::static_table(element_segments, &bodies)? {
+            session.set_static_table(table);
+        }
+    }
+
+    for (idx, body) in bodies.iter().enumerate() {
+        let mut relocs = UnimplementedRelocSink;
+        let func_idx = DefinedFuncIndex(idx as u32);
+
+        if elided.contains(&func_idx) {
+            let trap: Vec<microwasm::OperatorFromWasm> = vec![microwasm::Operator::Unreachable];
+            function_body::translate(&mut session, &mut relocs, func_idx, trap)?;
+        } else {
+            function_body::translate_wasm(&mut session, &mut relocs, func_idx, body)?;
+        }
+    }
 
-    for (idx, body) in code.into_iter().enumerate() {
+    exit_stubs(&mut session, translation_ctx);
+
+    Ok(session.into_translated_code_section()?)
+}
+
+/// Parses the Code section of the wasm module, emitting a stub for each function that
+/// translates it the first time that it's called rather than translating it now. Returns the
+/// stubs along with the body of each function and where it starts in the module, for
+/// `lazy_function` to translate it from.
+pub fn lazy_code(
+    code: CodeSectionReader,
+    translation_ctx: &SimpleContext,
+    options: CodeGenOptions,
+) -> Result<
+    (
+        TranslatedCodeSection,
+        IndexVec<DefinedFuncIndex, (usize, Vec<u8>)>,
+    ),
+    Error,
+> {
+    assert!(
+        options.debug.is_none(),
+        "Breakpoints are kept with the code section, so functions can't be translated lazily"
+    );
+
+    let mut session = CodeGenSession::new(code.get_count(), translation_ctx);
+    session.set_options(options);
+    let translate_offset = translation_ctx.vmctx_builtin_function(BuiltinFunction::CompileLazily);
+    let mut bodies = IndexVec::new();
+
+    for body in code {
         let body = body?;
+
+        // Invalid functions are still rejected up front, since a stub has no way to report
+        // why it couldn't translate a function
+        validate_data_indices(&body, translation_ctx.data_count())?;
+
+        let mut reader = body.get_binary_reader();
+        let offset = reader.original_position();
+        let func_idx = bodies.push((
+            offset,
+            reader.read_bytes(reader.bytes_remaining())?.to_vec(),
+        ));
+        session.lazy_stub(func_idx, translate_offset as i32);
+    }
+
+    exit_stubs(&mut session, translation_ctx);
+
+    Ok((session.into_translated_code_section()?, bodies))
+}
+
+/// Translates one function of a module whose Code section was parsed by `lazy_code`, into a
+/// code section of its own. The rest of the module's functions are left out of it, and it
+/// calls them through their stubs in `stubs`, the code section that `lazy_code` returned.
+pub fn lazy_function(
+    func_idx: DefinedFuncIndex,
+    body: &FunctionBody,
+    translation_ctx: &SimpleContext,
+    stubs: &TranslatedCodeSection,
+    options: CodeGenOptions,
+    metrics: Option<Arc<dyn CompilationMetrics>>,
+) -> Result<TranslatedCodeSection, Error> {
+    let func_count = translation_ctx.defined_func_count();
+    let mut session = CodeGenSession::new(func_count, translation_ctx);
+    session.set_options(options);
+    session.set_call_linkage(CallLinkage::Linked(
+        (0..func_count)
+            .map(DefinedFuncIndex)
+            .filter(|&other| other != func_idx)
+            .map(|other| (other, stubs.func_start(other) as usize))
+            .collect(),
+    ));
+    if let Some(metrics) = metrics {
+        session.set_metrics(metrics);
+    }
+
+    let mut relocs = UnimplementedRelocSink;
+    function_body::translate_wasm(&mut session, &mut relocs, func_idx, body)?;
+
+    Ok(session.into_translated_code_section()?)
+}
+
+/// Emits a stub for calling each host function that the module imports.
+fn exit_stubs(session: &mut CodeGenSession<SimpleContext>, translation_ctx: &SimpleContext) {
+    for (import, params) in translation_ctx.host_import_params() {
+        session.exit_stub(
+            import,
+            params.iter().map(SigType::to_microwasm_type),
+            HostImport::offset_of_shim(),
+            HostImport::offset_of_trapped(),
+            HostImport::offset_of_trap_sp(),
+        );
+    }
+}
+
+/// Compiles functions given directly as microwasm, with function `i` having the type of
+/// defined function `i` in `translation_ctx`.
+#[cfg(test)]
+pub fn microwasm_code<L>(
+    funcs: Vec<Vec<microwasm::Operator<L>>>,
+    translation_ctx: &SimpleContext,
+) -> Result<TranslatedCodeSection, Error>
+where
+    L: std::hash::Hash + Clone + Eq + Send + Sync + 'static,
+    microwasm::BrTarget<L>: std::fmt::Display,
+{
+    let mut session = CodeGenSession::new(funcs.len() as u32, translation_ctx);
+    session.set_call_linkage(CallLinkage::Linked(HashMap::new()));
+
+    for (idx, body) in funcs.into_iter().enumerate() {
         let mut relocs = UnimplementedRelocSink;
 
-        function_body::translate_wasm(&mut session, &mut relocs, idx as u32, &body)?;
+        function_body::translate(
+            &mut session,
+            &mut relocs,
+            DefinedFuncIndex(idx as u32),
+            body,
+        )?;
     }
 
     Ok(session.into_translated_code_section()?)
 }
 
-/// Parses the Data section of the wasm module.
-pub fn data(data: DataSectionReader) -> Result<(), Error> {
-    for entry in data {
-        entry?; // TODO
+/// Checks that every `memory.init` and `data.drop` in the body refers to an existing data
+/// segment. These operators are only valid if the module has a DataCount section, since
+/// the code section comes before the data section.
+fn validate_data_indices(body: &FunctionBody, data_count: Option<u32>) -> Result<(), Error> {
+    let mut ops = body.get_operators_reader()?;
+
+    while !ops.eof() {
+        let segment = match ops.read()? {
+            Operator::MemoryInit { segment } | Operator::DataDrop { segment } => segment,
+            _ => continue,
+        };
+
+        match data_count {
+            None => {
+                return Err(Error::Input(
+                    "`memory.init` and `data.drop` require a DataCount section".to_string(),
+                ));
+            }
+            Some(count) if segment >= count => {
+                return Err(Error::Input(format!(
+                    "Data segment index {} out of bounds (DataCount is {})",
+                    segment, count
+                )));
+            }
+            Some(_) => {}
+        }
     }
+
     Ok(())
 }
+
+fn segment_offset(init_expr: InitExpr) -> Result<SegmentOffset, Error> {
+    let mut ops = init_expr.get_operators_reader();
+
+    let offset = match ops.read()? {
+        Operator::I32Const { value } => SegmentOffset::Const(value as u32),
+        // Segments of 64-bit memories have 64-bit offsets, but those memories can't be any
+        // bigger than 32-bit ones
+        Operator::I64Const { value } => {
+            if value < 0 || value > i64::from(u32::max_value()) {
+                return Err(Error::Input(format!(
+                    "Segment offset {} is out of bounds",
+                    value as u64
+                )));
+            }
+
+            SegmentOffset::Const(value as u32)
+        }
+        Operator::GetGlobal { global_index } => SegmentOffset::Global(global_index),
+        other => {
+            return Err(Error::Input(format!(
+                "Unsupported operator in segment offset: {:?}",
+                other
+            )));
+        }
+    };
+
+    match ops.read()? {
+        Operator::End => Ok(offset),
+        _ => Err(Error::Input(
+            "Segment offset must be a single constant".to_string(),
+        )),
+    }
+}
+
+/// Parses the Data section of the wasm module. Segments keep their index in the section as
+/// their id, so passive segments can be referred to by `memory.init` and `data.drop`.
+pub fn data(data: DataSectionReader, data_count: Option<u32>) -> Result<Vec<DataSegment>, Error> {
+    let segments = data
+        .into_iter()
+        .map(|entry| {
+            let entry = entry?;
+
+            let kind = match entry.kind {
+                DataKind::Passive => DataSegmentKind::Passive,
+                DataKind::Active {
+                    memory_index,
+                    init_expr,
+                } => DataSegmentKind::Active {
+                    memory_index,
+                    offset: segment_offset(init_expr)?,
+                },
+            };
+
+            Ok(DataSegment {
+                kind,
+                data: entry.data.to_vec(),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    if let Some(count) = data_count {
+        if count as usize != segments.len() {
+            return Err(Error::Input(format!(
+                "DataCount section says {} data segments but data section has {}",
+                count,
+                segments.len()
+            )));
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Parses the function names out of the Name section of the wasm module, by index in the
+/// function index space.
+pub fn names(names: NameSectionReader) -> Result<HashMap<u32, String>, Error> {
+    let mut func_names = HashMap::new();
+    for name in names {
+        if let Name::Function(names) = name? {
+            let mut map = names.get_map()?;
+            for _ in 0..map.get_count() {
+                let Naming { index, name } = map.read()?;
+                func_names.insert(index, name.to_string());
+            }
+        }
+    }
+
+    Ok(func_names)
+}
@@ -0,0 +1,206 @@
+//! Unwind information for generated functions, so that the system unwinder (and so panics,
+//! debuggers and sampling profilers) can walk the stack through wasm frames.
+//!
+//! We don't keep a frame in a fixed shape: the stack pointer moves whenever values are spilled
+//! or calls are set up, so the unwind information is a table of the stack depth at every
+//! point where it changes.
+
+/// The frame layout from `offset` (relative to the start of the function) until the next row.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnwindRow {
+    pub offset: u32,
+    /// The canonical frame address (the value of `rsp` in the caller just before the `call`)
+    /// is `rsp + cfa_offset`. The return address is always just below it.
+    pub cfa_offset: u32,
+    /// Whether the canonical frame address is `rbp + cfa_offset` instead, in stubs that
+    /// realign the stack and so don't know how far `rsp` has moved.
+    pub cfa_rbp: bool,
+    /// Whether the caller's `rbp` is saved just below the return address.
+    pub rbp_saved: bool,
+    /// How many of the callee-saved registers (`rbx`, then `r12` to `r15`, then `rbp` if
+    /// the frame pointer is omitted) are saved, in order, below the return address and the
+    /// caller's `rbp`.
+    pub saved_regs: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FunctionUnwind {
+    /// The size of the function body in bytes. Out-of-line code shared between functions,
+    /// like trap handlers and constants, isn't covered.
+    pub len: u32,
+    /// Sorted by offset, with the first row at offset 0.
+    pub rows: Vec<UnwindRow>,
+}
+
+impl FunctionUnwind {
+    /// Record the frame layout from `offset` onwards. A later row at the same offset replaces
+    /// an earlier one, since the earlier layout never applies to any instruction.
+    pub(crate) fn push(&mut self, row: UnwindRow) {
+        if let Some(last) = self.rows.last_mut() {
+            if last.offset == row.offset {
+                *last = row;
+                return;
+            }
+            if (
+                last.cfa_offset,
+                last.cfa_rbp,
+                last.rbp_saved,
+                last.saved_regs,
+            ) == (row.cfa_offset, row.cfa_rbp, row.rbp_saved, row.saved_regs)
+            {
+                return;
+            }
+        }
+
+        self.rows.push(row);
+    }
+}
+
+// DWARF register numbers on x86-64
+const DW_REG_RBP: u8 = 6;
+const DW_REG_RSP: u8 = 7;
+const DW_REG_RA: u8 = 16;
+/// The callee-saved registers in the order that functions push them.
+const DW_REG_CALLEE_SAVED: [u8; 6] = [3, 12, 13, 14, 15, DW_REG_RBP];
+
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xc0;
+
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Write a length-prefixed CIE or FDE, padded to a multiple of the pointer size.
+fn write_entry(out: &mut Vec<u8>, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    body(out);
+    while (out.len() - start) % 8 != 0 {
+        out.push(DW_CFA_NOP);
+    }
+    let len = (out.len() - start - 4) as u32;
+    out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+/// Build an `.eh_frame` section describing each function, given along with the address that
+/// it starts at. The section is terminated by a zero-length entry, as expected by
+/// `__register_frame`.
+pub fn eh_frame<'a>(funcs: impl IntoIterator<Item = (*const u8, &'a FunctionUnwind)>) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    write_entry(&mut out, |out| {
+        // CIE id and version
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.push(1);
+        out.extend_from_slice(b"zR\0");
+        // Code alignment factor
+        write_uleb128(out, 1);
+        // Data alignment factor
+        write_sleb128(out, -8);
+        write_uleb128(out, u64::from(DW_REG_RA));
+        // Augmentation data: the encoding of addresses in FDEs
+        write_uleb128(out, 1);
+        out.push(DW_EH_PE_ABSPTR);
+        // On entry the CFA is just above the return address
+        out.extend_from_slice(&[DW_CFA_DEF_CFA, DW_REG_RSP, 8]);
+        out.push(DW_CFA_OFFSET | DW_REG_RA);
+        write_uleb128(out, 1);
+    });
+
+    for (start, unwind) in funcs {
+        let fde_start = out.len();
+        write_entry(&mut out, |out| {
+            // Distance back from this field to the CIE
+            out.extend_from_slice(&(fde_start as u32 + 4).to_le_bytes());
+            out.extend_from_slice(&(start as u64).to_le_bytes());
+            out.extend_from_slice(&u64::from(unwind.len).to_le_bytes());
+            // No augmentation data
+            write_uleb128(out, 0);
+
+            let mut offset = 0;
+            let mut cfa_rbp = false;
+            let mut rbp_saved = false;
+            let mut saved_regs = 0;
+            for row in &unwind.rows {
+                let advance = row.offset - offset;
+                if advance > 0 {
+                    if advance <= u32::from(u8::max_value()) {
+                        out.push(DW_CFA_ADVANCE_LOC1);
+                        out.push(advance as u8);
+                    } else if advance <= u32::from(u16::max_value()) {
+                        out.push(DW_CFA_ADVANCE_LOC2);
+                        out.extend_from_slice(&(advance as u16).to_le_bytes());
+                    } else {
+                        out.push(DW_CFA_ADVANCE_LOC4);
+                        out.extend_from_slice(&advance.to_le_bytes());
+                    }
+                }
+                offset = row.offset;
+
+                if row.cfa_rbp != cfa_rbp {
+                    let reg = if row.cfa_rbp { DW_REG_RBP } else { DW_REG_RSP };
+                    out.extend_from_slice(&[DW_CFA_DEF_CFA, reg]);
+                    cfa_rbp = row.cfa_rbp;
+                } else {
+                    out.push(DW_CFA_DEF_CFA_OFFSET);
+                }
+                write_uleb128(out, u64::from(row.cfa_offset));
+
+                if row.rbp_saved != rbp_saved {
+                    if row.rbp_saved {
+                        out.push(DW_CFA_OFFSET | DW_REG_RBP);
+                        write_uleb128(out, 2);
+                    } else {
+                        out.push(DW_CFA_RESTORE | DW_REG_RBP);
+                    }
+                    rbp_saved = row.rbp_saved;
+                }
+
+                // The registers are saved just below the return address and `rbp`
+                let first_slot = 2 + u64::from(row.rbp_saved);
+                for (i, &reg) in DW_REG_CALLEE_SAVED.iter().enumerate() {
+                    let (was_saved, is_saved) = (i < saved_regs, i < row.saved_regs as usize);
+                    if is_saved && !was_saved {
+                        out.push(DW_CFA_OFFSET | reg);
+                        write_uleb128(out, first_slot + i as u64);
+                    } else if was_saved && !is_saved {
+                        out.push(DW_CFA_RESTORE | reg);
+                    }
+                }
+                saved_regs = row.saved_regs as usize;
+            }
+        });
+    }
+
+    out.extend_from_slice(&0u32.to_le_bytes());
+
+    out
+}
@@ -0,0 +1,463 @@
+//! The layout of the context that every wasm function is called with.
+//!
+//! A `VmCtx` starts with fields that are the same for every module, at the offsets given by
+//! the `VmCtx::offset_of_*` functions. It's followed by parts whose size depends on the
+//! module, which `VmCtxLayout` finds:
+//!
+//! - each imported function, as an `ImportedFunc`, indexed by function index;
+//! - the address of each imported global's value, indexed by global index;
+//! - the value of each defined global, in an 8-byte slot, indexed by defined global index;
+//! - the signature id of each of the module's types, indexed by type index.
+//!
+//! `SimpleContext` gives these offsets to the backend, so the layout is only written down
+//! here. Code generated for one layout must not be run with another, so `VERSION` changes
+//! whenever it does.
+
+use crate::linear_memory::{LinearMemory, MemoryGrowth};
+use crate::module::{
+    BoxSlice, BuiltinFunction, CompiledModule, HostError, RuntimeFunc, WASM_PAGE_SIZE,
+};
+use crate::profiling::{OperatorClass, OperatorCounts};
+use std::{
+    any::Any,
+    collections::HashMap,
+    convert::TryInto,
+    mem, ptr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Mutex,
+    },
+};
+
+/// The version of the layout, stored at the start of every `VmCtx`.
+pub const VERSION: u32 = 4;
+
+/// An imported function as seen from wasm code: the code to call and the context to call it
+/// with.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct ImportedFunc {
+    pub(crate) body: *const u8,
+    pub(crate) vmctx: *const u8,
+}
+
+impl ImportedFunc {
+    pub(crate) const NULL: ImportedFunc = ImportedFunc {
+        body: ptr::null(),
+        vmctx: ptr::null(),
+    };
+}
+
+/// Where a linear memory is and how big it is, as read by generated code. The memory is owned
+/// by the instance or, if it's imported, by a `HostMemory`.
+#[repr(C)]
+pub(crate) struct MemoryDefinition {
+    pub(crate) len: usize,
+    pub(crate) ptr: *mut u8,
+}
+
+/// The context passed to every wasm function. See the module documentation for what follows
+/// it in memory.
+#[repr(C)]
+pub struct VmCtx {
+    /// Always `VERSION`.
+    pub(crate) version: u32,
+    pub(crate) table: BoxSlice<RuntimeFunc>,
+    pub(crate) table_maximum: u32,
+    /// Wasm values referring to functions point to one of these, so that references to the
+    /// same function are equal. See `VmCtx::func_ref_value`.
+    pub(crate) func_refs: Mutex<HashMap<RuntimeFunc, Box<RuntimeFunc>>>,
+    /// The address of each builtin, indexed by `BuiltinFunction`, for deterministic code to
+    /// call through. See `CodeGenOptions::deterministic`.
+    pub(crate) builtins: [*const u8; BuiltinFunction::COUNT],
+    /// Unused if the memory is imported.
+    pub(crate) mem: MemoryDefinition,
+    /// Null unless the memory is imported.
+    pub(crate) imported_mem: *const MemoryDefinition,
+    /// The memory that `mem` describes, or `None` if the memory is imported.
+    pub(crate) owned_memory: Option<LinearMemory>,
+    /// The most pages that the memory can grow to.
+    pub(crate) memory_maximum: u32,
+    pub(crate) memory_growth: MemoryGrowth,
+    /// The module that this is an instance of, which the instance keeps alive.
+    pub(crate) module: *const CompiledModule,
+    /// How many operators of each class have run, indexed by `OperatorClass`, if the code
+    /// was generated with `CodeGenOptions::profile_operators`.
+    pub(crate) operator_counts: [u64; OperatorClass::COUNT],
+    /// The lowest address that wasm code may grow the native stack to, or 0 for no limit.
+    pub(crate) stack_limit: usize,
+    /// Why the last trap that returned through an `EntryStub` trapped, as numbered by
+    /// `backend::trap_number`, or 0 if nothing has. Written by generated code.
+    pub(crate) trap_reason: u32,
+    /// Set by the embedder with `Instance::set_host_state`, for host functions to read.
+    pub(crate) host_state: Option<Box<dyn Any + Send + Sync>>,
+    /// The stack pointer that trapping code, or the exit stub of a failing host function,
+    /// returns to, saved by the `EntryStub` that the innermost call into the instance went
+    /// through, or 0 outside of any call.
+    pub(crate) trap_sp: AtomicUsize,
+    /// The error of the last host function to fail, for the call into the instance to
+    /// return.
+    pub(crate) host_error: Mutex<Option<HostError>>,
+}
+
+impl VmCtx {
+    /// The version of the layout that this context was created with.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The state that the embedder attached to the instance with `Instance::set_host_state`,
+    /// if it's a `T`.
+    pub fn host_state<T: Any>(&self) -> Option<&T> {
+        self.host_state.as_ref()?.downcast_ref()
+    }
+
+    /// Append `delta` copies of `init` to the table, returning its previous size, or `None` if
+    /// that would exceed the table's maximum size.
+    pub(crate) fn grow_table(&mut self, delta: u32, init: RuntimeFunc) -> Option<u32> {
+        let old_size = self.table.len() as u32;
+        let new_size = old_size.checked_add(delta)?;
+        if new_size > self.table_maximum {
+            return None;
+        }
+
+        let table = mem::replace(
+            &mut self.table,
+            BoxSlice::from(Vec::new().into_boxed_slice()),
+        );
+        let mut elements = table.into_vec();
+        elements.resize(new_size as usize, init);
+        self.table = elements.into_boxed_slice().into();
+
+        Some(old_size)
+    }
+
+    /// The wasm value of a reference to `func`: a pointer to an equal `RuntimeFunc` that lives
+    /// as long as the instance, or 0 for null.
+    pub(crate) fn func_ref_value(&self, func: RuntimeFunc) -> u64 {
+        if func.is_null() {
+            return 0;
+        }
+
+        // Poisoning can't leave the map inconsistent, and we mustn't panic into wasm code.
+        let mut func_refs = self
+            .func_refs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let value = func_refs.entry(func).or_insert_with(|| Box::new(func));
+        &**value as *const RuntimeFunc as u64
+    }
+
+    /// Grow the memory by `delta` pages, returning its previous size in pages, or `None` if
+    /// that would exceed its maximum, the growth policy denies it or the memory is imported.
+    /// Generated code reads the memory's base and length from its definition on every
+    /// access, so the memory can move while wasm code is on the stack.
+    pub(crate) fn grow_memory(&mut self, delta: u32) -> Option<u32> {
+        let old_pages = (self.memory_definition().len / WASM_PAGE_SIZE) as u32;
+        if delta == 0 {
+            return Some(old_pages);
+        }
+
+        let memory = self.owned_memory.as_mut()?;
+        let new_pages = old_pages.checked_add(delta)?;
+        if new_pages > self.memory_maximum || !self.memory_growth.allows(old_pages, new_pages) {
+            return None;
+        }
+        if !memory.grow(new_pages as usize * WASM_PAGE_SIZE) {
+            return None;
+        }
+
+        self.mem = MemoryDefinition {
+            len: memory.len(),
+            ptr: memory.as_mut_ptr(),
+        };
+        Some(old_pages)
+    }
+
+    fn memory_definition(&self) -> &MemoryDefinition {
+        if self.imported_mem.is_null() {
+            &self.mem
+        } else {
+            unsafe { &*self.imported_mem }
+        }
+    }
+
+    /// The linear memory of the instance.
+    pub fn memory(&self) -> &[u8] {
+        let definition = self.memory_definition();
+        if definition.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(definition.ptr, definition.len) }
+    }
+
+    pub(crate) fn memory_mut(&mut self) -> &mut [u8] {
+        let definition = self.memory_definition();
+        if definition.len == 0 {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(definition.ptr, definition.len) }
+    }
+
+    /// How many operators of each class have run in this instance. These are only counted
+    /// if the code was generated with `CodeGenOptions::profile_operators`.
+    pub fn operator_counts(&self) -> OperatorCounts {
+        // Wasm code running on another thread may be writing to the counters
+        let counters = unsafe { ptr::read_volatile(&self.operator_counts) };
+        OperatorCounts::from_counters(&counters)
+    }
+
+    pub fn offset_of_version() -> u32 {
+        offset_of!(VmCtx, version)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_table() -> u32 {
+        offset_of!(VmCtx, table)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_table_ptr() -> u32 {
+        offset_of!(VmCtx, table.ptr)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_table_len() -> u32 {
+        offset_of!(VmCtx, table.len)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_builtin(builtin: BuiltinFunction) -> u32 {
+        (offset_of!(VmCtx, builtins) + builtin as usize * mem::size_of::<*const u8>())
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_operator_count(class: OperatorClass) -> u32 {
+        (offset_of!(VmCtx, operator_counts) + class as usize * mem::size_of::<u64>())
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_memory() -> u32 {
+        offset_of!(VmCtx, mem)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_memory_ptr() -> u32 {
+        offset_of!(VmCtx, mem.ptr)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_memory_len() -> u32 {
+        offset_of!(VmCtx, mem.len)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_imported_memory() -> u32 {
+        offset_of!(VmCtx, imported_mem)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_stack_limit() -> u32 {
+        offset_of!(VmCtx, stack_limit)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_trap_reason() -> u32 {
+        offset_of!(VmCtx, trap_reason)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_trap_sp() -> u32 {
+        offset_of!(VmCtx, trap_sp)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+}
+
+/// Where the parts of a `VmCtx` that depend on the module are, given how many of each thing
+/// the module has.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VmCtxLayout {
+    pub num_imported_funcs: u32,
+    pub num_imported_globals: u32,
+    pub num_defined_globals: u32,
+    pub num_types: u32,
+}
+
+impl VmCtxLayout {
+    fn offset_of_import(&self, index: u32) -> usize {
+        debug_assert!(index <= self.num_imported_funcs);
+        mem::size_of::<VmCtx>() + index as usize * mem::size_of::<ImportedFunc>()
+    }
+
+    fn offset_of_global_import_usize(&self, index: u32) -> usize {
+        debug_assert!(index <= self.num_imported_globals);
+        self.offset_of_import(self.num_imported_funcs) + index as usize * mem::size_of::<*mut u64>()
+    }
+
+    fn offset_of_global_usize(&self, defined_index: u32) -> usize {
+        debug_assert!(defined_index <= self.num_defined_globals);
+        self.offset_of_global_import_usize(self.num_imported_globals)
+            + defined_index as usize * mem::size_of::<u64>()
+    }
+
+    fn offset_of_sig_id_usize(&self, type_index: u32) -> usize {
+        debug_assert!(type_index <= self.num_types);
+        self.offset_of_global_usize(self.num_defined_globals)
+            + type_index as usize * mem::size_of::<u32>()
+    }
+
+    pub fn offset_of_import_body(&self, index: u32) -> u32 {
+        (self.offset_of_import(index) + offset_of!(ImportedFunc, body))
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_import_vmctx(&self, index: u32) -> u32 {
+        (self.offset_of_import(index) + offset_of!(ImportedFunc, vmctx))
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    /// Where the address of the value of the imported global with the given index is kept.
+    pub fn offset_of_global_import(&self, index: u32) -> u32 {
+        self.offset_of_global_import_usize(index)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    /// Where the value of the global with the given index among the module's defined
+    /// globals is kept.
+    pub fn offset_of_global(&self, defined_index: u32) -> u32 {
+        self.offset_of_global_usize(defined_index)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    pub fn offset_of_sig_id(&self, type_index: u32) -> u32 {
+        self.offset_of_sig_id_usize(type_index)
+            .try_into()
+            .expect("Offset exceeded size of u32")
+    }
+
+    /// The size of the whole `VmCtx`, including everything that follows it.
+    pub fn size(&self) -> usize {
+        self.offset_of_sig_id_usize(self.num_types)
+    }
+}
+
+/// Owns a `VmCtx` allocated together with the imports, globals and signature ids that follow
+/// it.
+pub(crate) struct VmCtxBox {
+    ptr: *mut VmCtx,
+    layout: VmCtxLayout,
+    words: usize,
+}
+
+impl VmCtxBox {
+    /// Imports are left null, to be filled in with `imports_mut` and `global_imports_mut`,
+    /// and globals 0, to be filled in with `globals_mut`.
+    pub(crate) fn new(ctx: VmCtx, layout: VmCtxLayout, sig_ids: &[u32]) -> Self {
+        assert_eq!(sig_ids.len(), layout.num_types as usize);
+
+        // Allocate in words so that the `VmCtx` is properly aligned.
+        let words = (layout.size() + mem::size_of::<u64>() - 1) / mem::size_of::<u64>();
+        let ptr = Box::into_raw(vec![0u64; words].into_boxed_slice()) as *mut u64 as *mut VmCtx;
+
+        unsafe {
+            ptr::write(ptr, ctx);
+            let imports = ptr.add(1) as *mut ImportedFunc;
+            for i in 0..layout.num_imported_funcs as usize {
+                ptr::write(imports.add(i), ImportedFunc::NULL);
+            }
+            ptr::copy_nonoverlapping(
+                sig_ids.as_ptr(),
+                (ptr as *mut u8).add(layout.offset_of_sig_id(0) as usize) as *mut u32,
+                sig_ids.len(),
+            );
+        }
+
+        VmCtxBox { ptr, layout, words }
+    }
+
+    pub(crate) fn imports(&self) -> &[ImportedFunc] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.ptr.add(1) as *const ImportedFunc,
+                self.layout.num_imported_funcs as usize,
+            )
+        }
+    }
+
+    pub(crate) fn imports_mut(&mut self) -> &mut [ImportedFunc] {
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.ptr.add(1) as *mut ImportedFunc,
+                self.layout.num_imported_funcs as usize,
+            )
+        }
+    }
+
+    /// The address of each imported global's value.
+    pub(crate) fn global_imports_mut(&mut self) -> &mut [*const AtomicU64] {
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                (self.ptr as *mut u8).add(self.layout.offset_of_global_import(0) as usize)
+                    as *mut *const AtomicU64,
+                self.layout.num_imported_globals as usize,
+            )
+        }
+    }
+
+    /// The bits of each defined global's value, zero-extended to 64 bits.
+    pub(crate) fn globals_mut(&mut self) -> &mut [u64] {
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                (self.ptr as *mut u8).add(self.layout.offset_of_global(0) as usize) as *mut u64,
+                self.layout.num_defined_globals as usize,
+            )
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr as *const u8
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut VmCtx {
+        unsafe { &mut *self.ptr }
+    }
+
+    pub(crate) fn table(&self) -> &[RuntimeFunc] {
+        unsafe { &(*self.ptr).table }
+    }
+
+    pub(crate) fn table_mut(&mut self) -> &mut [RuntimeFunc] {
+        &mut self.get_mut().table
+    }
+}
+
+unsafe impl Send for VmCtxBox {}
+
+impl Drop for VmCtxBox {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr);
+            Box::from_raw(std::slice::from_raw_parts_mut(
+                self.ptr as *mut u64,
+                self.words,
+            ));
+        }
+    }
+}
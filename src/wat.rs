@@ -0,0 +1,38 @@
+//! Translating modules written in the WebAssembly text format, so that embedders don't need to
+//! assemble them first. This is only built with the `wat` feature.
+
+use crate::error::Error;
+use crate::module::{translate, Instance};
+use std::{fs, path::Path};
+
+/// The magic number that every binary module starts with.
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+fn wat_to_wasm(wat: impl AsRef<[u8]>) -> Result<Vec<u8>, Error> {
+    wabt::wat2wasm(wat).map_err(|e| Error::Input(format!("Invalid text format: {}", e)))
+}
+
+/// Translate a module in the text format and instantiate it, like `translate`.
+pub fn translate_str(wat: &str) -> Result<Instance, Error> {
+    translate(&wat_to_wasm(wat)?)
+}
+
+/// Read the module in the file at `path`, assembling it if it's in the text format rather
+/// than the binary format.
+pub fn read_module(path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+    let data = fs::read(path)
+        .map_err(|e| Error::Input(format!("Couldn't read {}: {}", path.display(), e)))?;
+
+    if data.starts_with(WASM_MAGIC) {
+        Ok(data)
+    } else {
+        wat_to_wasm(data)
+    }
+}
+
+/// Translate the module in the file at `path` and instantiate it, like `translate`. The file
+/// can be in either the binary or the text format.
+pub fn translate_path(path: impl AsRef<Path>) -> Result<Instance, Error> {
+    translate(&read_module(path)?)
+}
@@ -0,0 +1,107 @@
+//! Compares the code generated for the modules in `tests/codegen` with the disassembly
+//! recorded next to each of them, so that changes to the code we generate, like extra spills
+//! or longer sequences for an operator, show up as a diff in review.
+//!
+//! The recorded disassembly changes with every improvement to code generation, so these
+//! don't run with the normal test suite. Run them with
+//! `cargo test --test codegen -- --ignored`, and set `LIGHTBEAM_BLESS=1` to record the code
+//! that's generated now in place of what was recorded. A module without a recording gets one
+//! the first time that it's run. Modules are translated with `CodeGenOptions::deterministic`,
+//! so that the code doesn't depend on where anything is in the process.
+
+extern crate lightbeam;
+extern crate wabt;
+
+use lightbeam::{translate_only_with, CodeGenOptions, TranslateOptions};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The disassembly of the module in the text format at `path`.
+fn disassembly(path: &Path) -> String {
+    let wat = fs::read_to_string(path).unwrap();
+    let wasm = wabt::wat2wasm(wat).unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+    let options = TranslateOptions {
+        codegen: CodeGenOptions {
+            deterministic: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let module =
+        translate_only_with(&wasm, options).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    module
+        .code_section()
+        .map(|code| code.disassembly().unwrap())
+        .unwrap_or_default()
+}
+
+/// Describe how `actual` differs from `expected`, line by line. Lines are compared in
+/// place, so an inserted instruction shows up as every line after it changing, but that's
+/// enough to see where the first change is.
+fn describe_diff(expected: &str, actual: &str) -> String {
+    let (expected, actual) = (
+        expected.lines().collect::<Vec<_>>(),
+        actual.lines().collect::<Vec<_>>(),
+    );
+    let first = expected
+        .iter()
+        .zip(&actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+
+    let mut out = format!(
+        "{} lines were recorded and {} were generated, differing from line {}:\n",
+        expected.len(),
+        actual.len(),
+        first + 1
+    );
+    for line in expected.iter().skip(first).take(10) {
+        out += &format!("-{}\n", line);
+    }
+    for line in actual.iter().skip(first).take(10) {
+        out += &format!("+{}\n", line);
+    }
+    out
+}
+
+#[test]
+#[ignore]
+fn golden_disassembly() {
+    let bless = env::var_os("LIGHTBEAM_BLESS").is_some();
+    let mut failures = vec![];
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/codegen");
+    let mut inputs = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "wat"))
+        .collect::<Vec<_>>();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "No modules in {}", dir.display());
+
+    for input in inputs {
+        let golden = input.with_extension("disasm");
+        let actual = disassembly(&input);
+
+        match fs::read_to_string(&golden) {
+            Ok(ref expected) if *expected == actual => {}
+            Ok(ref expected) if !bless => failures.push(format!(
+                "{} changed:\n{}",
+                golden.display(),
+                describe_diff(expected, &actual)
+            )),
+            _ => {
+                println!("Recording {}", golden.display());
+                fs::write(&golden, actual).unwrap();
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{}\nRun with LIGHTBEAM_BLESS=1 to record the new code if it's what you expect.",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,17 @@
+(module
+  (type $unary (func (param i32) (result i32)))
+  (import "env" "log" (func $log (param i32)))
+  (table 2 anyfunc)
+  (elem (i32.const 0) $double $square)
+  (func $double (type $unary) (i32.shl (get_local 0) (i32.const 1)))
+  (func $square (type $unary) (i32.mul (get_local 0) (get_local 0)))
+  (func (export "apply") (param $f i32) (param $x i32) (result i32)
+    (call $log (get_local $x))
+    (call_indirect (type $unary) (get_local $x) (get_local $f)))
+  (func (export "dispatch") (param i32) (result i32)
+    (block $c (block $b (block $a
+      (br_table $a $b $c (get_local 0)))
+      (return (i32.const 10)))
+      (return (i32.const 20)))
+    (i32.const 30))
+)
@@ -0,0 +1,9 @@
+(module
+  (func $fib (export "fib") (param i32) (result i32)
+    (if (result i32) (i32.lt_u (get_local 0) (i32.const 2))
+      (then (get_local 0))
+      (else
+        (i32.add
+          (call $fib (i32.sub (get_local 0) (i32.const 1)))
+          (call $fib (i32.sub (get_local 0) (i32.const 2)))))))
+)
@@ -0,0 +1,11 @@
+(module
+  (func (export "hypot") (param f64 f64) (result f64)
+    (f64.sqrt
+      (f64.add
+        (f64.mul (get_local 0) (get_local 0))
+        (f64.mul (get_local 1) (get_local 1)))))
+  (func (export "to_int") (param f32) (result i32)
+    (i32.trunc_s/f32 (f32.nearest (get_local 0))))
+  (func (export "clamp") (param f32 f32 f32) (result f32)
+    (f32.min (f32.max (get_local 0) (get_local 1)) (get_local 2)))
+)
@@ -0,0 +1,24 @@
+(module
+  (memory 1)
+  ;; Sum of the `n` i32s starting at `base`
+  (func (export "sum") (param $base i32) (param $n i32) (result i32)
+    (local $total i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (get_local $n)))
+        (set_local $total
+          (i32.add (get_local $total) (i32.load (get_local $base))))
+        (set_local $base (i32.add (get_local $base) (i32.const 4)))
+        (set_local $n (i32.sub (get_local $n) (i32.const 1)))
+        (br $next)))
+    (get_local $total))
+  (func (export "copy") (param $dst i32) (param $src i32) (param $len i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (get_local $len)))
+        (i64.store (get_local $dst) (i64.load (get_local $src)))
+        (set_local $dst (i32.add (get_local $dst) (i32.const 8)))
+        (set_local $src (i32.add (get_local $src) (i32.const 8)))
+        (set_local $len (i32.sub (get_local $len) (i32.const 1)))
+        (br $next))))
+)
@@ -0,0 +1,64 @@
+//! Translation logs through the `log` crate in debug builds. This is a separate test binary
+//! because `quickcheck` installs its own logger in the process running the unit tests.
+
+#![cfg(debug_assertions)]
+
+extern crate lightbeam;
+extern crate log;
+extern crate wabt;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+
+struct Capture {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Capture = Capture {
+    records: Mutex::new(Vec::new()),
+};
+
+const ADD: &str = r#"
+(module
+  (func (param i32 i32) (result i32) (i32.add (get_local 0) (get_local 1))))
+"#;
+
+#[test]
+fn logs_translation() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let wasm = wabt::wat2wasm(ADD).unwrap();
+    lightbeam::translate(&wasm).unwrap();
+
+    let records = LOGGER.records.lock().unwrap();
+    let logged = |level: Level, prefix: &str| {
+        records
+            .iter()
+            .any(|(l, message)| *l == level && message.starts_with(prefix))
+    };
+
+    assert!(logged(Level::Debug, "Translating function 0"));
+    assert!(logged(Level::Trace, "Microwasm:\n.fn_0:"));
+    assert!(logged(Level::Trace, "Wasm @0x"));
+    assert!(records
+        .iter()
+        .any(|(_, message)| message.contains("i32.add") && message.contains("registers {")));
+    assert!(logged(Level::Debug, "Translated function 0 in"));
+    assert!(logged(Level::Debug, "Generated "));
+}
@@ -0,0 +1,528 @@
+//! Runs `.wast` scripts, in the format of the official WebAssembly spec tests, through
+//! Lightbeam.
+//!
+//! The scripts in `tests/spec` are run as part of the normal test suite and must all pass.
+//! To measure how much of the official testsuite passes, point `LIGHTBEAM_SPEC_TESTSUITE`
+//! at a checkout of https://github.com/WebAssembly/testsuite and run
+//! `cargo test --test spec -- --ignored --nocapture`.
+//!
+//! `assert_trap` passes for any trap, since Lightbeam's trap codes don't map one to one onto
+//! the spec's messages. Nothing stops wasm code from overflowing the native stack, so
+//! `assert_exhaustion` is skipped, as is anything that needs linking between modules. Every
+//! skipped command is counted by what it was, and the bundled scripts mustn't skip any.
+//!
+//! A failing command doesn't stop the script: every command after it still runs, so that one
+//! bug doesn't hide the rest of a script's results.
+
+extern crate lightbeam;
+extern crate wabt;
+
+use lightbeam::ValueType;
+use lightbeam::{translate_only, ExecutionError, Instance, InstanceImports};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use wabt::script::{Action, CommandKind, ScriptParser, Value};
+
+/// The most integer and float arguments that fit in registers. Integer arguments after those
+/// are passed on the stack, up to `MAX_STACK_ARGS` of them, and Lightbeam can't pass float
+/// arguments on the stack at all.
+const MAX_INT_ARGS: usize = 5;
+const MAX_FLOAT_ARGS: usize = 8;
+const MAX_STACK_ARGS: usize = 6;
+
+#[derive(Debug, Default)]
+struct Report {
+    passed: usize,
+    /// How many commands were skipped for each reason.
+    skipped: BTreeMap<&'static str, usize>,
+    failures: Vec<String>,
+}
+
+impl Report {
+    fn add(&mut self, other: Report) {
+        self.passed += other.passed;
+        for (reason, count) in other.skipped {
+            *self.skipped.entry(reason).or_insert(0) += count;
+        }
+        self.failures.extend(other.failures);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.passed,
+            self.failures.len(),
+            self.skipped.values().sum::<usize>()
+        )?;
+        for (i, (reason, count)) in self.skipped.iter().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{}{}: {}", separator, reason, count)?;
+        }
+        if !self.skipped.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a single command.
+enum Outcome {
+    Pass,
+    Fail(String),
+    /// The command wasn't run, for the given reason.
+    Skip(&'static str),
+}
+
+/// The raw result of calling a function: `rax` for integers and `xmm0` for floats, or the
+/// error that the call returned.
+#[derive(Debug, Clone)]
+enum RawResult {
+    None,
+    Int(u64),
+    Float(u64),
+    Trap(ExecutionError),
+}
+
+/// Call the function exported as `field` with `args`, passing the first arguments of each
+/// kind in registers and the rest of the integers on the stack, so that one signature works
+/// for any function within the limits on arguments.
+fn invoke(instance: &Instance, field: &str, args: &[Value]) -> Result<RawResult, Outcome> {
+    let (func, ty) = instance
+        .module()
+        .exported_func(field)
+        .ok_or_else(|| Outcome::Fail(format!("no exported function {:?}", field)))?;
+    check_args(&ty.params, args)?;
+
+    let mut ints = Vec::new();
+    let mut floats = Vec::new();
+    for arg in args {
+        match *arg {
+            Value::I32(i) => ints.push(u64::from(i as u32)),
+            Value::I64(i) => ints.push(i as u64),
+            Value::F32(f) => floats.push(f64::from_bits(u64::from(f.to_bits()))),
+            Value::F64(f) => floats.push(f),
+        }
+    }
+    if ints.len() > MAX_INT_ARGS + MAX_STACK_ARGS {
+        return Err(Outcome::Skip("too many integer arguments"));
+    }
+    if floats.len() > MAX_FLOAT_ARGS {
+        return Err(Outcome::Skip("too many float arguments"));
+    }
+    ints.resize(MAX_INT_ARGS + MAX_STACK_ARGS, 0);
+    floats.resize(MAX_FLOAT_ARGS, 0.);
+
+    let (i, f) = (&ints[..], &floats[..]);
+    let args = (
+        i[0], i[1], i[2], i[3], i[4], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7], i[5], i[6],
+        i[7], i[8], i[9], i[10],
+    );
+    let result = match ty.returns[..] {
+        [] => unsafe { instance.try_execute_func_unchecked::<_, ()>(func, args) }
+            .map(|()| RawResult::None),
+        [ValueType::I32] | [ValueType::I64] => {
+            unsafe { instance.try_execute_func_unchecked::<_, u64>(func, args) }.map(RawResult::Int)
+        }
+        [ValueType::F32] | [ValueType::F64] => {
+            unsafe { instance.try_execute_func_unchecked::<_, f64>(func, args) }
+                .map(|f| RawResult::Float(f.to_bits()))
+        }
+        _ => return Err(Outcome::Skip("multiple results")),
+    };
+
+    Ok(result.unwrap_or_else(RawResult::Trap))
+}
+
+fn check_args(params: &[ValueType], args: &[Value]) -> Result<(), Outcome> {
+    let matches = params.len() == args.len()
+        && params
+            .iter()
+            .zip(args)
+            .all(|(param, arg)| match (param, arg) {
+                (ValueType::I32, Value::I32(_))
+                | (ValueType::I64, Value::I64(_))
+                | (ValueType::F32, Value::F32(_))
+                | (ValueType::F64, Value::F64(_)) => true,
+                _ => false,
+            });
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Outcome::Fail(format!(
+            "arguments {:?} don't match parameters {:?}",
+            args, params
+        )))
+    }
+}
+
+/// Whether `result` is `expected`, comparing floats bit for bit.
+fn result_matches(result: &RawResult, expected: &[Value]) -> bool {
+    match (result, expected) {
+        (RawResult::None, []) => true,
+        (RawResult::Int(bits), [Value::I32(i)]) => bits as u32 == *i as u32,
+        (RawResult::Int(bits), [Value::I64(i)]) => bits == *i as u64,
+        (RawResult::Float(bits), [Value::F32(f)]) => bits as u32 == f.to_bits(),
+        (RawResult::Float(bits), [Value::F64(f)]) => bits == f.to_bits(),
+        _ => false,
+    }
+}
+
+/// Whether `result` is a NaN of either width. Lightbeam doesn't tell us the type here, but
+/// the script only asks about results that are floats.
+fn is_nan(result: &RawResult, canonical: bool) -> bool {
+    const F32_QUIET: u32 = 1 << 22;
+    const F64_QUIET: u64 = 1 << 51;
+
+    match result {
+        &RawResult::Float(bits) => {
+            let f32_bits = bits as u32;
+            let f32_nan = f32::from_bits(f32_bits).is_nan()
+                && (!canonical || f32_bits & 0x7fff_ffff == 0x7f80_0000 | F32_QUIET)
+                && f32_bits & F32_QUIET != 0;
+            let f64_nan = f64::from_bits(bits).is_nan()
+                && (!canonical || bits & !(1 << 63) == 0x7ff0_0000_0000_0000 | F64_QUIET)
+                && bits & F64_QUIET != 0;
+            f32_nan || f64_nan
+        }
+        _ => false,
+    }
+}
+
+/// Check that Lightbeam can translate `module` but not instantiate it, since it traps or
+/// can't be linked.
+fn fails_to_instantiate(module: &[u8], message: &str) -> Result<(), Outcome> {
+    let module = translate_only(module)
+        .map_err(|e| Outcome::Fail(format!("failed to translate module: {}", e)))?;
+    let module = Arc::new(module);
+    let instantiate = || Instance::link(module, &InstanceImports::default()).map(drop);
+    match panic::catch_unwind(AssertUnwindSafe(instantiate)) {
+        Ok(Ok(())) => Err(Outcome::Fail(format!(
+            "instantiated a module that should fail with {:?}",
+            message
+        ))),
+        Ok(Err(_)) => Ok(()),
+        Err(_) => Err(Outcome::Fail(format!(
+            "panicked on a module that should fail with {:?}",
+            message
+        ))),
+    }
+}
+
+/// Check that Lightbeam rejects `module` with an error. A panic is a failure too, since
+/// embedders can't recover from it.
+fn rejects(module: &[u8], message: &str) -> Result<(), Outcome> {
+    // Modules written with `(module quote ...)` are passed on as text. If they don't even
+    // parse then they're malformed before Lightbeam sees them.
+    let wasm = if module.starts_with(b"\0asm") {
+        module.to_vec()
+    } else {
+        match wabt::wat2wasm(module) {
+            Ok(wasm) => wasm,
+            Err(_) => return Ok(()),
+        }
+    };
+
+    match panic::catch_unwind(|| translate_only(&wasm).map(drop)) {
+        Ok(Ok(())) => Err(Outcome::Fail(format!(
+            "translated a module that should fail with {:?}",
+            message
+        ))),
+        Ok(Err(_)) => Ok(()),
+        Err(_) => Err(Outcome::Fail(format!(
+            "panicked on a module that should fail with {:?}",
+            message
+        ))),
+    }
+}
+
+struct Runner {
+    /// The last module under `None`, and every named module under its name.
+    instances: HashMap<Option<String>, Rc<Instance>>,
+}
+
+impl Runner {
+    fn new() -> Self {
+        Runner {
+            instances: HashMap::new(),
+        }
+    }
+
+    fn instance(&self, module: &Option<String>) -> Result<&Instance, Outcome> {
+        self.instances
+            .get(module)
+            .map(|instance| &**instance)
+            .ok_or_else(|| Outcome::Fail(format!("no module {:?} to run", module)))
+    }
+
+    fn perform(&self, action: &Action) -> Result<RawResult, Outcome> {
+        match action {
+            Action::Invoke {
+                module,
+                field,
+                args,
+            } => invoke(self.instance(module)?, field, args),
+            Action::Get { .. } => Err(Outcome::Skip("get")),
+        }
+    }
+
+    fn run_command(&mut self, kind: CommandKind) -> Outcome {
+        let result = match kind {
+            CommandKind::Module { module, name } => {
+                // Whether or not this works, later commands shouldn't run an earlier module
+                self.instances.remove(&None);
+                match lightbeam::translate(&module.into_vec()) {
+                    Ok(instance) => {
+                        let instance = Rc::new(instance);
+                        if name.is_some() {
+                            self.instances.insert(name, instance.clone());
+                        }
+                        self.instances.insert(None, instance);
+                        Ok(())
+                    }
+                    Err(e) => Err(Outcome::Fail(format!("failed to translate module: {}", e))),
+                }
+            }
+            CommandKind::AssertReturn { action, expected } => {
+                self.perform(&action).and_then(|result| {
+                    if result_matches(&result, &expected) {
+                        Ok(())
+                    } else {
+                        Err(Outcome::Fail(format!(
+                            "{:?} returned {:?}, expected {:?}",
+                            action, result, expected
+                        )))
+                    }
+                })
+            }
+            CommandKind::AssertReturnCanonicalNan { action } => {
+                self.perform(&action).and_then(|result| {
+                    if is_nan(&result, true) {
+                        Ok(())
+                    } else {
+                        Err(Outcome::Fail(format!(
+                            "{:?} returned {:?}, expected a canonical NaN",
+                            action, result
+                        )))
+                    }
+                })
+            }
+            CommandKind::AssertReturnArithmeticNan { action } => {
+                self.perform(&action).and_then(|result| {
+                    if is_nan(&result, false) {
+                        Ok(())
+                    } else {
+                        Err(Outcome::Fail(format!(
+                            "{:?} returned {:?}, expected an arithmetic NaN",
+                            action, result
+                        )))
+                    }
+                })
+            }
+            CommandKind::AssertInvalid { module, message }
+            | CommandKind::AssertMalformed { module, message } => {
+                rejects(&module.into_vec(), &message)
+            }
+            CommandKind::AssertTrap { action, message } => {
+                self.perform(&action).and_then(|result| match result {
+                    RawResult::Trap(ExecutionError::Trap(_)) => Ok(()),
+                    result => Err(Outcome::Fail(format!(
+                        "{:?} returned {:?}, expected a trap with {:?}",
+                        action, result, message
+                    ))),
+                })
+            }
+            CommandKind::AssertUninstantiable { module, message }
+            | CommandKind::AssertUnlinkable { module, message } => {
+                fails_to_instantiate(&module.into_vec(), &message)
+            }
+            CommandKind::PerformAction(action) => {
+                self.perform(&action).and_then(|result| match result {
+                    RawResult::Trap(e) => {
+                        Err(Outcome::Fail(format!("{:?} failed with {:?}", action, e)))
+                    }
+                    _ => Ok(()),
+                })
+            }
+            CommandKind::AssertExhaustion { .. } => Err(Outcome::Skip("assert_exhaustion")),
+            CommandKind::Register { .. } => Err(Outcome::Skip("register")),
+        };
+
+        match result {
+            Ok(()) => Outcome::Pass,
+            Err(outcome) => outcome,
+        }
+    }
+}
+
+/// Run every command in the script at `path`, carrying on after failures.
+fn run_script(path: &Path) -> Report {
+    let source = fs::read(path).unwrap();
+    run_source(&source, &path.display().to_string())
+}
+
+/// Run every command in `source`, a script called `name`, carrying on after failures.
+fn run_source(source: &[u8], name: &str) -> Report {
+    let mut report = Report::default();
+
+    let file_name = Path::new(name).file_name().unwrap().to_string_lossy();
+    let mut parser = match ScriptParser::<f32, f64>::from_source_and_name(source, &file_name) {
+        Ok(parser) => parser,
+        Err(e) => {
+            report
+                .failures
+                .push(format!("{}: failed to parse: {:?}", name, e));
+            return report;
+        }
+    };
+
+    let mut runner = Runner::new();
+    loop {
+        let command = match parser.next() {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            // The parser has already moved past the command that it failed on, so the
+            // rest of the script can still run
+            Err(e) => {
+                report
+                    .failures
+                    .push(format!("{}: failed to parse a command: {:?}", name, e));
+                continue;
+            }
+        };
+
+        let line = command.line;
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| runner.run_command(command.kind)))
+            .unwrap_or_else(|_| Outcome::Fail("panicked".to_string()));
+        match outcome {
+            Outcome::Pass => report.passed += 1,
+            Outcome::Skip(reason) => *report.skipped.entry(reason).or_insert(0) += 1,
+            Outcome::Fail(message) => report
+                .failures
+                .push(format!("{}:{}: {}", name, line, message)),
+        }
+    }
+
+    report
+}
+
+/// Run every script in `dir`, in order, printing a summary of each.
+fn run_dir(dir: &Path) -> Report {
+    let mut paths = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map(|ext| ext == "wast").unwrap_or(false))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut total = Report::default();
+    for path in paths {
+        let report = run_script(&path);
+        println!("{}: {}", path.display(), report);
+        for failure in &report.failures {
+            println!("    {}", failure);
+        }
+        total.add(report);
+    }
+
+    println!("total: {}", total);
+    total
+}
+
+#[test]
+fn bundled() {
+    let report = run_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/spec"));
+    assert!(report.passed > 0);
+    assert!(report.failures.is_empty(), "{:#?}", report.failures);
+    assert!(report.skipped.is_empty(), "{}", report);
+}
+
+#[test]
+fn keeps_going_after_failures() {
+    let report = run_source(
+        br#"
+        (module
+          (func (export "add") (param i32 i32) (result i32)
+            (i32.add (get_local 0) (get_local 1))))
+        (assert_return (invoke "add" (i32.const 1) (i32.const 2)) (i32.const 4))
+        (assert_return (invoke "add" (i32.const 1)) (i32.const 1))
+        (assert_return (invoke "add" (i32.const 1) (i32.const 2)) (i32.const 3))
+        (assert_invalid
+          (module (func (result i32) (i64.const 0)))
+          "type mismatch")
+        (assert_invalid
+          (module (func (call 1)))
+          "unknown function")
+        (assert_malformed
+          (module binary "\00asm" "\02\00\00\00")
+          "unknown binary version")
+        (assert_malformed
+          (module quote "(func (result i32) (i32.const))")
+          "unexpected token")
+        (assert_invalid
+          (module (func (result i32) (i32.const 0)))
+          "a valid module")
+        (assert_return (invoke "add" (i32.const 2) (i32.const 2)) (i32.const 4))
+        "#,
+        "recovery.wast",
+    );
+
+    assert_eq!(report.passed, 7, "{:#?}", report.failures);
+    assert_eq!(report.failures.len(), 3, "{:#?}", report.failures);
+    assert!(report.failures[0].starts_with("recovery.wast:5: "));
+    assert!(report.failures[1].starts_with("recovery.wast:6: "));
+    assert!(report.failures[2].starts_with("recovery.wast:20: "));
+}
+
+#[test]
+fn traps_and_skips() {
+    let report = run_source(
+        br#"
+        (module
+          (func (export "div") (param i32 i32) (result i32)
+            (i32.div_u (get_local 0) (get_local 1)))
+          (func (export "many") (param i32 i32 i32 i32 i32 i32 i32) (result i32)
+            (i32.add (get_local 0) (get_local 6))))
+        (assert_trap (invoke "div" (i32.const 1) (i32.const 0)) "integer divide by zero")
+        (assert_trap (invoke "div" (i32.const 4) (i32.const 2)) "integer divide by zero")
+        (assert_return (invoke "div" (i32.const 1) (i32.const 0)) (i32.const 0))
+        (assert_return
+          (invoke "many"
+            (i32.const 1) (i32.const 2) (i32.const 3) (i32.const 4) (i32.const 5)
+            (i32.const 6) (i32.const 7))
+          (i32.const 8))
+        (register "m")
+        (assert_exhaustion (invoke "div" (i32.const 1) (i32.const 1)) "call stack exhausted")
+        (assert_return (invoke "div" (i32.const 4) (i32.const 2)) (i32.const 2))
+        "#,
+        "traps.wast",
+    );
+
+    assert_eq!(report.passed, 4, "{:#?}", report.failures);
+    assert_eq!(report.failures.len(), 2, "{:#?}", report.failures);
+    assert!(report.failures[0].starts_with("traps.wast:8: "));
+    assert!(report.failures[1].starts_with("traps.wast:9: "));
+    assert_eq!(report.skipped.get("register"), Some(&1));
+    assert_eq!(report.skipped.get("assert_exhaustion"), Some(&1));
+    assert_eq!(
+        report.to_string(),
+        "4 passed, 2 failed, 2 skipped (assert_exhaustion: 1, register: 1)"
+    );
+}
+
+#[test]
+#[ignore]
+fn official_testsuite() {
+    let dir = std::env::var("LIGHTBEAM_SPEC_TESTSUITE")
+        .expect("Set LIGHTBEAM_SPEC_TESTSUITE to a checkout of the WebAssembly testsuite");
+    let report = run_dir(Path::new(&dir));
+    assert!(report.failures.is_empty(), "{}", report);
+}
@@ -0,0 +1,61 @@
+;; Structured control flow, from the spec's block.wast, loop.wast, br_table.wast and if.wast
+
+(module
+  (func (export "block-value") (result i32)
+    (block (result i32) (i32.const 1) (br 0 (i32.const 7)) (drop)))
+  (func (export "nested-br") (param i32) (result i32)
+    (block $outer (result i32)
+      (block $inner (result i32)
+        (drop (br_if $outer (i32.const 10) (get_local 0)))
+        (i32.const 20))
+      (i32.const 1)
+      (i32.add)))
+  (func (export "fac") (param i64) (result i64) (local i64)
+    (set_local 1 (i64.const 1))
+    (block $done
+      (loop $top
+        (br_if $done (i64.eqz (get_local 0)))
+        (set_local 1 (i64.mul (get_local 0) (get_local 1)))
+        (set_local 0 (i64.sub (get_local 0) (i64.const 1)))
+        (br $top)))
+    (get_local 1))
+  (func (export "select") (param i32) (result i32)
+    (block (block (block (block (br_table 0 1 2 3 (get_local 0)))
+      (return (i32.const 100)))
+      (return (i32.const 101)))
+      (return (i32.const 102)))
+    (i32.const 103))
+  (func (export "if-else") (param i32) (param i32) (result i32)
+    (if (result i32) (get_local 0)
+      (then (if (result i32) (get_local 1) (then (i32.const 3)) (else (i32.const 2))))
+      (else (i32.const 1))))
+  (func (export "unreachable-after-br") (result i32)
+    (block (result i32) (br 0 (i32.const 9)) (unreachable)))
+)
+
+(assert_return (invoke "block-value") (i32.const 7))
+(assert_return (invoke "nested-br" (i32.const 0)) (i32.const 21))
+(assert_return (invoke "nested-br" (i32.const 1)) (i32.const 10))
+(assert_return (invoke "fac" (i64.const 0)) (i64.const 1))
+(assert_return (invoke "fac" (i64.const 20)) (i64.const 2432902008176640000))
+(assert_return (invoke "select" (i32.const 0)) (i32.const 100))
+(assert_return (invoke "select" (i32.const 2)) (i32.const 102))
+(assert_return (invoke "select" (i32.const 3)) (i32.const 103))
+(assert_return (invoke "select" (i32.const -1)) (i32.const 103))
+(assert_return (invoke "if-else" (i32.const 0) (i32.const 1)) (i32.const 1))
+(assert_return (invoke "if-else" (i32.const 1) (i32.const 0)) (i32.const 2))
+(assert_return (invoke "if-else" (i32.const 1) (i32.const 1)) (i32.const 3))
+(assert_return (invoke "unreachable-after-br") (i32.const 9))
+
+(assert_invalid
+  (module (func $type-value-num-vs-void (block (i32.const 1))))
+  "type mismatch"
+)
+(assert_invalid
+  (module (func $unbound-label (br 1)))
+  "unknown label"
+)
+(assert_malformed
+  (module binary "\00asm" "\02\00\00\00")
+  "unknown binary version"
+)